@@ -0,0 +1,91 @@
+//! Conversion of frames copied through the cpu, at 4k where it costs the most.
+//!
+//! Run with `cargo bench`, the pools of 1 thread convert on the calling thread.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nvscreencopy::{
+    convert::{memory_layout, Converter, Pool},
+    ColorDepth,
+};
+use smithay::backend::allocator::Fourcc;
+
+const WIDTH: usize = 3840;
+const HEIGHT: usize = 2160;
+/// Rows of dmabufs are usually padded
+const PADDING: usize = 256;
+const THREADS: [usize; 3] = [1, 4, 8];
+const FORMATS: [(&str, Fourcc, ColorDepth); 3] = [
+    ("argb8888", Fourcc::Argb8888, ColorDepth::Eight),
+    ("xbgr8888", Fourcc::Xbgr8888, ColorDepth::Eight),
+    ("xrgb2101010", Fourcc::Xrgb2101010, ColorDepth::Ten),
+];
+
+fn frame(stride: usize) -> Vec<u8> {
+    (0..stride * HEIGHT).map(|i| i as u8).collect()
+}
+
+fn swizzle(c: &mut Criterion) {
+    let stride = WIDTH * 4;
+    let mut group = c.benchmark_group("swizzle");
+    group.throughput(Throughput::Bytes((stride * HEIGHT) as u64));
+    for (name, format, depth) in FORMATS {
+        let layout = memory_layout(format).unwrap();
+        for threads in THREADS {
+            let pool = Pool::new(threads);
+            let mut pixels = frame(stride);
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, _| {
+                b.iter(|| pool.swizzle(&mut pixels, stride, layout, depth))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn repack(c: &mut Criterion) {
+    let (src_stride, dst_stride) = (WIDTH * 4 + PADDING, WIDTH * 4);
+    let mut group = c.benchmark_group("repack");
+    group.throughput(Throughput::Bytes((dst_stride * HEIGHT) as u64));
+    for (name, format, depth) in FORMATS {
+        let layout = memory_layout(format).unwrap();
+        for threads in THREADS {
+            let pool = Pool::new(threads);
+            let src = frame(src_stride);
+            let mut dst = vec![0; dst_stride * HEIGHT];
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, _| {
+                b.iter(|| pool.repack(&src, src_stride, &mut dst, dst_stride, WIDTH * 4, HEIGHT, layout, depth))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// A frame through the conversion thread, as the event loop hands it over
+fn convert(c: &mut Criterion) {
+    let stride = WIDTH * 4;
+    let layout = memory_layout(Fourcc::Argb8888).unwrap();
+    let mut group = c.benchmark_group("convert");
+    group.throughput(Throughput::Bytes((stride * HEIGHT) as u64));
+    for threads in THREADS {
+        let mut converter = Converter::<()>::new(threads);
+        let pixels = frame(stride);
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+            b.iter(|| {
+                let mut buffer = converter.buffer();
+                buffer.clear();
+                buffer.extend_from_slice(&pixels);
+                converter.submit(buffer, stride, layout, ColorDepth::Eight, ()).unwrap();
+                let job = loop {
+                    match converter.latest() {
+                        Some(job) => break job,
+                        None => std::thread::yield_now(),
+                    }
+                };
+                converter.recycle(job.pixels);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, swizzle, repack, convert);
+criterion_main!(benches);
@@ -39,6 +39,9 @@ fn main() {
             "EGL_KHR_stream_fifo",
             "EGL_NV_output_drm_flip_event",
             "EGL_NV_stream_attrib",
+            "EGL_KHR_fence_sync",
+            "EGL_KHR_wait_sync",
+            "EGL_ANDROID_native_fence_sync",
         ],
     )
     .write_bindings(gl_generator::GlobalGenerator, &mut file)
@@ -53,4 +56,11 @@ fn main() {
         &dest.join("drm.rs"),
         Side::Client, // Replace by `Side::Server` for server-side code
     );
+
+    // linux-dmabuf v4, which is not yet part of wayland-protocols
+    generate_code(
+        "resources/linux-dmabuf-unstable-v1.xml",
+        &dest.join("linux_dmabuf.rs"),
+        Side::Client,
+    );
 }
\ No newline at end of file
@@ -0,0 +1,282 @@
+use anyhow::Result;
+use smithay::{
+    backend::renderer::{gles2::ffi, Transform},
+    utils::{Buffer, Physical, Rectangle, Size},
+};
+
+use crate::geometry;
+
+/// Color adjustments applied to the mirrored content on the target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustments {
+    /// Added to every channel, between -1 and 1
+    pub brightness: f32,
+    /// Scales the distance of every channel to 0.5, between 0 and 4
+    pub contrast: f32,
+    /// Channels are raised to the power of 1/gamma, between 0.1 and 10
+    pub gamma: f32,
+}
+
+impl Adjustments {
+    pub const NEUTRAL: Adjustments = Adjustments {
+        brightness: 0.0,
+        contrast: 1.0,
+        gamma: 1.0,
+    };
+
+    /// Limits the values to ranges that still show something
+    pub fn clamped(self) -> Adjustments {
+        Adjustments {
+            brightness: self.brightness.max(-1.0).min(1.0),
+            contrast: self.contrast.max(0.0).min(4.0),
+            gamma: self.gamma.max(0.1).min(10.0),
+        }
+    }
+
+    pub fn is_neutral(&self) -> bool {
+        *self == Adjustments::NEUTRAL
+    }
+}
+
+const VERTEX_SHADER: &str = r#"
+attribute vec2 vert;
+uniform vec4 dst;
+uniform vec4 src;
+uniform mat2 transform;
+uniform mat2 tex_transform;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_Position = vec4(transform * (dst.xy + vert * dst.zw), 0.0, 1.0);
+    v_tex_coords = src.xy + (tex_transform * (vert - 0.5) + 0.5) * src.zw;
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+// highp is optional in fragment shaders of GLES2, the coordinates are just less exact without it
+#ifdef GL_FRAGMENT_PRECISION_HIGH
+#define COORD highp
+#else
+#define COORD mediump
+#endif
+
+uniform float brightness;
+uniform float contrast;
+uniform float gamma;
+uniform bool linear_blending;
+uniform COORD vec2 tex_size;
+varying COORD vec2 v_tex_coords;
+
+vec3 to_linear(vec3 color) {
+    return mix(color / 12.92, pow((color + 0.055) / 1.055, vec3(2.4)), step(0.04045, color));
+}
+
+vec3 to_srgb(vec3 color) {
+    return mix(color * 12.92, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, color));
+}
+
+vec3 texel(COORD vec2 coords) {
+    return to_linear(texture2D(tex, clamp(coords, 0.5 / tex_size, 1.0 - 0.5 / tex_size)).rgb);
+}
+
+// bilinear filtering of the texels around v_tex_coords, in linear light instead of on the sRGB values
+vec3 sample_linear() {
+    COORD vec2 pos = v_tex_coords * tex_size - 0.5;
+    COORD vec2 base = floor(pos);
+    COORD vec2 center = (base + 0.5) / tex_size;
+    COORD vec2 texel_size = 1.0 / tex_size;
+    vec2 weight = pos - base;
+    vec3 top = mix(texel(center), texel(center + vec2(texel_size.x, 0.0)), weight.x);
+    vec3 bottom = mix(texel(center + vec2(0.0, texel_size.y)), texel(center + texel_size), weight.x);
+    return to_srgb(mix(top, bottom, weight.y));
+}
+
+void main() {
+    vec3 color = linear_blending ? sample_linear() : texture2D(tex, v_tex_coords).rgb;
+    color = clamp((color - 0.5) * contrast + 0.5 + brightness, 0.0, 1.0);
+    gl_FragColor = vec4(pow(color, vec3(1.0 / gamma)), 1.0);
+}
+"#;
+
+/// Declarations of the fragment shader, depending on the kind of texture
+const SAMPLER_2D: &str = "precision mediump float;\nuniform sampler2D tex;\n";
+const SAMPLER_EXTERNAL: &str = "#extension GL_OES_EGL_image_external : require\nprecision mediump float;\nuniform samplerExternalOES tex;\n";
+
+/// Unit square drawn as triangle strip
+const VERTS: [f32; 8] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+
+unsafe fn compile(gl: &ffi::Gles2, kind: u32, source: &str) -> Result<u32> {
+    let shader = gl.CreateShader(kind);
+    gl.ShaderSource(
+        shader,
+        1,
+        &(source.as_ptr() as *const _),
+        &(source.len() as i32),
+    );
+    gl.CompileShader(shader);
+    let mut status = 0;
+    gl.GetShaderiv(shader, ffi::COMPILE_STATUS, &mut status);
+    if status == ffi::FALSE as i32 {
+        gl.DeleteShader(shader);
+        anyhow::bail!("Failed to compile color adjustment shader");
+    }
+    Ok(shader)
+}
+
+struct Program {
+    program: u32,
+    vert: u32,
+    dst: i32,
+    src: i32,
+    transform: i32,
+    tex_transform: i32,
+    tex: i32,
+    brightness: i32,
+    contrast: i32,
+    gamma: i32,
+    linear_blending: i32,
+    tex_size: i32,
+}
+
+impl Program {
+    unsafe fn link(gl: &ffi::Gles2, sampler: &str) -> Result<Program> {
+        let vertex = compile(gl, ffi::VERTEX_SHADER, VERTEX_SHADER)?;
+        let fragment = match compile(gl, ffi::FRAGMENT_SHADER, &format!("{}{}", sampler, FRAGMENT_SHADER)) {
+            Ok(fragment) => fragment,
+            Err(err) => {
+                gl.DeleteShader(vertex);
+                return Err(err);
+            }
+        };
+        let program = gl.CreateProgram();
+        gl.AttachShader(program, vertex);
+        gl.AttachShader(program, fragment);
+        gl.LinkProgram(program);
+        gl.DetachShader(program, vertex);
+        gl.DetachShader(program, fragment);
+        gl.DeleteShader(vertex);
+        gl.DeleteShader(fragment);
+        let mut status = 0;
+        gl.GetProgramiv(program, ffi::LINK_STATUS, &mut status);
+        if status == ffi::FALSE as i32 {
+            gl.DeleteProgram(program);
+            anyhow::bail!("Failed to link color adjustment shader");
+        }
+
+        // names need to be nul terminated
+        let location = |name: &[u8]| gl.GetUniformLocation(program, name.as_ptr() as *const _);
+        Ok(Program {
+            program,
+            vert: gl.GetAttribLocation(program, b"vert\0".as_ptr() as *const _) as u32,
+            dst: location(b"dst\0"),
+            src: location(b"src\0"),
+            transform: location(b"transform\0"),
+            tex_transform: location(b"tex_transform\0"),
+            tex: location(b"tex\0"),
+            brightness: location(b"brightness\0"),
+            contrast: location(b"contrast\0"),
+            gamma: location(b"gamma\0"),
+            linear_blending: location(b"linear_blending\0"),
+            tex_size: location(b"tex_size\0"),
+        })
+    }
+}
+
+/// Draws textures with `Adjustments` applied and optionally filtered in linear light, which smithays renderer can't do
+pub struct AdjustShader {
+    texture_2d: Program,
+    external: Option<Program>,
+}
+
+impl AdjustShader {
+    /// Needs to be called with the target context current
+    pub unsafe fn new(gl: &ffi::Gles2) -> Result<AdjustShader> {
+        Ok(AdjustShader {
+            texture_2d: Program::link(gl, SAMPLER_2D)?,
+            // only needed for directly imported yuv frames
+            external: Program::link(gl, SAMPLER_EXTERNAL).ok(),
+        })
+    }
+
+    /// Draws `src` of the texture into `dst` of the currently bound framebuffer,
+    /// which shows an area of `dest_size` after applying `transform`.
+    ///
+    /// `flipped` textures store their rows bottom to top, the filters of the texture are used as they are.
+    /// With `linear_blending` the texture is filtered bilinearly in linear light instead, for `--linear-blending`.
+    /// The content of the texture was transformed by `src_transform`, which is undone.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn draw(
+        &self,
+        gl: &ffi::Gles2,
+        texture: (u32, Size<i32, Buffer>),
+        external: bool,
+        flipped: bool,
+        src: Rectangle<i32, Buffer>,
+        src_transform: Transform,
+        dst: Rectangle<f64, Physical>,
+        dest_size: Size<i32, Physical>,
+        transform: Transform,
+        adjustments: Adjustments,
+        linear_blending: bool,
+    ) -> Result<()> {
+        let (target, program) = match (external, self.external.as_ref()) {
+            (false, _) => (ffi::TEXTURE_2D, &self.texture_2d),
+            (true, Some(program)) => (ffi::TEXTURE_EXTERNAL_OES, program),
+            (true, None) => anyhow::bail!("Color adjustments of external textures are not supported"),
+        };
+        let (tex, size) = texture;
+
+        // clip space grows upwards, while our rectangles grow downwards
+        let (w, h) = (dest_size.w as f32, dest_size.h as f32);
+        let dst = [
+            -1.0 + 2.0 * dst.loc.x as f32 / w,
+            1.0 - 2.0 * dst.loc.y as f32 / h,
+            2.0 * dst.size.w as f32 / w,
+            -2.0 * dst.size.h as f32 / h,
+        ];
+        let (tw, th) = (size.w as f32, size.h as f32);
+        let src = if flipped {
+            [
+                src.loc.x as f32 / tw,
+                (src.loc.y + src.size.h) as f32 / th,
+                src.size.w as f32 / tw,
+                -src.size.h as f32 / th,
+            ]
+        } else {
+            [
+                src.loc.x as f32 / tw,
+                src.loc.y as f32 / th,
+                src.size.w as f32 / tw,
+                src.size.h as f32 / th,
+            ]
+        };
+
+        let viewport = geometry::transformed_size(dest_size, transform);
+        gl.Viewport(0, 0, viewport.w, viewport.h);
+        gl.Disable(ffi::BLEND);
+        gl.ActiveTexture(ffi::TEXTURE0);
+        gl.BindTexture(target, tex);
+        gl.UseProgram(program.program);
+        gl.Uniform1i(program.tex, 0);
+        gl.Uniform4fv(program.dst, 1, dst.as_ptr());
+        gl.Uniform4fv(program.src, 1, src.as_ptr());
+        gl.UniformMatrix2fv(program.transform, 1, ffi::FALSE, geometry::clip_matrix(transform).as_ptr());
+        let tex_transform = geometry::texture_matrix(src_transform);
+        gl.UniformMatrix2fv(program.tex_transform, 1, ffi::FALSE, tex_transform.as_ptr());
+        gl.Uniform1f(program.brightness, adjustments.brightness);
+        gl.Uniform1f(program.contrast, adjustments.contrast);
+        gl.Uniform1f(program.gamma, adjustments.gamma);
+        gl.Uniform1i(program.linear_blending, linear_blending as i32);
+        gl.Uniform2f(program.tex_size, tw, th);
+
+        gl.BindBuffer(ffi::ARRAY_BUFFER, 0);
+        gl.EnableVertexAttribArray(program.vert);
+        gl.VertexAttribPointer(program.vert, 2, ffi::FLOAT, ffi::FALSE, 0, VERTS.as_ptr() as *const _);
+        gl.DrawArrays(ffi::TRIANGLE_STRIP, 0, 4);
+        gl.DisableVertexAttribArray(program.vert);
+
+        gl.BindTexture(target, 0);
+        gl.UseProgram(0);
+        Ok(())
+    }
+}
@@ -0,0 +1,1107 @@
+use anyhow::{Context, Result};
+use calloop::timer::TimerHandle;
+use smithay::backend::allocator::{
+    dmabuf::{Dmabuf, DmabufFlags},
+    Format, Fourcc, Modifier,
+};
+use smithay_client_toolkit::reexports::{
+    client::{protocol::wl_output, Attached, DispatchData, Main},
+    protocols::wlr::unstable::export_dmabuf::v1::client::{
+        zwlr_export_dmabuf_frame_v1::{self as export_dmabuf_frame, Event as ExportDmabufEvent},
+        zwlr_export_dmabuf_manager_v1::ZwlrExportDmabufManagerV1 as ExportDmabufManager,
+    },
+};
+
+use crate::{
+    constraints::CaptureConstraints,
+    egl::EglFence,
+    render::{self, Release},
+    source::Source,
+    stats,
+    streak::{Streak, Verdict},
+    trace::{FrameTracer, Stage},
+    WaylandState,
+};
+
+use std::{collections::VecDeque, convert::TryFrom, os::unix::io::RawFd, str::FromStr, time::Duration};
+
+/// Source of captured frames.
+///
+/// A backend only issues the capture request, the frames itself are delivered
+/// through the wayland event queue into the `WaylandState` and are handed
+/// to the matching function of the `render` module.
+pub trait CaptureBackend {
+    fn name(&self) -> &'static str;
+    /// Whether frames need to be read back on the compositors gpu
+    fn needs_render_gpu(&self) -> bool;
+    /// Captures `output`, whose frames belong to the source with index `source` of the `WaylandState`
+    fn capture(&mut self, source: usize, output: &wl_output::WlOutput, state: &mut WaylandState);
+    /// Whether capturing `source` again likely yields new content, a fixed `--capture-rate` skips the capture otherwise.
+    ///
+    /// Backends without a way to tell capture at every tick of the timer.
+    fn new_content(&self, _source: usize, _state: &WaylandState) -> bool {
+        true
+    }
+    /// File descriptor to poll, for backends receiving frames outside of the wayland event queue
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// Called whenever `fd` is readable
+    fn dispatch(&mut self, _state: &mut WaylandState) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureBackendKind {
+    Auto,
+    ExportDmabuf,
+    Portal,
+    Screencopy,
+}
+
+impl CaptureBackendKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "export-dmabuf", "portal", "screencopy"];
+}
+
+impl FromStr for CaptureBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<CaptureBackendKind> {
+        match name {
+            "auto" => Ok(CaptureBackendKind::Auto),
+            "export-dmabuf" => Ok(CaptureBackendKind::ExportDmabuf),
+            "portal" => Ok(CaptureBackendKind::Portal),
+            "screencopy" => Ok(CaptureBackendKind::Screencopy),
+            x => anyhow::bail!("Unknown capture backend: {}", x),
+        }
+    }
+}
+
+/// What drives capturing, requested with `--capture-rate`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureRate {
+    /// Every vblank of the target captures the next frame, the frame is swapped as soon as it arrived
+    VBlank,
+    /// A timer captures at this many frames per second, every vblank swaps the newest frame that arrived
+    Hz(f64),
+}
+
+impl CaptureRate {
+    /// Time between captures, `None` if driven by vblanks
+    pub fn interval(&self) -> Option<Duration> {
+        match self {
+            CaptureRate::VBlank => None,
+            CaptureRate::Hz(hz) => Some(Duration::from_secs_f64(1.0 / hz)),
+        }
+    }
+}
+
+impl FromStr for CaptureRate {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<CaptureRate> {
+        if input == "vblank" {
+            return Ok(CaptureRate::VBlank);
+        }
+        match input.parse::<f64>() {
+            Ok(hz) if hz.is_finite() && hz >= 1.0 => Ok(CaptureRate::Hz(hz)),
+            _ => anyhow::bail!("Capture rate needs to be \"vblank\" or at least 1 frame per second: {}", input),
+        }
+    }
+}
+
+/// What happens to frames arriving while the target is still busy with the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Only the newest frame is kept until the target can take it, older ones are dropped
+    Latest,
+    /// Every frame is rendered in order, e.g. for recordings where every frame matters
+    All,
+}
+
+impl QueuePolicy {
+    pub const VARIANTS: &'static [&'static str] = &["latest", "all"];
+}
+
+impl FromStr for QueuePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<QueuePolicy> {
+        match name {
+            "latest" => Ok(QueuePolicy::Latest),
+            "all" => Ok(QueuePolicy::All),
+            x => anyhow::bail!("Unknown queue policy: {}", x),
+        }
+    }
+}
+
+/// Delay before retrying after the first failed capture
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(5);
+/// Upper bound for waiting on the gpus to finish reading a frame, before releasing it anyway
+const RELEASE_TIMEOUT: u64 = 1_000_000_000;
+/// Consecutive failures after which we start complaining
+const RETRY_WARN_THRESHOLD: u32 = 32;
+
+/// Delay before the next capture after `failures` consecutive failed ones.
+///
+/// Doubles with every failure, but never exceeds `max`.
+pub fn backoff(failures: u32, max: Duration) -> Duration {
+    if failures == 0 {
+        return Duration::ZERO;
+    }
+    let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+    RETRY_BASE_DELAY
+        .checked_mul(factor)
+        .map(|delay| delay.min(max))
+        .unwrap_or(max)
+}
+
+/// Keeps track of failed captures, so we do not hammer an overloaded compositor
+pub struct Retry {
+    failures: Streak,
+    /// A capture is scheduled on `timer` already
+    pending: bool,
+    max_delay: Duration,
+    /// Captures again once it fires, retries are dropped without one
+    timer: Option<TimerHandle<()>>,
+}
+
+impl Retry {
+    pub fn new(max_delay: Duration) -> Retry {
+        Retry {
+            failures: Streak::new(RETRY_WARN_THRESHOLD, None),
+            pending: false,
+            max_delay,
+            timer: None,
+        }
+    }
+
+    /// Schedules retries on `timer`, whose callback needs to call `fired`
+    pub fn set_timer(&mut self, timer: TimerHandle<()>) {
+        self.timer = Some(timer);
+    }
+
+    /// A capture failed and needs to be repeated
+    pub fn failed(&mut self, log: &slog::Logger) {
+        if self.failures.failed() == Verdict::Warn {
+            slog::warn!(
+                log,
+                "{} captures failed in a row, the compositor seems to be overloaded",
+                self.failures.count()
+            );
+        }
+        self.schedule();
+    }
+
+    /// Another capture is needed right away, without anything having failed
+    pub fn again(&mut self) {
+        self.schedule();
+    }
+
+    /// A frame was captured successfully
+    pub fn succeeded(&mut self) {
+        self.failures.succeeded();
+    }
+
+    /// The timer fired and the capture is being repeated
+    pub fn fired(&mut self) {
+        self.pending = false;
+    }
+
+    /// Starts the timer, unless a retry is pending already.
+    ///
+    /// The delay grows with the failures so far, and is zero if the last capture succeeded.
+    fn schedule(&mut self) {
+        if self.pending {
+            return;
+        }
+        if let Some(timer) = self.timer.as_ref() {
+            timer.add_timeout(backoff(self.failures.count(), self.max_delay), ());
+            self.pending = true;
+        }
+    }
+}
+
+pub struct ExportDmabufBackend {
+    manager: Attached<ExportDmabufManager>,
+}
+
+impl ExportDmabufBackend {
+    pub fn new(manager: Attached<ExportDmabufManager>) -> ExportDmabufBackend {
+        ExportDmabufBackend { manager }
+    }
+}
+
+impl CaptureBackend for ExportDmabufBackend {
+    fn name(&self) -> &'static str {
+        "export-dmabuf"
+    }
+
+    fn needs_render_gpu(&self) -> bool {
+        true
+    }
+
+    fn capture(&mut self, source: usize, output: &wl_output::WlOutput, state: &mut WaylandState) {
+        request_frame(&self.manager, source, output, state);
+    }
+
+    /// Export-dmabuf has no damage, so the timestamps of the frames tell whether the source rendered since
+    fn new_content(&self, source: usize, state: &WaylandState) -> bool {
+        state.sources[source].cadence.due(stats::monotonic_now())
+    }
+}
+
+/// Deltas between frames longer than this are the source idling, not its frame rate
+const MAX_CADENCE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Frame rate of a source, as far as the timestamps of its frames tell.
+///
+/// Frames repeating the timestamp of the previous one hold no new content.
+#[derive(Debug, Default)]
+pub struct Cadence {
+    /// Timestamp of the latest frame with new content
+    last: Option<Duration>,
+    /// Moving average of the time between frames with new content
+    interval: Option<Duration>,
+}
+
+impl Cadence {
+    /// A frame with the compositor timestamp `captured` arrived, returns whether it holds new content
+    pub fn frame(&mut self, captured: Duration) -> bool {
+        let last = match self.last {
+            Some(last) if captured <= last => return false,
+            last => last,
+        };
+        self.last = Some(captured);
+        if let Some(delta) = last.map(|last| captured - last).filter(|delta| *delta <= MAX_CADENCE_INTERVAL) {
+            self.interval = Some(match self.interval {
+                Some(interval) => (interval * 7 + delta) / 8,
+                None => delta,
+            });
+        }
+        true
+    }
+
+    /// Whether the source likely rendered another frame by `now`, on the clock of the timestamps
+    pub fn due(&self, now: Duration) -> bool {
+        match (self.last, self.interval) {
+            // a bit early, the compositor only sends the frame once it is done anyway
+            (Some(last), Some(interval)) => now >= last + interval * 3 / 4,
+            _ => true,
+        }
+    }
+}
+
+/// Most planes a dmabuf can have
+const MAX_PLANES: u32 = 4;
+
+/// A plane of a `CapturedFrame`, its fd is closed along it unless it was handed on to a dmabuf
+#[derive(Debug)]
+pub struct CapturedPlane {
+    fd: Option<RawFd>,
+    /// Size of the object holding the plane, in bytes
+    pub size: u32,
+    pub offset: u32,
+    pub stride: u32,
+    pub index: u32,
+}
+
+impl Drop for CapturedPlane {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            let _ = nix::unistd::close(fd);
+        }
+    }
+}
+
+/// A dmabuf frame as the capture protocol describes it, built up across its events and rendered as a unit
+#[derive(Debug)]
+pub struct CapturedFrame {
+    pub size: (i32, i32),
+    pub format: Fourcc,
+    pub modifier: Modifier,
+    pub buffer_flags: DmabufFlags,
+    /// The transient flag of export-dmabuf, such a buffer is only valid until the next frame and never cached
+    pub transient: bool,
+    /// Planes the compositor announced
+    pub num_objects: u32,
+    pub planes: Vec<CapturedPlane>,
+    /// Capture timestamp, known once the frame is ready
+    pub captured: Option<Duration>,
+}
+
+impl CapturedFrame {
+    pub fn new(
+        size: (i32, i32),
+        format: Fourcc,
+        modifier: Modifier,
+        buffer_flags: DmabufFlags,
+        transient: bool,
+        num_objects: u32,
+    ) -> CapturedFrame {
+        CapturedFrame {
+            size,
+            format,
+            modifier,
+            buffer_flags,
+            transient,
+            num_objects,
+            planes: Vec::new(),
+            captured: None,
+        }
+    }
+
+    /// Adds a plane, the frame owns `fd` from now on, even if the plane is refused
+    pub fn add_plane(&mut self, fd: RawFd, size: u32, offset: u32, stride: u32, index: u32) -> Result<()> {
+        let plane = CapturedPlane {
+            fd: Some(fd),
+            size,
+            offset,
+            stride,
+            index,
+        };
+        if plane.index >= MAX_PLANES {
+            anyhow::bail!("Captured frame has plane index {}, dmabufs have at most {} planes", index, MAX_PLANES);
+        }
+        if self.planes.iter().any(|other| other.index == index) {
+            anyhow::bail!("Captured frame has plane {} twice", index);
+        }
+        if self.planes.len() as u32 >= self.num_objects {
+            anyhow::bail!("Captured frame has more than the {} planes it announced", self.num_objects);
+        }
+        self.planes.push(plane);
+        Ok(())
+    }
+
+    /// Completes the frame captured at `captured`, failing if it lacks planes
+    pub fn ready(&mut self, captured: Duration) -> Result<()> {
+        if self.planes.is_empty() {
+            anyhow::bail!("Captured frame is ready without any planes");
+        }
+        if self.planes.len() as u32 != self.num_objects {
+            anyhow::bail!("Captured frame is ready with {} of {} planes", self.planes.len(), self.num_objects);
+        }
+        self.planes.sort_by_key(|plane| plane.index);
+        if let Some((missing, _)) = self
+            .planes
+            .iter()
+            .enumerate()
+            .find(|(expected, plane)| plane.index != *expected as u32)
+        {
+            anyhow::bail!("Captured frame lacks plane {}", missing);
+        }
+        self.captured = Some(captured);
+        Ok(())
+    }
+
+    /// Hands the fds of the planes over to a dmabuf, which closes them once dropped
+    pub fn into_dmabuf(mut self) -> Result<Dmabuf> {
+        let mut builder = Dmabuf::builder(self.size, self.format, self.buffer_flags);
+        for plane in self.planes.iter_mut() {
+            let fd = match plane.fd.take() {
+                Some(fd) => fd,
+                None => continue,
+            };
+            if !builder.add_plane(fd, plane.index, plane.offset, plane.stride, self.modifier) {
+                let _ = nix::unistd::close(fd);
+                anyhow::bail!("Captured frame has too many planes");
+            }
+        }
+        builder.build().context("Failed to build dmabuf")
+    }
+}
+
+/// An export-dmabuf frame requested from the compositor, that was not rendered yet
+pub struct PendingFrame<F = Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>> {
+    id: u32,
+    frame: F,
+    /// Owns the fds of the planes received so far, dropping the frame closes them
+    captured: Option<CapturedFrame>,
+    /// Number of `--trace-frames`
+    trace_id: Option<u64>,
+}
+
+/// A complete export-dmabuf frame, held back until the target took the previous one
+pub struct ReadyFrame {
+    frame: Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>,
+    /// Dropping it closes the fds
+    captured: CapturedFrame,
+    trace_id: Option<u64>,
+}
+
+/// A rendered export-dmabuf frame, which is released to the compositor once we are done reading it
+pub struct ReleasingFrame {
+    frame: Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>,
+    fence: EglFence,
+}
+
+/// Releases the frames the gpus are done with.
+///
+/// Waits for the oldest ones, if more than the pipeline depth are held back.
+fn release_frames(state: &mut WaylandState) {
+    while let Some(releasing) = state.releasing.front() {
+        if !releasing.fence.signaled() {
+            if state.releasing.len() <= state.pipeline_depth {
+                break;
+            }
+            releasing.fence.wait(RELEASE_TIMEOUT);
+        }
+        let releasing = state.releasing.pop_front().unwrap();
+        releasing.frame.destroy();
+    }
+}
+
+/// Releases all held back frames right away, e.g. because their fences will never signal
+pub fn release_all(state: &mut WaylandState) {
+    for releasing in state.releasing.drain(..) {
+        releasing.frame.destroy();
+    }
+}
+
+/// Requests another frame, as long as the pipeline is not full yet
+fn request_frame(
+    manager: &Attached<ExportDmabufManager>,
+    source: usize,
+    output: &wl_output::WlOutput,
+    state: &mut WaylandState,
+) {
+    release_frames(state);
+    if state.sources[source].frames.len() >= state.pipeline_depth {
+        return;
+    }
+    let trace_id = state.tracer.as_mut().map(|tracer| tracer.requested());
+    let frame = manager.capture_output(state.overlay_cursor as i32, output);
+    state.sources[source].frames.push_back(PendingFrame {
+        id: frame.as_ref().id(),
+        frame: frame.clone(),
+        captured: None,
+        trace_id,
+    });
+    let manager = manager.clone();
+    let output = output.clone();
+    frame.quick_assign(move |frame, event, data| handle_frame(frame, event, data, &manager, source, &output));
+}
+
+/// Gives up on the frames requested so far, events of them are not received anymore
+pub fn abandon_frames(source: &mut Source) {
+    for pending in source.frames.drain(..) {
+        pending.frame.destroy();
+    }
+    if let Some(ready) = source.ready.take() {
+        ready.frame.destroy();
+    }
+}
+
+/// Renders a complete frame and hands it over to be released once the gpus are done with it
+fn render_frame(
+    state: &mut WaylandState,
+    source: usize,
+    frame: Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>,
+    captured: CapturedFrame,
+    trace_id: Option<u64>,
+) {
+    if let (Some(tracer), Some(id)) = (state.tracer.as_mut(), trace_id) {
+        tracer.begin(id);
+    }
+    match render::render_dmabuf(state, source, captured) {
+        Ok(Release::Fence(fence)) => state.releasing.push_back(ReleasingFrame { frame, fence }),
+        Ok(Release::Scanout) => match state.scanout.as_mut() {
+            Some(scanout) => scanout.hold(frame),
+            None => frame.destroy(),
+        },
+        Ok(Release::Now) => frame.destroy(),
+        Err(err) => {
+            frame.destroy();
+            render::render_failed(state, err);
+            return;
+        }
+    }
+    state.render_failures = 0;
+}
+
+/// Renders the frames held back by `QueuePolicy::Latest`, once the target took the previous one
+pub fn render_ready(state: &mut WaylandState) {
+    for source in 0..state.sources.len() {
+        if state.swap_pending {
+            return;
+        }
+        if let Some(ready) = state.sources[source].ready.take() {
+            render_frame(state, source, ready.frame, ready.captured, ready.trace_id);
+        }
+    }
+}
+
+/// Removes the queue slot of the given frame
+fn take_frame<F>(frames: &mut VecDeque<PendingFrame<F>>, id: u32) -> Option<PendingFrame<F>> {
+    let idx = frames.iter().position(|pending| pending.id == id)?;
+    frames.remove(idx)
+}
+
+/// Records why a frame was dropped, another one is captured after a delay
+pub fn frame_failed(state: &mut WaylandState, err: anyhow::Error) {
+    slog::debug!(state.log, "Dropping frame: {:#}", err);
+    state.stats.error(format!("Dropped frame: {}", err));
+    state.frame_error = Some(err);
+    state.retry.failed(&state.log);
+}
+
+fn handle_frame(
+    frame: Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>,
+    event: ExportDmabufEvent,
+    mut data: DispatchData,
+    manager: &Attached<ExportDmabufManager>,
+    source: usize,
+    output: &wl_output::WlOutput,
+) {
+    let state: &mut WaylandState = data.get().unwrap();
+    if let Err(err) = frame_event(&frame, event, state, manager, source, output) {
+        take_frame(&mut state.sources[source].frames, frame.as_ref().id());
+        frame.destroy();
+        frame_failed(state, err);
+    }
+}
+
+/// Handles an event of `frame`, on errors the caller drops the frame
+fn frame_event(
+    frame: &Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>,
+    event: ExportDmabufEvent,
+    state: &mut WaylandState,
+    manager: &Attached<ExportDmabufManager>,
+    source: usize,
+    output: &wl_output::WlOutput,
+) -> Result<()> {
+    let id = frame.as_ref().id();
+    match event {
+        ExportDmabufEvent::Cancel {
+            reason: export_dmabuf_frame::CancelReason::Permanent,
+        } => {
+            slog::debug!(state.log, "Frame cancelled permanently");
+            state.stats.error("Source lost");
+            take_frame(&mut state.sources[source].frames, id);
+            frame.destroy();
+            state.sources[source]
+                .source_lost
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        ExportDmabufEvent::Cancel { .. } => {
+            slog::debug!(state.log, "Frame cancelled");
+            state.stats.error("Frame cancelled");
+            take_frame(&mut state.sources[source].frames, id);
+            frame.destroy();
+            state.retry.failed(&state.log);
+        }
+        event => {
+            let collected = collect_event(
+                &mut state.sources[source].frames,
+                id,
+                event,
+                &mut state.constraints,
+                state.tracer.as_ref(),
+                &state.log,
+            )?;
+            let Collected {
+                captured,
+                trace_id,
+                timestamp,
+            } = match collected {
+                Some(collected) => collected,
+                None => return Ok(()),
+            };
+            state.retry.succeeded();
+            state.stats.frame_captured();
+            if state.sources[source].cadence.frame(timestamp) {
+                state.stats.source_frame();
+            }
+            // overlap capturing the next frame with rendering this one
+            if state.pipeline_depth > 1 {
+                request_frame(manager, source, output, state);
+            }
+            // the target still shows the previous frame, so this one waits for the next vblank
+            if state.queue_policy == QueuePolicy::Latest && state.swap_pending {
+                let ready = ReadyFrame {
+                    frame: frame.clone(),
+                    captured,
+                    trace_id,
+                };
+                if let Some(older) = state.sources[source].ready.replace(ready) {
+                    older.frame.destroy();
+                    state.stats.frame_dropped();
+                }
+                return Ok(());
+            }
+            render_frame(state, source, frame.clone(), captured, trace_id);
+        }
+    }
+    Ok(())
+}
+
+/// A frame whose events are complete
+struct Collected {
+    captured: CapturedFrame,
+    trace_id: Option<u64>,
+    timestamp: Duration,
+}
+
+/// Adds the Frame, Object or Ready `event` of frame `id` to its entry in `frames`, returns the frame once it is ready.
+///
+/// On errors the caller drops the frame, which closes the fds it received so far.
+fn collect_event<F>(
+    frames: &mut VecDeque<PendingFrame<F>>,
+    id: u32,
+    event: ExportDmabufEvent,
+    constraints: &mut CaptureConstraints,
+    tracer: Option<&FrameTracer>,
+    log: &slog::Logger,
+) -> Result<Option<Collected>> {
+    match event {
+        ExportDmabufEvent::Frame {
+            width,
+            height,
+            buffer_flags,
+            flags,
+            format,
+            mod_high,
+            mod_low,
+            num_objects,
+            ..
+        } => {
+            let format = Fourcc::try_from(format)
+                .map_err(|_| anyhow::anyhow!("Unknown format of exported frame: 0x{:x}", format))?;
+            let modifier = ((mod_high as u64) << 32) | mod_low as u64;
+            // export-dmabuf has no say in the format, so we can only refuse frames
+            constraints.report_dmabuf(
+                Format {
+                    code: format,
+                    modifier: Modifier::from(modifier),
+                },
+                log,
+            );
+            constraints.check(format)?;
+            let pending = frames
+                .iter_mut()
+                .find(|pending| pending.id == id)
+                .context("Frame event for unknown frame")?;
+            if let (Some(tracer), Some(id)) = (tracer, pending.trace_id) {
+                tracer.record(id, Stage::Metadata);
+            }
+            pending.captured = Some(CapturedFrame::new(
+                (width as i32, height as i32),
+                format,
+                Modifier::from(modifier),
+                DmabufFlags::from_bits_truncate(buffer_flags),
+                flags.contains(export_dmabuf_frame::Flags::Transient),
+                num_objects,
+            ));
+            Ok(None)
+        }
+        ExportDmabufEvent::Object {
+            fd,
+            size,
+            offset,
+            stride,
+            plane_index,
+            ..
+        } => {
+            let pending = frames
+                .iter_mut()
+                .find(|pending| pending.id == id)
+                .and_then(|pending| pending.captured.as_mut());
+            // until the frame took the fd, nothing else closes it
+            match pending {
+                Some(captured) => captured.add_plane(fd, size, offset, stride, plane_index)?,
+                None => {
+                    let _ = nix::unistd::close(fd);
+                    anyhow::bail!("Object event before Frame event");
+                }
+            }
+            Ok(None)
+        }
+        ExportDmabufEvent::Ready {
+            tv_sec_hi,
+            tv_sec_lo,
+            tv_nsec,
+        } => {
+            slog::debug!(log, "Frame ready");
+            let pending = take_frame(frames, id);
+            let trace_id = pending.as_ref().and_then(|pending| pending.trace_id);
+            if let (Some(tracer), Some(id)) = (tracer, trace_id) {
+                tracer.record(id, Stage::Ready);
+            }
+            let mut captured = pending
+                .and_then(|pending| pending.captured)
+                .context("Ready event before Frame event")?;
+            let timestamp = stats::protocol_timestamp(tv_sec_hi, tv_sec_lo, tv_nsec);
+            captured.ready(timestamp)?;
+            slog::debug!(log, "Captured frame: {:?}", captured);
+            Ok(Some(Collected {
+                captured,
+                trace_id,
+                timestamp,
+            }))
+        }
+        _ => anyhow::bail!("Unknown export-dmabuf event"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashSet, path::PathBuf};
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[test]
+    fn backoff_doubles_per_failure() {
+        let max = Duration::from_secs(1);
+        assert_eq!(backoff(0, max), Duration::ZERO);
+        assert_eq!(backoff(1, max), RETRY_BASE_DELAY);
+        assert_eq!(backoff(2, max), RETRY_BASE_DELAY * 2);
+        assert_eq!(backoff(5, max), RETRY_BASE_DELAY * 16);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let max = Duration::from_millis(100);
+        assert_eq!(backoff(6, max), max);
+        assert_eq!(backoff(31, max), max);
+        // the factor overflows
+        assert_eq!(backoff(33, max), max);
+        assert_eq!(backoff(u32::MAX, max), max);
+    }
+
+    #[test]
+    fn retry_without_timer_drops_retries() {
+        let mut retry = Retry::new(Duration::from_secs(1));
+        retry.failed(&log());
+        assert!(!retry.pending);
+        assert_eq!(retry.failures.count(), 1);
+    }
+
+    #[test]
+    fn retry_schedules_once_until_fired() {
+        let timer = calloop::timer::Timer::<()>::new().unwrap();
+        let mut retry = Retry::new(Duration::from_secs(1));
+        retry.set_timer(timer.handle());
+        retry.failed(&log());
+        assert!(retry.pending);
+        retry.failed(&log());
+        retry.again();
+        assert!(retry.pending);
+        assert_eq!(retry.failures.count(), 2);
+        retry.fired();
+        assert!(!retry.pending);
+    }
+
+    #[test]
+    fn retry_resets_on_success() {
+        let max = Duration::from_secs(1);
+        let mut retry = Retry::new(max);
+        for _ in 0..4 {
+            retry.failed(&log());
+        }
+        assert_eq!(backoff(retry.failures.count(), max), RETRY_BASE_DELAY * 8);
+        retry.succeeded();
+        assert_eq!(retry.failures.count(), 0);
+        assert_eq!(backoff(retry.failures.count(), max), Duration::ZERO);
+    }
+
+    /// Read ends of pipes standing in for the dmabuf fds of the compositor
+    struct Fds(HashSet<PathBuf>);
+
+    impl Fds {
+        fn new() -> Fds {
+            Fds(HashSet::new())
+        }
+
+        fn open(&mut self) -> RawFd {
+            let (read, write) = nix::unistd::pipe().unwrap();
+            nix::unistd::close(write).unwrap();
+            self.0.insert(std::fs::read_link(format!("/proc/self/fd/{}", read)).unwrap());
+            read
+        }
+
+        /// Fds of ours still open, other tests opening fds meanwhile don't count
+        fn count(&self) -> usize {
+            std::fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+                .filter(|target| self.0.contains(target))
+                .count()
+        }
+    }
+
+    fn frame(num_objects: u32) -> CapturedFrame {
+        CapturedFrame::new(
+            (64, 64),
+            Fourcc::Argb8888,
+            Modifier::Linear,
+            DmabufFlags::empty(),
+            false,
+            num_objects,
+        )
+    }
+
+    #[test]
+    fn fds_stay_flat_across_reinitializations() {
+        let mut fds = Fds::new();
+        for _ in 0..20 {
+            // frames in every state, as a reinitialization finds them
+            let mut frames = Vec::new();
+            for _ in 0..50 {
+                // rendered and released
+                let mut rendered = frame(1);
+                rendered.add_plane(fds.open(), 16384, 0, 256, 0).unwrap();
+                rendered.ready(Duration::from_millis(16)).unwrap();
+                drop(rendered.into_dmabuf().unwrap());
+                // complete, waiting for the target
+                let mut ready = frame(2);
+                ready.add_plane(fds.open(), 16384, 0, 256, 1).unwrap();
+                ready.add_plane(fds.open(), 16384, 0, 256, 0).unwrap();
+                ready.ready(Duration::from_millis(16)).unwrap();
+                frames.push(ready);
+                // cancelled halfway through
+                let mut partial = frame(2);
+                partial.add_plane(fds.open(), 16384, 0, 256, 0).unwrap();
+                assert!(partial.ready(Duration::from_millis(16)).is_err());
+                frames.push(partial);
+                // planes the frame refuses
+                let mut refused = frame(1);
+                refused.add_plane(fds.open(), 16384, 0, 256, 0).unwrap();
+                assert!(refused.add_plane(fds.open(), 16384, 0, 256, 0).is_err());
+                assert!(refused.add_plane(fds.open(), 16384, 0, 256, 1).is_err());
+                assert!(refused.add_plane(fds.open(), 16384, 0, 256, MAX_PLANES).is_err());
+                frames.push(refused);
+            }
+            assert_eq!(fds.count(), 50 * 4);
+            drop(frames);
+            assert_eq!(fds.count(), 0);
+        }
+    }
+
+    fn announce(num_objects: u32, flags: export_dmabuf_frame::Flags) -> ExportDmabufEvent {
+        ExportDmabufEvent::Frame {
+            width: 64,
+            height: 32,
+            offset_x: 0,
+            offset_y: 0,
+            buffer_flags: DmabufFlags::Y_INVERT.bits(),
+            flags,
+            format: Fourcc::Xrgb8888 as u32,
+            mod_high: 0x0100_0000,
+            mod_low: 0x4,
+            num_objects,
+        }
+    }
+
+    fn object(fd: RawFd, plane_index: u32) -> ExportDmabufEvent {
+        ExportDmabufEvent::Object {
+            index: plane_index,
+            fd,
+            size: 8192,
+            offset: 0,
+            stride: 256,
+            plane_index,
+        }
+    }
+
+    fn ready() -> ExportDmabufEvent {
+        ExportDmabufEvent::Ready {
+            tv_sec_hi: 1,
+            tv_sec_lo: 2,
+            tv_nsec: 3,
+        }
+    }
+
+    /// Events of a frame, opening the fds it takes
+    type Sequence = fn(&mut Fds) -> Vec<ExportDmabufEvent>;
+
+    /// Frames requested from the compositor, before any of their events arrived
+    fn requested(ids: &[u32]) -> VecDeque<PendingFrame<()>> {
+        ids.iter()
+            .map(|&id| PendingFrame {
+                id,
+                frame: (),
+                captured: None,
+                trace_id: None,
+            })
+            .collect()
+    }
+
+    /// Hands `event` of frame `id` to `collect_event`, on errors the frame is dropped like `handle_frame` does
+    fn collect(
+        frames: &mut VecDeque<PendingFrame<()>>,
+        id: u32,
+        event: ExportDmabufEvent,
+    ) -> Result<Option<Collected>> {
+        let mut constraints = CaptureConstraints::new(None);
+        collect_event(frames, id, event, &mut constraints, None, &log()).map_err(|err| {
+            take_frame(frames, id);
+            err
+        })
+    }
+
+    /// Builds a `CapturedFrame` from `events` of a single requested frame
+    fn replay(events: Vec<ExportDmabufEvent>) -> Result<CapturedFrame> {
+        let mut frames = requested(&[1]);
+        for event in events {
+            if let Some(collected) = collect(&mut frames, 1, event)? {
+                assert!(frames.is_empty());
+                return Ok(collected.captured);
+            }
+        }
+        take_frame(&mut frames, 1);
+        anyhow::bail!("Frame is incomplete")
+    }
+
+    #[test]
+    fn events_build_the_frame() {
+        let mut fds = Fds::new();
+        let events = vec![
+            announce(2, export_dmabuf_frame::Flags::Transient),
+            // planes may arrive in any order
+            object(fds.open(), 1),
+            object(fds.open(), 0),
+            ready(),
+        ];
+        let captured = replay(events).unwrap();
+        assert_eq!(captured.size, (64, 32));
+        assert_eq!(captured.format, Fourcc::Xrgb8888);
+        assert_eq!(captured.modifier, Modifier::from(0x0100_0000_0000_0004));
+        assert_eq!(captured.buffer_flags, DmabufFlags::Y_INVERT);
+        assert!(captured.transient);
+        assert_eq!(captured.planes.iter().map(|plane| plane.index).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(captured.captured, Some(Duration::new((1 << 32) | 2, 3)));
+        assert_eq!(fds.count(), 2);
+        drop(captured);
+        assert_eq!(fds.count(), 0);
+
+        let captured = replay(vec![announce(1, export_dmabuf_frame::Flags::empty()), object(fds.open(), 0), ready()]);
+        assert!(!captured.unwrap().transient);
+    }
+
+    #[test]
+    fn malformed_events_drop_the_frame() {
+        // every sequence opens its own fds, so the ones of the others don't count
+        let sequences: Vec<Sequence> = vec![
+            // Ready with zero planes
+            |_| vec![announce(0, export_dmabuf_frame::Flags::empty()), ready()],
+            |_| vec![announce(1, export_dmabuf_frame::Flags::empty()), ready()],
+            // duplicate plane index
+            |fds| {
+                vec![
+                    announce(2, export_dmabuf_frame::Flags::empty()),
+                    object(fds.open(), 0),
+                    object(fds.open(), 0),
+                    ready(),
+                ]
+            },
+            // more planes than announced
+            |fds| {
+                vec![
+                    announce(1, export_dmabuf_frame::Flags::empty()),
+                    object(fds.open(), 0),
+                    object(fds.open(), 1),
+                ]
+            },
+            // fewer planes than announced
+            |fds| {
+                vec![
+                    announce(2, export_dmabuf_frame::Flags::empty()),
+                    object(fds.open(), 0),
+                    ready(),
+                ]
+            },
+            // a gap in the plane indices
+            |fds| {
+                vec![
+                    announce(2, export_dmabuf_frame::Flags::empty()),
+                    object(fds.open(), 0),
+                    object(fds.open(), 2),
+                    ready(),
+                ]
+            },
+            |fds| {
+                vec![
+                    announce(1, export_dmabuf_frame::Flags::empty()),
+                    object(fds.open(), MAX_PLANES),
+                ]
+            },
+        ];
+        for sequence in sequences {
+            let mut fds = Fds::new();
+            let events = sequence(&mut fds);
+            let description = format!("{:?}", events);
+            assert!(replay(events).is_err(), "{}", description);
+            // the fds of a dropped frame are closed, including those it refused
+            assert_eq!(fds.count(), 0, "{}", description);
+        }
+    }
+
+    #[test]
+    fn out_of_order_events_drop_the_frame() {
+        let sequences: Vec<(Sequence, &str)> = vec![
+            (|fds| vec![object(fds.open(), 0)], "Object event before Frame event"),
+            (|_| vec![ready()], "Ready event before Frame event"),
+            // Ready while the frame still waits for its planes
+            (
+                |_| vec![announce(1, export_dmabuf_frame::Flags::empty()), ready()],
+                "Captured frame is ready without any planes",
+            ),
+            (
+                |fds| vec![announce(2, export_dmabuf_frame::Flags::empty()), object(fds.open(), 1), ready()],
+                "Captured frame is ready with 1 of 2 planes",
+            ),
+        ];
+        for (sequence, expected) in sequences {
+            let mut fds = Fds::new();
+            let events = sequence(&mut fds);
+            let description = format!("{:?}", events);
+            let err = replay(events).err().unwrap();
+            assert_eq!(format!("{}", err), expected, "{}", description);
+            assert_eq!(fds.count(), 0, "{}", description);
+        }
+    }
+
+    #[test]
+    fn events_of_dropped_frames_are_refused() {
+        let mut fds = Fds::new();
+        let mut frames = requested(&[1]);
+        assert!(collect(&mut frames, 1, object(fds.open(), 0)).is_err());
+        assert!(frames.is_empty());
+        // the compositor did not see the frame being destroyed yet
+        let err = collect(&mut frames, 1, announce(1, export_dmabuf_frame::Flags::empty())).err().unwrap();
+        assert_eq!(format!("{}", err), "Frame event for unknown frame");
+        assert!(collect(&mut frames, 1, object(fds.open(), 0)).is_err());
+        assert!(collect(&mut frames, 1, ready()).is_err());
+        assert_eq!(fds.count(), 0);
+    }
+
+    #[test]
+    fn events_of_other_frames_leave_the_pending_one_alone() {
+        let mut fds = Fds::new();
+        let mut frames = requested(&[1, 2]);
+        assert!(collect(&mut frames, 1, announce(2, export_dmabuf_frame::Flags::empty())).unwrap().is_none());
+        assert!(collect(&mut frames, 1, object(fds.open(), 0)).unwrap().is_none());
+
+        // the next frame is ready before it was announced
+        let err = collect(&mut frames, 2, ready()).err().unwrap();
+        assert_eq!(format!("{}", err), "Ready event before Frame event");
+        assert_eq!(frames.iter().map(|pending| pending.id).collect::<Vec<_>>(), [1]);
+        assert_eq!(fds.count(), 1);
+        let err = collect(&mut frames, 3, announce(1, export_dmabuf_frame::Flags::empty())).err().unwrap();
+        assert_eq!(format!("{}", err), "Frame event for unknown frame");
+        assert_eq!(frames.len(), 1);
+
+        assert!(collect(&mut frames, 1, object(fds.open(), 1)).unwrap().is_none());
+        let collected = collect(&mut frames, 1, ready()).unwrap().unwrap();
+        assert!(frames.is_empty());
+        assert_eq!(collected.timestamp, Duration::new((1 << 32) | 2, 3));
+        assert_eq!(collected.captured.captured, Some(collected.timestamp));
+        assert_eq!(fds.count(), 2);
+        drop(collected);
+        assert_eq!(fds.count(), 0);
+    }
+}
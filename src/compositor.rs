@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// Compositors known to offer the wlr capture protocols
+const KNOWN_CAPTURE: &str = "sway, Hyprland, river, Wayfire and other wlroots based compositors";
+/// Compositors known to offer wl_drm, which is provided by mesa for compositors rendering through EGL
+const KNOWN_WL_DRM: &str = "sway, Hyprland, river, Wayfire and KDE Plasma";
+
+/// Best guess of the running compositor, from the environment it sets for its clients
+pub fn detect() -> String {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    if var("SWAYSOCK").is_some() {
+        return String::from("sway");
+    }
+    if var("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        return String::from("Hyprland");
+    }
+    if let Some(desktop) = var("XDG_CURRENT_DESKTOP") {
+        return desktop;
+    }
+    match var("WAYLAND_DISPLAY") {
+        Some(display) => format!("unknown compositor on {}", display),
+        None => String::from("unknown compositor"),
+    }
+}
+
+/// Error for a global the compositor does not advertise, `needed` tells what for
+pub fn missing_global(global: &str, needed: &str) -> anyhow::Error {
+    let known = if global == "wl_drm" { KNOWN_WL_DRM } else { KNOWN_CAPTURE };
+    anyhow::anyhow!(
+        "The compositor ({}) does not advertise {}, which is needed {}. It is known to be supported by {}.",
+        detect(),
+        global,
+        needed,
+        known
+    )
+}
+
+/// Error for wl_drm being advertised without the device following it within `timeout`
+pub fn missing_drm_device(timeout: Duration) -> anyhow::Error {
+    anyhow::anyhow!(
+        "The compositor ({}) advertises wl_drm, but did not send the device node of its gpu within {:?}. \
+         Check that its wl_drm global works, e.g. with wayland-info, or capture with --capture-backend screencopy.",
+        detect(),
+        timeout
+    )
+}
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use smithay::backend::{
+    allocator::{Format, Fourcc},
+    renderer::{gles2::Gles2Renderer, ImportDma},
+};
+
+use crate::render;
+
+use std::{collections::HashSet, convert::TryFrom, fmt};
+
+/// Parses a drm fourcc code like "XR24", as passed to `--force-format`
+pub fn parse_fourcc(input: &str) -> Result<Fourcc> {
+    let bytes = input.as_bytes();
+    if bytes.len() != 4 {
+        anyhow::bail!("A fourcc code has four characters, e.g. XR24: {}", input);
+    }
+    let code = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    Fourcc::try_from(code).map_err(|_| anyhow::anyhow!("Unknown fourcc code: {}", input))
+}
+
+/// Who makes use of a buffer the compositor offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consumer {
+    /// The target imports the dmabuf itself
+    Target,
+    /// The render gpu imports it and reads it back into linear memory
+    Readback,
+    /// Uploaded from shared memory to the target
+    Upload,
+    /// Neither gpu can use it, or `--force-format` asks for another one
+    Nobody,
+}
+
+impl fmt::Display for Consumer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Consumer::Target => write!(f, "imported by the target"),
+            Consumer::Readback => write!(f, "read back by the render gpu"),
+            Consumer::Upload => write!(f, "uploaded to the target"),
+            Consumer::Nobody => write!(f, "unusable"),
+        }
+    }
+}
+
+/// The buffers the target can consume, announced to the compositor as far as the capture protocol allows.
+///
+/// Screencopy lets us choose among the buffer types the compositor offers,
+/// export-dmabuf frames are only checked against them.
+pub struct CaptureConstraints {
+    /// Dmabufs the target imports directly
+    import: HashSet<Format>,
+    /// Dmabufs the render gpu imports and reads back
+    readback: HashSet<Format>,
+    /// Set by `--force-format`, every other format is refused
+    forced: Option<Fourcc>,
+    /// Offers of the compositor that were logged already
+    reported: HashSet<Vec<Fourcc>>,
+}
+
+impl CaptureConstraints {
+    pub fn new(forced: Option<Fourcc>) -> CaptureConstraints {
+        CaptureConstraints {
+            import: HashSet::new(),
+            readback: HashSet::new(),
+            forced,
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Derives the constraints from the formats the gpus import, keeps `--force-format`
+    pub fn update(&mut self, target: &Gles2Renderer, render: Option<&Gles2Renderer>, log: &slog::Logger) {
+        self.import = target.dmabuf_formats().copied().collect();
+        self.readback = render
+            .map(|render| render.dmabuf_formats().copied().collect())
+            .unwrap_or_default();
+        self.reported.clear();
+        slog::info!(log, "Capture constraints: {}", self);
+        slog::debug!(log, "Imported by the target: {:?}", self.import);
+        slog::debug!(log, "Read back by the render gpu: {:?}", self.readback);
+    }
+
+    /// Who uses a dmabuf of `format`, the target is preferred
+    pub fn dmabuf(&self, format: Format) -> Consumer {
+        if self.forced.map(|forced| forced != format.code).unwrap_or(false) {
+            Consumer::Nobody
+        } else if self.import.contains(&format) {
+            Consumer::Target
+        } else if self.readback.contains(&format) {
+            Consumer::Readback
+        } else {
+            Consumer::Nobody
+        }
+    }
+
+    /// Who uses a dmabuf of `code` with any modifier, screencopy does not offer a modifier
+    fn dmabuf_code(&self, code: Fourcc) -> Consumer {
+        let any = |formats: &HashSet<Format>| formats.iter().any(|format| format.code == code);
+        if self.forced.map(|forced| forced != code).unwrap_or(false) {
+            Consumer::Nobody
+        } else if any(&self.import) {
+            Consumer::Target
+        } else if any(&self.readback) {
+            Consumer::Readback
+        } else {
+            Consumer::Nobody
+        }
+    }
+
+    /// Whether an shm buffer of `code` can be uploaded
+    pub fn shm(&self, code: Fourcc) -> Consumer {
+        if self.forced.map(|forced| forced != code).unwrap_or(false) || render::memory_layout(code).is_none() {
+            Consumer::Nobody
+        } else {
+            Consumer::Upload
+        }
+    }
+
+    /// Fails for frames `--force-format` does not ask for
+    pub fn check(&self, code: Fourcc) -> Result<()> {
+        match self.forced {
+            Some(forced) if forced != code => {
+                anyhow::bail!("Compositor sent a {:?} frame, but --force-format asks for {:?}", code, forced)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Index of the shm buffer to use among the ones offered, whose unknown formats are `None`
+    pub fn pick_shm(&self, offers: &[Option<Fourcc>]) -> Option<usize> {
+        offers
+            .iter()
+            .position(|code| code.map(|code| self.shm(code) != Consumer::Nobody).unwrap_or(false))
+    }
+
+    /// Logs what the compositor offers and what we make of it, at info level the first time the same set is offered
+    pub fn report(&mut self, shm: &[Fourcc], dmabuf: &[Fourcc], picked: Option<Fourcc>, log: &slog::Logger) {
+        let offer = shm.iter().chain(dmabuf.iter()).copied().collect::<Vec<_>>();
+        let describe = |offers: &[Fourcc], consumer: &dyn Fn(Fourcc) -> Consumer| {
+            offers
+                .iter()
+                .map(|code| format!("{:?} ({})", code, consumer(*code)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let shm = describe(shm, &|code| self.shm(code));
+        let dmabuf = describe(dmabuf, &|code| self.dmabuf_code(code));
+        if self.reported.insert(offer) {
+            slog::info!(log, "Compositor offers shm [{}], dmabuf [{}], using {:?}", shm, dmabuf, picked);
+        } else {
+            slog::debug!(log, "Compositor offers shm [{}], dmabuf [{}], using {:?}", shm, dmabuf, picked);
+        }
+    }
+
+    /// Logs the format of an exported frame, at info level the first time
+    pub fn report_dmabuf(&mut self, format: Format, log: &slog::Logger) {
+        let consumer = self.dmabuf(format);
+        if self.reported.insert(vec![format.code]) {
+            slog::info!(log, "Compositor exports {:?} with {:?}, {}", format.code, format.modifier, consumer);
+        } else {
+            slog::debug!(log, "Compositor exports {:?} with {:?}, {}", format.code, format.modifier, consumer);
+        }
+    }
+}
+
+impl fmt::Display for CaptureConstraints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} formats imported by the target, {} read back by the render gpu",
+            self.import.len(),
+            self.readback.len()
+        )?;
+        if let Some(forced) = self.forced {
+            write!(f, ", forced to {:?}", forced)?;
+        }
+        Ok(())
+    }
+}
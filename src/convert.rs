@@ -0,0 +1,338 @@
+use rayon::prelude::*;
+
+use std::{
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{gpu::ColorDepth, render::ChannelOrder};
+
+// for the benchmarks
+pub use crate::render::{memory_layout, Layout};
+
+/// Frames smaller than this are converted on the calling thread, splitting them up costs more than it saves
+const PARALLEL_MIN_BYTES: usize = 1 << 20;
+/// Rows converted at once by a thread of the pool
+const BAND_ROWS: usize = 32;
+
+/// Buffers kept around for the next readbacks
+const MAX_SPARE: usize = 3;
+
+/// Converts pixels of the given layout to RGBA of the given depth in place.
+///
+/// 10 bit RGBA is packed like `GL_UNSIGNED_INT_2_10_10_10_REV` expects it,
+/// converting 8 bit pixels to 10 bit is not supported.
+/// Without `alpha` the padding bits are undefined and get replaced by an opaque value.
+pub fn swizzle(pixels: &mut [u8], layout: Layout, depth: ColorDepth) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        if layout.depth == ColorDepth::Eight {
+            if layout.order == ChannelOrder::Bgra {
+                pixel.swap(0, 2);
+            }
+            if !layout.alpha {
+                pixel[3] = 0xff;
+            }
+            continue;
+        }
+
+        let value = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        let channel = |shift: u32| (value >> shift) & 0x3ff;
+        let (r, g, b) = match layout.order {
+            ChannelOrder::Rgba => (channel(0), channel(10), channel(20)),
+            ChannelOrder::Bgra => (channel(20), channel(10), channel(0)),
+        };
+        let a = if layout.alpha { value >> 30 } else { 0x3 };
+        let converted = match depth {
+            ColorDepth::Ten => (r | g << 10 | b << 20 | a << 30).to_ne_bytes(),
+            ColorDepth::Eight => [(r >> 2) as u8, (g >> 2) as u8, (b >> 2) as u8, (a * 0x55) as u8],
+        };
+        pixel.copy_from_slice(&converted);
+    }
+}
+
+/// Splits conversions of large frames into bands of rows, which are converted in parallel
+#[derive(Clone)]
+pub struct Pool(Option<Arc<rayon::ThreadPool>>);
+
+impl Pool {
+    /// Converts everything on the calling thread
+    pub const SCALAR: Pool = Pool(None);
+
+    /// A pool of `threads` threads, fewer than 2 convert on the calling thread
+    pub fn new(threads: usize) -> Pool {
+        if threads < 2 {
+            return Pool::SCALAR;
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("convert-{}", i))
+            .build()
+            .expect("Failed to spawn conversion threads");
+        Pool(Some(Arc::new(pool)))
+    }
+
+    fn parallel(&self, len: usize) -> Option<&rayon::ThreadPool> {
+        self.0.as_deref().filter(|_| len >= PARALLEL_MIN_BYTES)
+    }
+
+    /// Like `swizzle`, for rows `stride` bytes apart
+    pub fn swizzle(&self, pixels: &mut [u8], stride: usize, layout: Layout, depth: ColorDepth) {
+        match self.parallel(pixels.len()) {
+            Some(pool) => pool.install(|| {
+                pixels
+                    .par_chunks_mut(stride * BAND_ROWS)
+                    .for_each(|band| swizzle(band, layout, depth))
+            }),
+            None => swizzle(pixels, layout, depth),
+        }
+    }
+
+    /// Copies `rows` rows of `row_len` bytes from `src` into `dst` and converts them like `swizzle`.
+    ///
+    /// Rows start at the beginning of both and are `src_stride` and `dst_stride` bytes apart.
+    /// Rows `src` is too short for are left alone.
+    #[allow(clippy::too_many_arguments)]
+    pub fn repack(
+        &self,
+        src: &[u8],
+        src_stride: usize,
+        dst: &mut [u8],
+        dst_stride: usize,
+        row_len: usize,
+        rows: usize,
+        layout: Layout,
+        depth: ColorDepth,
+    ) {
+        let repack_row = |(dst, src): (&mut [u8], &[u8])| {
+            if let (Some(dst), Some(src)) = (dst.get_mut(..row_len), src.get(..row_len)) {
+                dst.copy_from_slice(src);
+                swizzle(dst, layout, depth);
+            }
+        };
+        match self.parallel(rows * row_len) {
+            Some(pool) => pool.install(|| {
+                dst.par_chunks_mut(dst_stride)
+                    .zip(src.par_chunks(src_stride))
+                    .take(rows)
+                    .with_min_len(BAND_ROWS)
+                    .for_each(repack_row)
+            }),
+            None => dst
+                .chunks_mut(dst_stride)
+                .zip(src.chunks(src_stride))
+                .take(rows)
+                .for_each(repack_row),
+        }
+    }
+}
+
+/// Pixels read back from the render gpu, which are converted to RGBA
+pub struct Job<T> {
+    pub pixels: Vec<u8>,
+    pub meta: T,
+    stride: usize,
+    layout: Layout,
+    depth: ColorDepth,
+    seq: u64,
+}
+
+/// Converts read back frames off the event loop, so it is not stalled by large frames.
+///
+/// Frames are handed out newest first, frames overtaken by a newer one are dropped.
+pub struct Converter<T> {
+    jobs: Option<SyncSender<Job<T>>>,
+    done: Receiver<Job<T>>,
+    thread: Option<JoinHandle<()>>,
+    pool: Pool,
+    spare: Vec<Vec<u8>>,
+    next_seq: u64,
+    /// Frames older than this were already handed out
+    min_seq: u64,
+}
+
+impl<T: Send + 'static> Converter<T> {
+    /// Every frame is split among `threads` threads, one frame waits while another is converted
+    pub fn new(threads: usize) -> Converter<T> {
+        let (jobs, queue) = mpsc::sync_channel::<Job<T>>(1);
+        let (finished, done) = mpsc::channel();
+        let pool = Pool::new(threads);
+        let worker = pool.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("convert"))
+            .spawn(move || {
+                // ends once the sender is gone, everything queued before was processed
+                for mut job in queue {
+                    worker.swizzle(&mut job.pixels, job.stride, job.layout, job.depth);
+                    if finished.send(job).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Failed to spawn conversion thread");
+
+        Converter {
+            jobs: Some(jobs),
+            done,
+            thread: Some(thread),
+            pool,
+            spare: Vec::with_capacity(MAX_SPARE),
+            next_seq: 0,
+            min_seq: 0,
+        }
+    }
+
+    /// Converts on the event loop with the threads of the converter
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// A buffer to read back into, reusing the ones of earlier frames
+    pub fn buffer(&mut self) -> Vec<u8> {
+        self.spare.pop().unwrap_or_default()
+    }
+
+    pub fn recycle(&mut self, buffer: Vec<u8>) {
+        if self.spare.len() < MAX_SPARE {
+            self.spare.push(buffer);
+        }
+    }
+
+    /// Queues `pixels` with rows `stride` bytes apart for conversion from `layout` to RGBA of `depth`.
+    ///
+    /// Returns the pixels again, if a frame is already waiting.
+    pub fn submit(
+        &mut self,
+        pixels: Vec<u8>,
+        stride: usize,
+        layout: Layout,
+        depth: ColorDepth,
+        meta: T,
+    ) -> Result<(), Vec<u8>> {
+        let job = Job {
+            pixels,
+            meta,
+            stride,
+            layout,
+            depth,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        match self.jobs.as_ref().unwrap().try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Err(job.pixels),
+        }
+    }
+
+    /// The newest converted frame not handed out yet
+    pub fn latest(&mut self) -> Option<Job<T>> {
+        let mut latest: Option<Job<T>> = None;
+        while let Ok(job) = self.done.try_recv() {
+            let newer = job.seq >= self.min_seq && latest.as_ref().map(|x| job.seq > x.seq).unwrap_or(true);
+            let stale = if newer { latest.replace(job) } else { Some(job) };
+            if let Some(stale) = stale {
+                self.recycle(stale.pixels);
+            }
+        }
+        if let Some(job) = latest.as_ref() {
+            self.min_seq = job.seq + 1;
+        }
+        latest
+    }
+}
+
+impl<T> Drop for Converter<T> {
+    fn drop(&mut self) {
+        // closing the queue lets the worker finish what is queued and exit
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::memory_layout;
+    use smithay::backend::allocator::Fourcc;
+
+    /// Two pixels of red 0x10, green 0x20, blue 0x30 and alpha 0x80 in the memory order of `format`
+    fn pixels(format: Fourcc, alpha: u8) -> Vec<u8> {
+        let pixel = match format {
+            Fourcc::Argb8888 | Fourcc::Xrgb8888 => [0x30, 0x20, 0x10, alpha],
+            _ => [0x10, 0x20, 0x30, alpha],
+        };
+        pixel.iter().chain(pixel.iter()).copied().collect()
+    }
+
+    fn swizzled(format: Fourcc, alpha: u8) -> Vec<u8> {
+        let mut pixels = pixels(format, alpha);
+        swizzle(&mut pixels, memory_layout(format).unwrap(), ColorDepth::Eight);
+        pixels
+    }
+
+    #[test]
+    fn swizzle_argb8888() {
+        assert_eq!(swizzled(Fourcc::Argb8888, 0x80), [0x10, 0x20, 0x30, 0x80, 0x10, 0x20, 0x30, 0x80]);
+    }
+
+    #[test]
+    fn swizzle_xrgb8888() {
+        assert_eq!(swizzled(Fourcc::Xrgb8888, 0x42), [0x10, 0x20, 0x30, 0xff, 0x10, 0x20, 0x30, 0xff]);
+    }
+
+    #[test]
+    fn swizzle_abgr8888() {
+        assert_eq!(swizzled(Fourcc::Abgr8888, 0x80), [0x10, 0x20, 0x30, 0x80, 0x10, 0x20, 0x30, 0x80]);
+    }
+
+    #[test]
+    fn swizzle_xbgr8888() {
+        assert_eq!(swizzled(Fourcc::Xbgr8888, 0x42), [0x10, 0x20, 0x30, 0xff, 0x10, 0x20, 0x30, 0xff]);
+    }
+
+    #[test]
+    fn swizzle_ignores_trailing_bytes() {
+        let mut pixels = pixels(Fourcc::Argb8888, 0x80);
+        pixels.extend_from_slice(&[1, 2, 3]);
+        swizzle(&mut pixels, memory_layout(Fourcc::Argb8888).unwrap(), ColorDepth::Eight);
+        assert_eq!(&pixels[8..], [1, 2, 3]);
+    }
+
+    #[test]
+    fn repack_odd_width_with_padding() {
+        // 3 pixels of XRGB8888 per row, padded to 16 bytes with 0xee
+        let (width, height, src_stride, dst_stride) = (3, 2, 16, 14);
+        let mut src = vec![0xee; src_stride * height];
+        for row in 0..height {
+            for column in 0..width {
+                let at = row * src_stride + column * 4;
+                src[at..at + 4].copy_from_slice(&[column as u8, row as u8, 0x30, 0]);
+            }
+        }
+        let mut dst = vec![0x55; dst_stride * height];
+        let layout = memory_layout(Fourcc::Xrgb8888).unwrap();
+        Pool::SCALAR.repack(&src, src_stride, &mut dst, dst_stride, width * 4, height, layout, ColorDepth::Eight);
+        for row in 0..height {
+            let dst = &dst[row * dst_stride..(row + 1) * dst_stride];
+            for column in 0..width {
+                assert_eq!(dst[column * 4..column * 4 + 4], [0x30, row as u8, column as u8, 0xff]);
+            }
+            // the padding of neither buffer is touched
+            assert_eq!(dst[width * 4..], [0x55, 0x55]);
+        }
+    }
+
+    #[test]
+    fn repack_skips_rows_beyond_src() {
+        let src = vec![0x10; 8];
+        let mut dst = vec![0; 16];
+        let layout = memory_layout(Fourcc::Abgr8888).unwrap();
+        Pool::SCALAR.repack(&src, 8, &mut dst, 8, 8, 2, layout, ColorDepth::Eight);
+        assert_eq!(dst[..8], [0x10; 8]);
+        assert_eq!(dst[8..], [0; 8]);
+    }
+}
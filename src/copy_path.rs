@@ -0,0 +1,304 @@
+use smithay::backend::allocator::{dmabuf::Dmabuf, Buffer, Format};
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+/// Consecutive failed imports, before giving up on DirectImport
+const MAX_IMPORT_FAILURES: u32 = 3;
+
+/// Copy path requested on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyPathKind {
+    Auto,
+    Import,
+    Cpu,
+}
+
+impl CopyPathKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "import", "cpu"];
+}
+
+impl FromStr for CopyPathKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<CopyPathKind> {
+        match name {
+            "auto" => Ok(CopyPathKind::Auto),
+            "import" => Ok(CopyPathKind::Import),
+            "cpu" => Ok(CopyPathKind::Cpu),
+            x => anyhow::bail!("Unknown copy path: {}", x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImportState {
+    /// No import succeeded yet
+    Probing { failures: u32 },
+    Working { failures: u32 },
+    /// Imports of this format kept failing, it is tried again once the source changes its format or modifier
+    GaveUp(Format),
+}
+
+/// Why a frame could not be imported directly, for the log and the stats output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportFailure {
+    pub format: Format,
+    pub planes: usize,
+    pub size: (i32, i32),
+    /// The error of the import, or what `--verify-import` found
+    pub reason: String,
+    /// The import succeeded, but the texture does not hold the frame
+    pub garbage: bool,
+}
+
+impl ImportFailure {
+    pub fn of(buf: &Dmabuf, reason: impl Into<String>, garbage: bool) -> ImportFailure {
+        ImportFailure {
+            format: buf.format(),
+            planes: buf.num_planes(),
+            size: buf.size().into(),
+            reason: reason.into(),
+            garbage,
+        }
+    }
+}
+
+impl fmt::Display for ImportFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} with modifier {:?}, {} planes, {}x{}: {}",
+            self.format.code, self.format.modifier, self.planes, self.size.0, self.size.1, self.reason
+        )
+    }
+}
+
+/// Decides whether frames are imported directly or copied through the cpu.
+///
+/// In auto mode DirectImport is preferred, but a few failures in a row fall back to the cpu,
+/// so neither a single transient failure nor a broken import decide the path forever.
+#[derive(Debug)]
+pub struct CopyPath {
+    kind: CopyPathKind,
+    import: ImportState,
+    /// Latest failure, until an import works again
+    failure: Option<ImportFailure>,
+    /// Formats whose failures were logged already
+    reported: HashSet<Format>,
+}
+
+impl CopyPath {
+    pub fn new(kind: CopyPathKind) -> CopyPath {
+        CopyPath {
+            kind,
+            import: ImportState::Probing { failures: 0 },
+            failure: None,
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Starts probing again, e.g. after reconnecting to the compositor
+    pub fn reset(&mut self) {
+        self.import = ImportState::Probing { failures: 0 };
+    }
+
+    /// Whether a frame of `format` should be imported directly, before falling back to the cpu
+    pub fn try_import(&mut self, format: Format, importable: bool) -> bool {
+        match self.kind {
+            CopyPathKind::Import => true,
+            CopyPathKind::Cpu => false,
+            CopyPathKind::Auto if !importable => false,
+            CopyPathKind::Auto => match self.import {
+                ImportState::GaveUp(failed) if failed == format => false,
+                ImportState::GaveUp(_) => {
+                    self.import = ImportState::Probing { failures: 0 };
+                    true
+                }
+                _ => true,
+            },
+        }
+    }
+
+    /// The source switched to frames of `format`, whose import is probed like the first one.
+    ///
+    /// A format DirectImport was given up on stays given up.
+    pub fn format_changed(&mut self, format: Format) {
+        match self.import {
+            ImportState::GaveUp(failed) if failed == format => {}
+            _ => self.import = ImportState::Probing { failures: 0 },
+        }
+    }
+
+    /// Whether frames may be flipped onto the output as they are, before importing them
+    pub fn allow_scanout(&self) -> bool {
+        self.kind == CopyPathKind::Auto
+    }
+
+    /// Whether frames may be copied through the cpu
+    pub fn allow_cpu(&self) -> bool {
+        self.kind != CopyPathKind::Import
+    }
+
+    /// Whether no import succeeded yet, the next one decides if DirectImport works
+    pub fn probing(&self) -> bool {
+        matches!(self.import, ImportState::Probing { .. })
+    }
+
+    /// Returns true, if this was the first successful import
+    pub fn import_succeeded(&mut self) -> bool {
+        let first = self.probing();
+        self.import = ImportState::Working { failures: 0 };
+        self.failure = None;
+        first
+    }
+
+    /// Why the latest import failed, `None` once one works again
+    pub fn failure(&self) -> Option<&ImportFailure> {
+        self.failure.as_ref()
+    }
+
+    /// Remembers `failure`, returns true if it is the first one of its format
+    pub fn record_failure(&mut self, failure: ImportFailure) -> bool {
+        let first = self.reported.insert(failure.format);
+        self.failure = Some(failure);
+        first
+    }
+
+    /// Gives up on DirectImport of `format` at once, as its imports don't show the frame
+    pub fn import_garbage(&mut self, format: Format) {
+        self.import = ImportState::GaveUp(format);
+    }
+
+    /// Returns true, if DirectImport is given up for `format`
+    pub fn import_failed(&mut self, format: Format) -> bool {
+        let failures = match self.import {
+            ImportState::Probing { failures } | ImportState::Working { failures } => failures + 1,
+            ImportState::GaveUp(_) => return false,
+        };
+        self.import = match self.import {
+            _ if failures >= MAX_IMPORT_FAILURES => ImportState::GaveUp(format),
+            ImportState::Probing { .. } => ImportState::Probing { failures },
+            _ => ImportState::Working { failures },
+        };
+        matches!(self.import, ImportState::GaveUp(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smithay::backend::allocator::{Fourcc, Modifier};
+
+    fn format(code: Fourcc) -> Format {
+        Format {
+            code,
+            modifier: Modifier::Linear,
+        }
+    }
+
+    #[test]
+    fn auto_imports_first() {
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        assert!(path.probing());
+        assert!(path.try_import(format(Fourcc::Argb8888), true));
+        assert!(path.import_succeeded());
+        assert!(!path.probing());
+        // only the first success counts as such
+        assert!(!path.import_succeeded());
+    }
+
+    #[test]
+    fn auto_falls_back_to_cpu() {
+        let argb = format(Fourcc::Argb8888);
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        for _ in 1..MAX_IMPORT_FAILURES {
+            assert!(path.try_import(argb, true));
+            assert!(!path.import_failed(argb));
+        }
+        assert!(path.try_import(argb, true));
+        assert!(path.import_failed(argb));
+        assert!(!path.try_import(argb, true));
+        assert!(path.allow_cpu());
+        // given up already
+        assert!(!path.import_failed(argb));
+    }
+
+    #[test]
+    fn successes_reset_the_failures() {
+        let argb = format(Fourcc::Argb8888);
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        path.import_succeeded();
+        for _ in 0..MAX_IMPORT_FAILURES * 2 {
+            assert!(!path.import_failed(argb));
+            path.import_succeeded();
+        }
+        assert!(path.try_import(argb, true));
+    }
+
+    #[test]
+    fn recovers_on_format_change() {
+        let (argb, xrgb) = (format(Fourcc::Argb8888), format(Fourcc::Xrgb8888));
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        path.import_garbage(argb);
+        assert!(!path.try_import(argb, true));
+        // the same format stays given up
+        path.format_changed(argb);
+        assert!(!path.try_import(argb, true));
+        path.format_changed(xrgb);
+        assert!(path.probing());
+        assert!(path.try_import(xrgb, true));
+        // frames of another format probe again right away
+        path.import_garbage(argb);
+        assert!(path.try_import(xrgb, true));
+        assert!(path.probing());
+    }
+
+    #[test]
+    fn recovers_on_reset() {
+        let argb = format(Fourcc::Argb8888);
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        path.import_garbage(argb);
+        path.reset();
+        assert!(path.probing());
+        assert!(path.try_import(argb, true));
+    }
+
+    #[test]
+    fn auto_skips_unimportable() {
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        assert!(!path.try_import(format(Fourcc::Argb8888), false));
+        assert!(path.allow_scanout());
+    }
+
+    #[test]
+    fn forced_paths() {
+        let argb = format(Fourcc::Argb8888);
+        let mut import = CopyPath::new(CopyPathKind::Import);
+        import.import_garbage(argb);
+        assert!(import.try_import(argb, false));
+        assert!(!import.allow_cpu());
+        assert!(!import.allow_scanout());
+        let mut cpu = CopyPath::new(CopyPathKind::Cpu);
+        assert!(!cpu.try_import(argb, true));
+        assert!(cpu.allow_cpu());
+        assert!(!cpu.allow_scanout());
+    }
+
+    #[test]
+    fn failures_are_reported_once_per_format() {
+        let failure = |code| ImportFailure {
+            format: format(code),
+            planes: 1,
+            size: (64, 64),
+            reason: String::from("EINVAL"),
+            garbage: false,
+        };
+        let mut path = CopyPath::new(CopyPathKind::Auto);
+        assert!(path.record_failure(failure(Fourcc::Argb8888)));
+        assert!(!path.record_failure(failure(Fourcc::Argb8888)));
+        assert!(path.record_failure(failure(Fourcc::Xrgb8888)));
+        assert_eq!(path.failure().map(|failure| failure.format), Some(format(Fourcc::Xrgb8888)));
+        path.import_succeeded();
+        assert_eq!(path.failure(), None);
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use smithay::utils::{Logical, Point};
+
+use crate::kms::HardwareCursor;
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// Cursor size if XCURSOR_SIZE is not set
+const DEFAULT_SIZE: u32 = 24;
+
+/// How the cursor gets onto the output, requested with `--cursor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorMode {
+    /// Part of the captured frames, lagging behind by the whole capture to scanout latency
+    Composited,
+    /// Shown on the cursor plane of the output and moved independently of the frames
+    Plane,
+}
+
+impl CursorMode {
+    pub const VARIANTS: &'static [&'static str] = &["composited", "plane"];
+}
+
+impl FromStr for CursorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<CursorMode> {
+        match name {
+            "composited" => Ok(CursorMode::Composited),
+            "plane" => Ok(CursorMode::Plane),
+            x => anyhow::bail!("Unknown cursor mode: {}", x),
+        }
+    }
+}
+
+/// Image of the default cursor of the cursor theme
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    pub hotspot: (i32, i32),
+    /// ARGB8888, rows are `width` pixels
+    pub pixels: Vec<u8>,
+}
+
+impl CursorImage {
+    /// Loads the arrow of the theme in XCURSOR_THEME, in the size closest to XCURSOR_SIZE times `scale`
+    pub fn load(scale: i32) -> Result<CursorImage> {
+        let theme = std::env::var("XCURSOR_THEME").unwrap_or_else(|_| String::from("default"));
+        let size = std::env::var("XCURSOR_SIZE")
+            .ok()
+            .and_then(|size| size.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_SIZE)
+            * scale.max(1) as u32;
+        let path = xcursor::CursorTheme::load(&theme)
+            .load_icon("left_ptr")
+            .with_context(|| format!("Cursor theme {} has no left_ptr cursor", theme))?;
+        let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let image = xcursor::parser::parse_xcursor(&data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?
+            .into_iter()
+            .min_by_key(|image| (image.size as i64 - size as i64).abs())
+            .with_context(|| format!("{} holds no images", path.display()))?;
+        Ok(CursorImage {
+            width: image.width,
+            height: image.height,
+            hotspot: (image.xhot as i32, image.yhot as i32),
+            pixels: image.pixels_argb,
+        })
+    }
+}
+
+/// Asks the compositor where the pointer is, as clients only learn that over their own surfaces.
+///
+/// Only Hyprland answers this over its ipc socket.
+pub struct PointerSource {
+    socket: PathBuf,
+}
+
+impl PointerSource {
+    /// `None` if the compositor is not known to tell the pointer position
+    pub fn detect() -> Option<PointerSource> {
+        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE").ok()?;
+        // moved from /tmp into the runtime dir in later versions
+        let mut candidates = Vec::new();
+        if let Some(runtime) = std::env::var_os("XDG_RUNTIME_DIR") {
+            candidates.push(PathBuf::from(runtime).join("hypr").join(&signature).join(".socket.sock"));
+        }
+        candidates.push(PathBuf::from("/tmp/hypr").join(&signature).join(".socket.sock"));
+        candidates
+            .into_iter()
+            .find(|path| path.exists())
+            .map(|socket| PointerSource { socket })
+    }
+
+    /// Position of the pointer in the global logical space of the compositor
+    pub fn position(&self) -> Result<Point<f64, Logical>> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to {}", self.socket.display()))?;
+        stream.write_all(b"j/cursorpos").context("Failed to ask for the pointer position")?;
+        let mut reply = String::new();
+        stream
+            .read_to_string(&mut reply)
+            .context("Failed to read the pointer position")?;
+        let reply: serde_json::Value = serde_json::from_str(&reply).context("Failed to parse the pointer position")?;
+        let coordinate = |name: &str| {
+            reply[name]
+                .as_f64()
+                .with_context(|| format!("Pointer position lacks {}", name))
+        };
+        Ok(Point::from((coordinate("x")?, coordinate("y")?)))
+    }
+}
+
+/// Cursor shown on the cursor plane of the target by `--cursor plane`
+pub struct CursorPlane {
+    pub pointer: PointerSource,
+    pub image: CursorImage,
+    /// `None` while there is no target
+    pub hardware: Option<HardwareCursor>,
+}
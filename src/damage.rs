@@ -0,0 +1,220 @@
+use smithay::utils::{Buffer, Rectangle, Size};
+
+use std::{collections::hash_map::DefaultHasher, hash::Hasher, str::FromStr};
+
+/// Beyond this many rectangles updating the whole frame is cheaper
+pub const MAX_RECTS: usize = 16;
+
+/// Pixels sampled along each axis by `sample_hash`
+const SAMPLES_PER_AXIS: i32 = 64;
+
+type Rect = Rectangle<i32, Buffer>;
+
+/// How frames that did not change are detected, which are then neither uploaded nor swapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleDetect {
+    Off,
+    /// Frames without damage, reported by screencopy or found by comparing frames read back
+    Damage,
+    /// Frames whose hash of a sparse sample of pixels did not change
+    Hash,
+}
+
+impl IdleDetect {
+    pub const VARIANTS: &'static [&'static str] = &["off", "damage", "hash"];
+}
+
+impl FromStr for IdleDetect {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<IdleDetect> {
+        match name {
+            "off" => Ok(IdleDetect::Off),
+            "damage" => Ok(IdleDetect::Damage),
+            "hash" => Ok(IdleDetect::Hash),
+            x => anyhow::bail!("Unknown idle detection: {}", x),
+        }
+    }
+}
+
+/// Overlapping or sharing an edge
+fn touches(a: &Rect, b: &Rect) -> bool {
+    a.loc.x <= b.loc.x + b.size.w
+        && b.loc.x <= a.loc.x + a.size.w
+        && a.loc.y <= b.loc.y + b.size.h
+        && b.loc.y <= a.loc.y + a.size.h
+}
+
+/// Smallest rectangle containing both
+fn union(a: &Rect, b: &Rect) -> Rect {
+    let x = a.loc.x.min(b.loc.x);
+    let y = a.loc.y.min(b.loc.y);
+    let x2 = (a.loc.x + a.size.w).max(b.loc.x + b.size.w);
+    let y2 = (a.loc.y + a.size.h).max(b.loc.y + b.size.h);
+    Rectangle::from_loc_and_size((x, y), (x2 - x, y2 - y))
+}
+
+/// Part of `rect` inside of `bounds`, relative to `bounds`
+pub fn clip(rect: Rect, bounds: Rect) -> Option<Rect> {
+    let x = rect.loc.x.max(bounds.loc.x);
+    let y = rect.loc.y.max(bounds.loc.y);
+    let x2 = (rect.loc.x + rect.size.w).min(bounds.loc.x + bounds.size.w);
+    let y2 = (rect.loc.y + rect.size.h).min(bounds.loc.y + bounds.size.h);
+    if x2 <= x || y2 <= y {
+        return None;
+    }
+    Some(Rectangle::from_loc_and_size(
+        (x - bounds.loc.x, y - bounds.loc.y),
+        (x2 - x, y2 - y),
+    ))
+}
+
+/// Merges touching rectangles, until all of them are disjoint.
+///
+/// Returns `None`, if more than `MAX_RECTS` remain and the whole frame should be updated instead.
+pub fn merge(mut rects: Vec<Rect>) -> Option<Vec<Rect>> {
+    rects.retain(|rect| rect.size.w > 0 && rect.size.h > 0);
+    // merging is quadratic, don't bother with frames that are damaged all over
+    if rects.len() > MAX_RECTS * 16 {
+        return None;
+    }
+    let mut merged = true;
+    while merged {
+        merged = false;
+        'outer: for i in 0..rects.len() {
+            for j in (i + 1)..rects.len() {
+                if touches(&rects[i], &rects[j]) {
+                    let other = rects.swap_remove(j);
+                    rects[i] = union(&rects[i], &other);
+                    merged = true;
+                    break 'outer;
+                }
+            }
+        }
+    }
+    if rects.len() > MAX_RECTS {
+        None
+    } else {
+        Some(rects)
+    }
+}
+
+/// Rows of `width` pixels, that differ between the images, merged into rectangles.
+///
+/// Rows are `stride` bytes apart with 4 bytes per pixel in both images.
+pub fn diff_rows(old: &[u8], new: &[u8], stride: usize, width: i32) -> Vec<Rect> {
+    let row_len = width as usize * 4;
+    let mut rects: Vec<Rect> = Vec::new();
+    for (y, (old, new)) in old.chunks(stride).zip(new.chunks(stride)).enumerate() {
+        if old.len() < row_len || new.len() < row_len || old[..row_len] == new[..row_len] {
+            continue;
+        }
+        let y = y as i32;
+        match rects.last_mut() {
+            // extend the run of changed rows
+            Some(last) if last.loc.y + last.size.h == y => last.size.h += 1,
+            _ => rects.push(Rectangle::from_loc_and_size((0, y), (width, 1))),
+        }
+    }
+    rects
+}
+
+/// Hash of a grid of pixels spread over an image of `size`, whose rows are `stride` bytes apart
+/// with 4 bytes per pixel. Changes between the sampled pixels go unnoticed.
+pub fn sample_hash(pixels: &[u8], stride: usize, size: Size<i32, Buffer>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let (columns, rows) = (SAMPLES_PER_AXIS.min(size.w), SAMPLES_PER_AXIS.min(size.h));
+    for y in 0..rows {
+        let row = (y * size.h / rows) as usize * stride;
+        for x in 0..columns {
+            let offset = row + (x * size.w / columns) as usize * 4;
+            if let Some(pixel) = pixels.get(offset..offset + 4) {
+                hasher.write(pixel);
+            }
+        }
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, w: i32, h: i32) -> Rect {
+        Rectangle::from_loc_and_size((x, y), (w, h))
+    }
+
+    fn sorted(rects: Option<Vec<Rect>>) -> Vec<Rect> {
+        let mut rects = rects.unwrap();
+        rects.sort_by_key(|rect| (rect.loc.x, rect.loc.y));
+        rects
+    }
+
+    #[test]
+    fn merge_overlapping() {
+        let merged = merge(vec![rect(0, 0, 10, 10), rect(5, 5, 10, 10)]);
+        assert_eq!(merged, Some(vec![rect(0, 0, 15, 15)]));
+    }
+
+    #[test]
+    fn merge_adjacent() {
+        let merged = merge(vec![rect(0, 0, 10, 10), rect(10, 0, 10, 10)]);
+        assert_eq!(merged, Some(vec![rect(0, 0, 20, 10)]));
+        let merged = merge(vec![rect(0, 0, 10, 10), rect(0, 10, 10, 5)]);
+        assert_eq!(merged, Some(vec![rect(0, 0, 10, 15)]));
+    }
+
+    #[test]
+    fn merge_keeps_disjoint() {
+        let merged = merge(vec![rect(0, 0, 10, 10), rect(11, 0, 10, 10)]);
+        assert_eq!(sorted(merged), vec![rect(0, 0, 10, 10), rect(11, 0, 10, 10)]);
+    }
+
+    #[test]
+    fn merge_transitively() {
+        // the first and last only touch through the union of the others
+        let merged = merge(vec![rect(0, 0, 4, 4), rect(20, 0, 4, 4), rect(4, 0, 4, 4), rect(8, 2, 12, 1)]);
+        assert_eq!(merged, Some(vec![rect(0, 0, 24, 4)]));
+    }
+
+    #[test]
+    fn merge_drops_empty() {
+        assert_eq!(merge(vec![rect(0, 0, 0, 10), rect(5, 5, 10, 0)]), Some(vec![]));
+    }
+
+    #[test]
+    fn merge_gives_up_beyond_max() {
+        let rects = (0..MAX_RECTS as i32 + 1).map(|i| rect(i * 2, 0, 1, 1)).collect::<Vec<_>>();
+        assert_eq!(merge(rects.clone()), None);
+        assert_eq!(merge(rects[..MAX_RECTS].to_vec()).map(|rects| rects.len()), Some(MAX_RECTS));
+    }
+
+    #[test]
+    fn clip_inside() {
+        assert_eq!(clip(rect(15, 25, 5, 5), rect(10, 20, 100, 100)), Some(rect(5, 5, 5, 5)));
+    }
+
+    #[test]
+    fn clip_partially_outside() {
+        assert_eq!(clip(rect(0, 0, 20, 30), rect(10, 20, 100, 100)), Some(rect(0, 0, 10, 10)));
+        assert_eq!(clip(rect(100, 110, 50, 50), rect(10, 20, 100, 100)), Some(rect(90, 90, 10, 10)));
+    }
+
+    #[test]
+    fn clip_out_of_bounds() {
+        assert_eq!(clip(rect(0, 0, 5, 5), rect(10, 20, 100, 100)), None);
+        // sharing an edge leaves nothing
+        assert_eq!(clip(rect(0, 20, 10, 10), rect(10, 20, 100, 100)), None);
+        assert_eq!(clip(rect(200, 200, 5, 5), rect(10, 20, 100, 100)), None);
+    }
+
+    #[test]
+    fn diff_rows_merges_runs() {
+        let old = vec![0u8; 4 * 2 * 5];
+        let mut new = old.clone();
+        for row in [1, 2, 4] {
+            new[row * 8 + 4] = 1;
+        }
+        assert_eq!(diff_rows(&old, &new, 8, 2), vec![rect(0, 1, 2, 2), rect(0, 4, 2, 1)]);
+    }
+}
@@ -24,28 +24,79 @@ mod generated {
     }
 }
 
+use anyhow::{Context, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use smithay::reexports::drm::Device as DrmDeviceNode;
+
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    io::{self, ErrorKind},
+    path::Path,
     rc::Rc,
+    time::{Duration, Instant},
 };
 
-use wayland_client::{Attached, DispatchData, protocol::wl_registry};
+use wayland_client::{Attached, DispatchData, EventQueue, protocol::wl_registry};
+
+/// How long the compositor may take to send the device or confirm authentication
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct WlDrmHandler {
     global: Option<Attached<wl_drm::WlDrm>>,
     path: Rc<RefCell<Option<String>>>,
+    authenticated: Rc<Cell<bool>>,
 }
 
 impl WlDrmHandler {
     pub fn new() -> WlDrmHandler {
-        WlDrmHandler { global: None, path: Rc::new(RefCell::new(None)) }
+        WlDrmHandler {
+            global: None,
+            path: Rc::new(RefCell::new(None)),
+            authenticated: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Device node of the compositors gpu, `None` if wl_drm was not advertised or its device did not arrive yet
+    pub fn path(&self) -> Option<String> {
+        self.path.borrow().clone()
+    }
+
+    /// Whether the compositor advertised wl_drm, the device is sent after binding it
+    pub fn advertised(&self) -> bool {
+        self.global.is_some()
+    }
+
+    /// Requests authentication of the opened `device`.
+    ///
+    /// The result is only known after a roundtrip, check it with `authenticated`.
+    /// Render nodes do not need to be authenticated and are marked as such immediately.
+    pub fn authenticate<D: DrmDeviceNode>(&self, path: &Path, device: &D) -> Result<()> {
+        if is_render_node(path) {
+            self.authenticated.set(true);
+            return Ok(());
+        }
+        let global = self.global.as_ref().context("WlDrm was not advertised")?;
+        let magic = device
+            .generate_auth_token()
+            .with_context(|| format!("Failed to get drm magic for {}", path.display()))?;
+        global.authenticate(magic.into());
+        Ok(())
     }
 
-    pub fn path(&self) -> String {
-        self.path.borrow().clone().expect("WlDrm was not advertised")
+    /// If the compositor did confirm our authentication request
+    pub fn authenticated(&self) -> bool {
+        self.authenticated.get()
     }
 }
 
+/// Render nodes allow rendering without being drm master or authenticated
+fn is_render_node(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("renderD"))
+        .unwrap_or(false)
+}
+
 impl smithay_client_toolkit::environment::GlobalHandler<wl_drm::WlDrm> for WlDrmHandler {
     fn created(
         &mut self,
@@ -56,13 +107,15 @@ impl smithay_client_toolkit::environment::GlobalHandler<wl_drm::WlDrm> for WlDrm
     ) {
         let wl_drm = registry.bind::<wl_drm::WlDrm>(1, id);
         let path_store = self.path.clone();
+        let authenticated = self.authenticated.clone();
         wl_drm.quick_assign(move |_, event, _| {
             match event {
+                // sending it again is allowed, the last one counts
                 wl_drm::Event::Device { name } => {
                     *path_store.borrow_mut() = Some(name);
                 },
                 wl_drm::Event::Authenticated => {
-                    println!("AUTHENTICATED");
+                    authenticated.set(true);
                 }
                 _ => {},
             }
@@ -73,4 +126,203 @@ impl smithay_client_toolkit::environment::GlobalHandler<wl_drm::WlDrm> for WlDrm
     fn get(&self) -> Option<Attached<wl_drm::WlDrm>> {
         self.global.clone()
     }
-}
\ No newline at end of file
+}
+
+/// What `dispatch_until` needs of an event queue
+pub trait Dispatch {
+    /// Dispatches the events read so far, those without handler are logged and dropped
+    fn dispatch_pending(&mut self, log: &slog::Logger) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// Reads the events arriving from the compositor within `timeout`, if any
+    fn read_events(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+/// Dispatches without any data, which only works before handlers of objects needing the `WaylandState` exist
+impl Dispatch for EventQueue {
+    fn dispatch_pending(&mut self, log: &slog::Logger) -> io::Result<()> {
+        EventQueue::dispatch_pending(self, &mut (), |event, object, _| {
+            slog::debug!(
+                log,
+                "Ignoring orphan event: {}@{} : {}",
+                event.interface,
+                object.as_ref().id(),
+                event.name
+            );
+        })
+        .map(|_| ())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.display().flush()
+    }
+
+    fn read_events(&mut self, timeout: Duration) -> io::Result<()> {
+        // another reader got there first, its events are dispatched next
+        let guard = match self.prepare_read() {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        let mut fds = [PollFd::new(self.display().get_connection_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, (timeout.as_millis() as i32).max(1)) {
+            Ok(ready) if ready > 0 => match guard.read_events() {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+                result => result,
+            },
+            // timed out or interrupted, the deadline is checked again
+            _ => {
+                guard.cancel();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Dispatches the events of `event_queue` until `done` holds, reading more from the compositor for up to `timeout`.
+///
+/// Returns whether `done` held in time. Events without handler are logged and dropped, like in `sync_roundtrip`.
+pub fn dispatch_until(
+    event_queue: &mut impl Dispatch,
+    timeout: Duration,
+    mut done: impl FnMut() -> bool,
+    log: &slog::Logger,
+) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        event_queue.dispatch_pending(log)?;
+        if done() {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        match event_queue.flush() {
+            // the rest goes out with the next flush
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+        event_queue.read_events(deadline - now)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    /// Stands in for the compositor, sending the events of `reads` one read at a time
+    #[derive(Default)]
+    struct Scripted {
+        /// Events of each read, `None` for reads that wake up without any
+        reads: VecDeque<Option<&'static str>>,
+        pending: Vec<&'static str>,
+        dispatched: Rc<RefCell<Vec<&'static str>>>,
+        flush_error: Option<ErrorKind>,
+        read_error: Option<ErrorKind>,
+        flushes: usize,
+        timeouts: usize,
+    }
+
+    impl Dispatch for Scripted {
+        fn dispatch_pending(&mut self, _log: &slog::Logger) -> io::Result<()> {
+            self.dispatched.borrow_mut().append(&mut self.pending);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            match self.flush_error {
+                Some(kind) => Err(kind.into()),
+                None => Ok(()),
+            }
+        }
+
+        fn read_events(&mut self, timeout: Duration) -> io::Result<()> {
+            if let Some(kind) = self.read_error {
+                return Err(kind.into());
+            }
+            match self.reads.pop_front().flatten() {
+                Some(event) => self.pending.push(event),
+                None => {
+                    self.timeouts += 1;
+                    std::thread::sleep(timeout.min(Duration::from_millis(5)));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn script(reads: &[Option<&'static str>]) -> Scripted {
+        Scripted {
+            reads: reads.iter().copied().collect(),
+            ..Scripted::default()
+        }
+    }
+
+    /// Dispatches `queue` until the device arrived
+    fn wait_for_device(queue: &mut Scripted, timeout: Duration) -> io::Result<bool> {
+        let dispatched = queue.dispatched.clone();
+        dispatch_until(queue, timeout, || dispatched.borrow().contains(&"device"), &log())
+    }
+
+    #[test]
+    fn events_arriving() {
+        let mut queue = script(&[Some("format"), None, Some("capabilities"), Some("device"), Some("format")]);
+        assert!(wait_for_device(&mut queue, EVENT_TIMEOUT).unwrap());
+        assert_eq!(*queue.dispatched.borrow(), ["format", "capabilities", "device"]);
+        assert_eq!((queue.flushes, queue.timeouts), (4, 1));
+        // the rest is left to the event loop
+        assert_eq!(queue.reads, [Some("format")]);
+    }
+
+    #[test]
+    fn events_read_already() {
+        let mut queue = script(&[Some("format")]);
+        queue.pending.push("device");
+        assert!(wait_for_device(&mut queue, EVENT_TIMEOUT).unwrap());
+        assert_eq!((queue.flushes, queue.reads.len()), (0, 1));
+    }
+
+    #[test]
+    fn events_timing_out() {
+        let timeout = Duration::from_millis(50);
+        let mut queue = script(&[Some("format"), Some("capabilities")]);
+        let start = Instant::now();
+        assert!(!wait_for_device(&mut queue, timeout).unwrap());
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(*queue.dispatched.borrow(), ["format", "capabilities"]);
+        assert!(queue.timeouts >= 1);
+
+        // a compositor that never sends anything
+        let mut queue = script(&[]);
+        assert!(!wait_for_device(&mut queue, Duration::ZERO).unwrap());
+        assert_eq!(queue.flushes, 0);
+    }
+
+    #[test]
+    fn connection_errors() {
+        // requests that did not fit go out with the next flush
+        let mut queue = Scripted {
+            flush_error: Some(ErrorKind::WouldBlock),
+            ..script(&[Some("device")])
+        };
+        assert!(wait_for_device(&mut queue, EVENT_TIMEOUT).unwrap());
+
+        let mut queue = Scripted {
+            flush_error: Some(ErrorKind::BrokenPipe),
+            ..script(&[Some("device")])
+        };
+        assert_eq!(wait_for_device(&mut queue, EVENT_TIMEOUT).unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+        let mut queue = Scripted {
+            read_error: Some(ErrorKind::ConnectionReset),
+            ..script(&[Some("device")])
+        };
+        assert_eq!(wait_for_device(&mut queue, EVENT_TIMEOUT).unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+}
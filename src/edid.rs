@@ -0,0 +1,128 @@
+use anyhow::Result;
+use smithay::reexports::drm::control::{connector, Device as ControlDevice};
+
+use std::fmt;
+
+/// Fixed pattern every EDID starts with
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+/// Size of the base block, extension blocks follow it
+const BLOCK_SIZE: usize = 128;
+/// Offsets of the four 18 byte descriptors of the base block
+const DESCRIPTORS: [usize; 4] = [54, 72, 90, 108];
+/// Tags of display descriptors holding text
+const TAG_SERIAL: u8 = 0xff;
+const TAG_NAME: u8 = 0xfc;
+
+/// What a monitor tells about itself in its EDID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edid {
+    /// Three letter PNP id of the manufacturer, e.g. "DEL"
+    pub manufacturer: String,
+    pub product: u16,
+    /// Model name, e.g. "DELL U2720Q"
+    pub name: Option<String>,
+    /// Serial number, either as text or the numeric one if the monitor only has that
+    pub serial: Option<String>,
+}
+
+/// Text of a display descriptor, which ends with a newline and is padded with spaces
+fn descriptor_text(data: &[u8]) -> Option<String> {
+    let text = data.split(|byte| *byte == b'\n').next().unwrap_or(data);
+    let text = String::from_utf8_lossy(text).trim().to_string();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+impl Edid {
+    /// Parses the base block of an EDID, extension blocks are ignored
+    pub fn parse(data: &[u8]) -> Result<Edid> {
+        if data.len() < BLOCK_SIZE || data[..8] != HEADER {
+            anyhow::bail!("Not an EDID");
+        }
+        // three letters of 5 bits each, 1 being 'A'
+        let id = u16::from_be_bytes([data[8], data[9]]);
+        let manufacturer = [10, 5, 0]
+            .iter()
+            .map(|shift| (b'A' - 1 + ((id >> shift) & 0x1f) as u8) as char)
+            .collect();
+        let product = u16::from_le_bytes([data[10], data[11]]);
+        let serial_number = u32::from_le_bytes([data[12], data[13], data[14], data[15]]);
+
+        let mut name = None;
+        let mut serial = None;
+        for offset in DESCRIPTORS.iter() {
+            let descriptor = &data[*offset..*offset + 18];
+            // display descriptors have no pixel clock
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                continue;
+            }
+            match descriptor[3] {
+                TAG_NAME => name = descriptor_text(&descriptor[5..]),
+                TAG_SERIAL => serial = descriptor_text(&descriptor[5..]),
+                _ => {}
+            }
+        }
+        if serial.is_none() && serial_number != 0 {
+            serial = Some(serial_number.to_string());
+        }
+
+        Ok(Edid {
+            manufacturer,
+            product,
+            name,
+            serial,
+        })
+    }
+
+    /// Whether `wanted` is part of the name or serial, ignoring case
+    pub fn matches(&self, wanted: &str) -> bool {
+        let wanted = wanted.to_lowercase();
+        self.name
+            .iter()
+            .chain(self.serial.iter())
+            .any(|value| value.to_lowercase().contains(&wanted))
+    }
+
+    /// Tells the monitor apart from others across connectors and runs, e.g. "DEL-a0b4-ABC123"
+    pub fn identity(&self) -> String {
+        match self.serial.as_ref() {
+            Some(serial) => format!("{}-{:04x}-{}", self.manufacturer, self.product, serial),
+            None => format!("{}-{:04x}", self.manufacturer, self.product),
+        }
+    }
+}
+
+impl fmt::Display for Edid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.as_ref() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{} {:04x}", self.manufacturer, self.product)?,
+        }
+        if let Some(serial) = self.serial.as_ref() {
+            write!(f, ", serial {}", serial)?;
+        }
+        Ok(())
+    }
+}
+
+/// EDID of the monitor plugged into `connector`, `None` if there is none
+pub fn read<D: ControlDevice>(device: &D, connector: connector::Handle) -> Result<Option<Edid>> {
+    let props = device.get_properties(connector)?;
+    let (handles, values) = props.as_props_and_values();
+    for (handle, value) in handles.iter().zip(values.iter()) {
+        let info = device.get_property(*handle)?;
+        if info.name().to_str() != Ok("EDID") {
+            continue;
+        }
+        // the value is the id of the blob, which is 0 without monitor
+        if *value == 0 {
+            return Ok(None);
+        }
+        let data = device.get_property_blob(*value)?;
+        return Ok(Some(Edid::parse(&data)?));
+    }
+    Ok(None)
+}
@@ -1,5 +1,5 @@
 use anyhow::{Context, Result};
-use nix::{libc::{major, minor}, sys::stat::fstat};
+use nix::{libc::{dev_t, major, minor}, sys::stat::fstat};
 use smithay::backend::{egl::{EGLError, SwapBuffersError, display::EGLDisplayHandle, native::{EGLNativeDisplay, EGLNativeSurface, EGLPlatform}}};
 use smithay::reexports::drm::control::{crtc, plane};
 
@@ -8,9 +8,12 @@ use super::gpu::Fd;
 use std::{
     cell::Cell,
     ffi::CStr,
+    path::Path,
     ptr,
+    rc::Rc,
+    str::FromStr,
     os::unix::{
-        io::AsRawFd,
+        io::{AsRawFd, RawFd},
     },
     sync::{Arc, atomic::{AtomicPtr, Ordering}},
 };
@@ -39,6 +42,8 @@ pub mod ffi {
     pub const DRM_FLIP_EVENT_DATA_NV: i32 = 0x333E;
     pub const CONSUMER_ACQUIRE_TIMEOUT_USEC_KHR: i32 = 0x321E;
     pub const RESOURCE_BUSY_EXT: u32 = 0x3353;
+    /// EGL_EXT_device_drm_render_node
+    pub const DRM_RENDER_NODE_FILE_EXT: i32 = 0x3377;
 
     #[allow(non_snake_case, unused_variables, dead_code)]
     #[inline]
@@ -131,13 +136,27 @@ pub mod ffi {
     }
 }
 fn wrap_egl_call<R, F: FnOnce() -> R>(call: F) -> Result<R, EGLError> {
+    wrap_egl_call_raw(call).map_err(EGLError::from)
+}
+
+/// Like `wrap_egl_call`, but keeps the raw error code for decoding nvidia specific errors
+fn wrap_egl_call_raw<R, F: FnOnce() -> R>(call: F) -> Result<R, u32> {
     let res = call();
     match unsafe { ffi::GetError() as u32 } {
         ffi::SUCCESS => Ok(res),
-        x => Err(EGLError::from(x)),
+        x => Err(x),
     }
 }
 
+/// Loads the EGL functions smithay doesn't expose
+pub fn load() -> Result<()> {
+    smithay::backend::egl::ffi::make_sure_egl_is_loaded()?;
+    ffi::load_with(|sym| unsafe { smithay::backend::egl::get_proc_address(sym) });
+    ffi::StreamConsumerAcquireAttribNV::load_with(|sym| unsafe { smithay::backend::egl::get_proc_address(sym) });
+    ffi::StreamConsumerReleaseAttribNV::load_with(|sym| unsafe { smithay::backend::egl::get_proc_address(sym) });
+    Ok(())
+}
+
 pub struct EGLDeviceEXT {
     device: ffi::types::EGLDeviceEXT,
     raw: Fd,
@@ -147,104 +166,120 @@ unsafe impl Send for EGLDeviceEXT {}
 
 impl EGLDeviceEXT {
     pub fn new(raw: Fd, log: slog::Logger) -> Result<EGLDeviceEXT> {
-        smithay::backend::egl::ffi::make_sure_egl_is_loaded()?;
-        ffi::load_with(|sym| unsafe { smithay::backend::egl::get_proc_address(sym) });
-        ffi::StreamConsumerAcquireAttribNV::load_with(|sym| unsafe { smithay::backend::egl::get_proc_address(sym) });
-        ffi::StreamConsumerReleaseAttribNV::load_with(|sym| unsafe { smithay::backend::egl::get_proc_address(sym) });
-
-        let device = unsafe {
-            // the first step is to query the list of extensions without any display, if supported
-            let dp_extensions = {
-                let p = wrap_egl_call(|| {
-                    ffi::QueryString(ffi::NO_DISPLAY, ffi::EXTENSIONS as i32)
-                })?;
-
-                // this possibility is available only with EGL 1.5 or EGL_EXT_platform_base, otherwise
-                // `eglQueryString` returns an error
-                if p.is_null() {
-                    vec![]
-                } else {
-                    let p = CStr::from_ptr(p);
-                    let list = String::from_utf8(p.to_bytes().to_vec()).unwrap_or_else(|_| String::new());
-                    list.split(' ').map(|e| e.to_string()).collect::<Vec<_>>()
-                }
-            };
-            slog::debug!(log, "EGL No-Display Extensions: {:?}", dp_extensions);
-
-            // we need either EGL_EXT_device_base or EGL_EXT_device_enumeration &_query
-            if !dp_extensions.iter().any(|x|  x == "EGL_EXT_device_base") {
-                if !(
-                    dp_extensions.iter().any(|x| x == "EGL_EXT_device_enumeration")
-                 && dp_extensions.iter().any(|x| x == "EGL_EXT_device_query")
-                ) {
-                    anyhow::bail!("Device does not support EGL_EXT_device");
-                }
+        let drm_rdev = fstat(raw.as_raw_fd()).context("Unable to get device id")?.st_rdev;
+        let device = find_device(drm_rdev, &log)?;
+        Ok(EGLDeviceEXT {
+            device,
+            raw
+        })
+    }
+
+    /// Checks whether EGL knows the drm device `rdev` the way `new` looks it up, without opening the device
+    pub fn probe(rdev: dev_t, log: &slog::Logger) -> Result<()> {
+        find_device(rdev, log).map(|_| ())
+    }
+}
+
+/// The egl device of the drm device `drm_rdev`, which may be of either node.
+///
+/// Devices failing to report their nodes are skipped and listed in the `DeviceLookupError`, if none matches.
+fn find_device(drm_rdev: dev_t, log: &slog::Logger) -> Result<ffi::types::EGLDeviceEXT> {
+    load()?;
+
+    let device = unsafe {
+        // the first step is to query the list of extensions without any display, if supported
+        let dp_extensions = {
+            let p = wrap_egl_call(|| {
+                ffi::QueryString(ffi::NO_DISPLAY, ffi::EXTENSIONS as i32)
+            })?;
+
+            // this possibility is available only with EGL 1.5 or EGL_EXT_platform_base, otherwise
+            // `eglQueryString` returns an error
+            if p.is_null() {
+                vec![]
+            } else {
+                let p = CStr::from_ptr(p);
+                let list = String::from_utf8(p.to_bytes().to_vec()).unwrap_or_else(|_| String::new());
+                list.split(' ').map(|e| e.to_string()).collect::<Vec<_>>()
             }
+        };
+        slog::debug!(log, "EGL No-Display Extensions: {:?}", dp_extensions);
 
-            let mut num_devices = 0;
-            wrap_egl_call(|| ffi::QueryDevicesEXT(0, ptr::null_mut(), &mut num_devices))?;
-            if num_devices == 0 {
+        // we need either EGL_EXT_device_base or EGL_EXT_device_enumeration &_query
+        if !dp_extensions.iter().any(|x|  x == "EGL_EXT_device_base") {
+            if !(
+                dp_extensions.iter().any(|x| x == "EGL_EXT_device_enumeration")
+             && dp_extensions.iter().any(|x| x == "EGL_EXT_device_query")
+            ) {
                 anyhow::bail!("Device does not support EGL_EXT_device");
             }
+        }
 
-            let mut devices = Vec::with_capacity(num_devices as usize);
-            wrap_egl_call(|| ffi::QueryDevicesEXT(num_devices, devices.as_mut_ptr(), &mut num_devices))?;
-            devices.set_len(num_devices as usize);
-            slog::debug!(log, "Devices: {:#?}, Count: {}", devices, num_devices);
-                            
-            let drm_rdev = fstat(raw.as_raw_fd()).expect("Unable to get device id").st_rdev;
-            slog::debug!(log, "rdev: {:?} ({}:{})", drm_rdev, major(drm_rdev), minor(drm_rdev));
-            let name = std::fs::read_dir(format!("/sys/dev/char/{}:{}/device/drm", major(drm_rdev), minor(drm_rdev)))?
-                .find(|x| x.as_ref().ok()
-                    .and_then(|entry| entry.file_name().to_str().map(|x| x.starts_with("card")))
-                    .unwrap_or(false)
-                ).context("Unable to find device")??;
-            let path = format!("/dev/dri/{}", name.file_name().to_str().unwrap());
-
-            devices
-                .into_iter()
-                .find(|device| {
-                    *device != ffi::NO_DEVICE_EXT
-                        && {
-                            let device_extensions = {
-                                let p = ffi::QueryDeviceStringEXT(*device, ffi::EXTENSIONS as i32);
-                                if p.is_null() {
-                                    vec![]
-                                } else {
-                                    let p = CStr::from_ptr(p);
-                                    let list = String::from_utf8(p.to_bytes().to_vec())
-                                        .unwrap_or_else(|_| String::new());
-                                    list.split(' ').map(|e| e.to_string()).collect::<Vec<_>>()
-                                }
-                            };
-                            slog::debug!(log, "EGL Device Extensions: {:?}", device_extensions);
-
-                            device_extensions.iter().any(|s| *s == "EGL_EXT_device_drm")
-                        }
-                        && {
-                            let egl_path = {
-                                let p = ffi::QueryDeviceStringEXT(
-                                    *device,
-                                    ffi::DRM_DEVICE_FILE_EXT as i32,
-                                );
-                                if p.is_null() {
-                                    String::new()
-                                } else {
-                                    let p = CStr::from_ptr(p);
-                                    String::from_utf8(p.to_bytes().to_vec()).unwrap_or_else(|_| String::new())
-                                }
-                            };
-
-                            egl_path == path
-                        }
-                }).ok_or(anyhow::anyhow!("Device does not support EGL_EXT_device"))?
+        let mut num_devices = 0;
+        wrap_egl_call(|| ffi::QueryDevicesEXT(0, ptr::null_mut(), &mut num_devices))?;
+        if num_devices == 0 {
+            return Err(DeviceLookupError::NoDevices.into());
+        }
+
+        let mut devices = Vec::with_capacity(num_devices as usize);
+        wrap_egl_call(|| ffi::QueryDevicesEXT(num_devices, devices.as_mut_ptr(), &mut num_devices))?;
+        devices.set_len(num_devices as usize);
+        slog::debug!(log, "Devices: {:#?}, Count: {}", devices, num_devices);
+                        
+        slog::debug!(log, "rdev: {:?} ({}:{})", drm_rdev, major(drm_rdev), minor(drm_rdev));
+        let nodes = DeviceNodes::find(Path::new("/sys"), drm_rdev)?;
+        slog::debug!(log, "Device nodes: {:?}", nodes);
+
+        let query = |device: ffi::types::EGLDeviceEXT, name: i32| {
+            let p = ffi::QueryDeviceStringEXT(device, name);
+            if p.is_null() {
+                None
+            } else {
+                String::from_utf8(CStr::from_ptr(p).to_bytes().to_vec()).ok()
+            }
         };
 
-        Ok(EGLDeviceEXT {
-            device,
-            raw
-        })
-    }
+        let mut candidates = Vec::new();
+        let mut found = None;
+        for device in devices.into_iter().filter(|device| *device != ffi::NO_DEVICE_EXT) {
+            let extensions = query(device, ffi::EXTENSIONS as i32)
+                .map(|list| list.split(' ').map(|e| e.to_string()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            slog::debug!(log, "EGL Device Extensions: {:?}", extensions);
+            let mut candidate = DeviceCandidate {
+                device: device as usize,
+                primary: None,
+                render: None,
+                reason: String::new(),
+                extensions,
+            };
+            if !candidate.extensions.iter().any(|s| *s == "EGL_EXT_device_drm") {
+                candidate.reason = String::from("missing EGL_EXT_device_drm");
+                candidates.push(candidate);
+                continue;
+            }
+
+            candidate.primary = query(device, ffi::DRM_DEVICE_FILE_EXT as i32);
+            // the primary node might not be accessible or not be what the compositor advertised
+            if candidate.extensions.iter().any(|s| *s == "EGL_EXT_device_drm_render_node") {
+                candidate.render = query(device, ffi::DRM_RENDER_NODE_FILE_EXT);
+            }
+            if nodes.matches(candidate.primary.as_deref(), candidate.render.as_deref()) {
+                found = Some(device);
+                break;
+            }
+            candidate.reason = String::from("different device");
+            candidates.push(candidate);
+        }
+        let device = found.ok_or(DeviceLookupError::NoMatch {
+            wanted: nodes.clone(),
+            candidates,
+        })?;
+        slog::info!(log, "Using egl device of {}", nodes);
+        device
+    };
+
+    Ok(device)
 }
 
 impl EGLNativeDisplay for EGLDeviceEXT {
@@ -270,64 +305,656 @@ impl EGLNativeDisplay for EGLDeviceEXT {
     }
 }
 
+/// An egl device rejected while looking for a drm device
+#[derive(Debug, Clone)]
+pub struct DeviceCandidate {
+    /// Address of the `EGLDeviceEXT`
+    pub device: usize,
+    pub extensions: Vec<String>,
+    /// `EGL_DRM_DEVICE_FILE_EXT`
+    pub primary: Option<String>,
+    /// `EGL_DRM_RENDER_NODE_FILE_EXT`
+    pub render: Option<String>,
+    pub reason: String,
+}
+
+/// Why no egl device was found for a drm device
+#[derive(Debug)]
+pub enum DeviceLookupError {
+    /// EGL enumerates no devices at all, which points to a driver or ICD problem
+    NoDevices,
+    /// None of the devices is the one we are looking for
+    NoMatch {
+        wanted: DeviceNodes,
+        candidates: Vec<DeviceCandidate>,
+    },
+}
+
+impl std::fmt::Display for DeviceLookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceLookupError::NoDevices => write!(f, "EGL does not know any devices"),
+            DeviceLookupError::NoMatch { wanted, candidates } => {
+                write!(f, "No EGL device matches {}", wanted)?;
+                for candidate in candidates {
+                    write!(
+                        f,
+                        "\n  device 0x{:x} ({}, {}): {}",
+                        candidate.device,
+                        candidate.primary.as_deref().unwrap_or("no device file"),
+                        candidate.render.as_deref().unwrap_or("no render node"),
+                        candidate.reason,
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceLookupError {}
+
+/// Device files of a drm device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceNodes {
+    /// e.g. `/dev/dri/card0`
+    pub primary: Option<String>,
+    /// e.g. `/dev/dri/renderD128`
+    pub render: Option<String>,
+}
+
+impl DeviceNodes {
+    /// Looks up the nodes of the device `rdev` belongs to in `sysfs`, which is usually `/sys`.
+    ///
+    /// `rdev` may be of either node.
+    pub fn find(sysfs: &Path, rdev: dev_t) -> Result<DeviceNodes> {
+        let dir = sysfs.join(format!("dev/char/{}:{}/device/drm", major(rdev), minor(rdev)));
+        let mut nodes = DeviceNodes {
+            primary: None,
+            render: None,
+        };
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+            let name = entry?.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.starts_with("card") && nodes.primary.is_none() {
+                nodes.primary = Some(format!("/dev/dri/{}", name));
+            } else if name.starts_with("renderD") && nodes.render.is_none() {
+                nodes.render = Some(format!("/dev/dri/{}", name));
+            }
+        }
+        if nodes.primary.is_none() && nodes.render.is_none() {
+            anyhow::bail!("Unable to find device");
+        }
+        Ok(nodes)
+    }
+
+    /// Whether the egl device with the given node paths is this device, a match of either is enough
+    pub fn matches(&self, primary: Option<&str>, render: Option<&str>) -> bool {
+        let same = |ours: &Option<String>, theirs: Option<&str>| match (ours, theirs) {
+            (Some(ours), Some(theirs)) => ours == theirs,
+            _ => false,
+        };
+        same(&self.primary, primary) || same(&self.render, render)
+    }
+}
+
+impl std::fmt::Display for DeviceNodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.primary, &self.render) {
+            (Some(primary), Some(render)) => write!(f, "{} ({})", primary, render),
+            (Some(node), None) | (None, Some(node)) => write!(f, "{}", node),
+            (None, None) => write!(f, "unknown device"),
+        }
+    }
+}
+
+/// Explicit synchronization supported by the display of the current context
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncSupport {
+    /// EGL_KHR_fence_sync
+    pub fence_sync: bool,
+    /// EGL_ANDROID_native_fence_sync and EGL_KHR_wait_sync, to wait for sync_files on the gpu
+    pub native_fence_sync: bool,
+}
+
+impl SyncSupport {
+    /// Needs a context current
+    pub unsafe fn query() -> SyncSupport {
+        let p = ffi::QueryString(ffi::GetCurrentDisplay(), ffi::EXTENSIONS as i32);
+        let list = if p.is_null() {
+            String::new()
+        } else {
+            String::from_utf8(CStr::from_ptr(p).to_bytes().to_vec()).unwrap_or_else(|_| String::new())
+        };
+        let has = |name: &str| list.split(' ').any(|ext| ext == name);
+        SyncSupport {
+            fence_sync: has("EGL_KHR_fence_sync"),
+            native_fence_sync: has("EGL_KHR_fence_sync")
+                && has("EGL_KHR_wait_sync")
+                && has("EGL_ANDROID_native_fence_sync"),
+        }
+    }
+}
+
+/// Attributes of an EGLConfig, as far as choosing one for a stream surface is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigAttributes {
+    pub id: i32,
+    pub red: i32,
+    pub green: i32,
+    pub blue: i32,
+    pub alpha: i32,
+    pub depth: i32,
+    pub stencil: i32,
+    pub samples: i32,
+    /// EGL_MAX_PBUFFER_WIDTH/HEIGHT, which stream surfaces are bound by as well, 0 if unknown
+    pub max_width: i32,
+    pub max_height: i32,
+}
+
+impl std::fmt::Display for ConfigAttributes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "config {} (rgba {}/{}/{}/{}, depth {}, stencil {}, {} samples)",
+            self.id, self.red, self.green, self.blue, self.alpha, self.depth, self.stencil, self.samples
+        )
+    }
+}
+
+impl ConfigAttributes {
+    /// Sort key of the config for rendering `color_bits` per channel, lower is better.
+    ///
+    /// `None` for configs with fewer bits than that. Exact color depth is preferred,
+    /// then no multisampling, no alpha and the smallest ancillary buffers.
+    pub fn rank(&self, color_bits: i32) -> Option<(i32, i32, i32, i32, i32)> {
+        if self.red < color_bits || self.green < color_bits || self.blue < color_bits {
+            return None;
+        }
+        let excess = self.red + self.green + self.blue - 3 * color_bits;
+        Some((excess, self.samples, self.alpha, self.depth + self.stencil, self.id))
+    }
+}
+
+/// Extensions a display needs for scanning out through a stream
+const STREAM_EXTENSIONS: [&str; 6] = [
+    "EGL_EXT_output_base",
+    "EGL_EXT_output_drm",
+    "EGL_KHR_stream",
+    "EGL_NV_output_drm_flip_event",
+    "EGL_EXT_stream_consumer_egloutput",
+    "EGL_KHR_stream_producer_eglsurface",
+];
+
+/// Those of `STREAM_EXTENSIONS` `display` lacks
+pub fn missing_stream_extensions(display: &Arc<EGLDisplayHandle>) -> Vec<&'static str> {
+    let p = unsafe { ffi::QueryString(***display, ffi::EXTENSIONS as i32) };
+    let list = if p.is_null() {
+        String::new()
+    } else {
+        String::from_utf8(unsafe { CStr::from_ptr(p) }.to_bytes().to_vec()).unwrap_or_else(|_| String::new())
+    };
+    STREAM_EXTENSIONS
+        .iter()
+        .copied()
+        .filter(|name| !list.split(' ').any(|ext| ext == *name))
+        .collect()
+}
+
+/// Configs of `display` usable for stream producer surfaces and OpenGL ES contexts
+pub fn stream_configs(display: &Arc<EGLDisplayHandle>) -> Result<Vec<ConfigAttributes>> {
+    let attribs = [
+        ffi::SURFACE_TYPE as i32,
+        ffi::STREAM_BIT_KHR as i32,
+        ffi::RENDERABLE_TYPE as i32,
+        ffi::OPENGL_ES2_BIT as i32,
+        ffi::NONE as i32,
+    ];
+    let mut num_configs = 0;
+    unsafe {
+        wrap_egl_call(|| ffi::ChooseConfig(***display, attribs.as_ptr(), ptr::null_mut(), 0, &mut num_configs))
+            .context("Failed to count stream configs")?;
+        let mut configs = Vec::with_capacity(num_configs as usize);
+        wrap_egl_call(|| {
+            ffi::ChooseConfig(
+                ***display,
+                attribs.as_ptr(),
+                configs.as_mut_ptr(),
+                num_configs,
+                &mut num_configs,
+            )
+        })
+        .context("Failed to list stream configs")?;
+        configs.set_len(num_configs as usize);
+
+        let attrib = |config: ffi::types::EGLConfig, name: ffi::types::EGLenum| {
+            let mut value = 0;
+            wrap_egl_call(|| ffi::GetConfigAttrib(***display, config, name as i32, &mut value)).map(|_| value)
+        };
+        configs
+            .into_iter()
+            .map(|config| {
+                Ok(ConfigAttributes {
+                    id: attrib(config, ffi::CONFIG_ID)?,
+                    red: attrib(config, ffi::RED_SIZE)?,
+                    green: attrib(config, ffi::GREEN_SIZE)?,
+                    blue: attrib(config, ffi::BLUE_SIZE)?,
+                    alpha: attrib(config, ffi::ALPHA_SIZE)?,
+                    depth: attrib(config, ffi::DEPTH_SIZE)?,
+                    stencil: attrib(config, ffi::STENCIL_SIZE)?,
+                    samples: attrib(config, ffi::SAMPLES)?,
+                    max_width: attrib(config, ffi::MAX_PBUFFER_WIDTH)?,
+                    max_height: attrib(config, ffi::MAX_PBUFFER_HEIGHT)?,
+                })
+            })
+            .collect::<Result<Vec<_>, EGLError>>()
+            .context("Failed to query stream config")
+    }
+}
+
+/// Signals once the gpu finished the commands submitted before it
+pub struct EglFence {
+    display: ffi::types::EGLDisplay,
+    sync: ffi::types::EGLSyncKHR,
+}
+
+impl EglFence {
+    /// Inserts a fence into the command stream of the current context, which needs to be flushed afterwards
+    pub unsafe fn insert() -> Option<EglFence> {
+        let display = ffi::GetCurrentDisplay();
+        let sync = ffi::CreateSyncKHR(display, ffi::SYNC_FENCE_KHR, ptr::null());
+        if sync.is_null() {
+            None
+        } else {
+            Some(EglFence { display, sync })
+        }
+    }
+
+    pub fn signaled(&self) -> bool {
+        unsafe { ffi::ClientWaitSyncKHR(self.display, self.sync, 0, 0) as u32 == ffi::CONDITION_SATISFIED_KHR }
+    }
+
+    /// Blocks until the fence signaled or `timeout` nanoseconds passed
+    pub fn wait(&self, timeout: u64) {
+        unsafe {
+            ffi::ClientWaitSyncKHR(
+                self.display,
+                self.sync,
+                ffi::SYNC_FLUSH_COMMANDS_BIT_KHR as i32,
+                timeout,
+            )
+        };
+    }
+}
+
+impl Drop for EglFence {
+    fn drop(&mut self) {
+        unsafe { ffi::DestroySyncKHR(self.display, self.sync) };
+    }
+}
+
+/// Makes the current context wait on the gpu until the sync_file `fd` signaled, `fd` is consumed
+pub unsafe fn wait_native_fence(fd: RawFd) -> Result<()> {
+    let display = ffi::GetCurrentDisplay();
+    let attributes = [
+        ffi::SYNC_NATIVE_FENCE_FD_ANDROID as i32,
+        fd,
+        ffi::NONE as i32,
+    ];
+    let sync = ffi::CreateSyncKHR(display, ffi::SYNC_NATIVE_FENCE_ANDROID, attributes.as_ptr());
+    if sync.is_null() {
+        let _ = nix::unistd::close(fd);
+        anyhow::bail!("Failed to import sync_file");
+    }
+    // egl owns the fd from here on
+    let waited = ffi::WaitSyncKHR(display, sync, 0);
+    ffi::DestroySyncKHR(display, sync);
+    if waited as u32 != ffi::TRUE {
+        anyhow::bail!("Failed to wait for sync_file");
+    }
+    Ok(())
+}
+
+/// Longest FIFO accepted for the stream, longer ones only add latency
+pub const MAX_FIFO_LENGTH: u32 = 3;
+/// Time an acquire may block the event loop by default, a busy output is retried instead of waited for
+pub const DEFAULT_ACQUIRE_TIMEOUT_USEC: u32 = 2_000;
+
+/// Tunables of the stream feeding the output layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamOptions {
+    /// Frames queued in front of the output layer, 0 always shows the newest frame (mailbox mode)
+    pub fifo_length: u32,
+    /// Vblanks between flips, 0 flips immediately, clamped to what the layer supports
+    pub swap_interval: u32,
+    /// Which output layers are used
+    pub output_layer: OutputLayerKind,
+    /// Takes this one of the layers found instead of the one bound to our crtc, for experimenting with drivers
+    pub output_layer_index: Option<usize>,
+    /// How long acquiring a swapped frame may wait for the output layer, in microseconds
+    pub acquire_timeout_usec: u32,
+}
+
+/// Object the output layer consuming the stream is looked up for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputLayerKind {
+    /// The plane, falling back to the crtc if the plane has no layers
+    Auto,
+    Plane,
+    Crtc,
+}
+
+impl OutputLayerKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "plane", "crtc"];
+}
+
+impl FromStr for OutputLayerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<OutputLayerKind> {
+        match name {
+            "auto" => Ok(OutputLayerKind::Auto),
+            "plane" => Ok(OutputLayerKind::Plane),
+            "crtc" => Ok(OutputLayerKind::Crtc),
+            x => anyhow::bail!("Unknown output layer: {}", x),
+        }
+    }
+}
+
+/// Drm objects an output layer is bound to, as far as the driver tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerAttributes {
+    pub crtc: Option<u32>,
+    pub plane: Option<u32>,
+}
+
+/// Which of the output layers found is used and why, see `select_layer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerChoice {
+    /// Given by `--output-layer-index`
+    Forced(usize),
+    /// The only one found
+    Only(usize),
+    /// The first one bound to our crtc
+    Matching(usize),
+    /// The first one, as none is bound to our crtc
+    Fallback(usize),
+}
+
+impl LayerChoice {
+    pub fn index(self) -> usize {
+        match self {
+            LayerChoice::Forced(index)
+            | LayerChoice::Only(index)
+            | LayerChoice::Matching(index)
+            | LayerChoice::Fallback(index) => index,
+        }
+    }
+}
+
+/// Picks the layer bound to `crtc` out of `layers`, `None` if there are none or `forced` is out of range
+pub fn select_layer(layers: &[LayerAttributes], crtc: u32, forced: Option<usize>) -> Option<LayerChoice> {
+    if let Some(index) = forced {
+        return Some(LayerChoice::Forced(index)).filter(|_| index < layers.len());
+    }
+    match layers.len() {
+        0 => None,
+        1 => Some(LayerChoice::Only(0)),
+        _ => Some(
+            layers
+                .iter()
+                .position(|layer| layer.crtc == Some(crtc))
+                .map(LayerChoice::Matching)
+                .unwrap_or(LayerChoice::Fallback(0)),
+        ),
+    }
+}
+
+/// State of an EGLStream, as reported by `EGL_STREAM_STATE_KHR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    Created,
+    Connecting,
+    Empty,
+    NewFrameAvailable,
+    OldFrameAvailable,
+    Disconnected,
+    Unknown(i32),
+}
+
+impl From<i32> for StreamState {
+    fn from(state: i32) -> StreamState {
+        match state as u32 {
+            ffi::STREAM_STATE_CREATED_KHR => StreamState::Created,
+            ffi::STREAM_STATE_CONNECTING_KHR => StreamState::Connecting,
+            ffi::STREAM_STATE_EMPTY_KHR => StreamState::Empty,
+            ffi::STREAM_STATE_NEW_FRAME_AVAILABLE_KHR => StreamState::NewFrameAvailable,
+            ffi::STREAM_STATE_OLD_FRAME_AVAILABLE_KHR => StreamState::OldFrameAvailable,
+            ffi::STREAM_STATE_DISCONNECTED_KHR => StreamState::Disconnected,
+            _ => StreamState::Unknown(state),
+        }
+    }
+}
+
+/// Errors of presenting through the stream, decoded from the raw egl error and the stream state
+#[derive(Debug)]
+pub enum NvEglError {
+    /// EGL_RESOURCE_BUSY_EXT on swap, the output layer is still busy with the previous flip
+    ResourceBusy,
+    /// EGL_RESOURCE_BUSY_EXT on acquire, the output layer did not take the frame within the acquire timeout.
+    /// The previous frame stays on screen and the acquire is retried.
+    AcquireTimeout,
+    /// EGL_BAD_STATE_KHR, the stream was not ready for the operation
+    BadState(StreamState),
+    /// The output layer let go of the stream, e.g. after a modeset. It is recreated before the next frame.
+    Disconnected,
+    /// Any other error of the stream
+    Other(EGLError),
+    /// Errors of smithays surface handling, which never reached the stream
+    Surface(SwapBuffersError),
+}
+
+impl NvEglError {
+    /// Decodes the error `code` of a swap or of the following acquire, given the stream state afterwards
+    pub fn decode(code: u32, state: StreamState, acquiring: bool) -> NvEglError {
+        match code {
+            _ if state == StreamState::Disconnected => NvEglError::Disconnected,
+            ffi::RESOURCE_BUSY_EXT if acquiring => NvEglError::AcquireTimeout,
+            ffi::RESOURCE_BUSY_EXT => NvEglError::ResourceBusy,
+            ffi::BAD_STATE_KHR => NvEglError::BadState(state),
+            code => NvEglError::Other(EGLError::from(code)),
+        }
+    }
+
+    /// Whether the next frame can be presented as usual
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NvEglError::ResourceBusy
+            | NvEglError::AcquireTimeout
+            | NvEglError::BadState(_)
+            | NvEglError::Disconnected => true,
+            // smithay recreates the surface on the next swap
+            NvEglError::Surface(SwapBuffersError::EGLSwapBuffers(EGLError::BadSurface)) => true,
+            NvEglError::Other(_) | NvEglError::Surface(_) => false,
+        }
+    }
+
+    /// Short name of the variant, failures of the same kind are counted together
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NvEglError::ResourceBusy => "busy",
+            NvEglError::AcquireTimeout => "acquire timeout",
+            NvEglError::BadState(_) => "bad state",
+            NvEglError::Disconnected => "disconnected",
+            NvEglError::Other(_) => "egl",
+            NvEglError::Surface(_) => "surface",
+        }
+    }
+}
+
+impl std::fmt::Display for NvEglError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NvEglError::ResourceBusy => write!(f, "Output busy"),
+            NvEglError::AcquireTimeout => write!(f, "Acquiring the frame timed out"),
+            NvEglError::BadState(state) => write!(f, "Stream in bad state {:?}", state),
+            NvEglError::Disconnected => write!(f, "Stream disconnected"),
+            NvEglError::Other(err) => write!(f, "{}", err),
+            NvEglError::Surface(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for NvEglError {}
+
+/// Decoded error of the last failed swap, smithays `SwapBuffersError` can only carry the raw one
+pub type SwapErrorSlot = Rc<Cell<Option<NvEglError>>>;
+
+/// The stream of a surface, `None` until it is created and after it was invalidated
+pub type StreamSlot = Rc<Cell<Option<ffi::types::EGLStreamKHR>>>;
+
+/// Whether the surface of `slot` needs to be recreated, like `EGLNativeSurface::needs_recreation` tells.
+///
+/// Streams the output layer disconnected, e.g. across a DPMS cycle, are invalidated first.
+pub fn needs_recreation(display: &Arc<EGLDisplayHandle>, slot: &StreamSlot) -> bool {
+    invalidate_disconnected(slot, |stream| query_state(display, stream))
+}
+
+/// `needs_recreation` with the state of the stream given by `state`
+fn invalidate_disconnected(slot: &StreamSlot, state: impl FnOnce(ffi::types::EGLStreamKHR) -> StreamState) -> bool {
+    match slot.get() {
+        Some(stream) if state(stream) == StreamState::Disconnected => {
+            slot.set(None);
+            true
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
+
+fn query_state(display: &Arc<EGLDisplayHandle>, stream: ffi::types::EGLStreamKHR) -> StreamState {
+    let mut val = 0;
+    unsafe { ffi::QueryStreamKHR(***display, stream, ffi::STREAM_STATE_KHR, &mut val as *mut _) };
+    StreamState::from(val)
+}
+
+/// Acquire of the frame swapped last, which hands it to the output layer.
+///
+/// It is done separately from the swap, as it may wait for the previous flip, see `PendingAcquire::acquire`.
+pub struct PendingAcquire {
+    display: Arc<EGLDisplayHandle>,
+    stream: ffi::types::EGLStreamKHR,
+    slot: StreamSlot,
+    crtc: crtc::Handle,
+}
+
+impl PendingAcquire {
+    /// Blocks for at most the acquire timeout of the stream, on `NvEglError::AcquireTimeout` the frame stays queued
+    pub fn acquire(&self) -> Result<(), NvEglError> {
+        // the stream was replaced meanwhile, the frame went with it
+        if self.slot.get() != Some(self.stream) {
+            return Ok(());
+        }
+        let acquire_attributes = [
+            ffi::DRM_FLIP_EVENT_DATA_NV as isize,
+            Into::<u32>::into(self.crtc) as isize,
+            ffi::NONE as isize,
+        ];
+        let result = wrap_egl_call_raw(|| unsafe {
+            ffi::StreamConsumerAcquireAttribNV(***self.display, self.stream, acquire_attributes.as_ptr());
+        });
+        result.map_err(|code| {
+            let error = NvEglError::decode(code, query_state(&self.display, self.stream), true);
+            if let NvEglError::Disconnected = error {
+                self.slot.set(None);
+            }
+            error
+        })
+    }
+}
+
+/// Frame waiting to be acquired, filled by every successful swap
+pub type AcquireSlot = Rc<Cell<Option<PendingAcquire>>>;
+
 pub struct EglStreamSurface {
-    stream: Cell<Option<ffi::types::EGLStreamKHR>>,
+    stream: StreamSlot,
     crtc: crtc::Handle,
     plane: plane::Handle,
     surface: AtomicPtr<nix::libc::c_void>,
     mode: Cell<(i32, i32)>,
+    options: StreamOptions,
+    last_error: SwapErrorSlot,
+    pending_acquire: AcquireSlot,
     logger: slog::Logger,
 }
 
 impl EglStreamSurface {
-    pub fn new(crtc: crtc::Handle, plane: plane::Handle, mode: (i32, i32), logger: slog::Logger) -> EglStreamSurface {
+    pub fn new(
+        crtc: crtc::Handle,
+        plane: plane::Handle,
+        mode: (i32, i32),
+        options: StreamOptions,
+        logger: slog::Logger,
+    ) -> EglStreamSurface {
         EglStreamSurface {
-            stream: Cell::new(None),
+            stream: Rc::new(Cell::new(None)),
             crtc,
             plane,
             surface: AtomicPtr::new(std::ptr::null_mut()),
             mode: Cell::new(mode),
+            options,
+            last_error: Rc::new(Cell::new(None)),
+            pending_acquire: Rc::new(Cell::new(None)),
             logger,
         }
     }
 
-    fn create_stream(&self, handle: &Arc<EGLDisplayHandle>) -> Result<(), EGLError> {
-        let output_attribs = [
-            ffi::DRM_PLANE_EXT as isize,
-            Into::<u32>::into(self.plane) as isize,
-            ffi::NONE as isize,
-        ];
-        /*
-        let output_attribs = [
-            ffi::DRM_CRTC_EXT as isize,
-            Into::<u32>::into(self.crtc) as isize,
-            ffi::NONE as isize,
-        ];
-        */
+    /// Receives the decoded errors of failed swaps
+    pub fn last_error(&self) -> SwapErrorSlot {
+        self.last_error.clone()
+    }
 
-        let extensions = {
-            let p =
-                unsafe { CStr::from_ptr(ffi::QueryString(***handle, ffi::EXTENSIONS as i32)) };
-            let list = String::from_utf8(p.to_bytes().to_vec()).unwrap_or_else(|_| String::new());
-            list.split(' ').map(|e| e.to_string()).collect::<Vec<_>>()
-        };
+    /// Receives the frames swapped, which still need to be acquired
+    pub fn pending_acquire(&self) -> AcquireSlot {
+        self.pending_acquire.clone()
+    }
 
-        if !extensions.iter().any(|s| *s == "EGL_EXT_output_base")
-            || !extensions.iter().any(|s| *s == "EGL_EXT_output_drm")
-            || !extensions.iter().any(|s| *s == "EGL_KHR_stream")
-            || !extensions
-                .iter()
-                .any(|s| *s == "EGL_NV_output_drm_flip_event")
-            || !extensions
-                .iter()
-                .any(|s| *s == "EGL_EXT_stream_consumer_egloutput")
-            || !extensions
-                .iter()
-                .any(|s| *s == "EGL_KHR_stream_producer_eglsurface")
-        {
-            slog::error!(self.logger, "Extension for EGLStream surface creation missing");
-            return Err(EGLError::BadNativeWindow);
+    /// Empty while the surface needs to be recreated, see `needs_recreation`
+    pub fn stream(&self) -> StreamSlot {
+        self.stream.clone()
+    }
+
+    /// Decodes and records a failed swap
+    fn fail(&self, display: &Arc<EGLDisplayHandle>, stream: ffi::types::EGLStreamKHR, code: u32) -> SwapBuffersError {
+        let error = NvEglError::decode(code, query_state(display, stream), false);
+        if let NvEglError::Disconnected = error {
+            self.stream.set(None);
         }
+        self.last_error.set(Some(error));
+        SwapBuffersError::EGLSwapBuffers(EGLError::from(code))
+    }
+
+    /// The output layer of our plane or crtc bound to our crtc, `None` if the driver has none
+    fn find_layer(
+        &self,
+        handle: &Arc<EGLDisplayHandle>,
+        kind: OutputLayerKind,
+    ) -> Result<Option<ffi::types::EGLOutputLayerEXT>, EGLError> {
+        let output_attribs = match kind {
+            OutputLayerKind::Crtc => [
+                ffi::DRM_CRTC_EXT as isize,
+                Into::<u32>::into(self.crtc) as isize,
+                ffi::NONE as isize,
+            ],
+            _ => [
+                ffi::DRM_PLANE_EXT as isize,
+                Into::<u32>::into(self.plane) as isize,
+                ffi::NONE as isize,
+            ],
+        };
 
         let mut num_layers = 0;
         if unsafe {
@@ -347,8 +974,8 @@ impl EglStreamSurface {
             return Err(EGLError::BadParameter);
         }
         if num_layers == 0 {
-            slog::error!(self.logger, "Failed to find Output Layer");
-            return Err(EGLError::BadParameter);
+            slog::debug!(self.logger, "No Output Layer of the {:?}", kind);
+            return Ok(None);
         }
         let mut layers = Vec::with_capacity(num_layers as usize);
         if unsafe {
@@ -367,23 +994,103 @@ impl EglStreamSurface {
         unsafe {
             layers.set_len(num_layers as usize);
         }
+        let attributes = layers
+            .iter()
+            .map(|layer| {
+                let query = |attribute: u32| {
+                    let mut value: ffi::types::EGLAttrib = 0;
+                    let found =
+                        unsafe { ffi::QueryOutputLayerAttribEXT(***handle, *layer, attribute as i32, &mut value) };
+                    Some(value as u32).filter(|_| found == ffi::TRUE)
+                };
+                LayerAttributes {
+                    crtc: query(ffi::DRM_CRTC_EXT),
+                    plane: query(ffi::DRM_PLANE_EXT),
+                }
+            })
+            .collect::<Vec<_>>();
+        for (index, layer) in attributes.iter().enumerate() {
+            slog::debug!(self.logger, "Output Layer {} of the {:?}: {:?}", index, kind, layer);
+        }
+        let crtc = Into::<u32>::into(self.crtc);
+        let choice = match select_layer(&attributes, crtc, self.options.output_layer_index) {
+            Some(choice) => choice,
+            None => {
+                slog::error!(
+                    self.logger,
+                    "Output Layer {} requested, but the {:?} has {}",
+                    self.options.output_layer_index.unwrap_or(0),
+                    kind,
+                    attributes.len()
+                );
+                return Err(EGLError::BadParameter);
+            }
+        };
+        if let LayerChoice::Fallback(_) = choice {
+            slog::warn!(
+                self.logger,
+                "None of the {} Output Layers of the {:?} is bound to crtc {}, using the first",
+                attributes.len(),
+                kind,
+                crtc
+            );
+        }
+        slog::info!(self.logger, "Using Output Layer {} of the {:?}", choice.index(), kind);
+        Ok(Some(layers[choice.index()]))
+    }
 
-        let layer = layers[0];
+    fn create_stream(&self, handle: &Arc<EGLDisplayHandle>) -> Result<(), EGLError> {
+        let missing = missing_stream_extensions(handle);
+        if !missing.is_empty() {
+            slog::error!(self.logger, "Extension for EGLStream surface creation missing: {}", missing.join(", "));
+            return Err(EGLError::BadNativeWindow);
+        }
+
+        let layer = match self.options.output_layer {
+            OutputLayerKind::Plane => self.find_layer(handle, OutputLayerKind::Plane)?,
+            OutputLayerKind::Crtc => self.find_layer(handle, OutputLayerKind::Crtc)?,
+            // some older gpus only expose layers of the crtc
+            OutputLayerKind::Auto => match self.find_layer(handle, OutputLayerKind::Plane)? {
+                Some(layer) => Some(layer),
+                None => self.find_layer(handle, OutputLayerKind::Crtc)?,
+            },
+        };
+        let layer = match layer {
+            Some(layer) => layer,
+            None => {
+                slog::error!(self.logger, "Failed to find Output Layer");
+                return Err(EGLError::BadParameter);
+            }
+        };
         unsafe {
-            let mut interval = 1;
-            ffi::QueryOutputLayerAttribEXT(***handle, layer, ffi::MIN_SWAP_INTERVAL as i32, &mut interval);
-            slog::debug!(self.logger, "Min swap interval: {}", interval);
-            if interval == 0 { interval = 1; }
+            let (mut min, mut max): (ffi::types::EGLAttrib, ffi::types::EGLAttrib) = (1, 1);
+            ffi::QueryOutputLayerAttribEXT(***handle, layer, ffi::MIN_SWAP_INTERVAL as i32, &mut min);
+            ffi::QueryOutputLayerAttribEXT(***handle, layer, ffi::MAX_SWAP_INTERVAL as i32, &mut max);
+            slog::debug!(self.logger, "Swap interval range: {}-{}", min, max);
+            let requested = self.options.swap_interval as ffi::types::EGLAttrib;
+            let interval = requested.min(max).max(min);
+            if interval != requested {
+                slog::warn!(
+                    self.logger,
+                    "Swap interval {} is not supported by the output, using {}",
+                    requested,
+                    interval
+                );
+            }
             ffi::OutputLayerAttribEXT(***handle, layer, ffi::SWAP_INTERVAL_EXT as i32, interval);
         }
 
+        // frames are acquired explicitly after each swap, in FIFO mode that acquires the oldest queued frame,
+        // which may have to wait for the flip of the previous one. Timing out reports EGL_RESOURCE_BUSY_EXT,
+        // the frame then stays queued and the acquire is retried.
+        let fifo_length = self.options.fifo_length.min(MAX_FIFO_LENGTH) as i32;
         let stream_attributes = [
             ffi::STREAM_FIFO_LENGTH_KHR as i32,
-            0,
+            fifo_length,
             ffi::CONSUMER_AUTO_ACQUIRE_EXT as i32,
             ffi::FALSE as i32,
             ffi::CONSUMER_ACQUIRE_TIMEOUT_USEC_KHR as i32,
-            0,
+            self.options.acquire_timeout_usec.min(i32::MAX as u32) as i32,
             ffi::NONE as i32,
         ];
 
@@ -476,38 +1183,407 @@ unsafe impl EGLNativeSurface for EglStreamSurface {
         display: &Arc<EGLDisplayHandle>,
         surface: ffi::types::EGLSurface,
     ) -> Result<(), SwapBuffersError> {
-        let acquire_attributes = [
-            ffi::DRM_FLIP_EVENT_DATA_NV as isize,
-            Into::<u32>::into(self.crtc) as isize,
-            ffi::NONE as isize,
-        ];
-
-        let stream = self.stream.get().unwrap();
+        // invalidated by a resize, smithay recreates the surface on a bad surface
+        let stream = match self.stream.get() {
+            Some(stream) => stream,
+            None => return Err(SwapBuffersError::EGLSwapBuffers(EGLError::BadSurface)),
+        };
 
         let mut val = 0;
         unsafe { ffi::QueryStreamKHR(***display, stream, ffi::STREAM_STATE_KHR, &mut val as *mut _) };
         slog::debug!(self.logger, "Stream State (PRE SWAP): 0x{:x}", val);
 
-        let res = wrap_egl_call(|| unsafe { ffi::SwapBuffers(***display, surface as *const _) })
-            .map_err(SwapBuffersError::EGLSwapBuffers)?;
+        let res = wrap_egl_call_raw(|| unsafe { ffi::SwapBuffers(***display, surface as *const _) })
+            .map_err(|code| self.fail(display, stream, code))?;
         slog::debug!(self.logger, "res: {}", res);
         
         let mut val = 0;
         unsafe { ffi::QueryStreamKHR(***display, stream, ffi::STREAM_STATE_KHR, &mut val as *mut _) };
         slog::debug!(self.logger, "Stream State (AFTER SWAP): 0x{:x}", val);
-        wrap_egl_call(|| unsafe {
-            ffi::StreamConsumerAcquireAttribNV(
-                ***display,
-                stream,
-                acquire_attributes.as_ptr(),
-            );
-        })
-        .map_err(SwapBuffersError::EGLSwapBuffers)?;
 
-        let mut val = 0;
-        unsafe { ffi::QueryStreamKHR(***display, stream, ffi::STREAM_STATE_KHR, &mut val as *mut _) };
-        slog::debug!(self.logger, "Stream State (AFTER ACQUIRE): 0x{:x}", val);
-        
+        // acquired once the requests to the compositor went out, see `TargetBackend::acquire`
+        self.pending_acquire.set(Some(PendingAcquire {
+            display: display.clone(),
+            stream,
+            slot: self.stream.clone(),
+            crtc: self.crtc,
+        }));
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// EGL_BAD_SURFACE
+    const BAD_SURFACE: u32 = 0x300D;
+
+    #[test]
+    fn busy_depends_on_acquiring() {
+        let state = StreamState::NewFrameAvailable;
+        assert!(matches!(
+            NvEglError::decode(ffi::RESOURCE_BUSY_EXT, state, false),
+            NvEglError::ResourceBusy
+        ));
+        assert!(matches!(
+            NvEglError::decode(ffi::RESOURCE_BUSY_EXT, state, true),
+            NvEglError::AcquireTimeout
+        ));
+    }
+
+    #[test]
+    fn busy_acquire_times_out_in_every_state() {
+        let states = [
+            StreamState::Created,
+            StreamState::Connecting,
+            StreamState::Empty,
+            StreamState::NewFrameAvailable,
+            StreamState::OldFrameAvailable,
+            StreamState::Unknown(0),
+        ];
+        for state in states {
+            assert!(
+                matches!(NvEglError::decode(ffi::RESOURCE_BUSY_EXT, state, true), NvEglError::AcquireTimeout),
+                "{:?}",
+                state
+            );
+            assert!(
+                matches!(NvEglError::decode(ffi::RESOURCE_BUSY_EXT, state, false), NvEglError::ResourceBusy),
+                "{:?}",
+                state
+            );
+        }
+    }
+
+    #[test]
+    fn acquire_timeout_is_retried() {
+        let err = NvEglError::decode(ffi::RESOURCE_BUSY_EXT, StreamState::OldFrameAvailable, true);
+        assert!(err.is_retryable());
+        assert_eq!(err.kind(), "acquire timeout");
+        assert_eq!(err.to_string(), "Acquiring the frame timed out");
+        // other failures of the acquire are no timeouts
+        assert!(matches!(
+            NvEglError::decode(ffi::RESOURCE_BUSY_EXT, StreamState::Disconnected, true),
+            NvEglError::Disconnected
+        ));
+        assert!(matches!(
+            NvEglError::decode(BAD_SURFACE, StreamState::NewFrameAvailable, true),
+            NvEglError::Other(EGLError::BadSurface)
+        ));
+    }
+
+    #[test]
+    fn disconnected_stream_wins() {
+        for code in [ffi::RESOURCE_BUSY_EXT, ffi::BAD_STATE_KHR, BAD_SURFACE] {
+            assert!(matches!(
+                NvEglError::decode(code, StreamState::Disconnected, false),
+                NvEglError::Disconnected
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_state_keeps_the_state() {
+        assert!(matches!(
+            NvEglError::decode(ffi::BAD_STATE_KHR, StreamState::Empty, true),
+            NvEglError::BadState(StreamState::Empty)
+        ));
+    }
+
+    #[test]
+    fn other_errors() {
+        let err = NvEglError::decode(BAD_SURFACE, StreamState::OldFrameAvailable, false);
+        assert!(matches!(err, NvEglError::Other(EGLError::BadSurface)));
+        assert!(!err.is_retryable());
+        assert_eq!(err.kind(), "egl");
+    }
+
+    #[test]
+    fn retryable() {
+        assert!(NvEglError::ResourceBusy.is_retryable());
+        assert!(NvEglError::AcquireTimeout.is_retryable());
+        assert!(NvEglError::BadState(StreamState::Connecting).is_retryable());
+        assert!(NvEglError::Disconnected.is_retryable());
+        assert!(NvEglError::Surface(SwapBuffersError::EGLSwapBuffers(EGLError::BadSurface)).is_retryable());
+        assert!(!NvEglError::Surface(SwapBuffersError::EGLSwapBuffers(EGLError::BadAlloc)).is_retryable());
+        assert!(!NvEglError::Surface(SwapBuffersError::EGLCreateSurface(EGLError::BadSurface)).is_retryable());
+    }
+
+    #[test]
+    fn kinds_are_distinct() {
+        let kinds = [
+            NvEglError::ResourceBusy.kind(),
+            NvEglError::AcquireTimeout.kind(),
+            NvEglError::BadState(StreamState::Empty).kind(),
+            NvEglError::Disconnected.kind(),
+            NvEglError::Other(EGLError::BadAlloc).kind(),
+            NvEglError::Surface(SwapBuffersError::AlreadySwapped).kind(),
+        ];
+        for (i, kind) in kinds.iter().enumerate() {
+            assert!(!kinds[i + 1..].contains(kind), "{} is used twice", kind);
+        }
+    }
+
+    /// Lays out the drm entries `nodes` of a pci device in `sysfs`, like the kernel does
+    pub(crate) fn fake_sysfs(sysfs: &Path, nodes: &[(&str, u64)]) {
+        let device = sysfs.join("devices/pci0000:00/0000:01:00.0");
+        for (name, minor) in nodes {
+            let node = device.join("drm").join(name);
+            std::fs::create_dir_all(&node).unwrap();
+            std::os::unix::fs::symlink("../../../0000:01:00.0", node.join("device")).unwrap();
+            std::fs::create_dir_all(sysfs.join("dev/char")).unwrap();
+            std::os::unix::fs::symlink(&node, sysfs.join(format!("dev/char/226:{}", minor))).unwrap();
+        }
+    }
+
+    #[test]
+    fn nodes_of_either_node() {
+        let sysfs = tempfile::tempdir().unwrap();
+        fake_sysfs(sysfs.path(), &[("card1", 1), ("renderD129", 129)]);
+        let expected = DeviceNodes {
+            primary: Some(String::from("/dev/dri/card1")),
+            render: Some(String::from("/dev/dri/renderD129")),
+        };
+        for minor in [1, 129] {
+            let nodes = DeviceNodes::find(sysfs.path(), nix::sys::stat::makedev(226, minor)).unwrap();
+            assert_eq!(nodes, expected);
+        }
+        assert_eq!(expected.to_string(), "/dev/dri/card1 (/dev/dri/renderD129)");
+    }
+
+    #[test]
+    fn nodes_without_render_node() {
+        let sysfs = tempfile::tempdir().unwrap();
+        fake_sysfs(sysfs.path(), &[("card0", 0)]);
+        let nodes = DeviceNodes::find(sysfs.path(), nix::sys::stat::makedev(226, 0)).unwrap();
+        assert_eq!(nodes.primary.as_deref(), Some("/dev/dri/card0"));
+        assert_eq!(nodes.render, None);
+    }
+
+    #[test]
+    fn nodes_of_unknown_device() {
+        let sysfs = tempfile::tempdir().unwrap();
+        fake_sysfs(sysfs.path(), &[("card0", 0)]);
+        assert!(DeviceNodes::find(sysfs.path(), nix::sys::stat::makedev(226, 1)).is_err());
+    }
+
+    #[test]
+    fn nodes_of_device_without_nodes() {
+        let sysfs = tempfile::tempdir().unwrap();
+        fake_sysfs(sysfs.path(), &[("controlD64", 64)]);
+        assert!(DeviceNodes::find(sysfs.path(), nix::sys::stat::makedev(226, 64)).is_err());
+    }
+
+    #[test]
+    fn nodes_match_either() {
+        let nodes = DeviceNodes {
+            primary: Some(String::from("/dev/dri/card0")),
+            render: Some(String::from("/dev/dri/renderD128")),
+        };
+        assert!(nodes.matches(Some("/dev/dri/card0"), None));
+        assert!(nodes.matches(None, Some("/dev/dri/renderD128")));
+        assert!(nodes.matches(Some("/dev/dri/card1"), Some("/dev/dri/renderD128")));
+        assert!(!nodes.matches(Some("/dev/dri/card1"), Some("/dev/dri/renderD129")));
+        assert!(!nodes.matches(None, None));
+        let primary_only = DeviceNodes {
+            primary: Some(String::from("/dev/dri/card0")),
+            render: None,
+        };
+        assert!(!primary_only.matches(None, Some("/dev/dri/renderD128")));
+    }
+
+    #[test]
+    fn stream_states() {
+        assert_eq!(StreamState::from(ffi::STREAM_STATE_DISCONNECTED_KHR as i32), StreamState::Disconnected);
+        assert_eq!(StreamState::from(ffi::STREAM_STATE_NEW_FRAME_AVAILABLE_KHR as i32), StreamState::NewFrameAvailable);
+        assert_eq!(StreamState::from(-1), StreamState::Unknown(-1));
+    }
+
+    fn config(id: i32, bits: i32, alpha: i32, samples: i32) -> ConfigAttributes {
+        ConfigAttributes {
+            id,
+            red: bits,
+            green: bits,
+            blue: bits,
+            alpha,
+            depth: 0,
+            stencil: 0,
+            samples,
+            max_width: 16384,
+            max_height: 16384,
+        }
+    }
+
+    fn ranked(configs: &[ConfigAttributes], bits: i32) -> Vec<i32> {
+        let mut candidates = configs
+            .iter()
+            .filter_map(|config| config.rank(bits).map(|rank| (rank, config.id)))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.into_iter().map(|(_, id)| id).collect()
+    }
+
+    #[test]
+    fn configs_lacking_bits_are_unsuitable() {
+        assert_eq!(config(1, 8, 0, 0).rank(10), None);
+        assert_eq!(config(2, 5, 0, 0).rank(8), None);
+        assert!(config(3, 10, 2, 0).rank(10).is_some());
+        assert!(config(4, 10, 2, 0).rank(8).is_some());
+    }
+
+    #[test]
+    fn configs_by_preference() {
+        let configs = [
+            config(1, 10, 2, 0),
+            config(2, 8, 8, 0),
+            config(3, 8, 0, 4),
+            config(4, 8, 0, 0),
+            ConfigAttributes {
+                depth: 24,
+                stencil: 8,
+                ..config(5, 8, 0, 0)
+            },
+            config(6, 8, 0, 0),
+        ];
+        // exact depth, then no multisampling, no alpha, no ancillary buffers and the lowest id
+        assert_eq!(ranked(&configs, 8), vec![4, 6, 5, 2, 3, 1]);
+        assert_eq!(ranked(&configs, 10), vec![1]);
+    }
+
+    #[test]
+    fn config_description() {
+        let config = ConfigAttributes {
+            depth: 24,
+            ..config(7, 8, 8, 0)
+        };
+        assert_eq!(config.to_string(), "config 7 (rgba 8/8/8/8, depth 24, stencil 0, 0 samples)");
+    }
+
+    #[test]
+    fn layer_selection() {
+        let layer = |crtc: Option<u32>| LayerAttributes { crtc, plane: Some(31) };
+        let cases: &[(&[LayerAttributes], Option<usize>, Option<LayerChoice>)] = &[
+            (&[], None, None),
+            (&[layer(Some(40))], None, Some(LayerChoice::Only(0))),
+            // a single layer is taken whatever it is bound to
+            (&[layer(Some(41))], None, Some(LayerChoice::Only(0))),
+            (&[layer(Some(41)), layer(Some(40))], None, Some(LayerChoice::Matching(1))),
+            (&[layer(Some(40)), layer(Some(40))], None, Some(LayerChoice::Matching(0))),
+            (&[layer(None), layer(Some(40))], None, Some(LayerChoice::Matching(1))),
+            (&[layer(Some(41)), layer(None)], None, Some(LayerChoice::Fallback(0))),
+            (&[layer(Some(41)), layer(Some(40))], Some(0), Some(LayerChoice::Forced(0))),
+            (&[layer(Some(41)), layer(Some(40))], Some(2), None),
+            (&[], Some(0), None),
+        ];
+        for (layers, forced, expected) in cases {
+            assert_eq!(select_layer(layers, 40, *forced), *expected, "{:?} forced {:?}", layers, forced);
+        }
+        assert_eq!(LayerChoice::Fallback(3).index(), 3);
+    }
+
+    /// Tracks its stream like `EglStreamSurface`, without a display to create it on
+    struct MockSurface {
+        stream: StreamSlot,
+        mode: Cell<(i32, i32)>,
+        created: Cell<usize>,
+    }
+
+    unsafe impl Send for MockSurface {}
+    unsafe impl Sync for MockSurface {}
+
+    impl MockSurface {
+        fn new(mode: (i32, i32)) -> MockSurface {
+            MockSurface {
+                stream: Rc::new(Cell::new(None)),
+                mode: Cell::new(mode),
+                created: Cell::new(0),
+            }
+        }
+
+        fn recreate(&self) {
+            self.created.set(self.created.get() + 1);
+            self.stream.set(Some(self.created.get() as ffi::types::EGLStreamKHR));
+        }
+
+        /// What `TargetGPU::ensure_surface` does before every frame, with the stream in `state`
+        fn ensure(&self, state: StreamState) -> bool {
+            let recreate = invalidate_disconnected(&self.stream, |_| state);
+            assert_eq!(recreate, EGLNativeSurface::needs_recreation(self));
+            if recreate {
+                self.recreate();
+            }
+            recreate
+        }
+    }
+
+    unsafe impl EGLNativeSurface for MockSurface {
+        fn create(
+            &self,
+            _display: &Arc<EGLDisplayHandle>,
+            _config_id: ffi::types::EGLConfig,
+        ) -> Result<*const nix::libc::c_void, EGLError> {
+            self.recreate();
+            Ok(ptr::null())
+        }
+
+        fn needs_recreation(&self) -> bool {
+            self.stream.get().is_none()
+        }
+
+        fn resize(&self, width: i32, height: i32, _dx: i32, _dy: i32) -> bool {
+            if self.mode.get() != (width, height) {
+                self.stream.set(None);
+                self.mode.set((width, height));
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn surface_transitions() {
+        let surface = MockSurface::new((1920, 1080));
+        // created on the first frame
+        assert!(surface.ensure(StreamState::Created));
+        assert!(!surface.ensure(StreamState::Empty));
+        assert!(!surface.ensure(StreamState::NewFrameAvailable));
+        assert_eq!(surface.created.get(), 1);
+
+        // a resize to the same mode keeps the stream
+        assert!(surface.resize(1920, 1080, 0, 0));
+        assert!(!surface.ensure(StreamState::OldFrameAvailable));
+        assert_eq!(surface.created.get(), 1);
+
+        // a mode change invalidates it, whatever state the old stream is in
+        assert!(surface.resize(1280, 720, 0, 0));
+        assert!(surface.stream.get().is_none());
+        assert!(surface.ensure(StreamState::OldFrameAvailable));
+        assert_eq!(surface.created.get(), 2);
+        assert_eq!(surface.mode.get(), (1280, 720));
+
+        // the output layer let go of the stream, e.g. across a DPMS cycle
+        assert!(surface.ensure(StreamState::Disconnected));
+        assert_eq!(surface.created.get(), 3);
+        assert!(!surface.ensure(StreamState::Empty));
+        assert_eq!(surface.stream.get(), Some(3 as ffi::types::EGLStreamKHR));
+    }
+
+    #[test]
+    fn only_disconnected_streams_are_invalidated() {
+        let slot: StreamSlot = Rc::new(Cell::new(None));
+        assert!(invalidate_disconnected(&slot, |_| panic!("There is no stream to query")));
+        for state in [
+            StreamState::Created,
+            StreamState::Connecting,
+            StreamState::Empty,
+            StreamState::NewFrameAvailable,
+            StreamState::OldFrameAvailable,
+            StreamState::Unknown(0),
+        ] {
+            slot.set(Some(1 as ffi::types::EGLStreamKHR));
+            assert!(!invalidate_disconnected(&slot, |_| state), "{:?}", state);
+            assert!(slot.get().is_some());
+        }
+        assert!(invalidate_disconnected(&slot, |_| StreamState::Disconnected));
+        assert!(slot.get().is_none());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{stats::Stats, status::Status, CopyState};
+
+use std::time::Duration;
+
+/// State changes of a running `ScreenCopy`, for embedders that want to show its status
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Frames started taking a different path from the compositor to the target
+    CopyPath(CopyState),
+    /// Sent along the periodic latency report
+    Stats(FrameStats),
+    /// The session or another process took the output away, nothing is mirrored until `Resumed`
+    Paused,
+    Resumed,
+    /// Everything was set up from scratch, e.g. after a system resume
+    Reinitialized,
+    /// Something went wrong, that was recovered from
+    Error(String),
+    /// The `state` of the status changed, e.g. mirroring started or the monitor was unplugged
+    StateChanged(Status),
+    /// Something went wrong that can't be recovered from, mirroring stops
+    Fatal(String),
+}
+
+/// Snapshot of the runtime statistics
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameStats {
+    /// Frames per second arriving from the compositor
+    pub captured_fps: f64,
+    /// Frames per second with new content, which is what the source actually renders
+    pub source_fps: f64,
+    /// Frames per second reaching the screen
+    pub displayed_fps: f64,
+    /// Average and 95th percentile of the capture to scanout latency, `None` until a frame was displayed
+    pub latency: Option<(Duration, Duration)>,
+    /// Frames dropped since the start, because a newer one arrived before the output took them
+    pub dropped_frames: u64,
+    /// Wayland events nothing handled since the start, which hints at a protocol version mismatch
+    pub orphan_events: u64,
+}
+
+impl FrameStats {
+    pub fn of(stats: &Stats) -> FrameStats {
+        let (captured_fps, displayed_fps) = stats.fps();
+        FrameStats {
+            captured_fps,
+            source_fps: stats.source_fps(),
+            displayed_fps,
+            latency: stats.latency(),
+            dropped_frames: stats.frames_dropped(),
+            orphan_events: stats.orphan_events(),
+        }
+    }
+}
+
+/// Passes events on to everyone following them: the callback given to `ScreenCopy::on_event`, if any,
+/// and internal subscribers like desktop notifications
+#[derive(Default)]
+pub struct Events {
+    subscribers: Vec<Box<dyn FnMut(&Event)>>,
+}
+
+impl Events {
+    pub fn new(callback: Option<Box<dyn FnMut(Event)>>) -> Events {
+        let mut events = Events::default();
+        if let Some(mut callback) = callback {
+            events.subscribe(move |event| callback(event.clone()));
+        }
+        events
+    }
+
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&Event) + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber(&event);
+        }
+    }
+}
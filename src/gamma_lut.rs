@@ -0,0 +1,500 @@
+use anyhow::{Context, Result};
+
+use std::{convert::TryInto, path::Path};
+
+/// Entries of the CSV tables `--gamma-lut` accepts
+const CSV_SIZES: [usize; 2] = [256, 1024];
+/// Offset of the "acsp" signature in the header of ICC profiles
+const ICC_SIGNATURE_OFFSET: usize = 36;
+/// Offset of the tag table, following the 128 byte header
+const ICC_TAG_TABLE_OFFSET: usize = 128;
+/// Entries generated for a VCGT given by formula
+const FORMULA_SIZE: usize = 256;
+/// Bytes of a `drm_color_lut` entry: red, green, blue and a reserved u16
+const DRM_COLOR_LUT_SIZE: usize = 8;
+
+/// Gamma table for the crtc of the target, given by `--gamma-lut`.
+///
+/// Entries map the evenly spaced inputs from black to white onto outputs between 0 and 65535.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GammaLut {
+    pub red: Vec<u16>,
+    pub green: Vec<u16>,
+    pub blue: Vec<u16>,
+}
+
+impl GammaLut {
+    /// Identity table of `size` entries
+    pub fn linear(size: usize) -> GammaLut {
+        let ramp = (0..size)
+            .map(|index| (index * 0xffff / size.saturating_sub(1).max(1)) as u16)
+            .collect::<Vec<_>>();
+        GammaLut {
+            red: ramp.clone(),
+            green: ramp.clone(),
+            blue: ramp,
+        }
+    }
+
+    /// Reads the VCGT tag of an ICC profile or a CSV table, see `from_csv`
+    pub fn load(path: &Path) -> Result<GammaLut> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read the gamma table {}", path.display()))?;
+        let lut = if data.get(ICC_SIGNATURE_OFFSET..ICC_SIGNATURE_OFFSET + 4) == Some(&b"acsp"[..]) {
+            GammaLut::from_icc(&data)
+        } else {
+            std::str::from_utf8(&data)
+                .context("Neither an ICC profile nor a CSV table")
+                .and_then(GammaLut::from_csv)
+        }
+        .with_context(|| format!("Invalid gamma table {}", path.display()))?;
+        lut.validate()
+            .with_context(|| format!("Invalid gamma table {}", path.display()))?;
+        Ok(lut)
+    }
+
+    /// 256 or 1024 lines of "red,green,blue", either integers up to 65535 or fractions between 0 and 1.
+    ///
+    /// Columns may also be separated by semicolons or whitespace, lines starting with # are comments.
+    pub fn from_csv(text: &str) -> Result<GammaLut> {
+        let mut lut = GammaLut {
+            red: Vec::new(),
+            green: Vec::new(),
+            blue: Vec::new(),
+        };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                .filter(|value| !value.is_empty())
+                .map(parse_entry)
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Invalid entry in line {}", number + 1))?;
+            match values.as_slice() {
+                [red, green, blue] => {
+                    lut.red.push(*red);
+                    lut.green.push(*green);
+                    lut.blue.push(*blue);
+                }
+                _ => anyhow::bail!("Line {} has {} columns instead of 3", number + 1, values.len()),
+            }
+        }
+        if !CSV_SIZES.contains(&lut.red.len()) {
+            anyhow::bail!(
+                "Table has {} entries, it needs {} or {}",
+                lut.red.len(),
+                CSV_SIZES[0],
+                CSV_SIZES[1]
+            );
+        }
+        Ok(lut)
+    }
+
+    /// The VCGT tag of an ICC profile, as a table or a formula per channel
+    pub fn from_icc(data: &[u8]) -> Result<GammaLut> {
+        let count = read_u32(data, ICC_TAG_TABLE_OFFSET)? as usize;
+        let tag = (0..count)
+            .map(|index| ICC_TAG_TABLE_OFFSET + 4 + index * 12)
+            .find(|entry| data.get(*entry..*entry + 4) == Some(&b"vcgt"[..]))
+            .context("The ICC profile has no VCGT tag")?;
+        let offset = read_u32(data, tag + 4)? as usize;
+        let gamma_type = read_u32(data, offset + 8)?;
+        match gamma_type {
+            // table
+            0 => {
+                let channels = read_u16(data, offset + 12)? as usize;
+                let entries = read_u16(data, offset + 14)? as usize;
+                let entry_size = read_u16(data, offset + 16)? as usize;
+                if channels != 3 {
+                    anyhow::bail!("VCGT table has {} channels instead of 3", channels);
+                }
+                let read = |index: usize| -> Result<u16> {
+                    let at = offset + 18 + index * entry_size;
+                    match entry_size {
+                        1 => Ok(*data.get(at).context("VCGT table ends early")? as u16 * 0x101),
+                        2 => read_u16(data, at),
+                        size => anyhow::bail!("VCGT table has entries of {} bytes", size),
+                    }
+                };
+                let channel = |channel: usize| -> Result<Vec<u16>> {
+                    (0..entries).map(|index| read(channel * entries + index)).collect()
+                };
+                Ok(GammaLut {
+                    red: channel(0)?,
+                    green: channel(1)?,
+                    blue: channel(2)?,
+                })
+            }
+            // gamma, minimum and maximum of every channel as s15Fixed16
+            1 => {
+                let channel = |channel: usize| -> Result<Vec<u16>> {
+                    let at = offset + 12 + channel * 12;
+                    let fixed = |at: usize| read_u32(data, at).map(|value| value as i32 as f64 / 65536.0);
+                    let (gamma, min, max) = (fixed(at)?, fixed(at + 4)?, fixed(at + 8)?);
+                    Ok((0..FORMULA_SIZE)
+                        .map(|index| {
+                            let input = index as f64 / (FORMULA_SIZE - 1) as f64;
+                            to_entry(min + (max - min) * input.powf(gamma))
+                        })
+                        .collect())
+                };
+                Ok(GammaLut {
+                    red: channel(0)?,
+                    green: channel(1)?,
+                    blue: channel(2)?,
+                })
+            }
+            other => anyhow::bail!("Unknown VCGT type {}", other),
+        }
+    }
+
+    /// Fails unless all channels have the same number of entries, at least two, and never decrease
+    pub fn validate(&self) -> Result<()> {
+        let size = self.red.len();
+        if self.green.len() != size || self.blue.len() != size {
+            anyhow::bail!(
+                "Channels have {}, {} and {} entries",
+                size,
+                self.green.len(),
+                self.blue.len()
+            );
+        }
+        if size < 2 {
+            anyhow::bail!("Table has {} entries, it needs at least 2", size);
+        }
+        for (name, channel) in [("red", &self.red), ("green", &self.green), ("blue", &self.blue)] {
+            if let Some(index) = channel.windows(2).position(|pair| pair[1] < pair[0]) {
+                anyhow::bail!(
+                    "The {} channel decreases from {} to {} at entry {}",
+                    name,
+                    channel[index],
+                    channel[index + 1],
+                    index + 1
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.red.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.red.is_empty()
+    }
+
+    /// The table interpolated to `size` entries, the gamma size of the crtc
+    pub fn resampled(&self, size: usize) -> GammaLut {
+        self.map(size, |input| input)
+    }
+
+    /// The table resampled for a crtc with a gamma table of `size` entries, failing if it has none
+    pub fn for_crtc(&self, size: usize) -> Result<GammaLut> {
+        if size < 2 {
+            anyhow::bail!("--gamma-lut needs a crtc with a gamma table, this one has {} entries", size);
+        }
+        Ok(self.resampled(size))
+    }
+
+    /// The table applied after raising its inputs to the power of 1/`gamma`, like `--gamma` does in the shader
+    pub fn with_gamma(&self, gamma: f32) -> GammaLut {
+        self.map(self.len(), |input| input.powf(1.0 / gamma as f64))
+    }
+
+    /// The table taking over the `--gamma` of the shader, which is reset to 1 so it is not applied twice
+    pub fn take_over_gamma(&self, gamma: &mut f32) -> GammaLut {
+        let lut = self.with_gamma(*gamma);
+        *gamma = 1.0;
+        lut
+    }
+
+    /// Table of `size` entries, looking up `input` of the evenly spaced inputs between 0 and 1
+    fn map(&self, size: usize, input: impl Fn(f64) -> f64) -> GammaLut {
+        let channel = |channel: &[u16]| {
+            (0..size)
+                .map(|index| sample(channel, input(index as f64 / size.saturating_sub(1).max(1) as f64)))
+                .collect()
+        };
+        GammaLut {
+            red: channel(&self.red),
+            green: channel(&self.green),
+            blue: channel(&self.blue),
+        }
+    }
+
+    /// Contents of a "GAMMA_LUT" blob, an array of `drm_color_lut`
+    pub fn to_drm_color_lut(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.len() * DRM_COLOR_LUT_SIZE);
+        for index in 0..self.len() {
+            for value in [self.red[index], self.green[index], self.blue[index], 0] {
+                data.extend_from_slice(&value.to_ne_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn from_drm_color_lut(data: &[u8]) -> GammaLut {
+        let value = |entry: &[u8], at: usize| u16::from_ne_bytes([entry[at], entry[at + 1]]);
+        let entries = data.chunks_exact(DRM_COLOR_LUT_SIZE);
+        GammaLut {
+            red: entries.clone().map(|entry| value(entry, 0)).collect(),
+            green: entries.clone().map(|entry| value(entry, 2)).collect(),
+            blue: entries.map(|entry| value(entry, 4)).collect(),
+        }
+    }
+}
+
+/// Linear interpolation of `channel` at `input` between 0 and 1
+fn sample(channel: &[u16], input: f64) -> u16 {
+    let position = input.max(0.0).min(1.0) * (channel.len() - 1) as f64;
+    let below = position.floor() as usize;
+    let above = (below + 1).min(channel.len() - 1);
+    let fraction = position - below as f64;
+    (channel[below] as f64 * (1.0 - fraction) + channel[above] as f64 * fraction).round() as u16
+}
+
+/// Output between 0 and 1 as an entry
+fn to_entry(value: f64) -> u16 {
+    (value.max(0.0).min(1.0) * 65535.0).round() as u16
+}
+
+fn parse_entry(value: &str) -> Result<u16> {
+    if value.contains('.') {
+        match value.parse::<f64>() {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(to_entry(fraction)),
+            _ => anyhow::bail!("\"{}\" is no fraction between 0 and 1", value),
+        }
+    } else {
+        value
+            .parse::<u16>()
+            .with_context(|| format!("\"{}\" is no integer up to 65535", value))
+    }
+}
+
+/// ICC profiles are big endian
+fn read_u32(data: &[u8], at: usize) -> Result<u32> {
+    let bytes = data.get(at..at + 4).context("ICC profile ends early")?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16> {
+    let bytes = data.get(at..at + 2).context("ICC profile ends early")?;
+    Ok(u16::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ICC profile holding nothing but the `vcgt` tag
+    fn icc(vcgt: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; ICC_TAG_TABLE_OFFSET];
+        data[ICC_SIGNATURE_OFFSET..ICC_SIGNATURE_OFFSET + 4].copy_from_slice(b"acsp");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"vcgt");
+        data.extend_from_slice(&(ICC_TAG_TABLE_OFFSET as u32 + 16).to_be_bytes());
+        data.extend_from_slice(&(vcgt.len() as u32).to_be_bytes());
+        data.extend_from_slice(vcgt);
+        data
+    }
+
+    fn vcgt(gamma_type: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = b"vcgt\0\0\0\0".to_vec();
+        data.extend_from_slice(&gamma_type.to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn vcgt_table(channels: u16, entries: u16, entry_size: u16, values: &[u16]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for value in [channels, entries, entry_size] {
+            body.extend_from_slice(&value.to_be_bytes());
+        }
+        for value in values {
+            match entry_size {
+                1 => body.push(*value as u8),
+                _ => body.extend_from_slice(&value.to_be_bytes()),
+            }
+        }
+        vcgt(0, &body)
+    }
+
+    fn csv(size: usize, line: impl Fn(usize) -> String) -> String {
+        (0..size).map(|index| line(index) + "\n").collect()
+    }
+
+    #[test]
+    fn csv_tables() {
+        let lut = GammaLut::from_csv(&csv(256, |index| format!("{},{},{}", index * 257, index * 256, 0))).unwrap();
+        assert_eq!(lut.len(), 256);
+        assert_eq!(lut.red[255], 65535);
+        assert_eq!(lut.green[255], 65280);
+        assert_eq!(lut.blue, vec![0; 256]);
+
+        // fractions, other separators and comments
+        let text = String::from("# red green blue\n\n") + &csv(1024, |index| format!("{} ;0.5\t1.0", index));
+        let lut = GammaLut::from_csv(&text).unwrap();
+        assert_eq!(lut.len(), 1024);
+        assert_eq!((lut.red[1023], lut.green[1023], lut.blue[1023]), (1023, 32768, 65535));
+    }
+
+    #[test]
+    fn invalid_csv_tables() {
+        let cases = [
+            csv(255, |_| String::from("0,0,0")),
+            csv(512, |_| String::from("0,0,0")),
+            csv(256, |_| String::from("0,0")),
+            csv(256, |_| String::from("0,0,0,0")),
+            csv(256, |_| String::from("0,0,65536")),
+            csv(256, |_| String::from("0,0,1.5")),
+            csv(256, |_| String::from("0,0,-1")),
+            csv(256, |_| String::from("0,0,red")),
+        ];
+        for text in cases.iter() {
+            assert!(GammaLut::from_csv(text).is_err(), "{:?}", &text[..text.find('\n').unwrap()]);
+        }
+    }
+
+    #[test]
+    fn icc_tables() {
+        let values = (0..3u16)
+            .flat_map(|channel| (0..4u16).map(move |index| index * 0x1000 + channel))
+            .collect::<Vec<_>>();
+        let lut = GammaLut::from_icc(&icc(&vcgt_table(3, 4, 2, &values))).unwrap();
+        assert_eq!(lut.red, [0, 0x1000, 0x2000, 0x3000]);
+        assert_eq!(lut.green, [1, 0x1001, 0x2001, 0x3001]);
+        assert_eq!(lut.blue, [2, 0x1002, 0x2002, 0x3002]);
+
+        // bytes are scaled to the whole range
+        let lut = GammaLut::from_icc(&icc(&vcgt_table(3, 2, 1, &[0, 255, 0, 128, 16, 32]))).unwrap();
+        assert_eq!(lut.red, [0, 0xffff]);
+        assert_eq!(lut.green, [0, 0x8080]);
+        assert_eq!(lut.blue, [0x1010, 0x2020]);
+    }
+
+    #[test]
+    fn icc_formulas() {
+        let fixed = |value: f64| ((value * 65536.0) as i32 as u32).to_be_bytes();
+        let mut body = Vec::new();
+        for (gamma, min, max) in [(1.0, 0.0, 1.0), (2.0, 0.0, 1.0), (1.0, 0.25, 0.75)] {
+            for value in [gamma, min, max] {
+                body.extend_from_slice(&fixed(value));
+            }
+        }
+        let lut = GammaLut::from_icc(&icc(&vcgt(1, &body))).unwrap();
+        assert_eq!(lut.len(), FORMULA_SIZE);
+        assert_eq!(lut.red, GammaLut::linear(FORMULA_SIZE).red);
+        assert_eq!(lut.green[0], 0);
+        assert_eq!(lut.green[255], 65535);
+        assert_eq!(lut.green[128], to_entry((128.0f64 / 255.0).powi(2)));
+        assert_eq!((lut.blue[0], lut.blue[255]), (16384, 49151));
+    }
+
+    #[test]
+    fn invalid_icc_profiles() {
+        let table = vcgt_table(3, 2, 2, &[0, 1, 0, 1, 0, 1]);
+        // the only tag is another one
+        let mut untagged = icc(&table);
+        untagged[ICC_TAG_TABLE_OFFSET + 4] = b'x';
+        let mut truncated = icc(&table);
+        truncated.pop();
+        let cases = [
+            untagged,
+            icc(&vcgt_table(1, 2, 2, &[0, 1])),
+            icc(&vcgt_table(3, 2, 4, &[0; 12])),
+            icc(&vcgt(2, &[])),
+            truncated,
+        ];
+        for (index, profile) in cases.iter().enumerate() {
+            assert!(GammaLut::from_icc(profile).is_err(), "case {}", index);
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(GammaLut::linear(256).validate().is_ok());
+        assert!(GammaLut::linear(2).validate().is_ok());
+        assert!(GammaLut::linear(1).validate().is_err());
+        let mut lut = GammaLut::linear(256);
+        lut.blue.pop();
+        assert!(lut.validate().is_err());
+        let mut lut = GammaLut::linear(256);
+        // flat is fine, decreasing is not
+        lut.green[10] = lut.green[9];
+        assert!(lut.validate().is_ok());
+        lut.green[10] = lut.green[9] - 1;
+        let error = lut.validate().unwrap_err();
+        assert!(error.to_string().contains("green channel decreases"), "{}", error);
+    }
+
+    #[test]
+    fn loading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("projector.icc");
+        std::fs::write(&path, icc(&vcgt_table(3, 2, 2, &[0, 0xffff, 0, 0x8000, 0x100, 0x200]))).unwrap();
+        assert_eq!(GammaLut::load(&path).unwrap().green, [0, 0x8000]);
+
+        let path = dir.path().join("projector.csv");
+        std::fs::write(&path, csv(256, |index| format!("{0},{0},{0}", index))).unwrap();
+        assert_eq!(GammaLut::load(&path).unwrap().red[200], 200);
+        // parsed, but decreasing
+        std::fs::write(&path, csv(256, |index| format!("{0},{0},{1}", index, 255 - index))).unwrap();
+        assert!(GammaLut::load(&path).is_err());
+        assert!(GammaLut::load(&dir.path().join("missing.csv")).is_err());
+    }
+
+    #[test]
+    fn crtc_sizes() {
+        let lut = GammaLut {
+            red: vec![0, 65535],
+            green: vec![0, 32768],
+            blue: vec![65535, 65535],
+        };
+        let resampled = lut.for_crtc(1024).unwrap();
+        assert_eq!(resampled.len(), 1024);
+        assert!(resampled.validate().is_ok());
+        assert_eq!((resampled.red[0], resampled.red[511], resampled.red[1023]), (0, 32735, 65535));
+        assert_eq!((resampled.green[0], resampled.green[1023]), (0, 32768));
+        assert_eq!(resampled.blue, vec![65535; 1024]);
+        assert_eq!(GammaLut::linear(256).for_crtc(256).unwrap(), GammaLut::linear(256));
+        // a crtc without gamma table
+        assert!(lut.for_crtc(0).is_err());
+        assert!(lut.for_crtc(1).is_err());
+    }
+
+    #[test]
+    fn shader_gamma_is_applied_once() {
+        let lut = GammaLut::linear(256);
+        let mut gamma = 1.0;
+        assert_eq!(lut.take_over_gamma(&mut gamma), lut);
+        assert_eq!(gamma, 1.0);
+
+        let mut gamma = 2.0;
+        let combined = lut.take_over_gamma(&mut gamma);
+        // the shader leaves the frames alone now
+        assert_eq!(gamma, 1.0);
+        assert_eq!(combined.red[64], to_entry((64.0f64 / 255.0).sqrt()));
+        assert_eq!((combined.red[0], combined.red[255]), (0, 65535));
+        assert_eq!(combined.take_over_gamma(&mut gamma), combined);
+    }
+
+    #[test]
+    fn drm_color_luts() {
+        let lut = GammaLut {
+            red: vec![0, 0x1234],
+            green: vec![1, 0x5678],
+            blue: vec![2, 0x9abc],
+        };
+        let data = lut.to_drm_color_lut();
+        assert_eq!(data.len(), 2 * DRM_COLOR_LUT_SIZE);
+        let entry = [0x1234u16, 0x5678, 0x9abc, 0]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(data[8..16], entry[..]);
+        assert_eq!(GammaLut::from_drm_color_lut(&data), lut);
+    }
+}
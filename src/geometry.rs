@@ -0,0 +1,612 @@
+use smithay::{
+    backend::renderer::Transform,
+    utils::{Buffer, Physical, Point, Rectangle, Size},
+};
+use wayland_client::protocol::wl_output;
+
+use std::str::FromStr;
+
+/// How the shown region of a source is drawn onto the target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    /// Region of the source, within its size
+    pub src: Rectangle<i32, Buffer>,
+    /// Region of the target `src` is drawn into
+    pub dst: Rectangle<f64, Physical>,
+}
+
+impl Mapping {
+    /// Horizontal and vertical factors `src` is scaled by
+    pub fn scale(&self) -> (f64, f64) {
+        scale_factors(self.src.size, self.dst)
+    }
+
+    /// Position on the target `point` of the source is drawn at
+    pub fn map(&self, point: Point<f64, Buffer>) -> Point<f64, Physical> {
+        let (x, y) = self.scale();
+        Point::from((
+            self.dst.loc.x + (point.x - self.src.loc.x as f64) * x,
+            self.dst.loc.y + (point.y - self.src.loc.y as f64) * y,
+        ))
+    }
+}
+
+/// Maps `region` of a source of `size` onto a target of `dest_size`.
+///
+/// The region is stretched over the whole target, so mismatched modes show
+/// all of it, distorted if the aspect ratios differ. Parts of `region` outside of the source are dropped.
+pub fn destination(size: Size<i32, Buffer>, region: Rectangle<i32, Buffer>, dest_size: Size<i32, Physical>) -> Mapping {
+    let x = region.loc.x.max(0).min(size.w);
+    let y = region.loc.y.max(0).min(size.h);
+    let x2 = (region.loc.x + region.size.w).max(x).min(size.w);
+    let y2 = (region.loc.y + region.size.h).max(y).min(size.h);
+    Mapping {
+        src: Rectangle::from_loc_and_size((x, y), (x2 - x, y2 - y)),
+        dst: Rectangle::from_loc_and_size((0.0, 0.0), (dest_size.w as f64, dest_size.h as f64)),
+    }
+}
+
+/// Where a single source is shown unscaled on a larger target, given as "X,Y" or "center"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Center,
+    At(Point<i32, Physical>),
+}
+
+impl Placement {
+    /// Top left corner of a source of `size` on the target, fails if the source does not fit onto it
+    pub fn offset(
+        self,
+        size: Size<i32, Buffer>,
+        dest_size: Size<i32, Physical>,
+    ) -> anyhow::Result<Point<i32, Physical>> {
+        let offset = match self {
+            Placement::Center => Point::from(((dest_size.w - size.w) / 2, (dest_size.h - size.h) / 2)),
+            Placement::At(position) => position,
+        };
+        if offset.x < 0 || offset.y < 0 || offset.x + size.w > dest_size.w || offset.y + size.h > dest_size.h {
+            anyhow::bail!(
+                "Source of {}x{} at {},{} exceeds the output of {}x{}",
+                size.w,
+                size.h,
+                offset.x,
+                offset.y,
+                dest_size.w,
+                dest_size.h
+            );
+        }
+        Ok(offset)
+    }
+}
+
+impl FromStr for Placement {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Placement> {
+        if input == "center" {
+            return Ok(Placement::Center);
+        }
+        let parts = input
+            .split(',')
+            .map(|x| u32::from_str_radix(x, 10).map(|x| x as i32))
+            .collect::<Result<Vec<i32>, _>>()
+            .map_err(|err| anyhow::anyhow!("Failed to parse position: {}", err))?;
+        if parts.len() != 2 {
+            anyhow::bail!("Position needs to have the format \"X,Y\" or be \"center\"");
+        }
+        Ok(Placement::At(Point::from((parts[0], parts[1]))))
+    }
+}
+
+/// Arrangement of several sources in cells of equal size, given as "grid" or "COLSxROWS"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// As many columns as rows or one more, which keeps the cells close to the aspect ratio of the target
+    Grid,
+    Fixed { columns: u32, rows: u32 },
+}
+
+impl Layout {
+    /// Columns and rows holding `count` sources
+    pub fn dimensions(self, count: usize) -> (u32, u32) {
+        match self {
+            Layout::Grid => {
+                let count = count.max(1) as u32;
+                let columns = (1..).find(|columns| columns * columns >= count).unwrap();
+                (columns, (count + columns - 1) / columns)
+            }
+            Layout::Fixed { columns, rows } => (columns, rows),
+        }
+    }
+
+    /// Fails if a fixed layout has fewer cells than `count` sources
+    pub fn fits(self, count: usize) -> anyhow::Result<()> {
+        let (columns, rows) = self.dimensions(count);
+        if (columns * rows) < count as u32 {
+            anyhow::bail!("Layout of {}x{} has no cells for all {} sources", columns, rows, count);
+        }
+        Ok(())
+    }
+
+    /// Cells of `count` sources on a target of `dest_size`, filled row by row from the top left
+    pub fn cells(self, count: usize, dest_size: Size<i32, Physical>) -> anyhow::Result<Vec<Rectangle<f64, Physical>>> {
+        self.fits(count)?;
+        let (columns, rows) = self.dimensions(count);
+        let (width, height) = (dest_size.w as f64 / columns as f64, dest_size.h as f64 / rows as f64);
+        Ok((0..count as u32)
+            .map(|index| {
+                Rectangle::from_loc_and_size(
+                    ((index % columns) as f64 * width, (index / columns) as f64 * height),
+                    (width, height),
+                )
+            })
+            .collect())
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Layout> {
+        if input == "grid" {
+            return Ok(Layout::Grid);
+        }
+        let parts = input
+            .split('x')
+            .map(|x| u32::from_str_radix(x, 10))
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|err| anyhow::anyhow!("Failed to parse layout: {}", err))?;
+        match parts.as_slice() {
+            [columns, rows] if *columns > 0 && *rows > 0 => Ok(Layout::Fixed {
+                columns: *columns,
+                rows: *rows,
+            }),
+            _ => anyhow::bail!("Layout needs to have the format \"COLSxROWS\" or be \"grid\""),
+        }
+    }
+}
+
+/// Largest region of `cell` showing `size` undistorted, centered with the background left over on two sides
+pub fn letterbox(size: Size<i32, Buffer>, cell: Rectangle<f64, Physical>) -> Rectangle<f64, Physical> {
+    if size.w <= 0 || size.h <= 0 {
+        return cell;
+    }
+    let factor = (cell.size.w / size.w as f64).min(cell.size.h / size.h as f64);
+    let (width, height) = (size.w as f64 * factor, size.h as f64 * factor);
+    Rectangle::from_loc_and_size(
+        (
+            cell.loc.x + (cell.size.w - width) / 2.0,
+            cell.loc.y + (cell.size.h - height) / 2.0,
+        ),
+        (width, height),
+    )
+}
+
+/// Horizontal and vertical factors `src` is scaled by to fill `dst`
+pub fn scale_factors(src: Size<i32, Buffer>, dst: Rectangle<f64, Physical>) -> (f64, f64) {
+    (dst.size.w / src.w as f64, dst.size.h / src.h as f64)
+}
+
+/// Every source pixel covers the same whole number of target pixels
+pub fn is_integer_scale(src: Size<i32, Buffer>, dst: Rectangle<f64, Physical>) -> bool {
+    let (x, y) = scale_factors(src, dst);
+    let integer = |factor: f64| factor >= 1.0 && (factor - factor.round()).abs() < 1e-6;
+    integer(x) && integer(y)
+}
+
+/// How the source is sampled when it is scaled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+/// Filter requested on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    Auto,
+    Nearest,
+    Linear,
+}
+
+impl FilterKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "nearest", "linear"];
+
+    /// Auto keeps integer scaled content sharp and smooths everything else
+    pub fn resolve(self, src: Size<i32, Buffer>, dst: Rectangle<f64, Physical>) -> Filter {
+        match self {
+            FilterKind::Nearest => Filter::Nearest,
+            FilterKind::Linear => Filter::Linear,
+            FilterKind::Auto if is_integer_scale(src, dst) => Filter::Nearest,
+            FilterKind::Auto => Filter::Linear,
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<FilterKind> {
+        match name {
+            "auto" => Ok(FilterKind::Auto),
+            "nearest" => Ok(FilterKind::Nearest),
+            "linear" => Ok(FilterKind::Linear),
+            x => anyhow::bail!("Unknown filter: {}", x),
+        }
+    }
+}
+
+/// Names accepted by `--transform`
+pub const TRANSFORMS: &[&str] = &[
+    "normal",
+    "90",
+    "180",
+    "270",
+    "flipped",
+    "flipped-90",
+    "flipped-180",
+    "flipped-270",
+];
+
+/// Transform of the target given on the command line.
+///
+/// Like wayland output transforms, rotations are counter-clockwise and flipped ones are mirrored horizontally first.
+pub fn parse_transform(name: &str) -> anyhow::Result<Transform> {
+    match name {
+        "normal" => Ok(Transform::Normal),
+        "90" => Ok(Transform::_90),
+        "180" => Ok(Transform::_180),
+        "270" => Ok(Transform::_270),
+        "flipped" => Ok(Transform::Flipped),
+        "flipped-90" => Ok(Transform::Flipped90),
+        "flipped-180" => Ok(Transform::Flipped180),
+        "flipped-270" => Ok(Transform::Flipped270),
+        x => anyhow::bail!("Unknown transform: {}", x),
+    }
+}
+
+/// Whether `transform` swaps width and height
+pub fn swaps_axes(transform: Transform) -> bool {
+    matches!(
+        transform,
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+    )
+}
+
+/// Size of something of `size` after applying `transform`
+pub fn transformed_size<K>(size: Size<i32, K>, transform: Transform) -> Size<i32, K> {
+    if swaps_axes(transform) {
+        (size.h, size.w).into()
+    } else {
+        size
+    }
+}
+
+/// Transform of a wl_output, which the compositor applies to the content of its frames
+pub fn output_transform(transform: wl_output::Transform) -> Transform {
+    match transform {
+        wl_output::Transform::_90 => Transform::_90,
+        wl_output::Transform::_180 => Transform::_180,
+        wl_output::Transform::_270 => Transform::_270,
+        wl_output::Transform::Flipped => Transform::Flipped,
+        wl_output::Transform::Flipped90 => Transform::Flipped90,
+        wl_output::Transform::Flipped180 => Transform::Flipped180,
+        wl_output::Transform::Flipped270 => Transform::Flipped270,
+        _ => Transform::Normal,
+    }
+}
+
+/// Whether `transform` mirrors and its counter-clockwise quarter turns, which follow the mirroring
+fn decompose(transform: Transform) -> (bool, u8) {
+    match transform {
+        Transform::Normal => (false, 0),
+        Transform::_90 => (false, 1),
+        Transform::_180 => (false, 2),
+        Transform::_270 => (false, 3),
+        Transform::Flipped => (true, 0),
+        Transform::Flipped90 => (true, 1),
+        Transform::Flipped180 => (true, 2),
+        Transform::Flipped270 => (true, 3),
+    }
+}
+
+fn recompose(flipped: bool, turns: u8) -> Transform {
+    match (flipped, turns % 4) {
+        (false, 0) => Transform::Normal,
+        (false, 1) => Transform::_90,
+        (false, 2) => Transform::_180,
+        (false, 3) => Transform::_270,
+        (true, 0) => Transform::Flipped,
+        (true, 1) => Transform::Flipped90,
+        (true, 2) => Transform::Flipped180,
+        _ => Transform::Flipped270,
+    }
+}
+
+/// The transform applying `first` and then `second`.
+///
+/// Mirroring reverses the direction of the turns before it, so they are subtracted then.
+pub fn compose(first: Transform, second: Transform) -> Transform {
+    let (first_flipped, first_turns) = decompose(first);
+    let (second_flipped, second_turns) = decompose(second);
+    let turns = if second_flipped {
+        second_turns + 4 - first_turns
+    } else {
+        second_turns + first_turns
+    };
+    recompose(first_flipped != second_flipped, turns)
+}
+
+/// Column-major matrix applying `transform` to clip space coordinates
+pub fn clip_matrix(transform: Transform) -> [f32; 4] {
+    match transform {
+        Transform::Normal => [1.0, 0.0, 0.0, 1.0],
+        Transform::_90 => [0.0, 1.0, -1.0, 0.0],
+        Transform::_180 => [-1.0, 0.0, 0.0, -1.0],
+        Transform::_270 => [0.0, -1.0, 1.0, 0.0],
+        Transform::Flipped => [-1.0, 0.0, 0.0, 1.0],
+        Transform::Flipped90 => [0.0, -1.0, -1.0, 0.0],
+        Transform::Flipped180 => [1.0, 0.0, 0.0, -1.0],
+        Transform::Flipped270 => [0.0, 1.0, 1.0, 0.0],
+    }
+}
+
+/// Like `clip_matrix`, for texture coordinates which grow downwards
+pub fn texture_matrix(transform: Transform) -> [f32; 4] {
+    let [a, b, c, d] = clip_matrix(transform);
+    [a, -b, -c, d]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full(w: i32, h: i32) -> Rectangle<i32, Buffer> {
+        Rectangle::from_loc_and_size((0, 0), (w, h))
+    }
+
+    fn assert_maps(mapping: &Mapping, from: (f64, f64), to: (f64, f64)) {
+        let point = mapping.map(Point::from(from));
+        assert!(
+            (point.x - to.0).abs() < 1e-9 && (point.y - to.1).abs() < 1e-9,
+            "{:?} mapped to {:?} instead of {:?}",
+            from,
+            (point.x, point.y),
+            to
+        );
+    }
+
+    #[test]
+    fn destination_upscales() {
+        let mapping = destination(Size::from((1280, 720)), full(1280, 720), Size::from((1920, 1080)));
+        assert_eq!(mapping.src, full(1280, 720));
+        assert_eq!(mapping.dst, Rectangle::from_loc_and_size((0.0, 0.0), (1920.0, 1080.0)));
+        assert_eq!(mapping.scale(), (1.5, 1.5));
+        assert_maps(&mapping, (1280.0, 720.0), (1920.0, 1080.0));
+        assert_maps(&mapping, (100.0, 10.0), (150.0, 15.0));
+    }
+
+    #[test]
+    fn destination_downscales() {
+        let mapping = destination(Size::from((3840, 2160)), full(3840, 2160), Size::from((1920, 1080)));
+        assert_eq!(mapping.scale(), (0.5, 0.5));
+        assert_maps(&mapping, (3840.0, 2160.0), (1920.0, 1080.0));
+        assert_maps(&mapping, (1001.0, 3.0), (500.5, 1.5));
+    }
+
+    #[test]
+    fn destination_non_integer() {
+        let mapping = destination(Size::from((1366, 768)), full(1366, 768), Size::from((1920, 1080)));
+        let (x, y) = mapping.scale();
+        assert!((x - 1920.0 / 1366.0).abs() < 1e-9);
+        assert!((y - 1.40625).abs() < 1e-9);
+        assert_maps(&mapping, (683.0, 384.0), (960.0, 540.0));
+        assert_maps(&mapping, (1366.0, 768.0), (1920.0, 1080.0));
+        assert!(!is_integer_scale(mapping.src.size, mapping.dst));
+    }
+
+    #[test]
+    fn destination_stretches() {
+        let mapping = destination(Size::from((1920, 1080)), full(1920, 1080), Size::from((1280, 1024)));
+        let (x, y) = mapping.scale();
+        assert!((x - 2.0 / 3.0).abs() < 1e-9);
+        assert!((y - 1024.0 / 1080.0).abs() < 1e-9);
+        assert_maps(&mapping, (1920.0, 1080.0), (1280.0, 1024.0));
+    }
+
+    #[test]
+    fn destination_of_region() {
+        let region = Rectangle::from_loc_and_size((100, 50), (200, 100));
+        let mapping = destination(Size::from((1920, 1080)), region, Size::from((400, 400)));
+        assert_eq!(mapping.src, region);
+        assert_eq!(mapping.scale(), (2.0, 4.0));
+        assert_maps(&mapping, (100.0, 50.0), (0.0, 0.0));
+        assert_maps(&mapping, (300.0, 150.0), (400.0, 400.0));
+    }
+
+    #[test]
+    fn destination_clips_region_to_source() {
+        let region = Rectangle::from_loc_and_size((-10, 600), (200, 200));
+        let mapping = destination(Size::from((1280, 720)), region, Size::from((1920, 1080)));
+        assert_eq!(mapping.src, Rectangle::from_loc_and_size((0, 600), (190, 120)));
+        let outside = Rectangle::from_loc_and_size((2000, 0), (10, 10));
+        let mapping = destination(Size::from((1280, 720)), outside, Size::from((1920, 1080)));
+        assert_eq!(mapping.src.size, Size::from((0, 10)));
+    }
+
+    fn cell(x: f64, y: f64, w: f64, h: f64) -> Rectangle<f64, Physical> {
+        Rectangle::from_loc_and_size((x, y), (w, h))
+    }
+
+    #[test]
+    fn grid_dimensions() {
+        let dimensions = (1..=6).map(|count| Layout::Grid.dimensions(count)).collect::<Vec<_>>();
+        assert_eq!(dimensions, vec![(1, 1), (2, 1), (2, 2), (2, 2), (3, 2), (3, 2)]);
+        // nothing to show still takes the whole target
+        assert_eq!(Layout::Grid.dimensions(0), (1, 1));
+    }
+
+    #[test]
+    fn grid_cells() {
+        let dest = Size::from((1920, 1080));
+        let cells = |count: usize| Layout::Grid.cells(count, dest).unwrap();
+        assert_eq!(cells(1), vec![cell(0.0, 0.0, 1920.0, 1080.0)]);
+        assert_eq!(cells(2), vec![cell(0.0, 0.0, 960.0, 1080.0), cell(960.0, 0.0, 960.0, 1080.0)]);
+        let quarters = vec![
+            cell(0.0, 0.0, 960.0, 540.0),
+            cell(960.0, 0.0, 960.0, 540.0),
+            cell(0.0, 540.0, 960.0, 540.0),
+            cell(960.0, 540.0, 960.0, 540.0),
+        ];
+        // the last cell of three stays empty
+        assert_eq!(cells(3), quarters[..3].to_vec());
+        assert_eq!(cells(4), quarters);
+        let sixths = vec![
+            cell(0.0, 0.0, 640.0, 540.0),
+            cell(640.0, 0.0, 640.0, 540.0),
+            cell(1280.0, 0.0, 640.0, 540.0),
+            cell(0.0, 540.0, 640.0, 540.0),
+            cell(640.0, 540.0, 640.0, 540.0),
+            cell(1280.0, 540.0, 640.0, 540.0),
+        ];
+        assert_eq!(cells(5), sixths[..5].to_vec());
+        assert_eq!(cells(6), sixths);
+    }
+
+    #[test]
+    fn fixed_layouts() {
+        let layout = "1x3".parse::<Layout>().unwrap();
+        assert_eq!(layout, Layout::Fixed { columns: 1, rows: 3 });
+        assert_eq!(
+            layout.cells(2, Size::from((1920, 1080))).unwrap(),
+            vec![cell(0.0, 0.0, 1920.0, 360.0), cell(0.0, 360.0, 1920.0, 360.0)]
+        );
+        assert!(layout.fits(3).is_ok());
+        assert!(layout.cells(4, Size::from((1920, 1080))).is_err());
+        assert_eq!("grid".parse::<Layout>().unwrap(), Layout::Grid);
+        for invalid in ["", "2", "0x2", "2x", "2x2x2", "twoxtwo"] {
+            assert!(invalid.parse::<Layout>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn letterboxing() {
+        // a 4:3 source in a 16:9 cell is pillarboxed, a 21:9 one letterboxed
+        let quarter = cell(960.0, 540.0, 960.0, 540.0);
+        assert_eq!(letterbox(Size::from((1024, 768)), quarter), cell(1080.0, 540.0, 720.0, 540.0));
+        assert_eq!(letterbox(Size::from((2560, 1080)), quarter), cell(960.0, 607.5, 960.0, 405.0));
+        assert_eq!(letterbox(Size::from((1920, 1080)), quarter), quarter);
+        assert_eq!(letterbox(Size::from((0, 0)), quarter), quarter);
+    }
+
+    const TRANSFORMS: [Transform; 8] = [
+        Transform::Normal,
+        Transform::_90,
+        Transform::_180,
+        Transform::_270,
+        Transform::Flipped,
+        Transform::Flipped90,
+        Transform::Flipped180,
+        Transform::Flipped270,
+    ];
+
+    /// Product of two column-major matrices, applying `first` and then `second`
+    fn then(first: [f32; 4], second: [f32; 4]) -> [f32; 4] {
+        let [a, b, c, d] = second;
+        let [e, f, g, h] = first;
+        [a * e + c * f, b * e + d * f, a * g + c * h, b * g + d * h]
+    }
+
+    #[test]
+    fn composition_matches_the_matrices() {
+        for first in TRANSFORMS {
+            for second in TRANSFORMS {
+                assert_eq!(
+                    clip_matrix(compose(first, second)),
+                    then(clip_matrix(first), clip_matrix(second)),
+                    "{:?} then {:?}",
+                    first,
+                    second
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn composition() {
+        assert_eq!(compose(Transform::_90, Transform::_90), Transform::_180);
+        assert_eq!(compose(Transform::_90, Transform::_270), Transform::Normal);
+        assert_eq!(compose(Transform::_270, Transform::_180), Transform::_90);
+        assert_eq!(compose(Transform::Flipped, Transform::Flipped), Transform::Normal);
+        assert_eq!(compose(Transform::_90, Transform::Flipped), Transform::Flipped270);
+        assert_eq!(compose(Transform::Flipped, Transform::_90), Transform::Flipped90);
+        assert_eq!(compose(Transform::Flipped90, Transform::Flipped90), Transform::Normal);
+        for transform in TRANSFORMS {
+            assert_eq!(compose(transform, Transform::Normal), transform);
+            assert_eq!(compose(Transform::Normal, transform), transform);
+            // every transform is undone by exactly one other
+            let inverses = TRANSFORMS
+                .iter()
+                .filter(|inverse| compose(transform, **inverse) == Transform::Normal)
+                .collect::<Vec<_>>();
+            assert_eq!(inverses.len(), 1, "{:?}", transform);
+            assert_eq!(compose(*inverses[0], transform), Transform::Normal);
+            for second in TRANSFORMS {
+                for third in TRANSFORMS {
+                    assert_eq!(
+                        compose(compose(transform, second), third),
+                        compose(transform, compose(second, third))
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn output_transforms() {
+        let transforms = [
+            (wl_output::Transform::Normal, Transform::Normal),
+            (wl_output::Transform::_90, Transform::_90),
+            (wl_output::Transform::_180, Transform::_180),
+            (wl_output::Transform::_270, Transform::_270),
+            (wl_output::Transform::Flipped, Transform::Flipped),
+            (wl_output::Transform::Flipped90, Transform::Flipped90),
+            (wl_output::Transform::Flipped180, Transform::Flipped180),
+            (wl_output::Transform::Flipped270, Transform::Flipped270),
+        ];
+        for (output, transform) in transforms {
+            assert_eq!(output_transform(output), transform);
+        }
+    }
+
+    /// Texture coordinates the corner `vert` of the destination samples, as the vertex shader of `adjust` computes them
+    fn sampled(transform: Transform, vert: (f32, f32)) -> (f32, f32) {
+        let [a, b, c, d] = texture_matrix(transform);
+        let (x, y) = (vert.0 - 0.5, vert.1 - 0.5);
+        (a * x + c * y + 0.5, b * x + d * y + 0.5)
+    }
+
+    #[test]
+    fn texture_matrices() {
+        // the corners of the texture shown in the top left, top right, bottom left and bottom right of the destination
+        let corners = [
+            (Transform::Normal, [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]),
+            (Transform::_90, [(0.0, 1.0), (0.0, 0.0), (1.0, 1.0), (1.0, 0.0)]),
+            (Transform::_180, [(1.0, 1.0), (0.0, 1.0), (1.0, 0.0), (0.0, 0.0)]),
+            (Transform::_270, [(1.0, 0.0), (1.0, 1.0), (0.0, 0.0), (0.0, 1.0)]),
+            (Transform::Flipped, [(1.0, 0.0), (0.0, 0.0), (1.0, 1.0), (0.0, 1.0)]),
+            (Transform::Flipped90, [(0.0, 0.0), (0.0, 1.0), (1.0, 0.0), (1.0, 1.0)]),
+            (Transform::Flipped180, [(0.0, 1.0), (1.0, 1.0), (0.0, 0.0), (1.0, 0.0)]),
+            (Transform::Flipped270, [(1.0, 1.0), (1.0, 0.0), (0.0, 1.0), (0.0, 0.0)]),
+        ];
+        for (transform, expected) in corners {
+            let verts = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)];
+            let actual = verts.iter().map(|vert| sampled(transform, *vert)).collect::<Vec<_>>();
+            assert_eq!(actual, expected, "{:?}", transform);
+        }
+        // the center stays where it is
+        for transform in TRANSFORMS {
+            assert_eq!(sampled(transform, (0.5, 0.5)), (0.5, 0.5), "{:?}", transform);
+        }
+    }
+}
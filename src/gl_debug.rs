@@ -0,0 +1,61 @@
+use anyhow::Result;
+use smithay::backend::renderer::gles2::ffi;
+
+use std::{
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+};
+
+/// Enums of GL_KHR_debug
+const GL_DEBUG_OUTPUT_KHR: u32 = 0x92E0;
+const GL_DEBUG_OUTPUT_SYNCHRONOUS_KHR: u32 = 0x8242;
+const GL_DEBUG_SEVERITY_HIGH_KHR: u32 = 0x9146;
+const GL_DEBUG_SEVERITY_MEDIUM_KHR: u32 = 0x9147;
+const GL_DEBUG_SEVERITY_LOW_KHR: u32 = 0x9148;
+
+type DebugProc = extern "system" fn(u32, u32, u32, u32, i32, *const c_char, *mut c_void);
+type DebugMessageCallback = unsafe extern "system" fn(Option<DebugProc>, *const c_void);
+
+/// Routes the messages of the current context into `log`, needs GL_KHR_debug.
+///
+/// Messages arrive synchronously, so they are logged right after the call causing them.
+pub unsafe fn install(gl: &ffi::Gles2, log: &slog::Logger) -> Result<()> {
+    let ptr = smithay::backend::egl::get_proc_address("glDebugMessageCallbackKHR");
+    if ptr.is_null() {
+        anyhow::bail!("glDebugMessageCallbackKHR is missing, despite GL_KHR_debug");
+    }
+    let debug_message_callback = std::mem::transmute::<_, DebugMessageCallback>(ptr);
+    // the context may report messages until it is destroyed, which smithay does without telling us
+    let logger = Box::into_raw(Box::new(log.new(slog::o!("gl" => "debug"))));
+    gl.Enable(GL_DEBUG_OUTPUT_KHR);
+    gl.Enable(GL_DEBUG_OUTPUT_SYNCHRONOUS_KHR);
+    debug_message_callback(Some(message), logger as *const c_void);
+    Ok(())
+}
+
+extern "system" fn message(
+    _source: u32,
+    _ty: u32,
+    id: u32,
+    severity: u32,
+    length: i32,
+    text: *const c_char,
+    user_param: *mut c_void,
+) {
+    let log = unsafe { &*(user_param as *const slog::Logger) };
+    let text = if text.is_null() {
+        String::new()
+    } else if length < 0 {
+        unsafe { CStr::from_ptr(text) }.to_string_lossy().into_owned()
+    } else {
+        let bytes = unsafe { std::slice::from_raw_parts(text as *const u8, length as usize) };
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    match severity {
+        GL_DEBUG_SEVERITY_HIGH_KHR => slog::error!(log, "{} ({})", text, id),
+        GL_DEBUG_SEVERITY_MEDIUM_KHR => slog::warn!(log, "{} ({})", text, id),
+        GL_DEBUG_SEVERITY_LOW_KHR => slog::info!(log, "{} ({})", text, id),
+        // notifications
+        _ => slog::debug!(log, "{} ({})", text, id),
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use smithay::{
+    backend::renderer::{Frame as _, Renderer},
+    utils::{Rectangle, Size},
+};
+
+use crate::{
+    adjust::{AdjustShader, Adjustments},
+    convert::{self, memory_layout},
+    geometry::Filter,
+    gpu::{self, ColorDepth, Fd},
+    render,
+    source::{Source, SourceSpec, DEFAULT_SOURCE},
+};
+
+// for the golden tests
+pub use crate::{geometry::FilterKind, offscreen::save_png};
+pub use smithay::backend::{allocator::Fourcc, renderer::Transform};
+
+use std::path::{Path, PathBuf};
+
+/// Overrides the render node the golden tests render on
+pub const NODE_VAR: &str = "NVSCREENCOPY_GOLDEN_NODE";
+
+/// A captured frame in memory, like the cpu copy path reads it back
+pub struct Frame {
+    pub format: Fourcc,
+    pub size: (i32, i32),
+    /// Bytes between the starts of two rows
+    pub stride: i32,
+    pub pixels: Vec<u8>,
+    /// The rows are stored bottom to top
+    pub y_invert: bool,
+}
+
+/// How a frame is shown on an offscreen target of `size`
+pub struct Scene {
+    pub size: (i32, i32),
+    /// Transform of the source output, which is undone
+    pub transform: Transform,
+    pub filter: FilterKind,
+    /// Filter in linear light like `--linear-blending`, through the shader that also applies the adjustments
+    pub linear_blending: bool,
+}
+
+/// The render node given by `NVSCREENCOPY_GOLDEN_NODE` or the first one of the machine, `None` without any
+pub fn render_node() -> Option<PathBuf> {
+    if let Some(node) = std::env::var_os(NODE_VAR) {
+        return Some(PathBuf::from(node));
+    }
+    let mut nodes = std::fs::read_dir("/dev/dri")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("renderD"))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    nodes.sort();
+    nodes.into_iter().next()
+}
+
+/// Converts `frame` to RGBA, uploads it and draws it like every frame of the target is drawn,
+/// into an offscreen framebuffer on the gpu of `node`.
+/// With `linear_blending` it is drawn through the shader of `--linear-blending` instead.
+///
+/// Returns the pixels of the target as RGBA, starting with the top row.
+pub fn render(node: &Path, frame: &Frame, scene: &Scene) -> Result<Vec<u8>> {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let layout = memory_layout(frame.format).with_context(|| format!("{:?} can't be converted", frame.format))?;
+    let mut pixels = frame.pixels.clone();
+    convert::swizzle(&mut pixels, layout, ColorDepth::Eight);
+
+    let fd = Fd::open(&node).with_context(|| format!("Failed to open {}", node.display()))?;
+    let mut target = gpu::init_offscreen_target(fd, scene.size, ColorDepth::Eight, Transform::Normal, false, log)?;
+    let size = Size::from(frame.size);
+    let texture = render::create_texture(&mut target.renderer, target.gl, size.w, size.h, ColorDepth::Eight)?;
+    let whole = Rectangle::from_loc_and_size((0, 0), size);
+    render::update_bitmap(
+        &mut target.renderer,
+        target.gl,
+        &texture,
+        &pixels,
+        frame.stride,
+        &[whole],
+        ColorDepth::Eight,
+    )?;
+
+    let mut source = Source::new(DEFAULT_SOURCE.parse::<SourceSpec>()?, 1, size, size, texture, ColorDepth::Eight, 1);
+    source.transform = scene.transform;
+    source.texture_flipped = frame.y_invert;
+    source.shown = true;
+    let surface_size = target.surface_size();
+    let dst = source.destination(surface_size);
+    let sources = [(&source, dst)];
+    target.bind()?;
+    render::set_filters(&mut target.renderer, &sources, scene.filter)?;
+    if scene.linear_blending {
+        let transform = target.transform;
+        let shader = target.renderer.with_context(|_renderer, gl| unsafe { AdjustShader::new(gl) })??;
+        target.renderer.render(surface_size, transform, |_, frame| frame.clear([0.0, 0.0, 0.0, 1.0]))??;
+        target.renderer.with_context(|_renderer, gl| unsafe {
+            shader.draw(
+                gl,
+                (source.texture.tex_id(), source.texture.size()),
+                source.texture_external,
+                source.texture_flipped,
+                source.texture_src,
+                source.transform,
+                dst,
+                surface_size,
+                transform,
+                Adjustments::NEUTRAL,
+                scene.filter.resolve(source.upright_size(), dst) == Filter::Linear,
+            )
+        })??;
+    } else {
+        target.renderer.render(surface_size, target.transform, |_, frame| {
+            frame.clear([0.0, 0.0, 0.0, 1.0])?;
+            render::draw_textures(frame, &sources)
+        })??;
+    }
+    target.read_frame()
+}
@@ -1,54 +1,987 @@
 use anyhow::{Context, Result};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
 use smithay::{
     backend::{
-        allocator::{Fourcc},
-        drm::{DrmDevice, DrmSurface},
+        allocator::{
+            dmabuf::{AsDmabuf, Dmabuf},
+            gbm::GbmDevice,
+            Allocator, Fourcc, Modifier,
+        },
+        drm::{DrmDevice, DrmSurface, GbmBufferedSurface},
         egl::{
-            context::{GlAttributes, PixelFormatRequirements},
+            context::{GlAttributes, PixelFormat, PixelFormatRequirements},
+            display::EGLDisplayHandle,
             EGLContext, EGLDisplay, EGLSurface,
         },
-        renderer::gles2::Gles2Renderer,
+        renderer::{gles2::Gles2Renderer, Bind, ImportDma, Transform},
         udev::{driver, UdevBackend},
     },
-    reexports::drm::{
-        control::{
-            connector::{Info as ConnectorInfo, Interface, State as ConnectorState},
-            dumbbuffer::DumbBuffer,
-            framebuffer, Device as ControlDevice,
+    reexports::{
+        drm::{
+            control::{
+                connector::{self, Info as ConnectorInfo, Interface, State as ConnectorState},
+                Mode, ModeTypeFlags, ResourceHandles,
+                dumbbuffer::DumbBuffer,
+                crtc, encoder, framebuffer, plane, Device as ControlDevice, Event,
+            },
+            buffer::{Buffer, Handle as BufferHandle, PlanarBuffer},
+            Device as DrmDeviceNode,
         },
-        Device as DrmDeviceNode,
+        udev,
     },
+    utils::{Physical, Rectangle, Size},
 };
 
-use crate::egl::{EGLDeviceEXT, EglStreamSurface};
+#[cfg(feature = "vulkan")]
+use crate::vulkan::VulkanCopy;
+use crate::{
+    adjust::AdjustShader,
+    cursor::CursorImage,
+    edid::{self, Edid},
+    egl::{
+        self, AcquireSlot, ConfigAttributes, DeviceNodes, EGLDeviceEXT, EglStreamSurface, NvEglError, StreamOptions,
+        StreamSlot, SwapErrorSlot, SyncSupport,
+    },
+    gamma_lut::GammaLut,
+    geometry, gl_debug,
+    hdr::HdrMetadataSource,
+    kms::{
+        self, Dpms, HardwareCursor, PlaneEntry, PlaneRotation, PlaneScaling, PlaneSelection, PropertyAssignment,
+        PropertyCache,
+    },
+    offscreen::{self, OffscreenBackend},
+    render::{AsyncReadback, BlitTarget, Fence},
+    scanout::Scanout,
+};
 
 use std::{
-    fs::File,
+    collections::HashSet,
+    fmt,
+    fs::{File, OpenOptions},
+    ops::{Bound, RangeBounds},
     os::unix::io::{AsRawFd, RawFd},
     path::{Path, PathBuf},
     rc::Rc,
-    time::Duration,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+/// Bits per color channel, ordered by precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    Eight,
+    Ten,
+}
+
+impl FromStr for ColorDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(depth: &str) -> Result<ColorDepth> {
+        match depth {
+            "8" => Ok(ColorDepth::Eight),
+            "10" => Ok(ColorDepth::Ten),
+            x => anyhow::bail!("Unsupported color depth: {}", x),
+        }
+    }
+}
+
+/// How frames get from the target renderer to the crtc, requested on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetBackendKind {
+    Auto,
+    EglStream,
+    Gbm,
+}
+
+impl TargetBackendKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "eglstream", "gbm"];
+
+    /// Backend used for a gpu driven by `driver`, nvidia only supports EGLStreams
+    pub fn resolve(self, driver: &str) -> TargetBackendKind {
+        match self {
+            TargetBackendKind::Auto if driver.contains("nvidia") => TargetBackendKind::EglStream,
+            TargetBackendKind::Auto => TargetBackendKind::Gbm,
+            kind => kind,
+        }
+    }
+}
+
+impl FromStr for TargetBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<TargetBackendKind> {
+        match name {
+            "auto" => Ok(TargetBackendKind::Auto),
+            "eglstream" => Ok(TargetBackendKind::EglStream),
+            "gbm" => Ok(TargetBackendKind::Gbm),
+            x => anyhow::bail!("Unknown target backend: {}", x),
+        }
+    }
+}
+
+/// Failure to queue a rendered frame for scanout
+#[derive(Debug)]
+pub enum PresentError {
+    Stream(NvEglError),
+    Gbm(anyhow::Error),
+}
+
+impl PresentError {
+    /// Whether the frame may just be dropped
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PresentError::Stream(err) => err.is_retryable(),
+            // a failed page flip leaves the previous frame on screen
+            PresentError::Gbm(_) => true,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PresentError::Stream(err) => err.kind(),
+            PresentError::Gbm(_) => "page flip",
+        }
+    }
+}
+
+impl fmt::Display for PresentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresentError::Stream(err) => write!(f, "{}", err),
+            PresentError::Gbm(err) => write!(f, "Page flip failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PresentError {}
+
+/// Scanout of the frames rendered on the target
+pub trait TargetBackend {
+    fn name(&self) -> &'static str;
+    /// Makes the next buffer of the output the render target of `renderer`
+    fn bind(&mut self, renderer: &mut Gles2Renderer) -> Result<()>;
+    /// Queues the rendered buffer for scanout
+    fn present(&mut self) -> Result<(), PresentError>;
+    /// Hands the presented buffer to the display, once the event loop sent everything else out
+    fn acquire(&mut self) -> Result<(), PresentError>;
+    /// Called on every vblank of the crtc
+    fn frame_submitted(&mut self);
+    /// Switches the crtc to `drm_mode`
+    fn set_mode(&mut self, drm_mode: Mode) -> Result<()>;
+    /// Takes the crtc back in `drm_mode`, after somebody else used it
+    fn restore_scanout(&mut self, drm_mode: Mode) -> Result<()>;
+    /// Creates a new renderer for the output, after a gpu reset lost the old one
+    fn rebuild_context(&mut self, log: &slog::Logger) -> Result<Gles2Renderer>;
+    /// Size of the frames the plane scales to the mode, `None` if they are rendered in the size of the mode
+    fn plane_scaling(&self) -> Option<(i32, i32)>;
+    /// Recreates the surface rendered into, if a mode change or the display invalidated it.
+    /// Returns whether it was recreated, backends without a surface have nothing to do.
+    fn ensure_surface(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    /// Keeps `frame` on screen while the output is set up again, until the first new frame is shown.
+    /// Backends which leave their last buffer on the crtc have nothing to do.
+    fn freeze(&mut self, _frame: FrozenFrame) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The frame last shown, scanned out from a dumb buffer while the stream is set up again.
+///
+/// 8 bit RGBA with the top row first, in the size of the surface of the target.
+pub struct FrozenFrame {
+    pub pixels: Vec<u8>,
+    pub size: (i32, i32),
+}
+
+/// Feeds the plane through an EGLStream, as nvidia requires
+pub struct EglStreamBackend {
+    target: StreamTarget,
+    stream: StreamOptions,
+    depth: ColorDepth,
+    mode: (i32, i32),
+    /// Set if the plane transforms the stream, which then has the transformed size
+    rotation: Option<PlaneRotation>,
+    /// Set if the plane scales the stream, which then keeps its size across modes
+    scaling: Option<PlaneScaling>,
+    crtc: crtc::Handle,
+    plane: plane::Handle,
+    display: EGLDisplay,
+    _device: EGLDeviceEXT,
+    drm_surface: DrmSurface<Fd>,
+    /// Scanned out until the stream takes over the plane
+    fb: framebuffer::Handle,
+    db: DumbBuffer,
+    /// Copied into every new dumb buffer, until the stream shows a frame again
+    frozen: Option<FrozenFrame>,
+    log: slog::Logger,
+}
+
+impl EglStreamBackend {
+    /// Binds the stream to `plane` of the crtc of `drm_surface`.
+    ///
+    /// With `plane_scaling` the stream is kept at that size and scaled by the plane, if the driver accepts it.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        fd: Fd,
+        device: &DrmDevice<Fd>,
+        drm_surface: DrmSurface<Fd>,
+        plane: plane::Handle,
+        mode: (i32, i32),
+        depth: ColorDepth,
+        stream: StreamOptions,
+        rotation: Option<PlaneRotation>,
+        plane_scaling: Option<(i32, i32)>,
+        log: &slog::Logger,
+    ) -> Result<(EglStreamBackend, Gles2Renderer)> {
+        let egl_device = EGLDeviceEXT::new(fd.clone(), log.clone())?;
+        let crtc = drm_surface.crtc();
+        let (db, fb) = create_dumb_framebuffer(device, plane, dumb_buffer_size(mode, plane_scaling), depth, log)?;
+        // the stream can only attach once the plane is scanning out
+        wait_for_scanout(device, crtc, || Ok(drm_surface.commit([&(fb, plane)].iter().cloned(), true)?), log)?;
+
+        // tested against the dumb buffer, which is on the plane now
+        let scaling = plane_scaling.and_then(|src| {
+            PlaneScaling::query(fd, plane, src, plane_destination(src, mode)).unwrap_or_else(|err| {
+                slog::debug!(log, "Failed to test scaling by the plane: {}", err);
+                None
+            })
+        });
+        match (plane_scaling, scaling.as_ref()) {
+            (Some(src), Some(_)) => slog::info!(log, "Scaling {}x{} to {}x{}: plane", src.0, src.1, mode.0, mode.1),
+            (Some(_), None) => slog::warn!(log, "Plane can't scale frames, falling back to scaling while rendering"),
+            _ => {}
+        }
+
+        let display = EGLDisplay::new(&egl_device, log.clone())?;
+        // the dumb buffer keeps its format, it is only scanned out until the stream takes over
+        let depth = match depth {
+            ColorDepth::Ten if !has_stream_configs(&display.get_display_handle(), depth)? => {
+                slog::warn!(log, "No stream config has 10 bits per color, falling back to 8 bit");
+                ColorDepth::Eight
+            }
+            depth => depth,
+        };
+        if let Some(rotation) = rotation.as_ref() {
+            rotation.apply(true)?;
+        }
+        if let Some(scaling) = scaling.as_ref() {
+            scaling.apply(plane_destination(scaling.src, mode))?;
+        }
+        let (renderer, target) = create_target_context(
+            &display,
+            crtc,
+            plane,
+            stream_size(mode, rotation.as_ref(), scaling.as_ref()),
+            depth,
+            stream,
+            log,
+        )
+        .context(StreamRefused)?;
+        Ok((
+            EglStreamBackend {
+                target,
+                stream,
+                depth,
+                mode,
+                rotation,
+                scaling,
+                crtc,
+                plane,
+                display,
+                _device: egl_device,
+                drm_surface,
+                fb,
+                db,
+                frozen: None,
+                log: log.clone(),
+            },
+            renderer,
+        ))
+    }
+
+    /// Scans out the dumb buffer in `mode`, which is never rotated.
+    ///
+    /// The rectangles of the plane are set up for the stream again afterwards.
+    fn commit_dumb_buffer(&self, fb: framebuffer::Handle, mode: (i32, i32)) -> Result<()> {
+        if let Some(rotation) = self.rotation.as_ref() {
+            rotation.apply(false)?;
+        }
+        wait_for_scanout(
+            &self.drm_surface,
+            self.crtc,
+            || Ok(self.drm_surface.commit([&(fb, self.plane)].iter().cloned(), true)?),
+            &self.log,
+        )?;
+        if let Some(rotation) = self.rotation.as_ref() {
+            rotation.apply(true)?;
+        }
+        if let Some(scaling) = self.scaling.as_ref() {
+            scaling.apply(plane_destination(scaling.src, mode))?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of the stream feeding a plane in `mode`, the plane rotates and scales it into place
+fn stream_size(mode: (i32, i32), rotation: Option<&PlaneRotation>, scaling: Option<&PlaneScaling>) -> (i32, i32) {
+    match (rotation, scaling) {
+        (_, Some(scaling)) => scaling.src,
+        (Some(rotation), None) if rotation.swaps_axes() => (mode.1, mode.0),
+        _ => mode,
+    }
+}
+
+/// The dumb buffer is scanned out in the size of the mode, but needs to hold the scaled source rectangle as well
+fn dumb_buffer_size(mode: (i32, i32), plane_scaling: Option<(i32, i32)>) -> (i32, i32) {
+    let src = plane_scaling.unwrap_or(mode);
+    (mode.0.max(src.0), mode.1.max(src.1))
+}
+
+/// Region of the crtc in `mode` a scaling plane shows the stream of `src` in, matching what the renderer would do
+fn plane_destination(src: (i32, i32), mode: (i32, i32)) -> Rectangle<i32, Physical> {
+    let dst = geometry::destination(Size::from(src), Rectangle::from_loc_and_size((0, 0), src), Size::from(mode)).dst;
+    Rectangle::from_loc_and_size(
+        (dst.loc.x.round() as i32, dst.loc.y.round() as i32),
+        (dst.size.w.round() as i32, dst.size.h.round() as i32),
+    )
+}
+
+impl TargetBackend for EglStreamBackend {
+    fn name(&self) -> &'static str {
+        "eglstream"
+    }
+
+    fn bind(&mut self, renderer: &mut Gles2Renderer) -> Result<()> {
+        renderer.bind(self.target.surface.clone())?;
+        Ok(())
+    }
+
+    /// Errors of the stream are decoded
+    fn present(&mut self) -> Result<(), PresentError> {
+        let swap_error = &self.target.swap_error;
+        self.target
+            .surface
+            .swap_buffers()
+            .map_err(|err| PresentError::Stream(swap_error.take().unwrap_or(NvEglError::Surface(err))))
+    }
+
+    /// A timed out acquire is kept, to be retried on the next call
+    fn acquire(&mut self) -> Result<(), PresentError> {
+        let pending = match self.target.pending_acquire.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        match pending.acquire() {
+            Err(NvEglError::AcquireTimeout) => {
+                self.target.pending_acquire.set(Some(pending));
+                Err(PresentError::Stream(NvEglError::AcquireTimeout))
+            }
+            // the stream took over the plane again
+            Ok(()) => {
+                self.frozen = None;
+                Ok(())
+            }
+            result => result.map_err(PresentError::Stream),
+        }
+    }
+
+    // the stream flips on its own
+    fn frame_submitted(&mut self) {}
+
+    /// The stream is invalidated before the modeset, which scans out a dumb buffer of the new size,
+    /// so the recreated stream attaches to the plane only once the new mode is active.
+    /// That happens when `ensure_surface` recreates the EGL surface before the next frame.
+    /// A frozen frame is scaled into the dumb buffer, so the switch shows a pause instead of black.
+    fn set_mode(&mut self, drm_mode: Mode) -> Result<()> {
+        let mode = (drm_mode.size().0 as i32, drm_mode.size().1 as i32);
+        let (mut db, fb) = create_dumb_framebuffer(
+            &self.drm_surface,
+            self.plane,
+            dumb_buffer_size(mode, self.scaling.as_ref().map(|scaling| scaling.src)),
+            self.depth,
+            &self.log,
+        )?;
+        // a scaled stream keeps its size, so it is not recreated
+        let (width, height) = stream_size(mode, self.rotation.as_ref(), self.scaling.as_ref());
+        if let Some(frame) = self.frozen.as_ref() {
+            if let Err(err) = fill_dumb_buffer(&self.drm_surface, &mut db, frame, (width, height)) {
+                slog::debug!(self.log, "Failed to copy the frozen frame: {:#}", err);
+            }
+        }
+        self.target.surface.resize(width, height, 0, 0);
+        let committed = self
+            .drm_surface
+            .use_mode(drm_mode)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.commit_dumb_buffer(fb, mode));
+        if let Err(err) = committed {
+            let _ = self.drm_surface.destroy_framebuffer(fb);
+            let _ = self.drm_surface.destroy_dumb_buffer(db);
+            return Err(err);
+        }
+        let _ = self.drm_surface.destroy_framebuffer(std::mem::replace(&mut self.fb, fb));
+        let _ = self.drm_surface.destroy_dumb_buffer(std::mem::replace(&mut self.db, db));
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Scans out the dumb buffer again, the stream is not attached to the plane anymore
+    /// and `rebuild_context` needs to follow.
+    fn restore_scanout(&mut self, drm_mode: Mode) -> Result<()> {
+        self.drm_surface.use_mode(drm_mode)?;
+        self.commit_dumb_buffer(self.fb, self.mode)
+    }
+
+    fn rebuild_context(&mut self, log: &slog::Logger) -> Result<Gles2Renderer> {
+        let (renderer, target) = create_target_context(
+            &self.display,
+            self.crtc,
+            self.plane,
+            stream_size(self.mode, self.rotation.as_ref(), self.scaling.as_ref()),
+            self.depth,
+            self.stream,
+            log,
+        )?;
+        self.target = target;
+        Ok(renderer)
+    }
+
+    /// The context stays, only the surface and its stream are replaced
+    fn ensure_surface(&mut self) -> Result<bool> {
+        if !egl::needs_recreation(&self.display.get_display_handle(), &self.target.stream) {
+            return Ok(false);
+        }
+        let (width, height) = stream_size(self.mode, self.rotation.as_ref(), self.scaling.as_ref());
+        slog::info!(self.log, "The stream of the output is gone, recreating the surface in {}x{}", width, height);
+        self.target = StreamTarget::new(
+            &self.display,
+            self.target.pixel_format,
+            self.target.config_id,
+            self.crtc,
+            self.plane,
+            (width, height),
+            self.stream,
+            &self.log,
+        )?;
+        Ok(true)
+    }
+
+    fn plane_scaling(&self) -> Option<(i32, i32)> {
+        self.scaling.as_ref().map(|scaling| scaling.src)
+    }
+
+    /// Copied into the dumb buffer right away, which `restore_scanout` commits, and into the one of the next mode
+    fn freeze(&mut self, frame: FrozenFrame) -> Result<()> {
+        let size = stream_size(self.mode, self.rotation.as_ref(), self.scaling.as_ref());
+        fill_dumb_buffer(&self.drm_surface, &mut self.db, &frame, size)?;
+        self.frozen = Some(frame);
+        Ok(())
+    }
+}
+
+impl Drop for EglStreamBackend {
+    fn drop(&mut self) {
+        let _ = self.drm_surface.destroy_framebuffer(self.fb);
+        let _ = self.drm_surface.destroy_dumb_buffer(self.db);
+    }
+}
+
+/// Renders into gbm buffers and flips them onto the crtc, for everything but nvidia
+pub struct GbmBackend {
+    surface: GbmBufferedSurface<Fd>,
+    display: EGLDisplay,
+    _gbm: GbmDevice<Fd>,
+    log: slog::Logger,
+}
+
+impl GbmBackend {
+    fn new(fd: Fd, drm_surface: DrmSurface<Fd>, log: &slog::Logger) -> Result<(GbmBackend, Gles2Renderer)> {
+        // the sync functions are not loaded by smithay
+        egl::load()?;
+        let gbm = GbmDevice::new(fd).with_context(|| "Failed to create gbm device")?;
+        let display = EGLDisplay::new(&gbm, log.clone())?;
+        let renderer = create_gbm_context(&display, log)?;
+        let formats = Bind::<Dmabuf>::supported_formats(&renderer)
+            .with_context(|| "Target renderer can't render into dmabufs")?;
+        let surface = GbmBufferedSurface::new(drm_surface, gbm.clone(), formats, log.clone())?;
+        Ok((
+            GbmBackend {
+                surface,
+                display,
+                _gbm: gbm,
+                log: log.clone(),
+            },
+            renderer,
+        ))
+    }
+}
+
+impl TargetBackend for GbmBackend {
+    fn name(&self) -> &'static str {
+        "gbm"
+    }
+
+    fn bind(&mut self, renderer: &mut Gles2Renderer) -> Result<()> {
+        let buffer = self.surface.next_buffer()?;
+        renderer.bind(buffer)?;
+        Ok(())
+    }
+
+    fn present(&mut self) -> Result<(), PresentError> {
+        self.surface.queue_buffer().map_err(|err| PresentError::Gbm(err.into()))
+    }
+
+    // queueing flips already
+    fn acquire(&mut self) -> Result<(), PresentError> {
+        Ok(())
+    }
+
+    fn frame_submitted(&mut self) {
+        if let Err(err) = self.surface.frame_submitted() {
+            slog::warn!(self.log, "Failed to submit next frame: {}", err);
+        }
+    }
+
+    /// Takes effect with the next queued buffer
+    fn set_mode(&mut self, drm_mode: Mode) -> Result<()> {
+        self.surface.use_mode(drm_mode)?;
+        Ok(())
+    }
+
+    /// The next queued buffer does a full modeset
+    fn restore_scanout(&mut self, drm_mode: Mode) -> Result<()> {
+        self.surface.reset_buffers();
+        self.surface.use_mode(drm_mode)?;
+        Ok(())
+    }
+
+    fn rebuild_context(&mut self, log: &slog::Logger) -> Result<Gles2Renderer> {
+        self.surface.reset_buffers();
+        create_gbm_context(&self.display, log)
+    }
+
+    // the buffers of the surface always have the size of the mode
+    fn plane_scaling(&self) -> Option<(i32, i32)> {
+        None
+    }
+}
+
+/// Whether the monitor still receives what the crtc scans out, as far as the driver can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkHealth {
+    Good,
+    /// The connector reports a bad "link-status", e.g. because the cable can't carry the bandwidth of the mode
+    Bad,
+    /// The crtc lost its mode without us turning it off
+    CrtcOff,
+}
+
+impl fmt::Display for LinkHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkHealth::Good => write!(f, "Link of the connector is good"),
+            LinkHealth::Bad => write!(f, "Link of the connector went bad"),
+            LinkHealth::CrtcOff => write!(f, "Crtc of the connector stopped scanning out"),
+        }
+    }
+}
+
 pub struct TargetGPU {
     pub renderer: Gles2Renderer,
-    pub surface: Rc<EGLSurface>,
-    _display: EGLDisplay,
-    _device: EGLDeviceEXT,
-    _drm_surface: DrmSurface<Fd>,
-    _fb: framebuffer::Handle,
-    _db: DumbBuffer,
+    backend: Box<dyn TargetBackend>,
+    /// Explicit synchronization with the compositor, for directly imported frames
+    pub sync: SyncSupport,
+    /// Color depth of the scanout buffer
+    pub depth: ColorDepth,
+    /// Signals the last upload finished reading the cpu buffer
+    pub upload_fence: Option<Fence>,
+    /// Requested vblanks between flips, without vsync the output is frequently still busy with the last flip
+    pub swap_interval: u32,
+    /// Current mode of the connector
+    pub mode: (i32, i32),
+    /// Transform the renderer applies, `Transform::Normal` if the plane does it
+    pub transform: Transform,
+    /// Transform given by `--transform`, however it is applied
+    output_transform: Transform,
+    /// The output runs with variable refresh rate, so frames are shown as soon as they are swapped
+    pub vrr: bool,
+    /// Name of the connector, like list-connectors shows it
+    pub connector_name: String,
+    /// What the context offers beyond GLES 2
+    pub gl: GlCapabilities,
+    /// Install the debug callback of `--gl-debug` on every new context
+    gl_debug: bool,
+    /// `None` for offscreen targets
+    kms: Option<KmsOutput>,
+}
+
+/// The connector a `TargetGPU` scans out on
+struct KmsOutput {
+    connector: connector::Handle,
+    /// Mode given by `--modeline`, which the connector does not list
+    modeline: Option<Mode>,
+    /// Connector and crtc properties, changed atomically if possible
+    props: PropertyCache,
+    /// Monitor the output was last set up for, to notice it being replaced
+    edid: Option<Edid>,
+    /// Gamma table of the crtc before `--gamma-lut` replaced it, `Some(None)` if it had none
+    restore_gamma: Option<Option<GammaLut>>,
+    fd: Fd,
+    log: slog::Logger,
 }
 
-impl Drop for TargetGPU {
+impl Drop for KmsOutput {
     fn drop(&mut self) {
-        let _ = self._drm_surface.destroy_framebuffer(self._fb);
-        let _ = self._drm_surface.destroy_dumb_buffer(self._db);
+        if let Some(previous) = self.restore_gamma.take() {
+            if let Err(err) = self.props.set_gamma(previous.as_ref()) {
+                slog::warn!(self.log, "Failed to restore the gamma table of the crtc: {}", err);
+            }
+        }
+    }
+}
+
+/// What changed about the monitor on the connector, after a hotplug event
+#[derive(Debug, Default)]
+pub struct Hotplug {
+    /// Monitors before and after, if another one was plugged in
+    pub replaced: Option<(Option<Edid>, Option<Edid>)>,
+    /// Mode to switch to, `None` keeps the current one
+    pub mode: Option<(i32, i32)>,
+}
+
+impl TargetGPU {
+    /// Initializes the target like `init_target_gpu`, cleaning up what a crashed run may have left on the crtc
+    /// and trying again, if the failure looks like it was caused by that
+    pub fn initialize_with_recovery(
+        fd: Fd,
+        options: &TargetOptions,
+        log: slog::Logger,
+    ) -> Result<(TargetGPU, DrmDevice<Fd>)> {
+        retry_with_recovery(
+            || init_target_gpu(fd.clone(), options, log.clone()),
+            || clean_up_target(&fd, options, &log),
+            &log,
+        )
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    /// Size of the mirrored content, which is the mode after applying the transform,
+    /// unless the plane scales the frames to the mode
+    pub fn size(&self) -> Size<i32, Physical> {
+        match self.backend.plane_scaling() {
+            Some(src) => geometry::transformed_size(Size::from(src), self.transform),
+            None => geometry::transformed_size(Size::from(self.mode), self.output_transform),
+        }
+    }
+
+    /// Size of the buffers rendered into, which the renderer applies `transform` to
+    pub fn surface_size(&self) -> Size<i32, Physical> {
+        geometry::transformed_size(self.size(), self.transform)
+    }
+
+    /// Whether the plane scales the frames to the mode, see `TargetOptions::plane_scaling`
+    pub fn plane_scaled(&self) -> bool {
+        self.backend.plane_scaling().is_some()
+    }
+
+    /// Monitor the output was last set up for, `None` offscreen or if its EDID can't be read
+    pub fn edid(&self) -> Option<&Edid> {
+        self.kms.as_ref().and_then(|kms| kms.edid.as_ref())
+    }
+
+    /// Depth frames are uploaded in, which is at most 8 bit if the context lacks `GlCapabilities::ten_bit`
+    pub fn upload_depth(&self) -> ColorDepth {
+        if self.gl.ten_bit {
+            self.depth
+        } else {
+            ColorDepth::Eight
+        }
+    }
+
+    /// Makes the output the render target of the renderer, recreating its surface first if needed
+    pub fn bind(&mut self) -> Result<()> {
+        self.ensure_surface()?;
+        self.backend.bind(&mut self.renderer)
+    }
+
+    /// Recreates the surface of the output after a mode change, a disconnected stream or a DPMS cycle invalidated it.
+    ///
+    /// The context and renderer are kept, `bind` then targets the new surface. Returns whether it was recreated.
+    pub fn ensure_surface(&mut self) -> Result<bool> {
+        self.backend
+            .ensure_surface()
+            .context("Failed to recreate the surface of the output")
+    }
+
+    /// Keeps `frame`, see `render::freeze_frame`, on screen while the output is set up again
+    pub fn freeze(&mut self, frame: FrozenFrame) -> Result<()> {
+        self.backend.freeze(frame).context("Failed to freeze the frame")
+    }
+
+    /// Presents the rendered frame, `acquire` hands it to the display
+    pub fn swap_buffers(&mut self) -> Result<(), PresentError> {
+        self.backend.present()
+    }
+
+    /// Hands the last presented frame to the display, may wait up to the acquire timeout for the previous flip
+    pub fn acquire(&mut self) -> Result<(), PresentError> {
+        self.backend.acquire()
+    }
+
+    /// Called on every vblank of the crtc
+    pub fn frame_submitted(&mut self) {
+        self.backend.frame_submitted()
+    }
+
+    /// Powers the monitor on or off, frames are not displayed while it is off
+    pub fn set_dpms(&self, dpms: Dpms) -> Result<()> {
+        let kms = match self.kms.as_ref() {
+            Some(kms) => kms,
+            None => return Ok(()),
+        };
+        kms.props.set_dpms(dpms)?;
+        if dpms == Dpms::On && !kms.props.wait_for_link()? {
+            anyhow::bail!("Link of the connector did not come up");
+        }
+        Ok(())
+    }
+
+    /// Whether we are drm master of the device, becoming it again if nobody else is.
+    ///
+    /// Another process takes the device over e.g. while another VT is active.
+    pub fn is_master(&self) -> bool {
+        match self.kms.as_ref() {
+            Some(kms) => unsafe { drm_set_master(kms.fd.as_raw_fd()) }.is_ok(),
+            None => true,
+        }
+    }
+
+    /// Whether a monitor is still plugged into the connector
+    pub fn connected(&self) -> Result<bool> {
+        match self.kms.as_ref() {
+            Some(kms) => Ok(kms.fd.get_connector(kms.connector)?.state() != ConnectorState::Disconnected),
+            None => Ok(true),
+        }
+    }
+
+    /// Reads the link status of the connector and the state of the crtc, offscreen targets are always good
+    pub fn link_health(&self) -> Result<LinkHealth> {
+        let kms = match self.kms.as_ref() {
+            Some(kms) => kms,
+            None => return Ok(LinkHealth::Good),
+        };
+        if kms.fd.get_crtc(kms.props.crtc())?.mode().is_none() {
+            return Ok(LinkHealth::CrtcOff);
+        }
+        Ok(if kms.props.link_good()? {
+            LinkHealth::Good
+        } else {
+            LinkHealth::Bad
+        })
+    }
+
+    /// Switches to the mode needing the next lower bandwidth, after the link failed in the current one.
+    ///
+    /// Returns the new mode, `None` if the connector has no lower one.
+    pub fn lower_mode(&mut self) -> Result<Option<Mode>> {
+        let kms = self.kms.as_ref().context("Offscreen targets have no link")?;
+        let current = match kms.fd.get_crtc(kms.props.crtc())?.mode() {
+            Some(current) => current,
+            // the crtc lost it, so the mode we set last is the one that failed
+            None => self
+                .find_mode(self.mode)?
+                .with_context(|| format!("Mode {}x{} not supported by connector", self.mode.0, self.mode.1))?,
+        };
+        let info = kms.fd.get_connector(kms.connector)?;
+        let drm_mode = match next_lower_mode(info.modes(), &current) {
+            Some(drm_mode) => drm_mode,
+            None => return Ok(None),
+        };
+        self.backend.set_mode(drm_mode).with_context(|| {
+            format!(
+                "Failed to set mode {}x{}@{}",
+                drm_mode.size().0,
+                drm_mode.size().1,
+                drm_mode.vrefresh()
+            )
+        })?;
+        self.mode = (drm_mode.size().0 as i32, drm_mode.size().1 as i32);
+        Ok(Some(drm_mode))
+    }
+
+    /// The drm mode of `mode`, offscreen targets take any size as is
+    fn find_mode(&self, mode: (i32, i32)) -> Result<Option<Mode>> {
+        let kms = match self.kms.as_ref() {
+            Some(kms) => kms,
+            None => return Ok(None),
+        };
+        let info = kms.fd.get_connector(kms.connector)?;
+        Ok(kms
+            .modeline
+            .iter()
+            .chain(info.modes().iter())
+            .find(|drm_mode| drm_mode.size() == (mode.0 as u16, mode.1 as u16))
+            .cloned())
+    }
+
+    /// Replaces the context, after a gpu reset lost it.
+    ///
+    /// The drm side survives resets, so the output keeps its mode.
+    /// Everything created with the old renderer needs to be recreated as well.
+    pub fn rebuild_context(&mut self, log: &slog::Logger) -> Result<()> {
+        let mut renderer = self.backend.rebuild_context(log)?;
+        let (sync, gl) = setup_context(&mut renderer, self.gl_debug, log)?;
+        self.sync = sync;
+        self.gl = gl;
+        self.upload_fence = None;
+        self.renderer = renderer;
+        Ok(())
+    }
+
+    /// Takes the crtc back, after the session was inactive and somebody else used it.
+    ///
+    /// `rebuild_context` needs to follow.
+    pub fn restore_scanout(&mut self) -> Result<()> {
+        if self.kms.is_none() {
+            return Ok(());
+        }
+        let drm_mode = self
+            .find_mode(self.mode)?
+            .with_context(|| format!("Mode {}x{} not supported by connector anymore", self.mode.0, self.mode.1))?;
+        self.backend.restore_scanout(drm_mode)
+    }
+
+    /// Flips captured frames onto the crtc instead of rendering them, `None` for offscreen targets
+    pub fn direct_scanout(&self, log: &slog::Logger) -> Option<Scanout> {
+        self.kms
+            .as_ref()
+            .map(|kms| Scanout::new(kms.fd.clone(), kms.props.crtc(), log.clone()))
+    }
+
+    /// Whether a frame of `size` would be shown as is, neither scaled nor transformed
+    pub fn shows_unchanged(&self, size: Size<i32, Physical>) -> bool {
+        self.output_transform == Transform::Normal
+            && self.backend.plane_scaling().is_none()
+            && Size::from(self.mode) == size
+    }
+
+    /// Shows `image` on the cursor plane of the crtc.
+    ///
+    /// Fails if the frames are rotated or scaled by the display engine, which cursor positions don't follow.
+    pub fn create_cursor(&self, image: &CursorImage) -> Result<HardwareCursor> {
+        if self.output_transform != Transform::Normal || self.backend.plane_scaling().is_some() {
+            anyhow::bail!("The cursor plane can't be used along --transform or --plane-scaling");
+        }
+        let kms = self.kms.as_ref().context("Offscreen targets have no cursor plane")?;
+        HardwareCursor::new(kms.fd.clone(), kms.props.crtc(), image)
+    }
+
+    /// Looks at the monitor on the connector again, after a hotplug event.
+    ///
+    /// `wanted` is used whenever the monitor supports it. Otherwise the current mode is kept if possible and
+    /// the preferred mode of the monitor used if not, while a different monitor gets the mode closest to `wanted`.
+    pub fn hotplug(&mut self, wanted: (i32, i32)) -> Result<Hotplug> {
+        let kms = match self.kms.as_mut() {
+            Some(kms) => kms,
+            None => return Ok(Hotplug::default()),
+        };
+        let info = kms.fd.get_connector(kms.connector)?;
+        if info.state() != ConnectorState::Connected {
+            return Ok(Hotplug::default());
+        }
+        let edid = edid::read(&kms.fd, kms.connector).ok().flatten();
+        let replaced = if edid != kms.edid {
+            Some((std::mem::replace(&mut kms.edid, edid.clone()), edid))
+        } else {
+            None
+        };
+        let size = |drm_mode: &Mode| (drm_mode.size().0 as i32, drm_mode.size().1 as i32);
+        let supported = |mode: (i32, i32)| {
+            kms.modeline
+                .iter()
+                .chain(info.modes().iter())
+                .any(|drm_mode| size(drm_mode) == mode)
+        };
+        let mode = if supported(wanted) {
+            Some(wanted)
+        } else if replaced.is_some() {
+            closest_mode(info.modes(), wanted, None).map(|drm_mode| size(&drm_mode))
+        } else if supported(self.mode) {
+            Some(self.mode)
+        } else {
+            info.modes()
+                .iter()
+                .find(|drm_mode| drm_mode.mode_type().contains(ModeTypeFlags::PREFERRED))
+                .or_else(|| info.modes().first())
+                .map(size)
+        };
+        Ok(Hotplug {
+            replaced,
+            mode: mode.filter(|mode| *mode != self.mode),
+        })
+    }
+
+    /// Writes the last rendered frame to `path`, only offscreen targets keep it around
+    pub fn dump_png(&mut self, path: &Path) -> Result<()> {
+        if self.kms.is_some() {
+            anyhow::bail!("Only offscreen targets can be dumped");
+        }
+        self.bind()?;
+        offscreen::write_png(&mut self.renderer, self.mode, path)
+    }
+
+    /// Pixels of the frame last rendered offscreen as RGBA, starting with the top row
+    pub fn read_frame(&mut self) -> Result<Vec<u8>> {
+        if self.kms.is_some() {
+            anyhow::bail!("Only offscreen targets can be read back");
+        }
+        self.bind()?;
+        offscreen::read_frame(&mut self.renderer, self.mode)
+    }
+
+    /// Switches the connector to `mode`, without touching anything on the compositor side.
+    pub fn set_mode(&mut self, mode: (i32, i32)) -> Result<()> {
+        if self.kms.is_none() {
+            anyhow::bail!("Offscreen targets keep their size");
+        }
+        let drm_mode = self
+            .find_mode(mode)?
+            .with_context(|| format!("Mode {}x{} not supported by connector", mode.0, mode.1))?;
+        self.backend
+            .set_mode(drm_mode)
+            .with_context(|| format!("Failed to set mode {}x{}", mode.0, mode.1))?;
+        self.mode = mode;
+        Ok(())
     }
 }
 
 pub struct RenderGPU {
     pub renderer: Gles2Renderer,
+    /// Supports `GL_PACK_ROW_LENGTH` (GLES 3 or `GL_NV_pack_subimage`)
+    pub pack_row_length: bool,
+    /// Supports pixel buffer objects (GLES 3)
+    pub pixel_buffers: bool,
+    /// Explicit synchronization with the compositor, which otherwise relies on implicit sync
+    pub sync: SyncSupport,
+    /// Pixel buffers of `--async-readback`, created on first use
+    pub readback: Option<AsyncReadback>,
+    /// Framebuffer of the blit readback route, created on first use
+    pub blit: Option<BlitTarget>,
+    /// Scales frames down in linear light for `--linear-blending`, created on first use
+    pub blend_shader: Option<AdjustShader>,
+    /// Modifiers of frames read back so far, the route taken is logged once for each
+    pub readback_modifiers: HashSet<Modifier>,
+    /// Copies frames through vulkan instead of reading them back, if the gpu supports it
+    #[cfg(feature = "vulkan")]
+    pub vulkan: Option<VulkanCopy>,
     _display: EGLDisplay,
     _device: EGLDeviceEXT,
 }
@@ -58,8 +991,11 @@ pub struct Fd {
 }
 
 impl Fd {
+    /// Opens a drm node read-write, modesetting ioctls fail on read-only fds
     pub fn open<P: AsRef<Path>>(file: &P) -> std::io::Result<Fd> {
-        Ok(Fd::new(File::open(file.as_ref())?))
+        Ok(Fd::new(
+            OpenOptions::new().read(true).write(true).open(file.as_ref())?,
+        ))
     }
 
     pub fn new(file: File) -> Fd {
@@ -86,145 +1022,2380 @@ impl AsRawFd for Fd {
     }
 }
 impl DrmDeviceNode for Fd {}
+impl ControlDevice for Fd {}
 
-pub fn find_nvidia_gpu(log: slog::Logger) -> Option<PathBuf> {
-    let seat = std::env::var("XDG_SEAT").expect("XDG_SEAT is not set");
-    let udev_backend = UdevBackend::new(seat, log).ok()?;
+/// A connector of a gpu, along the monitor plugged into it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectorEntry {
+    pub name: String,
+    /// Object id of the connector, like modetest and kmsprint show it
+    pub id: u32,
+    pub state: ConnectorState,
+    /// `None` without monitor or if its EDID can't be read
+    pub edid: Option<Edid>,
+}
 
-    // Enumerate gpus
-    let path = udev_backend
-        .device_list()
-        .flat_map(|(dev, path)| driver(dev).ok().and_then(|x| x.map(|x| (x, path))))
-        .flat_map(|(driver_os, path)| driver_os.into_string().ok().map(|x| (x, path)))
-        .filter(|(driver, _)| driver.contains("nvidia"))
-        .map(|(_, path)| path.to_path_buf())
-        .next();
+impl ConnectorEntry {
+    pub fn query<D: ControlDevice>(device: &D, info: &ConnectorInfo) -> ConnectorEntry {
+        ConnectorEntry {
+            name: connector_name(info),
+            id: u32::from(info.handle()),
+            state: info.state(),
+            edid: edid::read(device, info.handle()).ok().flatten(),
+        }
+    }
+}
 
-    path
+impl fmt::Display for ConnectorEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.edid.as_ref() {
+            Some(edid) => write!(f, "{} ({})", self.name, edid),
+            None => write!(f, "{}", self.name),
+        }
+    }
 }
 
-pub fn init_render_gpu(fd: Fd, log: slog::Logger) -> Result<RenderGPU> {
-    let egl_device = EGLDeviceEXT::new(fd, log.clone())?;
-    let display = EGLDisplay::new(&egl_device, log.clone())?;
-    let context = EGLContext::new(&display, log.clone())?;
-    let renderer = unsafe { Gles2Renderer::new(context, log.clone())? };
+/// Prefix of `--connector` selecting a connector by its object id
+const CONNECTOR_ID_PREFIX: &str = "id:";
 
-    Ok(RenderGPU {
-        _device: egl_device,
-        _display: display,
-        renderer,
-    })
+/// Index of the connected connector `wanted` refers to, either by its name, by "id:N" or by the name or serial of its
+/// monitor.
+///
+/// Monitor names matching multiple connectors, e.g. of two identical monitors, are an error listing them.
+pub fn resolve_connector(connectors: &[ConnectorEntry], wanted: &str) -> Result<Option<usize>> {
+    let connected = connectors
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.state == ConnectorState::Connected);
+    if let Some(id) = wanted.strip_prefix(CONNECTOR_ID_PREFIX) {
+        let id = u32::from_str_radix(id, 10).with_context(|| format!("Invalid connector id \"{}\"", id))?;
+        return Ok(connected.clone().find(|(_, entry)| entry.id == id).map(|(index, _)| index));
+    }
+    if let Some((index, _)) = connected.clone().find(|(_, entry)| entry.name == wanted) {
+        return Ok(Some(index));
+    }
+    let matching = connected
+        .filter(|(_, entry)| entry.edid.as_ref().map(|edid| edid.matches(wanted)).unwrap_or(false))
+        .collect::<Vec<_>>();
+    match matching.as_slice() {
+        [] => Ok(None),
+        [(index, _)] => Ok(Some(*index)),
+        _ => {
+            let candidates = matching.iter().map(|(_, entry)| entry.to_string()).collect::<Vec<_>>();
+            anyhow::bail!(
+                "Monitor \"{}\" is plugged into multiple connectors, pick one by name or serial: {}",
+                wanted,
+                candidates.join(", ")
+            )
+        }
+    }
 }
 
-pub fn init_target_gpu(
-    path: PathBuf,
+/// A gpu found while looking for the target: device path, driver and its connectors
+pub type GpuCandidate = (PathBuf, String, Vec<ConnectorEntry>);
+
+/// Picks the gpu to scan out on among `candidates`, returns its index and why the ones before were skipped.
+///
+/// Only nvidia gpus are considered, unless `any_driver` is set. `device_index` selects among those explicitly,
+/// otherwise the first one with a connected connector is used, or with `connector` connected if given.
+pub fn select_nvidia_gpu(
+    candidates: &[GpuCandidate],
     connector: Option<&str>,
-    mode: (i32, i32),
-    log: slog::Logger,
-) -> Result<(TargetGPU, DrmDevice<Fd>)> {
-    let fd = Fd {
-        fd: File::open(&path)?,
-    };
-    let device = DrmDevice::new(fd.clone(), false, log.clone())?;
-    let egl_device = EGLDeviceEXT::new(fd, log.clone())?;
-    // Get a set of all modesetting resource handles (excluding planes):
-    let res_handles = device.resource_handles().unwrap();
+    device_index: Option<usize>,
+    any_driver: bool,
+) -> (Option<usize>, Vec<(usize, String)>) {
+    let mut skipped = Vec::new();
+    let mut nvidia = 0;
+    for (i, (_, driver, connectors)) in candidates.iter().enumerate() {
+        if !any_driver && !driver.contains("nvidia") {
+            skipped.push((i, format!("driven by {}", driver)));
+            continue;
+        }
+        let index = nvidia;
+        nvidia += 1;
 
-    // Use first connected connector
-    let connector_info: ConnectorInfo = res_handles
-        .connectors()
-        .iter()
-        .map(|conn| device.get_connector(*conn).unwrap())
-        .filter(|conn| conn.state() == ConnectorState::Connected)
-        .inspect(|conn| {
-            slog::info!(
-                log,
-                "Connected: {:?}-{:?}",
-                conn.interface(),
-                conn.interface_id()
-            )
+        // an ambiguous monitor name fails later on, listing the candidates
+        let connected = |name: &str| !matches!(resolve_connector(connectors, name), Ok(None));
+        let reason = match (device_index, connector) {
+            (Some(wanted), _) if wanted != index => Some(format!("not device index {}", wanted)),
+            (Some(_), _) => None,
+            (None, Some(name)) if !connected(name) => Some(format!("{} is not connected", name)),
+            (None, None) if !connectors.iter().any(|entry| entry.state == ConnectorState::Connected) => {
+                Some(String::from("no connected connectors"))
+            }
+            (None, _) => None,
+        };
+        match reason {
+            Some(reason) => skipped.push((i, reason)),
+            None => return (Some(i), skipped),
+        }
+    }
+    (None, skipped)
+}
+
+/// Name of the connector as used by `--connector`, e.g. "HDMI-1"
+pub fn connector_name(conn: &ConnectorInfo) -> String {
+    format_connector_name(conn.interface(), conn.interface_id())
+}
+
+/// Name of the `interface_id`th connector of `interface`, counted from 1 per interface
+fn format_connector_name(interface: Interface, interface_id: u32) -> String {
+    format!("{}-{}", interface_name(interface), interface_id)
+}
+
+/// Prefix of the interface in connector names.
+///
+/// DVI and HDMI drop the subtype the kernel names them by, so "HDMI-A-1" of modetest is "HDMI-1" here.
+pub fn interface_name(interface: Interface) -> &'static str {
+    match interface {
+        Interface::VGA => "VGA",
+        Interface::DVII | Interface::DVID | Interface::DVIA => "DVI",
+        Interface::Composite => "Composite",
+        Interface::SVideo => "SVIDEO",
+        Interface::LVDS => "LVDS",
+        Interface::Component => "Component",
+        Interface::NinePinDIN => "DIN",
+        Interface::DisplayPort => "DP",
+        Interface::HDMIA | Interface::HDMIB => "HDMI",
+        Interface::TV => "TV",
+        Interface::EmbeddedDisplayPort => "eDP",
+        Interface::Virtual => "Virtual",
+        Interface::DSI => "DSI",
+        Interface::DPI => "DPI",
+        // drm 0.4 does not know Writeback, SPI and USB, those are selected by id
+        _ => "Unknown",
+    }
+}
+
+/// Whether a monitor is plugged into `connector`, or into any connector if `None`
+pub fn connector_connected(fd: &Fd, connector: Option<&str>) -> Result<bool> {
+    let res_handles = fd.resource_handles()?;
+    let connectors = res_handles
+        .connectors()
+        .iter()
+        .flat_map(|conn| fd.get_connector(*conn).ok())
+        .map(|conn| ConnectorEntry::query(fd, &conn))
+        .collect::<Vec<_>>();
+    match connector {
+        Some(name) => Ok(resolve_connector(&connectors, name)?.is_some()),
+        None => Ok(connectors.iter().any(|entry| entry.state == ConnectorState::Connected)),
+    }
+}
+
+/// Monitor on the connector the target would be set up on, before it is
+pub fn target_edid(fd: &Fd, connector: Option<&str>) -> Result<Option<Edid>> {
+    let res_handles = fd.resource_handles()?;
+    // the connectors are listed once the target is set up
+    let quiet = slog::Logger::root(slog::Discard, slog::o!());
+    let connector_info = find_connector(fd, &res_handles, connector, &quiet)?;
+    edid::read(fd, connector_info.handle())
+}
+
+/// All connectors of the gpu at `path`
+fn list_connectors(path: &Path, log: &slog::Logger) -> Result<Vec<ConnectorEntry>> {
+    let device = DrmDevice::new(Fd::open(&path)?, false, log.clone())?;
+    let res_handles = device.resource_handles()?;
+    Ok(res_handles
+        .connectors()
+        .iter()
+        .flat_map(|conn| device.get_connector(*conn).ok())
+        .map(|conn| ConnectorEntry::query(&device, &conn))
+        .collect())
+}
+
+/// Seat whose gpus are searched: `--seat`, the one of the session in `XDG_SEAT` or "seat0"
+pub fn resolve_seat(flag: Option<&str>, env: Option<String>) -> String {
+    flag.map(String::from)
+        .or_else(|| env.filter(|seat| !seat.is_empty()))
+        .unwrap_or_else(|| String::from("seat0"))
+}
+
+/// Drm devices of all seats, with the seat udev assigned them to
+fn gpus_of_all_seats() -> Result<Vec<(nix::libc::dev_t, PathBuf, String)>> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("drm")?;
+    enumerator.match_sysname("card[0-9]*")?;
+    Ok(enumerator
+        .scan_devices()?
+        .filter_map(|device| {
+            // devices without assignment belong to the default seat
+            let seat = device
+                .property_value("ID_SEAT")
+                .and_then(|seat| seat.to_str())
+                .unwrap_or("seat0")
+                .to_string();
+            Some((device.devnum()?, device.devnode()?.to_path_buf(), seat))
         })
-        .find(|conn| {
-            if let Some(connector) = connector {
-                format!(
-                    "{}-{}",
-                    match conn.interface() {
-                        Interface::VGA => "VGA",
-                        Interface::DVII | Interface::DVID | Interface::DVIA => "DVI",
-                        Interface::LVDS => "LVDS",
-                        Interface::DisplayPort => "DP",
-                        Interface::HDMIA | Interface::HDMIB => "HDMI",
-                        Interface::EmbeddedDisplayPort => "eDP",
-                        _ => "Unsupported",
-                    },
-                    conn.interface_id()
-                ) == connector
+        .collect())
+}
+
+/// Finds the nvidia gpu to scan out on, see `select_nvidia_gpu`.
+///
+/// If no gpu is assigned to `seat`, the gpus of all seats are searched.
+pub fn find_nvidia_gpu(
+    seat: &str,
+    connector: Option<&str>,
+    device_index: Option<usize>,
+    any_driver: bool,
+    log: slog::Logger,
+) -> Option<PathBuf> {
+    let udev_backend = UdevBackend::new(seat, log.clone()).ok()?;
+    let mut devices = udev_backend
+        .device_list()
+        .map(|(dev, path)| (dev, path.to_path_buf()))
+        .collect::<Vec<_>>();
+    if devices.is_empty() {
+        slog::warn!(log, "No gpus are assigned to {}, searching the gpus of all seats", seat);
+        devices = gpus_of_all_seats()
+            .map(|gpus| gpus.into_iter().map(|(dev, path, _)| (dev, path)).collect())
+            .unwrap_or_else(|err| {
+                slog::warn!(log, "Failed to enumerate gpus: {}", err);
+                Vec::new()
+            });
+    }
+
+    // Enumerate gpus
+    let candidates = devices
+        .iter()
+        .flat_map(|(dev, path)| driver(*dev).ok().and_then(|x| x.map(|x| (x, path))))
+        .flat_map(|(driver_os, path)| driver_os.into_string().ok().map(|x| (x, path)))
+        .map(|(driver, path)| {
+            let connectors = if any_driver || driver.contains("nvidia") {
+                list_connectors(path, &log).unwrap_or_else(|err| {
+                    slog::warn!(log, "Failed to read connectors of {}: {}", path.display(), err);
+                    Vec::new()
+                })
             } else {
-                true
+                Vec::new()
+            };
+            slog::debug!(log, "Gpu {} ({}): {:?}", path.display(), driver, connectors);
+            (path.to_path_buf(), driver, connectors)
+        })
+        .collect::<Vec<GpuCandidate>>();
+
+    let (selected, skipped) = select_nvidia_gpu(&candidates, connector, device_index, any_driver);
+    for (i, reason) in skipped {
+        slog::info!(log, "Skipping gpu {}: {}", candidates[i].0.display(), reason);
+    }
+    selected.map(|i| candidates[i].0.clone())
+}
+
+/// Whether any drm device of any seat is bound to nvidia, or to any driver at all with `any_driver`
+pub fn gpu_present(any_driver: bool) -> bool {
+    gpus_of_all_seats()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(dev, _, _)| driver(dev).ok().flatten())
+        .any(|driver| any_driver || driver.to_string_lossy().contains("nvidia"))
+}
+
+/// What a gpu can be used for, as judged by `list-gpus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuVerdict {
+    /// An nvidia gpu with modesetting, which EGL can drive through EGLStreams
+    Target,
+    /// Any other gpu EGL knows, which compositors can render on
+    Render,
+    Unsupported,
+}
+
+impl fmt::Display for GpuVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpuVerdict::Target => write!(f, "usable as target"),
+            GpuVerdict::Render => write!(f, "usable as render"),
+            GpuVerdict::Unsupported => write!(f, "unsupported"),
+        }
+    }
+}
+
+/// A drm device of any seat, along what it takes to mirror onto or from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuEntry {
+    pub path: PathBuf,
+    pub seat: String,
+    /// `None` if no driver is bound
+    pub driver: Option<String>,
+    pub render_node: Option<PathBuf>,
+    /// Whether nvidia-drm has modesetting enabled, `None` for other drivers or if it is not loaded
+    pub modeset: Option<bool>,
+    /// Why EGL does not know the device, `None` if `EGLDeviceEXT` finds it
+    pub egl_error: Option<String>,
+}
+
+impl GpuEntry {
+    pub fn is_nvidia(&self) -> bool {
+        self.driver.as_deref().map(|driver| driver.contains("nvidia")).unwrap_or(false)
+    }
+
+    pub fn verdict(&self) -> GpuVerdict {
+        if self.egl_error.is_some() {
+            GpuVerdict::Unsupported
+        } else if self.is_nvidia() && self.modeset == Some(true) {
+            GpuVerdict::Target
+        } else if self.render_node.is_some() {
+            GpuVerdict::Render
+        } else {
+            GpuVerdict::Unsupported
+        }
+    }
+}
+
+/// Drm devices of all seats, each probed on its own so one failing does not hide the others
+pub fn list_gpus(log: &slog::Logger) -> Result<Vec<GpuEntry>> {
+    Ok(gpus_of_all_seats()?
+        .into_iter()
+        .map(|(dev, path, seat)| {
+            let driver = driver(dev).ok().flatten().and_then(|driver| driver.into_string().ok());
+            let render_node = DeviceNodes::find(Path::new("/sys"), dev)
+                .ok()
+                .and_then(|nodes| nodes.render)
+                .map(PathBuf::from);
+            let modeset = match driver.as_deref() {
+                Some(driver) if driver.contains("nvidia") => nvidia_drm_modeset(),
+                _ => None,
+            };
+            let egl_error = EGLDeviceEXT::probe(dev, log).err().map(|err| err.to_string());
+            GpuEntry {
+                path,
+                seat,
+                driver,
+                render_node,
+                modeset,
+                egl_error,
             }
         })
-        .with_context(|| "Unable to find connector")?;
+        .collect())
+}
 
-    let crtc = connector_info
-        .encoders()
+const NVIDIA_DRM_MODESET: &str = "/sys/module/nvidia_drm/parameters/modeset";
+
+/// Whether nvidia-drm was loaded with modesetting enabled, `None` if it is not loaded (yet)
+pub fn nvidia_drm_modeset() -> Option<bool> {
+    std::fs::read_to_string(NVIDIA_DRM_MODESET)
+        .ok()
+        .map(|value| matches!(value.trim(), "Y" | "1"))
+}
+
+/// Why no nvidia gpu shows up, if the state of nvidia-drm explains it
+pub fn nvidia_drm_hint() -> Option<&'static str> {
+    match nvidia_drm_modeset() {
+        Some(true) => None,
+        Some(false) => Some("nvidia-drm is loaded without modesetting, load nvidia-drm with modeset=1"),
+        None => Some("nvidia-drm is not loaded, load nvidia-drm with modeset=1"),
+    }
+}
+
+/// Version string and extensions of the renderers context
+fn gl_info(renderer: &mut Gles2Renderer) -> Result<(String, Vec<String>)> {
+    Ok(renderer.with_context(|_renderer, gl| unsafe {
+        use smithay::backend::renderer::gles2::ffi;
+        let string = |name| {
+            let ptr = gl.GetString(name);
+            if ptr.is_null() {
+                String::new()
+            } else {
+                std::ffi::CStr::from_ptr(ptr as *const _)
+                    .to_string_lossy()
+                    .into_owned()
+            }
+        };
+        let extensions = string(ffi::EXTENSIONS)
+            .split(' ')
+            .map(String::from)
+            .collect();
+        (string(ffi::VERSION), extensions)
+    })?)
+}
+
+/// What a context offers beyond GLES 2, older drivers like 390xx only create GLES 2 contexts for the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlCapabilities {
+    /// Version of GLES the context reports, which may be newer than the requested one
+    pub version: (u32, u32),
+    /// GLES 3 or GL_EXT_unpack_subimage, to upload rects straight out of a larger image
+    pub unpack_row_length: bool,
+    /// GLES 3 or GL_EXT_texture_type_2_10_10_10_REV, to upload 10 bit pixels
+    pub ten_bit: bool,
+    /// GL_KHR_debug, for `--gl-debug`
+    pub khr_debug: bool,
+    /// GL_MAX_TEXTURE_SIZE, the largest frame the context can texture from in either dimension
+    pub max_texture_size: i32,
+}
+
+impl GlCapabilities {
+    pub fn query(renderer: &mut Gles2Renderer) -> Result<GlCapabilities> {
+        let (version, extensions) = gl_info(renderer)?;
+        let has = |name: &str| extensions.iter().any(|ext| ext == name);
+        // "OpenGL ES <major>.<minor> <vendor specific>"
+        let version = version
+            .strip_prefix("OpenGL ES ")
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|number| {
+                let (major, minor) = number.split_once('.')?;
+                Some((major.parse().ok()?, minor.parse().ok()?))
+            })
+            .unwrap_or((2, 0));
+        let gles3 = version.0 >= 3;
+        let max_texture_size = renderer.with_context(|_renderer, gl| unsafe {
+            let mut size = 0;
+            gl.GetIntegerv(smithay::backend::renderer::gles2::ffi::MAX_TEXTURE_SIZE, &mut size);
+            size
+        })?;
+        Ok(GlCapabilities {
+            version,
+            unpack_row_length: gles3 || has("GL_EXT_unpack_subimage"),
+            ten_bit: gles3 || has("GL_EXT_texture_type_2_10_10_10_REV"),
+            khr_debug: has("GL_KHR_debug"),
+            max_texture_size,
+        })
+    }
+
+    /// Largest texture size in either dimension, drivers not reporting a maximum are trusted with any
+    pub fn texture_limit(&self) -> i32 {
+        if self.max_texture_size > 0 {
+            self.max_texture_size
+        } else {
+            i32::MAX
+        }
+    }
+
+    pub fn fits_texture(&self, width: i32, height: i32) -> bool {
+        width <= self.texture_limit() && height <= self.texture_limit()
+    }
+
+    /// Immutable texture storage, fence syncs and framebuffer blits
+    pub fn gles3(&self) -> bool {
+        self.version.0 >= 3
+    }
+}
+
+impl fmt::Display for GlCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+        write!(
+            f,
+            "GLES {}.{} (row length: {}, 10 bit uploads: {}, KHR_debug: {}, max texture size: {})",
+            self.version.0,
+            self.version.1,
+            yes_no(self.unpack_row_length),
+            yes_no(self.ten_bit),
+            yes_no(self.khr_debug),
+            self.max_texture_size
+        )
+    }
+}
+
+pub fn init_render_gpu(fd: Fd, log: slog::Logger) -> Result<RenderGPU> {
+    #[cfg(feature = "vulkan")]
+    let vulkan = match VulkanCopy::new(fd.as_raw_fd(), &log) {
+        Ok(vulkan) => Some(vulkan),
+        Err(err) => {
+            slog::warn!(log, "Vulkan copies unavailable: {:#}", err);
+            None
+        }
+    };
+    let egl_device = EGLDeviceEXT::new(fd, log.clone())?;
+    let display = EGLDisplay::new(&egl_device, log.clone())?;
+    let context = EGLContext::new(&display, log.clone())?;
+    let mut renderer = unsafe { Gles2Renderer::new(context, log.clone())? };
+    let (version, extensions) = gl_info(&mut renderer)?;
+    let pixel_buffers = version.starts_with("OpenGL ES 3");
+    let pack_row_length =
+        pixel_buffers || extensions.iter().any(|ext| ext == "GL_NV_pack_subimage");
+    let sync = renderer.with_context(|_renderer, _gl| unsafe { SyncSupport::query() })?;
+    slog::debug!(log, "Render gpu synchronization: {:?}", sync);
+
+    Ok(RenderGPU {
+        _device: egl_device,
+        _display: display,
+        renderer,
+        pack_row_length,
+        pixel_buffers,
+        sync,
+        readback: None,
+        blit: None,
+        blend_shader: None,
+        readback_modifiers: HashSet::new(),
+        #[cfg(feature = "vulkan")]
+        vulkan,
+    })
+}
+
+/// Edge length of the buffer `probe_import` allocates
+const PROBE_SIZE: u32 = 4;
+
+/// Checks `renderer` can import a dmabuf allocated on the gpu at `source`, the way captured frames arrive from it.
+///
+/// A tiny linear buffer is used, the layout most likely to work across devices.
+pub fn probe_import(source: &Path, renderer: &mut Gles2Renderer) -> Result<()> {
+    // allocating needs no authentication on the render node
+    let node = render_node(source).ok().flatten().unwrap_or_else(|| source.to_path_buf());
+    let fd = Fd::open(&node).with_context(|| format!("Failed to open {}", node.display()))?;
+    let mut gbm = GbmDevice::new(fd).with_context(|| "Failed to create gbm device")?;
+    let buffer = gbm
+        .create_buffer(PROBE_SIZE, PROBE_SIZE, Fourcc::Argb8888, &[Modifier::Linear])
+        .with_context(|| format!("Failed to allocate a probe buffer on {}", node.display()))?;
+    let dmabuf = buffer.export().with_context(|| "Failed to export the probe buffer")?;
+    renderer
+        .import_dmabuf(&dmabuf)
+        .with_context(|| format!("Failed to import a buffer of {}", node.display()))?;
+    Ok(())
+}
+
+fn find_connector<D: ControlDevice>(
+    device: &D,
+    res_handles: &ResourceHandles,
+    connector: Option<&str>,
+    log: &slog::Logger,
+) -> Result<ConnectorInfo> {
+    let mut connected = res_handles
+        .connectors()
         .iter()
-        .filter_map(|e| *e)
-        .flat_map(|encoder_handle| device.get_encoder(encoder_handle))
-        .flat_map(|encoder_info| res_handles.filter_crtcs(encoder_info.possible_crtcs()))
-        .next()
-        .with_context(|| "Unable to find suitable crtc")?;
+        .map(|conn| device.get_connector(*conn).unwrap())
+        .filter(|conn| conn.state() == ConnectorState::Connected)
+        .collect::<Vec<_>>();
+    let entries = connected
+        .iter()
+        .map(|conn| ConnectorEntry::query(device, conn))
+        .collect::<Vec<_>>();
+    for entry in &entries {
+        slog::info!(log, "Connected: {}", entry);
+    }
+    // Use first connected connector
+    let index = match connector {
+        Some(name) => resolve_connector(&entries, name)?,
+        None => Some(0).filter(|_| !connected.is_empty()),
+    };
+    index
+        .map(|index| connected.swap_remove(index))
+        .with_context(|| "Unable to find connector")
+}
 
+/// Size and refresh rate (in Hz) of the preferred mode of the connector we are going to use
+pub fn preferred_mode(
+    path: &Path,
+    connector: Option<&str>,
+    log: slog::Logger,
+) -> Result<(i32, i32, u32)> {
+    let device = DrmDevice::new(Fd::open(&path)?, false, log.clone())?;
+    let res_handles = device.resource_handles().unwrap();
+    let connector_info = find_connector(&device, &res_handles, connector, &log)?;
     let drm_mode = connector_info
         .modes()
         .iter()
-        .find(|drm_mode| drm_mode.size() == (mode.0 as u16, mode.1 as u16))
-        .cloned()
-        .expect("Output mode not supported by connector");
-    let db = device.create_dumb_buffer((mode.0 as u32, mode.1 as u32), Fourcc::Argb8888, 32)?;
-    let fb = device.add_framebuffer(&db, 24, 32)?;
-    let drm_surface = device.create_surface(crtc, drm_mode, &[connector_info.handle()])?;
-    let plane = drm_surface.plane();
-    drm_surface.commit([&(fb, plane)].iter().cloned(), true)?;
-    std::thread::sleep(Duration::from_secs(1));
-
-    let egl_display = EGLDisplay::new(&egl_device, log.clone())?;
-    let egl_context = EGLContext::new_with_config(
-        &egl_display,
-        GlAttributes {
-            version: (3, 0),
-            profile: None,
-            debug: cfg!(debug_assertions),
-            vsync: false,
-        },
+        .find(|drm_mode| drm_mode.mode_type().contains(ModeTypeFlags::PREFERRED))
+        .or_else(|| connector_info.modes().first())
+        .with_context(|| "Connector does not support any mode")?;
+    let (width, height) = drm_mode.size();
+    Ok((width as i32, height as i32, drm_mode.vrefresh()))
+}
+
+/// How far `size` is from `wanted`, as the sum of the log ratios of their areas and aspect ratios
+fn mode_distance(size: (u16, u16), wanted: (i32, i32)) -> f64 {
+    let (width, height) = (size.0 as f64, size.1 as f64);
+    let (wanted_width, wanted_height) = (wanted.0 as f64, wanted.1 as f64);
+    let area = ((width * height) / (wanted_width * wanted_height)).ln().abs();
+    let aspect = ((width / height) / (wanted_width / wanted_height)).ln().abs();
+    area + aspect
+}
+
+/// The mode of `modes` closest to `wanted` by area and aspect ratio.
+///
+/// Ties go to the mode closest to `refresh` (in Hz), then to the preferred mode.
+pub fn closest_mode(modes: &[Mode], wanted: (i32, i32), refresh: Option<u32>) -> Option<Mode> {
+    // lower is better
+    let tie_break = |drm_mode: &Mode| {
+        (
+            refresh.map(|refresh| (drm_mode.vrefresh() as i64 - refresh as i64).abs()).unwrap_or(0),
+            !drm_mode.mode_type().contains(ModeTypeFlags::PREFERRED),
+        )
+    };
+    let mut best: Option<(f64, &Mode)> = None;
+    for drm_mode in modes {
+        let distance = mode_distance(drm_mode.size(), wanted);
+        let better = match best {
+            None => true,
+            Some((best_distance, _)) if distance < best_distance => true,
+            Some((best_distance, best_mode)) => distance == best_distance && tie_break(drm_mode) < tie_break(best_mode),
+        };
+        if better {
+            best = Some((distance, drm_mode));
+        }
+    }
+    best.map(|(_, drm_mode)| *drm_mode)
+}
+
+/// The mode of `modes` with the highest pixel clock below the one of `current`, ties go to modes of the same size.
+///
+/// Stepping down this ladder lowers the bandwidth the link has to carry, one mode at a time.
+pub fn next_lower_mode(modes: &[Mode], current: &Mode) -> Option<Mode> {
+    modes
+        .iter()
+        .filter(|drm_mode| drm_mode.clock() < current.clock())
+        .max_by_key(|drm_mode| (drm_mode.clock(), drm_mode.size() == current.size()))
+        .copied()
+}
+
+/// The mode of `size` or, unless `strict`, the closest supported one, at the refresh rate closest to `refresh`
+fn select_mode(
+    modes: &[Mode],
+    size: (i32, i32),
+    refresh: Option<u32>,
+    strict: bool,
+    log: &slog::Logger,
+) -> Result<Mode> {
+    let drm_mode = closest_mode(modes, size, refresh);
+    if let Some(drm_mode) = drm_mode.filter(|drm_mode| drm_mode.size() == (size.0 as u16, size.1 as u16)) {
+        return Ok(drm_mode);
+    }
+    if strict {
+        anyhow::bail!("Mode {}x{} not supported by connector", size.0, size.1);
+    }
+    let drm_mode = drm_mode.with_context(|| "Connector does not support any mode")?;
+    slog::warn!(
+        log,
+        "Mode {}x{} not supported by connector, using the closest one by area and aspect ratio: {}x{}, the content is scaled",
+        size.0,
+        size.1,
+        drm_mode.size().0,
+        drm_mode.size().1
+    );
+    Ok(drm_mode)
+}
+
+/// How long `wait_for_scanout` waits for the page flip of a commit, before committing again
+const SCANOUT_TIMEOUT: Duration = Duration::from_millis(500);
+const SCANOUT_ATTEMPTS: u32 = 3;
+
+/// Runs `commit`, which needs to request a page flip event, and blocks until `crtc` reports the flip.
+///
+/// The crtc is actively scanning out afterwards. Without an event in time the commit is retried, if that does not
+/// help either the monitor is assumed to be slow and we go on anyway. Events read meanwhile are consumed, so nothing
+/// else may be waiting for a flip of the crtc.
+pub fn wait_for_scanout<D: ControlDevice>(
+    device: &D,
+    crtc: crtc::Handle,
+    mut commit: impl FnMut() -> Result<()>,
+    log: &slog::Logger,
+) -> Result<()> {
+    for attempt in 1..=SCANOUT_ATTEMPTS {
+        commit()?;
+        let deadline = Instant::now() + SCANOUT_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::ZERO {
+                break;
+            }
+            let mut fds = [PollFd::new(device.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, left.as_millis() as i32) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => return Err(err).context("Failed to wait for the page flip"),
+            }
+            let flipped = device
+                .receive_events()
+                .context("Failed to read drm events")?
+                .any(|event| match event {
+                    Event::PageFlip(event) => event.crtc == crtc,
+                    Event::Vblank(event) => event.crtc == crtc,
+                    _ => false,
+                });
+            if flipped {
+                return Ok(());
+            }
+        }
+        slog::debug!(log, "No page flip within {:?} after commit {}", SCANOUT_TIMEOUT, attempt);
+    }
+    slog::warn!(log, "Crtc did not report scanning out after {} commits, going on anyway", SCANOUT_ATTEMPTS);
+    Ok(())
+}
+
+fn plane_supports(device: &DrmDevice<Fd>, plane: plane::Handle, format: Fourcc) -> bool {
+    device
+        .get_plane(plane)
+        .map(|info| info.formats().contains(&(format as u32)))
+        .unwrap_or(false)
+}
+
+/// Fails if framebuffers of `mode` are larger than the crtcs of the device scan out
+fn check_fb_limits(res_handles: &ResourceHandles, mode: (i32, i32)) -> Result<()> {
+    fn max(range: impl RangeBounds<u32>) -> Option<u32> {
+        match range.end_bound() {
+            Bound::Included(max) => Some(*max),
+            Bound::Excluded(end) => Some(end.saturating_sub(1)),
+            Bound::Unbounded => None,
+        }
+    }
+    // 0 if the driver does not tell
+    let width = max(res_handles.supported_fb_width()).filter(|max| *max > 0);
+    let height = max(res_handles.supported_fb_height()).filter(|max| *max > 0);
+    if let Some(width) = width.filter(|width| mode.0 as u32 > *width) {
+        anyhow::bail!("Mode {}x{} exceeds the maximum framebuffer width of {}", mode.0, mode.1, width);
+    }
+    if let Some(height) = height.filter(|height| mode.1 as u32 > *height) {
+        anyhow::bail!("Mode {}x{} exceeds the maximum framebuffer height of {}", mode.0, mode.1, height);
+    }
+    Ok(())
+}
+
+/// Creates the renderer of the target and the stream surface feeding `plane`.
+///
+/// Drivers list configs the stream producer surface can't be created with, so every suitable one is tried in turn.
+fn create_target_context(
+    display: &EGLDisplay,
+    crtc: crtc::Handle,
+    plane: plane::Handle,
+    mode: (i32, i32),
+    depth: ColorDepth,
+    stream: StreamOptions,
+    log: &slog::Logger,
+) -> Result<(Gles2Renderer, StreamTarget)> {
+    let (configs, candidates) = stream_candidates(&display.get_display_handle(), mode, depth)?;
+
+    let mut last_error = None;
+    for config in candidates {
+        slog::debug!(log, "Trying {}", config);
+        match create_stream_surface(display, crtc, plane, mode, config, stream, log) {
+            Ok((renderer, target, chosen)) => {
+                match configs.iter().find(|config| config.id == chosen) {
+                    Some(chosen) => slog::info!(log, "Rendering with {}", chosen),
+                    None => slog::info!(log, "Rendering with config {}", chosen),
+                }
+                return Ok((renderer, target));
+            }
+            Err(err) => {
+                slog::warn!(log, "Failed to create stream surface with {}: {:#}", config, err);
+                last_error = Some(err);
+            }
+        }
+    }
+    // at least one was tried
+    Err(last_error.unwrap().context("No stream config worked"))
+}
+
+/// Whether any stream config of `display` has the bits per color of `depth`
+fn has_stream_configs(display: &Arc<EGLDisplayHandle>, depth: ColorDepth) -> Result<bool> {
+    let bits = channel_bits(depth);
+    Ok(egl::stream_configs(display)?.iter().any(|config| config.rank(bits).is_some()))
+}
+
+/// All stream configs of `display`, along those suitable for surfaces of `mode` in `depth` by preference.
+///
+/// Fails if none is, naming the limit `mode` exceeds.
+fn stream_candidates(
+    display: &Arc<EGLDisplayHandle>,
+    mode: (i32, i32),
+    depth: ColorDepth,
+) -> Result<(Vec<ConfigAttributes>, Vec<ConfigAttributes>)> {
+    let bits = channel_bits(depth);
+    let configs = egl::stream_configs(display)?;
+    let mut candidates = configs
+        .iter()
+        .filter_map(|config| config.rank(bits).map(|rank| (rank, *config)))
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(rank, _)| *rank);
+    if candidates.is_empty() {
+        anyhow::bail!("None of the {} stream configs has {} bits per color", configs.len(), bits);
+    }
+    // the driver only fails creating the surface, without telling why
+    let (max_width, max_height) = candidates
+        .iter()
+        .map(|(_, config)| (config.max_width, config.max_height))
+        .fold((0, 0), |(w, h), (max_w, max_h)| (w.max(max_w), h.max(max_h)));
+    if max_width > 0 && mode.0 > max_width {
+        anyhow::bail!(
+            "Mode {}x{} exceeds the maximum surface width of {}, EGL_MAX_PBUFFER_WIDTH of the stream configs",
+            mode.0,
+            mode.1,
+            max_width
+        );
+    }
+    if max_height > 0 && mode.1 > max_height {
+        anyhow::bail!(
+            "Mode {}x{} exceeds the maximum surface height of {}, EGL_MAX_PBUFFER_HEIGHT of the stream configs",
+            mode.0,
+            mode.1,
+            max_height
+        );
+    }
+    let candidates = candidates.into_iter().map(|(_, config)| config).collect();
+    Ok((configs, candidates))
+}
+
+/// A context and stream surface matching `config`, along the id of the config EGL picked for it
+fn create_stream_surface(
+    display: &EGLDisplay,
+    crtc: crtc::Handle,
+    plane: plane::Handle,
+    mode: (i32, i32),
+    config: ConfigAttributes,
+    stream: StreamOptions,
+    log: &slog::Logger,
+) -> Result<(Gles2Renderer, StreamTarget, i32)> {
+    // smithay offers no way to request EGL_EXT_create_context_robustness,
+    // so resets are detected by failing renders as well, see `render::render_failed`
+    let egl_context = create_context(
+        display,
         PixelFormatRequirements {
             hardware_accelerated: Some(true),
-            color_bits: Some(3),
-            alpha_bits: Some(0),
-            depth_bits: Some(1),
+            color_bits: Some((config.red + config.green + config.blue) as u8),
+            alpha_bits: Some(config.alpha as u8),
+            depth_bits: Some(config.depth as u8),
+            stencil_bits: Some(config.stencil as u8),
+            multisampling: if config.samples > 0 { Some(config.samples as u16) } else { None },
             ..Default::default()
         },
-        log.clone(),
+        log,
     )?;
-    let surface = EglStreamSurface::new(crtc, plane, mode, log.clone());
-    let egl_surface = Rc::new(EGLSurface::new(
-        &egl_display,
+    let chosen = egl_context.config_id() as i32;
+    let target = StreamTarget::new(
+        display,
         egl_context.pixel_format().unwrap(),
         egl_context.config_id(),
-        surface,
-        log.clone(),
-    )?);
+        crtc,
+        plane,
+        mode,
+        stream,
+        log,
+    )?;
     let renderer = unsafe { Gles2Renderer::new(egl_context, log.clone())? };
+    Ok((renderer, target, chosen))
+}
+
+/// Stream surface of a target context, along the slots its native surface reports through
+struct StreamTarget {
+    surface: Rc<EGLSurface>,
+    swap_error: SwapErrorSlot,
+    pending_acquire: AcquireSlot,
+    /// Empty while the surface needs to be recreated
+    stream: StreamSlot,
+    /// Of the context, which a recreated surface needs to match
+    pixel_format: PixelFormat,
+    config_id: egl::ffi::types::EGLConfig,
+}
+
+impl StreamTarget {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        display: &EGLDisplay,
+        pixel_format: PixelFormat,
+        config_id: egl::ffi::types::EGLConfig,
+        crtc: crtc::Handle,
+        plane: plane::Handle,
+        mode: (i32, i32),
+        stream: StreamOptions,
+        log: &slog::Logger,
+    ) -> Result<StreamTarget> {
+        let native = EglStreamSurface::new(crtc, plane, mode, stream, log.clone());
+        let swap_error = native.last_error();
+        let pending_acquire = native.pending_acquire();
+        let stream = native.stream();
+        let surface = Rc::new(EGLSurface::new(display, pixel_format, config_id, native, log.clone())?);
+        Ok(StreamTarget {
+            surface,
+            swap_error,
+            pending_acquire,
+            stream,
+            pixel_format,
+            config_id,
+        })
+    }
+}
+
+/// Creates the renderer of a gbm target, which renders into buffers instead of a surface
+fn create_gbm_context(display: &EGLDisplay, log: &slog::Logger) -> Result<Gles2Renderer> {
+    let egl_context = create_context(display, PixelFormatRequirements::default(), log)?;
+    Ok(unsafe { Gles2Renderer::new(egl_context, log.clone())? })
+}
+
+/// Synchronization and capabilities of a new target context, with the debug callback of `--gl-debug` installed
+fn setup_context(
+    renderer: &mut Gles2Renderer,
+    gl_debug: bool,
+    log: &slog::Logger,
+) -> Result<(SyncSupport, GlCapabilities)> {
+    let sync = renderer.with_context(|_renderer, _gl| unsafe { SyncSupport::query() })?;
+    slog::debug!(log, "Target gpu synchronization: {:?}", sync);
+    let caps = GlCapabilities::query(renderer)?;
+    slog::info!(log, "Target context: {}", caps);
+    if gl_debug {
+        if caps.khr_debug {
+            renderer.with_context(|_renderer, gl| unsafe { gl_debug::install(gl, log) })??;
+        } else {
+            slog::warn!(log, "Target context lacks GL_KHR_debug, --gl-debug has no effect");
+        }
+    }
+    Ok((sync, caps))
+}
+
+/// GLES versions requested for target contexts, by preference
+const GL_VERSIONS: [(u8, u8); 2] = [(3, 0), (2, 0)];
+
+/// A context of the newest version in `GL_VERSIONS` the driver creates, see `GlCapabilities` for what it offers
+fn create_context(
+    display: &EGLDisplay,
+    pixel_format: PixelFormatRequirements,
+    log: &slog::Logger,
+) -> Result<EGLContext> {
+    let mut last_error = None;
+    for version in GL_VERSIONS {
+        let attributes = GlAttributes {
+            version,
+            profile: None,
+            debug: cfg!(debug_assertions),
+            vsync: false,
+        };
+        match EGLContext::new_with_config(display, attributes, pixel_format, log.clone()) {
+            Ok(context) => {
+                if last_error.is_some() {
+                    slog::info!(log, "Falling back to a GLES {}.{} context", version.0, version.1);
+                }
+                return Ok(context);
+            }
+            Err(err) => {
+                slog::debug!(log, "Failed to create a GLES {}.{} context: {}", version.0, version.1, err);
+                last_error = Some(err);
+            }
+        }
+    }
+    // at least one was tried
+    Err(last_error.unwrap()).context("Failed to create a GLES 2.0 context")
+}
+
+/// Formats the placeholder dumb buffer is tried in, by preference
+const DUMB_FORMATS: [Fourcc; 4] = [Fourcc::Xrgb2101010, Fourcc::Xrgb8888, Fourcc::Argb8888, Fourcc::Rgb565];
+
+/// Formats to try for the dumb buffer of a plane supporting `supported`.
+///
+/// 10 bit is only tried for `ColorDepth::Ten`, formats the plane lists come first
+/// and the rest follows, in case the driver lists incomplete formats.
+fn dumb_buffer_formats(depth: ColorDepth, supported: &[u32]) -> Vec<Fourcc> {
+    let candidates = DUMB_FORMATS
+        .iter()
+        .copied()
+        .filter(|format| depth == ColorDepth::Ten || *format != Fourcc::Xrgb2101010);
+    let (listed, unlisted): (Vec<Fourcc>, Vec<Fourcc>) =
+        candidates.partition(|format| supported.contains(&(*format as u32)));
+    listed.into_iter().chain(unlisted).collect()
+}
+
+/// Depth and bits per pixel the legacy AddFB ioctl maps to `format`
+fn legacy_depth(format: Fourcc) -> Option<(u32, u32)> {
+    match format {
+        Fourcc::Xrgb8888 => Some((24, 32)),
+        Fourcc::Argb8888 => Some((32, 32)),
+        Fourcc::Xrgb2101010 => Some((30, 32)),
+        Fourcc::Rgb565 => Some((16, 16)),
+        _ => None,
+    }
+}
+
+/// A dumb buffer as single plane buffer, to add its framebuffer with an explicit format through AddFB2
+struct DumbPlanes<'a>(&'a DumbBuffer);
+
+impl PlanarBuffer for DumbPlanes<'_> {
+    fn size(&self) -> (u32, u32) {
+        Buffer::size(self.0)
+    }
+
+    fn format(&self) -> Fourcc {
+        Buffer::format(self.0)
+    }
+
+    fn pitches(&self) -> [u32; 4] {
+        [Buffer::pitch(self.0), 0, 0, 0]
+    }
+
+    fn handles(&self) -> [Option<BufferHandle>; 4] {
+        [Some(Buffer::handle(self.0)), None, None, None]
+    }
+
+    fn offsets(&self) -> [u32; 4] {
+        [0; 4]
+    }
+}
+
+/// Creates the placeholder dumb buffer of `size` and a framebuffer for it, in the first format the plane accepts
+fn create_dumb_framebuffer<D: ControlDevice>(
+    device: &D,
+    plane: plane::Handle,
+    size: (i32, i32),
+    depth: ColorDepth,
+    log: &slog::Logger,
+) -> Result<(DumbBuffer, framebuffer::Handle)> {
+    let supported = device
+        .get_plane(plane)
+        .map(|info| info.formats().to_vec())
+        .unwrap_or_default();
+    for format in dumb_buffer_formats(depth, &supported) {
+        let bpp = legacy_depth(format).map(|(_, bpp)| bpp).unwrap_or(32);
+        let db = match device.create_dumb_buffer((size.0 as u32, size.1 as u32), format, bpp) {
+            Ok(db) => db,
+            Err(err) => {
+                slog::debug!(log, "Failed to create {:?} dumb buffer: {}", format, err);
+                continue;
+            }
+        };
+        // AddFB2 takes the format as is, drivers without it only know depth and bpp
+        let fb = device
+            .add_planar_framebuffer(&DumbPlanes(&db), &[None, None, None, None], 0)
+            .or_else(|err| match legacy_depth(format) {
+                Some((depth, bpp)) => device.add_framebuffer(&db, depth, bpp),
+                None => Err(err),
+            });
+        match fb {
+            Ok(fb) => {
+                slog::debug!(log, "Dumb buffer format: {:?}", format);
+                return Ok((db, fb));
+            }
+            Err(err) => {
+                slog::debug!(log, "Failed to add {:?} framebuffer: {}", format, err);
+                let _ = device.destroy_dumb_buffer(db);
+            }
+        }
+    }
+    anyhow::bail!("Plane accepts none of the dumb buffer formats {:?}", DUMB_FORMATS)
+}
+
+/// Copies `frame` into `db` scaled to `size`, converting it to the format of the dumb buffer.
+///
+/// The rest of the dumb buffer is left alone, parts of `size` it can't hold are cut off.
+fn fill_dumb_buffer<D: ControlDevice>(
+    device: &D,
+    db: &mut DumbBuffer,
+    frame: &FrozenFrame,
+    size: (i32, i32),
+) -> Result<()> {
+    let (width, height) = Buffer::size(db);
+    let (format, pitch) = (Buffer::format(db), Buffer::pitch(db) as usize);
+    let bytes = if format == Fourcc::Rgb565 { 2 } else { 4 };
+    let (columns, rows) = ((size.0 as u32).min(width) as usize, (size.1 as u32).min(height) as usize);
+    let mut mapping = device.map_dumb_buffer(db).context("Failed to map dumb buffer")?;
+    let mapping = mapping.as_mut();
+    for y in 0..rows {
+        let src_y = y * frame.size.1 as usize / size.1 as usize;
+        let row = &mut mapping[y * pitch..y * pitch + columns * bytes];
+        for (x, pixel) in row.chunks_exact_mut(bytes).enumerate() {
+            let src_x = x * frame.size.0 as usize / size.0 as usize;
+            let at = (src_y * frame.size.0 as usize + src_x) * 4;
+            let (r, g, b) = (frame.pixels[at] as u32, frame.pixels[at + 1] as u32, frame.pixels[at + 2] as u32);
+            match format {
+                Fourcc::Xrgb2101010 => pixel.copy_from_slice(&(r << 22 | g << 12 | b << 2).to_le_bytes()),
+                Fourcc::Rgb565 => pixel.copy_from_slice(&((r >> 3) << 11 | (g >> 2) << 5 | b >> 3).to_le_bytes()[..2]),
+                _ => pixel.copy_from_slice(&[b as u8, g as u8, r as u8, 0xff]),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bits per color channel of the config of the stream surface
+fn channel_bits(depth: ColorDepth) -> i32 {
+    match depth {
+        ColorDepth::Eight => 8,
+        ColorDepth::Ten => 10,
+    }
+}
+
+/// Udev events of all gpus and the device number of the one at `path`,
+/// to follow monitors being plugged into it.
+///
+/// Events are taken from the seat the gpu is assigned to, which is `seat` unless it was found on another one.
+pub fn hotplug_events(path: &Path, seat: &str, log: slog::Logger) -> Result<(UdevBackend, nix::libc::dev_t)> {
+    let device = nix::sys::stat::stat(path)
+        .with_context(|| format!("Failed to stat {}", path.display()))?
+        .st_rdev;
+    let seat = seat_of_device(gpus_of_all_seats()?, device, seat);
+    Ok((UdevBackend::new(seat, log)?, device))
+}
+
+/// Seat of the gpu with the device number `device`, `seat` if it is none of `gpus`
+fn seat_of_device(gpus: Vec<(nix::libc::dev_t, PathBuf, String)>, device: nix::libc::dev_t, seat: &str) -> String {
+    gpus.into_iter()
+        .find(|(dev, _, _)| *dev == device)
+        .map(|(_, _, seat)| seat)
+        .unwrap_or_else(|| seat.to_string())
+}
+
+/// Render node of the drm device at `path`, which needs neither drm master nor authentication.
+///
+/// `None` if the device has none, e.g. with drivers that only do modesetting.
+pub fn render_node(path: &Path) -> Result<Option<PathBuf>> {
+    let device = nix::sys::stat::stat(path)
+        .with_context(|| format!("Failed to stat {}", path.display()))?
+        .st_rdev;
+    render_node_of(Path::new("/sys"), device)
+}
+
+/// Render node of the drm device `device` belongs to, looked up in `sysfs`
+fn render_node_of(sysfs: &Path, device: nix::libc::dev_t) -> Result<Option<PathBuf>> {
+    Ok(DeviceNodes::find(sysfs, device)?.render.map(PathBuf::from))
+}
+
+/// Kernel driver of the gpu at `path`
+pub fn gpu_driver(path: &Path) -> Result<String> {
+    let device = nix::sys::stat::stat(path)
+        .with_context(|| format!("Failed to stat {}", path.display()))?
+        .st_rdev;
+    driver(device)?
+        .and_then(|driver| driver.into_string().ok())
+        .with_context(|| format!("No driver bound to {}", path.display()))
+}
+
+nix::ioctl_none!(drm_set_master, b'd', 0x1e);
+nix::ioctl_none!(drm_drop_master, b'd', 0x1f);
+
+/// Why we could not become drm master of the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MasterError {
+    /// Another process, most likely a compositor, is master of the device
+    Busy,
+    /// Only the first process opening the device or one with CAP_SYS_ADMIN may become master
+    PermissionDenied,
+    Other(Errno),
+}
+
+impl MasterError {
+    pub fn classify(errno: Errno) -> MasterError {
+        match errno {
+            // older kernels report EINVAL, if another fd is master
+            Errno::EBUSY | Errno::EINVAL => MasterError::Busy,
+            Errno::EACCES | Errno::EPERM => MasterError::PermissionDenied,
+            errno => MasterError::Other(errno),
+        }
+    }
+
+    /// Explanation including what the user can do about it
+    pub fn describe(&self, path: &Path) -> String {
+        match self {
+            MasterError::Busy => format!(
+                "{} is already driven by another process, most likely your compositor. \
+                 Limit it to the other gpus, e.g. with WLR_DRM_DEVICES for wlroots based compositors.",
+                path.display()
+            ),
+            MasterError::PermissionDenied => format!(
+                "Not allowed to modeset on {}. Run nvscreencopy from a session logind granted the seat to, \
+                 or make sure no other process opened the device first.",
+                path.display()
+            ),
+            MasterError::Other(errno) => format!("Failed to become drm master of {}: {}", path.display(), errno),
+        }
+    }
+}
+
+/// Makes sure we may modeset on the device, the render gpu does not need this
+pub fn acquire_master(fd: &Fd, path: &Path) -> Result<()> {
+    match unsafe { drm_set_master(fd.as_raw_fd()) } {
+        Ok(_) => Ok(()),
+        Err(nix::Error::Sys(errno)) => anyhow::bail!(MasterError::classify(errno).describe(path)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Crtcs the target connector could use and which of them drive other outputs right now
+pub struct CrtcTopology {
+    /// Crtcs reachable from the encoders of the target connector, the one it currently uses first
+    pub candidates: Vec<crtc::Handle>,
+    /// Active crtcs driving connected connectors other than the target, with the names of those
+    pub in_use: Vec<(crtc::Handle, String)>,
+}
+
+impl CrtcTopology {
+    fn query<D: ControlDevice>(device: &D, res_handles: &ResourceHandles, target: &ConnectorInfo) -> Result<CrtcTopology> {
+        let current_crtc = |conn: &ConnectorInfo| {
+            conn.current_encoder()
+                .and_then(|encoder| device.get_encoder(encoder).ok())
+                .and_then(|encoder| encoder.crtc())
+        };
+
+        let mut candidates = current_crtc(target).into_iter().collect::<Vec<_>>();
+        for encoder in target.encoders().iter().filter_map(|e| *e) {
+            let info: encoder::Info = device.get_encoder(encoder)?;
+            for crtc in res_handles.filter_crtcs(info.possible_crtcs()) {
+                if !candidates.contains(&crtc) {
+                    candidates.push(crtc);
+                }
+            }
+        }
+
+        let mut in_use = Vec::new();
+        for conn in res_handles.connectors() {
+            if *conn == target.handle() {
+                continue;
+            }
+            let info = device.get_connector(*conn)?;
+            if info.state() != ConnectorState::Connected {
+                continue;
+            }
+            if let Some(crtc) = current_crtc(&info) {
+                if device.get_crtc(crtc)?.mode().is_some() {
+                    in_use.push((crtc, connector_name(&info)));
+                }
+            }
+        }
+        Ok(CrtcTopology { candidates, in_use })
+    }
+}
+
+/// Picks the crtc of the target, one not driving another output unless `allow_steal` is set.
+///
+/// Returns the crtc and the name of the output it is taken from, if any.
+pub fn select_crtc(topology: &CrtcTopology, allow_steal: bool) -> Result<(crtc::Handle, Option<String>)> {
+    let owner = |crtc: crtc::Handle| {
+        topology
+            .in_use
+            .iter()
+            .find(|(used, _)| *used == crtc)
+            .map(|(_, name)| name.clone())
+    };
+    if let Some(crtc) = topology.candidates.iter().find(|crtc| owner(**crtc).is_none()) {
+        return Ok((*crtc, None));
+    }
+    let crtc = *topology
+        .candidates
+        .first()
+        .with_context(|| "Unable to find suitable crtc")?;
+    // every candidate has an owner at this point
+    let output = owner(crtc).unwrap();
+    if !allow_steal {
+        let outputs = topology
+            .candidates
+            .iter()
+            .flat_map(|crtc| owner(*crtc))
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "Every crtc usable by the connector drives another output ({}), pass --allow-crtc-steal to take over the one of {}",
+            outputs.join(", "),
+            output
+        );
+    }
+    Ok((crtc, Some(output)))
+}
+
+/// Attaching the EGLStream to the output layer failed, e.g. as a crashed run left its framebuffer on the plane
+#[derive(Debug)]
+struct StreamRefused;
+
+impl fmt::Display for StreamRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The EGLStream did not attach to the output")
+    }
+}
+
+/// Why initializing the target failed, as far as cleaning up after a crashed run might help
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitFailure {
+    /// The kernel reported EBUSY, e.g. for the framebuffer still on the crtc
+    Busy,
+    /// See `StreamRefused`
+    StreamRefused,
+    /// Nothing a cleanup would fix
+    Other,
+}
+
+impl InitFailure {
+    pub fn classify(err: &anyhow::Error) -> InitFailure {
+        if err.downcast_ref::<StreamRefused>().is_some() {
+            return InitFailure::StreamRefused;
+        }
+        let busy = err.chain().any(|cause| {
+            cause.downcast_ref::<Errno>() == Some(&Errno::EBUSY)
+                || cause.downcast_ref::<nix::Error>() == Some(&nix::Error::Sys(Errno::EBUSY))
+                || cause.downcast_ref::<std::io::Error>().and_then(std::io::Error::raw_os_error)
+                    == Some(Errno::EBUSY as i32)
+                // the drm crate only keeps the errno in its message
+                || cause.to_string().contains(Errno::EBUSY.desc())
+        });
+        if busy {
+            InitFailure::Busy
+        } else {
+            InitFailure::Other
+        }
+    }
+}
+
+impl fmt::Display for InitFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitFailure::Busy => write!(f, "device busy"),
+            InitFailure::StreamRefused => write!(f, "stream refused"),
+            InitFailure::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Cleanups tried before giving up on initializing the target
+const MAX_RECOVERY_ATTEMPTS: u32 = 1;
+
+/// What `TargetGPU::initialize_with_recovery` does after a failed initialization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Clean up the crtc and try again
+    CleanUp,
+    GiveUp,
+}
+
+/// Decides how to go on after `attempt` cleanups were tried already and initializing failed with `failure`
+pub fn recovery(attempt: u32, failure: InitFailure) -> Recovery {
+    match failure {
+        InitFailure::Other => Recovery::GiveUp,
+        _ if attempt >= MAX_RECOVERY_ATTEMPTS => Recovery::GiveUp,
+        InitFailure::Busy | InitFailure::StreamRefused => Recovery::CleanUp,
+    }
+}
+
+/// Calls `init` until it succeeds, with `clean_up` in between as long as `recovery` decides to try that
+fn retry_with_recovery<T>(
+    mut init: impl FnMut() -> Result<T>,
+    mut clean_up: impl FnMut() -> Result<()>,
+    log: &slog::Logger,
+) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        let err = match init() {
+            Ok(initialized) => return Ok(initialized),
+            Err(err) => err,
+        };
+        let failure = InitFailure::classify(&err);
+        match recovery(attempt, failure) {
+            Recovery::GiveUp => return Err(err),
+            Recovery::CleanUp => {
+                slog::warn!(
+                    log,
+                    "Failed to initialize the output ({}): {:#}, cleaning up what a previous run left behind",
+                    failure,
+                    err
+                );
+                if let Err(cleanup) = clean_up() {
+                    slog::warn!(log, "Cleanup failed: {:#}", cleanup);
+                    return Err(err);
+                }
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Turns off the crtc of the target connector, removes the framebuffer left on it and becomes drm master anew
+fn clean_up_target(fd: &Fd, options: &TargetOptions, log: &slog::Logger) -> Result<()> {
+    let res_handles = fd.resource_handles()?;
+    let connector_info = find_connector(fd, &res_handles, options.connector.as_deref(), log)?;
+    let crtc = connector_info
+        .current_encoder()
+        .and_then(|encoder| fd.get_encoder(encoder).ok())
+        .and_then(|encoder| encoder.crtc());
+    match crtc {
+        Some(crtc) => {
+            let fb = fd.get_crtc(crtc)?.framebuffer();
+            let atomic = !options.legacy_modesetting;
+            match kms::disable_crtc(fd, connector_info.handle(), crtc, atomic) {
+                Ok(()) => slog::info!(log, "Cleanup: disabled crtc {}", u32::from(crtc)),
+                Err(err) => slog::warn!(log, "Cleanup: failed to disable crtc {}: {:#}", u32::from(crtc), err),
+            }
+            if let Some(fb) = fb {
+                match fd.destroy_framebuffer(fb) {
+                    Ok(()) => slog::info!(log, "Cleanup: removed framebuffer {}", u32::from(fb)),
+                    // only our own can be removed, those of exited processes are gone already
+                    Err(err) => slog::info!(log, "Cleanup: framebuffer {} is not ours: {}", u32::from(fb), err),
+                }
+            }
+        }
+        None => slog::info!(log, "Cleanup: connector drives no crtc"),
+    }
+    match unsafe { drm_drop_master(fd.as_raw_fd()) } {
+        Ok(_) => slog::info!(log, "Cleanup: dropped drm master"),
+        Err(err) => slog::info!(log, "Cleanup: failed to drop drm master: {}", err),
+    }
+    unsafe { drm_set_master(fd.as_raw_fd()) }.context("Failed to become drm master again")?;
+    slog::info!(log, "Cleanup: became drm master again");
+    Ok(())
+}
+
+/// How the target is set up, see `init_target_gpu`
+#[derive(Debug, Clone)]
+pub struct TargetOptions {
+    /// Needs to be resolved already, see `TargetBackendKind::resolve`
+    pub backend: TargetBackendKind,
+    /// Connector to scan out on, the first connected one if `None`
+    pub connector: Option<String>,
+    /// Mode used whenever the monitor supports it
+    pub mode: (i32, i32),
+    /// Mode from `--modeline`, used instead of the modes of the monitor
+    pub modeline: Option<Mode>,
+    /// Refresh rate of the source in Hz, which picks among the modes of the same size
+    pub refresh: Option<u32>,
+    /// Fail instead of using the closest mode, if the monitor lacks `mode`
+    pub strict_mode: bool,
+    /// Take over a crtc driving another output, if no free one is left
+    pub allow_crtc_steal: bool,
+    pub depth: ColorDepth,
+    pub stream: StreamOptions,
+    /// Use the legacy drm api even if the device supports atomic modesetting
+    pub legacy_modesetting: bool,
+    /// Enable variable refresh rate, if the monitor supports it
+    pub vrr: bool,
+    /// Properties set on the connector before the first commit
+    pub connector_props: Vec<PropertyAssignment>,
+    /// Value of the "Colorspace" property of the connector, by name
+    pub colorspace: Option<String>,
+    /// Sent to the monitor through "HDR_OUTPUT_METADATA", needs 10 bit scanout just like `colorspace`
+    pub hdr_metadata: Option<HdrMetadataSource>,
+    /// Loaded into the crtc instead of its gamma table, which is restored on exit
+    pub gamma_lut: Option<GammaLut>,
+    /// Rotation and mirroring of the output, done by the plane if it supports it
+    pub transform: Transform,
+    /// Size of the content to let the plane scale to the mode, instead of rendering it scaled
+    pub plane_scaling: Option<(i32, i32)>,
+    /// Plane to bind the stream to, `None` takes the primary plane smithay picked
+    pub plane: Option<PlaneSelection>,
+    /// Route the KHR_debug messages of the context into the log
+    pub gl_debug: bool,
+}
+
+/// Planes of the gpu behind `fd`, along the crtc `connector` would be driven by
+pub fn list_planes(
+    fd: Fd,
+    connector: Option<&str>,
+    allow_crtc_steal: bool,
+    log: &slog::Logger,
+) -> Result<(crtc::Handle, Vec<PlaneEntry>)> {
+    let device = DrmDevice::new(fd.clone(), false, log.clone())?;
+    let res_handles = device.resource_handles()?;
+    let connector_info = find_connector(&device, &res_handles, connector, log)?;
+    let topology = CrtcTopology::query(&device, &res_handles, &connector_info)?;
+    let (crtc, _) = select_crtc(&topology, allow_crtc_steal)?;
+    Ok((crtc, PlaneEntry::query_all(&fd)?))
+}
+
+/// Sets up a target rendering into a framebuffer of `mode` on the gpu behind `fd`, which is never shown.
+///
+/// Frames go through the same copy paths and rendering as with a monitor, `TargetGPU::dump_png` shows the result.
+pub fn init_offscreen_target(
+    fd: Fd,
+    mode: (i32, i32),
+    depth: ColorDepth,
+    transform: Transform,
+    gl_debug: bool,
+    log: slog::Logger,
+) -> Result<TargetGPU> {
+    let (backend, mut renderer) = OffscreenBackend::new(fd, mode, depth, &log)?;
+    let (sync, gl) = setup_context(&mut renderer, gl_debug, &log)?;
+    slog::info!(log, "Rendering offscreen in {}x{}", mode.0, mode.1);
+    Ok(TargetGPU {
+        renderer,
+        backend: Box::new(backend),
+        sync,
+        depth,
+        upload_fence: None,
+        swap_interval: 1,
+        mode,
+        transform,
+        output_transform: transform,
+        vrr: false,
+        connector_name: String::from("offscreen"),
+        gl,
+        gl_debug,
+        kms: None,
+    })
+}
+
+/// Sets up scanout on the connector of `options`.
+///
+/// Falls back to 8 bit, if the plane can't scan out 10 bit buffers or gbm is used.
+/// What `init_target_gpu` sets up on the device, found without changing any of its state
+pub struct TargetPlan {
+    pub connector: ConnectorInfo,
+    pub crtc: crtc::Handle,
+    /// Output whose crtc is taken over, which goes dark
+    pub stolen: Option<String>,
+    pub drm_mode: Mode,
+    /// `None` for the primary plane of the crtc, which the surface picks on its own
+    pub plane: Option<plane::Handle>,
+}
+
+impl TargetPlan {
+    pub fn mode(&self) -> (i32, i32) {
+        (self.drm_mode.size().0 as i32, self.drm_mode.size().1 as i32)
+    }
+}
+
+/// Picks connector, crtc, mode and plane of the target, checking everything that can be checked without a modeset.
+///
+/// This is the first half of `init_target_gpu`, `--dry-run` stops after it.
+pub fn plan_target(device: &DrmDevice<Fd>, fd: &Fd, options: &TargetOptions, log: &slog::Logger) -> Result<TargetPlan> {
+    // Get a set of all modesetting resource handles (excluding planes):
+    let res_handles = device.resource_handles()?;
+
+    let connector = find_connector(device, &res_handles, options.connector.as_deref(), log)?;
+
+    let topology = CrtcTopology::query(device, &res_handles, &connector)?;
+    let (crtc, stolen) = select_crtc(&topology, options.allow_crtc_steal)?;
+
+    let drm_mode = match options.modeline {
+        Some(modeline) => {
+            // the monitor is trusted to handle it, even if its EDID claims otherwise
+            slog::info!(log, "Using modeline {:?}", modeline);
+            modeline
+        }
+        None => select_mode(connector.modes(), options.mode, options.refresh, options.strict_mode, log)?,
+    };
+    check_fb_limits(&res_handles, (drm_mode.size().0 as i32, drm_mode.size().1 as i32))?;
+    let plane = match options.plane {
+        None => None,
+        Some(_) if options.backend == TargetBackendKind::Gbm => {
+            anyhow::bail!("--plane needs the eglstream backend, gbm always scans out on the primary plane")
+        }
+        Some(selection) => {
+            let planes = PlaneEntry::query_all(fd)?;
+            Some(kms::select_plane(&planes, selection, crtc)?)
+        }
+    };
+    Ok(TargetPlan {
+        connector,
+        crtc,
+        stolen,
+        drm_mode,
+        plane,
+    })
+}
+
+/// Checks the EGL device of `fd` offers stream surfaces of `mode`, returning the config a target would try first.
+///
+/// Nothing is scanned out, so the driver may still refuse the surface once it is created for real.
+pub fn probe_stream(fd: Fd, mode: (i32, i32), depth: ColorDepth, log: &slog::Logger) -> Result<ConfigAttributes> {
+    let egl_device = EGLDeviceEXT::new(fd, log.clone())?;
+    let display = EGLDisplay::new(&egl_device, log.clone())?;
+    let handle = display.get_display_handle();
+    let missing = egl::missing_stream_extensions(&handle);
+    if !missing.is_empty() {
+        anyhow::bail!("The EGL display lacks {}", missing.join(", "));
+    }
+    let (_, candidates) = stream_candidates(&handle, mode, depth)?;
+    // not empty, or it would have failed
+    Ok(candidates[0])
+}
+
+pub fn init_target_gpu(fd: Fd, options: &TargetOptions, log: slog::Logger) -> Result<(TargetGPU, DrmDevice<Fd>)> {
+    if options.legacy_modesetting {
+        // smithay negotiates atomic modesetting on its own, unless told otherwise
+        std::env::set_var("SMITHAY_USE_LEGACY", "1");
+    }
+    let device = DrmDevice::new(fd.clone(), false, log.clone())?;
+    let plan = plan_target(&device, &fd, options, &log)?;
+    if let Some(output) = plan.stolen.as_ref() {
+        slog::error!(log, "Taking over the crtc of {}, which will go dark", output);
+    }
+    let (connector_info, crtc, drm_mode, mode) = (plan.connector, plan.crtc, plan.drm_mode, plan.mode());
+
+    let drm_surface = device.create_surface(crtc, drm_mode, &[connector_info.handle()])?;
+    let plane = match plan.plane {
+        None => drm_surface.plane(),
+        Some(plane) => {
+            if plane != drm_surface.plane() && drm_surface.is_legacy() {
+                anyhow::bail!("--plane needs atomic modesetting for planes other than the primary one");
+            }
+            slog::info!(log, "Binding the stream to plane {}", u32::from(plane));
+            plane
+        }
+    };
+    let props = PropertyCache::new(fd.clone(), connector_info.handle(), crtc, !drm_surface.is_legacy())?;
+    slog::info!(
+        log,
+        "Modesetting: {}",
+        if props.is_atomic() { "atomic" } else { "legacy" }
+    );
+    for assignment in &options.connector_props {
+        props
+            .assign(assignment)
+            .with_context(|| format!("Failed to set connector property {}", assignment.name))?;
+        slog::info!(log, "Set connector property {} to {}", assignment.name, assignment.value);
+    }
+    if options.colorspace.is_some() || options.hdr_metadata.is_some() {
+        // the content is passed through as is, so it has to reach the monitor with all of its bits
+        if !props.is_atomic() {
+            anyhow::bail!("--colorspace and --hdr-metadata need atomic modesetting");
+        }
+        if options.depth != ColorDepth::Ten {
+            anyhow::bail!("--colorspace and --hdr-metadata need 10 bit scanout, see --color-depth");
+        }
+        if options.backend == TargetBackendKind::Gbm {
+            anyhow::bail!("--colorspace and --hdr-metadata need 10 bit scanout, which the gbm backend can't do");
+        }
+        if !plane_supports(&device, plane, Fourcc::Xrgb2101010) {
+            anyhow::bail!("--colorspace and --hdr-metadata need 10 bit scanout, but the plane does not support XRGB2101010");
+        }
+    }
+    if let Some(colorspace) = options.colorspace.as_ref() {
+        props
+            .assign(&PropertyAssignment {
+                name: String::from("Colorspace"),
+                value: colorspace.clone(),
+            })
+            .context("Failed to set the colorspace")?;
+        slog::info!(log, "Set colorspace to {}", colorspace);
+    }
+    if let Some(source) = options.hdr_metadata.as_ref() {
+        let metadata = source.load()?;
+        props
+            .set_connector_blob("HDR_OUTPUT_METADATA", &metadata.encode()?)
+            .context("Failed to send HDR metadata, the driver may not support HDR on this connector")?;
+        slog::info!(log, "Sending HDR metadata: {:?}", metadata);
+    }
+    let restore_gamma = match options.gamma_lut.as_ref() {
+        Some(lut) => {
+            let size = props.gamma_size()?;
+            let resampled = lut.for_crtc(size)?;
+            let previous = props.gamma().context("Failed to read the gamma table of the crtc")?;
+            props
+                .set_gamma(Some(&resampled))
+                .context("Failed to load the gamma table into the crtc")?;
+            slog::info!(
+                log,
+                "Loaded gamma table of {} entries into the crtc ({} entries, {})",
+                lut.len(),
+                size,
+                if props.atomic_gamma() { "GAMMA_LUT" } else { "legacy gamma ramp" }
+            );
+            Some(previous)
+        }
+        None => None,
+    };
+    // monitors left in standby by a previous user sometimes stay black after the modeset
+    match props.set_dpms(Dpms::On).and_then(|_| props.wait_for_link()) {
+        Ok(true) => {}
+        Ok(false) => slog::warn!(log, "Link of the connector did not come up, trying anyway"),
+        Err(err) => slog::warn!(log, "Failed to power on the connector: {}", err),
+    }
+    let vrr = options.vrr
+        && match props.vrr_capable().and_then(|capable| {
+            if capable {
+                props.set_vrr(true)?;
+            }
+            Ok(capable)
+        }) {
+            Ok(true) => {
+                slog::info!(log, "Variable refresh rate enabled");
+                true
+            }
+            Ok(false) => {
+                slog::warn!(log, "Monitor or driver does not support variable refresh rate, using fixed vblanks");
+                false
+            }
+            Err(err) => {
+                slog::warn!(log, "Failed to enable variable refresh rate: {}", err);
+                false
+            }
+        };
+
+    // gbm buffers always have the size of the mode, so only the stream can be rotated by the plane
+    let rotation = match options.transform {
+        Transform::Normal => None,
+        _ if !props.is_atomic() || options.backend == TargetBackendKind::Gbm => None,
+        transform => PlaneRotation::query(fd.clone(), plane, transform).unwrap_or_else(|err| {
+            slog::debug!(log, "Failed to query the rotation of the plane: {}", err);
+            None
+        }),
+    };
+    let transform = match rotation {
+        Some(_) => Transform::Normal,
+        None => options.transform,
+    };
+    if options.transform != Transform::Normal {
+        slog::info!(
+            log,
+            "Transform {:?}: {}",
+            options.transform,
+            if rotation.is_some() { "plane" } else { "gl" }
+        );
+    }
+
+    let plane_scaling = match options.plane_scaling {
+        Some(_) if !props.is_atomic() || options.backend == TargetBackendKind::Gbm => {
+            slog::warn!(
+                log,
+                "Scaling by the plane needs atomic modesetting and the eglstream backend, scaling while rendering"
+            );
+            None
+        }
+        size => size,
+    };
+
+    let (backend, mut renderer, depth): (Box<dyn TargetBackend>, _, _) = match options.backend {
+        TargetBackendKind::Gbm => {
+            if options.depth == ColorDepth::Ten {
+                slog::warn!(log, "The gbm backend only scans out 8 bit, falling back to 8 bit");
+            }
+            let (backend, renderer) = GbmBackend::new(fd.clone(), drm_surface, &log)?;
+            (Box::new(backend), renderer, ColorDepth::Eight)
+        }
+        TargetBackendKind::Auto | TargetBackendKind::EglStream => {
+            let depth = match options.depth {
+                ColorDepth::Ten if !plane_supports(&device, plane, Fourcc::Xrgb2101010) => {
+                    slog::warn!(log, "Plane does not support XRGB2101010, falling back to 8 bit");
+                    ColorDepth::Eight
+                }
+                depth => depth,
+            };
+            let (backend, renderer) = EglStreamBackend::new(
+                fd.clone(),
+                &device,
+                drm_surface,
+                plane,
+                mode,
+                depth,
+                options.stream,
+                rotation,
+                // the stream holds the content the way the renderer transforms it
+                plane_scaling.map(|size| {
+                    let size = geometry::transformed_size(Size::<i32, Physical>::from(size), transform);
+                    (size.w, size.h)
+                }),
+                &log,
+            )?;
+            // 8 bit without any 10 bit stream config
+            let depth = backend.depth;
+            (Box::new(backend), renderer, depth)
+        }
+    };
+    if (options.colorspace.is_some() || options.hdr_metadata.is_some()) && depth != ColorDepth::Ten {
+        anyhow::bail!("--colorspace and --hdr-metadata need 10 bit scanout, which no stream config offers");
+    }
+    let (sync, gl) = setup_context(&mut renderer, options.gl_debug, &log)?;
+    if !gl.fits_texture(mode.0, mode.1) {
+        anyhow::bail!(
+            "Mode {}x{} exceeds the maximum texture size of the target gpu, GL_MAX_TEXTURE_SIZE is {}",
+            mode.0,
+            mode.1,
+            gl.max_texture_size
+        );
+    }
+    if depth == ColorDepth::Ten && !gl.ten_bit {
+        slog::warn!(log, "Context can't upload 10 bit pixels, uploading frames in 8 bit");
+    }
 
     Ok((
         TargetGPU {
-            _device: egl_device,
-            _display: egl_display,
-            surface: egl_surface,
             renderer,
-            _drm_surface: drm_surface,
-            _fb: fb,
-            _db: db,
+            backend,
+            sync,
+            depth,
+            upload_fence: None,
+            swap_interval: options.stream.swap_interval,
+            mode,
+            transform,
+            output_transform: options.transform,
+            vrr,
+            connector_name: connector_name(&connector_info),
+            gl,
+            gl_debug: options.gl_debug,
+            kms: Some(KmsOutput {
+                connector: connector_info.handle(),
+                modeline: options.modeline,
+                props,
+                edid: edid::read(&device, connector_info.handle()).ok().flatten(),
+                restore_gamma,
+                fd,
+                log: log.clone(),
+            }),
         },
         device,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::stat::makedev;
+    use smithay::reexports::drm::control;
+
+    /// A mode of `width`x`height` at `refresh` Hz with simple timings
+    fn mode(width: u16, height: u16, refresh: u32, preferred: bool) -> Mode {
+        let (htotal, vtotal) = (width + 160, height + 30);
+        Mode::from(drm_ffi::drm_mode_modeinfo {
+            clock: htotal as u32 * vtotal as u32 * refresh / 1000,
+            hdisplay: width,
+            hsync_start: width + 48,
+            hsync_end: width + 80,
+            htotal,
+            hskew: 0,
+            vdisplay: height,
+            vsync_start: height + 3,
+            vsync_end: height + 9,
+            vtotal,
+            vscan: 0,
+            vrefresh: refresh,
+            flags: 0,
+            type_: if preferred { ModeTypeFlags::PREFERRED.bits() } else { 0 },
+            name: [0; 32],
+        })
+    }
+
+    fn size_and_refresh(drm_mode: Option<Mode>) -> Option<(u16, u16, u32)> {
+        drm_mode.map(|drm_mode| (drm_mode.size().0, drm_mode.size().1, drm_mode.vrefresh()))
+    }
+
+    #[test]
+    fn closest_mode_exact() {
+        let modes = [mode(3840, 2160, 60, true), mode(1920, 1080, 60, false), mode(1280, 720, 60, false)];
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1080), None)), Some((1920, 1080, 60)));
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1280, 720), Some(60))), Some((1280, 720, 60)));
+    }
+
+    #[test]
+    fn closest_mode_nearest_refresh() {
+        let modes = [
+            mode(1920, 1080, 144, true),
+            mode(1920, 1080, 50, false),
+            mode(1920, 1080, 60, false),
+            mode(1920, 1080, 75, false),
+        ];
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1080), Some(60))), Some((1920, 1080, 60)));
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1080), Some(70))), Some((1920, 1080, 75)));
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1080), Some(240))), Some((1920, 1080, 144)));
+        // without a refresh rate the preferred mode wins
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1080), None)), Some((1920, 1080, 144)));
+        // the size still comes first
+        let modes = [mode(1920, 1080, 60, false), mode(1920, 1200, 30, false)];
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1200), Some(60))), Some((1920, 1200, 30)));
+    }
+
+    #[test]
+    fn closest_mode_nearest_size() {
+        let modes = [mode(1920, 1080, 60, false), mode(1280, 1024, 60, false), mode(3840, 2160, 60, false)];
+        // slightly taller, scaled
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1920, 1200), None)), Some((1920, 1080, 60)));
+        assert_eq!(size_and_refresh(closest_mode(&modes, (1280, 960), None)), Some((1280, 1024, 60)));
+        assert_eq!(size_and_refresh(closest_mode(&modes, (5120, 2880), None)), Some((3840, 2160, 60)));
+    }
+
+    #[test]
+    fn closest_mode_ties_go_to_preferred() {
+        let modes = [mode(1920, 1080, 60, false), mode(1920, 1080, 60, true), mode(1920, 1080, 60, false)];
+        let drm_mode = closest_mode(&modes, (1920, 1080), Some(60)).unwrap();
+        assert!(drm_mode.mode_type().contains(ModeTypeFlags::PREFERRED));
+    }
+
+    #[test]
+    fn closest_mode_of_no_modes() {
+        assert_eq!(size_and_refresh(closest_mode(&[], (1920, 1080), Some(60))), None);
+    }
+
+    #[test]
+    fn mode_ladder() {
+        let modes = [
+            mode(1920, 1080, 60, false),
+            mode(3840, 2160, 60, true),
+            mode(1280, 720, 60, false),
+            mode(3840, 2160, 30, false),
+            mode(1920, 1080, 50, false),
+            mode(2560, 1440, 60, false),
+        ];
+        let mut current = modes[1];
+        let mut ladder = Vec::new();
+        while let Some(lower) = next_lower_mode(&modes, &current) {
+            assert!(lower.clock() < current.clock());
+            ladder.push(size_and_refresh(Some(lower)).unwrap());
+            current = lower;
+        }
+        assert_eq!(
+            ladder,
+            vec![(3840, 2160, 30), (2560, 1440, 60), (1920, 1080, 60), (1920, 1080, 50), (1280, 720, 60)]
+        );
+    }
+
+    #[test]
+    fn mode_ladder_prefers_the_same_size() {
+        let current = mode(1920, 1080, 60, false);
+        let with_clock = |drm_mode: Mode, clock: u32| {
+            let mut info = drm_ffi::drm_mode_modeinfo::from(drm_mode);
+            info.clock = clock;
+            Mode::from(info)
+        };
+        let modes = [
+            current,
+            with_clock(mode(1680, 1050, 60, false), 100_000),
+            with_clock(mode(1920, 1080, 50, false), 100_000),
+            with_clock(mode(1600, 900, 60, false), 100_000),
+        ];
+        assert_eq!(size_and_refresh(next_lower_mode(&modes, &current)), Some((1920, 1080, 50)));
+    }
+
+    #[test]
+    fn mode_ladder_ends_at_the_lowest_mode() {
+        let modes = [mode(1920, 1080, 60, false), mode(1280, 720, 60, false)];
+        assert_eq!(size_and_refresh(next_lower_mode(&modes, &modes[1])), None);
+        assert_eq!(size_and_refresh(next_lower_mode(&[], &modes[0])), None);
+    }
+
+    #[test]
+    fn select_mode_strict() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let modes = [mode(1920, 1080, 60, true), mode(1920, 1080, 30, false)];
+        let exact = select_mode(&modes, (1920, 1080), Some(30), true, &log).unwrap();
+        assert_eq!(size_and_refresh(Some(exact)), Some((1920, 1080, 30)));
+        assert!(select_mode(&modes, (1920, 1200), None, true, &log).is_err());
+        let closest = select_mode(&modes, (1920, 1200), None, false, &log).unwrap();
+        assert_eq!(size_and_refresh(Some(closest)), Some((1920, 1080, 60)));
+        assert!(select_mode(&[], (1920, 1080), None, false, &log).is_err());
+    }
+
+    #[test]
+    fn master_errors() {
+        assert_eq!(MasterError::classify(Errno::EBUSY), MasterError::Busy);
+        // older kernels
+        assert_eq!(MasterError::classify(Errno::EINVAL), MasterError::Busy);
+        assert_eq!(MasterError::classify(Errno::EACCES), MasterError::PermissionDenied);
+        assert_eq!(MasterError::classify(Errno::EPERM), MasterError::PermissionDenied);
+        assert_eq!(MasterError::classify(Errno::ENOTTY), MasterError::Other(Errno::ENOTTY));
+    }
+
+    #[test]
+    fn master_error_advice() {
+        let path = Path::new("/dev/dri/card1");
+        let busy = MasterError::Busy.describe(path);
+        assert!(busy.contains("/dev/dri/card1") && busy.contains("WLR_DRM_DEVICES"));
+        assert!(MasterError::PermissionDenied.describe(path).contains("logind"));
+        assert!(MasterError::Other(Errno::ENOTTY).describe(path).starts_with("Failed to become drm master"));
+    }
+
+    #[test]
+    fn master_of_no_drm_device() {
+        // the ioctl reaches a file instead of a drm device
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let fd = Fd::open(&file.path()).unwrap();
+        let err = acquire_master(&fd, file.path()).unwrap_err().to_string();
+        assert!(err.starts_with("Failed to become drm master"), "{}", err);
+    }
+
+    #[test]
+    fn seat_from_the_flag() {
+        assert_eq!(resolve_seat(Some("seat1"), Some("seat0".to_string())), "seat1");
+        assert_eq!(resolve_seat(Some("seat1"), None), "seat1");
+    }
+
+    #[test]
+    fn seat_from_the_session() {
+        assert_eq!(resolve_seat(None, Some("seat2".to_string())), "seat2");
+    }
+
+    #[test]
+    fn seat_defaults_to_seat0() {
+        // systemd user services and ssh sessions
+        assert_eq!(resolve_seat(None, None), "seat0");
+        assert_eq!(resolve_seat(None, Some(String::new())), "seat0");
+    }
+
+    #[test]
+    fn seat_of_the_gpu() {
+        let gpus = || {
+            vec![
+                (makedev(226, 0), PathBuf::from("/dev/dri/card0"), "seat0".to_string()),
+                (makedev(226, 1), PathBuf::from("/dev/dri/card1"), "seat1".to_string()),
+            ]
+        };
+        assert_eq!(seat_of_device(gpus(), makedev(226, 1), "seat0"), "seat1");
+        assert_eq!(seat_of_device(gpus(), makedev(226, 0), "seat1"), "seat0");
+        // not enumerated, e.g. without udev rules
+        assert_eq!(seat_of_device(gpus(), makedev(226, 2), "seat1"), "seat1");
+        assert_eq!(seat_of_device(Vec::new(), makedev(226, 0), "seat0"), "seat0");
+    }
+
+    #[test]
+    fn dumb_formats_of_xr24_only_plane() {
+        let formats = dumb_buffer_formats(ColorDepth::Eight, &[Fourcc::Xrgb8888 as u32]);
+        assert_eq!(formats, vec![Fourcc::Xrgb8888, Fourcc::Argb8888, Fourcc::Rgb565]);
+    }
+
+    #[test]
+    fn dumb_formats_prefer_listed_ones() {
+        // primary plane of a gpu without XR24 but with AR24 and RG16
+        let supported = [Fourcc::Argb8888 as u32, Fourcc::Rgb565 as u32, Fourcc::Nv12 as u32];
+        let formats = dumb_buffer_formats(ColorDepth::Eight, &supported);
+        assert_eq!(formats, vec![Fourcc::Argb8888, Fourcc::Rgb565, Fourcc::Xrgb8888]);
+    }
+
+    #[test]
+    fn dumb_formats_of_ten_bit() {
+        let supported = [Fourcc::Xrgb8888 as u32, Fourcc::Argb8888 as u32, Fourcc::Xrgb2101010 as u32];
+        assert_eq!(dumb_buffer_formats(ColorDepth::Ten, &supported)[0], Fourcc::Xrgb2101010);
+        assert!(!dumb_buffer_formats(ColorDepth::Eight, &supported).contains(&Fourcc::Xrgb2101010));
+        // a plane without 10 bit formats still tries them last
+        let formats = dumb_buffer_formats(ColorDepth::Ten, &[Fourcc::Xrgb8888 as u32]);
+        assert_eq!(formats.first(), Some(&Fourcc::Xrgb8888));
+        assert!(formats.contains(&Fourcc::Xrgb2101010));
+    }
+
+    #[test]
+    fn dumb_formats_of_unknown_plane() {
+        // drivers listing no formats get all of them, by preference
+        assert_eq!(
+            dumb_buffer_formats(ColorDepth::Eight, &[]),
+            vec![Fourcc::Xrgb8888, Fourcc::Argb8888, Fourcc::Rgb565]
+        );
+    }
+
+    #[test]
+    fn legacy_depths() {
+        assert_eq!(legacy_depth(Fourcc::Xrgb8888), Some((24, 32)));
+        assert_eq!(legacy_depth(Fourcc::Argb8888), Some((32, 32)));
+        assert_eq!(legacy_depth(Fourcc::Xrgb2101010), Some((30, 32)));
+        assert_eq!(legacy_depth(Fourcc::Rgb565), Some((16, 16)));
+        assert_eq!(legacy_depth(Fourcc::Nv12), None);
+        // every candidate can go through AddFB as well
+        assert!(DUMB_FORMATS.iter().all(|format| legacy_depth(*format).is_some()));
+    }
+
+    #[test]
+    fn render_node_of_card_node() {
+        let sysfs = tempfile::tempdir().unwrap();
+        crate::egl::tests::fake_sysfs(sysfs.path(), &[("card1", 1), ("renderD129", 129)]);
+        let render = Some(PathBuf::from("/dev/dri/renderD129"));
+        assert_eq!(render_node_of(sysfs.path(), makedev(226, 1)).unwrap(), render);
+        // --render-device may already be the render node
+        assert_eq!(render_node_of(sysfs.path(), makedev(226, 129)).unwrap(), render);
+    }
+
+    #[test]
+    fn render_node_of_modesetting_only_device() {
+        // e.g. simpledrm, which falls back to the card node and wl_drm authentication
+        let sysfs = tempfile::tempdir().unwrap();
+        crate::egl::tests::fake_sysfs(sysfs.path(), &[("card0", 0)]);
+        assert_eq!(render_node_of(sysfs.path(), makedev(226, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn render_node_of_unknown_device() {
+        let sysfs = tempfile::tempdir().unwrap();
+        crate::egl::tests::fake_sysfs(sysfs.path(), &[("card0", 0), ("renderD128", 128)]);
+        assert!(render_node_of(sysfs.path(), makedev(226, 1)).is_err());
+        assert!(render_node(Path::new("/nonexistent/card0")).is_err());
+    }
+
+    #[test]
+    fn init_failures() {
+        let classify = |err: anyhow::Error| InitFailure::classify(&err);
+        assert_eq!(classify(anyhow::anyhow!("EGL_BAD_ACCESS").context(StreamRefused)), InitFailure::StreamRefused);
+        assert_eq!(classify(anyhow::Error::new(Errno::EBUSY).context("Failed to create surface")), InitFailure::Busy);
+        assert_eq!(classify(anyhow::Error::new(nix::Error::Sys(Errno::EBUSY))), InitFailure::Busy);
+        assert_eq!(
+            classify(anyhow::Error::new(std::io::Error::from_raw_os_error(Errno::EBUSY as i32))),
+            InitFailure::Busy
+        );
+        assert_eq!(classify(anyhow::anyhow!("Error setting crtc: {}", Errno::EBUSY.desc())), InitFailure::Busy);
+        assert_eq!(classify(anyhow::Error::new(Errno::EACCES)), InitFailure::Other);
+        assert_eq!(classify(anyhow::anyhow!("Connector not found")), InitFailure::Other);
+    }
+
+    #[test]
+    fn recovery_decisions() {
+        assert_eq!(recovery(0, InitFailure::Busy), Recovery::CleanUp);
+        assert_eq!(recovery(0, InitFailure::StreamRefused), Recovery::CleanUp);
+        assert_eq!(recovery(0, InitFailure::Other), Recovery::GiveUp);
+        assert_eq!(recovery(MAX_RECOVERY_ATTEMPTS, InitFailure::Busy), Recovery::GiveUp);
+        assert_eq!(recovery(MAX_RECOVERY_ATTEMPTS, InitFailure::StreamRefused), Recovery::GiveUp);
+    }
+
+    /// Initializes with the results of `attempts` in turn, returning the result and the cleanups done
+    fn simulate(attempts: Vec<Result<u32, anyhow::Error>>, cleanup_works: bool) -> (Result<u32>, u32) {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut attempts = attempts.into_iter();
+        let mut cleanups = 0;
+        let result = retry_with_recovery(
+            || attempts.next().expect("initialized once too often"),
+            || {
+                cleanups += 1;
+                if cleanup_works {
+                    Ok(())
+                } else {
+                    anyhow::bail!("Failed to disable crtc")
+                }
+            },
+            &log,
+        );
+        (result, cleanups)
+    }
+
+    fn busy() -> anyhow::Error {
+        anyhow::Error::new(Errno::EBUSY).context("Failed to create surface")
+    }
+
+    #[test]
+    fn recovery_sequences() {
+        let (result, cleanups) = simulate(vec![Ok(1)], true);
+        assert_eq!((result.unwrap(), cleanups), (1, 0));
+
+        let (result, cleanups) = simulate(vec![Err(busy()), Ok(2)], true);
+        assert_eq!((result.unwrap(), cleanups), (2, 1));
+
+        let refused = anyhow::anyhow!("EGL_BAD_ACCESS").context(StreamRefused);
+        let (result, cleanups) = simulate(vec![Err(refused), Ok(3)], true);
+        assert_eq!((result.unwrap(), cleanups), (3, 1));
+
+        // cleaning up is only tried once
+        let still_busy = anyhow::Error::new(Errno::EBUSY).context("Failed to add framebuffer");
+        let (result, cleanups) = simulate(vec![Err(busy()), Err(still_busy)], true);
+        assert_eq!(cleanups, 1);
+        assert_eq!(result.unwrap_err().to_string(), "Failed to add framebuffer");
+
+        // nothing to clean up for
+        let (result, cleanups) = simulate(vec![Err(anyhow::anyhow!("Connector not found"))], true);
+        assert_eq!(cleanups, 0);
+        assert_eq!(result.unwrap_err().to_string(), "Connector not found");
+
+        // a failed cleanup hands out the original error
+        let (result, cleanups) = simulate(vec![Err(busy())], false);
+        assert_eq!(cleanups, 1);
+        assert_eq!(result.unwrap_err().to_string(), "Failed to create surface");
+    }
+
+    const INTERFACES: [Interface; 18] = [
+        Interface::Unknown,
+        Interface::VGA,
+        Interface::DVII,
+        Interface::DVID,
+        Interface::DVIA,
+        Interface::Composite,
+        Interface::SVideo,
+        Interface::LVDS,
+        Interface::Component,
+        Interface::NinePinDIN,
+        Interface::DisplayPort,
+        Interface::HDMIA,
+        Interface::HDMIB,
+        Interface::TV,
+        Interface::EmbeddedDisplayPort,
+        Interface::Virtual,
+        Interface::DSI,
+        Interface::DPI,
+    ];
+
+    fn connector(name: &str, id: u32, state: ConnectorState, monitor: Option<(&str, &str)>) -> ConnectorEntry {
+        ConnectorEntry {
+            name: String::from(name),
+            id,
+            state,
+            edid: monitor.map(|(name, serial)| Edid {
+                manufacturer: String::from("DEL"),
+                product: 0xa0c4,
+                name: Some(String::from(name)),
+                serial: Some(String::from(serial)),
+            }),
+        }
+    }
+
+    #[test]
+    fn connector_names() {
+        assert_eq!(format_connector_name(Interface::HDMIA, 1), "HDMI-1");
+        assert_eq!(format_connector_name(Interface::HDMIB, 2), "HDMI-2");
+        assert_eq!(format_connector_name(Interface::DisplayPort, 3), "DP-3");
+        assert_eq!(format_connector_name(Interface::EmbeddedDisplayPort, 1), "eDP-1");
+        assert_eq!(format_connector_name(Interface::DVID, 1), "DVI-1");
+        assert_eq!(format_connector_name(Interface::Unknown, 1), "Unknown-1");
+        for interface in INTERFACES {
+            let name = interface_name(interface);
+            assert!(!name.is_empty() && !name.contains('-'), "{:?} is named {:?}", interface, name);
+        }
+    }
+
+    #[test]
+    fn connector_names_round_trip() {
+        let connectors = INTERFACES
+            .iter()
+            .enumerate()
+            .map(|(index, interface)| {
+                // the kernel counts per interface, distinct ids keep the subtypes DVI and HDMI drop apart
+                let name = format_connector_name(*interface, index as u32 + 1);
+                connector(&name, 70 + index as u32, ConnectorState::Connected, None)
+            })
+            .collect::<Vec<_>>();
+        for (index, entry) in connectors.iter().enumerate() {
+            assert_eq!(resolve_connector(&connectors, &entry.name).unwrap(), Some(index), "{}", entry.name);
+            let id = format!("{}{}", CONNECTOR_ID_PREFIX, entry.id);
+            assert_eq!(resolve_connector(&connectors, &id).unwrap(), Some(index), "{}", id);
+        }
+    }
+
+    #[test]
+    fn connectors_by_id() {
+        let connectors = [
+            connector("DP-1", 84, ConnectorState::Disconnected, None),
+            connector("HDMI-1", 86, ConnectorState::Connected, Some(("DELL U2720Q", "ABC123"))),
+            connector("DP-2", 88, ConnectorState::Connected, None),
+        ];
+        assert_eq!(resolve_connector(&connectors, "id:86").unwrap(), Some(1));
+        assert_eq!(resolve_connector(&connectors, "id:88").unwrap(), Some(2));
+        // nothing is plugged in
+        assert_eq!(resolve_connector(&connectors, "id:84").unwrap(), None);
+        assert_eq!(resolve_connector(&connectors, "id:99").unwrap(), None);
+        for invalid in ["id:", "id:HDMI-1", "id:-86", "id:0x56"] {
+            assert!(resolve_connector(&connectors, invalid).is_err(), "{}", invalid);
+        }
+        // the prefix is only special in front
+        assert_eq!(resolve_connector(&connectors, "86").unwrap(), None);
+    }
+
+    #[test]
+    fn connectors_by_name_and_monitor() {
+        let connectors = [
+            connector("DP-1", 84, ConnectorState::Disconnected, Some(("DELL U2720Q", "OLD111"))),
+            connector("HDMI-1", 86, ConnectorState::Connected, Some(("DELL U2720Q", "ABC123"))),
+            connector("DP-2", 88, ConnectorState::Connected, Some(("DELL U2720Q", "XYZ789"))),
+            connector("DP-3", 90, ConnectorState::Unknown, None),
+        ];
+        assert_eq!(resolve_connector(&connectors, "HDMI-1").unwrap(), Some(1));
+        assert_eq!(resolve_connector(&connectors, "DP-1").unwrap(), None);
+        assert_eq!(resolve_connector(&connectors, "DP-3").unwrap(), None);
+        assert_eq!(resolve_connector(&connectors, "HDMI-A-1").unwrap(), None);
+        assert_eq!(resolve_connector(&connectors, "xyz789").unwrap(), Some(2));
+        assert_eq!(resolve_connector(&connectors, "OLD111").unwrap(), None);
+        let error = resolve_connector(&connectors, "U2720Q").unwrap_err().to_string();
+        assert!(error.contains("HDMI-1 (") && error.contains("DP-2 (") && !error.contains("DP-1"), "{}", error);
+    }
+
+    fn gpu(path: &str, driver: &str, connectors: Vec<ConnectorEntry>) -> GpuCandidate {
+        (PathBuf::from(path), String::from(driver), connectors)
+    }
+
+    fn connected(name: &str, id: u32) -> ConnectorEntry {
+        connector(name, id, ConnectorState::Connected, None)
+    }
+
+    fn headless(name: &str, id: u32) -> ConnectorEntry {
+        connector(name, id, ConnectorState::Disconnected, None)
+    }
+
+    /// The reasons of `skipped` as borrowed strings, for comparing them
+    fn reasons(skipped: &[(usize, String)]) -> Vec<(usize, &str)> {
+        skipped.iter().map(|(index, reason)| (*index, reason.as_str())).collect()
+    }
+
+    #[test]
+    fn headless_gpus_are_skipped() {
+        let candidates = [
+            gpu("/dev/dri/card0", "nvidia-drm", vec![headless("DP-1", 80), headless("HDMI-1", 82)]),
+            gpu("/dev/dri/card1", "nvidia-drm", vec![headless("DP-1", 90), connected("HDMI-1", 92)]),
+        ];
+        let (selected, skipped) = select_nvidia_gpu(&candidates, None, None, false);
+        assert_eq!(selected, Some(1));
+        assert_eq!(reasons(&skipped), [(0, "no connected connectors")]);
+    }
+
+    #[test]
+    fn gpus_by_connector() {
+        let candidates = [
+            gpu("/dev/dri/card0", "nvidia-drm", vec![connected("DP-1", 80)]),
+            gpu(
+                "/dev/dri/card1",
+                "nvidia-drm",
+                vec![headless("DP-1", 90), connector("HDMI-1", 92, ConnectorState::Connected, Some(("LG TV", "1234")))],
+            ),
+        ];
+        let (selected, skipped) = select_nvidia_gpu(&candidates, Some("HDMI-1"), None, false);
+        assert_eq!(selected, Some(1));
+        assert_eq!(reasons(&skipped), [(0, "HDMI-1 is not connected")]);
+        // by the monitor
+        assert_eq!(select_nvidia_gpu(&candidates, Some("LG TV"), None, false).0, Some(1));
+        assert_eq!(select_nvidia_gpu(&candidates, Some("DP-1"), None, false), (Some(0), Vec::new()));
+        let (selected, skipped) = select_nvidia_gpu(&candidates, Some("DP-2"), None, false);
+        assert_eq!(selected, None);
+        assert_eq!(reasons(&skipped), [(0, "DP-2 is not connected"), (1, "DP-2 is not connected")]);
+    }
+
+    #[test]
+    fn ambiguous_monitors_are_left_to_the_connector_lookup() {
+        let monitor = Some(("DELL U2720Q", "ABC123"));
+        let candidates = [gpu(
+            "/dev/dri/card0",
+            "nvidia-drm",
+            vec![
+                connector("DP-1", 80, ConnectorState::Connected, monitor),
+                connector("DP-2", 82, ConnectorState::Connected, monitor),
+            ],
+        )];
+        assert_eq!(select_nvidia_gpu(&candidates, Some("U2720Q"), None, false).0, Some(0));
+    }
+
+    #[test]
+    fn device_index_overrides_connectivity() {
+        let candidates = [
+            gpu("/dev/dri/card0", "nvidia-drm", vec![connected("DP-1", 80)]),
+            gpu("/dev/dri/card1", "i915", vec![connected("eDP-1", 85)]),
+            gpu("/dev/dri/card2", "nvidia-drm", vec![headless("DP-1", 90)]),
+        ];
+        // only nvidia gpus are counted
+        let (selected, skipped) = select_nvidia_gpu(&candidates, Some("HDMI-1"), Some(1), false);
+        assert_eq!(selected, Some(2));
+        assert_eq!(reasons(&skipped), [(0, "not device index 1"), (1, "driven by i915")]);
+        assert_eq!(select_nvidia_gpu(&candidates, None, Some(0), false), (Some(0), Vec::new()));
+        let (selected, skipped) = select_nvidia_gpu(&candidates, None, Some(2), false);
+        assert_eq!(selected, None);
+        assert_eq!(
+            reasons(&skipped),
+            [(0, "not device index 2"), (1, "driven by i915"), (2, "not device index 2")]
+        );
+        // every gpu is counted with any driver
+        assert_eq!(select_nvidia_gpu(&candidates, None, Some(1), true).0, Some(1));
+    }
+
+    #[test]
+    fn other_drivers_only_with_any_driver() {
+        let candidates = [
+            gpu("/dev/dri/card0", "i915", vec![connected("eDP-1", 80)]),
+            gpu("/dev/dri/card1", "amdgpu", vec![connected("DP-1", 85)]),
+            gpu("/dev/dri/card2", "nvidia-drm", vec![connected("DP-1", 90)]),
+        ];
+        let (selected, skipped) = select_nvidia_gpu(&candidates, None, None, false);
+        assert_eq!(selected, Some(2));
+        assert_eq!(reasons(&skipped), [(0, "driven by i915"), (1, "driven by amdgpu")]);
+        assert_eq!(select_nvidia_gpu(&candidates, None, None, true), (Some(0), Vec::new()));
+        let (selected, skipped) = select_nvidia_gpu(&candidates, Some("DP-1"), None, true);
+        assert_eq!(selected, Some(1));
+        assert_eq!(reasons(&skipped), [(0, "DP-1 is not connected")]);
+
+        let (selected, skipped) = select_nvidia_gpu(&candidates[..2], None, None, false);
+        assert_eq!(selected, None);
+        assert_eq!(reasons(&skipped), [(0, "driven by i915"), (1, "driven by amdgpu")]);
+        assert_eq!(select_nvidia_gpu(&[], None, None, true), (None, Vec::new()));
+    }
+
+    fn crtc(id: u32) -> crtc::Handle {
+        control::from_u32(id).unwrap()
+    }
+
+    fn crtc_topology(candidates: &[u32], in_use: &[(u32, &str)]) -> CrtcTopology {
+        CrtcTopology {
+            candidates: candidates.iter().map(|id| crtc(*id)).collect(),
+            in_use: in_use.iter().map(|(id, name)| (crtc(*id), String::from(*name))).collect(),
+        }
+    }
+
+    #[test]
+    fn free_crtcs_are_preferred() {
+        let topology = crtc_topology(&[40, 41, 42], &[(40, "DP-1"), (43, "HDMI-1")]);
+        for allow_steal in [false, true] {
+            assert_eq!(select_crtc(&topology, allow_steal).unwrap(), (crtc(41), None));
+        }
+        // the current one of the connector comes first
+        let topology = crtc_topology(&[42, 41], &[]);
+        assert_eq!(select_crtc(&topology, false).unwrap(), (crtc(42), None));
+    }
+
+    #[test]
+    fn crtcs_of_other_outputs() {
+        let topology = crtc_topology(&[40, 41], &[(41, "HDMI-1"), (40, "DP-1")]);
+        let error = select_crtc(&topology, false).unwrap_err().to_string();
+        assert!(error.contains("another output (DP-1, HDMI-1)"), "{}", error);
+        assert!(error.contains("--allow-crtc-steal to take over the one of DP-1"), "{}", error);
+        assert_eq!(select_crtc(&topology, true).unwrap(), (crtc(40), Some(String::from("DP-1"))));
+    }
+
+    #[test]
+    fn no_crtcs() {
+        for allow_steal in [false, true] {
+            let error = select_crtc(&crtc_topology(&[], &[(40, "DP-1")]), allow_steal).unwrap_err();
+            assert_eq!(error.to_string(), "Unable to find suitable crtc");
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use std::{path::PathBuf, str::FromStr};
+
+/// EOTF of `hdr_metadata_infoframe`, HDMI_EOTF_SMPTE_ST2084
+const EOTF_PQ: u8 = 2;
+/// HDMI_STATIC_METADATA_TYPE1, the only type the kernel knows
+const STATIC_METADATA_TYPE1: u8 = 0;
+
+/// Where the metadata of `--hdr-metadata` comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HdrMetadataSource {
+    /// `HdrMetadata::default`
+    Auto,
+    /// A JSON object with the fields of `HdrMetadata`
+    File(PathBuf),
+}
+
+impl FromStr for HdrMetadataSource {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<HdrMetadataSource> {
+        match input {
+            "" => anyhow::bail!("HDR metadata needs to be \"auto\" or the path of a JSON file"),
+            "auto" => Ok(HdrMetadataSource::Auto),
+            path => Ok(HdrMetadataSource::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl HdrMetadataSource {
+    pub fn load(&self) -> Result<HdrMetadata> {
+        match self {
+            HdrMetadataSource::Auto => Ok(HdrMetadata::default()),
+            HdrMetadataSource::File(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read HDR metadata from {}", path.display()))?;
+                serde_json::from_str(&contents)
+                    .with_context(|| format!("Failed to parse HDR metadata from {}", path.display()))
+            }
+        }
+    }
+}
+
+/// SMPTE ST.2086 mastering display and CTA-861.3 content light levels, fields missing in a file keep their default.
+///
+/// Chromaticities are CIE 1931 xy coordinates, luminances are in cd/m².
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HdrMetadata {
+    pub red: [f64; 2],
+    pub green: [f64; 2],
+    pub blue: [f64; 2],
+    pub white_point: [f64; 2],
+    pub max_luminance: f64,
+    pub min_luminance: f64,
+    /// Brightest pixel of the content
+    pub max_cll: u16,
+    /// Brightest frame of the content, on average
+    pub max_fall: u16,
+}
+
+/// BT.2020 primaries with a D65 white point, mastered on a 1000 nits display
+impl Default for HdrMetadata {
+    fn default() -> HdrMetadata {
+        HdrMetadata {
+            red: [0.708, 0.292],
+            green: [0.170, 0.797],
+            blue: [0.131, 0.046],
+            white_point: [0.3127, 0.3290],
+            max_luminance: 1000.0,
+            min_luminance: 0.005,
+            max_cll: 1000,
+            max_fall: 400,
+        }
+    }
+}
+
+/// `struct hdr_metadata_infoframe` of drm_mode.h
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct HdrInfoframe {
+    eotf: u8,
+    metadata_type: u8,
+    /// x and y in units of 0.00002
+    display_primaries: [[u16; 2]; 3],
+    white_point: [u16; 2],
+    /// In units of 1 cd/m²
+    max_display_mastering_luminance: u16,
+    /// In units of 0.0001 cd/m²
+    min_display_mastering_luminance: u16,
+    max_cll: u16,
+    max_fall: u16,
+}
+
+/// `struct hdr_output_metadata` of drm_mode.h, the contents of the "HDR_OUTPUT_METADATA" blob
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HdrOutputMetadata {
+    metadata_type: u32,
+    infoframe: HdrInfoframe,
+}
+
+fn chromaticity(name: &str, [x, y]: [f64; 2]) -> Result<[u16; 2]> {
+    let encode = |value: f64| {
+        if (0.0..=1.0).contains(&value) {
+            Ok((value * 50000.0).round() as u16)
+        } else {
+            Err(anyhow::anyhow!("Chromaticity of {} needs to be between 0 and 1, got {}", name, value))
+        }
+    };
+    Ok([encode(x)?, encode(y)?])
+}
+
+impl HdrMetadata {
+    /// Encodes the metadata for PQ content, failing on values the infoframe can't hold
+    pub fn encode(&self) -> Result<HdrOutputMetadata> {
+        if !(1.0..=65535.0).contains(&self.max_luminance) {
+            anyhow::bail!("Maximum luminance needs to be between 1 and 65535, got {}", self.max_luminance);
+        }
+        if !(0.0..=6.5535).contains(&self.min_luminance) || self.min_luminance >= self.max_luminance {
+            anyhow::bail!(
+                "Minimum luminance needs to be between 0 and 6.5535 and below the maximum, got {}",
+                self.min_luminance
+            );
+        }
+        Ok(HdrOutputMetadata {
+            metadata_type: STATIC_METADATA_TYPE1 as u32,
+            infoframe: HdrInfoframe {
+                eotf: EOTF_PQ,
+                metadata_type: STATIC_METADATA_TYPE1,
+                display_primaries: [
+                    chromaticity("red", self.red)?,
+                    chromaticity("green", self.green)?,
+                    chromaticity("blue", self.blue)?,
+                ],
+                white_point: chromaticity("the white point", self.white_point)?,
+                max_display_mastering_luminance: self.max_luminance.round() as u16,
+                min_display_mastering_luminance: (self.min_luminance * 10000.0).round() as u16,
+                max_cll: self.max_cll,
+                max_fall: self.max_fall,
+            },
+        })
+    }
+}
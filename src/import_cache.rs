@@ -0,0 +1,223 @@
+use nix::sys::stat::fstat;
+use smithay::backend::allocator::{dmabuf::Dmabuf, Buffer, Format};
+
+use std::collections::VecDeque;
+
+/// Identifies the memory behind a dmabuf, independent of the file descriptors used to pass it.
+///
+/// Every export hands us new file descriptors, but they still refer to the same dma-buf inode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BufferKey {
+    inodes: Vec<u64>,
+    size: (i32, i32),
+    format: Format,
+}
+
+impl BufferKey {
+    pub fn of(buf: &Dmabuf) -> Option<BufferKey> {
+        let inodes = buf
+            .handles()
+            .map(|fd| fstat(fd).map(|stat| stat.st_ino as u64))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        Some(BufferKey {
+            inodes,
+            size: buf.size().into(),
+            format: buf.format(),
+        })
+    }
+}
+
+/// Keeps the imports of the few buffers a compositor rotates through.
+///
+/// Entries are evicted least recently used first and all at once, if the buffer size or format changes.
+pub struct ImportCache<T> {
+    entries: VecDeque<(BufferKey, T)>,
+    capacity: usize,
+    /// Fourcc and modifier of the latest frame, `None` before the first one
+    format: Option<Format>,
+}
+
+impl<T> ImportCache<T> {
+    pub fn new(capacity: usize) -> ImportCache<T> {
+        ImportCache {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            format: None,
+        }
+    }
+
+    /// Records `format` as the one of the latest frame, returns the previous one if the source switched formats.
+    ///
+    /// The imports of the previous format are dropped then, like those of another size on `insert`.
+    pub fn switch_format(&mut self, format: Format) -> Option<Format> {
+        match self.format.replace(format) {
+            Some(previous) if previous != format => {
+                self.entries.clear();
+                Some(previous)
+            }
+            _ => None,
+        }
+    }
+
+    /// Looks up an import and marks it as recently used
+    pub fn get(&mut self, key: &BufferKey) -> Option<&T> {
+        let idx = self.entries.iter().position(|(entry, _)| entry == key)?;
+        let entry = self.entries.remove(idx)?;
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, value)| value)
+    }
+
+    pub fn insert(&mut self, key: BufferKey, value: T) {
+        // buffers of the old size will never come around again
+        self.entries.retain(|(entry, _)| entry.size == key.size);
+        self.entries.retain(|(entry, _)| *entry != key);
+        while self.entries.len() >= self.capacity.max(1) {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, value));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smithay::backend::allocator::{Fourcc, Modifier};
+
+    fn key(inode: u64, size: (i32, i32)) -> BufferKey {
+        BufferKey {
+            inodes: vec![inode],
+            size,
+            format: ARGB,
+        }
+    }
+
+    const ARGB: Format = Format {
+        code: Fourcc::Argb8888,
+        modifier: Modifier::Linear,
+    };
+    /// Laid out as the driver likes, e.g. the buffers of a fullscreen client sway scans out
+    const IMPLICIT: Format = Format {
+        code: Fourcc::Argb8888,
+        modifier: Modifier::Invalid,
+    };
+    const XRGB: Format = Format {
+        code: Fourcc::Xrgb8888,
+        modifier: Modifier::Linear,
+    };
+
+    #[test]
+    fn hit_and_miss() {
+        let mut cache = ImportCache::new(2);
+        cache.insert(key(1, (64, 64)), "first");
+        assert_eq!(cache.get(&key(1, (64, 64))), Some(&"first"));
+        assert_eq!(cache.get(&key(2, (64, 64))), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ImportCache::new(2);
+        cache.insert(key(1, (64, 64)), 1);
+        cache.insert(key(2, (64, 64)), 2);
+        // makes 2 the least recently used one
+        assert_eq!(cache.get(&key(1, (64, 64))), Some(&1));
+        cache.insert(key(3, (64, 64)), 3);
+        assert_eq!(cache.get(&key(2, (64, 64))), None);
+        assert_eq!(cache.get(&key(1, (64, 64))), Some(&1));
+        assert_eq!(cache.get(&key(3, (64, 64))), Some(&3));
+    }
+
+    #[test]
+    fn reinsert_replaces() {
+        let mut cache = ImportCache::new(2);
+        cache.insert(key(1, (64, 64)), 1);
+        cache.insert(key(2, (64, 64)), 2);
+        cache.insert(key(1, (64, 64)), 10);
+        assert_eq!(cache.get(&key(1, (64, 64))), Some(&10));
+        assert_eq!(cache.get(&key(2, (64, 64))), Some(&2));
+    }
+
+    #[test]
+    fn resize_evicts_everything() {
+        let mut cache = ImportCache::new(4);
+        cache.insert(key(1, (64, 64)), 1);
+        cache.insert(key(2, (64, 64)), 2);
+        cache.insert(key(3, (128, 64)), 3);
+        assert_eq!(cache.get(&key(1, (64, 64))), None);
+        assert_eq!(cache.get(&key(2, (64, 64))), None);
+        assert_eq!(cache.get(&key(3, (128, 64))), Some(&3));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_the_last() {
+        let mut cache = ImportCache::new(0);
+        cache.insert(key(1, (64, 64)), 1);
+        cache.insert(key(2, (64, 64)), 2);
+        assert_eq!(cache.get(&key(1, (64, 64))), None);
+        assert_eq!(cache.get(&key(2, (64, 64))), Some(&2));
+        cache.clear();
+        assert_eq!(cache.get(&key(2, (64, 64))), None);
+    }
+
+    #[test]
+    fn format_changes() {
+        let mut cache = ImportCache::<u64>::new(4);
+        assert_eq!(cache.switch_format(ARGB), None);
+        assert_eq!(cache.switch_format(ARGB), None);
+        assert_eq!(cache.switch_format(IMPLICIT), Some(ARGB));
+        assert_eq!(cache.switch_format(XRGB), Some(IMPLICIT));
+        assert_eq!(cache.switch_format(XRGB), None);
+        assert_eq!(cache.switch_format(ARGB), Some(XRGB));
+        // clearing keeps the format, the next frame of it is no change
+        cache.clear();
+        assert_eq!(cache.switch_format(ARGB), None);
+    }
+
+    #[test]
+    fn alternating_formats_drop_the_imports() {
+        let mut cache = ImportCache::new(4);
+        // buffers of the compositor, in the order it hands them out
+        let frames = [
+            (1, ARGB),
+            (2, ARGB),
+            (1, ARGB),
+            (3, IMPLICIT),
+            (4, IMPLICIT),
+            (3, IMPLICIT),
+            (1, ARGB),
+            (2, ARGB),
+        ];
+        let mut events = Vec::new();
+        for (inode, format) in frames {
+            let changed = cache.switch_format(format).is_some();
+            let key = BufferKey {
+                inodes: vec![inode],
+                size: (64, 64),
+                format,
+            };
+            let hit = cache.get(&key).is_some();
+            if !hit {
+                cache.insert(key, inode);
+            }
+            events.push((changed, hit));
+        }
+        assert_eq!(
+            events,
+            [
+                (false, false),
+                (false, false),
+                (false, true),
+                // the buffers of the client are new, and the linear ones are gone once they come back
+                (true, false),
+                (false, false),
+                (false, true),
+                (true, false),
+                (false, false),
+            ]
+        );
+    }
+}
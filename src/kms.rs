@@ -0,0 +1,853 @@
+use anyhow::{Context, Result};
+use smithay::{
+    backend::renderer::Transform,
+    reexports::drm::{
+        buffer::DrmFourcc,
+        control::{
+            atomic::AtomicModeReq, connector, crtc, dumbbuffer::DumbBuffer, plane, property, AtomicCommitFlags,
+            Device as ControlDevice, ResourceHandle,
+        },
+        Device as _, DriverCapability,
+    },
+    utils::{Physical, Rectangle},
+};
+
+use crate::{cursor::CursorImage, gamma_lut::GammaLut, geometry, gpu::Fd};
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    os::unix::io::AsRawFd,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Power state of a connector, as values of its "DPMS" property
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dpms {
+    On = 0,
+    Off = 3,
+}
+
+/// How long the link of a connector may take to come up after powering it on
+const LINK_TIMEOUT: Duration = Duration::from_secs(1);
+/// DRM_MODE_LINK_STATUS_GOOD, the value of "link-status" while the monitor receives the signal
+const LINK_STATUS_GOOD: property::RawValue = 0;
+/// Cursor size of drivers not reporting theirs
+const DEFAULT_CURSOR_SIZE: u64 = 64;
+
+/// A connector property to set on startup, given as "NAME=VALUE"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropertyAssignment {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for PropertyAssignment {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<PropertyAssignment> {
+        match input.split_once('=') {
+            Some((name, value)) if !name.is_empty() && !value.is_empty() => Ok(PropertyAssignment {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => anyhow::bail!("Connector property needs to have the format \"NAME=VALUE\""),
+        }
+    }
+}
+
+impl fmt::Display for PropertyAssignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Values a property accepts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropertyKind {
+    /// Names and values of an enum property
+    Enum(Vec<(String, property::RawValue)>),
+    Range(u64, u64),
+    SignedRange(i64, i64),
+    Boolean,
+    /// Objects, blobs and bitmasks, which can't be set from the command line
+    Unsupported,
+}
+
+impl From<property::ValueType> for PropertyKind {
+    fn from(kind: property::ValueType) -> PropertyKind {
+        match kind {
+            property::ValueType::Enum(values) => {
+                let (raw, names) = values.values();
+                PropertyKind::Enum(
+                    raw.iter()
+                        .zip(names.iter())
+                        .map(|(value, name)| (name.name().to_string_lossy().into_owned(), *value))
+                        .collect(),
+                )
+            }
+            property::ValueType::UnsignedRange(min, max) => PropertyKind::Range(min, max),
+            property::ValueType::SignedRange(min, max) => PropertyKind::SignedRange(min, max),
+            property::ValueType::Boolean => PropertyKind::Boolean,
+            _ => PropertyKind::Unsupported,
+        }
+    }
+}
+
+impl fmt::Display for PropertyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyKind::Enum(values) => {
+                let names = values.iter().map(|(name, _)| format!("\"{}\"", name)).collect::<Vec<_>>();
+                write!(f, "one of {}", names.join(", "))
+            }
+            PropertyKind::Range(min, max) => write!(f, "a number between {} and {}", min, max),
+            PropertyKind::SignedRange(min, max) => write!(f, "a number between {} and {}", min, max),
+            PropertyKind::Boolean => write!(f, "0 or 1"),
+            PropertyKind::Unsupported => write!(f, "nothing settable"),
+        }
+    }
+}
+
+impl PropertyKind {
+    /// Raw value of `input`, enum values are matched by name
+    pub fn resolve(&self, input: &str) -> Option<property::RawValue> {
+        match self {
+            PropertyKind::Enum(values) => values
+                .iter()
+                .find(|(name, _)| name == input)
+                .map(|(_, value)| *value),
+            PropertyKind::Range(min, max) => input
+                .parse::<u64>()
+                .ok()
+                .filter(|value| (*min..=*max).contains(value)),
+            PropertyKind::SignedRange(min, max) => input
+                .parse::<i64>()
+                .ok()
+                .filter(|value| (*min..=*max).contains(value))
+                .map(|value| value as property::RawValue),
+            PropertyKind::Boolean => match input {
+                "0" | "false" => Some(0),
+                "1" | "true" => Some(1),
+                _ => None,
+            },
+            PropertyKind::Unsupported => None,
+        }
+    }
+}
+
+/// Handle of the property `name`, failing with the names of all properties if there is none
+fn find_property<H: Clone>(props: &HashMap<String, H>, name: &str) -> Result<H> {
+    match props.get(name) {
+        Some(handle) => Ok(handle.clone()),
+        None => {
+            let mut names = props.keys().map(String::as_str).collect::<Vec<_>>();
+            names.sort_unstable();
+            anyhow::bail!("Connector has no property \"{}\", it has: {}", name, names.join(", "));
+        }
+    }
+}
+
+/// Raw value of an assignment, failing with the values the property accepts
+fn resolve_value(kind: &PropertyKind, assignment: &PropertyAssignment) -> Result<property::RawValue> {
+    kind.resolve(&assignment.value).with_context(|| {
+        format!(
+            "Invalid value \"{}\" for \"{}\", expected {}",
+            assignment.value, assignment.name, kind
+        )
+    })
+}
+
+/// Property handles of the connector and crtc of the target, looked up once.
+///
+/// Changes go through atomic commits if the device supports them and through the legacy property api otherwise.
+pub struct PropertyCache {
+    fd: Fd,
+    atomic: bool,
+    connector: connector::Handle,
+    crtc: crtc::Handle,
+    connector_props: HashMap<String, property::Handle>,
+    crtc_props: HashMap<String, property::Handle>,
+}
+
+/// Names and handles of all properties of `handle`
+fn lookup<H: ResourceHandle>(fd: &Fd, handle: H) -> Result<HashMap<String, property::Handle>> {
+    let props = fd.get_properties(handle)?;
+    let (handles, _) = props.as_props_and_values();
+    let mut names = HashMap::new();
+    for handle in handles {
+        let info = fd.get_property(*handle)?;
+        if let Ok(name) = info.name().to_str() {
+            names.insert(name.to_string(), *handle);
+        }
+    }
+    Ok(names)
+}
+
+impl PropertyCache {
+    pub fn new(fd: Fd, connector: connector::Handle, crtc: crtc::Handle, atomic: bool) -> Result<PropertyCache> {
+        Ok(PropertyCache {
+            connector_props: lookup(&fd, connector)?,
+            crtc_props: lookup(&fd, crtc)?,
+            fd,
+            atomic,
+            connector,
+            crtc,
+        })
+    }
+
+    pub fn is_atomic(&self) -> bool {
+        self.atomic
+    }
+
+    pub fn crtc(&self) -> crtc::Handle {
+        self.crtc
+    }
+
+    /// Current value of the connector property `name`, `None` if the connector has none
+    pub fn connector_value(&self, name: &str) -> Result<Option<property::RawValue>> {
+        self.value(self.connector, &self.connector_props, name)
+    }
+
+    /// Current value of the crtc property `name`, `None` if the crtc has none
+    pub fn crtc_value(&self, name: &str) -> Result<Option<property::RawValue>> {
+        self.value(self.crtc, &self.crtc_props, name)
+    }
+
+    fn value<H: ResourceHandle>(
+        &self,
+        object: H,
+        names: &HashMap<String, property::Handle>,
+        name: &str,
+    ) -> Result<Option<property::RawValue>> {
+        let handle = match names.get(name) {
+            Some(handle) => *handle,
+            None => return Ok(None),
+        };
+        let props = self.fd.get_properties(object)?;
+        let (handles, values) = props.as_props_and_values();
+        Ok(handles
+            .iter()
+            .zip(values.iter())
+            .find(|(other, _)| **other == handle)
+            .map(|(_, value)| *value))
+    }
+
+    pub fn set_connector(&self, name: &str, value: property::RawValue, modeset: bool) -> Result<()> {
+        let handle = *self
+            .connector_props
+            .get(name)
+            .with_context(|| format!("Connector has no {} property", name))?;
+        self.set(self.connector, handle, value, modeset)
+    }
+
+    /// Points the connector property `name` at a new blob holding `data`, which needs to be `repr(C)`
+    pub fn set_connector_blob<T>(&self, name: &str, data: &T) -> Result<()> {
+        let blob = match self.fd.create_property_blob(data)? {
+            property::Value::Blob(blob) => blob,
+            value => anyhow::bail!("Created blob has an unexpected value: {:?}", value),
+        };
+        let result = self.set_connector(name, blob, true);
+        // the connector holds a reference of its own
+        let _ = self.fd.destroy_property_blob(blob);
+        result
+    }
+
+    /// Sets a property given on the command line, failing with the valid names or values if it doesn't exist
+    pub fn assign(&self, assignment: &PropertyAssignment) -> Result<()> {
+        let handle = find_property(&self.connector_props, &assignment.name)?;
+        let kind = PropertyKind::from(self.fd.get_property(handle)?.value_type());
+        let value = resolve_value(&kind, assignment)?;
+        // e.g. the color range only changes with a modeset
+        self.set(self.connector, handle, value, true)
+    }
+
+    pub fn set_crtc(&self, name: &str, value: property::RawValue, modeset: bool) -> Result<()> {
+        let handle = *self
+            .crtc_props
+            .get(name)
+            .with_context(|| format!("Crtc has no {} property", name))?;
+        self.set(self.crtc, handle, value, modeset)
+    }
+
+    fn set<H: ResourceHandle>(&self, object: H, handle: property::Handle, value: property::RawValue, modeset: bool) -> Result<()> {
+        if !self.atomic {
+            self.fd.set_property(object, handle, value)?;
+            return Ok(());
+        }
+        let mut req = AtomicModeReq::new();
+        req.add_property(object, handle, property::Value::UnsignedRange(value));
+        let flags: &[AtomicCommitFlags] = if modeset {
+            &[AtomicCommitFlags::AllowModeset]
+        } else {
+            &[]
+        };
+        self.fd.atomic_commit(flags, req)?;
+        Ok(())
+    }
+
+    /// Powers the connector on or off.
+    ///
+    /// Atomic drivers ignore "DPMS" in commits, so there the crtc is (de)activated instead,
+    /// unless it was never set up, which the first modeset does on its own.
+    pub fn set_dpms(&self, dpms: Dpms) -> Result<()> {
+        if !self.atomic {
+            return self.set_connector("DPMS", dpms as property::RawValue, false);
+        }
+        if self.fd.get_crtc(self.crtc)?.mode().is_none() {
+            return Ok(());
+        }
+        self.set_crtc("ACTIVE", (dpms == Dpms::On) as property::RawValue, true)
+    }
+
+    /// Whether the monitor supports variable refresh rates, as reported by the connector
+    pub fn vrr_capable(&self) -> Result<bool> {
+        Ok(self.connector_value("vrr_capable")? == Some(1) && self.crtc_props.contains_key("VRR_ENABLED"))
+    }
+
+    /// Lets the crtc flip as soon as a frame is ready, instead of on fixed vblanks
+    pub fn set_vrr(&self, enabled: bool) -> Result<()> {
+        self.set_crtc("VRR_ENABLED", enabled as property::RawValue, false)
+    }
+
+    /// Waits for the "link-status" of the connector to become good, monitors coming out of standby may need a moment.
+    ///
+    /// Returns whether it did, connectors without link status are always considered good.
+    pub fn wait_for_link(&self) -> Result<bool> {
+        let deadline = Instant::now() + LINK_TIMEOUT;
+        loop {
+            match self.link_good()? {
+                true => return Ok(true),
+                false if Instant::now() >= deadline => return Ok(false),
+                false => std::thread::sleep(Duration::from_millis(50)),
+            }
+        }
+    }
+
+    /// Whether the "link-status" of the connector is good, the driver marks it bad e.g. if the cable can't keep up
+    pub fn link_good(&self) -> Result<bool> {
+        Ok(matches!(self.connector_value("link-status")?, Some(LINK_STATUS_GOOD) | None))
+    }
+
+    /// Whether the gamma table goes through the "GAMMA_LUT" property instead of the legacy gamma ramp
+    pub fn atomic_gamma(&self) -> bool {
+        self.atomic && self.crtc_props.contains_key("GAMMA_LUT") && self.crtc_props.contains_key("GAMMA_LUT_SIZE")
+    }
+
+    /// Entries the gamma table of the crtc needs
+    pub fn gamma_size(&self) -> Result<usize> {
+        if self.atomic_gamma() {
+            return Ok(self.crtc_value("GAMMA_LUT_SIZE")?.unwrap_or(0) as usize);
+        }
+        Ok(self.fd.get_crtc(self.crtc)?.gamma_length() as usize)
+    }
+
+    /// Current gamma table of the crtc, `None` if it has none, which is the same as a linear one
+    pub fn gamma(&self) -> Result<Option<GammaLut>> {
+        if self.atomic_gamma() {
+            return match self.crtc_value("GAMMA_LUT")? {
+                None | Some(0) => Ok(None),
+                Some(blob) => Ok(Some(GammaLut::from_drm_color_lut(&self.fd.get_property_blob(blob)?))),
+            };
+        }
+        let size = self.gamma_size()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        let mut lut = GammaLut::linear(size);
+        self.fd
+            .get_gamma(self.crtc, &mut lut.red, &mut lut.green, &mut lut.blue)?;
+        Ok(Some(lut))
+    }
+
+    /// Loads `lut` into the crtc, which needs to have `gamma_size` entries. `None` resets it to linear.
+    pub fn set_gamma(&self, lut: Option<&GammaLut>) -> Result<()> {
+        if !self.atomic_gamma() {
+            let linear;
+            let lut = match lut {
+                Some(lut) => lut,
+                None => {
+                    linear = GammaLut::linear(self.gamma_size()?);
+                    &linear
+                }
+            };
+            self.fd.set_gamma(self.crtc, &lut.red, &lut.green, &lut.blue)?;
+            return Ok(());
+        }
+        let lut = match lut {
+            Some(lut) => lut,
+            None => return self.set_crtc("GAMMA_LUT", 0, false),
+        };
+        // the blob is an array of entries, which `create_property_blob` of drm can't size
+        let mut data = lut.to_drm_color_lut();
+        let blob = drm_ffi::mode::create_property_blob(self.fd.as_raw_fd(), &mut data)?.blob_id;
+        let result = self.set_crtc("GAMMA_LUT", blob as property::RawValue, false);
+        // the crtc holds a reference of its own
+        let _ = drm_ffi::mode::destroy_property_blob(self.fd.as_raw_fd(), blob);
+        result
+    }
+}
+
+/// Turns `crtc` off and detaches `connector` from it, by an atomic null commit or the legacy SetCrtc
+pub fn disable_crtc(fd: &Fd, connector: connector::Handle, crtc: crtc::Handle, atomic: bool) -> Result<()> {
+    if !atomic {
+        fd.set_crtc(crtc, None, (0, 0), &[], None)?;
+        return Ok(());
+    }
+    let connector_props = lookup(fd, connector)?;
+    let crtc_props = lookup(fd, crtc)?;
+    let prop = |props: &HashMap<String, property::Handle>, name: &str| {
+        props
+            .get(name)
+            .copied()
+            .with_context(|| format!("No {} property to disable the crtc with", name))
+    };
+    let mut req = AtomicModeReq::new();
+    req.add_property(connector, prop(&connector_props, "CRTC_ID")?, property::Value::UnsignedRange(0));
+    req.add_property(crtc, prop(&crtc_props, "ACTIVE")?, property::Value::UnsignedRange(0));
+    req.add_property(crtc, prop(&crtc_props, "MODE_ID")?, property::Value::UnsignedRange(0));
+    fd.atomic_commit(&[AtomicCommitFlags::AllowModeset], req)?;
+    Ok(())
+}
+
+/// `DRM_MODE_ROTATE_*` and `DRM_MODE_REFLECT_X` bits of the "rotation" plane property
+const ROTATE_0: property::RawValue = 1 << 0;
+const ROTATE_90: property::RawValue = 1 << 1;
+const ROTATE_180: property::RawValue = 1 << 2;
+const ROTATE_270: property::RawValue = 1 << 3;
+const REFLECT_X: property::RawValue = 1 << 4;
+
+/// Value of the "rotation" property doing `transform`, both rotate counter-clockwise
+fn rotation_bits(transform: Transform) -> property::RawValue {
+    match transform {
+        Transform::Normal => ROTATE_0,
+        Transform::_90 => ROTATE_90,
+        Transform::_180 => ROTATE_180,
+        Transform::_270 => ROTATE_270,
+        Transform::Flipped => ROTATE_0 | REFLECT_X,
+        Transform::Flipped90 => ROTATE_90 | REFLECT_X,
+        Transform::Flipped180 => ROTATE_180 | REFLECT_X,
+        Transform::Flipped270 => ROTATE_270 | REFLECT_X,
+    }
+}
+
+/// Transforms the scanout of a plane through its "rotation" property, so nothing needs to be rendered transformed.
+///
+/// Only buffers rendered for the transformed size may be scanned out rotated,
+/// so the plane is put upright again for anything else.
+pub struct PlaneRotation {
+    fd: Fd,
+    plane: plane::Handle,
+    handle: property::Handle,
+    transform: Transform,
+}
+
+impl PlaneRotation {
+    /// `None` if the plane has no rotation property or the driver rejects `transform`,
+    /// which it checks against the rotations the plane supports.
+    ///
+    /// Needs atomic modesetting, the legacy api can't test values.
+    pub fn query(fd: Fd, plane: plane::Handle, transform: Transform) -> Result<Option<PlaneRotation>> {
+        let handle = match lookup(&fd, plane)?.get("rotation") {
+            Some(handle) => *handle,
+            None => return Ok(None),
+        };
+        let mut req = AtomicModeReq::new();
+        req.add_property(plane, handle, property::Value::Bitmask(rotation_bits(transform)));
+        if fd
+            .atomic_commit(&[AtomicCommitFlags::TestOnly, AtomicCommitFlags::AllowModeset], req)
+            .is_err()
+        {
+            return Ok(None);
+        }
+        Ok(Some(PlaneRotation {
+            fd,
+            plane,
+            handle,
+            transform,
+        }))
+    }
+
+    /// Buffers need to be rendered with width and height swapped
+    pub fn swaps_axes(&self) -> bool {
+        geometry::swaps_axes(self.transform)
+    }
+
+    /// Rotates the plane, or puts it upright with `rotated` unset
+    pub fn apply(&self, rotated: bool) -> Result<()> {
+        let transform = if rotated { self.transform } else { Transform::Normal };
+        let mut req = AtomicModeReq::new();
+        req.add_property(self.plane, self.handle, property::Value::Bitmask(rotation_bits(transform)));
+        self.fd
+            .atomic_commit(&[AtomicCommitFlags::AllowModeset], req)
+            .context("Failed to set the rotation of the plane")?;
+        Ok(())
+    }
+}
+
+/// Properties of a plane positioning its framebuffer on the crtc
+const RECT_PROPS: [&str; 8] = ["SRC_X", "SRC_Y", "SRC_W", "SRC_H", "CRTC_X", "CRTC_Y", "CRTC_W", "CRTC_H"];
+
+/// Scales the frames of a plane onto the crtc through its "SRC_*" and "CRTC_*" rectangles,
+/// so the display engine resamples them instead of the renderer.
+pub struct PlaneScaling {
+    fd: Fd,
+    plane: plane::Handle,
+    props: HashMap<String, property::Handle>,
+    /// Size of the frames, which stays the same across modes
+    pub src: (i32, i32),
+}
+
+impl PlaneScaling {
+    /// `None` if the plane lacks the properties or the driver rejects scaling `src` into `dst`,
+    /// which is tested against the framebuffer currently on the plane.
+    pub fn query(
+        fd: Fd,
+        plane: plane::Handle,
+        src: (i32, i32),
+        dst: Rectangle<i32, Physical>,
+    ) -> Result<Option<PlaneScaling>> {
+        let props = lookup(&fd, plane)?;
+        if !RECT_PROPS.iter().all(|name| props.contains_key(*name)) {
+            return Ok(None);
+        }
+        let scaling = PlaneScaling { fd, plane, props, src };
+        if scaling
+            .fd
+            .atomic_commit(&[AtomicCommitFlags::TestOnly, AtomicCommitFlags::AllowModeset], scaling.request(dst))
+            .is_err()
+        {
+            return Ok(None);
+        }
+        Ok(Some(scaling))
+    }
+
+    fn request(&self, dst: Rectangle<i32, Physical>) -> AtomicModeReq {
+        // the source rectangle is in 16.16 fixed point
+        let values = [
+            0,
+            0,
+            (self.src.0 as property::RawValue) << 16,
+            (self.src.1 as property::RawValue) << 16,
+            dst.loc.x as property::RawValue,
+            dst.loc.y as property::RawValue,
+            dst.size.w as property::RawValue,
+            dst.size.h as property::RawValue,
+        ];
+        let mut req = AtomicModeReq::new();
+        for (name, value) in RECT_PROPS.iter().zip(values.iter()) {
+            req.add_property(self.plane, self.props[*name], property::Value::UnsignedRange(*value));
+        }
+        req
+    }
+
+    /// Shows the whole frame in `dst` of the crtc
+    pub fn apply(&self, dst: Rectangle<i32, Physical>) -> Result<()> {
+        self.fd
+            .atomic_commit(&[AtomicCommitFlags::AllowModeset], self.request(dst))
+            .context("Failed to set the rectangles of the plane")?;
+        Ok(())
+    }
+}
+
+/// Kind of a plane, as values of its "type" property
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneType {
+    Overlay,
+    Primary,
+    Cursor,
+}
+
+impl PlaneType {
+    fn from_raw(value: property::RawValue) -> Option<PlaneType> {
+        match value {
+            0 => Some(PlaneType::Overlay),
+            1 => Some(PlaneType::Primary),
+            2 => Some(PlaneType::Cursor),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PlaneType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaneType::Overlay => write!(f, "overlay"),
+            PlaneType::Primary => write!(f, "primary"),
+            PlaneType::Cursor => write!(f, "cursor"),
+        }
+    }
+}
+
+/// A plane of the target device, as listed by `list-planes`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaneEntry {
+    pub handle: plane::Handle,
+    /// `None` if the driver does not report it
+    pub kind: Option<PlaneType>,
+    /// Crtcs the plane can be used with
+    pub crtcs: Vec<crtc::Handle>,
+    pub formats: Vec<DrmFourcc>,
+}
+
+impl PlaneEntry {
+    /// All planes of the device, which needs universal planes enabled to list more than the overlay planes
+    pub fn query_all(fd: &Fd) -> Result<Vec<PlaneEntry>> {
+        let res_handles = fd.resource_handles()?;
+        let mut entries = Vec::new();
+        for handle in fd.plane_handles()?.planes() {
+            let info = fd.get_plane(*handle)?;
+            let props = fd.get_properties(*handle)?;
+            let (handles, values) = props.as_props_and_values();
+            let mut kind = None;
+            for (prop, value) in handles.iter().zip(values.iter()) {
+                if fd.get_property(*prop)?.name().to_str() == Ok("type") {
+                    kind = PlaneType::from_raw(*value);
+                }
+            }
+            entries.push(PlaneEntry {
+                handle: *handle,
+                kind,
+                crtcs: res_handles.filter_crtcs(info.possible_crtcs()),
+                formats: info
+                    .formats()
+                    .iter()
+                    .filter_map(|code| DrmFourcc::try_from(*code).ok())
+                    .collect(),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Plane the stream of the target is bound to, given as id, "primary" or "overlay"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneSelection {
+    Primary,
+    /// The first overlay plane usable with the crtc
+    Overlay,
+    Id(u32),
+}
+
+impl FromStr for PlaneSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<PlaneSelection> {
+        match input {
+            "primary" => Ok(PlaneSelection::Primary),
+            "overlay" => Ok(PlaneSelection::Overlay),
+            id => id
+                .parse::<u32>()
+                .map(PlaneSelection::Id)
+                .map_err(|_| anyhow::anyhow!("Plane needs to be an id, \"primary\" or \"overlay\": {}", id)),
+        }
+    }
+}
+
+/// Picks the plane `selection` refers to among `planes`, explaining why if it can't be used with `crtc`
+pub fn select_plane(planes: &[PlaneEntry], selection: PlaneSelection, crtc: crtc::Handle) -> Result<plane::Handle> {
+    let usable = |entry: &&PlaneEntry| entry.crtcs.contains(&crtc);
+    let first_of = |wanted: PlaneType| {
+        planes
+            .iter()
+            .filter(|entry| entry.kind == Some(wanted))
+            .find(usable)
+            .map(|entry| entry.handle)
+            .with_context(|| format!("No {} plane can be used with crtc {}, see list-planes", wanted, u32::from(crtc)))
+    };
+    let id = match selection {
+        PlaneSelection::Primary => return first_of(PlaneType::Primary),
+        PlaneSelection::Overlay => return first_of(PlaneType::Overlay),
+        PlaneSelection::Id(id) => id,
+    };
+    let entry = planes
+        .iter()
+        .find(|entry| u32::from(entry.handle) == id)
+        .with_context(|| format!("The gpu has no plane {}, see list-planes", id))?;
+    if !usable(&entry) {
+        anyhow::bail!(
+            "Plane {} can't be used with crtc {}, the driver only allows it on crtcs {:?}",
+            id,
+            u32::from(crtc),
+            entry.crtcs.iter().map(|crtc| u32::from(*crtc)).collect::<Vec<_>>()
+        );
+    }
+    if entry.kind == Some(PlaneType::Cursor) {
+        anyhow::bail!("Plane {} is a cursor plane, which can't show frames of the size of the mode", id);
+    }
+    Ok(entry.handle)
+}
+
+/// The cursor plane of a crtc, showing the cursor independent of the frames
+pub struct HardwareCursor {
+    fd: Fd,
+    crtc: crtc::Handle,
+    buffer: Option<DumbBuffer>,
+    hotspot: (i32, i32),
+    /// Where the cursor was last put, `None` while hidden
+    position: Option<(i32, i32)>,
+}
+
+impl HardwareCursor {
+    /// Uploads `image` for the cursor plane of `crtc`, fails if the driver has none or the image does not fit it
+    pub fn new(fd: Fd, crtc: crtc::Handle, image: &CursorImage) -> Result<HardwareCursor> {
+        let width = fd.get_driver_capability(DriverCapability::CursorWidth).unwrap_or(DEFAULT_CURSOR_SIZE) as u32;
+        let height = fd.get_driver_capability(DriverCapability::CursorHeight).unwrap_or(DEFAULT_CURSOR_SIZE) as u32;
+        if image.width > width || image.height > height {
+            anyhow::bail!(
+                "Cursor of {}x{} exceeds the cursor plane of {}x{}",
+                image.width,
+                image.height,
+                width,
+                height
+            );
+        }
+        let mut buffer = fd
+            .create_dumb_buffer((width, height), DrmFourcc::Argb8888, 32)
+            .context("Failed to create cursor buffer")?;
+        let pitch = buffer.pitch() as usize;
+        {
+            let mut mapping = fd.map_dumb_buffer(&mut buffer).context("Failed to map cursor buffer")?;
+            // the rest of the plane stays transparent
+            mapping.as_mut().iter_mut().for_each(|byte| *byte = 0);
+            let row_len = image.width as usize * 4;
+            for (row, src) in image.pixels.chunks_exact(row_len).enumerate() {
+                mapping.as_mut()[row * pitch..row * pitch + row_len].copy_from_slice(src);
+            }
+        }
+        Ok(HardwareCursor {
+            fd,
+            crtc,
+            buffer: Some(buffer),
+            hotspot: image.hotspot,
+            position: None,
+        })
+    }
+
+    /// Puts the hotspot of the cursor at `position` of the crtc, `None` hides it
+    pub fn show(&mut self, position: Option<(i32, i32)>) -> Result<()> {
+        if position == self.position {
+            return Ok(());
+        }
+        match position {
+            Some(position) => {
+                if self.position.is_none() {
+                    self.fd
+                        .set_cursor2(self.crtc, self.buffer.as_ref(), self.hotspot)
+                        .context("Failed to show the cursor")?;
+                }
+                // the legacy call positions the top left corner
+                self.fd
+                    .move_cursor(self.crtc, (position.0 - self.hotspot.0, position.1 - self.hotspot.1))
+                    .context("Failed to move the cursor")?;
+            }
+            None => self
+                .fd
+                .set_cursor2::<DumbBuffer>(self.crtc, None, (0, 0))
+                .context("Failed to hide the cursor")?,
+        }
+        self.position = position;
+        Ok(())
+    }
+}
+
+impl Drop for HardwareCursor {
+    fn drop(&mut self) {
+        let _ = self.show(None);
+        if let Some(buffer) = self.buffer.take() {
+            let _ = self.fd.destroy_dumb_buffer(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Properties of an HDMI connector of the i915 driver
+    fn properties() -> HashMap<String, (u32, PropertyKind)> {
+        let mut props = HashMap::new();
+        props.insert(
+            "Broadcast RGB".to_string(),
+            (
+                1,
+                PropertyKind::Enum(vec![
+                    ("Automatic".to_string(), 0),
+                    ("Full".to_string(), 1),
+                    ("Limited 16:235".to_string(), 2),
+                ]),
+            ),
+        );
+        props.insert("max bpc".to_string(), (2, PropertyKind::Range(8, 12)));
+        props.insert("vrr_capable".to_string(), (3, PropertyKind::Boolean));
+        props.insert("EDID".to_string(), (4, PropertyKind::Unsupported));
+        props
+    }
+
+    fn resolve(input: &str) -> Result<(u32, property::RawValue)> {
+        let assignment = input.parse::<PropertyAssignment>()?;
+        let (handle, kind) = find_property(&properties(), &assignment.name)?;
+        Ok((handle, resolve_value(&kind, &assignment)?))
+    }
+
+    #[test]
+    fn assignment_parsing() {
+        let assignment = "Broadcast RGB=Limited 16:235".parse::<PropertyAssignment>().unwrap();
+        assert_eq!(assignment.name, "Broadcast RGB");
+        assert_eq!(assignment.value, "Limited 16:235");
+        assert_eq!(assignment.to_string(), "Broadcast RGB=Limited 16:235");
+        // only the first = separates the value
+        assert_eq!("a=b=c".parse::<PropertyAssignment>().unwrap().value, "b=c");
+        for invalid in &["max bpc", "=8", "max bpc=", ""] {
+            assert!(invalid.parse::<PropertyAssignment>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn enum_names() {
+        assert_eq!(resolve("Broadcast RGB=Full").unwrap(), (1, 1));
+        assert_eq!(resolve("Broadcast RGB=Limited 16:235").unwrap(), (1, 2));
+        let error = resolve("Broadcast RGB=full").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Invalid value \"full\" for \"Broadcast RGB\", expected one of \"Automatic\", \"Full\", \"Limited 16:235\""
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(resolve("max bpc=10").unwrap(), (2, 10));
+        assert_eq!(resolve("max bpc=12").unwrap(), (2, 12));
+        assert!(resolve("max bpc=16").is_err());
+        assert!(resolve("max bpc=ten").is_err());
+        let signed = PropertyKind::SignedRange(-5, 5);
+        assert_eq!(signed.resolve("-5"), Some(-5i64 as property::RawValue));
+        assert_eq!(signed.resolve("6"), None);
+        assert_eq!(signed.to_string(), "a number between -5 and 5");
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(resolve("vrr_capable=true").unwrap(), (3, 1));
+        assert_eq!(resolve("vrr_capable=0").unwrap(), (3, 0));
+        assert!(resolve("vrr_capable=yes").is_err());
+    }
+
+    #[test]
+    fn unsupported_kinds() {
+        assert_eq!(
+            resolve("EDID=0").unwrap_err().to_string(),
+            "Invalid value \"0\" for \"EDID\", expected nothing settable"
+        );
+    }
+
+    #[test]
+    fn unknown_names() {
+        assert_eq!(
+            resolve("Colorspace=BT2020_RGB").unwrap_err().to_string(),
+            "Connector has no property \"Colorspace\", it has: Broadcast RGB, EDID, max bpc, vrr_capable"
+        );
+    }
+}
@@ -0,0 +1,3551 @@
+//! Screen mirroring to nvidia gpus using the wayland export-dmabuf protocol.
+//!
+//! A session is set up from `Options` and run through `ScreenCopy`, which reports its state through
+//! `ScreenCopy::on_event` and is ended by a `StopHandle`. The nvscreencopy binary is a thin command line
+//! interface over it.
+
+use anyhow::Context;
+use calloop::{
+    generic::Generic,
+    ping::{make_ping, Ping, PingSource},
+    signals::{Signal, Signals},
+    timer::{Timeout, Timer, TimerHandle},
+    Dispatcher, EventLoop, Interest, LoopHandle, LoopSignal, PostAction, RegistrationToken,
+};
+use sctk::environment::Environment;
+use slog::o;
+use smithay::{
+    backend::{
+        allocator::{Format, Fourcc},
+        drm::{DrmDevice, DrmEvent},
+        renderer::{
+            gles2::Gles2Renderer,
+            ImportDma, Transform,
+        },
+        session::Signal as SessionSignal,
+        udev::UdevEvent,
+    },
+    reexports::drm::control::{crtc, Device, Mode},
+    signaling::{Linkable, Signaler},
+    utils::{Logical, Physical, Rectangle, Size},
+};
+use smithay_client_toolkit::{
+    self as sctk,
+    reexports::client::{
+        protocol::{wl_output, wl_shm},
+        Display,
+    },
+    reexports::protocols::wlr::unstable::{
+        export_dmabuf::v1::client::zwlr_export_dmabuf_manager_v1::ZwlrExportDmabufManagerV1 as ExportDmabufManager,
+        output_power_management::v1::client::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1 as OutputPowerManager,
+        screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1 as ScreencopyManager,
+    },
+};
+use wayland_client::{AnonymousObject, DispatchData, EventQueue, Main, RawEvent};
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    io::ErrorKind,
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+mod adjust;
+mod capture;
+mod compositor;
+mod constraints;
+#[doc(hidden)]
+pub mod convert;
+mod copy_path;
+mod cursor;
+mod damage;
+mod drm;
+mod edid;
+mod egl;
+mod events;
+mod gamma_lut;
+mod geometry;
+mod gl_debug;
+#[doc(hidden)]
+pub mod golden;
+mod gpu;
+mod hdr;
+mod import_cache;
+mod kms;
+mod linux_dmabuf;
+mod modeline;
+mod modifier;
+#[cfg(feature = "notify")]
+mod notify;
+#[cfg(feature = "nvenc")]
+mod nvenc;
+mod offscreen;
+mod output;
+mod overlay;
+mod pacing;
+mod persist;
+#[cfg(feature = "pipewire")]
+mod pipewire_node;
+#[cfg(feature = "portal")]
+mod portal;
+mod raw;
+mod render;
+mod scanout;
+mod screencopy;
+mod session;
+mod snapshot;
+mod sleep;
+mod source;
+mod source_match;
+mod source_power;
+mod splash;
+mod split;
+mod stats;
+mod status;
+mod streak;
+mod sway;
+mod test_pattern;
+mod trace;
+#[cfg(feature = "vulkan")]
+mod vulkan;
+mod watchdog;
+use self::capture::CaptureBackend;
+use self::drm::{wl_drm, WlDrmHandler};
+use self::linux_dmabuf::{zwp_linux_dmabuf_v1, LinuxDmabufHandler};
+use self::streak::Verdict;
+
+pub use self::{
+    adjust::Adjustments,
+    capture::{CaptureBackendKind, CaptureRate, QueuePolicy},
+    constraints::parse_fourcc,
+    copy_path::CopyPathKind,
+    cursor::CursorMode,
+    damage::IdleDetect,
+    edid::Edid,
+    egl::{OutputLayerKind, StreamOptions, DEFAULT_ACQUIRE_TIMEOUT_USEC, MAX_FIFO_LENGTH},
+    events::{Event, FrameStats},
+    geometry::{parse_transform, FilterKind, Layout, Placement, TRANSFORMS},
+    gpu::{ColorDepth, ConnectorEntry, GpuEntry, GpuVerdict, TargetBackendKind},
+    hdr::{HdrMetadata, HdrMetadataSource},
+    kms::{PlaneEntry, PlaneSelection, PlaneType, PropertyAssignment},
+    modeline::parse as parse_modeline,
+    output::OutputKind,
+    raw::{RawDestination, RawHeader},
+    render::{Downscale, SwapFailurePolicy},
+    session::SessionKind,
+    source::SourceSpec,
+    source_match::{OutputProps, SourceSelector},
+    split::{SplitCommand, TargetSpec},
+    status::{MirrorState, Status, StatusImportFailure, StatusMode},
+    sway::HeadlessMode,
+};
+
+struct Env {
+    outputs: sctk::output::OutputHandler,
+    export_dmabuf: sctk::environment::SimpleGlobal<ExportDmabufManager>,
+    screencopy: sctk::environment::SimpleGlobal<ScreencopyManager>,
+    output_power: sctk::environment::SimpleGlobal<OutputPowerManager>,
+    shm: sctk::environment::SimpleGlobal<wl_shm::WlShm>,
+    drm: WlDrmHandler,
+    linux_dmabuf: LinuxDmabufHandler,
+}
+
+sctk::environment!(Env,
+    singles = [
+        ExportDmabufManager => export_dmabuf,
+        ScreencopyManager => screencopy,
+        OutputPowerManager => output_power,
+        wl_shm::WlShm => shm,
+        wl_drm::WlDrm => drm,
+        zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1 => linux_dmabuf,
+    ],
+    multis = [
+        wl_output::WlOutput => outputs,
+    ]
+);
+
+impl sctk::output::OutputHandling for Env {
+    fn listen<F: FnMut(wl_output::WlOutput, &sctk::output::OutputInfo, wayland_client::DispatchData) + 'static>(
+        &mut self,
+        f: F,
+    ) -> sctk::output::OutputStatusListener {
+        self.outputs.listen(f)
+    }
+}
+
+/// Path frames take from the compositor to the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyState {
+    /// Flipped onto the output as is, without rendering
+    Scanout,
+    /// Imported by the target gpu as is
+    DirectImport,
+    /// Copied into cpu memory by the copy engine of the render gpu
+    #[cfg(feature = "vulkan")]
+    Vulkan,
+    CPUCopy,
+}
+
+/// How the render gpu makes a frame readable for the cpu copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadbackRoute {
+    /// Binding the dmabuf as framebuffer
+    Bind,
+    /// Drawing the dmabuf, imported as an EGLImage, into an offscreen framebuffer
+    Blit,
+}
+
+pub struct WaylandState {
+    /// `None` while no monitor is plugged into the target connector
+    target: Option<gpu::TargetGPU>,
+    render: Option<gpu::RenderGPU>,
+    /// Outputs captured and composited onto the target, in the order given on the command line
+    sources: Vec<source::Source>,
+    /// Rendered frames held back until the gpus finished reading them
+    releasing: VecDeque<capture::ReleasingFrame>,
+    pipeline_depth: usize,
+    /// Captures to repeat after failures
+    retry: capture::Retry,
+    /// Recover from gpu resets instead of failing on the first failed render
+    robustness: bool,
+    /// Consecutive failed renders
+    render_failures: u32,
+    capture_rate: CaptureRate,
+    queue_policy: QueuePolicy,
+    /// A swapped frame was not flipped yet
+    swap_pending: bool,
+    /// Capture time of the newest frame in the textures waiting for the flip, only with a fixed capture rate
+    latest_frame: Option<Duration>,
+    /// Consecutive temporary swap errors, by their kind
+    swap_failures: HashMap<&'static str, streak::Streak>,
+    swap_failure_limit: Option<u32>,
+    swap_failure_policy: render::SwapFailurePolicy,
+    /// Why the last frame was dropped, logged by the event loop
+    frame_error: Option<anyhow::Error>,
+    /// Something we can't recover from, the event loop stops on it
+    fatal: Option<anyhow::Error>,
+    /// The target gpu was reset and needs to be rebuilt
+    target_lost: bool,
+    /// The session or a VT switch gave the target device to somebody else, nothing is captured until it comes back
+    target_paused: bool,
+    dest_size: Size<i32, Physical>,
+    /// Region of the source to mirror, only allowed with a single source
+    crop: Option<Rectangle<i32, Logical>>,
+    /// Shows the single source unscaled, placed again whenever the target or source change size
+    placement: Option<geometry::Placement>,
+    /// Undo the transform of the source outputs, so their content is shown upright
+    orientation_follow_source: bool,
+    /// Arranges the sources in cells, placed again whenever the target changes size
+    layout: Option<geometry::Layout>,
+    /// Labels the cells of `layout` whose source is gone
+    labels: Option<overlay::Overlay>,
+    /// How the textures of the sources are sampled when scaled onto the target
+    filter: geometry::FilterKind,
+    /// Scale in linear light instead of on the sRGB values, through `adjust_shader` and the render gpu
+    linear_blending: bool,
+    /// Only update the regions of the textures that changed
+    damage_tracking: bool,
+    /// How unchanged frames are found, which are skipped
+    idle_detect: IdleDetect,
+    /// Path the last frame took
+    copy: Option<CopyState>,
+    copy_path: copy_path::CopyPath,
+    /// Compares the first imported frame against a readback through the render gpu
+    verify_import: bool,
+    /// Flips captured frames onto the target, `None` without a target or if only other paths are allowed
+    scanout: Option<scanout::Scanout>,
+    readback_route: Option<ReadbackRoute>,
+    /// Formats the compositor and the target gpu have in common
+    import_formats: HashSet<Format>,
+    /// Buffers the target can consume, picked among the ones the compositor offers
+    constraints: constraints::CaptureConstraints,
+    /// Fail on yuv frames instead of converting them
+    reject_yuv: bool,
+    /// Read back frames through pixel buffers, trading a frame of latency for throughput
+    async_readback: bool,
+    /// When frames are scaled to the size they are shown in before they are read back
+    downscale: render::Downscale,
+    /// Converts read back frames off the event loop, `None` converts them in place
+    converter: Option<convert::Converter<render::Readback>>,
+    /// Depth frames are kept in up to the target, the same as the scanout buffer
+    color_depth: gpu::ColorDepth,
+    stats: stats::Stats,
+    /// Logs the stages of every frame, set by `--trace-frames`
+    tracer: Option<trace::FrameTracer>,
+    /// Color of the target where the source is not shown
+    background: [f32; 4],
+    /// Shown instead of the background while there is nothing to mirror
+    splash: Option<splash::Splash>,
+    adjustments: adjust::Adjustments,
+    /// Applies `adjustments` and `linear_blending`, only created if either is needed
+    adjust_shader: Option<adjust::AdjustShader>,
+    /// Status box drawn on top of the mirrored content
+    overlay: Option<overlay::Overlay>,
+    /// Has the compositor draw the cursor into the captured frames
+    overlay_cursor: bool,
+    /// Cursor on the cursor plane of the target, `None` leaves it to the compositor
+    cursor: Option<cursor::CursorPlane>,
+    /// Delays swaps to a fixed latency after capture, `None` swaps right away
+    pacing: Option<pacing::Pacing>,
+    /// Encodes the frames shown on the output into a file
+    #[cfg(feature = "nvenc")]
+    recorder: Option<nvenc::Recorder>,
+    events: events::Events,
+    log: slog::Logger,
+}
+
+/// Everything tied to a single connection to the compositor
+struct Connection {
+    token: RegistrationToken,
+    /// Events of the capture backend outside of the wayland connection
+    capture_token: Option<RegistrationToken>,
+    display: Display,
+    event_queue: EventQueue,
+    environment: Environment<Env>,
+    capture: Box<dyn CaptureBackend>,
+    /// Output of every source, `None` while waiting for it to reappear
+    outputs: Vec<OutputSlot>,
+    _output_listeners: Vec<sctk::output::OutputStatusListener>,
+    /// Turns the sources off while idle, `None` without `--manage-source-power`
+    power: Option<source_power::SourcePower>,
+}
+
+struct CalloopState {
+    wayland_state: WaylandState,
+    /// `None` while the compositor is gone
+    connection: Option<Connection>,
+    handle: LoopHandle<'static, CalloopState>,
+    /// Stops the event loop, so `run` tears everything down
+    signal: LoopSignal,
+    /// Swaps frames held back by `--frame-pacing`
+    swap_timer: TimerHandle<()>,
+    capture_kind: CaptureBackendKind,
+    /// Set while any source is missing
+    source_lost_since: Option<Instant>,
+    source_timeout: Duration,
+    /// Grace period of `--manage-source-power`
+    source_power_grace: Option<Duration>,
+    /// Reads frames back on this device instead of the one of the compositor
+    render_device: Option<PathBuf>,
+    /// When mirroring started, for the uptime of the `Status`
+    started: Instant,
+    /// Last state `track_state` emitted
+    mirror_state: Option<MirrorState>,
+    /// Frames to show before stopping, `None` runs until stopped
+    frame_limit: Option<u64>,
+    /// Restarts stalled captures, `None` if disabled
+    watchdog: Option<watchdog::Watchdog>,
+    /// Reconnect instead of failing, if the compositor goes away
+    reconnect: bool,
+    disconnected: bool,
+    reconnect_delay: Duration,
+    next_reconnect: Instant,
+    error: Option<anyhow::Error>,
+    /// Owns the target device, when taken from logind
+    _session: session::Session,
+    session_events: session::SessionEvents,
+    /// Links the drm events of the target to the session, to pause them while it is inactive
+    signaler: Option<Signaler<SessionSignal>>,
+    target_config: TargetConfig,
+    /// Drm events of the target, `None` while there is no target
+    target_token: Option<RegistrationToken>,
+    /// Notices the system waking up, after which everything is set up again
+    sleep: sleep::SleepDetector,
+    /// `reinitialize` is due once the target is not paused
+    reinit_pending: bool,
+    next_reinit: Instant,
+    /// Failed `reinitialize`s in a row
+    reinit_failures: streak::Streak,
+    /// When `check_link` looks at the connector again
+    next_link_check: Instant,
+    /// The link failed in the lowest mode, which is only reported once
+    link_exhausted: bool,
+    /// Wakes the main loop for checks no event wakes it for, see `schedule_housekeeping`
+    housekeeping_timer: TimerHandle<()>,
+    /// Deadline the housekeeping timer is armed for
+    housekeeping: Option<(Instant, Timeout)>,
+}
+
+/// Everything needed to set up the target again, after a monitor was plugged into its connector
+struct TargetConfig {
+    fd: gpu::Fd,
+    options: gpu::TargetOptions,
+    /// Renders into a framebuffer of `options.mode` instead of onto the connector
+    offscreen: bool,
+}
+
+/// Compositors usually rotate through two or three buffers per output
+const IMPORT_CACHE_SIZE: usize = 4;
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// How often `--wait-for-connector` checks for a monitor at startup
+const CONNECTOR_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often `--wait-for-gpu` checks for the gpu, and how often it says it is still waiting
+const GPU_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const GPU_NOTICE_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval of the snapshots of `--stats-file` and `--stats-socket`
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+/// Delay between attempts to reinitialize, the gpu may need a moment after a resume
+const REINIT_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Failed attempts to reinitialize, after which we give up
+const REINIT_ATTEMPTS: u32 = 5;
+/// Checks for becoming drm master again while paused, if no session tells us
+const MASTER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the link of the connector is checked, not every driver sends a hotplug event when it goes bad
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The currently captured output of a source, shared with the output listener
+type OutputSlot = Rc<RefCell<Option<wl_output::WlOutput>>>;
+
+/// Finds the output `spec` selects and returns it along its current mode, see `source::select_output`
+fn find_output(
+    environment: &Environment<Env>,
+    spec: &SourceSpec,
+) -> anyhow::Result<Option<(wl_output::WlOutput, sctk::output::Mode)>> {
+    let (outputs, entries): (Vec<_>, Vec<_>) = list_outputs(environment).into_iter().unzip();
+    Ok(source::select_output(&entries, spec)?.and_then(|index| current_mode(&outputs[index])))
+}
+
+/// `output` along its current mode, `None` if it has none
+fn current_mode(output: &wl_output::WlOutput) -> Option<(wl_output::WlOutput, sctk::output::Mode)> {
+    sctk::output::with_output_info(output, |info| info.modes.iter().find(|mode| mode.is_current).cloned())
+        .flatten()
+        .map(|mode| (output.clone(), mode))
+}
+
+/// Outputs of the compositor, to select sources from and to tell what could be mirrored instead of a missing source
+fn list_outputs(environment: &Environment<Env>) -> Vec<(wl_output::WlOutput, source::OutputEntry)> {
+    environment
+        .get_all_outputs()
+        .into_iter()
+        .filter_map(|output| {
+            let entry = sctk::output::with_output_info(&output, |info| {
+                if info.obsolete {
+                    return None;
+                }
+                Some(source::OutputEntry {
+                    make: info.make.clone(),
+                    model: info.model.clone(),
+                    global_id: info.id,
+                    mode: info
+                        .modes
+                        .iter()
+                        .find(|mode| mode.is_current)
+                        .map(|mode| (mode.dimensions.0, mode.dimensions.1, mode.refresh_rate)),
+                })
+            })
+            .flatten()?;
+            Some((output, entry))
+        })
+        .collect()
+}
+
+/// Every output matched by one of `specs`, for `--layout`.
+///
+/// Each is selected by its whole make from then on, so it keeps its cell when it goes away and comes back.
+fn expand_sources(environment: &Environment<Env>, specs: &[SourceSpec]) -> anyhow::Result<Vec<SourceSpec>> {
+    let outputs = list_outputs(environment)
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+    let mut expanded: Vec<(usize, SourceSpec)> = Vec::new();
+    for spec in specs {
+        let matching = outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| spec.matches(output.props()))
+            .enumerate()
+            .filter(|(i, _)| spec.index.map(|index| index == *i).unwrap_or(true));
+        for (_, (index, output)) in matching {
+            if expanded.iter().any(|(other, _)| *other == index) {
+                continue;
+            }
+            if let SourceSelector::GlobalId(_) = spec.selector {
+                // selects a single output already
+                expanded.push((
+                    index,
+                    SourceSpec {
+                        position: None,
+                        ..spec.clone()
+                    },
+                ));
+                continue;
+            }
+            // outputs sharing their make are told apart by their order
+            let same_make = outputs[..index].iter().filter(|other| other.make == output.make).count();
+            let total = outputs.iter().filter(|other| other.make == output.make).count();
+            expanded.push((
+                index,
+                SourceSpec {
+                    selector: SourceSelector::Exact(output.make.clone()),
+                    position: None,
+                    index: Some(same_make).filter(|_| total > 1),
+                },
+            ));
+        }
+    }
+    if expanded.is_empty() {
+        let listing = outputs
+            .iter()
+            .map(|output| format!("\n  {}", output))
+            .collect::<String>();
+        anyhow::bail!("No output matches the sources of --layout, outputs of the compositor:{}", listing);
+    }
+    Ok(expanded.into_iter().map(|(_, spec)| spec).collect())
+}
+
+/// Handles a source that matched no output.
+///
+/// If the default source is missing and a single output besides built-in panels exists, `auto_source`
+/// mirrors that one instead and updates `spec` accordingly. Otherwise this fails, listing the outputs there are.
+fn missing_source(
+    environment: &Environment<Env>,
+    spec: &mut SourceSpec,
+    auto_source: bool,
+    log: &slog::Logger,
+) -> anyhow::Result<(wl_output::WlOutput, sctk::output::Mode)> {
+    let (handles, outputs): (Vec<_>, Vec<_>) = list_outputs(environment).into_iter().unzip();
+    if outputs.is_empty() {
+        anyhow::bail!("Unable to find source output {}, the compositor has no outputs", spec.selector);
+    }
+    let listing = outputs
+        .iter()
+        .map(|output| format!("\n  {}", output))
+        .collect::<String>();
+    if !spec.is_default() {
+        anyhow::bail!("Unable to find source output {}, outputs of the compositor:{}", spec.selector, listing);
+    }
+    match source::fallback_source(&outputs) {
+        Some(index) if auto_source => {
+            slog::warn!(log, "No headless output found, mirroring {} instead", outputs[index]);
+            spec.selector = SourceSelector::Exact(outputs[index].make.clone());
+            current_mode(&handles[index]).with_context(|| format!("Source output {} has no mode", outputs[index]))
+        }
+        Some(index) => anyhow::bail!(
+            "Unable to find a headless output to mirror. Use --source \"{}\" or --auto-source to mirror {}, outputs of the compositor:{}",
+            outputs[index].make,
+            outputs[index],
+            listing
+        ),
+        None => anyhow::bail!(
+            "Unable to find a headless output to mirror, pick one with --source. Outputs of the compositor:{}",
+            listing
+        ),
+    }
+}
+
+/// Connects to the compositor and collects its globals
+fn connect_environment() -> anyhow::Result<(Display, EventQueue, Environment<Env>)> {
+    let display = Display::connect_to_env()
+        .with_context(|| "Failed to connect to the wayland compositor")?;
+    let mut event_queue = display.create_event_queue();
+    let attached_display = display.attach(event_queue.token());
+    let environment = Environment::new(
+        &attached_display,
+        &mut event_queue,
+        Env {
+            outputs: sctk::output::OutputHandler::new(),
+            export_dmabuf: sctk::environment::SimpleGlobal::new(),
+            screencopy: sctk::environment::SimpleGlobal::new(),
+            output_power: sctk::environment::SimpleGlobal::new(),
+            shm: sctk::environment::SimpleGlobal::new(),
+            drm: WlDrmHandler::new(),
+            linux_dmabuf: LinuxDmabufHandler::new(),
+        },
+    )?;
+    Ok((display, event_queue, environment))
+}
+
+/// Binds the output power manager for `--manage-source-power`, `None` if the compositor lacks it
+fn source_power(
+    environment: &Environment<Env>,
+    grace: Duration,
+    log: &slog::Logger,
+) -> Option<source_power::SourcePower> {
+    match environment.get_global::<OutputPowerManager>() {
+        Some(manager) => Some(source_power::SourcePower::new(manager, grace, log.clone())),
+        None => {
+            slog::warn!(
+                log,
+                "The compositor lacks zwlr_output_power_manager_v1, the source outputs stay on while idle"
+            );
+            None
+        }
+    }
+}
+
+/// Globals of the compositor a session needs, resolved once right after connecting
+struct Globals {
+    capture: Box<dyn CaptureBackend>,
+    /// Device node of the compositors gpu, only if the capture backend reads back frames on it
+    drm_path: Option<PathBuf>,
+}
+
+/// Checks the compositor advertises everything the capture backend `kind` needs,
+/// before anything is done to the target.
+fn check_globals(
+    environment: &Environment<Env>,
+    event_queue: &mut EventQueue,
+    kind: CaptureBackendKind,
+    log: &slog::Logger,
+) -> anyhow::Result<Globals> {
+    let export_dmabuf = environment.get_global::<ExportDmabufManager>();
+    let screencopy = environment.get_global::<ScreencopyManager>();
+    let shm = environment.get_global::<wl_shm::WlShm>();
+    // only frames read back on the compositors gpu need its device
+    let drm_path = match kind {
+        CaptureBackendKind::Screencopy => None,
+        CaptureBackendKind::Auto if export_dmabuf.is_none() => None,
+        CaptureBackendKind::Auto => wl_drm_path(environment, event_queue, log).unwrap_or_else(|err| {
+            slog::warn!(log, "{:#}", err);
+            None
+        }),
+        _ => wl_drm_path(environment, event_queue, log)?,
+    };
+    if kind == CaptureBackendKind::Portal {
+        // dmabufs of the stream are read back on the compositors gpu just like export-dmabuf frames
+        if drm_path.is_none() {
+            return Err(compositor::missing_global("wl_drm", "to read back portal frames"));
+        }
+        return Ok(Globals {
+            capture: portal_backend(log)?,
+            drm_path,
+        });
+    }
+    // export-dmabuf frames are read back on the compositors gpu, which is found through wl_drm
+    let export_dmabuf = match (kind, export_dmabuf, drm_path.is_some()) {
+        (CaptureBackendKind::Screencopy, _, _) | (CaptureBackendKind::Portal, _, _) => None,
+        (_, Some(manager), true) => Some(manager),
+        (CaptureBackendKind::ExportDmabuf, None, _) => {
+            return Err(compositor::missing_global("zwlr_export_dmabuf_manager_v1", "by --capture-backend export-dmabuf"))
+        }
+        (CaptureBackendKind::ExportDmabuf, Some(_), false) => {
+            return Err(compositor::missing_global("wl_drm", "to read back export-dmabuf frames"))
+        }
+        (CaptureBackendKind::Auto, _, _) => None,
+    };
+    let capture: Box<dyn CaptureBackend> = match (export_dmabuf, screencopy, shm) {
+        (Some(manager), _, _) => Box::new(capture::ExportDmabufBackend::new(manager)),
+        (None, Some(manager), Some(shm)) => {
+            // frames only offer several buffer types to pick from since version 3
+            slog::info!(log, "Capturing through zwlr_screencopy_manager_v1 version {}", manager.as_ref().version());
+            Box::new(screencopy::ScreencopyBackend::new(manager, shm))
+        }
+        (None, Some(_), None) => return Err(compositor::missing_global("wl_shm", "for screencopy frames")),
+        (None, None, _) if kind == CaptureBackendKind::Screencopy => {
+            return Err(compositor::missing_global("zwlr_screencopy_manager_v1", "by --capture-backend screencopy"))
+        }
+        (None, None, _) => {
+            // name what is actually missing of the export-dmabuf path
+            let export_dmabuf = if environment.get_global::<ExportDmabufManager>().is_some() {
+                "wl_drm for its zwlr_export_dmabuf_manager_v1"
+            } else {
+                "zwlr_export_dmabuf_manager_v1"
+            };
+            return Err(compositor::missing_global(
+                &format!("{} nor zwlr_screencopy_manager_v1", export_dmabuf),
+                "to capture outputs",
+            ));
+        }
+    };
+    Ok(Globals {
+        drm_path: if capture.needs_render_gpu() { drm_path } else { None },
+        capture,
+    })
+}
+
+/// Device node of the compositors gpu from wl_drm, `None` if the compositor does not advertise it.
+///
+/// The device is sent after binding the global, which a busy compositor may not get to within the first roundtrips.
+fn wl_drm_path(
+    environment: &Environment<Env>,
+    event_queue: &mut EventQueue,
+    log: &slog::Logger,
+) -> anyhow::Result<Option<PathBuf>> {
+    if !environment.with_inner(|env| env.drm.advertised()) {
+        return Ok(None);
+    }
+    // known already when capturing is set up again, and dispatching without the WaylandState
+    // would then hand events of frames to handlers that need it
+    if let Some(path) = environment.with_inner(|env| env.drm.path()) {
+        return Ok(Some(PathBuf::from(path)));
+    }
+    let arrived = drm::dispatch_until(
+        event_queue,
+        drm::EVENT_TIMEOUT,
+        || environment.with_inner(|env| env.drm.path().is_some()),
+        log,
+    )
+    .context("Failed to receive the wl_drm device")?;
+    if !arrived {
+        return Err(compositor::missing_drm_device(drm::EVENT_TIMEOUT));
+    }
+    Ok(environment.with_inner(|env| env.drm.path()).map(PathBuf::from))
+}
+
+#[cfg(feature = "portal")]
+fn portal_backend(log: &slog::Logger) -> anyhow::Result<Box<dyn CaptureBackend>> {
+    Ok(Box::new(portal::PortalBackend::new(log.clone())?))
+}
+
+#[cfg(not(feature = "portal"))]
+fn portal_backend(_log: &slog::Logger) -> anyhow::Result<Box<dyn CaptureBackend>> {
+    anyhow::bail!("--capture-backend portal needs nvscreencopy built with --features portal")
+}
+
+/// Initializes the compositors gpu at `path` from wl_drm, if the capture backend needs to read back frames on it
+fn connect_render_gpu(
+    environment: &Environment<Env>,
+    event_queue: &mut EventQueue,
+    path: Option<PathBuf>,
+    device: Option<&Path>,
+    log: &slog::Logger,
+) -> anyhow::Result<Option<gpu::RenderGPU>> {
+    // the screencopy backend reads back on the compositor side
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    slog::info!(log, "Found wl gpu {}", path.display());
+    if let Some(device) = device {
+        return open_render_device(&path, device, log).map(Some);
+    }
+    // the compositor advertises its card node, the render node of the same gpu needs no authentication
+    match gpu::render_node(&path) {
+        Ok(Some(render)) => match gpu::Fd::open(&render) {
+            Ok(fd) => {
+                slog::info!(log, "Using render node {}", render.display());
+                return Ok(Some(gpu::init_render_gpu(fd, log.clone())?));
+            }
+            Err(err) => slog::warn!(log, "Failed to open {}, using {}: {}", render.display(), path.display(), err),
+        },
+        Ok(None) => slog::info!(log, "{} has no render node, authenticating through wl_drm", path.display()),
+        Err(err) => slog::debug!(log, "Failed to look up the render node of {}: {}", path.display(), err),
+    }
+    let fd = gpu::Fd::open(&path)?;
+    // card nodes refuse most ioctls until the compositor authenticated us
+    environment.with_inner(|env| env.drm.authenticate(&path, &fd))?;
+    // no WaylandState to count orphan events in yet
+    let authenticated = drm::dispatch_until(
+        event_queue,
+        drm::EVENT_TIMEOUT,
+        || environment.with_inner(|env| env.drm.authenticated()),
+        log,
+    )
+    .with_context(|| "Compositor refused wl_drm authentication")?;
+    if !authenticated {
+        anyhow::bail!(
+            "Compositor did not authenticate us for {} within {:?}",
+            path.display(),
+            drm::EVENT_TIMEOUT
+        );
+    }
+    Ok(Some(gpu::init_render_gpu(fd, log.clone())?))
+}
+
+/// Reads frames back on `device` given by `--render-device` instead of the gpu `path` of the compositor.
+///
+/// Frames are imported across the devices then, which is checked with a probe buffer but only warned about.
+fn open_render_device(path: &Path, device: &Path, log: &slog::Logger) -> anyhow::Result<gpu::RenderGPU> {
+    // card nodes of other gpus can't be authenticated through wl_drm
+    let node = gpu::render_node(device).ok().flatten().unwrap_or_else(|| device.to_path_buf());
+    let fd = gpu::Fd::open(&node).with_context(|| format!("Failed to open render device {}", node.display()))?;
+    slog::info!(log, "Using render device {} instead of {}", node.display(), path.display());
+    let mut render = gpu::init_render_gpu(fd, log.clone())?;
+    match gpu::probe_import(path, &mut render.renderer) {
+        Ok(()) => slog::info!(log, "{} imports buffers of {}", node.display(), path.display()),
+        Err(err) => slog::warn!(
+            log,
+            "{} can't import a probe buffer of {}, copying frames will likely fail: {:#}",
+            node.display(),
+            path.display(),
+            err
+        ),
+    }
+    Ok(render)
+}
+
+/// Only try to import formats both sides actually support
+fn negotiate_formats(
+    environment: &Environment<Env>,
+    renderer: &Gles2Renderer,
+    log: &slog::Logger,
+) -> HashSet<Format> {
+    let compositor_formats = environment.with_inner(|env| env.linux_dmabuf.formats());
+    let import_formats = renderer
+        .dmabuf_formats()
+        .filter(|format| compositor_formats.contains(format))
+        .copied()
+        .collect::<HashSet<_>>();
+    if import_formats.is_empty() {
+        slog::info!(
+            log,
+            "No dmabuf format is supported by both the compositor ({} formats) and the nvidia gpu, skipping DirectImport",
+            compositor_formats.len()
+        );
+    } else {
+        slog::info!(log, "Negotiated DirectImport formats: {:?}", import_formats);
+    }
+    import_formats
+}
+
+/// Fallback for events no handler takes, e.g. of frames or outputs that were already destroyed on our side,
+/// or new events of a compositor advertising a newer version of an interface than we know.
+///
+/// They are counted in the stats and ignored, warning at most every so often.
+fn orphan_event(event: RawEvent, object: Main<AnonymousObject>, mut data: DispatchData) {
+    if let Some(state) = data.get::<WaylandState>() {
+        state.stats.orphan_event(event.interface, object.as_ref().id(), event.name, &state.log);
+    }
+}
+
+/// Stops the event loop on an error we can't recover from, `run` returns it after tearing down
+fn fail(state: &mut CalloopState, err: anyhow::Error) {
+    slog::error!(state.wayland_state.log, "{:#}", err);
+    state.wayland_state.events.emit(Event::Fatal(format!("{:#}", err)));
+    if state.error.is_none() {
+        state.error = Some(err);
+    }
+    state.signal.stop();
+}
+
+/// Errors indicating the compositor went away, as opposed to protocol errors on our side
+fn is_disconnect(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof
+    )
+}
+
+fn insert_display_source(
+    handle: &LoopHandle<'static, CalloopState>,
+    display: &Display,
+) -> anyhow::Result<RegistrationToken> {
+    handle
+        .insert_source(
+            Generic::from_fd(display.get_connection_fd(), Interest::READ, calloop::Mode::Level),
+            move |_, _, state: &mut CalloopState| {
+                slog::debug!(state.wayland_state.log, "Wayland event");
+                let connection = match state.connection.as_mut() {
+                    Some(connection) => connection,
+                    None => return Ok(PostAction::Disable),
+                };
+                match connection
+                    .event_queue
+                    .dispatch(&mut state.wayland_state, orphan_event)
+                {
+                    Ok(_) => Ok(PostAction::Continue),
+                    Err(e) if state.reconnect && is_disconnect(&e) => {
+                        state.disconnected = true;
+                        Ok(PostAction::Disable)
+                    }
+                    Err(e) => {
+                        fail(state, anyhow::anyhow!("I/O error on the Wayland display: {}", e));
+                        Ok(PostAction::Disable)
+                    }
+                }
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to add display to event loop")
+}
+
+fn insert_capture_source(
+    handle: &LoopHandle<'static, CalloopState>,
+    capture: &dyn CaptureBackend,
+) -> anyhow::Result<Option<RegistrationToken>> {
+    let fd = match capture.fd() {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    let token = handle
+        .insert_source(
+            Generic::from_fd(fd, Interest::READ, calloop::Mode::Level),
+            |_, _, state: &mut CalloopState| {
+                let connection = match state.connection.as_mut() {
+                    Some(connection) => connection,
+                    None => return Ok(PostAction::Disable),
+                };
+                match connection.capture.dispatch(&mut state.wayland_state) {
+                    Ok(()) => Ok(PostAction::Continue),
+                    Err(err) => {
+                        fail(state, err);
+                        Ok(PostAction::Disable)
+                    }
+                }
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to add capture backend to event loop")?;
+    Ok(Some(token))
+}
+
+/// Keeps track of the source with index `source` coming and going
+fn listen_for_source(
+    environment: &Environment<Env>,
+    source: usize,
+    spec: &SourceSpec,
+    slot: OutputSlot,
+) -> sctk::output::OutputStatusListener {
+    // a single output coming back is taken, even if `spec.index` picked among several before
+    let spec = spec.clone();
+    environment.listen_for_outputs(move |output, info, mut data| {
+        let state = match data.get::<WaylandState>() {
+            Some(state) => state,
+            None => return,
+        };
+        let mut slot = slot.borrow_mut();
+        if info.obsolete {
+            if slot.as_ref() == Some(&output) {
+                slog::info!(state.log, "Source output {} was removed", info.make);
+                *slot = None;
+                state.sources[source].source_lost.store(true, Ordering::SeqCst);
+            }
+        } else if slot.is_none()
+            && spec.matches(OutputProps {
+                make: &info.make,
+                model: &info.model,
+                global_id: info.id,
+            })
+        {
+            slog::info!(state.log, "Source output {} was added", info.make);
+            *slot = Some(output);
+        }
+    })
+}
+
+/// Listens for the outputs of all `sources`, which are stored into the slot of the same index
+fn listen_for_sources(
+    environment: &Environment<Env>,
+    sources: &[source::Source],
+    outputs: &[OutputSlot],
+) -> Vec<sctk::output::OutputStatusListener> {
+    sources
+        .iter()
+        .zip(outputs.iter())
+        .enumerate()
+        .map(|(index, (source, slot))| listen_for_source(environment, index, &source.spec, slot.clone()))
+        .collect()
+}
+
+/// Requests the next frame of every source that is there
+fn capture_sources(connection: &mut Connection, state: &mut WaylandState) {
+    if state.target_paused || state.target.is_none() {
+        return;
+    }
+    // an output that is off has no frames to capture
+    if let Some(power) = connection.power.as_mut() {
+        power.power_on();
+    }
+    for (index, slot) in connection.outputs.iter().enumerate() {
+        if let Some(output) = slot.borrow().as_ref() {
+            // the scale may change at any time and the crop region depends on it
+            if let Some((scale, transform)) =
+                sctk::output::with_output_info(output, |info| (info.scale_factor, info.transform))
+            {
+                state.sources[index].scale = scale;
+                if state.orientation_follow_source {
+                    source_transformed(state, index, geometry::output_transform(transform));
+                }
+            }
+            // the fixed rate timer ticks again, so nothing is lost skipping captures of unchanged sources
+            if state.capture_rate != CaptureRate::VBlank && !connection.capture.new_content(index, state) {
+                continue;
+            }
+            connection.capture.capture(index, output, state);
+        }
+    }
+}
+
+/// Follows the output of `source` being turned or mirrored, placing it again just like after a change of its mode
+fn source_transformed(state: &mut WaylandState, source: usize, transform: Transform) {
+    let current = &mut state.sources[source];
+    if current.transform == transform {
+        return;
+    }
+    slog::info!(
+        state.log,
+        "Source {} is transformed by {:?}, showing it upright",
+        current.spec.selector,
+        transform
+    );
+    current.transform = transform;
+    replace_source(state);
+}
+
+/// Drops the frames requested from the compositor and requests new ones
+fn restart_captures(connection: &mut Connection, state: &mut WaylandState) {
+    for source in state.sources.iter_mut() {
+        capture::abandon_frames(source);
+    }
+    capture_sources(connection, state);
+}
+
+/// Replaces the capture backend, the sources are then looked up again by the main loop
+fn reinitialize_capture(state: &mut CalloopState) -> anyhow::Result<()> {
+    let log = state.wayland_state.log.clone();
+    let connection = match state.connection.as_mut() {
+        Some(connection) => connection,
+        None => return Ok(()),
+    };
+    for source in state.wayland_state.sources.iter_mut() {
+        capture::abandon_frames(source);
+    }
+    if let Some(token) = connection.capture_token.take() {
+        state.handle.remove(token);
+    }
+    // the compositors gpu stays the same, so the render gpu is kept
+    let Globals { capture, .. } = check_globals(&connection.environment, &mut connection.event_queue, state.capture_kind, &log)?;
+    slog::info!(log, "Capture backend: {}", capture.name());
+    connection.capture_token = insert_capture_source(&state.handle, capture.as_ref())?;
+    connection.capture = capture;
+    for slot in connection.outputs.iter() {
+        *slot.borrow_mut() = None;
+    }
+    state.source_lost_since = Some(Instant::now());
+    Ok(())
+}
+
+/// Restarts capturing if frames stopped arriving, see `Watchdog`
+fn check_watchdog(state: &mut CalloopState) {
+    let now = Instant::now();
+    let capturing = capturing(state);
+    let wl_state = &state.wayland_state;
+    let watchdog = match state.watchdog.as_mut() {
+        Some(watchdog) => watchdog,
+        None => return,
+    };
+    if !capturing {
+        watchdog.idle(now);
+        return;
+    }
+    match watchdog.check(now, wl_state.stats.last_captured()) {
+        Some(watchdog::Action::Restart) => {
+            slog::warn!(
+                wl_state.log,
+                "No frame arrived in time, restarting capture ({} in a row)",
+                watchdog.firings()
+            );
+            state.wayland_state.stats.error("Capture stalled");
+            let connection = state.connection.as_mut().unwrap();
+            restart_captures(connection, &mut state.wayland_state);
+        }
+        Some(watchdog::Action::Reinitialize) => {
+            slog::warn!(wl_state.log, "Capture keeps stalling, setting up the capture backend again");
+            state.wayland_state.stats.error("Capture stalled");
+            if let Err(err) = reinitialize_capture(state) {
+                fail(state, err.context("Failed to set up the capture backend again"));
+            }
+        }
+        None => {}
+    }
+}
+
+/// Whether frames are expected to arrive, as far as the watchdog is concerned
+fn capturing(state: &CalloopState) -> bool {
+    let wl_state = &state.wayland_state;
+    state.connection.is_some()
+        && state.source_lost_since.is_none()
+        && !state.reinit_pending
+        && !wl_state.target_paused
+        && wl_state.target.is_some()
+}
+
+fn mirror_state(state: &CalloopState) -> MirrorState {
+    let wl_state = &state.wayland_state;
+    if state.connection.is_none() {
+        MirrorState::Disconnected
+    } else if wl_state.target.is_none() {
+        MirrorState::WaitingForMonitor
+    } else if wl_state.target_paused {
+        MirrorState::Paused
+    } else if state.source_lost_since.is_some() {
+        MirrorState::WaitingForSource
+    } else {
+        MirrorState::Mirroring
+    }
+}
+
+/// Emits `Event::StateChanged` whenever `iterate` left the session in another `MirrorState`
+fn track_state(state: &mut CalloopState) {
+    let current = mirror_state(state);
+    if state.mirror_state == Some(current) {
+        return;
+    }
+    state.mirror_state = Some(current);
+    let status = current_status(state, state.started.elapsed());
+    state.wayland_state.events.emit(Event::StateChanged(status));
+}
+
+/// Snapshot of what is going on, for `--stats-file` and `--stats-socket`
+fn current_status(state: &CalloopState, uptime: Duration) -> Status {
+    let wl_state = &state.wayland_state;
+    let mirror_state = mirror_state(state);
+    let sources = match state.connection.as_ref() {
+        Some(connection) => connection
+            .outputs
+            .iter()
+            .zip(wl_state.sources.iter())
+            .map(|(slot, source)| {
+                slot.borrow()
+                    .as_ref()
+                    .and_then(|output| sctk::output::with_output_info(output, |info| info.make.clone()))
+                    .unwrap_or_else(|| source.spec.selector.name())
+            })
+            .collect(),
+        None => wl_state
+            .sources
+            .iter()
+            .map(|source| source.spec.selector.name())
+            .collect(),
+    };
+    let (captured_fps, displayed_fps) = wl_state.stats.fps();
+    Status {
+        state: mirror_state,
+        sources,
+        connector: wl_state.target.as_ref().map(|target| target.connector_name.clone()),
+        mode: wl_state.target.as_ref().map(|target| StatusMode {
+            width: target.mode.0,
+            height: target.mode.1,
+        }),
+        captured_fps,
+        source_fps: wl_state.stats.source_fps(),
+        displayed_fps,
+        dropped_frames: wl_state.stats.frames_dropped(),
+        orphan_events: wl_state.stats.orphan_events(),
+        copy_path: wl_state.copy.map(|copy| format!("{:?}", copy)),
+        import_failure: wl_state.copy_path.failure().map(|failure| StatusImportFailure {
+            fourcc: format!("{:?}", failure.format.code),
+            modifier: format!("{:?}", failure.format.modifier),
+            planes: failure.planes,
+            width: failure.size.0,
+            height: failure.size.1,
+            reason: failure.reason.clone(),
+            garbage: failure.garbage,
+        }),
+        last_error: wl_state.stats.last_error().map(String::from),
+        uptime_secs: uptime.as_secs_f64(),
+    }
+}
+
+/// Whether every source has an output to capture
+fn all_sources_present(connection: &Connection) -> bool {
+    connection.outputs.iter().all(|slot| slot.borrow().is_some())
+}
+
+/// Drops everything tied to the dead compositor, the target keeps showing a black frame
+fn disconnect(state: &mut CalloopState) {
+    state.disconnected = false;
+    if let Some(connection) = state.connection.take() {
+        state.handle.remove(connection.token);
+        if let Some(token) = connection.capture_token {
+            state.handle.remove(token);
+        }
+    }
+    let wl_state = &mut state.wayland_state;
+    slog::warn!(wl_state.log, "Lost connection to the compositor, trying to reconnect");
+    wl_state.stats.error("Lost connection to the compositor");
+    // the fences belong to the render gpu, which is dropped below
+    wl_state.releasing.clear();
+    for source in wl_state.sources.iter_mut() {
+        source.frames.clear();
+        source.ready = None;
+        source.import_cache.clear();
+    }
+    wl_state.render = None;
+    if let Err(err) = render::blank(wl_state) {
+        slog::warn!(wl_state.log, "Failed to blank target: {}", err);
+    }
+    // the splash is meant to be seen
+    if wl_state.splash.is_none() {
+        set_target_dpms(wl_state, kms::Dpms::Off);
+    }
+    state.reconnect_delay = RECONNECT_MIN_DELAY;
+    state.next_reconnect = Instant::now() + state.reconnect_delay;
+}
+
+/// Redoes the compositor dependent setup, the source is then picked up by the main loop
+fn reconnect(state: &mut CalloopState) -> anyhow::Result<()> {
+    let log = state.wayland_state.log.clone();
+    let (display, mut event_queue, environment) = connect_environment()?;
+    let Globals { capture, drm_path } = check_globals(&environment, &mut event_queue, state.capture_kind, &log)?;
+    let render_device = state.render_device.as_deref();
+    let render = connect_render_gpu(&environment, &mut event_queue, drm_path, render_device, &log)?;
+    let import_formats = match state.wayland_state.target.as_ref() {
+        Some(target) => negotiate_formats(&environment, &target.renderer, &log),
+        // negotiated once the target is back
+        None => HashSet::new(),
+    };
+    let outputs = (0..state.wayland_state.sources.len())
+        .map(|_| Rc::new(RefCell::new(None)))
+        .collect::<Vec<OutputSlot>>();
+    let output_listeners = listen_for_sources(&environment, &state.wayland_state.sources, &outputs);
+    let power = state
+        .source_power_grace
+        .and_then(|grace| source_power(&environment, grace, &log));
+    let token = insert_display_source(&state.handle, &display)?;
+    let capture_token = insert_capture_source(&state.handle, capture.as_ref())?;
+
+    state.wayland_state.render = render;
+    state.wayland_state.import_formats = import_formats;
+    if let Some(target) = state.wayland_state.target.as_ref() {
+        let render = state.wayland_state.render.as_ref().map(|render| &render.renderer);
+        state.wayland_state.constraints.update(&target.renderer, render, &log);
+    }
+    state.wayland_state.copy = None;
+    state.wayland_state.copy_path.reset();
+    state.wayland_state.readback_route = None;
+    for source in state.wayland_state.sources.iter_mut() {
+        // the capture backend might differ, so start over with full frames
+        source.texture_content = None;
+        source.shown = false;
+    }
+    // the source is looked up again, just as if it vanished
+    state.source_lost_since = Some(Instant::now());
+    state.connection = Some(Connection {
+        token,
+        capture_token,
+        display,
+        event_queue,
+        environment,
+        capture,
+        outputs,
+        _output_listeners: output_listeners,
+        power,
+    });
+    Ok(())
+}
+
+fn create_adjust_shader(target: &mut gpu::TargetGPU) -> anyhow::Result<adjust::AdjustShader> {
+    target
+        .renderer
+        .with_context(|_renderer, gl| unsafe { adjust::AdjustShader::new(gl) })?
+        .with_context(|| "Failed to create color adjustment shader")
+}
+
+/// Powers the monitor on the target on or off, while nothing is mirrored it may go to sleep
+fn set_target_dpms(state: &mut WaylandState, dpms: kms::Dpms) {
+    // the device belongs to somebody else while paused
+    if state.target_paused {
+        return;
+    }
+    if let Some(target) = state.target.as_ref() {
+        if let Err(err) = target.set_dpms(dpms) {
+            slog::warn!(state.log, "Failed to switch the output {:?}: {}", dpms, err);
+        }
+    }
+}
+
+/// Creates the textures, shader and overlay on a new context of the target
+fn create_target_resources(state: &mut WaylandState) -> anyhow::Result<()> {
+    let target = state.target.as_mut().expect("No target to create resources on");
+    for source in state.sources.iter_mut() {
+        // a placeholder until the first upload, which fails for frames too large for the target
+        let max = target.gl.texture_limit();
+        source.upload_texture = render::create_texture(
+            &mut target.renderer,
+            target.gl,
+            source.frame_size.w.min(max),
+            source.frame_size.h.min(max),
+            state.color_depth,
+        )?;
+        source.texture = source.upload_texture.clone();
+        // the next upload allocates storage of the right size again
+        source.upload_storage = None;
+        source.texture_content = None;
+        source.texture_external = false;
+        // the new texture is empty until the next frame
+        source.shown = false;
+    }
+    if state.adjust_shader.is_some() {
+        state.adjust_shader = Some(create_adjust_shader(target)?);
+    }
+    if state.overlay.is_some() {
+        state.overlay = Some(
+            overlay::Overlay::new(&mut target.renderer, target.gl, state.dest_size)
+                .with_context(|| "Failed to create overlay")?,
+        );
+    }
+    if state.labels.is_some() {
+        state.labels = Some(
+            overlay::Overlay::new(&mut target.renderer, target.gl, state.dest_size)
+                .with_context(|| "Failed to create labels")?,
+        );
+    }
+    if let Some(splash) = state.splash.as_mut() {
+        splash.upload(&mut target.renderer, target.gl)?;
+    }
+    Ok(())
+}
+
+/// Places the source according to `--position` on the current size of the target
+fn place_source(state: &mut WaylandState) -> anyhow::Result<()> {
+    if let Some(layout) = state.layout {
+        let cells = layout.cells(state.sources.len(), state.dest_size)?;
+        for (source, cell) in state.sources.iter_mut().zip(cells) {
+            source.cell = Some(cell);
+        }
+    }
+    if let Some(placement) = state.placement {
+        let source = &mut state.sources[0];
+        source.position = Some(placement.offset(source.upright_size(), state.dest_size)?);
+    }
+    Ok(())
+}
+
+/// Places the source again after the target or the source changed size, scaling it if it does not fit anymore
+fn replace_source(state: &mut WaylandState) {
+    if let Err(err) = place_source(state) {
+        slog::warn!(state.log, "{}, scaling it instead", err);
+        state.sources[0].position = None;
+    }
+}
+
+/// Recreates everything tied to the context of the target after a gpu reset, capturing just continues
+fn rebuild_target(state: &mut WaylandState) -> anyhow::Result<()> {
+    state.target_lost = false;
+    state.render_failures = 0;
+    // flips of the old stream never complete
+    state.swap_pending = false;
+    // fences of the lost context never signal
+    capture::release_all(state);
+    if let Some(pacing) = state.pacing.as_mut() {
+        pacing.cancel();
+    }
+    for source in state.sources.iter_mut() {
+        source.import_cache.clear();
+    }
+    #[cfg(feature = "nvenc")]
+    if let Some(recorder) = state.recorder.as_mut() {
+        recorder.forget();
+    }
+    match state.target.as_mut() {
+        Some(target) => target.rebuild_context(&state.log)?,
+        None => return Ok(()),
+    }
+    create_target_resources(state)?;
+    slog::info!(state.log, "Recovered from gpu reset");
+    Ok(())
+}
+
+/// Stops capturing and presenting until `resume_target`, while the session is inactive or another process
+/// took over the device.
+fn pause_target(state: &mut WaylandState, reason: &str) {
+    if state.target_paused {
+        return;
+    }
+    slog::info!(state.log, "Output unavailable ({}), pausing", reason);
+    // shown by `restore_scanout` once we are back, until the stream shows a frame again
+    freeze_target(state);
+    state.target_paused = true;
+    state.events.emit(Event::Paused);
+    // a deferred frame would be swapped into a device we don't own
+    if let Some(pacing) = state.pacing.as_mut() {
+        pacing.cancel();
+    }
+}
+
+/// Keeps the frame last shown on screen while the output is set up again, which otherwise shows black meanwhile
+fn freeze_target(state: &mut WaylandState) {
+    if let Err(err) = render::freeze_frame(state) {
+        slog::debug!(state.log, "Failed to freeze the last frame: {:#}", err);
+    }
+}
+
+/// Takes the output back after `pause_target`, the stream is recreated before the next frame
+fn resume_target(state: &mut CalloopState) {
+    let wl_state = &mut state.wayland_state;
+    if !wl_state.target_paused {
+        return;
+    }
+    wl_state.target_paused = false;
+    wl_state.events.emit(Event::Resumed);
+    match wl_state.target.as_mut() {
+        Some(target) => {
+            slog::info!(wl_state.log, "Output available again, resuming");
+            if let Err(err) = target.restore_scanout() {
+                slog::warn!(wl_state.log, "Failed to restore the output: {}", err);
+            }
+            // the stream lost the plane while we were away
+            wl_state.target_lost = true;
+            if let Some(scanout) = wl_state.scanout.as_mut().filter(|scanout| scanout.active()) {
+                scanout.leave();
+            }
+        }
+        // a monitor might have been plugged in meanwhile
+        None => target_hotplug(state),
+    }
+}
+
+/// The previous frame is on screen, the next one can be rendered and captured
+fn vblank(state: &mut CalloopState, log: &slog::Logger) {
+    if let Some(target) = state.wayland_state.target.as_mut() {
+        target.frame_submitted();
+    }
+    if let Some(scanout) = state.wayland_state.scanout.as_mut() {
+        scanout.flipped();
+    }
+    state.wayland_state.swap_pending = false;
+    update_cursor(state);
+    capture::render_ready(&mut state.wayland_state);
+    if let Some(tracer) = state.wayland_state.tracer.as_mut() {
+        tracer.displayed();
+    }
+    let stats = &mut state.wayland_state.stats;
+    stats.frame_displayed(stats::monotonic_now());
+    if stats.report(log) {
+        let snapshot = FrameStats::of(stats);
+        state.wayland_state.events.emit(Event::Stats(snapshot));
+    }
+    // with a fixed capture rate the capture timer captures instead
+    if state.wayland_state.capture_rate != CaptureRate::VBlank {
+        render::present_latest(&mut state.wayland_state);
+        return;
+    }
+    // with variable refresh captures follow the swapped frames instead, see `render::swap_frame`
+    let vrr = state.wayland_state.target.as_ref().map(|target| target.vrr).unwrap_or(false);
+    if let (Some(connection), false) = (state.connection.as_mut(), vrr) {
+        capture_sources(connection, &mut state.wayland_state);
+    }
+}
+
+/// Sets up the target `config` describes, along the drm device delivering its vblanks unless it is offscreen
+fn init_target(
+    config: &TargetConfig,
+    log: &slog::Logger,
+) -> anyhow::Result<(gpu::TargetGPU, Option<DrmDevice<gpu::Fd>>)> {
+    let options = &config.options;
+    if config.offscreen {
+        let target = gpu::init_offscreen_target(
+            config.fd.clone(),
+            options.mode,
+            options.depth,
+            options.transform,
+            options.gl_debug,
+            log.clone(),
+        )?;
+        return Ok((target, None));
+    }
+    let (target, device) = gpu::TargetGPU::initialize_with_recovery(config.fd.clone(), options, log.clone())?;
+    Ok((target, Some(device)))
+}
+
+/// What `init_target` would set up for `config`, for `--dry-run`.
+///
+/// Checks everything that can be checked without touching the state of the device, failing just like a real run.
+fn plan_target(config: &TargetConfig, log: &slog::Logger) -> anyhow::Result<String> {
+    let options = &config.options;
+    if config.offscreen {
+        return Ok(format!("render offscreen at {}x{}", options.mode.0, options.mode.1));
+    }
+    let device = DrmDevice::new(config.fd.clone(), false, log.clone())?;
+    let plan = gpu::plan_target(&device, &config.fd, options, log)?;
+    let plane = match plan.plane {
+        Some(plane) => Some(plane),
+        None => PlaneEntry::query_all(&config.fd)
+            .and_then(|planes| kms::select_plane(&planes, PlaneSelection::Primary, plan.crtc))
+            .ok(),
+    };
+    let (width, height) = plan.mode();
+    let mut summary = format!(
+        "scan out on {} at {}x{}@{} using plane {}",
+        gpu::connector_name(&plan.connector),
+        width,
+        height,
+        plan.drm_mode.vrefresh(),
+        plane.map(|plane| u32::from(plane).to_string()).unwrap_or_else(|| String::from("?"))
+    );
+    if let Some(output) = plan.stolen.as_ref() {
+        summary.push_str(&format!(" taken over from {}", output));
+    }
+    match options.backend {
+        TargetBackendKind::Gbm => summary.push_str(" through gbm"),
+        _ => {
+            let config = gpu::probe_stream(config.fd.clone(), (width, height), options.depth, log)?;
+            summary.push_str(&format!(" through a stream with {}", config));
+        }
+    }
+    Ok(summary)
+}
+
+/// Loads the settings remembered per monitor and applies those of the one on the connector,
+/// as far as the command line left them open. `None` if they can't be loaded, mirroring goes on without.
+fn recall_monitor(
+    fd: &gpu::Fd,
+    options: &mut gpu::TargetOptions,
+    explicit: persist::Explicit,
+    forget: bool,
+    content: (i32, i32),
+    log: &slog::Logger,
+) -> Option<persist::MonitorStore> {
+    let path = match persist::MonitorStore::default_path() {
+        Some(path) => path,
+        None => {
+            slog::warn!(log, "Neither XDG_STATE_HOME nor HOME is set, not remembering monitor settings");
+            return None;
+        }
+    };
+    let mut store = match persist::MonitorStore::load(path) {
+        Ok(store) => store,
+        Err(err) => {
+            slog::warn!(log, "Not remembering monitor settings: {:#}", err);
+            return None;
+        }
+    };
+    let edid = match gpu::target_edid(fd, options.connector.as_deref()) {
+        Ok(Some(edid)) => edid,
+        Ok(None) => return Some(store),
+        Err(err) => {
+            slog::debug!(log, "Failed to read the EDID of the monitor: {:#}", err);
+            return Some(store);
+        }
+    };
+    if forget {
+        if !store.forget(&edid) {
+            slog::info!(log, "No settings of {} to forget", edid);
+        } else if let Err(err) = store.save() {
+            slog::warn!(log, "Failed to forget the settings of {}: {:#}", edid, err);
+        } else {
+            slog::info!(log, "Forgot the settings of {}", edid);
+        }
+        return Some(store);
+    }
+    if let Some(settings) = store.get(&edid) {
+        let applied = settings.apply(explicit, options, content);
+        if !applied.is_empty() {
+            slog::info!(
+                log,
+                "Using the remembered {} of {} from {}",
+                applied.join(", "),
+                edid,
+                store.path().display()
+            );
+        }
+    }
+    Some(store)
+}
+
+/// Records the drm state of the target before it is set up, which a panic or `nvscreencopy restore` go back to.
+///
+/// The previous snapshot of the device is kept if this one fails, mirroring goes on either way.
+fn snapshot_target(fd: &gpu::Fd, path: &Path, log: &slog::Logger) {
+    let snapshot = match snapshot::Snapshot::take(fd, path) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            slog::warn!(log, "Failed to take a snapshot of the drm state: {:#}", err);
+            return;
+        }
+    };
+    match snapshot::Snapshot::default_path(path) {
+        Some(file) => match snapshot.save(&file) {
+            Ok(()) => slog::debug!(log, "Saved the drm state of {} to {}", path.display(), file.display()),
+            Err(err) => slog::warn!(log, "Failed to save the drm state: {:#}", err),
+        },
+        None => slog::warn!(log, "Neither XDG_STATE_HOME nor HOME is set, not saving the drm state"),
+    }
+    snapshot::restore_on_panic(fd.clone(), snapshot);
+}
+
+/// Records what the output was just set up with for its monitor
+fn remember_monitor(
+    store: &mut persist::MonitorStore,
+    target: &gpu::TargetGPU,
+    options: &gpu::TargetOptions,
+    log: &slog::Logger,
+) {
+    let edid = match target.edid() {
+        Some(edid) => edid,
+        None => return,
+    };
+    let settings = persist::MonitorSettings {
+        // a modeline is not among the modes of the monitor, which the closest one would be picked from
+        mode: if options.modeline.is_some() { None } else { Some(target.mode) },
+        plane_scaling: target.plane_scaled(),
+        connector_props: options.connector_props.iter().map(ToString::to_string).collect(),
+    };
+    if store.remember(edid, settings) {
+        match store.save() {
+            Ok(()) => slog::debug!(log, "Remembered the settings of {} in {}", edid, store.path().display()),
+            Err(err) => slog::warn!(log, "Failed to remember the settings of {}: {:#}", edid, err),
+        }
+    }
+}
+
+/// Writes the last frame of the offscreen output to the working directory, named after the frames shown so far
+fn dump_frame(state: &mut WaylandState) {
+    let path = PathBuf::from(format!("offscreen-{}.png", state.stats.frames_swapped()));
+    match state.target.as_mut().map(|target| target.dump_png(&path)) {
+        Some(Ok(())) => slog::info!(state.log, "Wrote {}", path.display()),
+        Some(Err(err)) => slog::warn!(state.log, "Failed to dump frame: {:?}", err),
+        None => slog::warn!(state.log, "No frame to dump, the target is gone"),
+    }
+}
+
+/// Registers the drm events of the target, its vblanks drive capturing
+fn insert_target_source(
+    handle: &LoopHandle<'static, CalloopState>,
+    device: DrmDevice<gpu::Fd>,
+    signaler: Option<&Signaler<SessionSignal>>,
+    log: slog::Logger,
+) -> anyhow::Result<RegistrationToken> {
+    let dispatcher = Dispatcher::new(device, move |event, _, state: &mut CalloopState| match event {
+        DrmEvent::VBlank(_crtc) => vblank(state, &log),
+        DrmEvent::Error(error) => slog::error!(log, "{:?}", error),
+    });
+    if let Some(signaler) = signaler {
+        dispatcher.as_source_mut().link(signaler.clone());
+    }
+    handle
+        .register_dispatcher(dispatcher)
+        .context("Failed to add drm device to event loop")
+}
+
+/// Tears the target down after the monitor was unplugged, nothing is captured until one is plugged in again
+fn drop_target(state: &mut CalloopState) {
+    slog::info!(state.wayland_state.log, "Monitor unplugged from the target connector, pausing");
+    teardown_target(state);
+}
+
+/// Drops the target and everything tied to its context, `restore_target` sets it up again
+fn teardown_target(state: &mut CalloopState) {
+    let wl_state = &mut state.wayland_state;
+    // fences of the target context
+    capture::release_all(wl_state);
+    if let Some(pacing) = wl_state.pacing.as_mut() {
+        pacing.cancel();
+    }
+    for source in wl_state.sources.iter_mut() {
+        source.import_cache.clear();
+    }
+    wl_state.target_lost = false;
+    wl_state.render_failures = 0;
+    // the cursor buffer lives on the device of the target
+    if let Some(cursor) = wl_state.cursor.as_mut() {
+        cursor.hardware = None;
+    }
+    // the next monitor might have another mode, so the recording continues with a new stream
+    #[cfg(feature = "nvenc")]
+    if let (Some(recorder), Some(target)) = (wl_state.recorder.as_mut(), wl_state.target.as_mut()) {
+        recorder.stop(&mut target.renderer);
+    }
+    wl_state.scanout = None;
+    wl_state.target = None;
+    if let Some(token) = state.target_token.take() {
+        state.handle.remove(token);
+    }
+}
+
+/// Sets the target up again after a monitor was plugged in, capturing resumes right away
+fn restore_target(state: &mut CalloopState) -> anyhow::Result<()> {
+    let log = state.wayland_state.log.clone();
+    let (target, device) = init_target(&state.target_config, &log)?;
+    state.target_token = device
+        .map(|device| insert_target_source(&state.handle, device, state.signaler.as_ref(), log.clone()))
+        .transpose()?;
+
+    let wl_state = &mut state.wayland_state;
+    wl_state.color_depth = target.upload_depth();
+    wl_state.dest_size = target.size();
+    replace_source(wl_state);
+    if let Some(connection) = state.connection.as_ref() {
+        wl_state.import_formats = negotiate_formats(&connection.environment, &target.renderer, &log);
+    }
+    let render = wl_state.render.as_ref().map(|render| &render.renderer);
+    wl_state.constraints.update(&target.renderer, render, &log);
+    if let Some(cursor) = wl_state.cursor.as_mut() {
+        match target.create_cursor(&cursor.image) {
+            Ok(hardware) => cursor.hardware = Some(hardware),
+            Err(err) => {
+                slog::warn!(log, "Failed to set up the cursor plane again, using the composited cursor: {:?}", err);
+                wl_state.cursor = None;
+                wl_state.overlay_cursor = true;
+            }
+        }
+    }
+    wl_state.copy_path.reset();
+    // portal frames go back to the stream right after rendering, they can't stay on screen
+    wl_state.scanout = if wl_state.copy_path.allow_scanout() && state.capture_kind != CaptureBackendKind::Portal {
+        target.direct_scanout(&log)
+    } else {
+        None
+    };
+    wl_state.target = Some(target);
+    wl_state.swap_pending = false;
+    wl_state.copy = None;
+    create_target_resources(wl_state)?;
+    // covers whatever the dumb buffer shows until the first frame
+    if let Err(err) = render::blank(wl_state) {
+        slog::warn!(log, "Failed to blank target: {}", err);
+    }
+
+    if let Some(connection) = state.connection.as_mut() {
+        capture_sources(connection, &mut state.wayland_state);
+    }
+    Ok(())
+}
+
+/// Follows monitors being plugged into, unplugged from or replaced on the target connector
+fn target_hotplug(state: &mut CalloopState) {
+    // the device can't be touched until the session is back, which checks again
+    if state.wayland_state.target_paused {
+        return;
+    }
+    let log = state.wayland_state.log.clone();
+    match state.wayland_state.target.as_ref().map(|target| target.connected()) {
+        Some(Ok(true)) => target_changed(&mut state.wayland_state, state.target_config.options.mode),
+        Some(Ok(false)) => drop_target(state),
+        Some(Err(err)) => slog::warn!(log, "Failed to read the state of the connector: {}", err),
+        None => {
+            let config = &state.target_config;
+            match gpu::connector_connected(&config.fd, config.options.connector.as_deref()) {
+                Ok(true) => match restore_target(state) {
+                    Ok(()) => slog::info!(log, "Monitor plugged into the target connector, resuming"),
+                    Err(err) => slog::warn!(log, "Failed to set up the target again: {:?}", err),
+                },
+                Ok(false) => {}
+                Err(err) => slog::warn!(log, "Failed to read connectors of the target: {}", err),
+            }
+        }
+    }
+}
+
+/// Makes sure the monitor still receives our frames, a commit may succeed while the link fails right after.
+///
+/// A bad link is retried with the mode needing the next lower bandwidth, a monitor that was unplugged without
+/// a hotplug event is dropped like on one.
+fn check_link(state: &mut CalloopState) {
+    state.next_link_check = Instant::now() + LINK_CHECK_INTERVAL;
+    if state.wayland_state.target_paused {
+        return;
+    }
+    let log = state.wayland_state.log.clone();
+    let health = match state.wayland_state.target.as_ref().map(|target| target.link_health()) {
+        Some(Ok(health)) => health,
+        Some(Err(err)) => {
+            slog::warn!(log, "Failed to read the link status of the connector: {}", err);
+            return;
+        }
+        None => return,
+    };
+    if health == gpu::LinkHealth::Good {
+        state.link_exhausted = false;
+        return;
+    }
+    if state.link_exhausted {
+        return;
+    }
+    if let Some(Ok(false)) = state.wayland_state.target.as_ref().map(|target| target.connected()) {
+        slog::warn!(log, "{}, the monitor was unplugged", health);
+        drop_target(state);
+        return;
+    }
+    let wl_state = &mut state.wayland_state;
+    freeze_target(wl_state);
+    let target = wl_state.target.as_mut().unwrap();
+    let failed = target.mode;
+    match target.lower_mode() {
+        Ok(Some(drm_mode)) => {
+            slog::warn!(
+                log,
+                "{} in mode {}x{}, switching to {}x{}@{} which needs less bandwidth",
+                health,
+                failed.0,
+                failed.1,
+                drm_mode.size().0,
+                drm_mode.size().1,
+                drm_mode.vrefresh()
+            );
+            wl_state.stats.error("Link failed, lowered the output mode");
+            if let Err(err) = target_resized(wl_state) {
+                slog::warn!(log, "{:?}", err);
+            }
+        }
+        Ok(None) => {
+            slog::error!(log, "{} in mode {}x{}, no mode needs less bandwidth", health, failed.0, failed.1);
+            wl_state.stats.error("Link failed");
+            state.link_exhausted = true;
+        }
+        Err(err) => {
+            slog::warn!(log, "{} in mode {}x{}, failed to lower the mode: {:?}", health, failed.0, failed.1, err);
+            wl_state.stats.error("Link failed");
+        }
+    }
+}
+
+/// Sets up the whole pipeline again, after a system resume or if recovering from a gpu reset failed.
+///
+/// The sources are looked up again, as the compositor may have recreated their outputs, and the target gets
+/// a fresh modeset, stream and textures.
+fn reinitialize(state: &mut CalloopState) -> anyhow::Result<()> {
+    state.reinit_pending = false;
+    slog::info!(state.wayland_state.log, "Reinitializing");
+    if let Some(connection) = state.connection.as_ref() {
+        for slot in connection.outputs.iter() {
+            *slot.borrow_mut() = None;
+        }
+        state.source_lost_since = Some(Instant::now());
+    }
+    for source in state.wayland_state.sources.iter_mut() {
+        source.shown = false;
+    }
+    state.wayland_state.swap_failures.clear();
+    if state.wayland_state.target.is_some() {
+        teardown_target(state);
+    }
+    let config = &state.target_config;
+    // without a monitor the target is set up by `target_hotplug` once one is plugged in
+    if gpu::connector_connected(&config.fd, config.options.connector.as_deref())? {
+        restore_target(state)?;
+    }
+    state.wayland_state.events.emit(Event::Reinitialized);
+    Ok(())
+}
+
+/// Follows the monitor on the target connector being replaced
+fn target_changed(state: &mut WaylandState, wanted: (i32, i32)) {
+    let target = match state.target.as_mut() {
+        Some(target) => target,
+        None => return,
+    };
+    let hotplug = match target.hotplug(wanted) {
+        Ok(hotplug) => hotplug,
+        Err(err) => {
+            slog::warn!(state.log, "Failed to read modes of the connector: {}", err);
+            return;
+        }
+    };
+    if let Some((old, new)) = hotplug.replaced.as_ref() {
+        slog::info!(state.log, "Monitor {} was replaced by {}", monitor_name(old), monitor_name(new));
+    }
+    let mode = match hotplug.mode {
+        Some(mode) => mode,
+        None => return,
+    };
+    slog::info!(state.log, "Switching output mode to {}x{}", mode.0, mode.1);
+    if let Err(err) = set_target_mode(state, mode) {
+        slog::warn!(state.log, "{:?}", err);
+        state.stats.error("Failed to switch output mode");
+    }
+}
+
+/// Name of a monitor for the log, going by its EDID
+fn monitor_name(edid: &Option<Edid>) -> String {
+    match edid {
+        Some(edid) => edid.to_string(),
+        None => String::from("without EDID"),
+    }
+}
+
+/// Switches the mode of the target while capturing continues, the next frame is scaled to the new size
+fn set_target_mode(state: &mut WaylandState, mode: (i32, i32)) -> anyhow::Result<()> {
+    freeze_target(state);
+    state
+        .target
+        .as_mut()
+        .expect("No target to switch the mode of")
+        .set_mode(mode)?;
+    target_resized(state)
+}
+
+/// Follows the target switching to another mode, the next frame is scaled to the new size
+fn target_resized(state: &mut WaylandState) -> anyhow::Result<()> {
+    // a deferred frame was rendered for the old size
+    if let Some(pacing) = state.pacing.as_mut() {
+        pacing.cancel();
+    }
+    let target = state.target.as_mut().expect("No target to resize");
+    state.dest_size = target.size();
+    if state.overlay.is_some() {
+        state.overlay = Some(
+            overlay::Overlay::new(&mut target.renderer, target.gl, state.dest_size)
+                .with_context(|| "Failed to create overlay")?,
+        );
+    }
+    if state.labels.is_some() {
+        state.labels = Some(
+            overlay::Overlay::new(&mut target.renderer, target.gl, state.dest_size)
+                .with_context(|| "Failed to create labels")?,
+        );
+    }
+    replace_source(state);
+    Ok(())
+}
+
+/// Sets up `--cursor plane`, `None` if the compositor has to draw the cursor after all
+fn create_cursor_plane(target: &gpu::TargetGPU, scale: i32, log: &slog::Logger) -> Option<cursor::CursorPlane> {
+    let pointer = match cursor::PointerSource::detect() {
+        Some(pointer) => pointer,
+        None => {
+            slog::info!(
+                log,
+                "Using the composited cursor, only Hyprland tells clients where the pointer is"
+            );
+            return None;
+        }
+    };
+    let result = cursor::CursorImage::load(scale).and_then(|image| {
+        let hardware = target.create_cursor(&image)?;
+        Ok(cursor::CursorPlane {
+            pointer,
+            image,
+            hardware: Some(hardware),
+        })
+    });
+    match result {
+        Ok(cursor) => {
+            slog::info!(log, "Showing the cursor on the cursor plane");
+            Some(cursor)
+        }
+        Err(err) => {
+            slog::info!(log, "Using the composited cursor: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Moves the cursor plane to the pointer, hiding it while the pointer is on none of the sources
+fn update_cursor(state: &mut CalloopState) {
+    let wl_state = &mut state.wayland_state;
+    let (cursor, connection) = match (wl_state.cursor.as_mut(), state.connection.as_ref()) {
+        (Some(cursor), Some(connection)) => (cursor, connection),
+        _ => return,
+    };
+    let hardware = match cursor.hardware.as_mut() {
+        Some(hardware) => hardware,
+        None => return,
+    };
+    let result = cursor.pointer.position().and_then(|pointer| {
+        let position = wl_state
+            .sources
+            .iter()
+            .zip(connection.outputs.iter())
+            .find_map(|(source, slot)| {
+                let output = slot.borrow().clone()?;
+                let (location, scale) =
+                    sctk::output::with_output_info(&output, |info| (info.location, info.scale_factor))?;
+                // physical position within the mirrored region of the source
+                let mut x = (pointer.x - location.0 as f64) * scale as f64;
+                let mut y = (pointer.y - location.1 as f64) * scale as f64;
+                if let Some(crop) = wl_state.crop.map(|crop| crop.to_physical(scale)) {
+                    x -= crop.loc.x as f64;
+                    y -= crop.loc.y as f64;
+                }
+                // the texture may hold the frame scaled down already
+                let size = match wl_state.crop {
+                    Some(crop) => crop.to_physical(scale).size,
+                    None => Size::from((source.frame_size.w, source.frame_size.h)),
+                };
+                if x < 0.0 || y < 0.0 || x >= size.w as f64 || y >= size.h as f64 {
+                    return None;
+                }
+                let destination = source.destination(wl_state.dest_size);
+                Some((
+                    (destination.loc.x + x * destination.size.w / size.w as f64) as i32,
+                    (destination.loc.y + y * destination.size.h / size.h as f64) as i32,
+                ))
+            });
+        hardware.show(position)
+    });
+    if let Err(err) = result {
+        slog::warn!(wl_state.log, "Cursor plane failed, using the composited cursor: {:?}", err);
+        wl_state.cursor = None;
+        wl_state.overlay_cursor = true;
+    }
+}
+
+/// Finds the gpu to scan out on, with `wait` polling until a monitor is plugged in instead of failing.
+///
+/// With `wait_for_gpu` a missing gpu is polled for until the timeout, as nvidia-drm may still be loading.
+fn find_target_gpu(
+    seat: &str,
+    connector: Option<&str>,
+    device_index: Option<usize>,
+    any_driver: bool,
+    wait: bool,
+    wait_for_gpu: Option<Duration>,
+    log: &slog::Logger,
+) -> anyhow::Result<PathBuf> {
+    let mut search_log = log.clone();
+    let mut waiting = false;
+    let gpu_deadline = wait_for_gpu.map(|timeout| Instant::now() + timeout);
+    let mut gpu_notice: Option<Instant> = None;
+    // the state of nvidia-drm explains most missing nvidia gpus
+    let hint = || gpu::nvidia_drm_hint().filter(|_| !any_driver);
+    loop {
+        if let Some(path) = gpu::find_nvidia_gpu(seat, connector, device_index, any_driver, search_log.clone()) {
+            // an explicit device index is picked regardless of its connectors
+            let connected = !wait || gpu::connector_connected(&gpu::Fd::open(&path)?, connector)?;
+            if connected {
+                return Ok(path);
+            }
+        } else if let Some(deadline) = gpu_deadline.filter(|_| !gpu::gpu_present(any_driver)) {
+            let now = Instant::now();
+            if now >= deadline {
+                match hint() {
+                    Some(hint) => anyhow::bail!("No nvidia gpu showed up in time, {}", hint),
+                    None => anyhow::bail!("No nvidia gpu showed up in time"),
+                }
+            }
+            if gpu_notice.map_or(true, |notice| now - notice >= GPU_NOTICE_INTERVAL) {
+                let left = (deadline - now).as_secs();
+                match hint() {
+                    Some(hint) => slog::info!(log, "Waiting for an nvidia gpu, {}s left ({})", left, hint),
+                    None => slog::info!(log, "Waiting for an nvidia gpu, {}s left", left),
+                }
+                gpu_notice = Some(now);
+                search_log = slog::Logger::root(slog::Discard, o!());
+            }
+            std::thread::sleep(GPU_POLL_INTERVAL);
+            continue;
+        }
+        if !wait {
+            match hint() {
+                Some(hint) => anyhow::bail!("Failed to automatically detect nvidia gpu, {}", hint),
+                None => anyhow::bail!("Failed to automatically detect nvidia gpu"),
+            }
+        }
+        if !waiting {
+            slog::info!(log, "Waiting for a monitor to be plugged in");
+            // every attempt would report the same skipped gpus
+            search_log = slog::Logger::root(slog::Discard, o!());
+            waiting = true;
+        }
+        std::thread::sleep(CONNECTOR_POLL_INTERVAL);
+    }
+}
+
+/// Everything a mirroring session is set up from, the defaults match those of the command line
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Connector to clone onto, by its name or by the name or serial of the monitor plugged into it.
+    /// `None` takes the first connected one.
+    pub connector: Option<String>,
+    /// Nvidia gpu to clone onto, `None` takes the first one with a connected connector
+    pub device_index: Option<usize>,
+    /// `None` takes XDG_SEAT, or seat0 without it
+    pub seat: Option<String>,
+    /// Outputs to mirror, every one needs a position if there are several
+    pub sources: Vec<SourceSpec>,
+    /// Mirrors the only output besides built-in panels, if the default source is missing
+    pub auto_source: bool,
+    /// Size of the mode of the target, `None` fits the sources
+    pub mode: Option<(i32, i32)>,
+    /// Mode not advertised by the monitor, set instead of `mode`
+    pub modeline: Option<Mode>,
+    /// Region of the source to mirror, only allowed with a single source
+    pub crop: Option<Rectangle<i32, Logical>>,
+    /// Shows a single source unscaled at the given position of a larger mode, instead of scaling it
+    pub position: Option<Placement>,
+    /// Arranges all outputs matching the sources in a grid, instead of showing them by position
+    pub layout: Option<Layout>,
+    /// Color of the target where no source is shown, RGBA
+    pub background: [f32; 4],
+    /// PNG shown instead of the background before the first frame and while the sources are gone
+    pub splash: Option<PathBuf>,
+    /// Clamped to the supported ranges
+    pub adjustments: Adjustments,
+    /// How long to wait for a lost source output to reappear
+    pub source_timeout: Duration,
+    /// Powers the source outputs off once nothing showed them for this long, `None` leaves them on
+    pub manage_source_power: Option<Duration>,
+    /// Gpu frames are read back on, `None` uses the one wl_drm advertises
+    pub render_device: Option<PathBuf>,
+    /// Restarts capturing once no frame arrived for this long, `None` waits forever
+    pub watchdog: Option<Duration>,
+    /// Watchdog firings in a row, after which the capture backend is set up again
+    pub watchdog_escalate: u32,
+    pub session: SessionKind,
+    pub target_backend: TargetBackendKind,
+    pub capture: CaptureBackendKind,
+    pub capture_rate: CaptureRate,
+    /// What happens to frames arriving while the target is busy with the previous one
+    pub queue_policy: QueuePolicy,
+    pub copy_path: CopyPathKind,
+    /// Checks that the first imported frame shows the same as one read back by the render gpu
+    pub verify_import: bool,
+    pub filter: FilterKind,
+    /// Scales the sources in linear light, which keeps downscaled text from darkening
+    pub linear_blending: bool,
+    pub transform: Transform,
+    /// Undo the transform of the source outputs on top of `transform`, following it when it changes
+    pub orientation_follow_source: bool,
+    pub pipeline_depth: usize,
+    pub async_readback: bool,
+    /// Scales frames copied through the cpu down on the render gpu first, which leaves less to read back
+    pub downscale_on_render: Downscale,
+    /// Threads converting read back frames, 0 converts them on the event loop
+    pub threads: usize,
+    pub stream: StreamOptions,
+    pub reject_yuv: bool,
+    /// Refuses captured frames of every other format, for debugging
+    pub force_format: Option<Fourcc>,
+    /// `None` follows the formats of the compositor
+    pub color_depth: Option<ColorDepth>,
+    pub reconnect: bool,
+    pub robustness: bool,
+    pub wait_for_connector: bool,
+    /// Polls for the gpu this long at startup, if nvidia-drm is not loaded yet
+    pub wait_for_gpu: Option<Duration>,
+    /// Leaves the output powered on after `ScreenCopy::run` returned
+    pub keep_display_on: bool,
+    pub strict_mode: bool,
+    pub allow_crtc_steal: bool,
+    pub legacy_modesetting: bool,
+    /// Route the KHR_debug messages of the target context into the log
+    pub gl_debug: bool,
+    pub vrr: bool,
+    pub plane_scaling: bool,
+    /// Plane the stream is bound to, `None` takes the primary plane
+    pub plane: Option<PlaneSelection>,
+    pub connector_props: Vec<PropertyAssignment>,
+    /// Recall and record the mode, plane scaling and connector properties per monitor
+    pub persist: bool,
+    /// Drops the remembered settings of the monitor on the connector, this run starts over from the defaults
+    pub forget_monitor: bool,
+    /// Value of the "Colorspace" property of the connector, e.g. "BT2020_RGB"
+    pub colorspace: Option<String>,
+    /// Metadata of HDR content sent to the monitor, the frames are expected to be encoded for it already
+    pub hdr_metadata: Option<HdrMetadataSource>,
+    /// CSV table or ICC profile loaded into the gamma table of the crtc, see `GammaLut::load`
+    pub gamma_lut: Option<PathBuf>,
+    pub damage_tracking: bool,
+    /// Skips rendering frames that did not change
+    pub idle_detect: IdleDetect,
+    pub overlay: bool,
+    /// Where the cursor is drawn, `CursorMode::Plane` falls back to the composited cursor if it can't be used
+    pub cursor: CursorMode,
+    /// Temporary swap errors of the same kind in a row, until `swap_failure_policy` kicks in. `None` retries forever.
+    pub swap_failure_limit: Option<u32>,
+    pub swap_failure_policy: SwapFailurePolicy,
+    /// Capture to swap latency frames are held back to, `None` swaps them right away
+    pub frame_pacing: Option<Duration>,
+    /// Encodes the frames shown on the output into this h264 file, needs the nvenc feature
+    pub record: Option<PathBuf>,
+    /// Creates a headless output on sway before connecting, `Some(None)` sizes it like the target
+    pub ensure_headless: Option<Option<HeadlessMode>>,
+    /// Where frames go, everything about the target is ignored for the raw output
+    pub output: OutputKind,
+    /// Written before the frames of the raw output
+    pub raw_header: RawHeader,
+    /// Stops on SIGINT and SIGTERM, only wanted if mirroring is all the process does
+    pub exit_on_signals: bool,
+    /// Rewrites this file with a `Status` every second
+    pub stats_file: Option<PathBuf>,
+    /// Answers every connection to this unix socket with the newest `Status`
+    pub stats_socket: Option<PathBuf>,
+    /// Shows desktop notifications when mirroring starts, a source or the monitor is lost or back,
+    /// frames fall back to the cpu copy and on fatal errors. Needs the notify feature.
+    pub notify: bool,
+    /// Logs the stages of every frame with its number and a timestamp
+    pub trace_frames: bool,
+    /// Drives the drm outputs from display processes started by these commands, one per target, this process only
+    /// captures. Empty without `--split-display`.
+    pub split_display: Vec<SplitCommand>,
+    /// Stops once this many frames were shown, only supported by the drm output
+    pub frames: Option<u64>,
+    /// Stops after running this long, only supported by the drm output
+    pub duration: Option<Duration>,
+    /// Resolves sources, gpu, connector, mode and stream config and logs what would be done, without a modeset
+    pub dry_run: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            connector: None,
+            device_index: None,
+            seat: None,
+            sources: vec![SourceSpec {
+                selector: SourceSelector::Substring(String::from(source::DEFAULT_SOURCE)),
+                position: None,
+                index: None,
+            }],
+            auto_source: false,
+            mode: None,
+            modeline: None,
+            crop: None,
+            position: None,
+            layout: None,
+            background: [0.0, 0.0, 0.0, 1.0],
+            splash: None,
+            adjustments: Adjustments::NEUTRAL,
+            source_timeout: Duration::from_secs(30),
+            manage_source_power: None,
+            render_device: None,
+            watchdog: None,
+            watchdog_escalate: 3,
+            session: SessionKind::Auto,
+            target_backend: TargetBackendKind::Auto,
+            capture: CaptureBackendKind::Auto,
+            capture_rate: CaptureRate::VBlank,
+            queue_policy: QueuePolicy::Latest,
+            copy_path: CopyPathKind::Auto,
+            verify_import: false,
+            filter: FilterKind::Auto,
+            linear_blending: false,
+            transform: Transform::Normal,
+            orientation_follow_source: true,
+            pipeline_depth: 1,
+            async_readback: false,
+            downscale_on_render: Downscale::Auto,
+            threads: 1,
+            stream: StreamOptions {
+                fifo_length: 0,
+                swap_interval: 1,
+                output_layer: OutputLayerKind::Auto,
+                output_layer_index: None,
+                acquire_timeout_usec: DEFAULT_ACQUIRE_TIMEOUT_USEC,
+            },
+            reject_yuv: false,
+            force_format: None,
+            color_depth: None,
+            reconnect: true,
+            robustness: true,
+            wait_for_connector: false,
+            wait_for_gpu: None,
+            keep_display_on: false,
+            strict_mode: false,
+            allow_crtc_steal: false,
+            legacy_modesetting: false,
+            gl_debug: false,
+            vrr: false,
+            plane_scaling: false,
+            plane: None,
+            connector_props: Vec::new(),
+            persist: true,
+            forget_monitor: false,
+            colorspace: None,
+            hdr_metadata: None,
+            gamma_lut: None,
+            damage_tracking: true,
+            idle_detect: IdleDetect::Damage,
+            overlay: false,
+            cursor: CursorMode::Composited,
+            swap_failure_limit: Some(120),
+            swap_failure_policy: SwapFailurePolicy::Recreate,
+            frame_pacing: None,
+            record: None,
+            ensure_headless: None,
+            output: OutputKind::Drm,
+            raw_header: RawHeader::None,
+            exit_on_signals: false,
+            stats_file: None,
+            stats_socket: None,
+            notify: false,
+            trace_frames: false,
+            split_display: Vec::new(),
+            frames: None,
+            duration: None,
+            dry_run: false,
+        }
+    }
+}
+
+/// Ends `ScreenCopy::run`, from any thread
+#[derive(Clone)]
+pub struct StopHandle(Ping);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.ping();
+    }
+}
+
+/// Sets up the whole pipeline of a running `ScreenCopy` again, from any thread.
+///
+/// For when the output stays black, e.g. after a resume that was not noticed. Mirroring stops if that fails
+/// repeatedly.
+#[derive(Clone)]
+pub struct ReinitializeHandle(Ping);
+
+impl ReinitializeHandle {
+    pub fn reinitialize(&self) {
+        self.0.ping();
+    }
+}
+
+/// A mirroring session
+pub struct ScreenCopy {
+    options: Options,
+    log: slog::Logger,
+    events: events::Events,
+    stop: Ping,
+    stop_source: PingSource,
+    reinit: Ping,
+    reinit_source: PingSource,
+}
+
+impl ScreenCopy {
+    pub fn new(options: Options) -> anyhow::Result<ScreenCopy> {
+        let (stop, stop_source) = make_ping().with_context(|| "Failed to create stop handle")?;
+        let (reinit, reinit_source) = make_ping().with_context(|| "Failed to create reinitialize handle")?;
+        Ok(ScreenCopy {
+            options,
+            log: slog::Logger::root(slog::Discard, o!()),
+            events: events::Events::default(),
+            stop,
+            stop_source,
+            reinit,
+            reinit_source,
+        })
+    }
+
+    /// Logs through `log` instead of discarding everything
+    pub fn logger(mut self, log: slog::Logger) -> ScreenCopy {
+        self.log = log;
+        self
+    }
+
+    /// Calls `callback` on the thread running the session for every state change
+    pub fn on_event(mut self, callback: impl FnMut(Event) + 'static) -> ScreenCopy {
+        self.events = events::Events::new(Some(Box::new(callback)));
+        self
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stop.clone())
+    }
+
+    pub fn reinitialize_handle(&self) -> ReinitializeHandle {
+        ReinitializeHandle(self.reinit.clone())
+    }
+
+    /// Sets everything up and mirrors until stopped through a `StopHandle`, a signal or a fatal error.
+    /// Blocks the calling thread meanwhile.
+    pub fn run(self) -> anyhow::Result<()> {
+        let ScreenCopy {
+            options,
+            log,
+            events,
+            stop: _stop,
+            stop_source,
+            reinit: _reinit,
+            reinit_source,
+        } = self;
+        run(options, log, events, stop_source, reinit_source)
+    }
+}
+
+/// Registry names and makes of the outputs of the compositor, as matched by `SourceSelector`
+pub fn list_sources() -> anyhow::Result<Vec<(u32, String)>> {
+    let (_display, _event_queue, environment) = connect_environment()?;
+    Ok(environment
+        .get_all_outputs()
+        .iter()
+        .filter_map(|output| sctk::output::with_output_info(output, |info| (info.id, info.make.clone())))
+        .collect())
+}
+
+/// Connectors of the gpu `options` select
+pub fn list_connectors(options: &Options, log: &slog::Logger) -> anyhow::Result<Vec<ConnectorEntry>> {
+    let any_driver = options.target_backend == gpu::TargetBackendKind::Gbm;
+    let seat = gpu::resolve_seat(options.seat.as_deref(), std::env::var("XDG_SEAT").ok());
+    let path = find_target_gpu(
+        &seat,
+        options.connector.as_deref(),
+        options.device_index,
+        any_driver,
+        options.wait_for_connector,
+        options.wait_for_gpu,
+        log,
+    )?;
+    let fd = gpu::Fd::open(&path)?;
+    let device = DrmDevice::new(fd, false, log.clone())?;
+    let res_handles = device.resource_handles()?;
+    let mut entries = Vec::new();
+    for conn in res_handles.connectors() {
+        let info = device.get_connector(*conn)?;
+        entries.push(gpu::ConnectorEntry::query(&device, &info));
+    }
+    Ok(entries)
+}
+
+/// Drm devices of all seats, whether or not they are bound to nvidia
+pub fn list_gpus(log: &slog::Logger) -> anyhow::Result<Vec<GpuEntry>> {
+    gpu::list_gpus(log)
+}
+
+/// Planes of the gpu `options` select, along the crtc its connector would use
+pub fn list_planes(options: &Options, log: &slog::Logger) -> anyhow::Result<(crtc::Handle, Vec<PlaneEntry>)> {
+    let any_driver = options.target_backend == gpu::TargetBackendKind::Gbm;
+    let seat = gpu::resolve_seat(options.seat.as_deref(), std::env::var("XDG_SEAT").ok());
+    let path = find_target_gpu(
+        &seat,
+        options.connector.as_deref(),
+        options.device_index,
+        any_driver,
+        options.wait_for_connector,
+        options.wait_for_gpu,
+        log,
+    )?;
+    gpu::list_planes(
+        gpu::Fd::open(&path)?,
+        options.connector.as_deref(),
+        options.allow_crtc_steal,
+        log,
+    )
+}
+
+/// Shows an animated test pattern on the target for `duration`, to check the output works without a compositor
+pub fn test_pattern(options: &Options, duration: Duration, log: &slog::Logger) -> anyhow::Result<()> {
+    test_pattern::run(options, duration, log.clone())
+}
+
+/// Runs the display process of `--split-display`, showing the frames arriving on the socket `fd`
+pub fn split_display(options: &Options, fd: RawFd, log: &slog::Logger) -> anyhow::Result<()> {
+    split::display(options, fd, log.clone())
+}
+
+/// Puts the gpus back into the state recorded before mirroring onto them, from `file` or every snapshot there is.
+///
+/// Stale snapshots, taken in another boot or of a device node that is another gpu by now, are skipped.
+/// Returns the number of crtcs that were set up again.
+pub fn restore(options: &Options, file: Option<&Path>, log: &slog::Logger) -> anyhow::Result<usize> {
+    let files = match file {
+        Some(file) => vec![file.to_path_buf()],
+        None => snapshot::list()?,
+    };
+    if files.is_empty() {
+        anyhow::bail!(
+            "No snapshot of the drm state in {}, nvscreencopy takes one whenever it starts",
+            snapshot::snapshot_dir().unwrap_or_default().display()
+        );
+    }
+    let (mut session, _notifier) = session::Session::new(options.session, log)?;
+    let mut restored = 0;
+    for file in files {
+        let snapshot = snapshot::Snapshot::load(&file)?;
+        if let Err(err) = snapshot.check_current() {
+            slog::warn!(log, "Skipping {}: {:#}", file.display(), err);
+            continue;
+        }
+        let fd = session.open_target(&snapshot.device)?;
+        let crtcs = snapshot.apply(&fd, log)?;
+        slog::info!(log, "Restored {} crtcs of {} from {}", crtcs, snapshot.device.display(), file.display());
+        restored += crtcs;
+    }
+    Ok(restored)
+}
+
+/// Follows up on whatever the last events changed, runs after every dispatch of the main loop
+fn iterate(state: &mut CalloopState) {
+    match state.session_events.take() {
+        Some(session::SessionEvent::Paused) => pause_target(&mut state.wayland_state, "session inactive"),
+        Some(session::SessionEvent::Resumed) => resume_target(state),
+        None => {}
+    }
+    // without logind nobody tells us when the other process lets go of the device
+    let master_back = state.signaler.is_none()
+        && state.wayland_state.target_paused
+        && state.wayland_state.target.as_ref().map(|target| target.is_master()).unwrap_or(false);
+    if master_back {
+        resume_target(state);
+    }
+    if Instant::now() >= state.next_link_check {
+        check_link(state);
+    }
+    if let Some(slept) = state.sleep.check() {
+        slog::info!(state.wayland_state.log, "System resumed after {:?}", slept);
+        state.reinit_pending = true;
+        state.next_reinit = Instant::now();
+    }
+    // the session hands the device back after the resume, which then needs to be set up again
+    if state.reinit_pending && !state.wayland_state.target_paused && Instant::now() >= state.next_reinit {
+        match reinitialize(state) {
+            Ok(()) => state.reinit_failures.succeeded(),
+            Err(err) => {
+                if state.reinit_failures.failed() == Verdict::Escalate {
+                    fail(state, err.context("Failed to reinitialize"));
+                    return;
+                }
+                slog::warn!(state.wayland_state.log, "Failed to reinitialize: {:?}", err);
+                state.reinit_pending = true;
+                state.next_reinit = Instant::now() + REINIT_RETRY_DELAY;
+            }
+        }
+    }
+    if state.wayland_state.target_lost {
+        match rebuild_target(&mut state.wayland_state) {
+            // captures stopped while the output was paused
+            Ok(()) => {
+                if let Some(connection) = state.connection.as_mut() {
+                    capture_sources(connection, &mut state.wayland_state);
+                }
+            }
+            Err(err) => {
+                slog::warn!(state.wayland_state.log, "Failed to recover from gpu reset: {:?}", err);
+                state.reinit_pending = true;
+            }
+        }
+    }
+    if state.disconnected {
+        disconnect(state);
+    }
+    if state.connection.is_none() {
+        if Instant::now() < state.next_reconnect {
+            return;
+        }
+        if let Err(err) = reconnect(state) {
+            state.reconnect_delay =
+                std::cmp::min(state.reconnect_delay * 2, RECONNECT_MAX_DELAY);
+            state.next_reconnect = Instant::now() + state.reconnect_delay;
+            slog::debug!(
+                state.wayland_state.log,
+                "Reconnecting failed: {}, retrying in {:?}",
+                err,
+                state.reconnect_delay
+            );
+            return;
+        }
+        slog::info!(state.wayland_state.log, "Reconnected to the compositor");
+    }
+    let connection = state.connection.as_mut().unwrap();
+
+    let mut lost = false;
+    for (source, slot) in state.wayland_state.sources.iter_mut().zip(connection.outputs.iter()) {
+        if source.source_lost.swap(false, Ordering::SeqCst) {
+            slog::warn!(
+                state.wayland_state.log,
+                "Source output {} died, waiting for it to reappear",
+                source.spec.selector
+            );
+            *slot.borrow_mut() = None;
+            source.shown = false;
+            lost = true;
+        }
+    }
+    if lost {
+        state.source_lost_since = Some(Instant::now());
+        // the remaining sources keep being shown, with the background in place of the lost ones
+        if connection.outputs.iter().all(|slot| slot.borrow().is_none()) {
+            if let Err(err) = render::blank(&mut state.wayland_state) {
+                slog::warn!(state.wayland_state.log, "Failed to blank target: {}", err);
+            }
+            if state.wayland_state.splash.is_none() {
+                set_target_dpms(&mut state.wayland_state, kms::Dpms::Off);
+            }
+        }
+    }
+    for (source, slot) in state.wayland_state.sources.iter().zip(connection.outputs.iter()) {
+        if slot.borrow().is_none() {
+            match find_output(&connection.environment, &source.spec) {
+                Ok(Some((output, _))) => *slot.borrow_mut() = Some(output),
+                Ok(None) => {}
+                Err(err) => slog::warn!(state.wayland_state.log, "{}", err),
+            }
+        }
+    }
+    if !all_sources_present(connection)
+        && state
+            .source_lost_since
+            .map(|since| since.elapsed() > state.source_timeout)
+            .unwrap_or(false)
+    {
+        fail(state, anyhow::anyhow!("Source output did not reappear"));
+        return;
+    }
+    if state.source_lost_since.is_some() && all_sources_present(connection) {
+        slog::info!(state.wayland_state.log, "Source output is back, resuming");
+        state.source_lost_since = None;
+        set_target_dpms(&mut state.wayland_state, kms::Dpms::On);
+        capture_sources(connection, &mut state.wayland_state);
+    }
+    if let Some(power) = connection.power.as_mut() {
+        // turned on again by `capture_sources` once there is a target to show the sources on
+        let idle = state.wayland_state.target_paused || state.wayland_state.target.is_none();
+        let outputs = connection
+            .outputs
+            .iter()
+            .filter_map(|slot| slot.borrow().clone())
+            .collect::<Vec<_>>();
+        power.update(idle, &outputs);
+    }
+    check_watchdog(state);
+    let connection = match state.connection.as_mut() {
+        Some(connection) => connection,
+        None => return,
+    };
+    // events read along a roundtrip elsewhere wait in the queue, requests are only sent once flushed
+    let result = connection
+        .event_queue
+        .dispatch_pending(&mut state.wayland_state, orphan_event)
+        .and_then(|_| match connection.display.flush() {
+            // the rest goes out with the next flush
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        });
+    if let Err(err) = result {
+        if state.reconnect && is_disconnect(&err) {
+            state.disconnected = true;
+        } else {
+            fail(state, anyhow::anyhow!("Wayland display died: {}", err));
+            return;
+        }
+    }
+    // frames are also rendered while dispatching, so this needs to come last
+    if let Some(delay) = state.wayland_state.pacing.as_mut().and_then(|pacing| pacing.take_timer()) {
+        state.swap_timer.add_timeout(delay, ());
+    }
+    if let Some(err) = state.wayland_state.frame_error.take() {
+        slog::warn!(state.wayland_state.log, "Dropped frame: {:#}", err);
+    }
+    if let Some(error) = state.wayland_state.stats.take_error() {
+        state.wayland_state.events.emit(Event::Error(error));
+    }
+    if let Some(err) = state.wayland_state.fatal.take() {
+        fail(state, err);
+        return;
+    }
+    if let Some(limit) = state.frame_limit {
+        if state.wayland_state.stats.frames_swapped() >= limit {
+            slog::info!(state.wayland_state.log, "Showed {} frames, exiting", limit);
+            state.signal.stop();
+        }
+    }
+}
+
+/// When the main loop needs to run without an event, for the checks of `iterate` that wait for time to pass
+fn next_deadline(state: &CalloopState) -> Option<Instant> {
+    let wl_state = &state.wayland_state;
+    // noticed while flushing, after `iterate` checked for it already
+    let disconnected = Some(Instant::now()).filter(|_| state.disconnected);
+    let reconnect = Some(state.next_reconnect).filter(|_| state.connection.is_none());
+    let reinit = Some(state.next_reinit).filter(|_| state.reinit_pending && !wl_state.target_paused);
+    let source_timeout = state.source_lost_since.map(|since| since + state.source_timeout);
+    let watchdog = state
+        .watchdog
+        .as_ref()
+        .filter(|_| capturing(state))
+        .map(|watchdog| watchdog.deadline());
+    // polled by `iterate` while paused
+    let master = Some(Instant::now() + MASTER_POLL_INTERVAL)
+        .filter(|_| state.signaler.is_none() && wl_state.target_paused);
+    let link = Some(state.next_link_check).filter(|_| wl_state.target.is_some() && !wl_state.target_paused);
+    let source_power = state
+        .connection
+        .as_ref()
+        .and_then(|connection| connection.power.as_ref())
+        .and_then(|power| power.deadline());
+    [disconnected, reconnect, reinit, source_timeout, watchdog, master, link, source_power]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+}
+
+/// Arms the housekeeping timer for `next_deadline`, so an idle loop sleeps until then
+fn schedule_housekeeping(state: &mut CalloopState) {
+    let deadline = next_deadline(state);
+    if state.housekeeping.as_ref().map(|(at, _)| *at) == deadline {
+        return;
+    }
+    if let Some((_, timeout)) = state.housekeeping.take() {
+        state.housekeeping_timer.cancel_timeout(&timeout);
+    }
+    if let Some(at) = deadline {
+        let timeout = state
+            .housekeeping_timer
+            .add_timeout(at.saturating_duration_since(Instant::now()), ());
+        state.housekeeping = Some((at, timeout));
+    }
+}
+
+fn run(
+    options: Options,
+    log: slog::Logger,
+    events: events::Events,
+    stop_source: PingSource,
+    reinit_source: PingSource,
+) -> anyhow::Result<()> {
+    let Options {
+        connector,
+        device_index,
+        seat,
+        sources: mut specs,
+        auto_source,
+        mode: dest_mode,
+        modeline,
+        crop,
+        position: placement,
+        layout,
+        background,
+        splash: splash_path,
+        adjustments: requested,
+        source_timeout,
+        manage_source_power,
+        render_device,
+        watchdog,
+        watchdog_escalate,
+        session: session_kind,
+        target_backend,
+        capture: capture_kind,
+        capture_rate,
+        queue_policy,
+        copy_path: copy_path_kind,
+        verify_import,
+        filter,
+        linear_blending,
+        transform,
+        orientation_follow_source,
+        pipeline_depth,
+        async_readback,
+        downscale_on_render,
+        threads,
+        stream: stream_options,
+        reject_yuv,
+        force_format,
+        color_depth,
+        reconnect,
+        robustness,
+        wait_for_connector,
+        wait_for_gpu,
+        keep_display_on,
+        strict_mode,
+        allow_crtc_steal,
+        legacy_modesetting,
+        gl_debug,
+        vrr,
+        plane_scaling,
+        plane,
+        connector_props,
+        persist,
+        forget_monitor,
+        colorspace,
+        hdr_metadata,
+        gamma_lut,
+        damage_tracking,
+        idle_detect,
+        overlay: show_overlay,
+        cursor: cursor_mode,
+        swap_failure_limit,
+        swap_failure_policy,
+        frame_pacing,
+        record,
+        ensure_headless,
+        output,
+        raw_header,
+        exit_on_signals,
+        stats_file,
+        stats_socket,
+        notify,
+        trace_frames,
+        split_display,
+        frames: frame_limit,
+        duration,
+        dry_run,
+    } = options;
+    let connector = connector.as_deref();
+    if specs.is_empty() {
+        anyhow::bail!("No source given");
+    }
+    // the modeline determines the outputs mode just like --mode
+    let dest_mode = dest_mode.or_else(|| {
+        modeline.map(|modeline| (modeline.size().0 as i32, modeline.size().1 as i32))
+    });
+    if specs.len() > 1 {
+        if crop.is_some() {
+            anyhow::bail!("Cropping only works with a single source");
+        }
+        if layout.is_none() && specs.iter().any(|spec| spec.position.is_none()) {
+            anyhow::bail!("Every source needs a position, if multiple are given");
+        }
+        if placement.is_some() {
+            anyhow::bail!("--position only works with a single source, multiple ones are placed by NAME@X,Y");
+        }
+    }
+    if layout.is_some() {
+        if specs.iter().any(|spec| spec.position.is_some()) {
+            anyhow::bail!("--layout places the sources itself, they can't be given a position by NAME@X,Y");
+        }
+        if crop.is_some() || placement.is_some() {
+            anyhow::bail!("--layout scales every source into its cell, which contradicts --crop and --position");
+        }
+        if capture_kind == CaptureBackendKind::Portal || !output.has_target() {
+            anyhow::bail!("--layout needs a target to composite onto and a capture backend taking several sources");
+        }
+    }
+    if placement.is_some() {
+        if specs[0].position.is_some() {
+            anyhow::bail!("--position and NAME@X,Y both place the source, only give one of them");
+        }
+        // the plane would scale the source to the mode again
+        if plane_scaling {
+            anyhow::bail!("--position shows the source unscaled, which contradicts --plane-scaling");
+        }
+    }
+    if capture_kind == CaptureBackendKind::Portal {
+        // the stream shows whatever monitor was picked in the dialog of the portal
+        if specs.len() > 1 {
+            anyhow::bail!("--capture-backend portal captures a single source");
+        }
+        if !output.has_target() {
+            anyhow::bail!("--capture-backend portal only works with the drm and offscreen outputs");
+        }
+    }
+    if (stats_file.is_some() || stats_socket.is_some()) && !output.has_target() {
+        anyhow::bail!("--stats-file and --stats-socket only work with the drm and offscreen outputs");
+    }
+    if (frame_limit.is_some() || duration.is_some()) && !output.has_target() {
+        anyhow::bail!("--frames and --duration only work with the drm and offscreen outputs");
+    }
+    if (colorspace.is_some() || hdr_metadata.is_some()) && output != OutputKind::Drm {
+        anyhow::bail!("--colorspace and --hdr-metadata only work with the drm output");
+    }
+    if gamma_lut.is_some() && output != OutputKind::Drm {
+        anyhow::bail!("--gamma-lut only works with the drm output");
+    }
+    if !split_display.is_empty() {
+        if output != OutputKind::Drm {
+            anyhow::bail!("--split-display only works with the drm output");
+        }
+        // the capture process takes the path of the raw output, everything else happens in the display process
+        if capture_kind == CaptureBackendKind::Portal {
+            anyhow::bail!("--split-display captures through screencopy, not the portal");
+        }
+        if stats_file.is_some() || stats_socket.is_some() || frame_limit.is_some() || duration.is_some() {
+            anyhow::bail!("--stats-file, --stats-socket, --frames and --duration don't work with --split-display");
+        }
+        if record.is_some() {
+            anyhow::bail!("--record doesn't work with --split-display");
+        }
+    }
+    if splash_path.is_some() && !output.has_target() {
+        anyhow::bail!("--splash only works with the drm and offscreen outputs");
+    }
+    if record.is_some() && output != OutputKind::Drm {
+        anyhow::bail!("--record encodes on the target gpu, which only the drm output uses");
+    }
+    #[cfg(feature = "nvenc")]
+    if let Some(path) = record.as_ref() {
+        nvenc::check_path(path)?;
+    }
+    #[cfg(not(feature = "nvenc"))]
+    if record.is_some() {
+        anyhow::bail!("--record needs nvscreencopy built with --features nvenc");
+    }
+    let mut adjustments = requested.clamped();
+    if adjustments != requested {
+        slog::warn!(log, "Color adjustments out of range, using {:?}", adjustments);
+    }
+    let gamma_lut = match gamma_lut.as_deref().map(gamma_lut::GammaLut::load).transpose()? {
+        // the table takes over --gamma, so the shader doesn't run just for it and rounds only once
+        Some(lut) if adjustments.gamma != 1.0 => {
+            slog::info!(log, "Applying --gamma {} through the gamma table", adjustments.gamma);
+            Some(lut.take_over_gamma(&mut adjustments.gamma))
+        }
+        lut => lut,
+    };
+    // only gbm can drive gpus of other vendors
+    let any_driver = target_backend == gpu::TargetBackendKind::Gbm;
+    let seat = gpu::resolve_seat(seat.as_deref(), std::env::var("XDG_SEAT").ok());
+    let pacing = frame_pacing.map(pacing::Pacing::new);
+
+    // create the source before connecting, so it is already advertised on connect
+    let _headless = if let Some(mode) = ensure_headless {
+        let mode = match (mode, dest_mode) {
+            (Some(mode), _) => mode,
+            (None, Some((width, height))) => sway::HeadlessMode {
+                width,
+                height,
+                refresh: None,
+            },
+            (None, None) if output != OutputKind::Drm => {
+                anyhow::bail!("--ensure-headless needs a mode to create the headless output with, if there is no target gpu")
+            }
+            (None, None) => {
+                let path = find_target_gpu(&seat, connector, device_index, any_driver, wait_for_connector, wait_for_gpu, &log)?;
+                let (width, height, refresh) = gpu::preferred_mode(&path, connector, log.clone())?;
+                sway::HeadlessMode {
+                    width,
+                    height,
+                    refresh: Some(refresh as f64),
+                }
+            }
+        };
+        Some(sway::HeadlessOutput::create(mode, log.clone())?)
+    } else {
+        None
+    };
+
+    // frames are handed on instead, which needs neither a target nor a render gpu
+    let sink: Option<Box<dyn raw::Sink>> = match output {
+        // the display process drives the target instead
+        OutputKind::Drm if !split_display.is_empty() => {
+            Some(Box::new(split::FrameSender::new(split_display, log.clone())?))
+        }
+        OutputKind::Drm => None,
+        OutputKind::Offscreen(_) => None,
+        OutputKind::Raw(destination) => Some(Box::new(raw::Writer::new(&destination, raw_header)?)),
+        #[cfg(feature = "pipewire")]
+        OutputKind::Pipewire => Some(Box::new(pipewire_node::PipewireSink::new(log.clone())?)),
+        #[cfg(not(feature = "pipewire"))]
+        OutputKind::Pipewire => anyhow::bail!("The pipewire output needs nvscreencopy built with --features pipewire"),
+    };
+    if let Some(sink) = sink {
+        if specs.len() > 1 {
+            anyhow::bail!("Outputs without target gpu take a single source");
+        }
+        let options = raw::RawOptions {
+            source: specs.into_iter().next().unwrap(),
+            auto_source,
+            crop,
+            capture_rate,
+            exit_on_signals,
+        };
+        return raw::run(options, sink, log, events, stop_source);
+    }
+
+    // Connect to the wayland server
+    let mut event_loop: EventLoop<'static, CalloopState> =
+        EventLoop::try_new().with_context(|| "Failed to create event loop")?;
+    let (client_display, mut event_queue, environment) = connect_environment()?;
+    let Globals { capture, drm_path } = check_globals(&environment, &mut event_queue, capture_kind, &log)?;
+    slog::info!(log, "Capture backend: {}", capture.name());
+
+    if let Some(layout) = layout {
+        specs = expand_sources(&environment, &specs)?;
+        layout.fits(specs.len())?;
+        slog::info!(
+            log,
+            "Arranging {} sources: {}",
+            specs.len(),
+            specs.iter().map(|spec| spec.selector.name()).collect::<Vec<_>>().join(", ")
+        );
+    }
+    // get the requested outputs, along their scale and the size of the mirrored region
+    let mut found = Vec::new();
+    for spec in specs.iter_mut() {
+        let (output, mode) = match find_output(&environment, spec)? {
+            Some(found) => found,
+            None => missing_source(&environment, spec, auto_source, &log)?,
+        };
+        // the mode is in physical pixels, the crop region in logical coordinates
+        let scale = sctk::output::with_output_info(&output, |info| info.scale_factor).unwrap_or(1);
+        if let Some(crop) = crop.map(|crop| crop.to_physical(scale)) {
+            if crop.loc.x + crop.size.w > mode.dimensions.0 || crop.loc.y + crop.size.h > mode.dimensions.1 {
+                anyhow::bail!(
+                    "Crop region {},{},{}x{} exceeds the source mode {}x{}",
+                    crop.loc.x,
+                    crop.loc.y,
+                    crop.size.w,
+                    crop.size.h,
+                    mode.dimensions.0,
+                    mode.dimensions.1
+                );
+            }
+        }
+        let size = crop
+            .map(|crop| crop.to_physical(scale))
+            .map(|crop| (crop.size.w, crop.size.h))
+            .unwrap_or(mode.dimensions);
+        found.push((output, mode, scale, size));
+    }
+    // positioned sources are shown unscaled, so by default the output fits all of them
+    let source_size = if let Some(layout) = layout {
+        // cells large enough for the largest source
+        let (columns, rows) = layout.dimensions(specs.len());
+        let (width, height) = found
+            .iter()
+            .fold((0, 0), |(w, h), (_, _, _, size)| (w.max(size.0), h.max(size.1)));
+        (width * columns as i32, height * rows as i32)
+    } else if specs.iter().all(|spec| spec.position.is_some()) {
+        specs
+            .iter()
+            .zip(found.iter())
+            .fold((0, 0), |(w, h), (spec, (_, _, _, size))| {
+                let position = spec.position.unwrap();
+                (w.max(position.x + size.0), h.max(position.y + size.1))
+            })
+    } else {
+        found[0].3
+    };
+
+    // retries are never delayed longer than a frame of the fastest source
+    let refresh_rate = found.iter().map(|(_, mode, _, _)| mode.refresh_rate).max().unwrap_or(0);
+    let frame_interval = if refresh_rate > 0 {
+        Duration::from_secs_f64(1000.0 / refresh_rate as f64)
+    } else {
+        Duration::from_millis(16)
+    };
+    // mirroring does not depend on the encoder, so it goes on without if the driver lacks it
+    #[cfg(feature = "nvenc")]
+    let recorder = record.and_then(|path| {
+        match nvenc::Recorder::new(&path, ((refresh_rate + 500) / 1000) as u32, log.clone()) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                slog::error!(log, "Not recording: {:?}", err);
+                None
+            }
+        }
+    });
+
+    // init target gpu
+    let offscreen = match output {
+        OutputKind::Offscreen(size) => Some(size),
+        _ => None,
+    };
+    let (path, target_backend) = match offscreen {
+        // the frames stay on the gpu of the compositor, no nvidia gpu needed
+        Some(_) => {
+            let path = drm_path
+                .clone()
+                .context("The offscreen output renders on the gpu of the compositor, which screencopy does not tell")?;
+            (gpu::render_node(&path).ok().flatten().unwrap_or(path), target_backend)
+        }
+        None => {
+            let path =
+                find_target_gpu(&seat, connector, device_index, any_driver, wait_for_connector, wait_for_gpu, &log)?;
+            let driver = gpu::gpu_driver(&path)?;
+            let target_backend = target_backend.resolve(&driver);
+            slog::info!(log, "Found gpu {} ({}), target backend: {:?}", path.display(), driver, target_backend);
+            (path, target_backend)
+        }
+    };
+    // the formats the compositor can import say nothing about the frames it captures, so 8 bit unless asked for
+    let color_depth = color_depth.unwrap_or_else(|| {
+        if colorspace.is_some() || hdr_metadata.is_some() {
+            // the content is passed through, which only keeps PQ intact in 10 bit
+            ColorDepth::Ten
+        } else {
+            ColorDepth::Eight
+        }
+    });
+    // offscreen there is neither a monitor to plug in nor a device to share with other sessions
+    let hotplug = match offscreen {
+        Some(_) => None,
+        None => Some(gpu::hotplug_events(&path, &seat, log.clone())?),
+    };
+    let target_device = hotplug.as_ref().map(|(_, device)| *device);
+    let (mut session, session_notifier) = session::Session::new(session_kind, &log)?;
+    slog::info!(log, "Session backend: {}", session.name());
+    let target_fd = match offscreen {
+        // reading the state of the device needs no session
+        _ if dry_run => gpu::Fd::open(&path).with_context(|| format!("Failed to open {}", path.display()))?,
+        Some(_) => gpu::Fd::open(&path).with_context(|| format!("Failed to open {}", path.display()))?,
+        None => session.open_target(&path)?,
+    };
+    // offscreen nothing is modeset
+    if offscreen.is_none() && !dry_run {
+        snapshot_target(&target_fd, &path, &log);
+    }
+    let explicit = persist::Explicit {
+        mode: dest_mode.is_some() || modeline.is_some(),
+        plane_scaling,
+        connector_props: !connector_props.is_empty(),
+    };
+    let mut target_options = gpu::TargetOptions {
+        backend: target_backend,
+        connector: connector.map(String::from),
+        // a rotated output shows the source upright
+        mode: offscreen.flatten().or(dest_mode).unwrap_or(if geometry::swaps_axes(transform) {
+            (source_size.1, source_size.0)
+        } else {
+            source_size
+        }),
+        modeline,
+        refresh: Some(((refresh_rate + 500) / 1000) as u32).filter(|refresh| *refresh > 0),
+        strict_mode,
+        allow_crtc_steal,
+        depth: color_depth,
+        stream: stream_options,
+        legacy_modesetting,
+        vrr,
+        connector_props,
+        colorspace,
+        hdr_metadata,
+        gamma_lut,
+        transform,
+        plane_scaling: if plane_scaling { Some(source_size) } else { None },
+        plane,
+        gl_debug,
+    };
+    // offscreen there is no monitor to remember anything for
+    let mut monitors = match offscreen {
+        None if persist => {
+            recall_monitor(&target_fd, &mut target_options, explicit, forget_monitor && !dry_run, source_size, &log)
+        }
+        _ => None,
+    };
+    let target_config = TargetConfig {
+        fd: target_fd,
+        options: target_options,
+        offscreen: offscreen.is_some(),
+    };
+    if dry_run {
+        let sources = specs
+            .iter()
+            .zip(found.iter())
+            .map(|(spec, (output, mode, _, size))| {
+                let name = sctk::output::with_output_info(output, |info| info.name.clone())
+                    .unwrap_or_else(|| spec.selector.name());
+                format!("{} {}x{}@{}", name, size.0, size.1, (mode.refresh_rate + 500) / 1000)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        // e.g. "renderD128"
+        let render = render_device
+            .as_ref()
+            .or(drm_path.as_ref())
+            .map(|path| gpu::render_node(path).ok().flatten().unwrap_or_else(|| path.clone()))
+            .and_then(|node| node.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| String::from("an unknown gpu"));
+        let target = plan_target(&target_config, &log)?;
+        slog::info!(
+            log,
+            "Dry run: would capture {}, render via {}, {}, copy path probe skipped",
+            sources,
+            render,
+            target
+        );
+        return Ok(());
+    }
+    let (mut target_gpu, target_event_source) = init_target(&target_config, &log)?;
+    if let Some(monitors) = monitors.as_mut() {
+        remember_monitor(monitors, &target_gpu, &target_config.options, &log);
+    }
+    // the encoder reads frames through a framebuffer blit
+    #[cfg(feature = "nvenc")]
+    let recorder = match recorder {
+        Some(_) if !target_gpu.gl.gles3() => {
+            slog::error!(log, "Not recording: blitting frames to the encoder needs a GLES 3 context");
+            None
+        }
+        recorder => recorder,
+    };
+    // holding frames back only adds latency, when the output waits for them anyway
+    let pacing = match pacing {
+        Some(_) if target_gpu.vrr => {
+            slog::info!(log, "Variable refresh rate is active, ignoring --frame-pacing");
+            None
+        }
+        pacing => pacing,
+    };
+
+    // init render gpu
+    let render_gpu = connect_render_gpu(&environment, &mut event_queue, drm_path, render_device.as_deref(), &log)?;
+    let import_formats = negotiate_formats(&environment, &target_gpu.renderer, &log);
+    let mut constraints = constraints::CaptureConstraints::new(force_format);
+    constraints.update(&target_gpu.renderer, render_gpu.as_ref().map(|render| &render.renderer), &log);
+    let display_token = insert_display_source(&event_loop.handle(), &client_display)?;
+    let capture_token = insert_capture_source(&event_loop.handle(), capture.as_ref())?;
+
+    let upload_depth = target_gpu.upload_depth();
+    // frames too large to texture from fail on upload, unless --downscale-on-render shrinks them first
+    let max_texture_size = target_gpu.gl.texture_limit();
+    let sources = specs
+        .into_iter()
+        .zip(found.iter())
+        .map(|(spec, (_, mode, scale, size))| {
+            let texture = render::create_texture(
+                &mut target_gpu.renderer,
+                target_gpu.gl,
+                size.0.min(max_texture_size),
+                size.1.min(max_texture_size),
+                upload_depth,
+            )?;
+            Ok(source::Source::new(
+                spec,
+                *scale,
+                Size::from(mode.dimensions),
+                Size::from(*size),
+                texture,
+                upload_depth,
+                IMPORT_CACHE_SIZE,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // might differ from the wanted mode, if the connector lacks it
+    let dest_size = target_gpu.size();
+    let adjust_shader = if adjustments.is_neutral() && !linear_blending {
+        None
+    } else {
+        Some(create_adjust_shader(&mut target_gpu)?)
+    };
+    let labels = if layout.is_some() {
+        Some(overlay::Overlay::new(&mut target_gpu.renderer, target_gpu.gl, dest_size).with_context(|| "Failed to create labels")?)
+    } else {
+        None
+    };
+    let overlay = if show_overlay {
+        Some(overlay::Overlay::new(&mut target_gpu.renderer, target_gpu.gl, dest_size).with_context(|| "Failed to create overlay")?)
+    } else {
+        None
+    };
+    let splash = match splash_path {
+        Some(path) => {
+            let mut splash = splash::Splash::load(&path)?;
+            splash.upload(&mut target_gpu.renderer, target_gpu.gl)?;
+            Some(splash)
+        }
+        None => None,
+    };
+    let cursor = match cursor_mode {
+        CursorMode::Composited => None,
+        CursorMode::Plane => create_cursor_plane(&target_gpu, found[0].2, &log),
+    };
+    let scanout = if copy_path_kind == CopyPathKind::Auto && capture_kind != CaptureBackendKind::Portal {
+        target_gpu.direct_scanout(&log)
+    } else {
+        None
+    };
+    // notifications follow the same events as the callback of `on_event`
+    let mut events = events;
+    #[cfg(feature = "notify")]
+    if notify {
+        if let Some(mut notifier) = notify::Notifier::new(log.clone()) {
+            events.subscribe(move |event| notifier.handle(event));
+        }
+    }
+    #[cfg(not(feature = "notify"))]
+    let _ = notify;
+    let mut wl_state = WaylandState {
+        render: render_gpu,
+        color_depth: upload_depth,
+        target: Some(target_gpu),
+        sources,
+        releasing: VecDeque::new(),
+        pipeline_depth,
+        log: log.clone(),
+        damage_tracking,
+        idle_detect,
+        copy: None,
+        copy_path: copy_path::CopyPath::new(copy_path_kind),
+        verify_import,
+        scanout,
+        readback_route: None,
+        import_formats,
+        constraints,
+        async_readback,
+        downscale: downscale_on_render,
+        converter: if threads > 0 {
+            Some(convert::Converter::new(threads))
+        } else {
+            None
+        },
+        reject_yuv,
+        stats: stats::Stats::new(),
+        tracer: if trace_frames {
+            Some(trace::FrameTracer::new(&log))
+        } else {
+            None
+        },
+        background,
+        splash,
+        adjustments,
+        adjust_shader,
+        overlay,
+        overlay_cursor: cursor.is_none(),
+        cursor,
+        pacing,
+        #[cfg(feature = "nvenc")]
+        recorder,
+        events,
+        dest_size,
+        crop,
+        placement,
+        orientation_follow_source,
+        layout,
+        labels,
+        filter,
+        linear_blending,
+        retry: capture::Retry::new(frame_interval),
+        robustness,
+        render_failures: 0,
+        capture_rate,
+        queue_policy,
+        swap_pending: false,
+        latest_frame: None,
+        swap_failures: HashMap::new(),
+        swap_failure_limit,
+        swap_failure_policy,
+        frame_error: None,
+        fatal: None,
+        target_lost: false,
+        target_paused: false,
+    };
+    place_source(&mut wl_state)?;
+    // covers whatever the dumb buffer shows until the first frame
+    if let Err(err) = render::blank(&mut wl_state) {
+        slog::warn!(log, "Failed to blank target: {}", err);
+    }
+
+    // logind takes the device away while our session is inactive, e.g. after switching vts
+    let session_events: session::SessionEvents = Rc::new(Cell::new(None));
+    let signaler = session_notifier.as_ref().map(|notifier| notifier.signaler());
+    let _session_token = signaler
+        .as_ref()
+        .zip(target_device)
+        .map(|(signaler, device)| session::listen(signaler, device, session_events.clone(), log.clone()));
+    // the state keeps handles of these
+    let housekeeping_timer = Timer::new().context("Failed to create timer")?;
+    let housekeeping_handle = housekeeping_timer.handle();
+    let swap_timer = Timer::new().context("Failed to create timer")?;
+    let swap_handle = swap_timer.handle();
+    let sleep = sleep::SleepDetector::new()?;
+
+    let outputs = found
+        .into_iter()
+        .map(|(output, _, _, _)| Rc::new(RefCell::new(Some(output))))
+        .collect::<Vec<OutputSlot>>();
+    let output_listeners = listen_for_sources(&environment, &wl_state.sources, &outputs);
+    let power = manage_source_power.and_then(|grace| source_power(&environment, grace, &log));
+
+    let mut state = CalloopState {
+        wayland_state: wl_state,
+        connection: Some(Connection {
+            token: display_token,
+            capture_token,
+            display: client_display,
+            event_queue,
+            environment,
+            capture,
+            outputs,
+            _output_listeners: output_listeners,
+            power,
+        }),
+        handle: event_loop.handle(),
+        signal: event_loop.get_signal(),
+        swap_timer: swap_handle,
+        capture_kind,
+        source_lost_since: None,
+        source_timeout,
+        source_power_grace: manage_source_power,
+        render_device,
+        started: Instant::now(),
+        mirror_state: None,
+        frame_limit,
+        watchdog: watchdog.map(|timeout| watchdog::Watchdog::new(timeout, watchdog_escalate, Instant::now())),
+        reconnect,
+        disconnected: false,
+        reconnect_delay: RECONNECT_MIN_DELAY,
+        next_reconnect: Instant::now(),
+        error: None,
+        _session: session,
+        session_events,
+        signaler,
+        target_config,
+        target_token: None,
+        sleep,
+        reinit_pending: false,
+        next_reinit: Instant::now(),
+        reinit_failures: streak::Streak::new(1, Some(REINIT_ATTEMPTS)),
+        next_link_check: Instant::now() + LINK_CHECK_INTERVAL,
+        link_exhausted: false,
+        housekeeping_timer: housekeeping_handle,
+        housekeeping: None,
+    };
+
+    // failing from here on stops like any other fatal error, so the target is still powered off
+    let handle = event_loop.handle();
+    let setup = (|| -> anyhow::Result<()> {
+        if let Some(notifier) = session_notifier {
+            handle
+                .insert_source(notifier, |_, _, _: &mut CalloopState| {})
+                .map_err(|err| err.error)
+                .context("Failed to add session to event loop")?;
+        }
+        state.target_token = target_event_source
+            .map(|device| insert_target_source(&handle, device, state.signaler.as_ref(), log.clone()))
+            .transpose()?;
+        // nothing scans out offscreen, so vblanks are made up at the refresh rate of the source
+        if offscreen.is_some() {
+            let vblank_timer = Timer::new().context("Failed to create timer")?;
+            vblank_timer.handle().add_timeout(frame_interval, ());
+            let log = log.clone();
+            handle
+                .insert_source(vblank_timer, move |_, timer, state: &mut CalloopState| {
+                    timer.add_timeout(frame_interval, ());
+                    vblank(state, &log);
+                })
+                .map_err(|err| err.error)
+                .context("Failed to add timer to event loop")?;
+            handle
+                .insert_source(
+                    Signals::new(&[Signal::SIGUSR1]).with_context(|| "Failed to block signals")?,
+                    |_, _, state: &mut CalloopState| dump_frame(&mut state.wayland_state),
+                )
+                .map_err(|err| err.error)
+                .context("Failed to add signals to event loop")?;
+        }
+
+        // failed captures are repeated after a delay
+        let retry_timer = Timer::new().context("Failed to create timer")?;
+        state.wayland_state.retry.set_timer(retry_timer.handle());
+        handle
+            .insert_source(retry_timer, |_, _, state: &mut CalloopState| {
+                state.wayland_state.retry.fired();
+                if let Some(connection) = state.connection.as_mut() {
+                    slog::debug!(state.wayland_state.log, "Init frame");
+                    capture_sources(connection, &mut state.wayland_state);
+                }
+            })
+            .map_err(|err| err.error)
+            .context("Failed to add timer to event loop")?;
+
+        // the checks of `iterate` run after every event, this only adds events when time has passed
+        handle
+            .insert_source(housekeeping_timer, |_, _, state: &mut CalloopState| {
+                state.housekeeping = None;
+            })
+            .map_err(|err| err.error)
+            .context("Failed to add timer to event loop")?;
+
+        // the clock jumps on resume, so the loop wakes up for noticing the suspend
+        handle
+            .insert_source(
+                Generic::from_fd(state.sleep.fd(), Interest::READ, calloop::Mode::Level),
+                |_, _, state: &mut CalloopState| {
+                    if let Err(err) = state.sleep.rearm() {
+                        slog::warn!(state.wayland_state.log, "Failed to wait for resumes: {:#}", err);
+                        return Ok(PostAction::Disable);
+                    }
+                    Ok(PostAction::Continue)
+                },
+            )
+            .map_err(|err| err.error)
+            .context("Failed to add timerfd to event loop")?;
+
+        // captures at a fixed rate, independent of the vblanks of the target
+        if let Some(interval) = capture_rate.interval() {
+            let capture_timer = Timer::new().context("Failed to create timer")?;
+            capture_timer.handle().add_timeout(interval, ());
+            handle
+                .insert_source(capture_timer, move |_, timer, state: &mut CalloopState| {
+                    timer.add_timeout(interval, ());
+                    if let Some(connection) = state.connection.as_mut() {
+                        capture_sources(connection, &mut state.wayland_state);
+                    }
+                })
+                .map_err(|err| err.error)
+                .context("Failed to add timer to event loop")?;
+        }
+
+        handle
+            .insert_source(swap_timer, |_, _, state: &mut CalloopState| {
+                let state = &mut state.wayland_state;
+                let due = state
+                    .pacing
+                    .as_mut()
+                    .and_then(|pacing| pacing.due(stats::monotonic_now()));
+                if let Some(captured) = due {
+                    render::swap_frame(state, captured);
+                }
+            })
+            .map_err(|err| err.error)
+            .context("Failed to add timer to event loop")?;
+
+        // monitors come and go, or are replaced by ones not supporting the current mode
+        if let Some((hotplug_events, target_device)) = hotplug {
+            handle
+                .insert_source(hotplug_events, move |event, _, state: &mut CalloopState| {
+                    if let UdevEvent::Changed { device_id } = event {
+                        if device_id == target_device {
+                            target_hotplug(state);
+                            check_link(state);
+                        }
+                    }
+                })
+                .map_err(|err| err.error)
+                .context("Failed to add udev source to event loop")?;
+        }
+
+        // exit through the end of run, to power the output off
+        let stop_signal = event_loop.get_signal();
+        handle
+            .insert_source(stop_source, move |_, _, state: &mut CalloopState| {
+                slog::info!(state.wayland_state.log, "Stop requested, exiting");
+                stop_signal.stop();
+            })
+            .map_err(|err| err.error)
+            .context("Failed to add stop handle to event loop")?;
+        handle
+            .insert_source(reinit_source, |_, _, state: &mut CalloopState| {
+                slog::info!(state.wayland_state.log, "Reinitialization requested");
+                state.reinit_pending = true;
+                state.next_reinit = Instant::now();
+            })
+            .map_err(|err| err.error)
+            .context("Failed to add reinitialize handle to event loop")?;
+        if stats_file.is_some() || stats_socket.is_some() {
+            let exporter = status::StatusExporter::new(stats_file, stats_socket, log.clone())?;
+            let status_timer = Timer::new().context("Failed to create timer")?;
+            status_timer.handle().add_timeout(Duration::ZERO, ());
+            handle
+                .insert_source(status_timer, move |_, timer, state: &mut CalloopState| {
+                    timer.add_timeout(STATUS_INTERVAL, ());
+                    exporter.publish(&current_status(state, state.started.elapsed()));
+                })
+                .map_err(|err| err.error)
+                .context("Failed to add timer to event loop")?;
+        }
+        // stops just like a signal, so reaching the limit is a success
+        if let Some(duration) = duration {
+            let duration_timer = Timer::new().context("Failed to create timer")?;
+            duration_timer.handle().add_timeout(duration, ());
+            let exit_signal = event_loop.get_signal();
+            handle
+                .insert_source(duration_timer, move |_, _, state: &mut CalloopState| {
+                    slog::info!(state.wayland_state.log, "Ran for {:?}, exiting", duration);
+                    exit_signal.stop();
+                })
+                .map_err(|err| err.error)
+                .context("Failed to add timer to event loop")?;
+        }
+        if exit_on_signals {
+            let exit_signal = event_loop.get_signal();
+            handle
+                .insert_source(
+                    Signals::new(&[Signal::SIGINT, Signal::SIGTERM]).with_context(|| "Failed to block signals")?,
+                    move |event, _, state: &mut CalloopState| {
+                        slog::info!(state.wayland_state.log, "Received {:?}, exiting", event.signal());
+                        exit_signal.stop();
+                    },
+                )
+                .map_err(|err| err.error)
+                .context("Failed to add signals to event loop")?;
+        }
+        Ok(())
+    })();
+    match setup {
+        // nothing happens without an event, every periodic check is woken up for by `schedule_housekeeping`
+        Ok(()) => {
+            let result = event_loop.run(None, &mut state, |state| {
+                iterate(state);
+                track_state(state);
+                // comes after the flush in `iterate`,
+                // so waiting for the previous flip does not hold back the compositor
+                render::acquire_frame(&mut state.wayland_state);
+                schedule_housekeeping(state);
+            });
+            if let Err(err) = result {
+                fail(&mut state, anyhow::Error::from(err).context("Event loop failed"));
+            }
+        }
+        Err(err) => fail(&mut state, err),
+    }
+
+    #[cfg(feature = "nvenc")]
+    if let (Some(mut recorder), Some(target)) =
+        (state.wayland_state.recorder.take(), state.wayland_state.target.as_mut())
+    {
+        recorder.stop(&mut target.renderer);
+    }
+    if !keep_display_on {
+        set_target_dpms(&mut state.wayland_state, kms::Dpms::Off);
+    }
+    // the sources would otherwise stay dark without us
+    if let Some(connection) = state.connection.as_mut() {
+        if let Some(power) = connection.power.as_mut() {
+            power.power_on();
+            let _ = connection.display.flush();
+        }
+    }
+    match state.error.take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
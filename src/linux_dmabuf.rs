@@ -0,0 +1,168 @@
+// Re-export only the actual code, and then only use this re-export
+// The `generated` module below is just some boilerplate to properly isolate stuff
+// and avoid exposing internal details.
+pub use generated::client::{zwp_linux_dmabuf_feedback_v1, zwp_linux_dmabuf_v1};
+
+mod generated {
+    // The generated code tends to trigger a lot of warnings
+    // so we isolate it into a very permissive module
+    #![allow(dead_code,non_camel_case_types,unused_unsafe,unused_variables)]
+    #![allow(non_upper_case_globals,non_snake_case,unused_imports)]
+
+    pub mod client {
+        // These imports are used by the generated code
+        pub(crate) use wayland_client::{Main, Attached, Proxy, ProxyMap, AnonymousObject};
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::{Interface, MessageGroup};
+        pub(crate) use wayland_commons::wire::{Argument, MessageDesc, ArgumentType, Message};
+        pub(crate) use wayland_commons::smallvec;
+        pub(crate) use wayland_client::sys;
+        pub(crate) use wayland_client::protocol::{wl_buffer, wl_surface};
+        include!(concat!(env!("OUT_DIR"), "/linux_dmabuf.rs"));
+    }
+}
+
+use nix::{
+    sys::mman::{mmap, munmap, MapFlags, ProtFlags},
+    unistd::close,
+};
+use smithay::backend::allocator::{Format, Fourcc, Modifier};
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    convert::{TryFrom, TryInto},
+    os::unix::io::RawFd,
+    rc::Rc,
+};
+
+use wayland_client::{Attached, DispatchData, protocol::wl_registry};
+
+#[derive(Default)]
+struct Feedback {
+    table: Vec<(u32, u64)>,
+    main_device: Option<u64>,
+    tranche_device: Option<u64>,
+    tranche_formats: Vec<u16>,
+    pending: HashSet<Format>,
+    formats: HashSet<Format>,
+}
+
+/// Collects the formats and modifiers the compositor can handle on its main device.
+///
+/// Uses the feedback mechanism of version 4, if available, and the `modifier` events otherwise.
+pub struct LinuxDmabufHandler {
+    global: Option<Attached<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>>,
+    feedback: Rc<RefCell<Feedback>>,
+}
+
+impl LinuxDmabufHandler {
+    pub fn new() -> LinuxDmabufHandler {
+        LinuxDmabufHandler { global: None, feedback: Rc::new(RefCell::new(Feedback::default())) }
+    }
+
+    /// Format-modifier pairs usable on the compositors main device
+    pub fn formats(&self) -> HashSet<Format> {
+        self.feedback.borrow().formats.clone()
+    }
+}
+
+fn read_format_table(fd: RawFd, size: u32) -> Vec<(u32, u64)> {
+    let mut table = Vec::new();
+    unsafe {
+        if let Ok(ptr) = mmap(
+            std::ptr::null_mut(),
+            size as usize,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_PRIVATE,
+            fd,
+            0,
+        ) {
+            let data = std::slice::from_raw_parts(ptr as *const u8, size as usize);
+            for entry in data.chunks_exact(16) {
+                table.push((
+                    u32::from_ne_bytes(entry[0..4].try_into().unwrap()),
+                    u64::from_ne_bytes(entry[8..16].try_into().unwrap()),
+                ));
+            }
+            let _ = munmap(ptr, size as usize);
+        }
+    }
+    let _ = close(fd);
+    table
+}
+
+fn dev_t(device: &[u8]) -> Option<u64> {
+    device.try_into().ok().map(u64::from_ne_bytes)
+}
+
+fn format(code: u32, modifier: u64) -> Option<Format> {
+    Fourcc::try_from(code).ok().map(|code| Format {
+        code,
+        modifier: Modifier::from(modifier),
+    })
+}
+
+impl smithay_client_toolkit::environment::GlobalHandler<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1> for LinuxDmabufHandler {
+    fn created(
+        &mut self,
+        registry: Attached<wl_registry::WlRegistry>,
+        id: u32,
+        version: u32,
+        _: DispatchData,
+    ) {
+        let version = std::cmp::min(version, 4);
+        let linux_dmabuf = registry.bind::<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>(version, id);
+        let feedback_store = self.feedback.clone();
+        if version >= 4 {
+            linux_dmabuf.quick_assign(|_, _, _| {});
+            let feedback = linux_dmabuf.get_default_feedback();
+            feedback.quick_assign(move |_, event, _| {
+                let mut feedback = feedback_store.borrow_mut();
+                match event {
+                    zwp_linux_dmabuf_feedback_v1::Event::FormatTable { fd, size } => {
+                        feedback.table = read_format_table(fd, size);
+                    },
+                    zwp_linux_dmabuf_feedback_v1::Event::MainDevice { device } => {
+                        feedback.main_device = dev_t(&device);
+                    },
+                    zwp_linux_dmabuf_feedback_v1::Event::TrancheTargetDevice { device } => {
+                        feedback.tranche_device = dev_t(&device);
+                    },
+                    zwp_linux_dmabuf_feedback_v1::Event::TrancheFormats { indices } => {
+                        feedback.tranche_formats.extend(
+                            indices.chunks_exact(2).map(|x| u16::from_ne_bytes([x[0], x[1]]))
+                        );
+                    },
+                    zwp_linux_dmabuf_feedback_v1::Event::TrancheDone => {
+                        let indices = std::mem::take(&mut feedback.tranche_formats);
+                        if feedback.tranche_device.take() == feedback.main_device {
+                            let formats = indices
+                                .into_iter()
+                                .flat_map(|idx| feedback.table.get(idx as usize).copied())
+                                .flat_map(|(code, modifier)| format(code, modifier))
+                                .collect::<Vec<_>>();
+                            feedback.pending.extend(formats);
+                        }
+                    },
+                    zwp_linux_dmabuf_feedback_v1::Event::Done => {
+                        feedback.formats = std::mem::take(&mut feedback.pending);
+                    },
+                    _ => {},
+                }
+            });
+        } else {
+            linux_dmabuf.quick_assign(move |_, event, _| {
+                if let zwp_linux_dmabuf_v1::Event::Modifier { format: code, modifier_hi, modifier_lo } = event {
+                    let modifier = ((modifier_hi as u64) << 32) | modifier_lo as u64;
+                    feedback_store.borrow_mut().formats.extend(format(code, modifier));
+                }
+            });
+        }
+        self.global = Some((*linux_dmabuf).clone());
+    }
+
+    fn get(&self) -> Option<Attached<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>> {
+        self.global.clone()
+    }
+}
@@ -1,146 +1,72 @@
-use anyhow::Context;
-use calloop::{generic::Generic, Dispatcher, EventLoop, Interest, PostAction};
 use clap::{App, Arg, SubCommand};
-use sctk::environment::Environment;
+use nvscreencopy::{
+    parse_fourcc, parse_modeline, parse_transform, Adjustments, CaptureBackendKind, CaptureRate, ColorDepth,
+    CopyPathKind, CursorMode, Downscale, FilterKind, HdrMetadataSource, HeadlessMode, IdleDetect, Layout, Options,
+    OutputKind, OutputLayerKind, Placement, PlaneSelection, PlaneType, PropertyAssignment, QueuePolicy, RawHeader,
+    ScreenCopy, SessionKind, SourceSelector, SourceSpec, SplitCommand, StreamOptions, SwapFailurePolicy,
+    TargetBackendKind, MAX_FIFO_LENGTH, TRANSFORMS,
+};
 use slog::{o, Drain};
 use smithay::{
-    backend::{
-        allocator::{
-            dmabuf::{Dmabuf, DmabufBuilder, DmabufFlags},
-            Fourcc, Modifier,
-        },
-        drm::{DrmDevice, DrmEvent},
-        renderer::gles2::Gles2Texture,
-    },
-    reexports::drm::control::{
-        connector::{Interface, State as ConnectorState},
-        Device,
-    },
-    utils::{Physical, Size},
-};
-use smithay_client_toolkit::{
-    self as sctk,
-    reexports::client::{protocol::wl_output, Display},
-    reexports::protocols::wlr::unstable::export_dmabuf::v1::client::{
-        zwlr_export_dmabuf_frame_v1::{self as export_dmabuf_frame, Event as ExportDmabufEvent},
-        zwlr_export_dmabuf_manager_v1::ZwlrExportDmabufManagerV1 as ExportDmabufManager,
-    },
+    reexports::drm::control::connector::State as ConnectorState,
+    utils::{Logical, Rectangle},
 };
-use wayland_client::{DispatchData, EventQueue, Main};
 
 use std::{
-    convert::TryFrom,
-    path::PathBuf,
-    sync::atomic::{AtomicBool, Ordering},
+    ffi::OsString,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
-mod drm;
-mod egl;
-mod gpu;
-mod render;
-use self::drm::{wl_drm, WlDrmHandler};
-
-struct Env {
-    outputs: sctk::output::OutputHandler,
-    export_dmabuf: sctk::environment::SimpleGlobal<ExportDmabufManager>,
-    drm: WlDrmHandler,
-}
-
-sctk::environment!(Env,
-    singles = [
-        ExportDmabufManager => export_dmabuf,
-        wl_drm::WlDrm => drm,
-    ],
-    multis = [
-        wl_output::WlOutput => outputs,
-    ]
-);
+/// Capture to swap latency of `--frame-pacing` without a value, about half a frame at 60Hz
+const DEFAULT_LATENCY_BUDGET_MS: u64 = 8;
+const DEFAULT_GPU_TIMEOUT_SECS: u64 = 30;
+/// Idle time of `--manage-source-power` without a value, before the sources are powered off
+const DEFAULT_SOURCE_POWER_GRACE_SECS: u64 = 10;
 
-enum CopyState {
-    DirectImport,
-    CPUCopy,
-}
-
-pub struct WaylandState {
-    target: gpu::TargetGPU,
-    render: gpu::RenderGPU,
-    dmabuf: Option<(DmabufBuilder, u64)>,
-    try_again: AtomicBool,
-    dest_size: Size<i32, Physical>,
-    buffer: Vec<u8>,
-    texture: Gles2Texture,
-    copy: Option<CopyState>,
-    log: slog::Logger,
+/// Parses a region in the format "X,Y,WxH"
+fn parse_crop(input: &str) -> Result<Rectangle<i32, Logical>, String> {
+    let parts = input
+        .split(|c| c == ',' || c == 'x')
+        .map(|x| u32::from_str_radix(x, 10))
+        .map(|x| x.map(|x| x as i32))
+        .collect::<Result<Vec<i32>, _>>()
+        .map_err(|err| format!("Failed to parse numeric values of crop: {}", err))?;
+    if parts.len() != 4 {
+        return Err(String::from("Crop needs to have the format \"X,Y,WIDTHxHEIGHT\""));
+    }
+    if parts[2] == 0 || parts[3] == 0 {
+        return Err(String::from("Crop with an empty region"));
+    }
+    Ok(Rectangle::from_loc_and_size((parts[0], parts[1]), (parts[2], parts[3])))
 }
 
-struct CalloopState {
-    wayland_state: WaylandState,
-    output: wl_output::WlOutput,
-    event_queue: EventQueue,
-    environment: Environment<Env>,
+/// Parses a color in the format "#RRGGBB" or "#RGB" into opaque RGBA
+fn parse_color(input: &str) -> Result<[f32; 4], String> {
+    let hex = input
+        .strip_prefix('#')
+        .ok_or_else(|| String::from("Color needs to start with '#'"))?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Color contains non-hexadecimal digits: {}", input));
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).unwrap() as f32 / 255.0;
+    match hex.len() {
+        // every digit is repeated, so "#f80" is "#ff8800"
+        3 => {
+            let c = |i: usize| channel(&hex[i..=i].repeat(2));
+            Ok([c(0), c(1), c(2), 1.0])
+        }
+        6 => Ok([channel(&hex[0..2]), channel(&hex[2..4]), channel(&hex[4..6]), 1.0]),
+        _ => Err(String::from("Color needs to have the format \"#RRGGBB\" or \"#RGB\"")),
+    }
 }
 
-pub fn handle_frame(
-    frame: Main<export_dmabuf_frame::ZwlrExportDmabufFrameV1>,
-    event: ExportDmabufEvent,
-    mut data: DispatchData,
-) {
-    let mut state: &mut WaylandState = data.get().unwrap();
-    match event {
-        ExportDmabufEvent::Frame {
-            width,
-            height,
-            buffer_flags,
-            format,
-            mod_high,
-            mod_low,
-            ..
-        } => {
-            state.dmabuf = Some((
-                Dmabuf::builder(
-                    (width as i32, height as i32),
-                    Fourcc::try_from(format).unwrap(),
-                    DmabufFlags::from_bits_truncate(buffer_flags),
-                ),
-                (((mod_high as u64) << 32) | mod_low as u64),
-            ));
-        }
-        ExportDmabufEvent::Object {
-            fd,
-            offset,
-            stride,
-            plane_index,
-            ..
-        } => {
-            let (dmabuf, modifier) = state
-                .dmabuf
-                .as_mut()
-                .expect("Object event before Frame event");
-            dmabuf.add_plane(fd, plane_index, offset, stride, Modifier::from(*modifier));
-        }
-        ExportDmabufEvent::Ready { .. } => {
-            slog::debug!(state.log, "Frame ready");
-            let (dmabuf, _) = state
-                .dmabuf
-                .take()
-                .expect("Object event before Frame event");
-            let buf = dmabuf.build().expect("Failed to build dmabuf");
-            slog::debug!(state.log, "Original Dmabuf: {:?}", buf);
-            render::render_dmabuf(state, buf).expect("Failed to render");
-            frame.destroy();
-        }
-        ExportDmabufEvent::Cancel {
-            reason: export_dmabuf_frame::CancelReason::Permanent,
-        } => panic!("Output died"),
-        ExportDmabufEvent::Cancel { .. } => {
-            slog::debug!(state.log, "Frame cancelled");
-            frame.destroy();
-            state
-                .try_again
-                .store(true, std::sync::atomic::Ordering::SeqCst);
-        }
-        _ => panic!("Unknown export-dmabuf event"),
+/// Parses a value of --brightness, --contrast or --gamma, ranges are enforced later
+fn parse_adjustment(input: &str) -> Result<f32, String> {
+    match input.parse::<f32>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        Ok(_) => Err(format!("Not a finite number: {}", input)),
+        Err(err) => Err(format!("Failed to parse adjustment: {}", err)),
     }
 }
 
@@ -153,12 +79,69 @@ fn main() -> anyhow::Result<()> {
             .short("c")
             .long("connector")
             .value_name("NAME")
-            .help("Connector to clone onto. By default takes the first connected one it finds")
+            .help("Connector to clone onto, by its name, by \"id:N\" with its drm object id or by the name or serial of the monitor plugged into it (case-insensitive, as shown by list-connectors). By default takes the first connected one it finds")
+            .takes_value(true))
+        .arg(Arg::with_name("CONNECTOR_PROP")
+            .long("connector-prop")
+            .value_name("NAME=VALUE")
+            .help("Sets a property of the connector before the output is set up, can be repeated. Enum values are given by name, e.g. \"Broadcast RGB=Full\" against crushed blacks on TVs, \"max bpc=10\" or \"underscan=on\" with \"underscan hborder\" and \"underscan vborder\".")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|input| input.parse::<PropertyAssignment>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("COLORSPACE")
+            .long("colorspace")
+            .value_name("NAME")
+            .help("Sets the \"Colorspace\" property of the connector, e.g. \"BT2020_RGB\" for HDR content. The frames are passed through unchanged, so the source has to be encoded for it already. Needs 10 bit scanout and atomic modesetting.")
+            .takes_value(true))
+        .arg(Arg::with_name("HDR_METADATA")
+            .long("hdr-metadata")
+            .value_name("FILE|auto")
+            .help("Sends HDR metadata of PQ content to the monitor, read from a JSON file or \"auto\" for BT.2020 mastered at 1000 nits. Needs 10 bit scanout and atomic modesetting.")
+            .validator(|input| input.parse::<HdrMetadataSource>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("DEVICE_INDEX")
+            .long("device-index")
+            .value_name("N")
+            .help("Nvidia gpu to clone onto, counting from 0. By default takes the first one with a connected connector")
+            .validator(|input| {
+                usize::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse device index: {}", err))
+            })
             .takes_value(true))
         .arg(Arg::with_name("SRC")
             .short("s")
             .long("source")
-            .help("Sets the monitor to copy from, checks by comparing the monitor make to contain the given value. Default is \"headless\".")
+            .value_name("NAME[@X,Y]")
+            .help("Sets the monitor to copy from, checks by comparing the monitor make to contain the given value, which needs to match a single output. \"exact:NAME\" matches the whole make, \"regex:PATTERN\" matches the make against a regular expression and \"model:NAME\" checks the model instead. Default is \"headless\". Can be repeated to show multiple sources side by side, each unscaled at the given position of the output, e.g. \"--source HEADLESS-1@0,0 --source HEADLESS-2@1920,0\".")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|input| input.parse::<SourceSpec>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("SOURCE_EXACT")
+            .long("source-exact")
+            .help("Match the whole make of the outputs against --source, instead of part of it"))
+        .arg(Arg::with_name("SOURCE_GLOBAL_ID")
+            .long("source-global-id")
+            .value_name("N")
+            .help("Sets the monitor to copy from by the registry name of its wl_output, as shown by list-sources, instead of matching its make. The id changes if the output goes away and comes back.")
+            .conflicts_with_all(&["SRC", "SOURCE_EXACT", "SOURCE_INDEX"])
+            .validator(|input| {
+                u32::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse global id: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("SOURCE_INDEX")
+            .long("source-index")
+            .value_name("N")
+            .help("Picks among several outputs matching --source, counting from 0 in the order they are listed if it is ambiguous. By default several matching outputs are an error.")
+            .validator(|input| {
+                usize::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse source index: {}", err))
+            })
             .takes_value(true))
         .arg(Arg::with_name("MODE")
             .short("m")
@@ -177,15 +160,515 @@ fn main() -> anyhow::Result<()> {
             })
             .takes_value(true)
         )
+        .arg(Arg::with_name("MODELINE")
+            .long("modeline")
+            .value_name("MODELINE")
+            .help("Sets the outputs mode by timings instead of picking one the monitor advertises, for monitors whose EDID lacks modes they support. Format \"PCLK HDISP HSYNCSTART HSYNCEND HTOTAL VDISP VSYNCSTART VSYNCEND VTOTAL [+/-hsync +/-vsync]\" with the pixel clock in MHz, as printed by cvt(1).")
+            .conflicts_with("MODE")
+            .validator(|input| parse_modeline(&input).map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("CROP")
+            .long("crop")
+            .value_name("X,Y,WxH")
+            .help("Only mirror the given region of the source, in logical coordinates of the source. Without --mode the region also determines the outputs mode.")
+            .validator(|input| parse_crop(&input).map(|_| ()))
+            .takes_value(true))
+        .arg(Arg::with_name("POSITION")
+            .long("position")
+            .value_name("X,Y|center")
+            .help("Shows the source unscaled at the given position of a larger mode instead of scaling it, e.g. with --mode 3840x2160. The rest of the output shows the background color.")
+            .validator(|input| input.parse::<Placement>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("LAYOUT")
+            .long("layout")
+            .value_name("grid|COLSxROWS")
+            .help("Shows every output matching one of the --source values at once, each scaled into a cell of a grid and letterboxed if its aspect ratio differs. grid picks the columns and rows by the number of outputs, e.g. 2x2 for three. A cell whose output is gone shows the background and its name.")
+            .validator(|input| input.parse::<Layout>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("BACKGROUND")
+            .long("background")
+            .value_name("#RRGGBB")
+            .help("Color of the output outside of the mirrored content and while there is nothing to mirror")
+            .default_value("#000000")
+            .validator(|input| parse_color(&input).map(|_| ()))
+            .takes_value(true))
+        .arg(Arg::with_name("SPLASH")
+            .long("splash")
+            .value_name("FILE")
+            .help("PNG shown on the output before the first frame and while the sources are gone, instead of the background color. Keeps the output powered on meanwhile.")
+            .takes_value(true))
+        .arg(Arg::with_name("BRIGHTNESS")
+            .long("brightness")
+            .value_name("VALUE")
+            .help("Added to the colors of the mirrored content, between -1 and 1")
+            .default_value("0")
+            .allow_hyphen_values(true)
+            .validator(|input| parse_adjustment(&input).map(|_| ()))
+            .takes_value(true))
+        .arg(Arg::with_name("CONTRAST")
+            .long("contrast")
+            .value_name("VALUE")
+            .help("Contrast of the mirrored content, between 0 and 4")
+            .default_value("1")
+            .validator(|input| parse_adjustment(&input).map(|_| ()))
+            .takes_value(true))
+        .arg(Arg::with_name("GAMMA")
+            .long("gamma")
+            .value_name("VALUE")
+            .help("Gamma applied to the mirrored content, between 0.1 and 10")
+            .default_value("1")
+            .validator(|input| parse_adjustment(&input).map(|_| ()))
+            .takes_value(true))
+        .arg(Arg::with_name("GAMMA_LUT")
+            .long("gamma-lut")
+            .value_name("FILE")
+            .help("Loads a gamma table into the crtc of the output, restoring the previous one on exit. Either an ICC profile with a VCGT tag or a CSV file of 256 or 1024 \"red,green,blue\" lines, as integers up to 65535 or fractions between 0 and 1. --gamma is folded into the table.")
+            .takes_value(true))
+        .arg(Arg::with_name("IDLE_DETECT")
+            .long("idle-detect")
+            .value_name("MODE")
+            .help("How frames that did not change are found, which are then neither uploaded nor shown again. damage uses the damage reported by screencopy or found by comparing frames read back (unless --no-damage), hash compares a sample of the pixels.")
+            .possible_values(IdleDetect::VARIANTS)
+            .default_value("damage")
+            .takes_value(true))
+        .arg(Arg::with_name("OUTPUT")
+            .long("output")
+            .value_name("OUTPUT")
+            .help("Where frames go: drm scans them out on the nvidia gpu, raw:PATH writes them to PATH (\"-\" for stdout) as raw BGRA frames, e.g. for piping into ffmpeg, pipewire offers them as PipeWire node. The latter two need no gpu. offscreen[:WxH] renders them on the gpu of the compositor without showing them, for testing without a monitor.")
+            .default_value("drm")
+            .validator(|input| input.parse::<OutputKind>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("RAW_HEADER")
+            .long("raw-header")
+            .value_name("HEADER")
+            .help("Written before the frames of the raw output: nothing, or a line of JSON with the format, size and frame rate")
+            .possible_values(RawHeader::VARIANTS)
+            .default_value("none")
+            .takes_value(true))
+        .arg(Arg::with_name("SEAT")
+            .long("seat")
+            .value_name("SEAT")
+            .help("Seat whose gpus are searched for the output. By default the seat of the session in XDG_SEAT or seat0, gpus of other seats are searched if none is assigned to it.")
+            .takes_value(true))
+        .arg(Arg::with_name("SESSION_BACKEND")
+            .long("session-backend")
+            .value_name("BACKEND")
+            .help("How the nvidia gpu is opened. By default it is taken from logind and opened directly without a logind session.")
+            .possible_values(SessionKind::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("TARGET_BACKEND")
+            .long("target-backend")
+            .value_name("BACKEND")
+            .help("How frames are scanned out. By default EGLStreams are used on nvidia gpus and gbm on all others.")
+            .possible_values(TargetBackendKind::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("CAPTURE_BACKEND")
+            .long("capture-backend")
+            .value_name("BACKEND")
+            .help("Protocol used to capture the source. By default export-dmabuf is used and screencopy if the former is unavailable. portal captures through xdg-desktop-portal, e.g. on GNOME.")
+            .possible_values(CaptureBackendKind::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("CAPTURE_RATE")
+            .long("capture-rate")
+            .value_name("HZ|vblank")
+            .help("Frames per second captured from the source. By default a frame is captured on every vblank of the output, with a rate captures run on their own timer and every vblank shows the newest frame.")
+            .default_value("vblank")
+            .validator(|input| input.parse::<CaptureRate>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("COPY_PATH")
+            .long("copy-path")
+            .value_name("PATH")
+            .help("How frames get to the nvidia gpu. By default they are scanned out as they are if the output can show them unchanged, imported directly otherwise and copied through the cpu if that keeps failing.")
+            .possible_values(CopyPathKind::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("DOWNSCALE_ON_RENDER")
+            .long("downscale-on-render")
+            .value_name("MODE")
+            .help("Scale frames copied through the cpu to the size they are shown in on the render gpu, before reading them back. By default this is done if the output is smaller than the source, e.g. to read back a 4K source for a 1080p output at a quarter of the cost.")
+            .possible_values(Downscale::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("CURSOR")
+            .long("cursor")
+            .value_name("MODE")
+            .help("Where the cursor is drawn. composited leaves it in the captured frames, plane shows it on the cursor plane of the output, following the pointer without the latency of capturing. plane needs Hyprland and falls back to composited otherwise.")
+            .possible_values(CursorMode::VARIANTS)
+            .default_value("composited")
+            .takes_value(true))
+        .arg(Arg::with_name("OUTPUT_LAYER")
+            .long("output-layer")
+            .value_name("LAYER")
+            .help("Which output layer shows the frames. By default the one of the plane is used and the one of the crtc if the driver has none.")
+            .possible_values(OutputLayerKind::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("OUTPUT_LAYER_INDEX")
+            .long("output-layer-index")
+            .value_name("N")
+            .help("Use the Nth of the output layers the driver lists for the plane or crtc, counting from 0. By default the one bound to the crtc of the output is used, the debug log lists all of them.")
+            .validator(|input| {
+                usize::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse output layer index: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("PLANE")
+            .long("plane")
+            .value_name("ID|primary|overlay")
+            .help("Plane the frames are shown on, by its id as shown by list-planes. overlay takes the first overlay plane usable with the crtc. By default the primary plane is used.")
+            .validator(|input| input.parse::<PlaneSelection>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("FILTER")
+            .long("filter")
+            .value_name("FILTER")
+            .help("How the source is sampled when it is scaled. By default nearest is used for integer scale factors and linear otherwise.")
+            .possible_values(FilterKind::VARIANTS)
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("LINEAR_BLENDING")
+            .long("linear-blending")
+            .help("Scales the source in linear light instead of on its sRGB values, which keeps downscaled text from looking darker. Applies to the target and to --downscale-on-render, costs a few more texture reads per pixel."))
+        .arg(Arg::with_name("TRANSFORM")
+            .long("transform")
+            .value_name("TRANSFORM")
+            .help("Rotates the output counter-clockwise, flipped variants mirror it horizontally first. Done by the plane if the driver supports it and by rendering rotated otherwise. Without --mode the mode of the source is rotated as well.")
+            .possible_values(TRANSFORMS)
+            .default_value("normal")
+            .takes_value(true))
+        .arg(Arg::with_name("ORIENTATION_FOLLOW_SOURCE")
+            .long("orientation-follow-source")
+            .value_name("on|off")
+            .help("Undo the transform of the source output, e.g. set by \"swaymsg output HEADLESS-1 transform 90\", so its content is shown upright and letterboxed, on top of --transform. Follows the source being rotated while mirroring.")
+            .possible_values(&["on", "off"])
+            .default_value("on")
+            .takes_value(true))
+        .arg(Arg::with_name("SOURCE_TIMEOUT")
+            .long("source-timeout")
+            .value_name("SECS")
+            .help("How long to wait for the source to reappear, if it vanishes while mirroring")
+            .default_value("30")
+            .validator(|input| {
+                u64::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse timeout: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("MANAGE_SOURCE_POWER")
+            .long("manage-source-power")
+            .value_name("SECS")
+            .help("Powers the source outputs off through wlr-output-power-management once the target was paused or gone for this long, e.g. to save the compositor rendering a phantom output nobody sees. They are powered on again right before capturing resumes. By default after 10 seconds.")
+            .validator(|input| {
+                u64::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse grace period: {}", err))
+            })
+            .min_values(0)
+            .max_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("WATCHDOG")
+            .long("watchdog")
+            .value_name("SECS")
+            .help("Restarts capturing once no frame arrived for this long, e.g. if the compositor stopped answering. A source that does not change may legitimately send no frames, so pick a timeout longer than it stays still.")
+            .validator(|input| match u64::from_str_radix(&input, 10) {
+                Ok(x) if x > 0 => Ok(()),
+                Ok(_) => Err(String::from("Watchdog timeout needs to be at least 1 second")),
+                Err(err) => Err(format!("Failed to parse timeout: {}", err)),
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("WATCHDOG_ESCALATE")
+            .long("watchdog-escalate")
+            .value_name("N")
+            .help("Watchdog firings in a row, after which the capture backend is set up again and the sources are looked up anew")
+            .default_value("3")
+            .validator(|input| match u32::from_str_radix(&input, 10) {
+                Ok(x) if x > 0 => Ok(()),
+                Ok(_) => Err(String::from("Needs to be at least 1")),
+                Err(err) => Err(format!("Failed to parse number: {}", err)),
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("QUEUE_POLICY")
+            .long("queue-policy")
+            .value_name("POLICY")
+            .help("What happens to export-dmabuf frames arriving while the output still waits for the previous one. latest only keeps the newest one and drops the others, all renders every frame in order, e.g. for --record.")
+            .possible_values(QueuePolicy::VARIANTS)
+            .default_value("latest")
+            .takes_value(true))
+        .arg(Arg::with_name("PIPELINE")
+            .long("pipeline")
+            .value_name("N")
+            .help("Maximum number of export-dmabuf frames in flight. Higher values reduce latency at the cost of gpu load.")
+            .default_value("1")
+            .validator(|input| match usize::from_str_radix(&input, 10) {
+                Ok(0) => Err(String::from("Pipeline depth needs to be at least 1")),
+                Ok(_) => Ok(()),
+                Err(err) => Err(format!("Failed to parse pipeline depth: {}", err)),
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("ENSURE_HEADLESS")
+            .long("ensure-headless")
+            .value_name("WxH[@Hz]")
+            .help("Creates a headless output on sway to mirror and removes it again on exit. By default it uses --mode or the preferred mode of the connector.")
+            .validator(|input| input.parse::<HeadlessMode>().map(|_| ()).map_err(|err| err.to_string()))
+            .min_values(0)
+            .max_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("COLOR_DEPTH")
+            .long("color-depth")
+            .value_name("BITS")
+            .help("Bits per color channel used for copying and scanout. By default 8 bit is used, unless --colorspace or --hdr-metadata need 10 bit.")
+            .possible_values(&["auto", "8", "10"])
+            .default_value("auto")
+            .takes_value(true))
+        .arg(Arg::with_name("FRAME_PACING")
+            .long("frame-pacing")
+            .value_name("MS")
+            .help("Shows every frame a fixed time after it was captured, which smooths out sources and outputs with slightly different refresh rates. By default frames are held back for 8ms.")
+            .validator(|input| {
+                u64::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse latency budget: {}", err))
+            })
+            .min_values(0)
+            .max_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("RECORD")
+            .long("record")
+            .value_name("FILE")
+            .help("Also encodes everything shown on the output with NVENC into an h264 file. Needs nvscreencopy built with --features nvenc.")
+            .takes_value(true))
+        .arg(Arg::with_name("RENDER_DEVICE")
+            .long("render-device")
+            .value_name("PATH")
+            .help("Reads captured frames back on this drm device instead of the gpu of the compositor advertised by wl_drm, e.g. /dev/dri/renderD129. The log warns if it can't import buffers of the compositor.")
+            .takes_value(true))
+        .arg(Arg::with_name("STATS_FILE")
+            .long("stats-file")
+            .value_name("PATH")
+            .help("Rewrites PATH every second with a JSON object of the state, source, connector, mode, frame rates, copy path, last error and uptime, e.g. for status bars")
+            .takes_value(true))
+        .arg(Arg::with_name("STATS_SOCKET")
+            .long("stats-socket")
+            .value_name("PATH")
+            .help("Listens on the unix socket PATH and answers every connection with a line of the same JSON object as --stats-file")
+            .takes_value(true))
+        .arg(Arg::with_name("FRAMES")
+            .long("frames")
+            .value_name("N")
+            .help("Exits after N frames were shown on the output, just like on SIGTERM")
+            .validator(|input| match u64::from_str_radix(&input, 10) {
+                Ok(x) if x > 0 => Ok(()),
+                Ok(_) => Err(String::from("Needs to be at least 1")),
+                Err(err) => Err(format!("Failed to parse number: {}", err)),
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("DURATION")
+            .long("duration")
+            .value_name("SECS")
+            .help("Exits after running this long, just like on SIGTERM. Along --frames whichever comes first ends it.")
+            .validator(|input| match input.parse::<f64>() {
+                Ok(x) if x.is_finite() && x > 0.0 => Ok(()),
+                _ => Err(format!("Duration needs to be a positive number of seconds: {}", input)),
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("DRY_RUN")
+            .long("dry-run")
+            .help("Finds the source, gpu, connector, mode and stream config and logs what would be done, then exits without setting a mode"))
+        .arg(Arg::with_name("AUTO_SOURCE")
+            .long("auto-source")
+            .help("If there is no headless output, mirror the only output that is not a built-in panel instead of failing"))
+        .arg(Arg::with_name("ASYNC_READBACK")
+            .long("async-readback")
+            .help("Read back frames asynchronously when copying through the cpu. Increases throughput, but adds a frame of latency."))
+        .arg(Arg::with_name("OVERLAY")
+            .long("overlay")
+            .help("Shows frame rates, the copy path and the last error in the top left corner of the output"))
+        .arg(Arg::with_name("TRACE_FRAMES")
+            .long("trace-frames")
+            .help("Numbers every captured frame and logs a line with a timestamp for each stage it passes, from the capture request to the vblank showing it"))
+        .arg(Arg::with_name("STREAM_FIFO")
+            .long("stream-fifo")
+            .value_name("N")
+            .help("Number of frames queued for the output. 0 always shows the newest frame, 1 to 3 trade latency for smoother playback.")
+            .default_value("0")
+            .validator(|input| match u32::from_str_radix(&input, 10) {
+                Ok(n) if n > MAX_FIFO_LENGTH => Err(format!("Stream FIFO can hold at most {} frames", MAX_FIFO_LENGTH)),
+                Ok(_) => Ok(()),
+                Err(err) => Err(format!("Failed to parse stream FIFO length: {}", err)),
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("ACQUIRE_TIMEOUT")
+            .long("acquire-timeout-us")
+            .value_name("USEC")
+            .help("Microseconds handing a frame to the output may wait for the previous flip. Longer waits block everything else, a busy output keeps the previous frame on screen and is retried instead.")
+            .default_value("2000")
+            .validator(|input| {
+                u32::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse acquire timeout: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("SWAP_INTERVAL")
+            .long("swap-interval")
+            .value_name("N")
+            .help("Vblanks between flips of the output. 0 disables vsync, higher values save power on high refresh rate outputs.")
+            .default_value("1")
+            .validator(|input| {
+                u32::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse swap interval: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("SWAP_FAILURE_LIMIT")
+            .long("swap-failure-limit")
+            .value_name("N")
+            .help("Temporary swap errors of the same kind in a row, after which --swap-failure-policy kicks in. 0 retries forever.")
+            .default_value("120")
+            .validator(|input| {
+                u32::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse swap failure limit: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("SWAP_FAILURE_POLICY")
+            .long("swap-failure-policy")
+            .value_name("POLICY")
+            .help("What to do once swaps keep failing: recreate the output like after a gpu reset, or exit")
+            .possible_values(SwapFailurePolicy::VARIANTS)
+            .default_value("recreate")
+            .takes_value(true))
+        .arg(Arg::with_name("THREADS")
+            .long("threads")
+            .value_name("N")
+            .help("Number of threads converting frames copied through the cpu, large frames are split among them. 0 converts them on the main thread.")
+            .default_value("1")
+            .validator(|input| {
+                usize::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse thread count: {}", err))
+            })
+            .takes_value(true))
+        .arg(Arg::with_name("REJECT_YUV")
+            .long("reject-yuv")
+            .help("Fail on yuv frames (e.g. NV12) instead of converting them on the gpu"))
+        .arg(Arg::with_name("FORCE_FORMAT")
+            .long("force-format")
+            .value_name("FOURCC")
+            .help("Only accept captured frames of this drm fourcc code (e.g. XR24), picking it among the buffers the compositor offers, for debugging")
+            .validator(|input| parse_fourcc(&input).map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("VERIFY_IMPORT")
+            .long("verify-import")
+            .help("Compare the first directly imported frame against a readback through the render gpu and copy through the cpu, if the import shows something else"))
+        .arg(Arg::with_name("LEGACY_MODESETTING")
+            .long("legacy-modesetting")
+            .help("Use the legacy drm api for the output, even if the driver supports atomic modesetting"))
+        .arg(Arg::with_name("NO_DAMAGE")
+            .long("no-damage")
+            .help("Always copy whole frames instead of only the regions that changed, useful when debugging artifacts"))
+        .arg(Arg::with_name("NO_NOTIFY")
+            .long("no-notify")
+            .help("Show no desktop notifications about state changes, which nvscreencopy built with --features notify sends"))
+        .arg(Arg::with_name("NO_RECONNECT")
+            .long("no-reconnect")
+            .help("Exit instead of waiting for the compositor to come back, if the connection is lost"))
+        .arg(Arg::with_name("NO_ROBUSTNESS")
+            .long("no-robustness")
+            .help("Exit on the first failed render instead of treating failures as gpu resets and recovering from them"))
+        .arg(Arg::with_name("NO_PERSIST")
+            .long("no-persist")
+            .help("Neither use nor record the mode, plane scaling and connector properties last used with the monitor"))
+        .arg(Arg::with_name("FORGET_MONITOR")
+            .long("forget-monitor")
+            .help("Forget what was recorded for the monitor on the connector and start over from the defaults")
+            .conflicts_with("NO_PERSIST"))
+        .arg(Arg::with_name("GL_DEBUG")
+            .long("gl-debug")
+            .help("Log the debug messages of the driver for the output context, if it supports KHR_debug"))
+        .arg(Arg::with_name("ALLOW_CRTC_STEAL")
+            .long("allow-crtc-steal")
+            .help("Take over a crtc driving another output, if the connector can't use any other. The other output goes dark."))
+        .arg(Arg::with_name("KEEP_DISPLAY_ON")
+            .long("keep-display-on")
+            .help("Leave the output powered on at exit, e.g. for another tool taking it over"))
+        .arg(Arg::with_name("PLANE_SCALING")
+            .long("plane-scaling")
+            .help("Keep frames in the size of the source and let the display engine scale them to the outputs mode, instead of scaling them while rendering. Needs atomic modesetting, falls back to scaling while rendering if the driver refuses."))
+        .arg(Arg::with_name("SPLIT_DISPLAY")
+            .long("split-display")
+            .help("Capture in this process and drive the output from a child process, connected by a socket. For when the EGL implementations of the render and the nvidia gpu don't get along in one process."))
+        .arg(Arg::with_name("SPLIT_DISPLAY_FD")
+            .long("split-display-fd")
+            .value_name("FD")
+            .hidden(true)
+            .validator(|input| input.parse::<i32>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("SPLIT_TARGET")
+            .long("split-target")
+            .value_name("N")
+            .hidden(true)
+            .requires("SPLIT_DISPLAY_FD")
+            .validator(|input| input.parse::<usize>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("EXTRA_TARGET")
+            .long("extra-target")
+            .value_name("N[:CONNECTOR]")
+            .help("Also mirror onto the nvidia gpu N, on CONNECTOR or its first connected connector, can be repeated. Implies --split-display: every gpu is driven by a display process of its own, while capturing and reading back happens once.")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|input| input.parse::<TargetSpec>().map(|_| ()).map_err(|err| err.to_string()))
+            .takes_value(true))
+        .arg(Arg::with_name("STRICT_MODE")
+            .long("strict-mode")
+            .help("Fail if the connector does not support the mode of the source or --mode, instead of using the closest one"))
+        .arg(Arg::with_name("VRR")
+            .long("vrr")
+            .help("Drive the output with variable refresh rate if the monitor supports it, showing frames as they arrive. Disables --frame-pacing."))
+        .arg(Arg::with_name("WAIT_FOR_CONNECTOR")
+            .long("wait-for-connector")
+            .help("Wait for a monitor to be plugged in at startup, instead of failing if none is connected"))
+        .arg(Arg::with_name("WAIT_FOR_GPU")
+            .long("wait-for-gpu")
+            .value_name("SECONDS")
+            .help("Wait for the nvidia gpu to show up at startup, e.g. while nvidia-drm is still loading. By default gives up after 30s.")
+            .validator(|input| {
+                u64::from_str_radix(&input, 10)
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to parse timeout: {}", err))
+            })
+            .min_values(0)
+            .max_values(1))
         .subcommand(SubCommand::with_name("list-sources")
                     .about("lists available sources"))
         .subcommand(SubCommand::with_name("list-connectors")
-                    .about("lists available sources"))
+                    .about("lists the connectors of the gpu along their drm object ids"))
+        .subcommand(SubCommand::with_name("list-gpus")
+                    .about("lists the gpus of all seats and whether they can be mirrored onto"))
+        .subcommand(SubCommand::with_name("list-planes")
+                    .about("lists the planes of the gpu and whether they can be used with --plane"))
+        .subcommand(SubCommand::with_name("restore")
+                    .about("puts the gpu back into the state it was in before nvscreencopy last started on it, e.g. after a crash")
+                    .arg(Arg::with_name("SNAPSHOT")
+                        .long("snapshot")
+                        .value_name("FILE")
+                        .help("Snapshot to restore, instead of the latest one of every gpu")
+                        .takes_value(true)))
+        .subcommand(SubCommand::with_name("test-pattern")
+                    .about("shows a test pattern on the output, without capturing anything")
+                    .arg(Arg::with_name("DURATION")
+                        .long("duration")
+                        .value_name("SECONDS")
+                        .help("How long the pattern is shown")
+                        .default_value("10")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .takes_value(true)))
         .get_matches();
 
-    
     // A logger facility, here we use the terminal here
-    let log = if matches.subcommand().1.is_some() {
+    let log = if matches.subcommand_name().map(|name| name.starts_with("list-")).unwrap_or(false) {
         slog::Logger::root(slog::Discard.fuse(), o!())
     } else {
         slog::Logger::root(slog_async::Async::default(slog_term::term_full().fuse()).fuse(), o!())
@@ -193,204 +676,366 @@ fn main() -> anyhow::Result<()> {
     let _guard = slog_scope::set_global_logger(log.clone());
     slog_stdlog::init().expect("Could not setup log backend");
 
-    let connector = matches.value_of("DEST");
-    let monitor = matches.value_of("SRC").unwrap_or("headless");
-    let dest_mode = matches.value_of("MODE").map(|x| {
-        let parts = x
-            .split("x")
-            .map(|x| u32::from_str_radix(x, 10))
-            .map(|x| x.map(|x| x as i32))
-            .collect::<Result<Vec<i32>, _>>()
-            .unwrap(); //already validated
-        (parts[0], parts[1])
-    });
-
-    // Connect to the wayland server
-    let client_display = Display::connect_to_env().unwrap();
-    let mut event_loop: EventLoop<'_, CalloopState> = EventLoop::try_new().unwrap();
-    let mut event_queue = client_display.create_event_queue();
-    let attached_display = client_display.attach(event_queue.token());
-    let environment = sctk::environment::Environment::new(
-        &attached_display,
-        &mut event_queue,
-        Env {
-            outputs: sctk::output::OutputHandler::new(),
-            export_dmabuf: sctk::environment::SimpleGlobal::new(),
-            drm: WlDrmHandler::new(),
+    let options = Options {
+        connector: matches.value_of("DEST").map(String::from),
+        device_index: matches
+            .value_of("DEVICE_INDEX")
+            .map(|index| usize::from_str_radix(index, 10).unwrap()), //already validated
+        seat: matches.value_of("SEAT").map(String::from),
+        sources: match matches.value_of("SOURCE_GLOBAL_ID") {
+            Some(id) => vec![SourceSpec {
+                selector: SourceSelector::GlobalId(u32::from_str_radix(id, 10).unwrap()), //already validated
+                position: None,
+                index: None,
+            }],
+            None => matches
+                .values_of("SRC")
+                .map(|values| {
+                    values
+                        .map(|value| value.parse::<SourceSpec>().unwrap()) //already validated
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_else(|| vec!["headless".parse::<SourceSpec>().unwrap()])
+                .into_iter()
+                .map(|spec| SourceSpec {
+                    selector: if matches.is_present("SOURCE_EXACT") {
+                        spec.selector.exact()
+                    } else {
+                        spec.selector
+                    },
+                    index: matches
+                        .value_of("SOURCE_INDEX")
+                        .map(|index| usize::from_str_radix(index, 10).unwrap()), //already validated
+                    ..spec
+                })
+                .collect(),
         },
-    )?;
-
-    // get the requested output
-    let mut output = None;
-    let outputs = environment.get_all_outputs();
+        auto_source: matches.is_present("AUTO_SOURCE"),
+        mode: matches.value_of("MODE").map(|x| {
+            let parts = x
+                .split("x")
+                .map(|x| u32::from_str_radix(x, 10))
+                .map(|x| x.map(|x| x as i32))
+                .collect::<Result<Vec<i32>, _>>()
+                .unwrap(); //already validated
+            (parts[0], parts[1])
+        }),
+        modeline: matches.value_of("MODELINE").map(|x| parse_modeline(x).unwrap()), //already validated
+        crop: matches.value_of("CROP").map(|x| parse_crop(x).unwrap()), //already validated
+        position: matches.value_of("POSITION").map(|x| x.parse::<Placement>().unwrap()), //already validated
+        layout: matches.value_of("LAYOUT").map(|x| x.parse::<Layout>().unwrap()), //already validated
+        background: parse_color(matches.value_of("BACKGROUND").unwrap()).unwrap(), //already validated
+        splash: matches.value_of("SPLASH").map(PathBuf::from),
+        adjustments: Adjustments {
+            brightness: parse_adjustment(matches.value_of("BRIGHTNESS").unwrap()).unwrap(), //already validated
+            contrast: parse_adjustment(matches.value_of("CONTRAST").unwrap()).unwrap(), //already validated
+            gamma: parse_adjustment(matches.value_of("GAMMA").unwrap()).unwrap(), //already validated
+        },
+        source_timeout: Duration::from_secs(
+            u64::from_str_radix(matches.value_of("SOURCE_TIMEOUT").unwrap(), 10).unwrap(), //already validated
+        ),
+        manage_source_power: if matches.is_present("MANAGE_SOURCE_POWER") {
+            let grace = matches
+                .value_of("MANAGE_SOURCE_POWER")
+                .map(|secs| u64::from_str_radix(secs, 10).unwrap()) //already validated
+                .unwrap_or(DEFAULT_SOURCE_POWER_GRACE_SECS);
+            Some(Duration::from_secs(grace))
+        } else {
+            None
+        },
+        watchdog: matches.value_of("WATCHDOG").map(|x| {
+            Duration::from_secs(u64::from_str_radix(x, 10).unwrap()) //already validated
+        }),
+        watchdog_escalate: u32::from_str_radix(matches.value_of("WATCHDOG_ESCALATE").unwrap(), 10).unwrap(), //already validated
+        session: matches
+            .value_of("SESSION_BACKEND")
+            .unwrap()
+            .parse::<SessionKind>()
+            .unwrap(), //already validated
+        target_backend: matches
+            .value_of("TARGET_BACKEND")
+            .unwrap()
+            .parse::<TargetBackendKind>()
+            .unwrap(), //already validated
+        capture: matches
+            .value_of("CAPTURE_BACKEND")
+            .unwrap()
+            .parse::<CaptureBackendKind>()
+            .unwrap(), //already validated
+        capture_rate: matches.value_of("CAPTURE_RATE").unwrap().parse::<CaptureRate>().unwrap(), //already validated
+        queue_policy: matches.value_of("QUEUE_POLICY").unwrap().parse::<QueuePolicy>().unwrap(), //already validated
+        copy_path: matches
+            .value_of("COPY_PATH")
+            .unwrap()
+            .parse::<CopyPathKind>()
+            .unwrap(), //already validated
+        verify_import: matches.is_present("VERIFY_IMPORT"),
+        linear_blending: matches.is_present("LINEAR_BLENDING"),
+        filter: matches
+            .value_of("FILTER")
+            .unwrap()
+            .parse::<FilterKind>()
+            .unwrap(), //already validated
+        transform: parse_transform(matches.value_of("TRANSFORM").unwrap()).unwrap(), //already validated
+        orientation_follow_source: matches.value_of("ORIENTATION_FOLLOW_SOURCE") == Some("on"),
+        pipeline_depth: usize::from_str_radix(matches.value_of("PIPELINE").unwrap(), 10).unwrap(), //already validated
+        async_readback: matches.is_present("ASYNC_READBACK"),
+        downscale_on_render: matches.value_of("DOWNSCALE_ON_RENDER").unwrap().parse::<Downscale>().unwrap(), //already validated
+        threads: usize::from_str_radix(matches.value_of("THREADS").unwrap(), 10).unwrap(), //already validated
+        stream: StreamOptions {
+            fifo_length: u32::from_str_radix(matches.value_of("STREAM_FIFO").unwrap(), 10).unwrap(), //already validated
+            swap_interval: u32::from_str_radix(matches.value_of("SWAP_INTERVAL").unwrap(), 10).unwrap(), //already validated
+            output_layer: matches.value_of("OUTPUT_LAYER").unwrap().parse::<OutputLayerKind>().unwrap(), //already validated
+            output_layer_index: matches
+                .value_of("OUTPUT_LAYER_INDEX")
+                .map(|index| usize::from_str_radix(index, 10).unwrap()), //already validated
+            acquire_timeout_usec: u32::from_str_radix(matches.value_of("ACQUIRE_TIMEOUT").unwrap(), 10).unwrap(), //already validated
+        },
+        reject_yuv: matches.is_present("REJECT_YUV"),
+        force_format: matches.value_of("FORCE_FORMAT").map(|code| parse_fourcc(code).unwrap()), //already validated
+        color_depth: match matches.value_of("COLOR_DEPTH").unwrap() {
+            "auto" => None,
+            depth => Some(depth.parse::<ColorDepth>().unwrap()), //already validated
+        },
+        reconnect: !matches.is_present("NO_RECONNECT"),
+        robustness: !matches.is_present("NO_ROBUSTNESS"),
+        wait_for_connector: matches.is_present("WAIT_FOR_CONNECTOR"),
+        wait_for_gpu: if matches.is_present("WAIT_FOR_GPU") {
+            let timeout = matches
+                .value_of("WAIT_FOR_GPU")
+                .map(|secs| u64::from_str_radix(secs, 10).unwrap()) //already validated
+                .unwrap_or(DEFAULT_GPU_TIMEOUT_SECS);
+            Some(Duration::from_secs(timeout))
+        } else {
+            None
+        },
+        keep_display_on: matches.is_present("KEEP_DISPLAY_ON"),
+        strict_mode: matches.is_present("STRICT_MODE"),
+        allow_crtc_steal: matches.is_present("ALLOW_CRTC_STEAL"),
+        legacy_modesetting: matches.is_present("LEGACY_MODESETTING"),
+        gl_debug: matches.is_present("GL_DEBUG"),
+        vrr: matches.is_present("VRR"),
+        plane_scaling: matches.is_present("PLANE_SCALING"),
+        plane: matches.value_of("PLANE").map(|plane| plane.parse::<PlaneSelection>().unwrap()), //already validated
+        connector_props: matches
+            .values_of("CONNECTOR_PROP")
+            .map(|values| {
+                values
+                    .map(|value| value.parse::<PropertyAssignment>().unwrap()) //already validated
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default(),
+        persist: !matches.is_present("NO_PERSIST"),
+        forget_monitor: matches.is_present("FORGET_MONITOR"),
+        colorspace: matches.value_of("COLORSPACE").map(String::from),
+        hdr_metadata: matches
+            .value_of("HDR_METADATA")
+            .map(|source| source.parse::<HdrMetadataSource>().unwrap()), //already validated
+        gamma_lut: matches.value_of("GAMMA_LUT").map(PathBuf::from),
+        damage_tracking: !matches.is_present("NO_DAMAGE"),
+        notify: !matches.is_present("NO_NOTIFY"),
+        idle_detect: matches.value_of("IDLE_DETECT").unwrap().parse::<IdleDetect>().unwrap(), //already validated
+        overlay: matches.is_present("OVERLAY"),
+        cursor: matches.value_of("CURSOR").unwrap().parse::<CursorMode>().unwrap(), //already validated
+        swap_failure_limit: match u32::from_str_radix(matches.value_of("SWAP_FAILURE_LIMIT").unwrap(), 10).unwrap() { //already validated
+            0 => None,
+            limit => Some(limit),
+        },
+        swap_failure_policy: matches
+            .value_of("SWAP_FAILURE_POLICY")
+            .unwrap()
+            .parse::<SwapFailurePolicy>()
+            .unwrap(), //already validated
+        frame_pacing: if matches.is_present("FRAME_PACING") {
+            let budget = matches
+                .value_of("FRAME_PACING")
+                .map(|ms| u64::from_str_radix(ms, 10).unwrap()) //already validated
+                .unwrap_or(DEFAULT_LATENCY_BUDGET_MS);
+            Some(Duration::from_millis(budget))
+        } else {
+            None
+        },
+        record: matches.value_of("RECORD").map(PathBuf::from),
+        render_device: matches.value_of("RENDER_DEVICE").map(PathBuf::from),
+        ensure_headless: if matches.is_present("ENSURE_HEADLESS") {
+            Some(
+                matches
+                    .value_of("ENSURE_HEADLESS")
+                    .map(|mode| mode.parse::<HeadlessMode>().unwrap()), //already validated
+            )
+        } else {
+            None
+        },
+        output: matches.value_of("OUTPUT").unwrap().parse::<OutputKind>().unwrap(), //already validated
+        raw_header: matches.value_of("RAW_HEADER").unwrap().parse::<RawHeader>().unwrap(), //already validated
+        exit_on_signals: true,
+        stats_file: matches.value_of("STATS_FILE").map(PathBuf::from),
+        stats_socket: matches.value_of("STATS_SOCKET").map(PathBuf::from),
+        trace_frames: matches.is_present("TRACE_FRAMES"),
+        split_display: if !matches.is_present("SPLIT_DISPLAY_FD")
+            && (matches.is_present("SPLIT_DISPLAY") || matches.is_present("EXTRA_TARGET"))
+        {
+            // the display processes run with the same options, just without spawning more of them
+            let program = std::env::current_exe()?;
+            let args = std::env::args_os().skip(1).filter(|arg| arg != "--split-display").collect::<Vec<_>>();
+            let extra = matches.values_of("EXTRA_TARGET").map(|values| values.count()).unwrap_or(0);
+            (0..=extra)
+                .map(|target| {
+                    let mut args = args.clone();
+                    // the first one drives the target of --device-index and --connector
+                    if target > 0 {
+                        args.push(OsString::from("--split-target"));
+                        args.push(OsString::from(target.to_string()));
+                    }
+                    args.push(OsString::from("--split-display-fd"));
+                    SplitCommand {
+                        program: program.clone(),
+                        args,
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        },
+        frames: matches.value_of("FRAMES").map(|x| u64::from_str_radix(x, 10).unwrap()), //already validated
+        duration: matches
+            .value_of("DURATION")
+            .map(|x| Duration::from_secs_f64(x.parse::<f64>().unwrap())), //already validated
+        dry_run: matches.is_present("DRY_RUN"),
+    };
 
     if matches.subcommand_matches("list-sources").is_some() {
-        for output in outputs {
-            sctk::output::with_output_info(&output, |info| {
-                println!("{}", info.make);
-            });
+        for (global_id, make) in nvscreencopy::list_sources()? {
+            // the global id works with --source-global-id
+            println!("{}: {}", global_id, make);
         }
         return Ok(());
     }
-
-    for test_output in outputs {
-        if let Some(Some(mode)) = sctk::output::with_output_info(&test_output, |info| {
-            if info.make.contains(monitor) {
-                for mode in &info.modes {
-                    if mode.is_current {
-                        return Some(mode.clone());
-                    }
-                }
-            }
-            None
-        }) {
-            output = Some((test_output, mode));
-        }
-    }
-    let (output, mode) = output.with_context(|| "Unable to find headless output")?;
-
-    // init target gpu
-    let path = gpu::find_nvidia_gpu(log.clone())
-        .with_context(|| "Failed to automatically detect nvidia gpu")?;
     if matches.subcommand_matches("list-connectors").is_some() {
-        let fd = gpu::Fd::open(&path)?;
-        let device = DrmDevice::new(fd, false, log)?;
-        let res_handles = device.resource_handles().unwrap();
-        for conn in res_handles
-            .connectors()
-            .iter()
-            .map(|conn| device.get_connector(*conn).unwrap())
-        {
+        for entry in nvscreencopy::list_connectors(&options, &log)? {
+            // the id, monitor name and serial work with --connector as well
             println!(
-                "{}-{}: {}",
-                match conn.interface() {
-                    Interface::VGA => "VGA",
-                    Interface::DVII | Interface::DVID | Interface::DVIA => "DVI",
-                    Interface::LVDS => "LVDS",
-                    Interface::DisplayPort => "DP",
-                    Interface::HDMIA | Interface::HDMIB => "HDMI",
-                    Interface::EmbeddedDisplayPort => "eDP",
-                    _ => "Unsupported",
-                },
-                conn.interface_id(),
-                match conn.state() {
+                "{} (id {}): {}{}",
+                entry.name,
+                entry.id,
+                match entry.state {
                     ConnectorState::Connected => "Connected",
                     ConnectorState::Disconnected => "Disconnected",
                     _ => "Unknown",
-                }
+                },
+                entry.edid.map(|edid| format!(" ({})", edid)).unwrap_or_default()
             )
         }
         return Ok(());
     }
-    slog::info!(log, "Found nvidia gpu {}", path.display());
-    let (mut target_gpu, target_event_source) = gpu::init_target_gpu(
-        path,
-        connector,
-        dest_mode.unwrap_or(mode.dimensions),
-        log.clone(),
-    )?;
 
-    // init render gpu
-    let path = PathBuf::from(environment.with_inner(|env| env.drm.path()));
-    slog::info!(log, "Found wl gpu {}", path.display());
-    let fd = gpu::Fd::open(&path)?;
-    event_queue.sync_roundtrip(&mut (), |_, _, _| ())?;
-    let render_gpu = gpu::init_render_gpu(fd, log.clone())?;
+    if matches.subcommand_matches("list-gpus").is_some() {
+        for gpu in nvscreencopy::list_gpus(&log)? {
+            let modeset = match gpu.modeset {
+                Some(true) => "on",
+                Some(false) => "off",
+                None if gpu.is_nvidia() => "nvidia-drm not loaded",
+                None => "n/a",
+            };
+            println!(
+                "{}: {} (driver: {}, render node: {}, modeset: {}, egl: {}, seat: {})",
+                gpu.path.display(),
+                gpu.verdict(),
+                gpu.driver.as_deref().unwrap_or("none"),
+                gpu.render_node
+                    .as_ref()
+                    .map(|node| node.display().to_string())
+                    .unwrap_or_else(|| String::from("none")),
+                modeset,
+                // the lookup error lists every egl device on lines of their own
+                gpu.egl_error.as_deref().and_then(|err| err.lines().next()).unwrap_or("ok"),
+                gpu.seat
+            )
+        }
+        return Ok(());
+    }
 
-    let conn_fd = client_display.get_connection_fd();
-    let _wayland_token = event_loop
-        .handle()
-        .insert_source(
-            Generic::from_fd(conn_fd, Interest::READ, calloop::Mode::Level),
-            move |_, _, state: &mut CalloopState| {
-                slog::debug!(state.wayland_state.log, "Wayland event");
-                match state
-                    .event_queue
-                    .dispatch(&mut state.wayland_state, |event, object, _| {
-                        panic!(
-                            "[calloop] Encountered an orphan event: {}@{} : {}",
-                            event.interface,
-                            object.as_ref().id(),
-                            event.name
-                        );
-                    }) {
-                    Ok(_) => Ok(PostAction::Continue),
-                    Err(e) => {
-                        panic!("I/O error on the Wayland display: {}", e)
-                    }
-                }
-            },
-        )
-        .expect("Failed to add display to event loop");
+    if matches.subcommand_matches("list-planes").is_some() {
+        let (crtc, planes) = nvscreencopy::list_planes(&options, &log)?;
+        for plane in planes {
+            let kind = plane.kind.map(|kind| kind.to_string()).unwrap_or_else(|| String::from("unknown"));
+            let usable = if plane.kind == Some(PlaneType::Cursor) {
+                String::from("cursor only")
+            } else if plane.crtcs.contains(&crtc) {
+                String::from("usable")
+            } else {
+                format!("not usable with crtc {}", u32::from(crtc))
+            };
+            println!(
+                "{}: {}, {}, formats: {}",
+                u32::from(plane.handle),
+                kind,
+                usable,
+                plane.formats.iter().map(|format| format!("{:?}", format)).collect::<Vec<_>>().join(" ")
+            )
+        }
+        return Ok(());
+    }
 
-    let texture = render::create_texture(
-        &mut target_gpu.renderer,
-        mode.dimensions.0,
-        mode.dimensions.1,
-    )
-    .unwrap();
-    let wl_state = WaylandState {
-        render: render_gpu,
-        target: target_gpu,
-        dmabuf: None,
-        log: log.clone(),
-        buffer: vec![0u8; (mode.dimensions.0 * mode.dimensions.1 * 4) as usize],
-        texture,
-        copy: None,
-        dest_size: dest_mode
-            .map(|(w, h)| Size::from((w as i32, h as i32)))
-            .unwrap_or(Size::from((mode.dimensions.0, mode.dimensions.1))),
-        try_again: AtomicBool::new(false),
-    };
+    if let Some(matches) = matches.subcommand_matches("restore") {
+        nvscreencopy::restore(&options, matches.value_of("SNAPSHOT").map(Path::new), &log)?;
+        return Ok(());
+    }
 
-    let event_dispatcher = Dispatcher::new(
-        target_event_source,
-        move |event, _, state: &mut CalloopState| match event {
-            DrmEvent::VBlank(_crtc) => {
-                let manager = state
-                    .environment
-                    .get_global::<ExportDmabufManager>()
-                    .expect("No Export-DMABUF protocol");
-                let frame = manager.capture_output(1, &state.output);
-                frame.quick_assign(handle_frame);
-            }
-            DrmEvent::Error(error) => slog::error!(log, "{:?}", error),
-        },
-    );
-    let _nv_token = event_loop
-        .handle()
-        .register_dispatcher(event_dispatcher.clone())
-        .unwrap();
+    if let Some(matches) = matches.subcommand_matches("test-pattern") {
+        let duration = matches.value_of("DURATION").unwrap().parse::<u64>().unwrap(); //already validated
+        return nvscreencopy::test_pattern(&options, Duration::from_secs(duration), &log);
+    }
 
-    let mut state = CalloopState {
-        wayland_state: wl_state,
-        environment,
-        output,
-        event_queue,
-    };
+    if let Some(fd) = matches.value_of("SPLIT_DISPLAY_FD") {
+        let fd = fd.parse::<i32>().unwrap(); //already validated
+        let mut options = options;
+        let target = matches
+            .value_of("SPLIT_TARGET")
+            .map(|target| target.parse::<usize>().unwrap()) //already validated
+            .unwrap_or(0);
+        if target > 0 {
+            let spec = matches
+                .values_of("EXTRA_TARGET")
+                .and_then(|mut values| values.nth(target - 1))
+                .map(|value| value.parse::<TargetSpec>().unwrap()) //already validated
+                .ok_or_else(|| anyhow::anyhow!("No --extra-target for display process {}", target))?;
+            options.device_index = Some(spec.device_index);
+            options.connector = spec.connector;
+        }
+        return nvscreencopy::split_display(&options, fd, &log);
+    }
 
-    event_loop
-        .run(Duration::from_secs(1), &mut state, |state| {
-            if state.wayland_state.try_again.swap(false, Ordering::SeqCst) {
-                let manager = state
-                    .environment
-                    .get_global::<ExportDmabufManager>()
-                    .expect("No Export-DMABUF protocol");
-                let frame = manager.capture_output(1, &state.output);
-                slog::debug!(state.wayland_state.log, "Init frame");
-                frame.quick_assign(handle_frame);
-            }
-            state
-                .event_queue
-                .sync_roundtrip(&mut state.wayland_state, |event, object, _| {
-                    panic!(
-                        "[calloop] Encountered an orphan event: {}@{} : {}",
-                        event.interface,
-                        object.as_ref().id(),
-                        event.name
-                    );
-                })
-                .expect("Wayland display died");
-        })
-        .map_err(|x| x.into())
+    ScreenCopy::new(options)?.logger(log).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_full() {
+        assert_eq!(parse_color("#000000"), Ok([0.0, 0.0, 0.0, 1.0]));
+        assert_eq!(parse_color("#ff00FF"), Ok([1.0, 0.0, 1.0, 1.0]));
+        assert_eq!(parse_color("#336699"), Ok([0.2, 0.4, 0.6, 1.0]));
+    }
+
+    #[test]
+    fn color_shorthand() {
+        assert_eq!(parse_color("#f80"), parse_color("#ff8800"));
+        assert_eq!(parse_color("#FFF"), Ok([1.0, 1.0, 1.0, 1.0]));
+        assert_eq!(parse_color("#369"), Ok([0.2, 0.4, 0.6, 1.0]));
+    }
+
+    #[test]
+    fn color_invalid() {
+        assert!(parse_color("ff8800").is_err());
+        assert!(parse_color("#").is_err());
+        assert!(parse_color("#ff88").is_err());
+        assert!(parse_color("#ff88000").is_err());
+        assert!(parse_color("#ff880g").is_err());
+        assert!(parse_color("#+f8").is_err());
+        // not split inside of a character
+        assert!(parse_color("#fé0").is_err());
+    }
 }
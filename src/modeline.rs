@@ -0,0 +1,182 @@
+use anyhow::{Context, Result};
+use smithay::reexports::drm::control::Mode;
+
+/// `DRM_MODE_FLAG_*` of the sync polarities
+const FLAG_PHSYNC: u32 = 1 << 0;
+const FLAG_NHSYNC: u32 = 1 << 1;
+const FLAG_PVSYNC: u32 = 1 << 2;
+const FLAG_NVSYNC: u32 = 1 << 3;
+const FLAG_INTERLACE: u32 = 1 << 4;
+/// `DRM_MODE_TYPE_USERDEF`, modes not coming from the EDID
+const TYPE_USERDEF: u32 = 1 << 5;
+
+const HINT: &str = "generate one with cvt(1), e.g. \"cvt 1280 800 60\"";
+
+/// Parses a modeline in the format
+/// "PCLK HDISP HSYNCSTART HSYNCEND HTOTAL VDISP VSYNCSTART VSYNCEND VTOTAL [+/-hsync +/-vsync]".
+///
+/// The pixel clock is in MHz. The output of cvt(1) or gtf(1) is accepted as is,
+/// including the leading "Modeline" and the quoted name.
+pub fn parse(input: &str) -> Result<Mode> {
+    let mut words = input.split_whitespace().peekable();
+    if words.peek().map(|word| word.eq_ignore_ascii_case("modeline")).unwrap_or(false) {
+        words.next();
+    }
+    if words.peek().map(|word| word.starts_with('"')).unwrap_or(false) {
+        words.next();
+    }
+
+    let clock = words
+        .next()
+        .context("Modeline is empty")?
+        .parse::<f64>()
+        .ok()
+        .filter(|clock| clock.is_finite() && *clock > 0.0)
+        .with_context(|| format!("Invalid pixel clock in modeline, {}", HINT))?;
+    let mut timings = [0u16; 8];
+    for timing in timings.iter_mut() {
+        *timing = words
+            .next()
+            .with_context(|| format!("Modeline needs 8 timings after the pixel clock, {}", HINT))?
+            .parse::<u16>()
+            .with_context(|| format!("Invalid timing in modeline, {}", HINT))?;
+    }
+    let [hdisplay, hsync_start, hsync_end, htotal, vdisplay, vsync_start, vsync_end, vtotal] = timings;
+    // the same rules the kernel checks modes against
+    if hdisplay == 0
+        || hsync_start < hdisplay
+        || hsync_end < hsync_start
+        || htotal < hsync_end
+        || vdisplay == 0
+        || vsync_start < vdisplay
+        || vsync_end < vsync_start
+        || vtotal < vsync_end
+    {
+        anyhow::bail!("Modeline has impossible timings, {}", HINT);
+    }
+
+    let mut flags = 0;
+    for word in words {
+        flags |= match word.to_ascii_lowercase().as_str() {
+            "+hsync" => FLAG_PHSYNC,
+            "-hsync" => FLAG_NHSYNC,
+            "+vsync" => FLAG_PVSYNC,
+            "-vsync" => FLAG_NVSYNC,
+            "interlace" => FLAG_INTERLACE,
+            _ => anyhow::bail!("Unknown modeline flag \"{}\", {}", word, HINT),
+        };
+    }
+
+    let clock = (clock * 1000.0).round() as u32;
+    let refresh = (clock as f64 * 1000.0 / (htotal as f64 * vtotal as f64)).round() as u32;
+    let mut name = [0; 32];
+    for (dst, src) in name.iter_mut().zip(format!("{}x{}", hdisplay, vdisplay).bytes()) {
+        *dst = src as _;
+    }
+    Ok(Mode::from(drm_ffi::drm_mode_modeinfo {
+        clock,
+        hdisplay,
+        hsync_start,
+        hsync_end,
+        htotal,
+        hskew: 0,
+        vdisplay,
+        vsync_start,
+        vsync_end,
+        vtotal,
+        vscan: 0,
+        vrefresh: refresh,
+        flags,
+        type_: TYPE_USERDEF,
+        name,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(input: &str) -> drm_ffi::drm_mode_modeinfo {
+        drm_ffi::drm_mode_modeinfo::from(parse(input).unwrap())
+    }
+
+    #[test]
+    fn cvt() {
+        let mode = info("Modeline \"1280x800_60.00\"   83.50  1280 1352 1480 1680  800 803 809 831 -hsync +vsync");
+        assert_eq!(mode.clock, 83500);
+        assert_eq!(
+            (mode.hdisplay, mode.hsync_start, mode.hsync_end, mode.htotal),
+            (1280, 1352, 1480, 1680)
+        );
+        assert_eq!(
+            (mode.vdisplay, mode.vsync_start, mode.vsync_end, mode.vtotal),
+            (800, 803, 809, 831)
+        );
+        assert_eq!(mode.vrefresh, 60);
+        assert_eq!(mode.flags, FLAG_NHSYNC | FLAG_PVSYNC);
+        assert_eq!(mode.type_, TYPE_USERDEF);
+        let name = mode.name.iter().take_while(|byte| **byte != 0).map(|byte| *byte as u8).collect::<Vec<_>>();
+        assert_eq!(name, b"1280x800");
+    }
+
+    #[test]
+    fn cvt_reduced_blanking() {
+        let mode = info("Modeline \"1920x1080R\"  138.50  1920 1968 2000 2080  1080 1083 1088 1111 +hsync -vsync");
+        assert_eq!(mode.clock, 138500);
+        assert_eq!(mode.vrefresh, 60);
+        assert_eq!(mode.flags, FLAG_PHSYNC | FLAG_NVSYNC);
+    }
+
+    #[test]
+    fn gtf() {
+        // gtf(1) capitalizes the flags
+        let mode = info("  Modeline \"1024x768_60.00\"  64.11  1024 1080 1184 1344  768 769 772 795  -HSync +Vsync");
+        assert_eq!(mode.clock, 64110);
+        assert_eq!(mode.vrefresh, 60);
+        assert_eq!(mode.flags, FLAG_NHSYNC | FLAG_PVSYNC);
+    }
+
+    #[test]
+    fn bare_timings() {
+        let mode = info("83.5 1280 1352 1480 1680 800 803 809 831");
+        assert_eq!((mode.hdisplay, mode.vdisplay, mode.vrefresh), (1280, 800, 60));
+        assert_eq!(mode.flags, 0);
+        assert_eq!(info("83.5 1280 1352 1480 1680 800 803 809 831 interlace").flags, FLAG_INTERLACE);
+    }
+
+    #[test]
+    fn malformed() {
+        for invalid in &[
+            "",
+            "Modeline \"1280x800\"",
+            "fast 1280 1352 1480 1680 800 803 809 831",
+            "0 1280 1352 1480 1680 800 803 809 831",
+            "-83.5 1280 1352 1480 1680 800 803 809 831",
+            "NaN 1280 1352 1480 1680 800 803 809 831",
+            "83.5 1280 1352 1480 1680 800 803 809",
+            "83.5 1280 1352 1480 1680 800 803 809 eight",
+            "83.5 1280 1352 1480 1680 800 803 809 70000",
+            "83.5 1280 1352 1480 1680 800 803 809 831 +csync",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn impossible_timings() {
+        for invalid in &[
+            "83.5 0 1352 1480 1680 800 803 809 831",
+            "83.5 1280 1200 1480 1680 800 803 809 831",
+            "83.5 1280 1352 1300 1680 800 803 809 831",
+            "83.5 1280 1352 1480 1400 800 803 809 831",
+            "83.5 1280 1352 1480 1680 0 803 809 831",
+            "83.5 1280 1352 1480 1680 800 700 809 831",
+            "83.5 1280 1352 1480 1680 800 803 802 831",
+            "83.5 1280 1352 1480 1680 800 803 809 808",
+        ] {
+            let error = parse(invalid).unwrap_err().to_string();
+            assert!(error.starts_with("Modeline has impossible timings"), "{}", invalid);
+            assert!(error.contains("cvt(1)"));
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use smithay::backend::allocator::Modifier;
+
+/// Vendor of a format modifier, the top 8 bits as assigned by drm_fourcc.h
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Nvidia,
+    Samsung,
+    Qualcomm,
+    Vivante,
+    Broadcom,
+    Arm,
+    Allwinner,
+    Amlogic,
+    Unknown(u8),
+}
+
+impl Vendor {
+    fn from_raw(raw: u8) -> Vendor {
+        match raw {
+            0x01 => Vendor::Intel,
+            0x02 => Vendor::Amd,
+            0x03 => Vendor::Nvidia,
+            0x04 => Vendor::Samsung,
+            0x05 => Vendor::Qualcomm,
+            0x06 => Vendor::Vivante,
+            0x07 => Vendor::Broadcom,
+            0x08 => Vendor::Arm,
+            0x09 => Vendor::Allwinner,
+            0x0a => Vendor::Amlogic,
+            x => Vendor::Unknown(x),
+        }
+    }
+}
+
+/// How the pixels of a buffer are laid out in memory, as far as its modifier tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferLayout {
+    /// Rows one after another
+    Linear,
+    /// No modifier was given, only the driver that allocated the buffer knows
+    Implicit,
+    /// Tiled or compressed in a layout of the vendor
+    Tiled(Vendor),
+}
+
+pub fn classify(modifier: Modifier) -> BufferLayout {
+    match modifier {
+        Modifier::Linear => BufferLayout::Linear,
+        Modifier::Invalid => BufferLayout::Implicit,
+        modifier => BufferLayout::Tiled(Vendor::from_raw((u64::from(modifier) >> 56) as u8)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(raw: u64) -> BufferLayout {
+        classify(Modifier::from(raw))
+    }
+
+    #[test]
+    fn linear_and_implicit() {
+        assert_eq!(layout(0), BufferLayout::Linear);
+        assert_eq!(classify(Modifier::Linear), BufferLayout::Linear);
+        // DRM_FORMAT_MOD_INVALID
+        assert_eq!(layout(0x00ff_ffff_ffff_ffff), BufferLayout::Implicit);
+    }
+
+    #[test]
+    fn intel() {
+        // I915_FORMAT_MOD_X_TILED, Y_TILED, Y_TILED_CCS and Y_TILED_GEN12_RC_CCS
+        for raw in [0x0100_0000_0000_0001, 0x0100_0000_0000_0002, 0x0100_0000_0000_0004, 0x0100_0000_0000_0006] {
+            assert_eq!(layout(raw), BufferLayout::Tiled(Vendor::Intel), "{:#x}", raw);
+        }
+    }
+
+    #[test]
+    fn amd() {
+        // GFX9 64K_S_X and GFX10 64K_R_X with DCC, as radeonsi exports them
+        for raw in [0x0200_0000_0000_0919, 0x0200_0000_0060_3b01] {
+            assert_eq!(layout(raw), BufferLayout::Tiled(Vendor::Amd), "{:#x}", raw);
+        }
+    }
+
+    #[test]
+    fn other_vendors() {
+        // NVIDIA 16Bx2 block linear
+        assert_eq!(layout(0x0300_0000_0060_6014), BufferLayout::Tiled(Vendor::Nvidia));
+        // ARM AFBC 16x16
+        assert_eq!(layout(0x0800_0000_0000_0001), BufferLayout::Tiled(Vendor::Arm));
+        assert_eq!(layout(0x2000_0000_0000_0001), BufferLayout::Tiled(Vendor::Unknown(0x20)));
+    }
+}
@@ -0,0 +1,196 @@
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::Connection,
+};
+
+use crate::{
+    events::Event,
+    status::{MirrorState, Status},
+    CopyState,
+};
+
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, SyncSender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// At most one notification of a category is shown within this time
+const RATE_LIMIT: Duration = Duration::from_secs(30);
+/// How long a notification call may take, before it is given up
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Notifications waiting for the thread, more are dropped
+const QUEUE_LENGTH: usize = 8;
+
+/// What a notification is about, each is rate limited on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Category {
+    Started,
+    Source,
+    Monitor,
+    CopyPath,
+    Fatal,
+}
+
+struct Notification {
+    summary: String,
+    body: String,
+    critical: bool,
+}
+
+/// Shows freedesktop notifications about state changes from the events of a running `ScreenCopy`.
+///
+/// Notifications are sent by a thread of their own, so a slow notification daemon does not stall mirroring.
+pub struct Notifier {
+    sender: Option<SyncSender<Notification>>,
+    thread: Option<JoinHandle<()>>,
+    last_sent: HashMap<Category, Instant>,
+    /// `state` of the last `StateChanged`
+    state: Option<MirrorState>,
+    copy_path: Option<CopyState>,
+    log: slog::Logger,
+}
+
+impl Notifier {
+    /// `None` without a session bus, which leaves notifications disabled
+    pub fn new(log: slog::Logger) -> Option<Notifier> {
+        let connection = match Connection::new_session() {
+            Ok(connection) => connection,
+            Err(err) => {
+                slog::info!(log, "No session bus, not sending notifications: {}", err);
+                return None;
+            }
+        };
+        let (sender, receiver) = mpsc::sync_channel::<Notification>(QUEUE_LENGTH);
+        let thread_log = log.clone();
+        let thread = std::thread::Builder::new()
+            .name(String::from("nvscreencopy-notify"))
+            .spawn(move || {
+                let proxy = connection.with_proxy(
+                    "org.freedesktop.Notifications",
+                    "/org/freedesktop/Notifications",
+                    CALL_TIMEOUT,
+                );
+                for notification in receiver {
+                    let mut hints = PropMap::new();
+                    // urgency, critical ones stay until dismissed
+                    let urgency: u8 = if notification.critical { 2 } else { 1 };
+                    hints.insert(String::from("urgency"), Variant(Box::new(urgency) as Box<dyn RefArg>));
+                    let result: Result<(u32,), _> = proxy.method_call(
+                        "org.freedesktop.Notifications",
+                        "Notify",
+                        (
+                            "nvscreencopy",
+                            0u32,
+                            "video-display",
+                            notification.summary.as_str(),
+                            notification.body.as_str(),
+                            Vec::<String>::new(),
+                            hints,
+                            -1i32,
+                        ),
+                    );
+                    if let Err(err) = result {
+                        slog::debug!(thread_log, "Failed to send notification: {}", err);
+                    }
+                }
+            })
+            .ok()?;
+        Some(Notifier {
+            sender: Some(sender),
+            thread: Some(thread),
+            last_sent: HashMap::new(),
+            state: None,
+            copy_path: None,
+            log,
+        })
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::StateChanged(status) => {
+                let previous = self.state.replace(status.state);
+                match (previous, status.state) {
+                    (None, MirrorState::Mirroring) => {
+                        self.notify(Category::Started, "Mirroring started", mirroring(status), false)
+                    }
+                    (Some(MirrorState::WaitingForSource), MirrorState::Mirroring) => {
+                        self.notify(Category::Source, "Source output is back", mirroring(status), false)
+                    }
+                    (Some(MirrorState::WaitingForMonitor), MirrorState::Mirroring) => {
+                        self.notify(Category::Monitor, "Monitor plugged in again", mirroring(status), false)
+                    }
+                    (_, MirrorState::WaitingForSource) => self.notify(
+                        Category::Source,
+                        "Source output lost",
+                        format!("Waiting for {} to reappear", status.sources.join(", ")),
+                        false,
+                    ),
+                    (_, MirrorState::WaitingForMonitor) => self.notify(
+                        Category::Monitor,
+                        "Monitor unplugged",
+                        String::from("Mirroring resumes once it is plugged in again"),
+                        false,
+                    ),
+                    _ => {}
+                }
+            }
+            Event::CopyPath(path) => {
+                // only a path that worked before degrading is news, some setups always copy through the cpu
+                if let (CopyState::CPUCopy, Some(previous)) = (*path, self.copy_path.replace(*path)) {
+                    self.notify(
+                        Category::CopyPath,
+                        "Frames are copied through the cpu",
+                        format!("{:?} stopped working, which costs cpu time and latency", previous),
+                        false,
+                    );
+                }
+            }
+            Event::Fatal(err) => self.notify(Category::Fatal, "Mirroring stopped", err.clone(), true),
+            _ => {}
+        }
+    }
+
+    fn notify(&mut self, category: Category, summary: &str, body: String, critical: bool) {
+        let now = Instant::now();
+        if let Some(last) = self.last_sent.get(&category) {
+            if now.duration_since(*last) < RATE_LIMIT {
+                slog::debug!(self.log, "Not notifying \"{}\", another one was sent just now", summary);
+                return;
+            }
+        }
+        self.last_sent.insert(category, now);
+        let notification = Notification {
+            summary: String::from(summary),
+            body,
+            critical,
+        };
+        if let Some(sender) = self.sender.as_ref() {
+            // the thread is stuck on a call, it is no use queueing more
+            let _ = sender.try_send(notification);
+        }
+    }
+}
+
+impl Drop for Notifier {
+    /// Waits for the queued notifications, so the one about a fatal error is sent before exiting
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// E.g. "HEADLESS-1 → HDMI-1 at 1920x1080"
+fn mirroring(status: &Status) -> String {
+    let mut body = status.sources.join(", ");
+    if let Some(connector) = status.connector.as_ref() {
+        body.push_str(&format!(" → {}", connector));
+    }
+    if let Some(mode) = status.mode {
+        body.push_str(&format!(" at {}x{}", mode.width, mode.height));
+    }
+    body
+}
@@ -0,0 +1,629 @@
+use anyhow::{Context, Result};
+use libloading::Library;
+use smithay::{
+    backend::renderer::gles2::{ffi, Gles2Renderer},
+    utils::{Physical, Size},
+};
+
+use crate::{gpu::ColorDepth, render::gl_format};
+
+use std::{
+    ffi::c_void,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    ptr,
+    time::{Duration, Instant},
+};
+
+// The parts of nvEncodeAPI.h of the Video Codec SDK 11.0 we need. Structs are versioned, the driver only reads
+// the fields of the version we pass and the rest is reserved space that has to stay zeroed.
+
+const API_MAJOR: u32 = 11;
+const API_MINOR: u32 = 0;
+const API_VERSION: u32 = API_MAJOR | (API_MINOR << 24);
+
+const fn struct_version(version: u32) -> u32 {
+    API_VERSION | (version << 16) | (0x7 << 28)
+}
+
+const FUNCTION_LIST_VER: u32 = struct_version(2);
+const OPEN_SESSION_VER: u32 = struct_version(1);
+const INITIALIZE_PARAMS_VER: u32 = struct_version(5) | (1 << 31);
+const REGISTER_RESOURCE_VER: u32 = struct_version(3);
+const MAP_INPUT_RESOURCE_VER: u32 = struct_version(4);
+const CREATE_BITSTREAM_VER: u32 = struct_version(1);
+const PIC_PARAMS_VER: u32 = struct_version(4) | (1 << 31);
+const LOCK_BITSTREAM_VER: u32 = struct_version(1);
+
+const SUCCESS: u32 = 0;
+const DEVICE_TYPE_OPENGL: u32 = 2;
+const INPUT_RESOURCE_TYPE_OPENGL_TEX: u32 = 3;
+const INPUT_IMAGE: u32 = 0;
+/// Packed 8 bit RGBA in memory order, as GL stores RGBA8 textures
+const BUFFER_FORMAT_ABGR: u32 = 0x1000_0000;
+/// Packed 10 bit RGB with 2 bit alpha, as GL stores RGB10_A2 textures
+const BUFFER_FORMAT_ABGR10: u32 = 0x2000_0000;
+const PIC_STRUCT_FRAME: u32 = 1;
+const PIC_FLAG_EOS: u32 = 0x8;
+const TUNING_INFO_LOW_LATENCY: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+const CODEC_H264_GUID: Guid = Guid {
+    data1: 0x6bc8_2762,
+    data2: 0x4e63,
+    data3: 0x4ca4,
+    data4: [0xaa, 0x85, 0x1e, 0x50, 0xf3, 0x21, 0xf6, 0xbf],
+};
+/// The middle of the P1 (fastest) to P7 (best quality) presets
+const PRESET_P4_GUID: Guid = Guid {
+    data1: 0x90a7_b826,
+    data2: 0xdf06,
+    data3: 0x4862,
+    data4: [0xb9, 0xd2, 0xcd, 0x6d, 0x73, 0xa0, 0x86, 0x81],
+};
+
+type Status = u32;
+type Encoder = *mut c_void;
+
+#[repr(C)]
+struct FunctionList {
+    version: u32,
+    reserved: u32,
+    open_encode_session: *const c_void,
+    get_encode_guid_count: *const c_void,
+    get_encode_profile_guid_count: *const c_void,
+    get_encode_profile_guids: *const c_void,
+    get_encode_guids: *const c_void,
+    get_input_format_count: *const c_void,
+    get_input_formats: *const c_void,
+    get_encode_caps: *const c_void,
+    get_encode_preset_count: *const c_void,
+    get_encode_preset_guids: *const c_void,
+    get_encode_preset_config: *const c_void,
+    initialize_encoder: Option<unsafe extern "C" fn(Encoder, *mut InitializeParams) -> Status>,
+    create_input_buffer: *const c_void,
+    destroy_input_buffer: *const c_void,
+    create_bitstream_buffer: Option<unsafe extern "C" fn(Encoder, *mut CreateBitstreamBuffer) -> Status>,
+    destroy_bitstream_buffer: Option<unsafe extern "C" fn(Encoder, *mut c_void) -> Status>,
+    encode_picture: Option<unsafe extern "C" fn(Encoder, *mut PicParams) -> Status>,
+    lock_bitstream: Option<unsafe extern "C" fn(Encoder, *mut LockBitstream) -> Status>,
+    unlock_bitstream: Option<unsafe extern "C" fn(Encoder, *mut c_void) -> Status>,
+    lock_input_buffer: *const c_void,
+    unlock_input_buffer: *const c_void,
+    get_encode_stats: *const c_void,
+    get_sequence_params: *const c_void,
+    register_async_event: *const c_void,
+    unregister_async_event: *const c_void,
+    map_input_resource: Option<unsafe extern "C" fn(Encoder, *mut MapInputResource) -> Status>,
+    unmap_input_resource: Option<unsafe extern "C" fn(Encoder, *mut c_void) -> Status>,
+    destroy_encoder: Option<unsafe extern "C" fn(Encoder) -> Status>,
+    invalidate_ref_frames: *const c_void,
+    open_encode_session_ex: Option<unsafe extern "C" fn(*mut OpenSessionParams, *mut Encoder) -> Status>,
+    register_resource: Option<unsafe extern "C" fn(Encoder, *mut RegisterResource) -> Status>,
+    unregister_resource: Option<unsafe extern "C" fn(Encoder, *mut c_void) -> Status>,
+    reconfigure_encoder: *const c_void,
+    reserved1: *const c_void,
+    create_mv_buffer: *const c_void,
+    destroy_mv_buffer: *const c_void,
+    run_motion_estimation_only: *const c_void,
+    get_last_error_string: Option<unsafe extern "C" fn(Encoder) -> *const std::os::raw::c_char>,
+    set_io_cuda_streams: *const c_void,
+    get_encode_preset_config_ex: *const c_void,
+    get_sequence_param_ex: *const c_void,
+    reserved2: [*const c_void; 277],
+}
+
+#[repr(C)]
+struct OpenSessionParams {
+    version: u32,
+    device_type: u32,
+    device: *mut c_void,
+    reserved: *mut c_void,
+    api_version: u32,
+    reserved1: [u32; 253],
+    reserved2: [*mut c_void; 64],
+}
+
+#[repr(C)]
+struct InitializeParams {
+    version: u32,
+    encode_guid: Guid,
+    preset_guid: Guid,
+    encode_width: u32,
+    encode_height: u32,
+    dar_width: u32,
+    dar_height: u32,
+    frame_rate_num: u32,
+    frame_rate_den: u32,
+    enable_encode_async: u32,
+    enable_ptd: u32,
+    bit_fields: u32,
+    priv_data_size: u32,
+    priv_data: *mut c_void,
+    /// Left null, which takes everything from the preset
+    encode_config: *mut c_void,
+    max_encode_width: u32,
+    max_encode_height: u32,
+    max_me_hint_counts_per_block: [[u32; 4]; 2],
+    tuning_info: u32,
+    buffer_format: u32,
+    reserved: [u32; 287],
+    reserved2: [*mut c_void; 64],
+}
+
+#[repr(C)]
+struct InputResourceOpenGlTex {
+    texture: u32,
+    target: u32,
+}
+
+#[repr(C)]
+struct RegisterResource {
+    version: u32,
+    resource_type: u32,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    sub_resource_index: u32,
+    resource_to_register: *mut c_void,
+    registered_resource: *mut c_void,
+    buffer_format: u32,
+    buffer_usage: u32,
+    input_fence_point: *mut c_void,
+    reserved1: [u32; 247],
+    reserved2: [*mut c_void; 61],
+}
+
+#[repr(C)]
+struct MapInputResource {
+    version: u32,
+    sub_resource_index: u32,
+    input_resource: *mut c_void,
+    registered_resource: *mut c_void,
+    mapped_resource: *mut c_void,
+    mapped_buffer_format: u32,
+    reserved1: [u32; 251],
+    reserved2: [*mut c_void; 63],
+}
+
+#[repr(C)]
+struct CreateBitstreamBuffer {
+    version: u32,
+    size: u32,
+    memory_heap: u32,
+    reserved: u32,
+    bitstream_buffer: *mut c_void,
+    bitstream_buffer_ptr: *mut c_void,
+    reserved1: [u32; 58],
+    reserved2: [*mut c_void; 64],
+}
+
+#[repr(C)]
+struct PicParams {
+    version: u32,
+    input_width: u32,
+    input_height: u32,
+    input_pitch: u32,
+    encode_pic_flags: u32,
+    frame_idx: u32,
+    input_time_stamp: u64,
+    input_duration: u64,
+    input_buffer: *mut c_void,
+    output_bitstream: *mut c_void,
+    completion_event: *mut c_void,
+    buffer_fmt: u32,
+    picture_struct: u32,
+    picture_type: u32,
+    codec_pic_params: [u32; 256],
+    me_hint_counts_per_block: [[u32; 4]; 2],
+    me_external_hints: *mut c_void,
+    reserved1: [u32; 6],
+    reserved2: [*mut c_void; 2],
+    qp_delta_map: *mut i8,
+    qp_delta_map_size: u32,
+    reserved_bit_fields: u32,
+    me_hint_ref_pic_dist: [u16; 2],
+    alpha_buffer: *mut c_void,
+    reserved3: [u32; 286],
+    reserved4: [*mut c_void; 60],
+}
+
+#[repr(C)]
+struct LockBitstream {
+    version: u32,
+    bit_fields: u32,
+    output_bitstream: *mut c_void,
+    slice_offsets: *mut u32,
+    frame_idx: u32,
+    hw_encode_status: u32,
+    num_slices: u32,
+    bitstream_size_in_bytes: u32,
+    output_time_stamp: u64,
+    output_duration: u64,
+    bitstream_buffer_ptr: *mut c_void,
+    picture_type: u32,
+    picture_struct: u32,
+    frame_avg_qp: u32,
+    frame_satd: u32,
+    ltr_frame_idx: u32,
+    ltr_frame_bitmap: u32,
+    temporal_id: u32,
+    reserved: [u32; 12],
+    intra_mb_count: u32,
+    inter_mb_count: u32,
+    average_mvx: i32,
+    average_mvy: i32,
+    reserved1: [u32; 219],
+    reserved2: [*mut c_void; 64],
+}
+
+/// The nvidia driver ships the encoder interface as part of its userspace, but not every distribution installs it
+const LIBRARY: &str = "libnvidia-encode.so.1";
+
+/// Zeroed instance of one of the structs above, all of them are plain data and valid when zeroed
+fn zeroed<T>() -> Box<T> {
+    unsafe { Box::new(std::mem::zeroed()) }
+}
+
+/// The entry points of libnvidia-encode, loaded at runtime so nvscreencopy works without it
+struct Api {
+    functions: Box<FunctionList>,
+    _library: Library,
+}
+
+impl Api {
+    fn load() -> Result<Api> {
+        let library = unsafe { Library::new(LIBRARY) }
+            .with_context(|| format!("Failed to load {}, is the encoder library of the nvidia driver installed?", LIBRARY))?;
+        let mut functions = zeroed::<FunctionList>();
+        functions.version = FUNCTION_LIST_VER;
+        unsafe {
+            let max_version = library
+                .get::<unsafe extern "C" fn(*mut u32) -> Status>(b"NvEncodeAPIGetMaxSupportedVersion\0")
+                .with_context(|| format!("{} lacks NvEncodeAPIGetMaxSupportedVersion", LIBRARY))?;
+            let mut version = 0;
+            check(max_version(&mut version), "query the supported NVENC version")?;
+            if version < (API_MAJOR << 4 | API_MINOR) {
+                anyhow::bail!(
+                    "The nvidia driver supports NVENC API {}.{}, recording needs {}.{}",
+                    version >> 4,
+                    version & 0xf,
+                    API_MAJOR,
+                    API_MINOR
+                );
+            }
+            let create = library
+                .get::<unsafe extern "C" fn(*mut FunctionList) -> Status>(b"NvEncodeAPICreateInstance\0")
+                .with_context(|| format!("{} lacks NvEncodeAPICreateInstance", LIBRARY))?;
+            check(create(&mut *functions), "load the NVENC entry points")?;
+        }
+        Ok(Api {
+            functions,
+            _library: library,
+        })
+    }
+}
+
+fn check(status: Status, what: &str) -> Result<()> {
+    if status != SUCCESS {
+        anyhow::bail!("Failed to {}: NVENC error {}", what, status);
+    }
+    Ok(())
+}
+
+/// Looks up an entry point, which every driver supporting the API version we ask for has
+macro_rules! call {
+    ($api:expr, $name:ident ( $($arg:expr),* )) => {
+        ($api.functions.$name.expect(concat!("NVENC lacks ", stringify!($name))))($($arg),*)
+    };
+}
+
+/// An encoder session of a fixed size, encoding from a texture frames are copied into
+struct Session {
+    encoder: Encoder,
+    size: Size<i32, Physical>,
+    depth: ColorDepth,
+    /// Texture and framebuffer on the target the shown frames are copied into
+    tex: u32,
+    fbo: u32,
+    registered: *mut c_void,
+    bitstream: *mut c_void,
+    frames: u32,
+}
+
+impl Session {
+    /// Needs the context of the target to be current, which NVENC encodes on
+    fn new(api: &Api, gl: &ffi::Gles2, size: Size<i32, Physical>, depth: ColorDepth, fps: u32) -> Result<Session> {
+        let mut params = zeroed::<OpenSessionParams>();
+        params.version = OPEN_SESSION_VER;
+        params.device_type = DEVICE_TYPE_OPENGL;
+        params.api_version = API_VERSION;
+        let mut encoder = ptr::null_mut();
+        check(
+            unsafe { call!(api, open_encode_session_ex(&mut *params, &mut encoder)) },
+            "open an NVENC session on the output",
+        )?;
+        let mut session = Session {
+            encoder,
+            size,
+            depth,
+            tex: 0,
+            fbo: 0,
+            registered: ptr::null_mut(),
+            bitstream: ptr::null_mut(),
+            frames: 0,
+        };
+        // the session is cleaned up by `destroy` from here on
+        if let Err(err) = unsafe { session.init(api, gl, fps) } {
+            session.destroy(api, gl);
+            return Err(err);
+        }
+        Ok(session)
+    }
+
+    unsafe fn init(&mut self, api: &Api, gl: &ffi::Gles2, fps: u32) -> Result<()> {
+        let (width, height) = (self.size.w as u32, self.size.h as u32);
+        let mut init = zeroed::<InitializeParams>();
+        init.version = INITIALIZE_PARAMS_VER;
+        init.encode_guid = CODEC_H264_GUID;
+        init.preset_guid = PRESET_P4_GUID;
+        init.tuning_info = TUNING_INFO_LOW_LATENCY;
+        init.encode_width = width;
+        init.encode_height = height;
+        init.dar_width = width;
+        init.dar_height = height;
+        init.max_encode_width = width;
+        init.max_encode_height = height;
+        init.frame_rate_num = fps.max(1);
+        init.frame_rate_den = 1;
+        init.enable_ptd = 1;
+        check(call!(api, initialize_encoder(self.encoder, &mut *init)), "initialize the encoder")
+            .map_err(|err| last_error(api, self.encoder, err))?;
+
+        let (internal, _, _) = gl_format(self.depth);
+        let format = match self.depth {
+            ColorDepth::Eight => BUFFER_FORMAT_ABGR,
+            ColorDepth::Ten => BUFFER_FORMAT_ABGR10,
+        };
+        gl.GenTextures(1, &mut self.tex);
+        gl.BindTexture(ffi::TEXTURE_2D, self.tex);
+        gl.TexStorage2D(ffi::TEXTURE_2D, 1, internal, self.size.w, self.size.h);
+        gl.BindTexture(ffi::TEXTURE_2D, 0);
+        let mut bound = 0;
+        gl.GetIntegerv(ffi::FRAMEBUFFER_BINDING, &mut bound);
+        gl.GenFramebuffers(1, &mut self.fbo);
+        gl.BindFramebuffer(ffi::FRAMEBUFFER, self.fbo);
+        gl.FramebufferTexture2D(ffi::FRAMEBUFFER, ffi::COLOR_ATTACHMENT0, ffi::TEXTURE_2D, self.tex, 0);
+        gl.BindFramebuffer(ffi::FRAMEBUFFER, bound as u32);
+
+        let mut texture = InputResourceOpenGlTex {
+            texture: self.tex,
+            target: ffi::TEXTURE_2D,
+        };
+        let mut register = zeroed::<RegisterResource>();
+        register.version = REGISTER_RESOURCE_VER;
+        register.resource_type = INPUT_RESOURCE_TYPE_OPENGL_TEX;
+        register.width = width;
+        register.height = height;
+        register.resource_to_register = &mut texture as *mut _ as *mut c_void;
+        register.buffer_format = format;
+        register.buffer_usage = INPUT_IMAGE;
+        check(call!(api, register_resource(self.encoder, &mut *register)), "register the output texture")
+            .map_err(|err| last_error(api, self.encoder, err))?;
+        self.registered = register.registered_resource;
+
+        let mut bitstream = zeroed::<CreateBitstreamBuffer>();
+        bitstream.version = CREATE_BITSTREAM_VER;
+        check(
+            call!(api, create_bitstream_buffer(self.encoder, &mut *bitstream)),
+            "create the bitstream buffer",
+        )?;
+        self.bitstream = bitstream.bitstream_buffer;
+        Ok(())
+    }
+
+    /// Copies the frame in the bound framebuffer of the target, which has the size of the session, and encodes it
+    unsafe fn encode(&mut self, api: &Api, gl: &ffi::Gles2, timestamp: Duration, out: &mut impl Write) -> Result<()> {
+        // depending on the backend the surface is the default framebuffer or one of smithay
+        let mut bound = 0;
+        gl.GetIntegerv(ffi::FRAMEBUFFER_BINDING, &mut bound);
+        gl.BindFramebuffer(ffi::READ_FRAMEBUFFER, bound as u32);
+        gl.BindFramebuffer(ffi::DRAW_FRAMEBUFFER, self.fbo);
+        // flipped, as GL stores rows bottom up and NVENC reads them top down
+        gl.BlitFramebuffer(
+            0,
+            0,
+            self.size.w,
+            self.size.h,
+            0,
+            self.size.h,
+            self.size.w,
+            0,
+            ffi::COLOR_BUFFER_BIT,
+            ffi::NEAREST,
+        );
+        gl.BindFramebuffer(ffi::FRAMEBUFFER, bound as u32);
+
+        let mut map = zeroed::<MapInputResource>();
+        map.version = MAP_INPUT_RESOURCE_VER;
+        map.registered_resource = self.registered;
+        check(call!(api, map_input_resource(self.encoder, &mut *map)), "map the output texture")?;
+        let mut pic = zeroed::<PicParams>();
+        pic.version = PIC_PARAMS_VER;
+        pic.input_width = self.size.w as u32;
+        pic.input_height = self.size.h as u32;
+        pic.frame_idx = self.frames;
+        pic.input_time_stamp = timestamp.as_micros() as u64;
+        pic.input_buffer = map.mapped_resource;
+        pic.output_bitstream = self.bitstream;
+        pic.buffer_fmt = map.mapped_buffer_format;
+        pic.picture_struct = PIC_STRUCT_FRAME;
+        let encoded = check(call!(api, encode_picture(self.encoder, &mut *pic)), "encode frame")
+            .map_err(|err| last_error(api, self.encoder, err));
+        call!(api, unmap_input_resource(self.encoder, map.mapped_resource));
+        encoded?;
+        self.frames += 1;
+        self.write_bitstream(api, out)
+    }
+
+    /// Appends the encoded frame to `out`, waiting for the encoder to finish it
+    unsafe fn write_bitstream(&mut self, api: &Api, out: &mut impl Write) -> Result<()> {
+        let mut lock = zeroed::<LockBitstream>();
+        lock.version = LOCK_BITSTREAM_VER;
+        lock.output_bitstream = self.bitstream;
+        check(call!(api, lock_bitstream(self.encoder, &mut *lock)), "lock the bitstream")?;
+        let data = std::slice::from_raw_parts(
+            lock.bitstream_buffer_ptr as *const u8,
+            lock.bitstream_size_in_bytes as usize,
+        );
+        let written = out.write_all(data).context("Failed to write the recording");
+        call!(api, unlock_bitstream(self.encoder, self.bitstream));
+        written
+    }
+
+    /// Flushes the encoder and frees everything, needs the context of the target to be current
+    fn destroy(self, api: &Api, gl: &ffi::Gles2) {
+        unsafe {
+            if !self.bitstream.is_null() {
+                call!(api, destroy_bitstream_buffer(self.encoder, self.bitstream));
+            }
+            if !self.registered.is_null() {
+                call!(api, unregister_resource(self.encoder, self.registered));
+            }
+            call!(api, destroy_encoder(self.encoder));
+            gl.DeleteFramebuffers(1, &self.fbo);
+            gl.DeleteTextures(1, &self.tex);
+        }
+    }
+
+    /// Tells the encoder the stream ends, without synchronous encoding it holds back no frames
+    unsafe fn flush(&mut self, api: &Api) -> Result<()> {
+        let mut pic = zeroed::<PicParams>();
+        pic.version = PIC_PARAMS_VER;
+        pic.encode_pic_flags = PIC_FLAG_EOS;
+        check(call!(api, encode_picture(self.encoder, &mut *pic)), "flush the encoder")
+    }
+}
+
+/// Adds the explanation of the driver to an error of `encoder`
+fn last_error(api: &Api, encoder: Encoder, err: anyhow::Error) -> anyhow::Error {
+    let message = match api.functions.get_last_error_string {
+        Some(last_error) => unsafe {
+            let message = last_error(encoder);
+            if message.is_null() {
+                return err;
+            }
+            std::ffi::CStr::from_ptr(message).to_string_lossy().into_owned()
+        },
+        None => return err,
+    };
+    err.context(message)
+}
+
+/// Records every frame shown on the output with NVENC into an h264 file.
+///
+/// Frames are copied on the target gpu into a texture the encoder reads from, so they never leave the gpu until
+/// they are encoded. The session follows the size of the output and is recreated with the target context,
+/// which starts a new h264 stream in the same file.
+pub struct Recorder {
+    api: Api,
+    file: BufWriter<File>,
+    session: Option<Session>,
+    fps: u32,
+    started: Instant,
+    log: slog::Logger,
+}
+
+/// Refuses file names suggesting a container, recordings are raw h264 streams
+pub fn check_path(path: &Path) -> Result<()> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("h264") | Some("264") => Ok(()),
+        Some("mkv") => anyhow::bail!(
+            "Recording into matroska is not supported yet, record into a .h264 file and remux it with \
+             \"ffmpeg -i recording.h264 -c copy recording.mkv\""
+        ),
+        _ => anyhow::bail!("Recordings are raw h264 streams, their file name needs to end with .h264"),
+    }
+}
+
+impl Recorder {
+    /// Fails if the encoder library is missing or too old for the API we use
+    pub fn new(path: &Path, fps: u32, log: slog::Logger) -> Result<Recorder> {
+        let api = Api::load()?;
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        slog::info!(log, "Recording to {}", path.display());
+        Ok(Recorder {
+            api,
+            file: BufWriter::new(file),
+            session: None,
+            fps,
+            started: Instant::now(),
+            log,
+        })
+    }
+
+    /// Encodes the frame rendered into the surface of the target, which is bound and `size`, before it is swapped
+    pub fn record(&mut self, renderer: &mut Gles2Renderer, size: Size<i32, Physical>, depth: ColorDepth) -> Result<()> {
+        let timestamp = self.started.elapsed();
+        let Recorder {
+            api,
+            file,
+            session,
+            fps,
+            log,
+            ..
+        } = self;
+        renderer.with_context(|_renderer, gl| {
+            if session.as_ref().map(|session| session.size != size || session.depth != depth).unwrap_or(false) {
+                slog::info!(log, "Output changed, starting a new stream in the recording");
+                let mut old = session.take().unwrap();
+                if let Err(err) = unsafe { old.flush(api) } {
+                    slog::warn!(log, "{}", err);
+                }
+                old.destroy(api, gl);
+            }
+            if session.is_none() {
+                *session = Some(Session::new(api, gl, size, depth, *fps)?);
+            }
+            unsafe { session.as_mut().unwrap().encode(api, gl, timestamp, file) }
+        })?
+    }
+
+    /// Ends the current stream, the next frame starts a new session. Needs the context of the target.
+    pub fn stop(&mut self, renderer: &mut Gles2Renderer) {
+        let Recorder {
+            api, session, log, ..
+        } = self;
+        if let Some(mut old) = session.take() {
+            let result = renderer.with_context(|_renderer, gl| {
+                let flushed = unsafe { old.flush(api) };
+                old.destroy(api, gl);
+                flushed
+            });
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => slog::warn!(log, "{}", err),
+                Err(err) => slog::warn!(log, "Failed to stop the encoder: {}", err),
+            }
+        }
+        if let Err(err) = self.file.flush() {
+            slog::warn!(self.log, "Failed to write the recording: {}", err);
+        }
+    }
+
+    /// Drops the session without touching the context of the target, which is gone already
+    pub fn forget(&mut self) {
+        if self.session.take().is_some() {
+            slog::info!(self.log, "Lost the encoder along the output, starting a new stream in the recording");
+        }
+    }
+}
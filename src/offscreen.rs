@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use smithay::{
+    backend::{
+        egl::{EGLContext, EGLDisplay},
+        renderer::gles2::{ffi, Gles2Renderer},
+    },
+    reexports::drm::control::Mode,
+};
+
+use crate::{
+    egl::EGLDeviceEXT,
+    gpu::{ColorDepth, Fd, GlCapabilities, PresentError, TargetBackend},
+    render,
+};
+
+use std::path::Path;
+
+/// Renders into a framebuffer object instead of scanning out, for running without a monitor or an nvidia gpu
+pub struct OffscreenBackend {
+    fbo: u32,
+    size: (i32, i32),
+    depth: ColorDepth,
+    display: EGLDisplay,
+    _device: EGLDeviceEXT,
+}
+
+impl OffscreenBackend {
+    /// Sets up a framebuffer of `size` on the gpu behind `fd`, which may be a render node
+    pub fn new(fd: Fd, size: (i32, i32), depth: ColorDepth, log: &slog::Logger) -> Result<(OffscreenBackend, Gles2Renderer)> {
+        let device = EGLDeviceEXT::new(fd, log.clone())?;
+        let display = EGLDisplay::new(&device, log.clone())?;
+        let (renderer, fbo) = create_context(&display, size, depth, log)?;
+        Ok((
+            OffscreenBackend {
+                fbo,
+                size,
+                depth,
+                display,
+                _device: device,
+            },
+            renderer,
+        ))
+    }
+}
+
+/// A renderer along a framebuffer of its context
+fn create_context(
+    display: &EGLDisplay,
+    size: (i32, i32),
+    depth: ColorDepth,
+    log: &slog::Logger,
+) -> Result<(Gles2Renderer, u32)> {
+    let context = EGLContext::new(display, log.clone())?;
+    let mut renderer = unsafe { Gles2Renderer::new(context, log.clone())? };
+    let caps = GlCapabilities::query(&mut renderer)?;
+    let fbo = renderer.with_context(|_renderer, gl| unsafe { create_framebuffer(gl, caps, size, depth) })??;
+    Ok((renderer, fbo))
+}
+
+/// Framebuffer with a texture of `size` and `depth` attached, which lives as long as the context
+unsafe fn create_framebuffer(gl: &ffi::Gles2, caps: GlCapabilities, size: (i32, i32), depth: ColorDepth) -> Result<u32> {
+    let (mut tex, mut fbo) = (0, 0);
+    let (internal, format, ty) = render::texture_format(depth, caps);
+    gl.GenTextures(1, &mut tex);
+    gl.BindTexture(ffi::TEXTURE_2D, tex);
+    gl.TexImage2D(
+        ffi::TEXTURE_2D,
+        0,
+        internal as i32,
+        size.0,
+        size.1,
+        0,
+        format,
+        ty,
+        std::ptr::null(),
+    );
+    gl.BindTexture(ffi::TEXTURE_2D, 0);
+    gl.GenFramebuffers(1, &mut fbo);
+    gl.BindFramebuffer(ffi::FRAMEBUFFER, fbo);
+    gl.FramebufferTexture2D(ffi::FRAMEBUFFER, ffi::COLOR_ATTACHMENT0, ffi::TEXTURE_2D, tex, 0);
+    let status = gl.CheckFramebufferStatus(ffi::FRAMEBUFFER);
+    gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+    if status != ffi::FRAMEBUFFER_COMPLETE {
+        anyhow::bail!("Offscreen framebuffer of {}x{} is incomplete: 0x{:x}", size.0, size.1, status);
+    }
+    Ok(fbo)
+}
+
+impl TargetBackend for OffscreenBackend {
+    fn name(&self) -> &'static str {
+        "offscreen"
+    }
+
+    fn bind(&mut self, renderer: &mut Gles2Renderer) -> Result<()> {
+        let fbo = self.fbo;
+        renderer.with_context(|_renderer, gl| unsafe { gl.BindFramebuffer(ffi::FRAMEBUFFER, fbo) })?;
+        Ok(())
+    }
+
+    // the frame stays in the framebuffer until the next one is rendered
+    fn present(&mut self) -> Result<(), PresentError> {
+        Ok(())
+    }
+
+    fn acquire(&mut self) -> Result<(), PresentError> {
+        Ok(())
+    }
+
+    fn frame_submitted(&mut self) {}
+
+    // there is no crtc, the framebuffer keeps its size
+    fn set_mode(&mut self, _drm_mode: Mode) -> Result<()> {
+        Ok(())
+    }
+
+    fn restore_scanout(&mut self, _drm_mode: Mode) -> Result<()> {
+        Ok(())
+    }
+
+    fn rebuild_context(&mut self, log: &slog::Logger) -> Result<Gles2Renderer> {
+        let (renderer, fbo) = create_context(&self.display, self.size, self.depth, log)?;
+        self.fbo = fbo;
+        Ok(renderer)
+    }
+
+    fn plane_scaling(&self) -> Option<(i32, i32)> {
+        None
+    }
+}
+
+/// Contents of the bound framebuffer of `size` as 8 bit RGBA, starting with the top row
+pub fn read_frame(renderer: &mut Gles2Renderer, size: (i32, i32)) -> Result<Vec<u8>> {
+    let (width, height) = size;
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    renderer.with_context(|_renderer, gl| unsafe {
+        gl.PixelStorei(ffi::PACK_ALIGNMENT, 1);
+        gl.ReadPixels(
+            0,
+            0,
+            width,
+            height,
+            ffi::RGBA,
+            ffi::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut _,
+        );
+    })?;
+    // rows of gl framebuffers start at the bottom
+    Ok(pixels
+        .chunks_exact(width as usize * 4)
+        .rev()
+        .flatten()
+        .copied()
+        .collect())
+}
+
+/// Writes the contents of the bound framebuffer of `size` to `path`, as 8 bit RGBA
+pub fn write_png(renderer: &mut Gles2Renderer, size: (i32, i32), path: &Path) -> Result<()> {
+    let rows = read_frame(renderer, size)?;
+    save_png(&rows, size, path)
+}
+
+/// Writes RGBA `pixels` of `size`, starting with the top row, to `path`
+pub fn save_png(pixels: &[u8], size: (i32, i32), path: &Path) -> Result<()> {
+    image::save_buffer_with_format(
+        path,
+        pixels,
+        size.0 as u32,
+        size.1 as u32,
+        image::ColorType::Rgba8,
+        image::ImageFormat::Png,
+    )
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,53 @@
+use crate::raw::RawDestination;
+
+use std::{path::PathBuf, str::FromStr};
+
+/// Where the mirrored frames go, requested with `--output`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Scanned out on a connector of the target gpu
+    Drm,
+    /// Written to a file or stdout as raw BGRA, without any target gpu
+    Raw(RawDestination),
+    /// Offered as PipeWire video source node, without any target gpu. Needs the pipewire feature.
+    Pipewire,
+    /// Rendered into a framebuffer on the render gpu, in the given size or the one of the source
+    Offscreen(Option<(i32, i32)>),
+}
+
+impl OutputKind {
+    /// Whether frames are rendered by a `TargetGPU`
+    pub fn has_target(&self) -> bool {
+        matches!(self, OutputKind::Drm | OutputKind::Offscreen(_))
+    }
+}
+
+impl FromStr for OutputKind {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<OutputKind> {
+        match input.split_once(':') {
+            None if input == "drm" => Ok(OutputKind::Drm),
+            None if input == "pipewire" => Ok(OutputKind::Pipewire),
+            None if input == "offscreen" => Ok(OutputKind::Offscreen(None)),
+            Some(("offscreen", size)) => {
+                let size = size
+                    .split_once('x')
+                    .and_then(|(width, height)| Some((width.parse::<i32>().ok()?, height.parse::<i32>().ok()?)));
+                match size {
+                    Some((width, height)) if width > 0 && height > 0 => {
+                        Ok(OutputKind::Offscreen(Some((width, height))))
+                    }
+                    _ => anyhow::bail!("Offscreen output needs a size like \"offscreen:1920x1080\""),
+                }
+            }
+            Some(("raw", "-")) => Ok(OutputKind::Raw(RawDestination::Stdout)),
+            Some(("raw", "")) => anyhow::bail!("Raw output needs a path or \"-\" for stdout, e.g. \"raw:-\""),
+            Some(("raw", path)) => Ok(OutputKind::Raw(RawDestination::File(PathBuf::from(path)))),
+            _ => anyhow::bail!(
+                "Unknown output \"{}\", expected \"drm\", \"raw:PATH\", \"pipewire\" or \"offscreen[:WxH]\"",
+                input
+            ),
+        }
+    }
+}
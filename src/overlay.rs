@@ -0,0 +1,205 @@
+use smithay::{
+    backend::renderer::{
+        gles2::{Gles2Error, Gles2Frame, Gles2Renderer, Gles2Texture},
+        Frame, Transform,
+    },
+    utils::{Buffer, Physical, Point, Rectangle, Size},
+};
+
+use crate::{
+    gpu::{ColorDepth, GlCapabilities},
+    render,
+};
+
+const GLYPH_WIDTH: i32 = 5;
+const GLYPH_HEIGHT: i32 = 7;
+/// Advance between characters and lines
+const CHAR_WIDTH: i32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: i32 = GLYPH_HEIGHT + 2;
+/// Distance from the corner and padding inside of the box
+const MARGIN: i32 = 4;
+const PADDING: i32 = 2;
+const BACKGROUND_ALPHA: f32 = 0.6;
+/// Longer lines are cut off
+const MAX_CHARS: usize = 48;
+
+/// Characters of the font, in the order of `GLYPHS`, which is also their order in the atlas
+const CHARSET: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ .:-/(),?_%=";
+
+/// 5x7 bitmap font, one byte per row with the leftmost pixel in bit 4
+const GLYPHS: [[u8; 7]; 48] = [
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+    [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+    [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+    [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+    [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+    [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+    [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+    [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+    [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+    [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+    [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+    [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+    [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+    [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+    [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+    [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+    [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+    [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+    [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+    [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+    [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+];
+
+/// Cell of the atlas following the glyphs, that is completely filled
+const SOLID: usize = GLYPHS.len();
+
+/// Cell of the atlas holding the given character, unknown ones are shown as '?'
+fn glyph_index(c: char) -> usize {
+    let c = c.to_ascii_uppercase();
+    CHARSET
+        .chars()
+        .position(|x| x == c)
+        .or_else(|| CHARSET.chars().position(|x| x == '?'))
+        .unwrap()
+}
+
+/// Premultiplied RGBA pixels of all glyphs and the solid cell next to each other,
+/// every font pixel enlarged to `scale`x`scale` pixels.
+///
+/// Returns the pixels and the size of the atlas.
+pub fn rasterize(scale: i32) -> (Vec<u8>, i32, i32) {
+    let width = (SOLID as i32 + 1) * GLYPH_WIDTH * scale;
+    let height = GLYPH_HEIGHT * scale;
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let cell = (x / (GLYPH_WIDTH * scale)) as usize;
+            let column = (x / scale) % GLYPH_WIDTH;
+            let row = (y / scale) as usize;
+            let set = cell == SOLID || GLYPHS[cell][row] & (0x10 >> column) != 0;
+            if set {
+                let offset = ((y * width + x) * 4) as usize;
+                pixels[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+            }
+        }
+    }
+    (pixels, width, height)
+}
+
+/// Small status box in the top left corner of the target
+pub struct Overlay {
+    atlas: Gles2Texture,
+    scale: i32,
+}
+
+impl Overlay {
+    pub fn new(
+        renderer: &mut Gles2Renderer,
+        caps: GlCapabilities,
+        dest_size: Size<i32, Physical>,
+    ) -> anyhow::Result<Overlay> {
+        // stay readable from across the room
+        let scale = (dest_size.h / 360).max(1);
+        let (pixels, width, height) = rasterize(scale);
+        let atlas = render::create_texture(renderer, caps, width, height, ColorDepth::Eight)?;
+        render::update_bitmap(
+            renderer,
+            caps,
+            &atlas,
+            &pixels,
+            width * 4,
+            &[Rectangle::from_loc_and_size((0, 0), (width, height))],
+            ColorDepth::Eight,
+        )?;
+        Ok(Overlay { atlas, scale })
+    }
+
+    fn cell(&self, index: usize) -> Rectangle<i32, Buffer> {
+        Rectangle::from_loc_and_size(
+            (index as i32 * GLYPH_WIDTH * self.scale, 0),
+            (GLYPH_WIDTH * self.scale, GLYPH_HEIGHT * self.scale),
+        )
+    }
+
+    /// Draws `lines` on top of whatever was rendered into `frame` before.
+    ///
+    /// Glyphs are drawn 1:1 in physical pixels, independent of how the source is scaled.
+    pub fn draw(&self, frame: &mut Gles2Frame, lines: &[String]) -> Result<(), Gles2Error> {
+        self.draw_at(frame, Point::from((0.0, 0.0)), lines)
+    }
+
+    /// Draws `lines` into the top left corner of the region starting at `origin`, e.g. a cell of `--layout`
+    pub fn draw_at(
+        &self,
+        frame: &mut Gles2Frame,
+        origin: Point<f64, Physical>,
+        lines: &[String],
+    ) -> Result<(), Gles2Error> {
+        let scale = self.scale;
+        let columns = lines
+            .iter()
+            .map(|line| line.chars().count().min(MAX_CHARS))
+            .max()
+            .unwrap_or(0) as i32;
+        let size = (
+            (columns * CHAR_WIDTH - 1 + 2 * PADDING) * scale,
+            (lines.len() as i32 * LINE_HEIGHT - 2 + 2 * PADDING) * scale,
+        );
+        // stretching the center of the solid cell gives an evenly filled box
+        let solid = self.cell(SOLID);
+        let solid = Rectangle::from_loc_and_size(
+            (solid.loc.x + solid.size.w / 2, solid.size.h / 2),
+            (1, 1),
+        );
+        let background = Rectangle::from_loc_and_size(
+            (origin.x + (MARGIN * scale) as f64, origin.y + (MARGIN * scale) as f64),
+            (size.0 as f64, size.1 as f64),
+        );
+        frame.render_texture_from_to(&self.atlas, solid, background, Transform::Normal, BACKGROUND_ALPHA)?;
+
+        for (row, line) in lines.iter().enumerate() {
+            let y = (MARGIN + PADDING + row as i32 * LINE_HEIGHT) * scale;
+            for (column, c) in line.chars().take(MAX_CHARS).enumerate() {
+                if c == ' ' {
+                    continue;
+                }
+                let x = (MARGIN + PADDING + column as i32 * CHAR_WIDTH) * scale;
+                let src = self.cell(glyph_index(c));
+                let dst = Rectangle::from_loc_and_size(
+                    (origin.x + x as f64, origin.y + y as f64),
+                    (src.size.w as f64, src.size.h as f64),
+                );
+                frame.render_texture_from_to(&self.atlas, src, dst, Transform::Normal, 1.0)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+/// Holds rendered frames back until a fixed time after their capture, so they reach the output
+/// at an even cadence, even if the source and the output refresh at slightly different rates.
+///
+/// The EGLOutput consumer takes no presentation time, `StreamConsumerAcquireAttribNV` only knows
+/// the flip event data, so the swap itself is delayed. Superseded frames never enter the stream,
+/// so there is nothing to release for them.
+pub struct Pacing {
+    /// Time between capturing a frame and swapping it
+    budget: Duration,
+    /// Capture time of the rendered frame waiting to be swapped
+    pending: Option<Duration>,
+    /// Delay of the swap timer to arm, taken by the main loop
+    timer: Option<Duration>,
+}
+
+impl Pacing {
+    pub fn new(budget: Duration) -> Pacing {
+        Pacing {
+            budget,
+            pending: None,
+            timer: None,
+        }
+    }
+
+    /// Whether the frame captured at `captured`, which was just rendered, is swapped later.
+    ///
+    /// A frame still waiting is superseded by it and never swapped.
+    pub fn defer(&mut self, captured: Duration, now: Duration, log: &slog::Logger) -> bool {
+        let superseded = self.pending.take().is_some();
+        if superseded {
+            slog::debug!(log, "Paced frame superseded before its swap");
+        }
+        let target = captured + self.budget;
+        if target <= now {
+            return false;
+        }
+        self.pending = Some(captured);
+        // an earlier timer re-arms itself in `due`
+        if !superseded {
+            self.timer = Some(target - now);
+        }
+        true
+    }
+
+    /// Takes the delay of the swap timer to arm
+    pub fn take_timer(&mut self) -> Option<Duration> {
+        self.timer.take()
+    }
+
+    /// The swap timer fired, returns the capture time of the frame to swap now
+    pub fn due(&mut self, now: Duration) -> Option<Duration> {
+        let captured = self.pending?;
+        let target = captured + self.budget;
+        if target > now {
+            self.timer = Some(target - now);
+            return None;
+        }
+        self.pending = None;
+        Some(captured)
+    }
+
+    /// The rendered frame was overwritten by something else
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+}
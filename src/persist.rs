@@ -0,0 +1,291 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{edid::Edid, gpu::TargetOptions};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// Settings that last worked on a monitor, recalled when it is plugged in again
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitorSettings {
+    /// Size of the mode, `None` if it was given by `--modeline`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<(i32, i32)>,
+    /// The display engine scaled the frames, see `--plane-scaling`
+    #[serde(default)]
+    pub plane_scaling: bool,
+    /// Properties set by `--connector-prop`, as "NAME=VALUE"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connector_props: Vec<String>,
+}
+
+/// What the command line set, which always wins over remembered settings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Explicit {
+    /// `--mode` or `--modeline`
+    pub mode: bool,
+    pub plane_scaling: bool,
+    pub connector_props: bool,
+}
+
+impl MonitorSettings {
+    /// Fills in what the command line left open, returns the names of the settings that were applied.
+    ///
+    /// `content` is the size of the mirrored content, which the plane scales to the mode.
+    pub fn apply(&self, explicit: Explicit, options: &mut TargetOptions, content: (i32, i32)) -> Vec<&'static str> {
+        let mut applied = Vec::new();
+        if let (false, Some(mode)) = (explicit.mode, self.mode) {
+            options.mode = mode;
+            applied.push("mode");
+        }
+        if !explicit.plane_scaling && self.plane_scaling {
+            options.plane_scaling = Some(content);
+            applied.push("plane scaling");
+        }
+        if !explicit.connector_props && !self.connector_props.is_empty() {
+            // the file may have been edited by hand
+            options.connector_props = self.connector_props.iter().flat_map(|prop| prop.parse().ok()).collect();
+            applied.push("connector properties");
+        }
+        applied
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    /// Keyed by `Edid::identity`
+    #[serde(default)]
+    monitors: BTreeMap<String, MonitorSettings>,
+}
+
+/// `$XDG_STATE_HOME/nvscreencopy`, `~/.local/state` without `XDG_STATE_HOME`
+pub fn state_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .map(|dir| dir.join("nvscreencopy"))
+}
+
+/// The settings of every monitor nvscreencopy was used with, kept in `monitors.toml`
+pub struct MonitorStore {
+    path: PathBuf,
+    file: StateFile,
+}
+
+impl MonitorStore {
+    /// `$XDG_STATE_HOME/nvscreencopy/monitors.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        state_dir().map(|dir| dir.join("monitors.toml"))
+    }
+
+    /// Reads the file at `path`, which holds no monitors yet if it does not exist
+    pub fn load(path: PathBuf) -> Result<MonitorStore> {
+        let file = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => StateFile::default(),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(MonitorStore { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, edid: &Edid) -> Option<&MonitorSettings> {
+        self.file.monitors.get(&edid.identity())
+    }
+
+    /// Returns whether the settings of the monitor changed, which then need to be saved
+    pub fn remember(&mut self, edid: &Edid, settings: MonitorSettings) -> bool {
+        self.file.monitors.insert(edid.identity(), settings.clone()) != Some(settings)
+    }
+
+    /// Returns whether the monitor was known
+    pub fn forget(&mut self, edid: &Edid) -> bool {
+        self.file.monitors.remove(&edid.identity()).is_some()
+    }
+
+    /// Replaces the file at once, so an interrupted write never leaves half of it behind
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let text = toml::to_string(&self.file).context("Failed to serialize the monitor settings")?;
+        let tmp = self.path.with_extension("toml.tmp");
+        fs::write(&tmp, text).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| format!("Failed to replace {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{ColorDepth, TargetBackendKind};
+    use smithay::backend::renderer::Transform;
+
+    fn edid(serial: Option<&str>) -> Edid {
+        Edid {
+            manufacturer: String::from("DEL"),
+            product: 0xa0c4,
+            name: Some(String::from("DELL U2720Q")),
+            serial: serial.map(String::from),
+        }
+    }
+
+    fn settings() -> MonitorSettings {
+        MonitorSettings {
+            mode: Some((2560, 1440)),
+            plane_scaling: true,
+            connector_props: vec![String::from("max bpc=10")],
+        }
+    }
+
+    /// What the defaults and an empty command line set up
+    fn options() -> TargetOptions {
+        TargetOptions {
+            backend: TargetBackendKind::Gbm,
+            connector: None,
+            mode: (1920, 1080),
+            modeline: None,
+            refresh: None,
+            strict_mode: false,
+            allow_crtc_steal: false,
+            depth: ColorDepth::Eight,
+            stream: crate::Options::default().stream,
+            legacy_modesetting: false,
+            vrr: false,
+            connector_props: Vec::new(),
+            colorspace: None,
+            hdr_metadata: None,
+            gamma_lut: None,
+            transform: Transform::Normal,
+            plane_scaling: None,
+            plane: None,
+            gl_debug: false,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        // the state directory is created on the first save
+        let path = dir.path().join("nvscreencopy/monitors.toml");
+        let mut store = MonitorStore::load(path.clone()).unwrap();
+        assert_eq!(store.get(&edid(Some("ABC123"))), None);
+
+        assert!(store.remember(&edid(Some("ABC123")), settings()));
+        assert!(!store.remember(&edid(Some("ABC123")), settings()));
+        let other = MonitorSettings {
+            mode: None,
+            plane_scaling: false,
+            connector_props: Vec::new(),
+        };
+        assert!(store.remember(&edid(None), other.clone()));
+        store.save().unwrap();
+        assert!(!path.with_extension("toml.tmp").exists());
+
+        let mut store = MonitorStore::load(path.clone()).unwrap();
+        assert_eq!(store.path(), path);
+        assert_eq!(store.get(&edid(Some("ABC123"))), Some(&settings()));
+        assert_eq!(store.get(&edid(None)), Some(&other));
+        assert_eq!(store.get(&edid(Some("XYZ"))), None);
+
+        assert!(store.forget(&edid(Some("ABC123"))));
+        assert!(!store.forget(&edid(Some("ABC123"))));
+        store.save().unwrap();
+        let store = MonitorStore::load(path).unwrap();
+        assert_eq!(store.get(&edid(Some("ABC123"))), None);
+        assert_eq!(store.get(&edid(None)), Some(&other));
+    }
+
+    #[test]
+    fn file_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("monitors.toml");
+        fs::write(
+            &path,
+            r#"
+            [monitors.DEL-a0c4-ABC123]
+            mode = [3840, 2160]
+
+            [monitors.DEL-a0c4]
+            plane_scaling = true
+            connector_props = ["max bpc=8", "garbage"]
+            "#,
+        )
+        .unwrap();
+        let store = MonitorStore::load(path.clone()).unwrap();
+        assert_eq!(
+            store.get(&edid(Some("ABC123"))),
+            Some(&MonitorSettings {
+                mode: Some((3840, 2160)),
+                ..MonitorSettings::default()
+            })
+        );
+        let edited = store.get(&edid(None)).unwrap();
+        assert!(edited.plane_scaling);
+        // entries edited by hand into something invalid are left out
+        let mut options = options();
+        edited.apply(Explicit::default(), &mut options, (1280, 720));
+        assert_eq!(options.connector_props, vec!["max bpc=8".parse().unwrap()]);
+
+        fs::write(&path, "monitors = 3").unwrap();
+        assert!(MonitorStore::load(path).is_err());
+    }
+
+    #[test]
+    fn empty_settings_keep_the_defaults() {
+        let mut options = options();
+        assert!(MonitorSettings::default()
+            .apply(Explicit::default(), &mut options, (1280, 720))
+            .is_empty());
+        assert_eq!(options.mode, (1920, 1080));
+        assert_eq!(options.plane_scaling, None);
+        assert!(options.connector_props.is_empty());
+    }
+
+    #[test]
+    fn remembered_settings_override_the_defaults() {
+        let mut options = options();
+        let applied = settings().apply(Explicit::default(), &mut options, (1280, 720));
+        assert_eq!(applied, ["mode", "plane scaling", "connector properties"]);
+        assert_eq!(options.mode, (2560, 1440));
+        assert_eq!(options.plane_scaling, Some((1280, 720)));
+        assert_eq!(options.connector_props, vec!["max bpc=10".parse().unwrap()]);
+    }
+
+    #[test]
+    fn command_line_overrides_remembered_settings() {
+        let explicit = Explicit {
+            mode: true,
+            plane_scaling: false,
+            connector_props: true,
+        };
+        let mut options = TargetOptions {
+            mode: (3840, 2160),
+            connector_props: vec!["Broadcast RGB=Full".parse().unwrap()],
+            ..options()
+        };
+        let applied = settings().apply(explicit, &mut options, (1280, 720));
+        assert_eq!(applied, ["plane scaling"]);
+        assert_eq!(options.mode, (3840, 2160));
+        assert_eq!(options.connector_props, vec!["Broadcast RGB=Full".parse().unwrap()]);
+
+        let explicit = Explicit {
+            mode: false,
+            plane_scaling: true,
+            connector_props: false,
+        };
+        let mut options = options();
+        let applied = settings().apply(explicit, &mut options, (1280, 720));
+        assert_eq!(applied, ["mode", "connector properties"]);
+        assert_eq!(options.plane_scaling, None);
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::{Context as _, Result};
+use pipewire::{
+    properties,
+    spa::{
+        self,
+        param::video::{VideoFormat, VideoInfoRaw},
+        pod::{serialize::PodSerializer, Object, Pod, Property, PropertyFlags, Value},
+        utils::{Choice, ChoiceEnum, ChoiceFlags, Fraction, Rectangle, SpaTypes},
+    },
+    stream::{Stream, StreamFlags, StreamListener, StreamState},
+    Context, Core, MainLoop,
+};
+
+use crate::raw::Sink;
+
+use std::{
+    cell::RefCell,
+    io::Cursor,
+    os::unix::io::{AsRawFd, RawFd},
+    rc::Rc,
+    time::Duration,
+};
+
+/// Buffers the consumer gets to choose from
+const BUFFERS: i32 = 4;
+
+/// State shared with the callbacks of the stream
+struct Shared {
+    /// Newest frame, not yet handed to the consumer
+    frame: Option<Vec<u8>>,
+    spare: Vec<Vec<u8>>,
+    /// A consumer is connected and takes frames
+    streaming: bool,
+    /// The consumer negotiated RGBA instead of BGRx
+    rgba: bool,
+    width: i32,
+    log: slog::Logger,
+}
+
+/// Offers the frames as a PipeWire video source node, for OBS, browsers and other PipeWire consumers.
+///
+/// Frames are copied into the buffers of the stream, dmabufs are not passed through.
+pub struct PipewireSink {
+    /// Declared first to be dropped before the stream
+    stream: Option<(StreamListener<Rc<RefCell<Shared>>>, Stream)>,
+    shared: Rc<RefCell<Shared>>,
+    core: Core,
+    _context: Context<MainLoop>,
+    mainloop: MainLoop,
+}
+
+fn serialize(value: Value) -> Result<Vec<u8>> {
+    Ok(PodSerializer::serialize(Cursor::new(Vec::new()), &value)
+        .map_err(|err| anyhow::anyhow!("Failed to serialize pod: {:?}", err))?
+        .0
+        .into_inner())
+}
+
+fn property(key: u32, value: Value) -> Property {
+    Property {
+        key,
+        flags: PropertyFlags::empty(),
+        value,
+    }
+}
+
+/// Formats offered to consumers, in the size of the frames
+fn format_param(width: i32, height: i32, fps: f64) -> Result<Vec<u8>> {
+    serialize(Value::Object(Object {
+        type_: SpaTypes::ObjectParamFormat.as_raw(),
+        id: spa::param::ParamType::EnumFormat.as_raw(),
+        properties: vec![
+            property(
+                spa::sys::SPA_FORMAT_mediaType,
+                Value::Id(spa::utils::Id(spa::sys::SPA_MEDIA_TYPE_video)),
+            ),
+            property(
+                spa::sys::SPA_FORMAT_mediaSubtype,
+                Value::Id(spa::utils::Id(spa::sys::SPA_MEDIA_SUBTYPE_raw)),
+            ),
+            property(
+                spa::sys::SPA_FORMAT_VIDEO_format,
+                Value::Choice(spa::pod::ChoiceValue::Id(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Enum {
+                        default: spa::utils::Id(VideoFormat::BGRx.as_raw()),
+                        alternatives: vec![
+                            spa::utils::Id(VideoFormat::BGRx.as_raw()),
+                            spa::utils::Id(VideoFormat::RGBA.as_raw()),
+                        ],
+                    },
+                ))),
+            ),
+            property(
+                spa::sys::SPA_FORMAT_VIDEO_size,
+                Value::Rectangle(Rectangle {
+                    width: width as u32,
+                    height: height as u32,
+                }),
+            ),
+            property(
+                spa::sys::SPA_FORMAT_VIDEO_framerate,
+                Value::Fraction(Fraction {
+                    num: fps.round() as u32,
+                    denom: 1,
+                }),
+            ),
+        ],
+    }))
+}
+
+/// Buffers of frames of `width` x `height`, in memory we can write to
+fn buffers_param(width: i32, height: i32) -> Result<Vec<u8>> {
+    serialize(Value::Object(Object {
+        type_: SpaTypes::ObjectParamBuffers.as_raw(),
+        id: spa::param::ParamType::Buffers.as_raw(),
+        properties: vec![
+            property(spa::sys::SPA_PARAM_BUFFERS_buffers, Value::Int(BUFFERS)),
+            property(spa::sys::SPA_PARAM_BUFFERS_blocks, Value::Int(1)),
+            property(spa::sys::SPA_PARAM_BUFFERS_size, Value::Int(width * height * 4)),
+            property(spa::sys::SPA_PARAM_BUFFERS_stride, Value::Int(width * 4)),
+            property(
+                spa::sys::SPA_PARAM_BUFFERS_dataType,
+                Value::Int(1 << spa::sys::SPA_DATA_MemFd),
+            ),
+        ],
+    }))
+}
+
+impl PipewireSink {
+    pub fn new(log: slog::Logger) -> Result<PipewireSink> {
+        pipewire::init();
+        let mainloop = MainLoop::new().with_context(|| "Failed to create PipeWire loop")?;
+        let context = Context::new(&mainloop).with_context(|| "Failed to create PipeWire context")?;
+        let core = context
+            .connect(None)
+            .with_context(|| "Failed to connect to PipeWire")?;
+        Ok(PipewireSink {
+            stream: None,
+            shared: Rc::new(RefCell::new(Shared {
+                frame: None,
+                spare: Vec::new(),
+                streaming: false,
+                rgba: false,
+                width: 0,
+                log,
+            })),
+            core,
+            _context: context,
+            mainloop,
+        })
+    }
+}
+
+impl Sink for PipewireSink {
+    fn start(&mut self, width: i32, height: i32, fps: f64) -> Result<()> {
+        self.shared.borrow_mut().width = width;
+        let stream = Stream::new(
+            &self.core,
+            "nvscreencopy",
+            properties! {
+                *pipewire::keys::MEDIA_CLASS => "Video/Source",
+                *pipewire::keys::MEDIA_ROLE => "Screen",
+            },
+        )
+        .with_context(|| "Failed to create PipeWire stream")?;
+        let listener = stream
+            .add_local_listener_with_user_data(self.shared.clone())
+            .state_changed(|stream, shared, _old, new| {
+                let mut shared = shared.borrow_mut();
+                match new {
+                    StreamState::Paused => {
+                        slog::info!(shared.log, "PipeWire node {} is ready", stream.node_id())
+                    }
+                    StreamState::Error(err) => slog::warn!(shared.log, "PipeWire stream failed: {}", err),
+                    _ => {}
+                }
+                shared.streaming = new == StreamState::Streaming;
+            })
+            .param_changed(move |stream, shared, id, param| {
+                let param = match param {
+                    Some(param) if id == spa::param::ParamType::Format.as_raw() => param,
+                    _ => return,
+                };
+                let mut info = VideoInfoRaw::default();
+                if info.parse(param).is_err() {
+                    return;
+                }
+                let mut shared = shared.borrow_mut();
+                shared.rgba = info.format() == VideoFormat::RGBA;
+                slog::info!(shared.log, "Consumer negotiated {:?}", info.format());
+                match buffers_param(width, height) {
+                    Ok(buffers) => {
+                        let mut params = [Pod::from_bytes(&buffers).unwrap()];
+                        if let Err(err) = stream.update_params(&mut params) {
+                            slog::warn!(shared.log, "Failed to set PipeWire buffers: {}", err);
+                        }
+                    }
+                    Err(err) => slog::warn!(shared.log, "{:#}", err),
+                }
+            })
+            .process(|stream, shared| {
+                let mut shared = shared.borrow_mut();
+                let frame = match shared.frame.take() {
+                    Some(frame) => frame,
+                    None => return,
+                };
+                if let Some(mut buffer) = stream.dequeue_buffer() {
+                    if let Some(data) = buffer.datas_mut().first_mut() {
+                        let mut len = 0;
+                        if let Some(dst) = data.data() {
+                            len = frame.len().min(dst.len());
+                            dst[..len].copy_from_slice(&frame[..len]);
+                            if shared.rgba {
+                                for pixel in dst[..len].chunks_exact_mut(4) {
+                                    pixel.swap(0, 2);
+                                }
+                            }
+                        }
+                        let chunk = data.chunk_mut();
+                        *chunk.offset_mut() = 0;
+                        *chunk.stride_mut() = shared.width * 4;
+                        *chunk.size_mut() = len as u32;
+                    }
+                }
+                shared.spare.push(frame);
+            })
+            .register()
+            .with_context(|| "Failed to listen on the PipeWire stream")?;
+
+        let format = format_param(width, height, fps)?;
+        let mut params = [Pod::from_bytes(&format).unwrap()];
+        stream
+            .connect(
+                spa::utils::Direction::Output,
+                None,
+                StreamFlags::DRIVER | StreamFlags::ALLOC_BUFFERS | StreamFlags::MAP_BUFFERS,
+                &mut params,
+            )
+            .with_context(|| "Failed to connect the PipeWire stream")?;
+        self.stream = Some((listener, stream));
+        Ok(())
+    }
+
+    /// Reuses the buffers already handed to the consumer
+    fn buffer(&mut self) -> Vec<u8> {
+        self.shared.borrow_mut().spare.pop().unwrap_or_default()
+    }
+
+    /// Replaces a frame the consumer did not pick up yet
+    fn push(&mut self, frame: Vec<u8>) -> Result<bool> {
+        let dropped = {
+            let mut shared = self.shared.borrow_mut();
+            let previous = shared.frame.replace(frame);
+            previous.map(|previous| shared.spare.push(previous)).is_some()
+        };
+        if let Some((_, stream)) = self.stream.as_ref() {
+            stream
+                .trigger_process()
+                .with_context(|| "Failed to hand the frame to PipeWire")?;
+        }
+        Ok(!dropped)
+    }
+
+    /// Captures only while a consumer is connected
+    fn wanted(&self) -> bool {
+        self.shared.borrow().streaming
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.mainloop.loop_().fd().as_raw_fd())
+    }
+
+    fn dispatch(&mut self) -> Result<()> {
+        self.mainloop.loop_().iterate(Duration::from_millis(0));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some((_, stream)) = self.stream.as_ref() {
+            stream
+                .disconnect()
+                .with_context(|| "Failed to disconnect the PipeWire stream")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,674 @@
+use anyhow::{Context as _, Result};
+use dbus::{
+    arg::{OwnedFd, PropMap, RefArg, Variant},
+    blocking::{stdintf::org_freedesktop_dbus::Properties, LocalConnection},
+    message::MatchRule,
+    Path as ObjectPath,
+};
+use nix::unistd::dup;
+use pipewire::{
+    properties,
+    spa::{
+        self,
+        param::video::{VideoFlags, VideoFormat, VideoInfoRaw},
+        pod::{serialize::PodSerializer, ChoiceValue, Object, Pod, Property, PropertyFlags, Value},
+        utils::{Choice, ChoiceEnum, ChoiceFlags, Fraction, Rectangle, SpaTypes},
+    },
+    stream::{Stream, StreamFlags, StreamListener, StreamState},
+    Context, Core, MainLoop,
+};
+use smithay::backend::allocator::{
+    dmabuf::DmabufFlags,
+    Fourcc, Modifier,
+};
+use smithay_client_toolkit::reexports::client::protocol::wl_output;
+
+use crate::{
+    capture::{self, CaptureBackend, CapturedFrame},
+    egl::EglFence,
+    render::{self, Release},
+    stats, WaylandState,
+};
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::Cursor,
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+const PORTAL_BUS: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SCREENCAST: &str = "org.freedesktop.portal.ScreenCast";
+const REQUEST: &str = "org.freedesktop.portal.Request";
+const SESSION: &str = "org.freedesktop.portal.Session";
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the user gets to pick a monitor in the dialog of the portal
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// `types` of SelectSources
+const SOURCE_MONITOR: u32 = 1;
+/// `cursor_mode` of SelectSources, the compositor draws the cursor into the frames
+const CURSOR_EMBEDDED: u32 = 2;
+/// `persist_mode` of SelectSources, the session can be restored until the user revokes it
+const PERSIST_UNTIL_REVOKED: u32 = 2;
+
+/// Buffers of the stream we keep from the compositor at once, while the gpus are still reading them
+const MAX_HELD: usize = 2;
+/// Upper bound for waiting on the gpus to finish reading a frame, before returning it anyway
+const RELEASE_TIMEOUT: u64 = 1_000_000_000;
+
+/// Where the restore token of the last session is kept, so the dialog only shows up once
+fn restore_token_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|dir| dir.join("nvscreencopy").join("portal-restore-token"))
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+/// A ScreenCast session of xdg-desktop-portal, which is closed on drop
+struct PortalSession {
+    connection: LocalConnection,
+    handle: ObjectPath<'static>,
+    /// Counts requests, to give each one a unique token
+    requests: u32,
+}
+
+impl PortalSession {
+    fn create() -> Result<PortalSession> {
+        let connection = LocalConnection::new_session().context("Failed to connect to the session bus")?;
+        let mut session = PortalSession {
+            connection,
+            handle: ObjectPath::from("/"),
+            requests: 0,
+        };
+        let mut options = PropMap::new();
+        options.insert(String::from("session_handle_token"), variant(String::from("nvscreencopy")));
+        let results = session.request("CreateSession", |options| (options,), options)?;
+        let handle = results
+            .get("session_handle")
+            .and_then(|handle| handle.0.as_str())
+            .context("CreateSession returned no session handle")?;
+        session.handle = ObjectPath::new(handle.to_string())
+            .map_err(|err| anyhow::anyhow!("Invalid session handle {}: {}", handle, err))?;
+        Ok(session)
+    }
+
+    fn proxy(&self) -> dbus::blocking::Proxy<'_, &LocalConnection> {
+        self.connection.with_proxy(PORTAL_BUS, PORTAL_PATH, CALL_TIMEOUT)
+    }
+
+    /// Calls `method`, which answers through the Response signal of a request object.
+    ///
+    /// `args` builds the arguments from `options`, which gets the token of the request added.
+    fn request<A: dbus::arg::AppendAll>(
+        &mut self,
+        method: &str,
+        args: impl FnOnce(PropMap) -> A,
+        mut options: PropMap,
+    ) -> Result<PropMap> {
+        self.requests += 1;
+        let token = format!("nvscreencopy{}", self.requests);
+        options.insert(String::from("handle_token"), variant(token.clone()));
+        // the path of the request is known up front, so the response can't be missed
+        let sender = self
+            .connection
+            .unique_name()
+            .to_string()
+            .trim_start_matches(':')
+            .replace('.', "_");
+        let path = ObjectPath::new(format!("{}/request/{}/{}", PORTAL_PATH, sender, token))
+            .map_err(|err| anyhow::anyhow!("Invalid request path: {}", err))?;
+        let response = Rc::new(RefCell::new(None));
+        let slot = response.clone();
+        let rule = MatchRule::new_signal(REQUEST, "Response").with_path(path);
+        let subscription = self
+            .connection
+            .add_match(rule, move |(code, results): (u32, PropMap), _, _| {
+                *slot.borrow_mut() = Some((code, results));
+                false
+            })
+            .context("Failed to listen for the response of the portal")?;
+
+        let result = self
+            .proxy()
+            .method_call::<(ObjectPath<'static>,), _, _, _>(SCREENCAST, method, args(options))
+            .with_context(|| format!("Failed to call {} of the ScreenCast portal", method));
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let result = result.and_then(|_| {
+            while response.borrow().is_none() {
+                if Instant::now() > deadline {
+                    anyhow::bail!("The ScreenCast portal did not answer {}", method);
+                }
+                self.connection.process(Duration::from_millis(100))?;
+            }
+            Ok(())
+        });
+        let _ = self.connection.remove_match(subscription);
+        result?;
+
+        let (code, results) = response.borrow_mut().take().unwrap();
+        match code {
+            0 => Ok(results),
+            1 => anyhow::bail!("Screen casting was cancelled in the dialog of the portal"),
+            _ => anyhow::bail!("The ScreenCast portal failed {}", method),
+        }
+    }
+
+    /// Lets the user pick a monitor, or restores the one of the last session from `restore_token`
+    fn select_monitor(&mut self, restore_token: Option<String>, log: &slog::Logger) -> Result<()> {
+        let mut options = PropMap::new();
+        options.insert(String::from("types"), variant(SOURCE_MONITOR));
+        options.insert(String::from("multiple"), variant(false));
+        // older portals only offer the default, which usually is embedded as well
+        match self.proxy().get::<u32>(SCREENCAST, "AvailableCursorModes") {
+            Ok(modes) if modes & CURSOR_EMBEDDED != 0 => {
+                options.insert(String::from("cursor_mode"), variant(CURSOR_EMBEDDED));
+            }
+            Ok(_) => slog::warn!(log, "The ScreenCast portal can't draw the cursor into the frames"),
+            Err(err) => slog::debug!(log, "Failed to query the cursor modes of the portal: {}", err),
+        }
+        options.insert(String::from("persist_mode"), variant(PERSIST_UNTIL_REVOKED));
+        if let Some(token) = restore_token {
+            options.insert(String::from("restore_token"), variant(token));
+        }
+        let handle = self.handle.clone();
+        self.request("SelectSources", |options| (handle, options), options)?;
+        Ok(())
+    }
+
+    /// Starts the session, returns the PipeWire node of the picked monitor and the token to restore it
+    fn start(&mut self) -> Result<(u32, Option<String>)> {
+        let handle = self.handle.clone();
+        let results = self.request("Start", |options| (handle, "", options), PropMap::new())?;
+        // streams is an array of (node id, properties)
+        let node = results
+            .get("streams")
+            .and_then(|streams| streams.0.as_iter())
+            .and_then(|mut streams| streams.next())
+            .and_then(|stream| stream.as_iter())
+            .and_then(|mut fields| fields.next())
+            .and_then(|node| node.as_u64())
+            .context("The ScreenCast portal started no stream")?;
+        let token = results
+            .get("restore_token")
+            .and_then(|token| token.0.as_str())
+            .map(String::from);
+        Ok((node as u32, token))
+    }
+
+    /// Connection to the PipeWire daemon, which only exposes the nodes of this session
+    fn open_pipewire_remote(&self) -> Result<RawFd> {
+        let (fd,): (OwnedFd,) = self
+            .proxy()
+            .method_call(SCREENCAST, "OpenPipeWireRemote", (self.handle.clone(), PropMap::new()))
+            .context("Failed to open the PipeWire remote of the portal")?;
+        Ok(fd.into_fd())
+    }
+}
+
+impl Drop for PortalSession {
+    fn drop(&mut self) {
+        let proxy = self.connection.with_proxy(PORTAL_BUS, self.handle.clone(), CALL_TIMEOUT);
+        let _: Result<(), _> = proxy.method_call(SESSION, "Close", ());
+    }
+}
+
+/// Format of the frames the stream negotiated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StreamFormat {
+    fourcc: Fourcc,
+    width: i32,
+    height: i32,
+    /// `None` for frames in shared memory
+    modifier: Option<u64>,
+}
+
+/// Drm format of the memory order of a raw video format
+fn video_fourcc(format: VideoFormat) -> Option<Fourcc> {
+    match format {
+        VideoFormat::BGRx => Some(Fourcc::Xrgb8888),
+        VideoFormat::BGRA => Some(Fourcc::Argb8888),
+        VideoFormat::RGBx => Some(Fourcc::Xbgr8888),
+        VideoFormat::RGBA => Some(Fourcc::Abgr8888),
+        _ => None,
+    }
+}
+
+/// State shared with the callbacks of the stream
+struct Shared {
+    format: Option<StreamFormat>,
+    /// Newest buffer, not yet handed to the renderer
+    latest: Option<*mut pipewire::sys::pw_buffer>,
+    /// The stream failed or the compositor ended the session
+    error: Option<String>,
+    log: slog::Logger,
+}
+
+fn serialize(value: Value) -> Result<Vec<u8>> {
+    Ok(PodSerializer::serialize(Cursor::new(Vec::new()), &value)
+        .map_err(|err| anyhow::anyhow!("Failed to serialize pod: {:?}", err))?
+        .0
+        .into_inner())
+}
+
+fn property(key: u32, value: Value) -> Property {
+    Property {
+        key,
+        flags: PropertyFlags::empty(),
+        value,
+    }
+}
+
+/// Formats we take, as dmabufs of linear layout, which every gpu can import, or in shared memory otherwise
+fn format_param(dmabuf: bool) -> Result<Vec<u8>> {
+    let formats = [VideoFormat::BGRx, VideoFormat::BGRA, VideoFormat::RGBx, VideoFormat::RGBA];
+    let mut properties = vec![
+        property(
+            spa::sys::SPA_FORMAT_mediaType,
+            Value::Id(spa::utils::Id(spa::sys::SPA_MEDIA_TYPE_video)),
+        ),
+        property(
+            spa::sys::SPA_FORMAT_mediaSubtype,
+            Value::Id(spa::utils::Id(spa::sys::SPA_MEDIA_SUBTYPE_raw)),
+        ),
+        property(
+            spa::sys::SPA_FORMAT_VIDEO_format,
+            Value::Choice(ChoiceValue::Id(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Enum {
+                    default: spa::utils::Id(formats[0].as_raw()),
+                    alternatives: formats.iter().map(|format| spa::utils::Id(format.as_raw())).collect(),
+                },
+            ))),
+        ),
+        property(
+            spa::sys::SPA_FORMAT_VIDEO_size,
+            Value::Choice(ChoiceValue::Rectangle(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Range {
+                    default: Rectangle {
+                        width: 1920,
+                        height: 1080,
+                    },
+                    min: Rectangle { width: 1, height: 1 },
+                    max: Rectangle {
+                        width: 16384,
+                        height: 16384,
+                    },
+                },
+            ))),
+        ),
+        property(
+            spa::sys::SPA_FORMAT_VIDEO_framerate,
+            Value::Choice(ChoiceValue::Fraction(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Range {
+                    default: Fraction { num: 60, denom: 1 },
+                    min: Fraction { num: 0, denom: 1 },
+                    max: Fraction { num: 1000, denom: 1 },
+                },
+            ))),
+        ),
+    ];
+    if dmabuf {
+        properties.push(Property {
+            key: spa::sys::SPA_FORMAT_VIDEO_modifier,
+            flags: PropertyFlags::MANDATORY,
+            value: Value::Long(u64::from(Modifier::Linear) as i64),
+        });
+    }
+    serialize(Value::Object(Object {
+        type_: SpaTypes::ObjectParamFormat.as_raw(),
+        id: spa::param::ParamType::EnumFormat.as_raw(),
+        properties,
+    }))
+}
+
+/// Buffers of the memory type fitting the negotiated format
+fn buffers_param(dmabuf: bool) -> Result<Vec<u8>> {
+    let data_type = if dmabuf {
+        1 << spa::sys::SPA_DATA_DmaBuf
+    } else {
+        (1 << spa::sys::SPA_DATA_MemFd) | (1 << spa::sys::SPA_DATA_MemPtr)
+    };
+    serialize(Value::Object(Object {
+        type_: SpaTypes::ObjectParamBuffers.as_raw(),
+        id: spa::param::ParamType::Buffers.as_raw(),
+        properties: vec![property(spa::sys::SPA_PARAM_BUFFERS_dataType, Value::Int(data_type))],
+    }))
+}
+
+/// Captures through the ScreenCast portal of xdg-desktop-portal, e.g. on GNOME, which lacks the wlr protocols.
+///
+/// The compositor pushes frames on a PipeWire stream on its own, each capture hands the newest one
+/// to the renderer. Only a single monitor is captured, the one picked in the dialog of the portal.
+pub struct PortalBackend {
+    /// Declared first to be dropped before the stream
+    stream: (StreamListener<Rc<RefCell<Shared>>>, Stream),
+    shared: Rc<RefCell<Shared>>,
+    _core: Core,
+    _context: Context<MainLoop>,
+    mainloop: MainLoop,
+    _session: PortalSession,
+    /// Buffers the gpus are still reading, oldest first
+    held: VecDeque<(*mut pipewire::sys::pw_buffer, EglFence)>,
+    /// A capture is pending, the next buffer is rendered right away
+    wanted: bool,
+}
+
+impl PortalBackend {
+    /// Starts a session, which shows the dialog of the portal unless the last session can be restored
+    pub fn new(log: slog::Logger) -> Result<PortalBackend> {
+        let token_path = restore_token_path();
+        let restore_token = token_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty());
+        let mut session = PortalSession::create()?;
+        session.select_monitor(restore_token, &log)?;
+        let (node, token) = session.start()?;
+        slog::info!(log, "ScreenCast portal started PipeWire node {}", node);
+        // tokens are only valid once, the next start needs the new one
+        if let (Some(path), Some(token)) = (token_path, token) {
+            let saved = path
+                .parent()
+                .map(std::fs::create_dir_all)
+                .unwrap_or(Ok(()))
+                .and_then(|_| std::fs::write(&path, token));
+            if let Err(err) = saved {
+                slog::warn!(log, "Failed to save the restore token to {}: {}", path.display(), err);
+            }
+        }
+        let fd = session.open_pipewire_remote()?;
+
+        pipewire::init();
+        let mainloop = MainLoop::new().context("Failed to create PipeWire loop")?;
+        let context = Context::new(&mainloop).context("Failed to create PipeWire context")?;
+        let core = context
+            .connect_fd(fd, None)
+            .context("Failed to connect to the PipeWire remote of the portal")?;
+        let stream = Stream::new(
+            &core,
+            "nvscreencopy",
+            properties! {
+                *pipewire::keys::MEDIA_TYPE => "Video",
+                *pipewire::keys::MEDIA_CATEGORY => "Capture",
+                *pipewire::keys::MEDIA_ROLE => "Screen",
+            },
+        )
+        .context("Failed to create PipeWire stream")?;
+        let shared = Rc::new(RefCell::new(Shared {
+            format: None,
+            latest: None,
+            error: None,
+            log,
+        }));
+        let listener = stream
+            .add_local_listener_with_user_data(shared.clone())
+            .state_changed(|_stream, shared, _old, new| {
+                let mut shared = shared.borrow_mut();
+                match new {
+                    StreamState::Error(err) => shared.error = Some(err),
+                    StreamState::Unconnected => shared.error = Some(String::from("The compositor ended the stream")),
+                    _ => {}
+                }
+            })
+            .param_changed(|stream, shared, id, param| {
+                let param = match param {
+                    Some(param) if id == spa::param::ParamType::Format.as_raw() => param,
+                    _ => return,
+                };
+                let mut info = VideoInfoRaw::default();
+                if info.parse(param).is_err() {
+                    return;
+                }
+                let mut shared = shared.borrow_mut();
+                let fourcc = match video_fourcc(info.format()) {
+                    Some(fourcc) => fourcc,
+                    None => {
+                        shared.error = Some(format!("Compositor negotiated unsupported format {:?}", info.format()));
+                        return;
+                    }
+                };
+                let dmabuf = info.flags().contains(VideoFlags::MODIFIER);
+                let format = StreamFormat {
+                    fourcc,
+                    width: info.size().width as i32,
+                    height: info.size().height as i32,
+                    modifier: if dmabuf { Some(info.modifier()) } else { None },
+                };
+                slog::info!(shared.log, "Portal stream negotiated {:?}", format);
+                shared.format = Some(format);
+                match buffers_param(dmabuf) {
+                    Ok(buffers) => {
+                        let mut params = [Pod::from_bytes(&buffers).unwrap()];
+                        if let Err(err) = stream.update_params(&mut params) {
+                            slog::warn!(shared.log, "Failed to set PipeWire buffers: {}", err);
+                        }
+                    }
+                    Err(err) => slog::warn!(shared.log, "{:#}", err),
+                }
+            })
+            .process(|stream, shared| {
+                // only the newest buffer is of interest, older ones go right back to the compositor
+                let mut shared = shared.borrow_mut();
+                loop {
+                    let buffer = unsafe { stream.dequeue_raw_buffer() };
+                    if buffer.is_null() {
+                        break;
+                    }
+                    if let Some(older) = shared.latest.replace(buffer) {
+                        unsafe { stream.queue_raw_buffer(older) };
+                    }
+                }
+            })
+            .register()
+            .context("Failed to listen on the PipeWire stream")?;
+
+        let dmabuf_format = format_param(true)?;
+        let shm_format = format_param(false)?;
+        let mut params = [
+            Pod::from_bytes(&dmabuf_format).unwrap(),
+            Pod::from_bytes(&shm_format).unwrap(),
+        ];
+        stream
+            .connect(
+                spa::utils::Direction::Input,
+                Some(node),
+                StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+                &mut params,
+            )
+            .context("Failed to connect the PipeWire stream")?;
+
+        Ok(PortalBackend {
+            stream: (listener, stream),
+            shared,
+            _core: core,
+            _context: context,
+            mainloop,
+            _session: session,
+            held: VecDeque::new(),
+            wanted: false,
+        })
+    }
+
+    fn queue(&self, buffer: *mut pipewire::sys::pw_buffer) {
+        unsafe { self.stream.1.queue_raw_buffer(buffer) };
+    }
+
+    /// Returns the buffers the gpus are done with, waiting for the oldest if too many are held
+    fn release_buffers(&mut self) {
+        while let Some((_, fence)) = self.held.front() {
+            if !fence.signaled() {
+                if self.held.len() <= MAX_HELD {
+                    break;
+                }
+                fence.wait(RELEASE_TIMEOUT);
+            }
+            let (buffer, _) = self.held.pop_front().unwrap();
+            self.queue(buffer);
+        }
+    }
+
+    /// Renders the newest buffer, if a capture is pending
+    fn deliver(&mut self, state: &mut WaylandState) {
+        self.release_buffers();
+        if !self.wanted {
+            return;
+        }
+        let (buffer, format) = {
+            let mut shared = self.shared.borrow_mut();
+            match (shared.latest.take(), shared.format) {
+                (Some(buffer), Some(format)) => (buffer, format),
+                (Some(buffer), None) => {
+                    drop(shared);
+                    self.queue(buffer);
+                    return;
+                }
+                (None, _) => return,
+            }
+        };
+        // buffers without content only carry cursor or damage updates
+        let empty = unsafe {
+            let spa_buffer = &*(*buffer).buffer;
+            spa_buffer.n_datas == 0 || {
+                let chunk = &*(*spa_buffer.datas).chunk;
+                chunk.size == 0 || chunk.flags & spa::sys::SPA_CHUNK_FLAG_CORRUPTED as i32 != 0
+            }
+        };
+        if empty {
+            self.queue(buffer);
+            return;
+        }
+        self.wanted = false;
+        state.retry.succeeded();
+        state.stats.frame_captured();
+        // the stream only delivers frames with new content
+        state.stats.source_frame();
+        match unsafe { render_buffer(state, buffer, format) } {
+            Ok(Some(fence)) => self.held.push_back((buffer, fence)),
+            Ok(None) => self.queue(buffer),
+            Err(err) => {
+                self.queue(buffer);
+                capture::frame_failed(state, err);
+            }
+        }
+    }
+}
+
+/// Hands the frame in `buffer` to the renderer, returns the fence to wait for before returning it to the compositor
+unsafe fn render_buffer(
+    state: &mut WaylandState,
+    buffer: *mut pipewire::sys::pw_buffer,
+    format: StreamFormat,
+) -> Result<Option<EglFence>> {
+    let spa_buffer = &*(*buffer).buffer;
+    let datas = std::slice::from_raw_parts(spa_buffer.datas, spa_buffer.n_datas as usize);
+    let captured = stats::monotonic_now();
+    if datas[0].type_ == spa::sys::SPA_DATA_DmaBuf {
+        let mut frame = CapturedFrame::new(
+            (format.width, format.height),
+            format.fourcc,
+            Modifier::from(format.modifier.unwrap_or_else(|| Modifier::Invalid.into())),
+            DmabufFlags::empty(),
+            false,
+            datas.len() as u32,
+        );
+        for (index, data) in datas.iter().enumerate() {
+            let chunk = &*data.chunk;
+            // the buffer stays with the stream, the frame closes its own copies
+            let fd = dup(data.fd as RawFd).context("Failed to duplicate dmabuf fd")?;
+            frame.add_plane(fd, data.maxsize, chunk.offset, chunk.stride as u32, index as u32)?;
+        }
+        frame.ready(captured)?;
+        match render::render_dmabuf(state, 0, frame) {
+            Ok(Release::Fence(fence)) => {
+                state.render_failures = 0;
+                Ok(Some(fence))
+            }
+            Ok(_) => {
+                state.render_failures = 0;
+                Ok(None)
+            }
+            Err(err) => {
+                render::render_failed(state, err);
+                Ok(None)
+            }
+        }
+    } else {
+        let data = &datas[0];
+        if data.data.is_null() {
+            anyhow::bail!("Portal frame in shared memory is not mapped");
+        }
+        let chunk = &*data.chunk;
+        let image = std::slice::from_raw_parts(
+            (data.data as *const u8).add(chunk.offset as usize),
+            chunk.size as usize,
+        );
+        let rendered = render::render_bitmap(
+            state,
+            0,
+            image,
+            format.fourcc,
+            format.width,
+            format.height,
+            chunk.stride,
+            false,
+            None,
+            captured,
+        );
+        match rendered {
+            Ok(()) => state.render_failures = 0,
+            Err(err) => render::render_failed(state, err),
+        }
+        Ok(None)
+    }
+}
+
+impl CaptureBackend for PortalBackend {
+    fn name(&self) -> &'static str {
+        "portal"
+    }
+
+    fn needs_render_gpu(&self) -> bool {
+        true
+    }
+
+    /// The stream shows the monitor picked in the dialog, `output` only determined the mode
+    fn capture(&mut self, _source: usize, _output: &wl_output::WlOutput, state: &mut WaylandState) {
+        self.wanted = true;
+        self.deliver(state);
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.mainloop.loop_().fd().as_raw_fd())
+    }
+
+    fn dispatch(&mut self, state: &mut WaylandState) -> Result<()> {
+        self.mainloop.loop_().iterate(Duration::from_millis(0));
+        if let Some(err) = self.shared.borrow_mut().error.take() {
+            anyhow::bail!("Portal stream failed: {}", err);
+        }
+        self.deliver(state);
+        Ok(())
+    }
+}
+
+impl Drop for PortalBackend {
+    fn drop(&mut self) {
+        for (buffer, fence) in std::mem::take(&mut self.held) {
+            fence.wait(RELEASE_TIMEOUT);
+            self.queue(buffer);
+        }
+        let _ = self.stream.1.disconnect();
+    }
+}
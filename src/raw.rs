@@ -0,0 +1,652 @@
+use anyhow::{Context, Result};
+use calloop::{
+    generic::Generic,
+    ping::PingSource,
+    signals::{Signal, Signals},
+    timer::Timer,
+    EventLoop, Interest, LoopSignal, PostAction,
+};
+use smithay::utils::{Logical, Physical, Rectangle};
+use smithay_client_toolkit::{
+    self as sctk,
+    reexports::{
+        client::{
+            protocol::{wl_output, wl_shm},
+            Attached, Main,
+        },
+        protocols::wlr::unstable::screencopy::v1::client::{
+            zwlr_screencopy_frame_v1::{self as screencopy_frame, Event as ScreencopyEvent},
+            zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1 as ScreencopyManager,
+        },
+    },
+};
+
+use crate::{
+    capture::CaptureRate,
+    convert,
+    events::{self, Event, FrameStats},
+    gpu::ColorDepth,
+    render::{self, ChannelOrder, Layout},
+    screencopy::{self, BufferInfo, ShmBuffer},
+    source::SourceSpec,
+    stats,
+};
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Write},
+    os::unix::io::RawFd,
+    path::PathBuf,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Frames waiting for the writer, further ones are dropped instead of stalling the event loop
+const QUEUE_LENGTH: usize = 2;
+
+/// Where `--output raw:PATH` writes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawDestination {
+    Stdout,
+    File(PathBuf),
+}
+
+/// What precedes the frames of the raw output, for tools to pick up their size and rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawHeader {
+    None,
+    /// A single line of JSON with the format, size and frame rate
+    Json,
+}
+
+impl RawHeader {
+    pub const VARIANTS: &'static [&'static str] = &["none", "json"];
+}
+
+impl FromStr for RawHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<RawHeader> {
+        match name {
+            "none" => Ok(RawHeader::None),
+            "json" => Ok(RawHeader::Json),
+            x => anyhow::bail!("Unknown raw header: {}", x),
+        }
+    }
+}
+
+/// The subset of `Options` the outputs without target gpu use
+pub struct RawOptions {
+    pub source: SourceSpec,
+    pub auto_source: bool,
+    pub crop: Option<Rectangle<i32, Logical>>,
+    pub capture_rate: CaptureRate,
+    pub exit_on_signals: bool,
+}
+
+/// Consumer of the frames of the outputs without target gpu, which are tightly packed 8 bit BGRA
+pub trait Sink {
+    /// Called once before the first frame, with the size all frames have
+    fn start(&mut self, width: i32, height: i32, fps: f64) -> Result<()>;
+    /// A buffer for the next frame, possibly reusing the one of an earlier frame
+    fn buffer(&mut self) -> Vec<u8>;
+    /// Hands `frame` on, returns `false` if a frame was dropped because the consumer is behind
+    fn push(&mut self, frame: Vec<u8>) -> Result<bool>;
+    /// Whether frames are wanted right now, capturing pauses otherwise
+    fn wanted(&self) -> bool {
+        true
+    }
+    /// Fd signalling events of the sink, which are then handled by `dispatch`
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+    fn dispatch(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Waits until everything handed on was consumed
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Writes frames on its own thread, so a blocked pipe does not stall the event loop
+pub struct Writer {
+    frames: Option<SyncSender<Vec<u8>>>,
+    /// Buffers of written frames, to be reused
+    spare: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    header: RawHeader,
+}
+
+impl Writer {
+    pub fn new(destination: &RawDestination, header: RawHeader) -> Result<Writer> {
+        let mut out: Box<dyn Write + Send> = match destination {
+            RawDestination::Stdout => Box::new(io::stdout()),
+            RawDestination::File(path) => Box::new(
+                File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+            ),
+        };
+        let (frames, queue) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LENGTH);
+        let (recycle, spare) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("raw-writer"))
+            .spawn(move || {
+                for frame in queue {
+                    out.write_all(&frame)?;
+                    // the event loop is gone, if nobody takes it back
+                    let _ = recycle.send(frame);
+                }
+                out.flush()
+            })
+            .with_context(|| "Failed to spawn raw writer thread")?;
+        Ok(Writer {
+            frames: Some(frames),
+            spare,
+            thread: Some(thread),
+            header,
+        })
+    }
+
+    /// Queues `data` even if the writer is behind, for the header
+    fn send(&mut self, data: Vec<u8>) -> Result<()> {
+        match self.frames.as_ref().map(|frames| frames.send(data)) {
+            Some(Ok(())) => Ok(()),
+            _ => Err(self.finish().err().unwrap_or_else(|| anyhow::anyhow!("Raw writer stopped"))),
+        }
+    }
+}
+
+impl Sink for Writer {
+    fn start(&mut self, width: i32, height: i32, fps: f64) -> Result<()> {
+        if self.header == RawHeader::Json {
+            let mut header = serde_json::to_vec(&serde_json::json!({
+                "format": "bgra",
+                "width": width,
+                "height": height,
+                "fps": fps,
+            }))?;
+            header.push(b'\n');
+            self.send(header)?;
+        }
+        Ok(())
+    }
+
+    /// Reuses the buffers already written
+    fn buffer(&mut self) -> Vec<u8> {
+        self.spare.try_recv().unwrap_or_default()
+    }
+
+    fn push(&mut self, frame: Vec<u8>) -> Result<bool> {
+        match self.frames.as_ref().map(|frames| frames.try_send(frame)) {
+            Some(Ok(())) => Ok(true),
+            Some(Err(TrySendError::Full(_))) => Ok(false),
+            _ => Err(self.finish().err().unwrap_or_else(|| anyhow::anyhow!("Raw writer stopped"))),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.frames = None;
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("Raw writer thread panicked"))?
+                .with_context(|| "Failed to write raw frames"),
+            None => Ok(()),
+        }
+    }
+}
+
+struct RawState {
+    manager: Attached<ScreencopyManager>,
+    shm: Attached<wl_shm::WlShm>,
+    output: wl_output::WlOutput,
+    buffer: RefCell<Option<ShmBuffer>>,
+    /// Mirrored region in physical pixels of the source
+    region: Rectangle<i32, Physical>,
+    sink: Box<dyn Sink>,
+    /// The sink wanted frames when last asked
+    wanted: bool,
+    /// A frame is being captured, the next one is not requested before it is done
+    in_flight: bool,
+    stats: stats::Stats,
+    events: events::Events,
+    signal: LoopSignal,
+    error: Option<anyhow::Error>,
+    log: slog::Logger,
+}
+
+impl RawState {
+    /// Stops the event loop, `run` returns `err` unless the reader merely went away
+    fn fail(&mut self, err: anyhow::Error) {
+        if reader_closed(&err) {
+            slog::info!(self.log, "Reader closed the raw output, exiting");
+        } else if self.error.is_none() {
+            slog::error!(self.log, "{:#}", err);
+            self.error = Some(err);
+        }
+        self.signal.stop();
+    }
+
+    fn capture(&mut self) {
+        if self.sink.wanted() != self.wanted {
+            self.wanted = !self.wanted;
+            slog::info!(
+                self.log,
+                "{}",
+                if self.wanted { "Consumer connected, capturing" } else { "No consumer, pausing capture" }
+            );
+        }
+        if self.in_flight || !self.wanted {
+            return;
+        }
+        self.in_flight = true;
+        let frame = self.manager.capture_output(0, &self.output);
+        let mut info = None;
+        let mut y_invert = false;
+        frame.quick_assign(move |frame, event, mut data| {
+            let state: &mut RawState = data.get().unwrap();
+            if let Err(err) = frame_event(&frame, event, state, &mut info, &mut y_invert) {
+                frame.destroy();
+                state.in_flight = false;
+                slog::debug!(state.log, "Dropped frame: {:#}", err);
+                state.stats.error(format!("{:#}", err));
+            }
+        });
+    }
+
+    /// Strips the stride of the frame in `buffer` and hands it to the sink
+    fn write_frame(&mut self, y_invert: bool) -> Result<()> {
+        let buffer = self.buffer.borrow();
+        let buffer = buffer.as_ref().context("Ready event before copy")?;
+        let format = screencopy::shm_fourcc(buffer.info.format)
+            .with_context(|| format!("Unknown shm format {:?}", buffer.info.format))?;
+        let layout = render::memory_layout(format)
+            .with_context(|| format!("Unsupported format for raw output: {:?}", format))?;
+        let mut pixels = self.sink.buffer();
+        copy_region(buffer.data(), &buffer.info, self.region, y_invert, &mut pixels)?;
+        to_bgra(&mut pixels, layout);
+        drop(buffer);
+
+        if !self.sink.push(pixels)? {
+            self.stats.frame_dropped();
+        }
+        if self.stats.report(&self.log) {
+            let snapshot = FrameStats::of(&self.stats);
+            self.events.emit(Event::Stats(snapshot));
+        }
+        Ok(())
+    }
+}
+
+/// Replaces `pixels` with the rows of `region` of the shm frame in `data`, without the stride
+fn copy_region(
+    data: &[u8],
+    info: &BufferInfo,
+    region: Rectangle<i32, Physical>,
+    y_invert: bool,
+    pixels: &mut Vec<u8>,
+) -> Result<()> {
+    let (width, height) = (info.width as usize, info.height as usize);
+    let Rectangle { loc, size, .. } = region;
+    let (x, y, w, h) = (loc.x as usize, loc.y as usize, size.w as usize, size.h as usize);
+    if x + w > width || y + h > height {
+        anyhow::bail!(
+            "Source changed its size to {}x{}, but the output needs frames of {}x{}",
+            width,
+            height,
+            x + w,
+            y + h
+        );
+    }
+
+    let stride = info.stride as usize;
+    let row_len = w * 4;
+    pixels.clear();
+    for row in y..y + h {
+        // flipped frames are stored bottom to top
+        let row = if y_invert { height - 1 - row } else { row };
+        let start = row * stride + x * 4;
+        let src = data.get(start..start + row_len).context("Shm buffer is smaller than announced")?;
+        pixels.extend_from_slice(src);
+    }
+    Ok(())
+}
+
+/// Whether writing failed because the reading end of the pipe was closed, e.g. ffmpeg was stopped
+fn reader_closed(err: &anyhow::Error) -> bool {
+    err.root_cause()
+        .downcast_ref::<io::Error>()
+        .map(|err| err.kind() == io::ErrorKind::BrokenPipe)
+        .unwrap_or(false)
+}
+
+/// Converts pixels in `layout` to 8 bit BGRA in place
+fn to_bgra(pixels: &mut [u8], layout: Layout) {
+    if layout.depth == ColorDepth::Eight && layout.order == ChannelOrder::Bgra {
+        if !layout.alpha {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel[3] = 0xff;
+            }
+        }
+        return;
+    }
+    convert::swizzle(pixels, layout, ColorDepth::Eight);
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+}
+
+/// Handles an event of `frame`, on errors the caller drops the frame
+fn frame_event(
+    frame: &Main<screencopy_frame::ZwlrScreencopyFrameV1>,
+    event: ScreencopyEvent,
+    state: &mut RawState,
+    info: &mut Option<BufferInfo>,
+    y_invert: &mut bool,
+) -> Result<()> {
+    match event {
+        ScreencopyEvent::Buffer {
+            format,
+            width,
+            height,
+            stride,
+        } => {
+            *info = Some(BufferInfo {
+                format,
+                width,
+                height,
+                stride,
+            });
+            // version 3 announces all buffer types first and signals the end with `buffer_done`
+            if frame.as_ref().version() < 3 {
+                screencopy::start_copy(frame, &state.shm, &state.buffer, info.unwrap(), false)
+                    .context("Failed to allocate shm buffer")?;
+            }
+        }
+        ScreencopyEvent::BufferDone => {
+            let info = info.context("BufferDone event without shm Buffer event")?;
+            screencopy::start_copy(frame, &state.shm, &state.buffer, info, false)
+                .context("Failed to allocate shm buffer")?;
+        }
+        ScreencopyEvent::Flags { flags } => {
+            *y_invert = flags.contains(screencopy_frame::Flags::YInvert);
+        }
+        ScreencopyEvent::Ready { .. } => {
+            frame.destroy();
+            state.in_flight = false;
+            state.stats.frame_captured();
+            if let Err(err) = state.write_frame(*y_invert) {
+                state.fail(err);
+            }
+        }
+        ScreencopyEvent::Failed => {
+            slog::debug!(state.log, "Frame copy failed");
+            state.stats.error("Frame copy failed");
+            frame.destroy();
+            state.in_flight = false;
+        }
+        ScreencopyEvent::Damage { .. } | ScreencopyEvent::LinuxDmabuf { .. } => {}
+        _ => anyhow::bail!("Unknown screencopy event"),
+    }
+    Ok(())
+}
+
+/// Captures the source through screencopy and hands its frames to `sink` until stopped, without any gpu
+pub fn run(
+    options: RawOptions,
+    mut sink: Box<dyn Sink>,
+    log: slog::Logger,
+    events: events::Events,
+    stop_source: PingSource,
+) -> Result<()> {
+    let RawOptions {
+        mut source,
+        auto_source,
+        crop,
+        capture_rate,
+        exit_on_signals,
+    } = options;
+
+    let mut event_loop: EventLoop<'static, RawState> =
+        EventLoop::try_new().with_context(|| "Failed to create event loop")?;
+    let (display, mut event_queue, environment) = crate::connect_environment()?;
+    let manager = environment
+        .get_global::<ScreencopyManager>()
+        .ok_or_else(|| crate::compositor::missing_global("zwlr_screencopy_manager_v1", "for outputs without target gpu"))?;
+    let shm = environment
+        .get_global::<wl_shm::WlShm>()
+        .ok_or_else(|| crate::compositor::missing_global("wl_shm", "for screencopy frames"))?;
+    let (output, mode) = match crate::find_output(&environment, &source)? {
+        Some(found) => found,
+        None => crate::missing_source(&environment, &mut source, auto_source, &log)?,
+    };
+    let scale = sctk::output::with_output_info(&output, |info| info.scale_factor).unwrap_or(1);
+    let region = match crop.map(|crop| crop.to_physical(scale)) {
+        Some(crop) if crop.loc.x + crop.size.w > mode.dimensions.0 || crop.loc.y + crop.size.h > mode.dimensions.1 => {
+            anyhow::bail!(
+                "Crop region {},{},{}x{} exceeds the source mode {}x{}",
+                crop.loc.x,
+                crop.loc.y,
+                crop.size.w,
+                crop.size.h,
+                mode.dimensions.0,
+                mode.dimensions.1
+            )
+        }
+        Some(crop) => crop,
+        None => Rectangle::from_loc_and_size((0, 0), mode.dimensions),
+    };
+    // without vblanks to follow, capture at the refresh rate of the source
+    let interval = capture_rate.interval().unwrap_or_else(|| {
+        if mode.refresh_rate > 0 {
+            Duration::from_secs_f64(1000.0 / mode.refresh_rate as f64)
+        } else {
+            Duration::from_millis(16)
+        }
+    });
+
+    let fps = 1.0 / interval.as_secs_f64();
+    sink.start(region.size.w, region.size.h, fps)?;
+    slog::info!(log, "Capturing {}x{} frames at {:.2} fps", region.size.w, region.size.h, fps);
+
+    let handle = event_loop.handle();
+    if let Some(fd) = sink.fd() {
+        handle
+            .insert_source(
+                Generic::from_fd(fd, Interest::READ, calloop::Mode::Level),
+                |_, _, state: &mut RawState| {
+                    if let Err(err) = state.sink.dispatch() {
+                        state.fail(err);
+                    }
+                    Ok(PostAction::Continue)
+                },
+            )
+            .map_err(|err| err.error)
+            .context("Failed to add sink to event loop")?;
+    }
+    handle
+        .insert_source(
+            Generic::from_fd(display.get_connection_fd(), Interest::READ, calloop::Mode::Level),
+            move |_, _, state: &mut RawState| match event_queue.dispatch(state, |_, _, _| {}) {
+                Ok(_) => Ok(PostAction::Continue),
+                Err(err) => {
+                    state.fail(anyhow::anyhow!("I/O error on the Wayland display: {}", err));
+                    Ok(PostAction::Disable)
+                }
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to add wayland connection to event loop")?;
+    let capture_timer = Timer::new().context("Failed to create timer")?;
+    capture_timer.handle().add_timeout(Duration::from_millis(0), ());
+    handle
+        .insert_source(capture_timer, move |_, timer, state: &mut RawState| {
+            timer.add_timeout(interval, ());
+            state.capture();
+        })
+        .map_err(|err| err.error)
+        .context("Failed to add timer to event loop")?;
+    let stop_signal = event_loop.get_signal();
+    handle
+        .insert_source(stop_source, move |_, _, state: &mut RawState| {
+            slog::info!(state.log, "Stop requested, exiting");
+            stop_signal.stop();
+        })
+        .map_err(|err| err.error)
+        .context("Failed to add stop handle to event loop")?;
+    if exit_on_signals {
+        let exit_signal = event_loop.get_signal();
+        handle
+            .insert_source(
+                Signals::new(&[Signal::SIGINT, Signal::SIGTERM]).with_context(|| "Failed to block signals")?,
+                move |event, _, state: &mut RawState| {
+                    slog::info!(state.log, "Received {:?}, exiting", event.signal());
+                    exit_signal.stop();
+                },
+            )
+            .map_err(|err| err.error)
+            .context("Failed to add signals to event loop")?;
+    }
+
+    let mut state = RawState {
+        manager,
+        shm,
+        output,
+        buffer: RefCell::new(None),
+        region,
+        sink,
+        wanted: true,
+        in_flight: false,
+        stats: stats::Stats::new(),
+        events,
+        signal: event_loop.get_signal(),
+        error: None,
+        log: log.clone(),
+    };
+    event_loop.run(None, &mut state, |state| {
+        // requests are only sent once flushed
+        if let Err(err) = display.flush() {
+            state.fail(anyhow::anyhow!("Wayland display died: {}", err));
+        }
+        if let Some(error) = state.stats.take_error() {
+            state.events.emit(Event::Error(error));
+        }
+    })?;
+
+    let written = state.sink.finish();
+    match (state.error.take(), written) {
+        (Some(err), _) => Err(err),
+        (None, Err(err)) if !reader_closed(&err) => Err(err),
+        (None, _) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smithay::backend::allocator::Fourcc;
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 4;
+    /// Every row is followed by one pixel of padding
+    const STRIDE: usize = (WIDTH + 1) * 4;
+    const PADDING: u8 = 0xee;
+
+    fn info() -> BufferInfo {
+        BufferInfo {
+            format: wl_shm::Format::Argb8888,
+            width: WIDTH as u32,
+            height: HEIGHT as u32,
+            stride: STRIDE as u32,
+        }
+    }
+
+    /// Pixel at `x`, `y` of the screen, which knows where it came from
+    fn pixel(x: usize, y: usize) -> [u8; 4] {
+        [x as u8, y as u8, 0x80, 0xff]
+    }
+
+    /// Shm frame of the screen, stored bottom to top if `y_invert`
+    fn frame(y_invert: bool) -> Vec<u8> {
+        let mut data = vec![PADDING; STRIDE * HEIGHT];
+        for (x, y) in (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y))) {
+            let row = if y_invert { HEIGHT - 1 - y } else { y };
+            let at = row * STRIDE + x * 4;
+            data[at..at + 4].copy_from_slice(&pixel(x, y));
+        }
+        data
+    }
+
+    /// The pixels of the screen in `region`, top to bottom
+    fn expected(region: Rectangle<i32, Physical>) -> Vec<u8> {
+        let Rectangle { loc, size, .. } = region;
+        (loc.y..loc.y + size.h)
+            .flat_map(|y| (loc.x..loc.x + size.w).map(move |x| (x as usize, y as usize)))
+            .flat_map(|(x, y)| pixel(x, y))
+            .collect()
+    }
+
+    fn copy(data: &[u8], region: Rectangle<i32, Physical>, y_invert: bool) -> Result<Vec<u8>> {
+        // leftovers of the previous frame are replaced
+        let mut pixels = vec![0; 8];
+        copy_region(data, &info(), region, y_invert, &mut pixels)?;
+        Ok(pixels)
+    }
+
+    #[test]
+    fn whole_frames_lose_the_stride() {
+        let whole = Rectangle::from_loc_and_size((0, 0), (WIDTH as i32, HEIGHT as i32));
+        for y_invert in [false, true] {
+            let pixels = copy(&frame(y_invert), whole, y_invert).unwrap();
+            assert_eq!(pixels.len(), WIDTH * HEIGHT * 4);
+            assert!(!pixels.contains(&PADDING));
+            assert_eq!(pixels, expected(whole), "y_invert: {}", y_invert);
+        }
+    }
+
+    #[test]
+    fn crops() {
+        for (x, y, w, h) in [(1, 2, 2, 1), (0, 0, 4, 1), (3, 1, 1, 3), (1, 1, 2, 2), (0, 3, 4, 1)] {
+            let region = Rectangle::from_loc_and_size((x, y), (w, h));
+            for y_invert in [false, true] {
+                let pixels = copy(&frame(y_invert), region, y_invert).unwrap();
+                assert_eq!(pixels, expected(region), "{:?}, y_invert: {}", region, y_invert);
+            }
+        }
+    }
+
+    #[test]
+    fn flipped_crops_are_not_mirrored_within_the_crop() {
+        // only symmetric crops come out right when just the rows of the crop are reversed
+        let top = Rectangle::from_loc_and_size((0, 0), (WIDTH as i32, 1));
+        let pixels = copy(&frame(true), top, true).unwrap();
+        assert_eq!(&pixels[..4], pixel(0, 0));
+        assert_eq!(&pixels[pixels.len() - 4..], pixel(WIDTH - 1, 0));
+    }
+
+    #[test]
+    fn mismatching_frames() {
+        let data = frame(false);
+        let larger = Rectangle::from_loc_and_size((1, 0), (WIDTH as i32, HEIGHT as i32));
+        let err = copy(&data, larger, false).unwrap_err();
+        assert!(format!("{}", err).contains("needs frames of 5x4"), "{}", err);
+
+        let whole = Rectangle::from_loc_and_size((0, 0), (WIDTH as i32, HEIGHT as i32));
+        let err = copy(&data[..STRIDE * 3], whole, false).unwrap_err();
+        assert_eq!(format!("{}", err), "Shm buffer is smaller than announced");
+    }
+
+    #[test]
+    fn bgra() {
+        let layout = |format| render::memory_layout(format).unwrap();
+        let mut pixels = [1, 2, 3, 4];
+        to_bgra(&mut pixels, layout(Fourcc::Argb8888));
+        assert_eq!(pixels, [1, 2, 3, 4]);
+        to_bgra(&mut pixels, layout(Fourcc::Xrgb8888));
+        assert_eq!(pixels, [1, 2, 3, 0xff]);
+        let mut pixels = [1, 2, 3, 4];
+        to_bgra(&mut pixels, layout(Fourcc::Abgr8888));
+        assert_eq!(pixels, [3, 2, 1, 4]);
+    }
+}
@@ -1,34 +1,116 @@
-use anyhow::Result;
-use smithay::backend::{allocator::{dmabuf::Dmabuf, Buffer}, egl::{EGLError, SwapBuffersError}, renderer::{
-        gles2::{Gles2Error, Gles2Renderer, Gles2Texture},
-        Bind, Frame, ImportDma, Renderer, Transform, Unbind,
-    }};
+use anyhow::{Context, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use smithay::{backend::{allocator::{dmabuf::Dmabuf, Buffer, Format, Fourcc, Modifier}, renderer::{
+        gles2::{ffi, Gles2Error, Gles2Frame, Gles2Renderer, Gles2Texture},
+        Bind, Frame, ImportDma, Renderer, Texture, Transform, Unbind,
+    }}, utils::{Buffer as BufferCoords, Physical, Point, Rectangle, Size}};
 
-use crate::{CopyState, WaylandState};
+use crate::{adjust::{AdjustShader, Adjustments}, capture::{CaptureRate, CapturedFrame}, convert, copy_path::ImportFailure, damage::{self, IdleDetect}, egl::{self, EglFence, NvEglError, SyncSupport}, events::Event, geometry::{self, Filter, FilterKind}, gpu::{ColorDepth, FrozenFrame, GlCapabilities, PresentError, RenderGPU, TargetGPU}, import_cache::BufferKey, modifier::{self, BufferLayout}, overlay::Overlay, pause_target, replace_source, source::Source, stats, streak::{Streak, Verdict}, trace::Stage, CopyState, ReadbackRoute, WaylandState};
 
+use std::{os::unix::io::RawFd, str::FromStr, time::Duration};
+
+/// Pixel store parameters of GLES 3
+const GL_PACK_ROW_LENGTH: u32 = 0x0D02;
+const GL_UNPACK_ROW_LENGTH: u32 = 0x0CF2;
+/// 10 bit color of GLES 3
+const GL_RGB10_A2: u32 = 0x8059;
+const GL_UNSIGNED_INT_2_10_10_10_REV: u32 = 0x8368;
+
+/// Order of the color channels in memory, starting at the lowest bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    Rgba,
+    Bgra,
+}
+
+/// Layout of a 32 bit pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub order: ChannelOrder,
+    pub alpha: bool,
+    pub depth: ColorDepth,
+}
+
+impl Layout {
+    const RGBA8: Layout = Layout {
+        order: ChannelOrder::Rgba,
+        alpha: true,
+        depth: ColorDepth::Eight,
+    };
+}
+
+/// Memory layout of the formats we can handle on the cpu.
+///
+/// Drm formats are little-endian, so e.g. `Argb8888` is stored as BGRA.
+/// All of them use 4 bytes per pixel, 10 bit formats leave 2 bits for alpha.
+pub fn memory_layout(format: Fourcc) -> Option<Layout> {
+    let (order, alpha, depth) = match format {
+        Fourcc::Argb8888 => (ChannelOrder::Bgra, true, ColorDepth::Eight),
+        Fourcc::Xrgb8888 => (ChannelOrder::Bgra, false, ColorDepth::Eight),
+        Fourcc::Abgr8888 => (ChannelOrder::Rgba, true, ColorDepth::Eight),
+        Fourcc::Xbgr8888 => (ChannelOrder::Rgba, false, ColorDepth::Eight),
+        Fourcc::Argb2101010 => (ChannelOrder::Bgra, true, ColorDepth::Ten),
+        Fourcc::Xrgb2101010 => (ChannelOrder::Bgra, false, ColorDepth::Ten),
+        Fourcc::Abgr2101010 => (ChannelOrder::Rgba, true, ColorDepth::Ten),
+        Fourcc::Xbgr2101010 => (ChannelOrder::Rgba, false, ColorDepth::Ten),
+        _ => return None,
+    };
+    Some(Layout { order, alpha, depth })
+}
+
+/// Formats that store luma and chroma separately, possibly in multiple planes.
+///
+/// The render gpu can only sample those through an external EGLImage, which converts them to RGB.
+pub fn is_yuv(format: Fourcc) -> bool {
+    matches!(
+        format,
+        Fourcc::Nv12 | Fourcc::Nv21 | Fourcc::Yuv420 | Fourcc::Yvu420
+    )
+}
+
+/// Format and type of pixels in the given depth, as uploaded or read back by GL
+pub fn gl_format(depth: ColorDepth) -> (u32, u32, u32) {
+    // (internal format, format, type)
+    match depth {
+        ColorDepth::Eight => (ffi::RGBA8, ffi::RGBA, ffi::UNSIGNED_BYTE),
+        ColorDepth::Ten => (GL_RGB10_A2, ffi::RGBA, GL_UNSIGNED_INT_2_10_10_10_REV),
+    }
+}
+
+/// Like `gl_format`, but GLES 2 only knows the unsized internal formats of `TexImage2D`
+pub fn texture_format(depth: ColorDepth, caps: GlCapabilities) -> (u32, u32, u32) {
+    let (internal, format, ty) = gl_format(depth);
+    if caps.gles3() {
+        (internal, format, ty)
+    } else {
+        (format, format, ty)
+    }
+}
+
+/// Creates a texture with storage for `width`x`height` pixels of `depth`, which is filled by `update_bitmap`.
+///
+/// With GLES 3 the storage is immutable, so uploads only replace its contents and never reallocate it.
+/// Fails for sizes beyond `GL_MAX_TEXTURE_SIZE`, which the driver would only answer with an incomplete texture.
 pub fn create_texture(
     renderer: &mut Gles2Renderer,
+    caps: GlCapabilities,
     width: i32,
     height: i32,
-) -> Result<Gles2Texture, Gles2Error> {
-    renderer.with_context(|renderer, gl| unsafe {
+    depth: ColorDepth,
+) -> Result<Gles2Texture> {
+    if !caps.fits_texture(width, height) {
+        anyhow::bail!(
+            "Frames of {}x{} exceed the maximum texture size of {} of the target gpu, \
+             --downscale-on-render lets the render gpu scale them down first",
+            width,
+            height,
+            caps.max_texture_size
+        );
+    }
+    let (internal, format, ty) = texture_format(depth, caps);
+    Ok(renderer.with_context(|renderer, gl| unsafe {
         let mut tex = 0;
         gl.GenTextures(1, &mut tex);
-        Gles2Texture::from_raw(renderer, tex, (width, height).into())
-    })
-}
-
-fn import_bitmap(
-    renderer: &mut Gles2Renderer,
-    texture: &mut Gles2Texture,
-    image: &[u8],
-    width: i32,
-    height: i32,
-) -> Result<(), Gles2Error> {
-    use smithay::backend::renderer::gles2::ffi;
-
-    renderer.with_context(|_renderer, gl| unsafe {
-        let tex = texture.tex_id();
         gl.BindTexture(ffi::TEXTURE_2D, tex);
         gl.TexParameteri(
             ffi::TEXTURE_2D,
@@ -40,102 +122,1854 @@ fn import_bitmap(
             ffi::TEXTURE_WRAP_T,
             ffi::CLAMP_TO_EDGE as i32,
         );
-        gl.TexImage2D(
-            ffi::TEXTURE_2D,
-            0,
-            ffi::RGBA as i32,
-            width,
-            height,
-            0,
-            ffi::RGBA,
-            ffi::UNSIGNED_BYTE as u32,
-            image.as_ptr() as *const _,
-        );
+        // without mipmaps the default minification filter leaves the texture incomplete
+        set_filter(gl, ffi::TEXTURE_2D, Filter::Linear);
+        if caps.gles3() {
+            gl.TexStorage2D(ffi::TEXTURE_2D, 1, internal, width, height);
+        } else {
+            gl.TexImage2D(
+                ffi::TEXTURE_2D,
+                0,
+                internal as i32,
+                width,
+                height,
+                0,
+                format,
+                ty,
+                std::ptr::null(),
+            );
+        }
         gl.BindTexture(ffi::TEXTURE_2D, 0);
+        Gles2Texture::from_raw(renderer, tex, (width, height).into())
+    })?)
+}
+
+/// Sets how the bound `target` is sampled when scaled.
+///
+/// smithay forces linear minification when drawing, so `Filter::Nearest` only fully applies to magnification there.
+unsafe fn set_filter(gl: &ffi::Gles2, target: u32, filter: Filter) {
+    let filter = match filter {
+        Filter::Nearest => ffi::NEAREST,
+        Filter::Linear => ffi::LINEAR,
+    } as i32;
+    gl.TexParameteri(target, ffi::TEXTURE_MIN_FILTER, filter);
+    gl.TexParameteri(target, ffi::TEXTURE_MAG_FILTER, filter);
+}
+
+/// Uploads `rects` of RGBA `image` into `texture`, rows of `image` are `stride` bytes apart.
+///
+/// `texture` needs to have been created for `depth` by `create_texture`.
+/// Without `GlCapabilities::unpack_row_length` rects narrower than the image are uploaded row by row.
+pub fn update_bitmap(
+    renderer: &mut Gles2Renderer,
+    caps: GlCapabilities,
+    texture: &Gles2Texture,
+    image: &[u8],
+    stride: i32,
+    rects: &[Rectangle<i32, BufferCoords>],
+    depth: ColorDepth,
+) -> Result<(), Gles2Error> {
+    let (_, format, ty) = gl_format(depth);
+    renderer.with_context(|_renderer, gl| unsafe {
+        gl.PixelStorei(ffi::UNPACK_ALIGNMENT, 1);
+        if caps.unpack_row_length {
+            gl.PixelStorei(GL_UNPACK_ROW_LENGTH, stride / 4);
+        }
+        gl.BindTexture(ffi::TEXTURE_2D, texture.tex_id());
+        for rect in rects {
+            // rows of the rect are only contiguous, if they span the whole image
+            let rows = if caps.unpack_row_length || rect.size.w * 4 == stride {
+                vec![(rect.loc.y, rect.size.h)]
+            } else {
+                (rect.loc.y..rect.loc.y + rect.size.h).map(|y| (y, 1)).collect()
+            };
+            for (y, height) in rows {
+                let offset = (y * stride + rect.loc.x * 4) as usize;
+                gl.TexSubImage2D(
+                    ffi::TEXTURE_2D,
+                    0,
+                    rect.loc.x,
+                    y,
+                    rect.size.w,
+                    height,
+                    format,
+                    ty,
+                    image[offset..].as_ptr() as *const _,
+                );
+            }
+        }
+        gl.BindTexture(ffi::TEXTURE_2D, 0);
+        if caps.unpack_row_length {
+            gl.PixelStorei(GL_UNPACK_ROW_LENGTH, 0);
+        }
+        gl.PixelStorei(ffi::UNPACK_ALIGNMENT, 4);
     })
 }
 
-fn copy_by_import(state: &mut WaylandState, buf: &Dmabuf) -> Result<()> {
+/// Whether the `upload_texture` of `source` holds a complete frame of the given size and depth,
+/// so damaged regions can be updated in place.
+fn can_update(state: &WaylandState, source: usize, size: Size<i32, BufferCoords>, depth: ColorDepth) -> bool {
+    state.damage_tracking && state.sources[source].texture_content == Some((size, depth))
+}
+
+/// Uploads the `buffer` of `source` holding a frame of `size`, only updating the `damage`d regions if possible.
+///
+/// `None` marks the whole frame as damaged.
+fn upload(
+    state: &mut WaylandState,
+    source: usize,
+    size: Size<i32, BufferCoords>,
+    stride: i32,
+    depth: ColorDepth,
+    damage: Option<Vec<Rectangle<i32, BufferCoords>>>,
+) -> Result<()> {
+    if state.sources[source].upload_storage != Some((size, depth)) {
+        // only changes with the source, every other frame streams into the existing storage
+        let target = active_target(&mut state.target);
+        let current = &mut state.sources[source];
+        current.upload_texture = create_texture(&mut target.renderer, target.gl, size.w, size.h, depth)?;
+        current.upload_storage = Some((size, depth));
+        current.texture_content = None;
+    }
+    let rects = damage
+        .filter(|_| can_update(state, source, size, depth))
+        .unwrap_or_else(|| vec![Rectangle::from_loc_and_size((0, 0), size)]);
+    let target = active_target(&mut state.target);
+    let current = &mut state.sources[source];
+    update_bitmap(&mut target.renderer, target.gl, &current.upload_texture, &current.buffer, stride, &rects, depth)?;
+    current.texture_content = Some((size, depth));
+    current.texture = current.upload_texture.clone();
+    target.upload_fence = if target.gl.gles3() {
+        Some(target.renderer.with_context(|_renderer, gl| unsafe { Fence::insert(gl) })?)
+    } else {
+        // without fences the buffer may only be reused once the upload finished
+        target.renderer.with_context(|_renderer, gl| unsafe { gl.Finish() })?;
+        None
+    };
+    Ok(())
+}
+
+/// Region of a captured frame of `source` of the given size, that is supposed to be displayed.
+///
+/// The region is in memory coordinates, so for `y_invert`ed frames it is mirrored vertically.
+fn source_region(
+    state: &WaylandState,
+    source: usize,
+    size: Size<i32, BufferCoords>,
+    y_invert: bool,
+) -> Rectangle<i32, BufferCoords> {
+    let region = match state.crop {
+        Some(crop) => {
+            // never read outside of the frame, the source might have shrunk since startup
+            let crop = crop.to_buffer(state.sources[source].scale);
+            let x = crop.loc.x.min(size.w);
+            let y = crop.loc.y.min(size.h);
+            Rectangle::from_loc_and_size(
+                (x, y),
+                (crop.size.w.min(size.w - x), crop.size.h.min(size.h - y)),
+            )
+        }
+        None => Rectangle::from_loc_and_size((0, 0), size),
+    };
+    if y_invert {
+        Rectangle::from_loc_and_size(
+            (region.loc.x, size.h - region.loc.y - region.size.h),
+            region.size,
+        )
+    } else {
+        region
+    }
+}
+
+/// Follows the source changing its resolution, the destination mode stays fixed.
+///
+/// `size` needs to be the size of the captured frame itself,
+/// the mode of the output may have changed again since the frame was captured.
+fn resize_source(state: &mut WaylandState, source: usize, size: Size<i32, BufferCoords>) -> Result<()> {
+    let frame_size = state.sources[source].frame_size;
+    if size == frame_size {
+        return Ok(());
+    }
+    slog::info!(
+        state.log,
+        "Source {} changed resolution from {}x{} to {}x{}",
+        state.sources[source].spec.selector,
+        frame_size.w,
+        frame_size.h,
+        size.w,
+        size.h
+    );
+    if let Some(crop) = state.crop.map(|crop| crop.to_buffer(state.sources[source].scale)) {
+        if crop.loc.x + crop.size.w > size.w || crop.loc.y + crop.size.h > size.h {
+            slog::warn!(state.log, "Crop region exceeds the source, only mirroring the part inside of it");
+        }
+    }
+    // the buffers are about to be reallocated
+    wait_for_upload(state)?;
+    let region = source_region(state, source, size, false);
+    let current = &mut state.sources[source];
+    current.texture_src = Rectangle::from_loc_and_size((0, 0), region.size);
+    // the next upload allocates a texture of the new size
+    current.upload_storage = None;
+    current.texture_content = None;
+    // don't hold on to the staging memory of a larger mode
+    current.buffer = Vec::new();
+    current.previous = Vec::new();
+    current.frame_size = size;
+    replace_source(state);
+    Ok(())
+}
+
+/// Follows the source switching the format or modifier of its frames, e.g. while sway scans a fullscreen client out.
+///
+/// Everything set up for the previous format is dropped and the copy path probed again,
+/// a readback of the previous format still in flight is lost.
+fn change_format(state: &mut WaylandState, source: usize, format: Format) -> Result<()> {
+    let previous = match state.sources[source].import_cache.switch_format(format) {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+    slog::info!(
+        state.log,
+        "Source {} changed format from {:?} with modifier {:?} to {:?} with modifier {:?}",
+        state.sources[source].spec.selector,
+        previous.code,
+        previous.modifier,
+        format.code,
+        format.modifier
+    );
+    wait_for_upload(state)?;
+    let current = &mut state.sources[source];
+    // partial updates would land on content converted from the previous format
+    current.texture_content = None;
+    if let Some(render) = state.render.as_mut() {
+        let (blit, readback) = (render.blit.take(), render.readback.take());
+        render.renderer.with_context(|_renderer, gl| {
+            if let Some(blit) = blit {
+                blit.destroy(gl);
+            }
+            if let Some(readback) = readback {
+                readback.destroy(gl);
+            }
+        })?;
+    }
+    state.copy_path.format_changed(format);
+    Ok(())
+}
+
+/// Imports `buf` on the target, through the import cache unless it is `transient` and thus never reused
+fn copy_by_import(state: &mut WaylandState, source: usize, buf: &Dmabuf, transient: bool) -> Result<()> {
     // that this works is actually very very unlikely.
     //
     // the src buffer is likely in a tiled layout incompatible with nvidia
     // and also not necessarily in memory accessible by the nvidia gpu.
     //
-    // We could try to do a better copy with a vulkan renderer (essentially doing
-    // what primus_vk is doing but in reverse), but smithay currently has no
-    // vulkan renderer and I do not want to deal with that now.
+    // With the `vulkan` feature the copy engine of the render gpu detiles the frame
+    // (essentially doing what primus_vk is doing but in reverse), for the formats it handles.
     //
-    // So we just fall back to a cpu copy in most (if not all) cases.
-    let imported = state.target.renderer.import_dmabuf(buf)?;
-    state.texture = imported;
+    // Otherwise we just fall back to a cpu copy in most (if not all) cases.
+    let key = BufferKey::of(buf).filter(|_| !transient);
+    let region = source_region(state, source, buf.size(), buf.y_inverted());
+    let current = &mut state.sources[source];
+    let cached = key
+        .as_ref()
+        .and_then(|key| current.import_cache.get(key))
+        .cloned();
+    current.texture_content = None;
+    current.texture = match cached {
+        Some(texture) => texture,
+        None => {
+            let imported = active_target(&mut state.target).renderer.import_dmabuf(buf)?;
+            if let Some(key) = key {
+                current.import_cache.insert(key, imported.clone());
+            }
+            imported
+        }
+    };
+    current.texture_src = region;
+    current.texture_flipped = buf.y_inverted();
+    // yuv buffers can only be sampled as external textures
+    current.texture_external = is_yuv(buf.format().code);
+    current.shown = true;
     Ok(())
 }
 
-fn copy_by_cpu(state: &mut WaylandState, buf: &Dmabuf) -> Result<()> {
-    let (w, h) = buf.size().into();
-    state.render.renderer.bind(buf.clone())?;
+/// Edge length of the framebuffer frames are drawn into for `--verify-import`
+const VERIFY_SIZE: i32 = 4;
+/// Largest difference of a color channel between both gpus, that still counts as the same color
+const VERIFY_TOLERANCE: i16 = 16;
+
+/// Whether the texture imported for `source` shows what the render gpu reads from `buf`, for `--verify-import`.
+///
+/// Verification is best effort, if it fails itself the import counts as good.
+fn import_verified(state: &mut WaylandState, source: usize, buf: &Dmabuf) -> bool {
+    match verify_import(state, source, buf) {
+        Ok(verified) => verified,
+        Err(err) => {
+            slog::info!(state.log, "Could not verify DirectImport: {:#}", err);
+            true
+        }
+    }
+}
+
+/// Both gpus draw the frame scaled down to a few pixels, which are compared
+fn verify_import(state: &mut WaylandState, source: usize, buf: &Dmabuf) -> Result<bool> {
+    let render = state.render.as_mut().context("No render gpu to compare against")?;
+    wait_for_producer(&mut render.renderer, render.sync, buf, &state.log);
+    let reference = render.renderer.import_dmabuf(buf)?;
+    let expected = sample_texture(&mut render.renderer, &reference)?;
+    let target = active_target(&mut state.target);
+    wait_for_producer(&mut target.renderer, target.sync, buf, &state.log);
+    let actual = sample_texture(&mut target.renderer, &state.sources[source].texture)?;
+    // alpha is undefined for formats without it
+    Ok(expected.chunks(4).zip(actual.chunks(4)).all(|(expected, actual)| {
+        expected[..3]
+            .iter()
+            .zip(&actual[..3])
+            .all(|(a, b)| (*a as i16 - *b as i16).abs() <= VERIFY_TOLERANCE)
+    }))
+}
 
-    let buffer_ptr = state.buffer.as_mut_ptr() as *mut _;
-    state.render.renderer.with_context(|_renderer, gl| unsafe {
-        use smithay::backend::renderer::gles2::ffi;
-        gl.ReadPixels(0, 0, w, h, ffi::RGBA, ffi::UNSIGNED_BYTE, buffer_ptr);
+/// Draws `texture` scaled to `VERIFY_SIZE` into a framebuffer of its own and reads it back as RGBA
+fn sample_texture(renderer: &mut Gles2Renderer, texture: &Gles2Texture) -> Result<Vec<u8>> {
+    let size = (VERIFY_SIZE, VERIFY_SIZE);
+    let blit = renderer.with_context(|_renderer, gl| {
+        let blit = BlitTarget::new(gl, size, ColorDepth::Eight);
+        unsafe { gl.BindFramebuffer(ffi::FRAMEBUFFER, blit.fbo) };
+        blit
     })?;
-    state.render.renderer.unbind()?;
-    import_bitmap(
-        &mut state.target.renderer,
-        &mut state.texture,
-        &state.buffer,
-        w,
-        h,
-    )?;
+    let src = Rectangle::from_loc_and_size((0, 0), texture.size());
+    renderer.render(Size::from(size), Transform::Normal, |_, frame| {
+        frame.clear([0.0, 0.0, 0.0, 0.0])?;
+        frame.render_texture_from_to(
+            texture,
+            src,
+            Rectangle::from_loc_and_size((0.0, 0.0), (size.0 as f64, size.1 as f64)),
+            Transform::Normal,
+            1.0,
+        )
+    })??;
+    let mut pixels = vec![0u8; (VERIFY_SIZE * VERIFY_SIZE * 4) as usize];
+    let ptr = pixels.as_mut_ptr() as *mut _;
+    let (_, format, ty) = gl_format(ColorDepth::Eight);
+    renderer.with_context(|_renderer, gl| unsafe {
+        read_pixels(gl, Rectangle::from_loc_and_size((0, 0), size), VERIFY_SIZE * 4, (format, ty), ptr);
+        gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+        blit.destroy(gl);
+    })?;
+    Ok(pixels)
+}
+
+/// Upper bound for waiting on a fence, before giving up and stalling the whole pipeline instead
+const FENCE_TIMEOUT: u64 = 1_000_000_000;
+
+/// Fence sync inserted into the command stream of a context, after everything that needs to finish first
+pub struct Fence(ffi::types::GLsync);
+
+impl Fence {
+    unsafe fn insert(gl: &ffi::Gles2) -> Fence {
+        Fence(gl.FenceSync(ffi::SYNC_GPU_COMMANDS_COMPLETE, 0))
+    }
+
+    /// Blocks until the commands preceding the fence finished and deletes it
+    unsafe fn wait(self, gl: &ffi::Gles2) {
+        let status = gl.ClientWaitSync(self.0, ffi::SYNC_FLUSH_COMMANDS_BIT, FENCE_TIMEOUT);
+        if status == ffi::TIMEOUT_EXPIRED || status == ffi::WAIT_FAILED {
+            gl.Finish();
+        }
+        gl.DeleteSync(self.0);
+    }
+}
+
+/// Blocks until all commands submitted so far finished, through a fence if the context supports them
+unsafe fn finish(gl: &ffi::Gles2, fences: bool) {
+    if fences {
+        Fence::insert(gl).wait(gl);
+    } else {
+        gl.Finish();
+    }
+}
+
+/// `struct dma_buf_export_sync_file` of linux/dma-buf.h
+#[repr(C)]
+struct DmaBufExportSyncFile {
+    flags: u32,
+    fd: i32,
+}
+
+/// How long a scanned out frame waits for the compositor to finish drawing it
+const PRODUCER_TIMEOUT_MS: i32 = 100;
+
+/// Fences a reader needs to wait for, i.e. those of the writers
+const DMA_BUF_SYNC_READ: u32 = 1;
+
+nix::ioctl_readwrite!(dma_buf_export_sync_file, b'b', 2, DmaBufExportSyncFile);
+
+/// The implicit fence of the compositors rendering into `buf` as sync_file, needs linux 6.0
+fn export_write_fence(buf: &Dmabuf) -> Option<RawFd> {
+    let fd = buf.handles().next()?;
+    let mut arg = DmaBufExportSyncFile {
+        flags: DMA_BUF_SYNC_READ,
+        fd: -1,
+    };
+    unsafe { dma_buf_export_sync_file(fd, &mut arg) }.ok()?;
+    Some(arg.fd)
+}
+
+/// Makes `renderer` wait on the gpu until the compositor finished rendering into `buf`.
+///
+/// export-dmabuf has no acquire fences, so the implicit one is exported from the dmabuf.
+/// Without kernel or driver support implicit synchronization is all we get.
+fn wait_for_producer(renderer: &mut Gles2Renderer, sync: SyncSupport, buf: &Dmabuf, log: &slog::Logger) {
+    if !sync.native_fence_sync {
+        return;
+    }
+    let fd = match export_write_fence(buf) {
+        Some(fd) => fd,
+        None => return,
+    };
+    match renderer.with_context(|_renderer, _gl| unsafe { egl::wait_native_fence(fd) }) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => slog::debug!(log, "{}", err),
+        Err(err) => slog::debug!(log, "Failed to wait for the compositor: {}", err),
+    }
+}
+
+/// Waits on the cpu until the compositor finished rendering into `buf`, as the display engine does not wait for it
+fn wait_for_producer_on_cpu(buf: &Dmabuf) {
+    if let Some(fd) = export_write_fence(buf) {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        let _ = poll(&mut fds, PRODUCER_TIMEOUT_MS);
+        let _ = nix::unistd::close(fd);
+    }
+}
+
+/// Fence signaling once `renderer` is done with all commands submitted so far, `None` without EGL_KHR_fence_sync
+fn release_fence(renderer: &mut Gles2Renderer, sync: SyncSupport) -> Result<Option<EglFence>> {
+    if !sync.fence_sync {
+        return Ok(None);
+    }
+    Ok(renderer.with_context(|_renderer, gl| unsafe {
+        let fence = EglFence::insert();
+        gl.Flush();
+        fence
+    })?)
+}
+
+/// Waits for the last upload to the target, before the `buffer` of a source may be overwritten.
+///
+/// Drivers are free to read the client memory of an upload, after the call returned.
+fn wait_for_upload(state: &mut WaylandState) -> Result<()> {
+    let target = active_target(&mut state.target);
+    if let Some(fence) = target.upload_fence.take() {
+        target
+            .renderer
+            .with_context(|_renderer, gl| unsafe { fence.wait(gl) })?;
+    }
+    Ok(())
+}
+
+/// Describes pixels read back from the render gpu
+pub struct Readback {
+    /// Index of the source the frame belongs to, readbacks may finish during a frame of another one
+    source: usize,
+    width: i32,
+    height: i32,
+    stride: i32,
+    /// Layout after reading back
+    layout: Layout,
+    y_invert: bool,
+    captured: Duration,
+}
+
+/// Two pixel pack buffers, so a frame can be read back while the previous one is copied out
+pub struct AsyncReadback {
+    pbos: [u32; 2],
+    size: usize,
+    current: usize,
+    /// Readback in flight in the other buffer and the fence signaling its completion
+    pending: Option<(Readback, Fence)>,
+}
+
+impl AsyncReadback {
+    fn new(gl: &ffi::Gles2, size: usize) -> AsyncReadback {
+        let mut pbos = [0; 2];
+        unsafe {
+            gl.GenBuffers(2, pbos.as_mut_ptr());
+            for pbo in &pbos {
+                gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, *pbo);
+                gl.BufferData(
+                    ffi::PIXEL_PACK_BUFFER,
+                    size as isize,
+                    std::ptr::null(),
+                    ffi::STREAM_READ,
+                );
+            }
+            gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, 0);
+        }
+        AsyncReadback {
+            pbos,
+            size,
+            current: 0,
+            pending: None,
+        }
+    }
+
+    fn destroy(self, gl: &ffi::Gles2) {
+        unsafe {
+            if let Some((_, fence)) = self.pending {
+                gl.DeleteSync(fence.0);
+            }
+            gl.DeleteBuffers(2, self.pbos.as_ptr());
+        }
+    }
+}
+
+/// Reads `region` of the currently bound framebuffer into `ptr`,
+/// which is an offset into the bound pixel pack buffer, if any.
+unsafe fn read_pixels(
+    gl: &ffi::Gles2,
+    region: Rectangle<i32, BufferCoords>,
+    stride: i32,
+    (format, ty): (u32, u32),
+    ptr: *mut std::ffi::c_void,
+) {
+    gl.PixelStorei(ffi::PACK_ALIGNMENT, 1);
+    if stride != region.size.w * 4 {
+        gl.PixelStorei(GL_PACK_ROW_LENGTH, stride / 4);
+    }
+    gl.ReadPixels(
+        region.loc.x,
+        region.loc.y,
+        region.size.w,
+        region.size.h,
+        format,
+        ty,
+        ptr as *mut _,
+    );
+    if stride != region.size.w * 4 {
+        gl.PixelStorei(GL_PACK_ROW_LENGTH, 0);
+    }
+    gl.PixelStorei(ffi::PACK_ALIGNMENT, 4);
+}
+
+/// Starts reading back into one pixel buffer and copies out the previous frame from the other one.
+///
+/// Returns the previous frame, if there was one.
+fn read_pixels_async(
+    render: &mut RenderGPU,
+    region: Rectangle<i32, BufferCoords>,
+    readback: Readback,
+    format: (u32, u32),
+    buffer: &mut Vec<u8>,
+) -> Result<Option<Readback>> {
+    let size = (readback.stride * readback.height) as usize;
+    let pbos = render.readback.take();
+    let (pbos, finished) = render.renderer.with_context(|_renderer, gl| unsafe {
+        // resolution changes invalidate the buffers and whatever is pending in them
+        let mut pbos = match pbos {
+            Some(pbos) if pbos.size == size => pbos,
+            old => {
+                if let Some(old) = old {
+                    old.destroy(gl);
+                }
+                AsyncReadback::new(gl, size)
+            }
+        };
+
+        gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, pbos.pbos[pbos.current]);
+        read_pixels(gl, region, readback.stride, format, std::ptr::null_mut());
+        let fence = Fence::insert(gl);
+
+        let finished = pbos.pending.take().map(|(readback, fence)| {
+            fence.wait(gl);
+            readback
+        });
+        if finished.is_some() {
+            gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, pbos.pbos[1 - pbos.current]);
+            let ptr = gl.MapBufferRange(ffi::PIXEL_PACK_BUFFER, 0, size as isize, ffi::MAP_READ_BIT);
+            if !ptr.is_null() {
+                buffer.resize(size, 0);
+                std::ptr::copy_nonoverlapping(ptr as *const u8, buffer.as_mut_ptr(), size);
+                gl.UnmapBuffer(ffi::PIXEL_PACK_BUFFER);
+            }
+        }
+        gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, 0);
+
+        pbos.pending = Some((readback, fence));
+        pbos.current = 1 - pbos.current;
+        (pbos, finished)
+    })?;
+    render.readback = Some(pbos);
+    Ok(finished)
+}
+
+/// Offscreen RGBA framebuffer on the render gpu, for `ReadbackRoute::Blit`
+pub struct BlitTarget {
+    fbo: u32,
+    tex: u32,
+    size: (i32, i32),
+    depth: ColorDepth,
+}
+
+impl BlitTarget {
+    fn new(gl: &ffi::Gles2, size: (i32, i32), depth: ColorDepth) -> BlitTarget {
+        let (mut tex, mut fbo) = (0, 0);
+        let (internal, format, ty) = gl_format(depth);
+        unsafe {
+            gl.GenTextures(1, &mut tex);
+            gl.BindTexture(ffi::TEXTURE_2D, tex);
+            gl.TexImage2D(
+                ffi::TEXTURE_2D,
+                0,
+                internal as i32,
+                size.0,
+                size.1,
+                0,
+                format,
+                ty,
+                std::ptr::null(),
+            );
+            gl.BindTexture(ffi::TEXTURE_2D, 0);
+            gl.GenFramebuffers(1, &mut fbo);
+            gl.BindFramebuffer(ffi::FRAMEBUFFER, fbo);
+            gl.FramebufferTexture2D(
+                ffi::FRAMEBUFFER,
+                ffi::COLOR_ATTACHMENT0,
+                ffi::TEXTURE_2D,
+                tex,
+                0,
+            );
+            gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+        }
+        BlitTarget {
+            fbo,
+            tex,
+            size,
+            depth,
+        }
+    }
+
+    fn destroy(self, gl: &ffi::Gles2) {
+        unsafe {
+            gl.DeleteFramebuffers(1, &self.fbo);
+            gl.DeleteTextures(1, &self.tex);
+        }
+    }
+}
+
+/// Makes the contents of `buf` available for reading in the current framebuffer of the render gpu.
+///
+/// `depth` is the depth of the offscreen framebuffer used by `ReadbackRoute::Blit`. With `downscale` the given
+/// region of `buf` is drawn scaled to the given size into it instead, which needs `ReadbackRoute::Blit`,
+/// blended in linear light with `linear_blending`.
+fn bind_source(
+    render: &mut RenderGPU,
+    buf: &Dmabuf,
+    route: ReadbackRoute,
+    depth: ColorDepth,
+    downscale: Option<(Rectangle<i32, BufferCoords>, Size<i32, BufferCoords>)>,
+    linear_blending: bool,
+) -> Result<()> {
+    match route {
+        ReadbackRoute::Bind => render.renderer.bind(buf.clone())?,
+        ReadbackRoute::Blit => {
+            // sampling through an EGLImage also resolves tiling and converts the format
+            let texture = render.renderer.import_dmabuf(buf)?;
+            let size: (i32, i32) = downscale.map(|(_, size)| size).unwrap_or_else(|| buf.size()).into();
+            let blit = render.blit.take();
+            let blit = render.renderer.with_context(|_renderer, gl| {
+                let blit = match blit {
+                    Some(blit) if blit.size == size && blit.depth == depth => blit,
+                    old => {
+                        if let Some(old) = old {
+                            old.destroy(gl);
+                        }
+                        BlitTarget::new(gl, size, depth)
+                    }
+                };
+                unsafe { gl.BindFramebuffer(ffi::FRAMEBUFFER, blit.fbo) };
+                blit
+            })?;
+            render.blit = Some(blit);
+            let blend = downscale.filter(|_| linear_blending).map(|(region, _)| region);
+            // framebuffers are stored bottom up, flip to keep the memory order of the source
+            render
+                .renderer
+                .render(Size::from(size), Transform::Flipped180, |_, frame| {
+                    frame.clear([0.0, 0.0, 0.0, 0.0])?;
+                    match downscale {
+                        // drawn by our own shader below
+                        Some(_) if blend.is_some() => Ok(()),
+                        Some((region, _)) => frame.render_texture_from_to(
+                            &texture,
+                            region,
+                            Rectangle::from_loc_and_size((0.0, 0.0), (size.0 as f64, size.1 as f64)),
+                            Transform::Normal,
+                            1.0,
+                        ),
+                        None => frame.render_texture_at(&texture, (0.0, 0.0).into(), 1, 1.0, Transform::Normal, 1.0),
+                    }
+                })??;
+            if let Some(region) = blend {
+                blend_downscaled(render, &texture, buf, region, Size::from(size))?;
+            }
+        }
+    }
+
+    let complete = render.renderer.with_context(|_renderer, gl| unsafe {
+        gl.CheckFramebufferStatus(ffi::FRAMEBUFFER) == ffi::FRAMEBUFFER_COMPLETE
+    })?;
+    if !complete {
+        unbind_source(render, route)?;
+        anyhow::bail!("Framebuffer of {:?} readback is incomplete", route);
+    }
+    Ok(())
+}
+
+/// Draws `region` of the imported `texture` of `buf` scaled to `size` into the bound framebuffer, like `bind_source`
+/// does through smithay, but filtered in linear light for `--linear-blending`
+fn blend_downscaled(
+    render: &mut RenderGPU,
+    texture: &Gles2Texture,
+    buf: &Dmabuf,
+    region: Rectangle<i32, BufferCoords>,
+    size: Size<i32, Physical>,
+) -> Result<()> {
+    let shader = match render.blend_shader.take() {
+        Some(shader) => shader,
+        None => render
+            .renderer
+            .with_context(|_renderer, gl| unsafe { AdjustShader::new(gl) })?
+            .context("Failed to create the linear blending shader")?,
+    };
+    let external = is_yuv(buf.format().code);
+    let result = render.renderer.with_context(|_renderer, gl| unsafe {
+        let target = if external { ffi::TEXTURE_EXTERNAL_OES } else { ffi::TEXTURE_2D };
+        // the shader filters on its own, from the exact texels
+        gl.BindTexture(target, texture.tex_id());
+        set_filter(gl, target, Filter::Nearest);
+        gl.BindTexture(target, 0);
+        shader.draw(
+            gl,
+            (texture.tex_id(), texture.size()),
+            external,
+            buf.y_inverted(),
+            region,
+            Transform::Normal,
+            Rectangle::from_loc_and_size((0.0, 0.0), (size.w as f64, size.h as f64)),
+            size,
+            Transform::Flipped180,
+            Adjustments::NEUTRAL,
+            true,
+        )
+    });
+    render.blend_shader = Some(shader);
+    result?
+}
+
+fn unbind_source(render: &mut RenderGPU, route: ReadbackRoute) -> Result<()> {
+    match route {
+        ReadbackRoute::Bind => render.renderer.unbind()?,
+        ReadbackRoute::Blit => render.renderer.with_context(|_renderer, gl| unsafe {
+            gl.BindFramebuffer(ffi::FRAMEBUFFER, 0)
+        })?,
+    }
     Ok(())
 }
 
-pub fn render_dmabuf(state: &mut WaylandState, buf: Dmabuf) -> Result<()> {
-    match state.copy {
+/// When the render gpu scales frames down before they are read back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Downscale {
+    /// Whenever the source is shown smaller than it is captured
+    Auto,
+    /// Whenever the source is shown in another size than it is captured, also if that enlarges it
+    On,
+    Off,
+}
+
+impl Downscale {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "on", "off"];
+}
+
+impl FromStr for Downscale {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Downscale> {
+        match name {
+            "auto" => Ok(Downscale::Auto),
+            "on" => Ok(Downscale::On),
+            "off" => Ok(Downscale::Off),
+            x => anyhow::bail!("Unknown downscale mode: {}", x),
+        }
+    }
+}
+
+/// Size the render gpu scales a `region` of `source` to before reading it back, `None` reads it back as is
+fn downscale_size(
+    state: &WaylandState,
+    source: usize,
+    region: Size<i32, BufferCoords>,
+) -> Option<Size<i32, BufferCoords>> {
+    let current = &state.sources[source];
+    // positioned sources are shown unscaled anyway
+    if state.downscale == Downscale::Off || current.position.is_some() {
+        return None;
+    }
+    let dst = current.destination(state.dest_size);
+    let size = Size::from((dst.size.w.round() as i32, dst.size.h.round() as i32));
+    let wanted = match state.downscale {
+        Downscale::On => size != region,
+        _ => size.w <= region.w && size.h <= region.h && size != region,
+    };
+    Some(size).filter(|_| wanted)
+}
+
+/// Size of the pixels read back for frames of `size` of `source`
+fn readback_size(state: &WaylandState, source: usize, size: Size<i32, BufferCoords>) -> Size<i32, BufferCoords> {
+    let region = source_region(state, source, size, false).size;
+    downscale_size(state, source, region).unwrap_or(region)
+}
+
+/// Whether frames with `modifier` need to be drawn into a linear framebuffer before reading them back.
+///
+/// Some drivers happily bind tiled dmabufs, but `ReadPixels` then returns the tiles in memory order.
+fn tiled_readback(render: &mut RenderGPU, modifier: Modifier, log: &slog::Logger) -> bool {
+    let layout = modifier::classify(modifier);
+    let tiled = matches!(layout, BufferLayout::Tiled(_));
+    if render.readback_modifiers.insert(modifier) {
+        if tiled {
+            slog::info!(log, "Frames with modifier {:?} are {:?}, reading them back through a linear framebuffer", modifier, layout);
+        } else {
+            slog::info!(log, "Frames with modifier {:?} are {:?}, reading them back directly", modifier, layout);
+        }
+    }
+    tiled
+}
+
+/// Returns the capture time of the frame now held by the texture of a source, if any
+fn copy_by_cpu(state: &mut WaylandState, source: usize, buf: &Dmabuf, captured: Duration) -> Result<Option<Duration>> {
+    // the readback overwrites the buffer of the last upload
+    wait_for_upload(state)?;
+    // only read back the region we are actually going to display
+    // rows are read back in memory order, so the flip is still pending after the copy
+    let region = source_region(state, source, buf.size(), buf.y_inverted());
+    // scaled down by the render gpu, which leaves less to read back
+    let downscale = downscale_size(state, source, region.size).map(|size| (region, size));
+    let region = match downscale {
+        Some((_, size)) => Rectangle::from_loc_and_size((0, 0), size),
+        None => region,
+    };
+    let (w, h): (i32, i32) = region.size.into();
+    let render = state
+        .render
+        .as_mut()
+        .context("No render gpu available for cpu copy")?;
+    let source_layout = memory_layout(buf.format().code);
+    // packed 10 bit readback needs GLES 3, just like pixel buffers
+    let deep = state.color_depth == ColorDepth::Ten
+        && render.pixel_buffers
+        && source_layout.map(|layout| layout.depth == ColorDepth::Ten).unwrap_or(false);
+    let blit_depth = if deep { ColorDepth::Ten } else { ColorDepth::Eight };
+    let tiled = tiled_readback(render, buf.format().modifier, &state.log);
+    let route = match state.readback_route {
+        // yuv can't be bound as a framebuffer, only blitting scales and resolves tiling,
+        // this does not change the route for other frames though
+        _ if is_yuv(buf.format().code) || downscale.is_some() || tiled => {
+            bind_source(render, buf, ReadbackRoute::Blit, blit_depth, downscale, state.linear_blending)?;
+            ReadbackRoute::Blit
+        }
+        Some(route) => {
+            bind_source(render, buf, route, blit_depth, None, false)?;
+            route
+        }
         None => {
-            if copy_by_import(state, &buf).is_ok() {
-                slog::info!(state.log, "Copy path: DirectImport");
-                state.copy = Some(CopyState::DirectImport);
-            } else if copy_by_cpu(state, &buf).is_ok() {
-                slog::info!(state.log, "Copy path: CPUCopy");
-                state.copy = Some(CopyState::CPUCopy);
-            } else {
-                panic!("Could not determine working copy path");
+            let route = match bind_source(render, buf, ReadbackRoute::Bind, blit_depth, None, false) {
+                Ok(()) => ReadbackRoute::Bind,
+                Err(err) => {
+                    slog::debug!(state.log, "Binding the dmabuf failed: {}", err);
+                    bind_source(render, buf, ReadbackRoute::Blit, blit_depth, None, false)?;
+                    ReadbackRoute::Blit
+                }
+            };
+            slog::info!(state.log, "Readback route: {:?}", route);
+            state.readback_route = Some(route);
+            route
+        }
+    };
+    let layout = match (route, source_layout) {
+        (ReadbackRoute::Bind, Some(source)) if deep || source.depth == ColorDepth::Eight => source,
+        // reading back 10 bit as bytes lets the gpu convert to RGBA8
+        (ReadbackRoute::Bind, Some(source)) => Layout {
+            order: ChannelOrder::Rgba,
+            alpha: source.alpha,
+            depth: ColorDepth::Eight,
+        },
+        (ReadbackRoute::Bind, None) => Layout::RGBA8,
+        // blitting already converted the frame to RGBA
+        (ReadbackRoute::Blit, _) => Layout {
+            depth: blit_depth,
+            ..Layout::RGBA8
+        },
+    };
+    // keep the row pitch of the source, if we can, otherwise read back tightly packed
+    let stride = match buf.strides().next() {
+        Some(stride) if render.pack_row_length && downscale.is_none() && stride as i32 >= w * 4 => stride as i32,
+        _ => w * 4,
+    };
+    // channels are always reordered by `convert`, so frames look the same whatever the driver offers
+    let (_, format, ty) = gl_format(layout.depth);
+    let format = (format, ty);
+    let readback = Readback {
+        source,
+        width: w,
+        height: h,
+        stride,
+        layout,
+        y_invert: buf.y_inverted(),
+        captured,
+    };
+    if state.async_readback && !render.pixel_buffers {
+        slog::warn!(state.log, "Render gpu lacks pixel buffer objects, falling back to synchronous readback");
+        state.async_readback = false;
+    }
+
+    // with conversion threads every readback needs its own buffer, earlier ones might still be converted
+    let mut pixels = match state.converter.as_mut() {
+        Some(converter) => converter.buffer(),
+        None => std::mem::take(&mut state.sources[source].buffer),
+    };
+    let finished = if state.async_readback {
+        read_pixels_async(render, region, readback, format, &mut pixels)?
+    } else {
+        // the frame size might differ from what the source reported at startup
+        pixels.resize((stride * h) as usize, 0);
+        let buffer_ptr = pixels.as_mut_ptr() as *mut _;
+        // fences need GLES 3, just like pixel buffers
+        let fences = render.pixel_buffers;
+        render.renderer.with_context(|_renderer, gl| unsafe {
+            read_pixels(gl, region, stride, format, buffer_ptr);
+            finish(gl, fences);
+        })?;
+        Some(readback)
+    };
+    unbind_source(render, route)?;
+
+    let readback = match state.converter.as_mut() {
+        None => {
+            let readback = match finished {
+                Some(readback) => readback,
+                None => {
+                    state.sources[source].buffer = pixels;
+                    return Ok(None);
+                }
+            };
+            let depth = readback.layout.depth.min(state.color_depth);
+            convert::swizzle(&mut pixels, readback.layout, depth);
+            state.sources[readback.source].buffer = pixels;
+            readback
+        }
+        Some(converter) => {
+            match finished {
+                Some(readback) => {
+                    let (layout, depth) = (readback.layout, readback.layout.depth.min(state.color_depth));
+                    let stride = readback.stride as usize;
+                    if let Err(pixels) = converter.submit(pixels, stride, layout, depth, readback) {
+                        slog::debug!(state.log, "Conversion threads are busy, dropping frame");
+                        converter.recycle(pixels);
+                    }
+                }
+                None => converter.recycle(pixels),
+            }
+            match converter.latest() {
+                // frames of the previous mode don't fit the texture anymore
+                Some(job)
+                    if Size::from((job.meta.width, job.meta.height))
+                        == readback_size(state, job.meta.source, state.sources[job.meta.source].frame_size) =>
+                {
+                    let old = std::mem::replace(&mut state.sources[job.meta.source].buffer, job.pixels);
+                    converter.recycle(old);
+                    job.meta
+                }
+                Some(job) => {
+                    converter.recycle(job.pixels);
+                    return Ok(None);
+                }
+                None => return Ok(None),
             }
         }
-        Some(CopyState::DirectImport) => copy_by_import(state, &buf)?,
-        Some(CopyState::CPUCopy) => copy_by_cpu(state, &buf)?,
     };
+    let depth = readback.layout.depth.min(state.color_depth);
+    let uploaded = upload_frame(
+        state,
+        readback.source,
+        Size::from((readback.width, readback.height)),
+        readback.stride,
+        depth,
+        readback.y_invert,
+    )?;
+    Ok(Some(readback.captured).filter(|_| uploaded))
+}
 
+/// Uploads the converted frame of `size` in the `buffer` of `source`, whose rows are `stride` bytes apart,
+/// and displays it from then on.
+///
+/// Returns `false` if the frame did not change and was skipped.
+fn upload_frame(
+    state: &mut WaylandState,
+    source: usize,
+    size: Size<i32, BufferCoords>,
+    stride: i32,
+    depth: ColorDepth,
+    y_invert: bool,
+) -> Result<bool> {
+    // export-dmabuf has no damage, so compare against the previous frame to find the changed rows
+    let current = &state.sources[source];
+    let damage = if state.damage_tracking && current.previous.len() == current.buffer.len() {
+        damage::merge(damage::diff_rows(
+            &current.previous,
+            &current.buffer,
+            stride as usize,
+            size.w,
+        ))
+    } else {
+        None
+    };
+    let unchanged = match state.idle_detect {
+        IdleDetect::Off => false,
+        IdleDetect::Damage => damage.as_ref().map(|damage| damage.is_empty()).unwrap_or(false),
+        IdleDetect::Hash => {
+            let hash = damage::sample_hash(&current.buffer, stride as usize, size);
+            same_hash(&mut state.sources[source], hash, size, depth)
+        }
+    };
+    if still_idle(&mut state.sources[source], unchanged) {
+        return Ok(false);
+    }
+    upload(state, source, size, stride, depth, damage)?;
+    let current = &mut state.sources[source];
+    if state.damage_tracking {
+        // the next readback overwrites the older frame
+        std::mem::swap(&mut current.buffer, &mut current.previous);
+    }
+    current.texture_src = Rectangle::from_loc_and_size((0, 0), size);
+    current.texture_flipped = y_invert;
+    current.texture_external = false;
+    current.shown = true;
+    Ok(true)
+}
+
+/// Whether `hash` of a frame of `size` in `depth` matches the frame in the texture of `current`
+fn same_hash(current: &mut Source, hash: u64, size: Size<i32, BufferCoords>, depth: ColorDepth) -> bool {
+    let same = current.idle_hash == Some(hash) && current.texture_content == Some((size, depth));
+    current.idle_hash = Some(hash);
+    same
+}
+
+/// Whether an `unchanged` frame of `current` is skipped.
+///
+/// Every `IDLE_REFRESH`th frame is shown anyway, in case the sample hash missed a change.
+fn still_idle(current: &mut Source, unchanged: bool) -> bool {
+    let idle = unchanged && current.shown && current.idle_skips < IDLE_REFRESH;
+    current.idle_skips = if idle { current.idle_skips + 1 } else { 0 };
+    idle
+}
+
+/// Copies the frame through the copy engine of the render gpu, which also detiles it.
+///
+/// Returns `false` if the frame did not change and was skipped.
+#[cfg(feature = "vulkan")]
+fn copy_by_vulkan(state: &mut WaylandState, source: usize, buf: &Dmabuf) -> Result<bool> {
+    // the copy overwrites the buffer of the last upload
+    wait_for_upload(state)?;
+    let region = source_region(state, source, buf.size(), buf.y_inverted());
+    let vulkan = state
+        .render
+        .as_mut()
+        .and_then(|render| render.vulkan.as_mut())
+        .context("No vulkan device for the render gpu")?;
+    let buffer = &mut state.sources[source].buffer;
+    let layout = vulkan.copy(buf, region, buffer)?;
+    convert::swizzle(buffer, layout, ColorDepth::Eight);
+    upload_frame(
+        state,
+        source,
+        region.size,
+        region.size.w * 4,
+        ColorDepth::Eight,
+        buf.y_inverted(),
+    )
+}
+
+/// Copies a frame that was not imported, through vulkan if possible and otherwise by reading it back
+fn copy_fallback(
+    state: &mut WaylandState,
+    source: usize,
+    buf: &Dmabuf,
+    captured: Duration,
+) -> Result<(CopyState, Option<Duration>)> {
+    #[cfg(feature = "vulkan")]
+    {
+        if state
+            .render
+            .as_ref()
+            .map(|render| render.vulkan.is_some())
+            .unwrap_or(false)
+        {
+            match copy_by_vulkan(state, source, buf) {
+                Ok(uploaded) => return Ok((CopyState::Vulkan, Some(captured).filter(|_| uploaded))),
+                Err(err) => slog::debug!(state.log, "Vulkan copy of {:?} failed: {:#}", buf.format(), err),
+            }
+        }
+    }
+    Ok((CopyState::CPUCopy, copy_by_cpu(state, source, buf, captured)?))
+}
+
+/// The target rendered to, rendering is only triggered by captures which require one
+fn active_target(target: &mut Option<TargetGPU>) -> &mut TargetGPU {
+    target.as_mut().expect("Rendering without a target")
+}
+
+/// Consecutive failed renders after which the target is considered reset, even if no reset was reported
+const RESET_THRESHOLD: u32 = 8;
+
+/// Whether the context of the target reports a reset or can't be made current anymore
+fn target_reset(state: &mut WaylandState) -> bool {
+    let status = active_target(&mut state.target).renderer.with_context(|_renderer, gl| unsafe {
+        if gl.GetGraphicsResetStatus.is_loaded() {
+            gl.GetGraphicsResetStatus()
+        } else {
+            ffi::NO_ERROR
+        }
+    });
+    match status {
+        Ok(ffi::NO_ERROR) => false,
+        Ok(status) => {
+            slog::warn!(state.log, "Target gpu was reset: 0x{:x}", status);
+            true
+        }
+        Err(_) => true,
+    }
+}
+
+/// Handles a failed render of a captured frame.
+///
+/// Without robustness this stops the event loop, otherwise the frame is retried and the target rebuilt,
+/// once it looks like the gpu was reset.
+pub fn render_failed(state: &mut WaylandState, err: anyhow::Error) {
+    if !state.robustness {
+        state.fatal = Some(err.context("Failed to render"));
+        return;
+    }
+    state.render_failures += 1;
+    slog::warn!(state.log, "Failed to render: {:#}", err);
+    state.stats.error(format!("Render failed: {}", err));
+    // the reset status is only reliable on robust contexts, which we can't create
+    if target_reset(state) || state.render_failures >= RESET_THRESHOLD {
+        slog::error!(state.log, "Lost the target gpu, rebuilding it");
+        state.target_lost = true;
+    }
+    state.retry.failed(&state.log);
+}
+
+/// When the compositor may reuse the buffer of a captured frame
+pub enum Release {
+    Now,
+    /// Once the fence signaled, the gpus are still reading the buffer
+    Fence(EglFence),
+    /// Once it was replaced on screen, the frame is handed to `Scanout::hold`
+    Scanout,
+}
+
+/// Whether `buf` of `source` can be flipped onto the target as it is, as nothing is drawn around or on top of it
+fn scanout_possible(state: &WaylandState, source: usize, buf: &Dmabuf) -> bool {
+    let target = match (state.target.as_ref(), state.scanout.as_ref()) {
+        (Some(target), Some(scanout)) if scanout.accepts(buf.format()) => target,
+        _ => return false,
+    };
+    #[cfg(feature = "nvenc")]
+    if state.recorder.is_some() {
+        return false;
+    }
+    state.sources.len() == 1
+        && state.sources[source].position.is_none()
+        && state.sources[source].transform == Transform::Normal
+        && state.crop.is_none()
+        && state.adjustments.is_neutral()
+        && state.overlay.is_none()
+        && state.pacing.is_none()
+        && !buf.y_inverted()
+        && target.shows_unchanged(Size::from((buf.size().w, buf.size().h)))
+}
+
+/// Flips `buf` onto the target, returns `false` if the last flip is still pending and the frame was dropped
+fn scan_out(state: &mut WaylandState, buf: &Dmabuf, captured: Duration) -> Result<bool> {
+    let scanout = state.scanout.as_mut().expect("Scanning out without direct scanout");
+    if scanout.flip_pending() {
+        return Ok(false);
+    }
+    let entering = !scanout.active();
+    wait_for_producer_on_cpu(buf);
+    scanout.flip(buf)?;
+    if entering {
+        slog::info!(state.log, "Scanning out {:?} directly", buf.format());
+    }
+    state.stats.frame_swapped(captured);
+    if let Some(tracer) = state.tracer.as_mut() {
+        tracer.swapped();
+    }
+    state.swap_pending = true;
+    // the textures don't hold the frame, they are filled again once rendering takes over
+    for source in state.sources.iter_mut() {
+        source.shown = false;
+    }
+    Ok(true)
+}
+
+/// Hands the output back to the renderer after direct scanout, rendered frames are shown once it is set up again.
+///
+/// Returns `false` while the last flip is still pending, which needs to complete first.
+fn leave_scanout(state: &mut WaylandState) -> bool {
+    let scanout = state.scanout.as_mut().expect("Leaving direct scanout without it");
+    if scanout.flip_pending() {
+        return false;
+    }
+    scanout.leave();
+    // the stream needs the plane back, just like after a vt switch
+    if let Err(err) = active_target(&mut state.target).restore_scanout() {
+        slog::warn!(state.log, "Failed to restore the output after direct scanout: {}", err);
+    }
+    state.target_lost = true;
+    true
+}
+
+/// Renders a captured dmabuf frame of `source`, which needs to be ready.
+///
+/// Returns when the frame may be released to the compositor. The fds of `frame` are closed on return,
+/// imports, framebuffers and the import cache keep the buffer alive on their own.
+pub fn render_dmabuf(state: &mut WaylandState, source: usize, frame: CapturedFrame) -> Result<Release> {
+    // frames still in flight when the target went away
+    if state.target.is_none() {
+        return Ok(Release::Now);
+    }
+    let captured = frame.captured.context("Rendering a frame that is not ready")?;
+    let transient = frame.transient;
+    let buf = frame.into_dmabuf()?;
+    if state.reject_yuv && is_yuv(buf.format().code) {
+        anyhow::bail!("Compositor sent a {:?} frame, but yuv is rejected", buf.format().code);
+    }
+    resize_source(state, source, buf.size())?;
+    let format = buf.format();
+    change_format(state, source, format)?;
+    if !state.target_paused && scanout_possible(state, source, &buf) {
+        match scan_out(state, &buf, captured) {
+            Ok(true) => {
+                set_copy_path(state, CopyState::Scanout);
+                return Ok(Release::Scanout);
+            }
+            // the next vblank captures again
+            Ok(false) => return Ok(Release::Now),
+            Err(err) => slog::info!(state.log, "Direct scanout of {:?} failed, rendering it: {:#}", format, err),
+        }
+    }
+    trace(state, Stage::CopyStart);
+    let importable = state.import_formats.contains(&format);
+    let imported = state.copy_path.try_import(format, importable)
+        && match copy_by_import(state, source, &buf, transient) {
+            Ok(())
+                if state.verify_import
+                    && state.copy_path.probing()
+                    && state.copy_path.allow_cpu()
+                    && !import_verified(state, source, &buf) =>
+            {
+                let failure = ImportFailure::of(&buf, "the imported texture differs from the readback", true);
+                slog::warn!(state.log, "DirectImport of {}, copying through the cpu", failure);
+                state.copy_path.record_failure(failure);
+                state.copy_path.import_garbage(format);
+                false
+            }
+            Ok(()) => {
+                if state.copy_path.import_succeeded() {
+                    slog::info!(state.log, "DirectImport works");
+                }
+                true
+            }
+            Err(err) if !state.copy_path.allow_cpu() => return Err(err),
+            Err(err) => {
+                // the cause is only interesting once, the fallback keeps working after all
+                let failure = ImportFailure::of(&buf, format!("{:#}", err), false);
+                let message = failure.to_string();
+                if state.copy_path.record_failure(failure) {
+                    slog::info!(state.log, "DirectImport failed for {}", message);
+                } else {
+                    slog::debug!(state.log, "DirectImport failed for {}", message);
+                }
+                if state.copy_path.import_failed(format) {
+                    slog::warn!(
+                        state.log,
+                        "DirectImport of {:?} keeps failing, copying through the cpu until the format changes",
+                        format
+                    );
+                }
+                false
+            }
+        };
+    let (path, displayed) = if imported {
+        let target = active_target(&mut state.target);
+        wait_for_producer(&mut target.renderer, target.sync, &buf, &state.log);
+        (CopyState::DirectImport, Some(captured))
+    } else {
+        if let Some(render) = state.render.as_mut() {
+            wait_for_producer(&mut render.renderer, render.sync, &buf, &state.log);
+        }
+        // also taken for formats we can't import, compositors switch e.g. to yuv during direct scanout
+        copy_fallback(state, source, &buf, captured)?
+    };
+    // asynchronous readbacks are still reading, the vulkan copy waited for its fence already
+    let mut release = match (path, state.render.as_mut()) {
+        (CopyState::CPUCopy, Some(render)) => release_fence(&mut render.renderer, render.sync)?,
+        _ => None,
+    };
+    set_copy_path(state, path);
+    trace(state, Stage::CopyEnd);
+
+    match displayed {
+        Some(captured) => frame_available(state, captured)?,
+        // asynchronous readbacks and conversions need another frame,
+        // which is not triggered by a vblank as nothing was swapped
+        None => state.retry.again(),
+    }
+    if path == CopyState::DirectImport {
+        let target = active_target(&mut state.target);
+        release = release_fence(&mut target.renderer, target.sync)?;
+    }
+    Ok(release.map(Release::Fence).unwrap_or(Release::Now))
+}
+
+/// Logs a stage of the frame being rendered, with `--trace-frames`
+fn trace(state: &WaylandState, stage: Stage) {
+    if let Some(tracer) = state.tracer.as_ref() {
+        tracer.current(stage);
+    }
+}
+
+fn set_copy_path(state: &mut WaylandState, path: CopyState) {
+    if state.copy != Some(path) {
+        slog::info!(state.log, "Copy path: {:?}", path);
+        state.copy = Some(path);
+        state.events.emit(Event::CopyPath(path));
+    }
+}
+
+/// Renders a frame of `source` from cpu memory, as delivered by the screencopy backend.
+///
+/// `image` needs to be in one of the formats known to `memory_layout`,
+/// `y_invert` marks images stored bottom to top.
+/// `damage` are the regions of `image` changed since the last frame, if known.
+#[allow(clippy::too_many_arguments)]
+pub fn render_bitmap(
+    state: &mut WaylandState,
+    source: usize,
+    image: &[u8],
+    format: Fourcc,
+    width: i32,
+    height: i32,
+    stride: i32,
+    y_invert: bool,
+    damage: Option<Vec<Rectangle<i32, BufferCoords>>>,
+    captured: Duration,
+) -> Result<()> {
+    if state.target.is_none() {
+        return Ok(());
+    }
+    let layout = memory_layout(format)
+        .with_context(|| format!("Unsupported format for cpu copies: {:?}", format))?;
+    resize_source(state, source, Size::from((width, height)))?;
+    // only copy the region we are actually going to display
+    let region = source_region(state, source, Size::from((width, height)), y_invert);
+    let depth = layout.depth.min(state.color_depth);
+    let row_len = region.size.w * 4;
+    wait_for_upload(state)?;
+    state.sources[source].buffer.resize((row_len * region.size.h) as usize, 0);
+    // the staging buffer keeps the undamaged parts of the last frame
+    let damage = damage
+        .filter(|_| can_update(state, source, region.size, depth))
+        .and_then(|damage| {
+            damage::merge(
+                damage
+                    .into_iter()
+                    .filter_map(|rect| damage::clip(rect, region))
+                    .collect(),
+            )
+        });
+    let unchanged = match state.idle_detect {
+        IdleDetect::Off => false,
+        IdleDetect::Damage => damage.as_ref().map(|damage| damage.is_empty()).unwrap_or(false),
+        IdleDetect::Hash => {
+            let hash = damage::sample_hash(image, stride as usize, Size::from((width, height)));
+            same_hash(&mut state.sources[source], hash, region.size, depth)
+        }
+    };
+    if still_idle(&mut state.sources[source], unchanged) {
+        slog::trace!(state.log, "Frame unchanged, skipping it");
+        // nothing is swapped, so no vblank triggers the next capture
+        if state.capture_rate == CaptureRate::VBlank {
+            state.retry.again();
+        }
+        return Ok(());
+    }
+    trace(state, Stage::CopyStart);
+    let rects = damage
+        .clone()
+        .unwrap_or_else(|| vec![Rectangle::from_loc_and_size((0, 0), region.size)]);
+    let pool = state
+        .converter
+        .as_ref()
+        .map(|converter| converter.pool())
+        .unwrap_or(&convert::Pool::SCALAR);
+    for rect in &rects {
+        let src = (region.loc.y + rect.loc.y) as usize * stride as usize + (region.loc.x + rect.loc.x) as usize * 4;
+        let dst = (rect.loc.y * row_len + rect.loc.x * 4) as usize;
+        pool.repack(
+            image.get(src..).unwrap_or_default(),
+            stride as usize,
+            &mut state.sources[source].buffer[dst..],
+            row_len as usize,
+            rect.size.w as usize * 4,
+            rect.size.h as usize,
+            layout,
+            depth,
+        );
+    }
+    upload(state, source, region.size, row_len, depth, damage)?;
+    trace(state, Stage::CopyEnd);
+    let current = &mut state.sources[source];
+    current.texture_src = Rectangle::from_loc_and_size((0, 0), region.size);
+    current.texture_flipped = y_invert;
+    current.texture_external = false;
+    current.shown = true;
+
+    frame_available(state, captured)
+}
+
+/// Names of the sources without a frame, at the top left corner of their `--layout` cell
+fn cell_labels(state: &WaylandState) -> Vec<(Point<f64, Physical>, Vec<String>)> {
     state
-        .target
-        .renderer
-        .bind(state.target.surface.clone())
-        .expect("Failed to bind surface");
-    let texture = &state.texture;
-    state
-        .target
-        .renderer
-        .render(
-            state.dest_size,
-            Transform::Normal,
-            |_, frame| {
-                frame.render_texture_at(texture, (0.0, 0.0).into(), 1, 1.0, Transform::Normal, 1.0)
-            },
-        )??;
-    match state.target.surface.swap_buffers() {
-        Err(SwapBuffersError::EGLSwapBuffers(x @ EGLError::Unknown(0x3353)))
-        | Err(SwapBuffersError::EGLSwapBuffers(x @ EGLError::Unknown(0x321c)))
-        | Err(SwapBuffersError::EGLSwapBuffers(x @ EGLError::BadSurface)) => {
-            slog::warn!(state.log, "Temporary Error: {:?}", x);
-            state
-                .try_again
-                .store(true, std::sync::atomic::Ordering::SeqCst);
-        }
-        Err(err) => panic!("Swapping buffers failed: {}", err),
-        _ => {}
+        .sources
+        .iter()
+        .filter(|source| !source.shown)
+        .filter_map(|source| {
+            let cell = source.cell?;
+            Some((cell.loc, vec![format!("{}: no signal", source.spec.selector.name())]))
+        })
+        .collect()
+}
+
+fn draw_labels(
+    frame: &mut Gles2Frame,
+    labels: &Option<(&Overlay, Vec<(Point<f64, Physical>, Vec<String>)>)>,
+) -> Result<(), Gles2Error> {
+    if let Some((overlay, labels)) = labels {
+        for (origin, lines) in labels {
+            overlay.draw_at(frame, *origin, lines)?;
+        }
+    }
+    Ok(())
+}
+
+/// Unchanged frames skipped in a row, after which one is shown anyway
+const IDLE_REFRESH: u32 = 60;
+
+/// Status shown by `--overlay`
+fn overlay_lines(state: &WaylandState) -> Vec<String> {
+    let (captured, displayed) = state.stats.fps();
+    let copy = match state.copy {
+        Some(copy) => format!("{:?}", copy),
+        None => String::from("-"),
     };
+    vec![
+        format!("CAPTURE {:.1} FPS", captured),
+        format!("DISPLAY {:.1} FPS", displayed),
+        format!("COPY {}", copy),
+        format!("ERROR {}", state.stats.last_error().unwrap_or("-")),
+    ]
+}
+
+/// The textures hold a new frame captured at `captured`.
+///
+/// Presented right away, unless captures are timer driven and the previous frame was not flipped yet.
+/// The newest frame is presented on the next vblank then.
+fn frame_available(state: &mut WaylandState, captured: Duration) -> Result<()> {
+    if state.capture_rate != CaptureRate::VBlank && state.swap_pending {
+        state.latest_frame = Some(captured);
+        return Ok(());
+    }
+    present(state, captured)
+}
+
+/// Presents the newest frame that arrived while the previous one was being flipped
+pub fn present_latest(state: &mut WaylandState) {
+    if let Some(captured) = state.latest_frame.take() {
+        if let Err(err) = present(state, captured) {
+            render_failed(state, err);
+        }
+    }
+}
+
+/// Draws the latest frame of every source that has one, the rest of the target shows the background
+fn present(state: &mut WaylandState, captured: Duration) -> Result<()> {
+    // frames still in flight when the output was paused
+    if state.target_paused {
+        return Ok(());
+    }
+    active_target(&mut state.target).bind().context("Failed to bind target")?;
+    draw_sources(state)?;
+    trace(state, Stage::RenderEnd);
+    if let Some(pacing) = state.pacing.as_mut() {
+        if pacing.defer(captured, stats::monotonic_now(), &state.log) {
+            return Ok(());
+        }
+    }
+    swap_frame(state, captured);
+
+    Ok(())
+}
+
+/// Draws the sources, the labels and the overlay into whatever the renderer of the target is bound to,
+/// in the surface size of the target
+fn draw_sources(state: &mut WaylandState) -> Result<()> {
+    let lines = state.overlay.as_ref().map(|_| overlay_lines(state));
+    let overlay = state.overlay.as_ref();
+    let labels = state.labels.as_ref().map(|labels| (labels, cell_labels(state)));
+    let background = state.background;
+    let dest_size = state.dest_size;
+    // the frames are in physical pixels just like the target
+    let sources = state
+        .sources
+        .iter()
+        .filter(|source| source.shown)
+        .map(|source| (source, source.destination(dest_size)))
+        .collect::<Vec<_>>();
+    let filter_kind = state.filter;
+    let linear_blending = state.linear_blending;
+    let target = active_target(&mut state.target);
+    let (surface_size, transform) = (target.surface_size(), target.transform);
+    let renderer = &mut target.renderer;
+    // imported textures change with every buffer, so this is simply done every frame
+    set_filters(renderer, &sources, filter_kind)?;
+    match state.adjust_shader.as_ref() {
+        Some(shader) if !state.adjustments.is_neutral() || linear_blending => {
+            // smithay can't apply the adjustments or blend in linear light, so our own shader draws in between
+            let adjustments = state.adjustments;
+            renderer.render(surface_size, transform, |_, frame| frame.clear(background))??;
+            renderer.with_context(|_renderer, gl| {
+                sources.iter().try_for_each(|(source, dst)| unsafe {
+                    shader.draw(
+                        gl,
+                        (source.texture.tex_id(), source.texture.size()),
+                        source.texture_external,
+                        source.texture_flipped,
+                        source.texture_src,
+                        source.transform,
+                        *dst,
+                        dest_size,
+                        transform,
+                        adjustments,
+                        // nearest filtered content, e.g. integer scaled by --filter auto, has nothing to blend
+                        linear_blending && filter_kind.resolve(source.upright_size(), *dst) == Filter::Linear,
+                    )
+                })
+            })??;
+            renderer.render(surface_size, transform, |_, frame| {
+                draw_labels(frame, &labels)?;
+                match (overlay, lines) {
+                    (Some(overlay), Some(lines)) => overlay.draw(frame, &lines),
+                    _ => Ok(()),
+                }
+            })??;
+        }
+        _ => {
+            renderer.render(surface_size, transform, |_, frame| {
+                frame.clear(background)?;
+                draw_textures(frame, &sources)?;
+                draw_labels(frame, &labels)?;
+                match (overlay, lines) {
+                    (Some(overlay), Some(lines)) => overlay.draw(frame, &lines),
+                    _ => Ok(()),
+                }
+            })??;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the filters of the textures of `sources` for drawing them into their destinations
+pub fn set_filters(
+    renderer: &mut Gles2Renderer,
+    sources: &[(&Source, Rectangle<f64, Physical>)],
+    filter_kind: FilterKind,
+) -> Result<(), Gles2Error> {
+    renderer.with_context(|_renderer, gl| unsafe {
+        for (source, dst) in sources {
+            let target = if source.texture_external {
+                ffi::TEXTURE_EXTERNAL_OES
+            } else {
+                ffi::TEXTURE_2D
+            };
+            gl.BindTexture(target, source.texture.tex_id());
+            set_filter(gl, target, filter_kind.resolve(source.upright_size(), *dst));
+            gl.BindTexture(target, 0);
+        }
+    })
+}
+
+/// Draws the textures of `sources` into their destinations, undoing the transforms of the sources
+pub fn draw_textures(
+    frame: &mut Gles2Frame,
+    sources: &[(&Source, Rectangle<f64, Physical>)],
+) -> Result<(), Gles2Error> {
+    for (source, dst) in sources {
+        frame.render_texture_from_to(&source.texture, source.texture_src, *dst, source.texture_transform(), 1.0)?;
+    }
+    Ok(())
+}
+
+/// Draws the frame as last shown into a framebuffer of its own and hands it to the target, which scans it out
+/// instead of black while the output is set up again, e.g. for a mode switch.
+///
+/// Does nothing if no source is shown, the output then shows the background anyway.
+pub fn freeze_frame(state: &mut WaylandState) -> Result<()> {
+    if state.target.is_none() || !state.sources.iter().any(|source| source.shown) {
+        return Ok(());
+    }
+    let target = active_target(&mut state.target);
+    let size = (target.surface_size().w, target.surface_size().h);
+    let blit = target.renderer.with_context(|_renderer, gl| {
+        let blit = BlitTarget::new(gl, size, ColorDepth::Eight);
+        unsafe { gl.BindFramebuffer(ffi::FRAMEBUFFER, blit.fbo) };
+        blit
+    })?;
+    let drawn = draw_sources(state);
+    let mut pixels = vec![0u8; size.0 as usize * size.1 as usize * 4];
+    let ptr = pixels.as_mut_ptr() as *mut _;
+    let target = active_target(&mut state.target);
+    target.renderer.with_context(|_renderer, gl| unsafe {
+        if drawn.is_ok() {
+            let (_, format, ty) = gl_format(ColorDepth::Eight);
+            read_pixels(gl, Rectangle::from_loc_and_size((0, 0), size), size.0 * 4, (format, ty), ptr);
+        }
+        gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+        blit.destroy(gl);
+    })?;
+    drawn?;
+    // rows of gl framebuffers start at the bottom
+    let pixels = pixels
+        .chunks_exact(size.0 as usize * 4)
+        .rev()
+        .flatten()
+        .copied()
+        .collect::<Vec<u8>>();
+    target.freeze(FrozenFrame { pixels, size })
+}
+
+/// Hands the rendered frame captured at `captured` to the display
+pub fn swap_frame(state: &mut WaylandState, captured: Duration) {
+    if state.target.is_some() && !state.target_paused && swap_buffers(state) {
+        state.stats.frame_swapped(captured);
+        if let Some(tracer) = state.tracer.as_mut() {
+            tracer.swapped();
+        }
+        state.swap_pending = true;
+        // with variable refresh the output follows our frames, so the next one is captured right away
+        if active_target(&mut state.target).vrr && state.capture_rate == CaptureRate::VBlank {
+            state.retry.again();
+        }
+    }
+}
+
+/// Hands the swapped frame to the display, once the requests to the compositor went out.
+///
+/// A timed out acquire leaves the previous frame on screen and is retried, instead of blocking the event loop.
+pub fn acquire_frame(state: &mut WaylandState) {
+    if state.target.is_none() || state.target_paused {
+        return;
+    }
+    let target = active_target(&mut state.target);
+    match target.acquire() {
+        Ok(()) => {}
+        Err(PresentError::Stream(NvEglError::AcquireTimeout)) => {
+            slog::debug!(state.log, "Output busy, acquiring the frame again");
+            // the vblank only follows the acquire, so something else needs to wake the event loop
+            state.retry.again();
+        }
+        // the frame is gone, the next one is rendered without waiting for a vblank
+        Err(err) if err.is_retryable() => {
+            state.swap_pending = false;
+            swap_failed(state, err);
+        }
+        Err(err) if !target.is_master() => {
+            state.swap_pending = false;
+            pause_target(state, &format!("acquire failed without drm master: {}", err));
+        }
+        Err(err) => state.fatal = Some(anyhow::anyhow!("Acquiring the frame failed: {}", err)),
+    }
+}
+
+/// Encodes the frame about to be swapped, recording stops on the first error while mirroring goes on
+#[cfg(feature = "nvenc")]
+fn record(state: &mut WaylandState) {
+    if let Some(recorder) = state.recorder.as_mut() {
+        let target = active_target(&mut state.target);
+        let (size, depth) = (target.surface_size(), target.depth);
+        if let Err(err) = recorder.record(&mut target.renderer, size, depth) {
+            slog::warn!(state.log, "Stopped recording: {:?}", err);
+            let mut recorder = state.recorder.take().unwrap();
+            recorder.stop(&mut active_target(&mut state.target).renderer);
+        }
+    }
+}
+
+/// Clears the target to the background color or shows the splash, used while there is nothing to mirror.
+pub fn blank(state: &mut WaylandState) -> Result<()> {
+    if let Some(pacing) = state.pacing.as_mut() {
+        pacing.cancel();
+    }
+    if state.target.is_none() || state.target_paused {
+        return Ok(());
+    }
+    active_target(&mut state.target).bind()?;
+    let lines = state.overlay.as_ref().map(|_| overlay_lines(state));
+    let overlay = state.overlay.as_ref();
+    let labels = state.labels.as_ref().map(|labels| (labels, cell_labels(state)));
+    let background = state.background;
+    // scaled just like the sources
+    let splash = state.splash.as_ref().and_then(|splash| splash.texture()).map(|(texture, size)| {
+        let full = Rectangle::from_loc_and_size((0, 0), size);
+        (texture, size, geometry::destination(size, full, state.dest_size))
+    });
+    let filter_kind = state.filter;
+    let target = active_target(&mut state.target);
+    let (surface_size, transform) = (target.surface_size(), target.transform);
+    if let Some((texture, size, mapping)) = splash {
+        target.renderer.with_context(|_renderer, gl| unsafe {
+            gl.BindTexture(ffi::TEXTURE_2D, texture.tex_id());
+            set_filter(gl, ffi::TEXTURE_2D, filter_kind.resolve(size, mapping.dst));
+            gl.BindTexture(ffi::TEXTURE_2D, 0);
+        })?;
+    }
+    target
+        .renderer
+        .render(surface_size, transform, |_, frame| {
+            frame.clear(background)?;
+            if let Some((texture, _, mapping)) = splash {
+                frame.render_texture_from_to(
+                    texture,
+                    mapping.src,
+                    mapping.dst,
+                    Transform::Normal,
+                    1.0,
+                )?;
+            }
+            draw_labels(frame, &labels)?;
+            match (overlay, lines) {
+                (Some(overlay), Some(lines)) => overlay.draw(frame, &lines),
+                _ => Ok(()),
+            }
+        })??;
+    swap_buffers(state);
 
     Ok(())
 }
+
+/// What happens once the same temporary swap error occurred `--swap-failure-limit` times in a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapFailurePolicy {
+    /// Recreate the context and stream of the target, like after a gpu reset
+    Recreate,
+    /// Give up and exit
+    Exit,
+}
+
+impl SwapFailurePolicy {
+    pub const VARIANTS: &'static [&'static str] = &["recreate", "exit"];
+}
+
+impl FromStr for SwapFailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<SwapFailurePolicy> {
+        match name {
+            "recreate" => Ok(SwapFailurePolicy::Recreate),
+            "exit" => Ok(SwapFailurePolicy::Exit),
+            x => anyhow::bail!("Unknown swap failure policy: {}", x),
+        }
+    }
+}
+
+/// Counts a temporary swap error, escalating according to the policy once it keeps happening
+fn swap_failed(state: &mut WaylandState, err: PresentError) {
+    state.stats.error(format!("Swap failed: {}", err));
+    let limit = state.swap_failure_limit;
+    let streak = state
+        .swap_failures
+        .entry(err.kind())
+        .or_insert_with(|| Streak::new(1, limit));
+    match streak.failed() {
+        Verdict::Quiet => slog::debug!(state.log, "Temporary Error: {}", err),
+        Verdict::Warn => slog::warn!(state.log, "Temporary Error: {} ({} in a row)", err, streak.count()),
+        Verdict::Escalate => {
+            let count = streak.count();
+            state.swap_failures.clear();
+            match state.swap_failure_policy {
+                SwapFailurePolicy::Recreate => {
+                    slog::error!(state.log, "{} swaps failed in a row with {}, recreating the output", count, err);
+                    state.target_lost = true;
+                }
+                SwapFailurePolicy::Exit => {
+                    state.fatal = Some(anyhow::anyhow!("{} swaps failed in a row: {}", count, err));
+                    return;
+                }
+            }
+        }
+    }
+    state.retry.failed(&state.log);
+}
+
+/// Returns if the frame was successfully queued for display
+fn swap_buffers(state: &mut WaylandState) -> bool {
+    // rendered frames are only shown once the renderer got the output back
+    if state.scanout.as_ref().map(|scanout| scanout.active()).unwrap_or(false) {
+        leave_scanout(state);
+        state.retry.again();
+        return false;
+    }
+    #[cfg(feature = "nvenc")]
+    record(state);
+    let target = active_target(&mut state.target);
+    let swap_interval = target.swap_interval;
+    match target.swap_buffers() {
+        // without vsync the previous flip is often still pending, which just drops this frame
+        Err(PresentError::Stream(NvEglError::ResourceBusy)) if swap_interval == 0 => {
+            slog::debug!(state.log, "Output busy, dropping frame");
+            state.retry.again();
+            false
+        }
+        Err(err) if err.is_retryable() => {
+            swap_failed(state, err);
+            false
+        }
+        // e.g. a VT switch without logind telling us, the output comes back once we are master again
+        Err(err) if !target.is_master() => {
+            pause_target(state, &format!("swap failed without drm master: {}", err));
+            false
+        }
+        Err(err) => {
+            state.fatal = Some(anyhow::anyhow!("Swapping buffers failed: {}", err));
+            false
+        }
+        Ok(()) => {
+            state.swap_failures.clear();
+            true
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use smithay::{
+    backend::allocator::{dmabuf::Dmabuf, Buffer, Format, Fourcc, Modifier},
+    reexports::drm::{
+        buffer::{Handle as BufferHandle, PlanarBuffer},
+        control::{crtc, framebuffer, Device as ControlDevice, PageFlipFlags},
+    },
+};
+use smithay_client_toolkit::reexports::{
+    client::Main,
+    protocols::wlr::unstable::export_dmabuf::v1::client::zwlr_export_dmabuf_frame_v1::ZwlrExportDmabufFrameV1,
+};
+
+use crate::gpu::Fd;
+
+use std::{collections::HashSet, os::unix::io::AsRawFd};
+
+/// `DRM_MODE_FB_MODIFIERS`, AddFB2 only reads the modifiers with it
+const FB_MODIFIERS: u32 = 1 << 1;
+
+#[repr(C)]
+struct GemClose {
+    handle: u32,
+    pad: u32,
+}
+
+nix::ioctl_write_ptr!(drm_gem_close, b'd', 0x09, GemClose);
+
+/// The planes of a dmabuf imported into the target device, to add a framebuffer for them
+struct ImportedPlanes {
+    size: (u32, u32),
+    format: Fourcc,
+    pitches: [u32; 4],
+    handles: [Option<BufferHandle>; 4],
+    offsets: [u32; 4],
+}
+
+impl PlanarBuffer for ImportedPlanes {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn format(&self) -> Fourcc {
+        self.format
+    }
+
+    fn pitches(&self) -> [u32; 4] {
+        self.pitches
+    }
+
+    fn handles(&self) -> [Option<BufferHandle>; 4] {
+        self.handles
+    }
+
+    fn offsets(&self) -> [u32; 4] {
+        self.offsets
+    }
+}
+
+/// A captured frame scanned out as is
+struct ScanoutFrame {
+    fb: framebuffer::Handle,
+    /// Keeps the compositor from reusing the buffer, `None` until `Scanout::hold`
+    frame: Option<Main<ZwlrExportDmabufFrameV1>>,
+}
+
+/// Flips captured dmabufs onto the crtc of the target, skipping the renderer entirely.
+///
+/// Only works if the display engine of the target can read the buffers of the compositor as they are,
+/// which is probed once per format and modifier. Frames are released to the compositor only once
+/// another buffer replaced them on screen.
+pub struct Scanout {
+    fd: Fd,
+    crtc: crtc::Handle,
+    /// Formats the target failed to import or scan out, which are not tried again
+    rejected: HashSet<Format>,
+    /// Flipped, but not on screen before the next vblank
+    pending: Option<ScanoutFrame>,
+    shown: Option<ScanoutFrame>,
+    /// Replaced by rendered frames, released on the next vblank
+    retired: Vec<ScanoutFrame>,
+    log: slog::Logger,
+}
+
+impl Scanout {
+    pub fn new(fd: Fd, crtc: crtc::Handle, log: slog::Logger) -> Scanout {
+        Scanout {
+            fd,
+            crtc,
+            rejected: HashSet::new(),
+            pending: None,
+            shown: None,
+            retired: Vec::new(),
+            log,
+        }
+    }
+
+    /// Whether frames of `format` are worth trying
+    pub fn accepts(&self, format: Format) -> bool {
+        !self.rejected.contains(&format)
+    }
+
+    /// Whether one of our framebuffers is on screen or about to be
+    pub fn active(&self) -> bool {
+        self.pending.is_some() || self.shown.is_some()
+    }
+
+    /// Whether the last flip did not complete yet
+    pub fn flip_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Imports `buf` on the target device and flips it onto the crtc.
+    ///
+    /// A failure rejects the format of `buf`, frames of it take the other copy paths from then on.
+    pub fn flip(&mut self, buf: &Dmabuf) -> Result<()> {
+        let result = self.import(buf).and_then(|fb| {
+            match self.fd.page_flip(self.crtc, fb, &[PageFlipFlags::PageFlipEvent], None) {
+                Ok(()) => Ok(fb),
+                Err(err) => {
+                    let _ = self.fd.destroy_framebuffer(fb);
+                    Err(err).context("Failed to flip the framebuffer")
+                }
+            }
+        });
+        match result {
+            Ok(fb) => {
+                self.pending = Some(ScanoutFrame { fb, frame: None });
+                Ok(())
+            }
+            Err(err) => {
+                self.rejected.insert(buf.format());
+                Err(err)
+            }
+        }
+    }
+
+    /// Adds a framebuffer for `buf`, the gem handles are not needed anymore afterwards
+    fn import(&self, buf: &Dmabuf) -> Result<framebuffer::Handle> {
+        let format = buf.format();
+        let mut planes = ImportedPlanes {
+            size: (buf.size().w as u32, buf.size().h as u32),
+            format: format.code,
+            pitches: [0; 4],
+            handles: [None; 4],
+            offsets: [0; 4],
+        };
+        let mut imported = Vec::new();
+        let mut result = Ok(());
+        let dmabuf_planes = buf.handles().zip(buf.offsets()).zip(buf.strides()).take(4);
+        for (i, ((fd, offset), stride)) in dmabuf_planes.enumerate() {
+            match self.fd.prime_fd_to_buffer(fd) {
+                Ok(handle) => {
+                    imported.push(handle);
+                    planes.handles[i] = Some(handle);
+                    planes.pitches[i] = stride;
+                    planes.offsets[i] = offset;
+                }
+                Err(err) => {
+                    result = Err(err).context("Failed to import the dmabuf");
+                    break;
+                }
+            }
+        }
+        let fb = result.and_then(|()| {
+            let (modifiers, flags) = match format.modifier {
+                Modifier::Invalid => ([None; 4], 0),
+                modifier => {
+                    let mut modifiers = [None; 4];
+                    modifiers.iter_mut().take(imported.len()).for_each(|m| *m = Some(modifier));
+                    (modifiers, FB_MODIFIERS)
+                }
+            };
+            self.fd
+                .add_planar_framebuffer(&planes, &modifiers, flags)
+                .with_context(|| format!("Failed to add a framebuffer for {:?}", format))
+        });
+        // the framebuffer references the buffer on its own, planes of the same buffer share the handle
+        imported.sort_unstable_by_key(|handle| u32::from(*handle));
+        imported.dedup();
+        for handle in imported {
+            let close = GemClose {
+                handle: handle.into(),
+                pad: 0,
+            };
+            if let Err(err) = unsafe { drm_gem_close(self.fd.as_raw_fd(), &close) } {
+                slog::debug!(self.log, "Failed to close gem handle: {}", err);
+            }
+        }
+        fb
+    }
+
+    /// Hands the frame of the last flip over, which is kept until another buffer replaced it on screen
+    pub fn hold(&mut self, frame: Main<ZwlrExportDmabufFrameV1>) {
+        match self.pending.as_mut() {
+            Some(pending) => pending.frame = Some(frame),
+            None => frame.destroy(),
+        }
+    }
+
+    /// Called on every vblank of the crtc, releases the frames that are not on screen anymore
+    pub fn flipped(&mut self) {
+        for retired in std::mem::take(&mut self.retired) {
+            self.release(retired);
+        }
+        if let Some(pending) = self.pending.take() {
+            if let Some(replaced) = self.shown.replace(pending) {
+                self.release(replaced);
+            }
+        }
+    }
+
+    /// Hands the crtc back to the renderer, our frames are released once its first frame was flipped
+    pub fn leave(&mut self) {
+        slog::info!(self.log, "Leaving direct scanout");
+        self.retired.extend(self.pending.take());
+        self.retired.extend(self.shown.take());
+    }
+
+    fn release(&self, scanout: ScanoutFrame) {
+        if let Err(err) = self.fd.destroy_framebuffer(scanout.fb) {
+            slog::debug!(self.log, "Failed to remove framebuffer: {}", err);
+        }
+        if let Some(frame) = scanout.frame {
+            frame.destroy();
+        }
+    }
+}
+
+impl Drop for Scanout {
+    fn drop(&mut self) {
+        let frames = self
+            .retired
+            .drain(..)
+            .chain(self.pending.take())
+            .chain(self.shown.take())
+            .collect::<Vec<_>>();
+        for frame in frames {
+            self.release(frame);
+        }
+    }
+}
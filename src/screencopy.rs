@@ -0,0 +1,362 @@
+use anyhow::{Context, Result};
+use nix::{
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+    },
+    unistd::{close, ftruncate},
+};
+use smithay::{
+    backend::allocator::Fourcc,
+    utils::{Buffer, Rectangle},
+};
+use smithay_client_toolkit::reexports::{
+    client::{
+        protocol::{wl_buffer, wl_output, wl_shm, wl_shm_pool},
+        Attached, DispatchData, Main,
+    },
+    protocols::wlr::unstable::screencopy::v1::client::{
+        zwlr_screencopy_frame_v1::{self as screencopy_frame, Event as ScreencopyEvent},
+        zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1 as ScreencopyManager,
+    },
+};
+
+use crate::{capture::{self, CaptureBackend}, render, stats, trace::Stage, WaylandState};
+
+use std::{cell::{Cell, RefCell}, convert::TryFrom, ffi::CString, os::unix::io::RawFd, rc::Rc};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferInfo {
+    pub format: wl_shm::Format,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+}
+
+/// What we learned about a frame from the events preceding `Ready`
+#[derive(Default)]
+struct FrameInfo {
+    /// Shm buffers the compositor offers, version 3 announces several
+    offers: Vec<BufferInfo>,
+    /// Formats of the dmabufs the compositor offers, only logged as we copy through shm
+    dmabuf_offers: Vec<Fourcc>,
+    y_invert: bool,
+    /// `None` if the compositor does not report damage or the buffer was just allocated
+    damage: Option<Vec<Rectangle<i32, Buffer>>>,
+    /// Number of `--trace-frames`
+    trace_id: Option<u64>,
+}
+
+/// `wl_shm` uses drm fourcc codes, except for the two mandatory formats
+pub fn shm_fourcc(format: wl_shm::Format) -> Option<Fourcc> {
+    match format {
+        wl_shm::Format::Argb8888 => Some(Fourcc::Argb8888),
+        wl_shm::Format::Xrgb8888 => Some(Fourcc::Xrgb8888),
+        format => Fourcc::try_from(format.to_raw()).ok(),
+    }
+}
+
+/// A memfd-backed `wl_buffer` the compositor copies the output contents into
+pub struct ShmBuffer {
+    fd: RawFd,
+    ptr: *mut nix::libc::c_void,
+    size: usize,
+    pool: Main<wl_shm_pool::WlShmPool>,
+    buffer: Main<wl_buffer::WlBuffer>,
+    pub info: BufferInfo,
+}
+
+impl ShmBuffer {
+    fn new(shm: &Attached<wl_shm::WlShm>, info: BufferInfo) -> Result<ShmBuffer> {
+        let size = (info.stride * info.height) as usize;
+        let name = CString::new("nvscreencopy").unwrap();
+        let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+            .with_context(|| "Failed to create shm file")?;
+        if let Err(err) = ftruncate(fd, size as nix::libc::off_t) {
+            let _ = close(fd);
+            return Err(err).with_context(|| "Failed to resize shm file");
+        }
+        let ptr = match unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                size,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )
+        } {
+            Ok(ptr) => ptr,
+            Err(err) => {
+                let _ = close(fd);
+                return Err(err).with_context(|| "Failed to map shm file");
+            }
+        };
+
+        let pool = shm.create_pool(fd, size as i32);
+        pool.quick_assign(|_, _, _| {});
+        let buffer = pool.create_buffer(
+            0,
+            info.width as i32,
+            info.height as i32,
+            info.stride as i32,
+            info.format,
+        );
+        // screencopy buffers are never attached, so we do not care about releases
+        buffer.quick_assign(|_, _, _| {});
+
+        Ok(ShmBuffer {
+            fd,
+            ptr,
+            size,
+            pool,
+            buffer,
+            info,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.size) }
+    }
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+        self.pool.destroy();
+        unsafe {
+            let _ = munmap(self.ptr, self.size);
+        }
+        let _ = close(self.fd);
+    }
+}
+
+pub struct ScreencopyBackend {
+    manager: Attached<ScreencopyManager>,
+    shm: Attached<wl_shm::WlShm>,
+    /// Buffer of every source, reused as long as its frames keep their size and format
+    buffers: Vec<Rc<RefCell<Option<ShmBuffer>>>>,
+    /// Whether a capture of the source is in flight, with damage it only completes once there is new content
+    in_flight: Vec<Rc<Cell<bool>>>,
+}
+
+impl ScreencopyBackend {
+    pub fn new(
+        manager: Attached<ScreencopyManager>,
+        shm: Attached<wl_shm::WlShm>,
+    ) -> ScreencopyBackend {
+        ScreencopyBackend {
+            manager,
+            shm,
+            buffers: Vec::new(),
+            in_flight: Vec::new(),
+        }
+    }
+}
+
+impl CaptureBackend for ScreencopyBackend {
+    fn name(&self) -> &'static str {
+        "screencopy"
+    }
+
+    fn needs_render_gpu(&self) -> bool {
+        false
+    }
+
+    fn capture(&mut self, source: usize, output: &wl_output::WlOutput, state: &mut WaylandState) {
+        if self.buffers.len() <= source {
+            self.buffers.resize_with(source + 1, Default::default);
+            self.in_flight.resize_with(source + 1, Default::default);
+        }
+        let mut info = FrameInfo {
+            trace_id: state.tracer.as_mut().map(|tracer| tracer.requested()),
+            ..FrameInfo::default()
+        };
+        let frame = self.manager.capture_output(0, output);
+        let shm = self.shm.clone();
+        let buffer = self.buffers[source].clone();
+        let in_flight = self.in_flight[source].clone();
+        in_flight.set(true);
+        frame.quick_assign(move |frame, event, data| {
+            handle_frame(frame, event, data, &shm, source, &buffer, &in_flight, &mut info)
+        });
+    }
+
+    /// Another capture while one is in flight only repeats the frame that one brings
+    fn new_content(&self, source: usize, _state: &WaylandState) -> bool {
+        !self.in_flight.get(source).map(|in_flight| in_flight.get()).unwrap_or(false)
+    }
+}
+
+/// Returns whether damage can be tracked, which is not the case for newly allocated buffers
+pub fn start_copy(
+    frame: &Main<screencopy_frame::ZwlrScreencopyFrameV1>,
+    shm: &Attached<wl_shm::WlShm>,
+    buffer: &RefCell<Option<ShmBuffer>>,
+    info: BufferInfo,
+    with_damage: bool,
+) -> Result<bool> {
+    let mut buffer = buffer.borrow_mut();
+    let reused = buffer.as_ref().map(|buf| buf.info == info).unwrap_or(false);
+    if !reused {
+        *buffer = Some(ShmBuffer::new(shm, info)?);
+    }
+    let buffer = &buffer.as_ref().unwrap().buffer;
+    // damage events need version 2
+    if with_damage && frame.as_ref().version() >= 2 {
+        frame.copy_with_damage(buffer);
+        Ok(reused)
+    } else {
+        frame.copy(buffer);
+        Ok(false)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_frame(
+    frame: Main<screencopy_frame::ZwlrScreencopyFrameV1>,
+    event: ScreencopyEvent,
+    mut data: DispatchData,
+    shm: &Attached<wl_shm::WlShm>,
+    source: usize,
+    buffer: &RefCell<Option<ShmBuffer>>,
+    in_flight: &Cell<bool>,
+    info: &mut FrameInfo,
+) {
+    let state: &mut WaylandState = data.get().unwrap();
+    let done = matches!(event, ScreencopyEvent::Ready { .. } | ScreencopyEvent::Failed);
+    if let Err(err) = frame_event(&frame, event, state, shm, source, buffer, info) {
+        in_flight.set(false);
+        frame.destroy();
+        capture::frame_failed(state, err);
+    } else if done {
+        in_flight.set(false);
+    }
+}
+
+/// Handles an event of `frame`, on errors the caller drops the frame
+fn frame_event(
+    frame: &Main<screencopy_frame::ZwlrScreencopyFrameV1>,
+    event: ScreencopyEvent,
+    state: &mut WaylandState,
+    shm: &Attached<wl_shm::WlShm>,
+    source: usize,
+    buffer: &RefCell<Option<ShmBuffer>>,
+    info: &mut FrameInfo,
+) -> Result<()> {
+    match event {
+        ScreencopyEvent::Buffer {
+            format,
+            width,
+            height,
+            stride,
+        } => {
+            let buffer_info = BufferInfo {
+                format,
+                width,
+                height,
+                stride,
+            };
+            // one event per buffer type the compositor offers
+            if info.offers.is_empty() {
+                if let (Some(tracer), Some(id)) = (state.tracer.as_ref(), info.trace_id) {
+                    tracer.record(id, Stage::Metadata);
+                }
+            }
+            info.offers.push(buffer_info);
+            // version 3 announces all buffer types first and signals the end with `buffer_done`
+            if frame.as_ref().version() < 3 {
+                let code = shm_fourcc(format).with_context(|| format!("Unknown shm format {:?}", format))?;
+                state.constraints.check(code)?;
+                let tracked = start_copy(frame, shm, buffer, buffer_info, state.damage_tracking)
+                    .context("Failed to allocate shm buffer")?;
+                info.damage = tracked.then(Vec::new);
+            }
+        }
+        ScreencopyEvent::BufferDone => {
+            if info.offers.is_empty() {
+                anyhow::bail!("BufferDone event without shm Buffer event");
+            }
+            let codes = info
+                .offers
+                .iter()
+                .map(|offer| shm_fourcc(offer.format))
+                .collect::<Vec<_>>();
+            let usable = codes.iter().flatten().copied().collect::<Vec<_>>();
+            let picked = state.constraints.pick_shm(&codes);
+            let log = state.log.clone();
+            state
+                .constraints
+                .report(&usable, &info.dmabuf_offers, picked.and_then(|idx| codes[idx]), &log);
+            let buffer_info = match picked {
+                Some(idx) => info.offers[idx],
+                None => anyhow::bail!("None of the shm formats {:?} the compositor offers can be used", usable),
+            };
+            let tracked = start_copy(frame, shm, buffer, buffer_info, state.damage_tracking)
+                .context("Failed to allocate shm buffer")?;
+            info.damage = tracked.then(Vec::new);
+        }
+        ScreencopyEvent::Ready {
+            tv_sec_hi,
+            tv_sec_lo,
+            tv_nsec,
+        } => {
+            slog::debug!(state.log, "Frame ready");
+            if let (Some(tracer), Some(id)) = (state.tracer.as_mut(), info.trace_id) {
+                tracer.record(id, Stage::Ready);
+                tracer.begin(id);
+            }
+            let buffer = buffer.borrow();
+            let buffer = buffer.as_ref().context("Ready event before copy")?;
+            let format = shm_fourcc(buffer.info.format)
+                .with_context(|| format!("Unknown shm format {:?}", buffer.info.format))?;
+            state.retry.succeeded();
+            state.stats.frame_captured();
+            state.stats.source_frame();
+            let rendered = render::render_bitmap(
+                state,
+                source,
+                buffer.data(),
+                format,
+                buffer.info.width as i32,
+                buffer.info.height as i32,
+                buffer.info.stride as i32,
+                info.y_invert,
+                info.damage.take(),
+                stats::protocol_timestamp(tv_sec_hi, tv_sec_lo, tv_nsec),
+            );
+            frame.destroy();
+            match rendered {
+                Ok(()) => state.render_failures = 0,
+                Err(err) => render::render_failed(state, err),
+            }
+        }
+        ScreencopyEvent::Failed => {
+            slog::debug!(state.log, "Frame copy failed");
+            state.stats.error("Frame copy failed");
+            frame.destroy();
+            state.retry.failed(&state.log);
+        }
+        ScreencopyEvent::Flags { flags } => {
+            info.y_invert = flags.contains(screencopy_frame::Flags::YInvert);
+        }
+        ScreencopyEvent::Damage {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            if let Some(damage) = info.damage.as_mut() {
+                damage.push(Rectangle::from_loc_and_size(
+                    (x as i32, y as i32),
+                    (width as i32, height as i32),
+                ));
+            }
+        }
+        ScreencopyEvent::LinuxDmabuf { format, .. } => {
+            info.dmabuf_offers.extend(Fourcc::try_from(format).ok());
+        }
+        _ => anyhow::bail!("Unknown screencopy event"),
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use nix::{
+    fcntl::OFlag,
+    libc::{dev_t, major, minor},
+};
+use smithay::{
+    backend::session::{
+        dbus::logind::{LogindSession, LogindSessionNotifier},
+        Session as _, Signal as SessionSignal,
+    },
+    signaling::{SignalToken, Signaler},
+};
+
+use crate::gpu::{self, Fd};
+
+use std::{cell::Cell, fs::File, os::unix::io::FromRawFd, path::Path, rc::Rc, str::FromStr};
+
+/// Session backend requested on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionKind {
+    Auto,
+    Logind,
+    Direct,
+}
+
+impl SessionKind {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "logind", "direct"];
+}
+
+impl FromStr for SessionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<SessionKind> {
+        match name {
+            "auto" => Ok(SessionKind::Auto),
+            "logind" => Ok(SessionKind::Logind),
+            "direct" => Ok(SessionKind::Direct),
+            x => anyhow::bail!("Unknown session backend: {}", x),
+        }
+    }
+}
+
+/// Whether the target may be used, as last announced by the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    Paused,
+    Resumed,
+}
+
+/// Latest event of the target device, not handled yet
+pub type SessionEvents = Rc<Cell<Option<SessionEvent>>>;
+
+/// How the target device is opened
+pub enum Session {
+    /// Takes the device from logind, which also makes us drm master while our session is active
+    Logind(LogindSession),
+    /// Opens the device ourselves, which needs permissions on the device and nobody else being master
+    Direct,
+}
+
+impl Session {
+    /// Returns the notifier for the event loop as well, if the session sends signals
+    pub fn new(kind: SessionKind, log: &slog::Logger) -> Result<(Session, Option<LogindSessionNotifier>)> {
+        match kind {
+            SessionKind::Direct => Ok((Session::Direct, None)),
+            SessionKind::Logind => {
+                let (session, notifier) =
+                    LogindSession::new(log.clone()).with_context(|| "Failed to connect to logind")?;
+                Ok((Session::Logind(session), Some(notifier)))
+            }
+            SessionKind::Auto => match LogindSession::new(log.clone()) {
+                Ok((session, notifier)) => Ok((Session::Logind(session), Some(notifier))),
+                Err(err) => {
+                    slog::info!(log, "No logind session ({}), opening the target directly", err);
+                    Ok((Session::Direct, None))
+                }
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Session::Logind(_) => "logind",
+            Session::Direct => "direct",
+        }
+    }
+
+    /// Opens the target device for modesetting
+    pub fn open_target(&mut self, path: &Path) -> Result<Fd> {
+        match self {
+            Session::Logind(session) => {
+                let fd = session
+                    .open(path, OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)
+                    .with_context(|| format!("logind refused to hand out {}", path.display()))?;
+                Ok(Fd::new(unsafe { File::from_raw_fd(fd) }))
+            }
+            Session::Direct => {
+                let fd = Fd::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+                gpu::acquire_master(&fd, path)?;
+                Ok(fd)
+            }
+        }
+    }
+}
+
+/// Forwards pauses and resumes of the session or of the device `rdev` into `events`
+pub fn listen(signaler: &Signaler<SessionSignal>, rdev: dev_t, events: SessionEvents, log: slog::Logger) -> SignalToken {
+    let target = (major(rdev), minor(rdev));
+    signaler.register(move |signal| {
+        let event = match *signal {
+            SessionSignal::PauseSession => SessionEvent::Paused,
+            SessionSignal::ActivateSession => SessionEvent::Resumed,
+            SessionSignal::PauseDevice { major, minor } if (major, minor) == target => SessionEvent::Paused,
+            SessionSignal::ActivateDevice { major, minor, new_fd } if (major, minor) == target => {
+                if new_fd.is_some() {
+                    slog::warn!(log, "logind replaced the fd of the target, which is not supported");
+                }
+                SessionEvent::Resumed
+            }
+            _ => return,
+        };
+        slog::info!(log, "Target {:?}", event);
+        events.set(Some(event));
+    })
+}
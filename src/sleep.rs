@@ -0,0 +1,94 @@
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    libc,
+    time::{clock_gettime, ClockId},
+    unistd::{close, read},
+};
+
+use std::{os::unix::io::RawFd, time::Duration};
+
+/// Drift between the clocks that is not taken as a suspend
+const MIN_SLEEP: Duration = Duration::from_secs(1);
+
+/// TFD_TIMER_CANCEL_ON_SET of linux/timerfd.h
+const TFD_TIMER_CANCEL_ON_SET: i32 = 1 << 1;
+
+/// Notices system suspends, during which the monotonic clock stops while the boottime clock keeps going.
+///
+/// Works without logind, unlike listening for PrepareForSleep.
+pub struct SleepDetector {
+    offset: Duration,
+    /// Timer on the realtime clock, which becomes readable when the clock jumps, as it does on resume
+    fd: RawFd,
+}
+
+impl SleepDetector {
+    pub fn new() -> Result<SleepDetector> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_REALTIME, libc::TFD_CLOEXEC | libc::TFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(anyhow::Error::new(Errno::last()).context("Failed to create a timerfd"));
+        }
+        let detector = SleepDetector {
+            offset: time_asleep(),
+            fd,
+        };
+        detector.arm()?;
+        Ok(detector)
+    }
+
+    /// Becomes readable after a resume, `rearm` needs to follow
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Waits for the next jump of the clock, after the last one woke us up
+    pub fn rearm(&self) -> Result<()> {
+        let mut expirations = [0u8; 8];
+        match read(self.fd, &mut expirations) {
+            // ECANCELED reports the jump
+            Ok(_) | Err(nix::Error::Sys(Errno::ECANCELED)) | Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+            Err(err) => return Err(anyhow::Error::new(err).context("Failed to read the timerfd")),
+        }
+        self.arm()
+    }
+
+    /// Never expires, only the cancellation on a clock jump is of interest
+    fn arm(&self) -> Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+            it_value: libc::timespec {
+                tv_sec: libc::time_t::MAX,
+                tv_nsec: 0,
+            },
+        };
+        let flags = libc::TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET;
+        if unsafe { libc::timerfd_settime(self.fd, flags, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(anyhow::Error::new(Errno::last()).context("Failed to arm the timerfd"));
+        }
+        Ok(())
+    }
+
+    /// How long the system was suspended since the last call, `None` if it was not
+    pub fn check(&mut self) -> Option<Duration> {
+        let offset = time_asleep();
+        let slept = offset.saturating_sub(self.offset);
+        self.offset = offset;
+        Some(slept).filter(|slept| *slept >= MIN_SLEEP)
+    }
+}
+
+impl Drop for SleepDetector {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+/// Total time spent suspended since boot
+fn time_asleep() -> Duration {
+    let boot = clock_gettime(ClockId::CLOCK_BOOTTIME).expect("Boottime clock unavailable");
+    let monotonic = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("Monotonic clock unavailable");
+    let boot = Duration::new(boot.tv_sec() as u64, boot.tv_nsec() as u32);
+    let monotonic = Duration::new(monotonic.tv_sec() as u64, monotonic.tv_nsec() as u32);
+    boot.saturating_sub(monotonic)
+}
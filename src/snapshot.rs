@@ -0,0 +1,559 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use smithay::reexports::drm::control::{self, crtc, framebuffer, plane, Device as ControlDevice, Mode};
+
+use crate::{gpu, gpu::Fd, persist};
+
+use std::{
+    ffi::CStr,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Timings of a mode, the fields of `drm_mode_modeinfo`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeInfo {
+    pub clock: u32,
+    pub hdisplay: u16,
+    pub hsync_start: u16,
+    pub hsync_end: u16,
+    pub htotal: u16,
+    pub hskew: u16,
+    pub vdisplay: u16,
+    pub vsync_start: u16,
+    pub vsync_end: u16,
+    pub vtotal: u16,
+    pub vscan: u16,
+    pub vrefresh: u32,
+    pub flags: u32,
+    #[serde(rename = "type")]
+    pub kind: u32,
+    pub name: String,
+}
+
+impl From<Mode> for ModeInfo {
+    fn from(mode: Mode) -> ModeInfo {
+        let raw = drm_ffi::drm_mode_modeinfo::from(mode);
+        ModeInfo {
+            clock: raw.clock,
+            hdisplay: raw.hdisplay,
+            hsync_start: raw.hsync_start,
+            hsync_end: raw.hsync_end,
+            htotal: raw.htotal,
+            hskew: raw.hskew,
+            vdisplay: raw.vdisplay,
+            vsync_start: raw.vsync_start,
+            vsync_end: raw.vsync_end,
+            vtotal: raw.vtotal,
+            vscan: raw.vscan,
+            vrefresh: raw.vrefresh,
+            flags: raw.flags,
+            kind: raw.type_,
+            name: unsafe { CStr::from_ptr(raw.name.as_ptr()) }.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+impl From<&ModeInfo> for Mode {
+    fn from(info: &ModeInfo) -> Mode {
+        // the kernel needs the terminating nul
+        let mut name = [0; 32];
+        for (dst, src) in name.iter_mut().take(31).zip(info.name.bytes()) {
+            *dst = src as _;
+        }
+        Mode::from(drm_ffi::drm_mode_modeinfo {
+            clock: info.clock,
+            hdisplay: info.hdisplay,
+            hsync_start: info.hsync_start,
+            hsync_end: info.hsync_end,
+            htotal: info.htotal,
+            hskew: info.hskew,
+            vdisplay: info.vdisplay,
+            vsync_start: info.vsync_start,
+            vsync_end: info.vsync_end,
+            vtotal: info.vtotal,
+            vscan: info.vscan,
+            vrefresh: info.vrefresh,
+            flags: info.flags,
+            type_: info.kind,
+            name,
+        })
+    }
+}
+
+/// A connector along its name, which tells whether the handle still means the same connector
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectorRef {
+    pub handle: u32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gamma {
+    pub red: Vec<u16>,
+    pub green: Vec<u16>,
+    pub blue: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrtcState {
+    pub handle: u32,
+    /// `None` if the crtc was off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ModeInfo>,
+    #[serde(default)]
+    pub position: (u32, u32),
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framebuffer: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connectors: Vec<ConnectorRef>,
+    /// Only read for active crtcs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gamma: Option<Gamma>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaneState {
+    pub handle: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crtc: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framebuffer: Option<u32>,
+}
+
+/// The modesetting state of a drm device before nvscreencopy touched it, for `nvscreencopy restore`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub device: PathBuf,
+    /// `st_rdev` of `device`, which differs if another gpu took its name since
+    pub rdev: u64,
+    /// Handles and framebuffers of another boot mean nothing
+    pub boot_id: String,
+    /// Seconds since the epoch
+    pub taken_at: u64,
+    pub crtcs: Vec<CrtcState>,
+    pub planes: Vec<PlaneState>,
+}
+
+impl Snapshot {
+    /// Reads the state of every crtc and plane of `fd`, opened from `device`
+    pub fn take(fd: &Fd, device: &Path) -> Result<Snapshot> {
+        let rdev = nix::sys::stat::stat(device)
+            .with_context(|| format!("Failed to stat {}", device.display()))?
+            .st_rdev;
+        let res_handles = fd.resource_handles()?;
+        let mut connectors = Vec::new();
+        for handle in res_handles.connectors() {
+            let info = fd.get_connector(*handle)?;
+            let crtc = info
+                .current_encoder()
+                .and_then(|encoder| fd.get_encoder(encoder).ok())
+                .and_then(|encoder| encoder.crtc());
+            connectors.push((crtc, ConnectorRef { handle: u32::from(*handle), name: gpu::connector_name(&info) }));
+        }
+        let mut crtcs = Vec::new();
+        for handle in res_handles.crtcs() {
+            let info = fd.get_crtc(*handle)?;
+            let gamma = match (info.mode(), info.gamma_length() as usize) {
+                (Some(_), length) if length > 0 => {
+                    let mut gamma = Gamma {
+                        red: vec![0; length],
+                        green: vec![0; length],
+                        blue: vec![0; length],
+                    };
+                    // not every driver hands out the gamma ramp
+                    fd.get_gamma(*handle, &mut gamma.red, &mut gamma.green, &mut gamma.blue)
+                        .ok()
+                        .map(|_| gamma)
+                }
+                _ => None,
+            };
+            crtcs.push(CrtcState {
+                handle: u32::from(*handle),
+                mode: info.mode().map(ModeInfo::from),
+                position: info.position(),
+                framebuffer: info.framebuffer().map(u32::from),
+                connectors: connectors
+                    .iter()
+                    .filter(|(crtc, _)| *crtc == Some(*handle))
+                    .map(|(_, connector)| connector.clone())
+                    .collect(),
+                gamma,
+            });
+        }
+        let mut planes = Vec::new();
+        for handle in fd.plane_handles()?.planes() {
+            let info = fd.get_plane(*handle)?;
+            planes.push(PlaneState {
+                handle: u32::from(*handle),
+                crtc: info.crtc().map(u32::from),
+                framebuffer: info.framebuffer().map(u32::from),
+            });
+        }
+        Ok(Snapshot {
+            device: device.to_path_buf(),
+            rdev,
+            boot_id: boot_id()?,
+            taken_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0),
+            crtcs,
+            planes,
+        })
+    }
+
+    /// `$XDG_STATE_HOME/nvscreencopy/drm/<node>.json`, one snapshot per device
+    pub fn default_path(device: &Path) -> Option<PathBuf> {
+        let node = device.file_name()?;
+        Some(snapshot_dir()?.join(node).with_extension("json"))
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Replaces the file at once, a crash while writing it keeps the previous snapshot
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let text = serde_json::to_string_pretty(self).context("Failed to serialize the drm state")?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, text).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Fails if the snapshot was taken in another boot or the device node is another gpu by now
+    pub fn check_current(&self) -> Result<()> {
+        if self.boot_id != boot_id()? {
+            anyhow::bail!("The snapshot of {} is from a previous boot", self.device.display());
+        }
+        let rdev = nix::sys::stat::stat(&self.device)
+            .with_context(|| format!("Failed to stat {}", self.device.display()))?
+            .st_rdev;
+        if rdev != self.rdev {
+            anyhow::bail!("{} is another device than when the snapshot was taken", self.device.display());
+        }
+        Ok(())
+    }
+
+    /// Puts `fd` back into the recorded state, which needs drm master.
+    ///
+    /// Planes that were off are turned off first and crtcs that were off disabled, which frees their connectors,
+    /// before the active crtcs are set up again and then get their gamma back.
+    /// Objects that are gone or mean something else by now are skipped, as is a crtc whose framebuffer went away.
+    /// Returns the number of crtcs put back.
+    pub fn apply(&self, fd: &Fd, log: &slog::Logger) -> Result<usize> {
+        let res_handles = fd.resource_handles()?;
+        for state in self.planes.iter().filter(|state| state.framebuffer.is_none()) {
+            let handle = match control::from_u32::<plane::Handle>(state.handle) {
+                Some(handle) => handle,
+                None => continue,
+            };
+            let info = match fd.get_plane(handle) {
+                Ok(info) => info,
+                Err(_) => {
+                    slog::warn!(log, "Plane {} is gone, skipping it", state.handle);
+                    continue;
+                }
+            };
+            if let (Some(crtc), Some(_)) = (info.crtc(), info.framebuffer()) {
+                fd.set_plane(handle, crtc, None, 0, (0, 0, 0, 0), (0, 0, 0, 0))
+                    .with_context(|| format!("Failed to turn plane {} off", state.handle))?;
+            }
+        }
+
+        let mut active = Vec::new();
+        for state in self.crtcs.iter() {
+            let handle = match control::from_u32::<crtc::Handle>(state.handle) {
+                Some(handle) if res_handles.crtcs().contains(&handle) => handle,
+                _ => {
+                    slog::warn!(log, "Crtc {} is gone, skipping it", state.handle);
+                    continue;
+                }
+            };
+            let framebuffer = state
+                .framebuffer
+                .and_then(control::from_u32::<framebuffer::Handle>)
+                .filter(|framebuffer| fd.get_framebuffer(*framebuffer).is_ok());
+            match (&state.mode, framebuffer) {
+                (Some(_), Some(framebuffer)) => active.push((handle, state, framebuffer)),
+                (Some(_), None) => {
+                    // framebuffers die with the process that created them, e.g. a compositor that exited since
+                    slog::warn!(log, "The framebuffer of crtc {} is gone, turning it off instead", state.handle);
+                    fd.set_crtc(handle, None, (0, 0), &[], None)
+                        .with_context(|| format!("Failed to disable crtc {}", state.handle))?;
+                }
+                (None, _) => {
+                    if fd.get_crtc(handle)?.mode().is_some() {
+                        fd.set_crtc(handle, None, (0, 0), &[], None)
+                            .with_context(|| format!("Failed to disable crtc {}", state.handle))?;
+                    }
+                }
+            }
+        }
+
+        let mut restored = 0;
+        for (handle, state, framebuffer) in active.iter() {
+            let mut connectors = Vec::new();
+            for connector in state.connectors.iter() {
+                let current = control::from_u32(connector.handle)
+                    .filter(|handle| res_handles.connectors().contains(handle))
+                    .and_then(|handle| fd.get_connector(handle).ok());
+                match current {
+                    Some(info) if gpu::connector_name(&info) == connector.name => connectors.push(info.handle()),
+                    _ => slog::warn!(log, "Connector {} is gone, not attaching it", connector.name),
+                }
+            }
+            if connectors.is_empty() {
+                slog::warn!(log, "None of the connectors of crtc {} are left, skipping it", state.handle);
+                continue;
+            }
+            let mode = state.mode.as_ref().map(Mode::from);
+            fd.set_crtc(*handle, Some(*framebuffer), state.position, &connectors, mode)
+                .with_context(|| format!("Failed to set up crtc {} again", state.handle))?;
+            if let Some(gamma) = state.gamma.as_ref() {
+                let length = fd.get_crtc(*handle)?.gamma_length() as usize;
+                if length == gamma.red.len() {
+                    fd.set_gamma(*handle, &gamma.red, &gamma.green, &gamma.blue)
+                        .with_context(|| format!("Failed to set the gamma of crtc {}", state.handle))?;
+                }
+            }
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
+
+/// Directory of the snapshots of all devices, below the state directory of `persist`
+pub fn snapshot_dir() -> Option<PathBuf> {
+    persist::state_dir().map(|dir| dir.join("drm"))
+}
+
+/// Every snapshot in `snapshot_dir`, empty if there is none
+pub fn list() -> Result<Vec<PathBuf>> {
+    let dir = snapshot_dir().context("Neither XDG_STATE_HOME nor HOME is set")?;
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Restores `snapshot` with `fd` once the thread calling this panics, then aborts.
+///
+/// Unwinding on would drop the outputs into the restored state, so the process ends right away instead.
+/// Panics of other threads are reported as errors and recovered from, so they are left alone.
+pub fn restore_on_panic(fd: Fd, snapshot: Snapshot) {
+    let thread = std::thread::current().id();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        if std::thread::current().id() != thread {
+            return;
+        }
+        // the async logger would not get to write anything before the abort
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        match snapshot.apply(&fd, &log) {
+            Ok(_) => eprintln!("Restored the drm state of {}", snapshot.device.display()),
+            Err(err) => eprintln!(
+                "Failed to restore the drm state of {}, try `nvscreencopy restore`: {:#}",
+                snapshot.device.display(),
+                err
+            ),
+        }
+        std::process::abort();
+    }));
+}
+
+fn boot_id() -> Result<String> {
+    Ok(fs::read_to_string(BOOT_ID)
+        .with_context(|| format!("Failed to read {}", BOOT_ID))?
+        .trim()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mode_info(name: &str) -> ModeInfo {
+        ModeInfo {
+            clock: 148500,
+            hdisplay: 1920,
+            hsync_start: 2008,
+            hsync_end: 2052,
+            htotal: 2200,
+            hskew: 0,
+            vdisplay: 1080,
+            vsync_start: 1084,
+            vsync_end: 1089,
+            vtotal: 1125,
+            vscan: 0,
+            vrefresh: 60,
+            flags: 0x5,
+            kind: 0x48,
+            name: String::from(name),
+        }
+    }
+
+    fn snapshot(device: &Path, rdev: u64, boot_id: &str) -> Snapshot {
+        Snapshot {
+            device: device.to_path_buf(),
+            rdev,
+            boot_id: String::from(boot_id),
+            taken_at: 1_700_000_000,
+            crtcs: vec![
+                CrtcState {
+                    handle: 41,
+                    mode: Some(mode_info("1920x1080")),
+                    position: (0, 0),
+                    framebuffer: Some(97),
+                    connectors: vec![ConnectorRef {
+                        handle: 77,
+                        name: String::from("HDMI-A-1"),
+                    }],
+                    gamma: Some(Gamma {
+                        red: vec![0, 0x8000, 0xffff],
+                        green: vec![0, 0x7000, 0xffff],
+                        blue: vec![0, 0x6000, 0xffff],
+                    }),
+                },
+                CrtcState {
+                    handle: 42,
+                    mode: None,
+                    position: (0, 0),
+                    framebuffer: None,
+                    connectors: Vec::new(),
+                    gamma: None,
+                },
+            ],
+            planes: vec![
+                PlaneState {
+                    handle: 31,
+                    crtc: Some(41),
+                    framebuffer: Some(97),
+                },
+                PlaneState {
+                    handle: 32,
+                    crtc: None,
+                    framebuffer: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        // the snapshot directory is created on the first save
+        let path = dir.path().join("drm/card0.json");
+        let snapshot = snapshot(Path::new("/dev/dri/card0"), 0xe200, "b0e6a4b2");
+        snapshot.save(&path).unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+        assert_eq!(Snapshot::load(&path).unwrap(), snapshot);
+
+        // a later snapshot replaces the earlier one
+        let later = Snapshot {
+            taken_at: snapshot.taken_at + 60,
+            crtcs: Vec::new(),
+            ..snapshot
+        };
+        later.save(&path).unwrap();
+        assert_eq!(Snapshot::load(&path).unwrap(), later);
+    }
+
+    #[test]
+    fn serialization() {
+        let value = serde_json::to_value(snapshot(Path::new("/dev/dri/card0"), 0xe200, "b0e6a4b2")).unwrap();
+        assert_eq!(value["crtcs"][0]["mode"]["type"], json!(0x48));
+        assert_eq!(value["crtcs"][0]["connectors"], json!([{ "handle": 77, "name": "HDMI-A-1" }]));
+        // what is off or unset is left out
+        assert_eq!(value["crtcs"][1], json!({ "handle": 42, "position": [0, 0] }));
+        assert_eq!(value["planes"][1], json!({ "handle": 32 }));
+    }
+
+    #[test]
+    fn missing_fields_are_defaults() {
+        let snapshot = serde_json::from_value::<Snapshot>(json!({
+            "device": "/dev/dri/card1",
+            "rdev": 57857,
+            "boot_id": "b0e6a4b2",
+            "taken_at": 0,
+            "crtcs": [{ "handle": 42 }],
+            "planes": [{ "handle": 32 }],
+        }))
+        .unwrap();
+        assert_eq!(
+            snapshot.crtcs[0],
+            CrtcState {
+                handle: 42,
+                mode: None,
+                position: (0, 0),
+                framebuffer: None,
+                connectors: Vec::new(),
+                gamma: None,
+            }
+        );
+        assert_eq!(
+            snapshot.planes[0],
+            PlaneState {
+                handle: 32,
+                crtc: None,
+                framebuffer: None,
+            }
+        );
+    }
+
+    #[test]
+    fn unreadable_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("card0.json");
+        assert!(Snapshot::load(&path).is_err());
+        for text in ["", "{", "[]", r#"{ "device": "/dev/dri/card0" }"#] {
+            fs::write(&path, text).unwrap();
+            assert!(Snapshot::load(&path).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn modes_round_trip() {
+        let info = mode_info("1920x1080");
+        assert_eq!(ModeInfo::from(Mode::from(&info)), info);
+        // the name is cut to leave room for the nul
+        let long = mode_info(&"x".repeat(40));
+        assert_eq!(ModeInfo::from(Mode::from(&long)), mode_info(&"x".repeat(31)));
+    }
+
+    #[test]
+    fn stale_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        // a regular file has no device number, which stands in for the one of the node
+        let device = dir.path().join("card0");
+        fs::write(&device, "").unwrap();
+        let current = boot_id().unwrap();
+        assert!(snapshot(&device, 0, &current).check_current().is_ok());
+
+        let error = snapshot(&device, 0, "another boot").check_current().unwrap_err();
+        assert!(error.to_string().contains("previous boot"), "{:#}", error);
+        let error = snapshot(&device, 0xe200, &current).check_current().unwrap_err();
+        assert!(error.to_string().contains("another device"), "{:#}", error);
+        assert!(snapshot(&dir.path().join("card1"), 0, &current).check_current().is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use smithay::{
+    backend::renderer::{gles2::Gles2Texture, Texture, Transform},
+    utils::{Buffer, Physical, Point, Rectangle, Size},
+};
+
+use crate::{
+    capture::{Cadence, PendingFrame, ReadyFrame},
+    geometry,
+    gpu::ColorDepth,
+    import_cache::ImportCache,
+    source_match::{OutputProps, SourceSelector},
+};
+
+use std::{collections::VecDeque, fmt, str::FromStr, sync::atomic::AtomicBool};
+
+/// Source used if none is given, the headless output compositors create for mirroring
+pub const DEFAULT_SOURCE: &str = "headless";
+/// Connector types of built-in panels
+const INTERNAL_CONNECTORS: &[&str] = &["edp", "lvds", "dsi"];
+
+/// An output of the compositor, as listed if the source is missing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputEntry {
+    pub make: String,
+    pub model: String,
+    /// Name of the wl_output global in the registry
+    pub global_id: u32,
+    /// Size and refresh rate in mHz of the current mode
+    pub mode: Option<(i32, i32, i32)>,
+}
+
+impl OutputEntry {
+    pub fn props(&self) -> OutputProps<'_> {
+        OutputProps {
+            make: &self.make,
+            model: &self.model,
+            global_id: self.global_id,
+        }
+    }
+
+    /// Whether this is the built-in panel of a laptop, judged by the connector type some compositors put
+    /// into make or model
+    pub fn is_internal(&self) -> bool {
+        self.make
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .chain(self.model.split(|c: char| !c.is_ascii_alphanumeric()))
+            .any(|word| INTERNAL_CONNECTORS.contains(&word.to_ascii_lowercase().as_str()))
+    }
+}
+
+impl fmt::Display for OutputEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.make, self.model)?;
+        match self.mode {
+            Some((width, height, refresh)) => write!(f, " ({}x{}@{:.2}Hz)", width, height, refresh as f64 / 1000.0),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Output to mirror if the default source is missing, the only one that is no built-in panel
+pub fn fallback_source(outputs: &[OutputEntry]) -> Option<usize> {
+    let mut external = outputs
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| !output.is_internal());
+    match (external.next(), external.next()) {
+        (Some((index, _)), None) => Some(index),
+        _ => None,
+    }
+}
+
+/// A source given on the command line as "SELECTOR" or "SELECTOR@X,Y"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpec {
+    pub selector: SourceSelector,
+    /// Offset on the target, `None` stretches the source over the whole target
+    pub position: Option<Point<i32, Physical>>,
+    /// Picks among several matching outputs, counting from 0
+    pub index: Option<usize>,
+}
+
+impl SourceSpec {
+    pub fn matches(&self, output: OutputProps<'_>) -> bool {
+        self.selector.matches(output)
+    }
+
+    /// Whether this is the source used if none is given
+    pub fn is_default(&self) -> bool {
+        self.selector == SourceSelector::Substring(String::from(DEFAULT_SOURCE))
+    }
+}
+
+/// Index of the output `spec` selects, `None` if no output matches.
+///
+/// If several outputs match, `spec.index` picks among them in the order of `outputs`.
+/// Without it that is an error listing the matches, instead of guessing and mirroring the wrong monitor.
+pub fn select_output(outputs: &[OutputEntry], spec: &SourceSpec) -> anyhow::Result<Option<usize>> {
+    let matching = outputs
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| spec.matches(output.props()))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let listing = || {
+        matching
+            .iter()
+            .enumerate()
+            .map(|(i, index)| format!("\n  {}: {}", i, outputs[*index]))
+            .collect::<String>()
+    };
+    match (matching.as_slice(), spec.index) {
+        ([], _) => Ok(None),
+        (_, Some(index)) => match matching.get(index) {
+            Some(found) => Ok(Some(*found)),
+            None => anyhow::bail!(
+                "Source index {} is out of range, {} outputs match {}:{}",
+                index,
+                matching.len(),
+                spec.selector,
+                listing()
+            ),
+        },
+        ([found], None) => Ok(Some(*found)),
+        (_, None) => anyhow::bail!(
+            "{} outputs match source {}, pick one with --source-index or match the whole make with --source-exact:{}",
+            matching.len(),
+            spec.selector,
+            listing()
+        ),
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<SourceSpec> {
+        let (selector, position) = match input.rsplit_once('@') {
+            Some((selector, position)) => {
+                let parts = position
+                    .split(',')
+                    .map(|x| u32::from_str_radix(x, 10).map(|x| x as i32))
+                    .collect::<Result<Vec<i32>, _>>()
+                    .map_err(|err| anyhow::anyhow!("Failed to parse position of source: {}", err))?;
+                if parts.len() != 2 {
+                    anyhow::bail!("Source position needs to have the format \"NAME@X,Y\"");
+                }
+                (selector, Some(Point::from((parts[0], parts[1]))))
+            }
+            None => (input, None),
+        };
+        Ok(SourceSpec {
+            selector: selector.parse()?,
+            position,
+            index: None,
+        })
+    }
+}
+
+/// Everything tied to capturing and displaying one source output
+pub struct Source {
+    /// Selects the output, which is looked up again whenever it went away
+    pub spec: SourceSpec,
+    pub position: Option<Point<i32, Physical>>,
+    /// Region of the target `--layout` assigned, the source is letterboxed into it
+    pub cell: Option<Rectangle<f64, Physical>>,
+    /// Export-dmabuf frames in flight, oldest first
+    pub frames: VecDeque<PendingFrame>,
+    /// Newest complete frame, waiting for the target to take the previous one
+    pub ready: Option<ReadyFrame>,
+    /// The source output died and needs to be looked up again
+    pub source_lost: AtomicBool,
+    /// Scale factor of the source, frames are captured in physical pixels
+    pub scale: i32,
+    /// Size of the most recent frame, the source may change its mode at any time
+    pub frame_size: Size<i32, Buffer>,
+    /// Frame rate of the source, for capture backends that can't tell new content apart on their own
+    pub cadence: Cadence,
+    /// Region of `texture` holding the current frame
+    pub texture_src: Rectangle<i32, Buffer>,
+    /// The rows of `texture` are stored bottom to top
+    pub texture_flipped: bool,
+    /// Transform of the source output, which its frames are captured in and which is undone when drawing them.
+    /// Stays `Transform::Normal` without `--orientation-follow-source`.
+    pub transform: Transform,
+    /// `texture` is an imported yuv buffer, that needs to be sampled as external texture
+    pub texture_external: bool,
+    /// `texture` holds a frame, otherwise the region of the source shows the background
+    pub shown: bool,
+    pub buffer: Vec<u8>,
+    /// Last frame read back, to find the rows that changed
+    pub previous: Vec<u8>,
+    pub texture: Gles2Texture,
+    /// Texture frames copied through the cpu are uploaded into, displayed through `texture`
+    pub upload_texture: Gles2Texture,
+    /// Size and depth `upload_texture` was allocated for
+    pub upload_storage: Option<(Size<i32, Buffer>, ColorDepth)>,
+    /// Size and depth of the frame fully uploaded into `upload_texture`, which partial updates need to match
+    pub texture_content: Option<(Size<i32, Buffer>, ColorDepth)>,
+    /// Textures of recently imported buffers, for DirectImport
+    pub import_cache: ImportCache<Gles2Texture>,
+    /// Sample hash of the latest frame, for `--idle-detect hash`
+    pub idle_hash: Option<u64>,
+    /// Unchanged frames skipped in a row
+    pub idle_skips: u32,
+}
+
+impl Source {
+    /// `texture` needs to have been created for frames of `size` in `depth`,
+    /// which is the displayed region of frames of `frame_size`
+    pub fn new(
+        spec: SourceSpec,
+        scale: i32,
+        frame_size: Size<i32, Buffer>,
+        size: Size<i32, Buffer>,
+        texture: Gles2Texture,
+        depth: ColorDepth,
+        import_cache_size: usize,
+    ) -> Source {
+        Source {
+            position: spec.position,
+            cell: None,
+            spec,
+            frames: VecDeque::new(),
+            ready: None,
+            source_lost: AtomicBool::new(false),
+            scale,
+            frame_size,
+            cadence: Cadence::default(),
+            texture_src: Rectangle::from_loc_and_size((0, 0), size),
+            texture_flipped: false,
+            transform: Transform::Normal,
+            texture_external: false,
+            shown: false,
+            buffer: vec![0u8; (size.w * size.h * 4) as usize],
+            previous: Vec::new(),
+            upload_texture: texture.clone(),
+            texture,
+            upload_storage: Some((size, depth)),
+            texture_content: None,
+            import_cache: ImportCache::new(import_cache_size),
+            idle_hash: None,
+            idle_skips: 0,
+        }
+    }
+
+    /// Region of the target the source is drawn into, positioned sources are not scaled
+    pub fn destination(&self, dest_size: Size<i32, Physical>) -> Rectangle<f64, Physical> {
+        let size = self.upright_size();
+        match (self.position, self.cell) {
+            (Some(position), _) => Rectangle::from_loc_and_size(
+                (position.x as f64, position.y as f64),
+                (size.w as f64, size.h as f64),
+            ),
+            (None, Some(cell)) => geometry::letterbox(size, cell),
+            // a turned source would be squashed into the target
+            (None, None) if geometry::swaps_axes(self.transform) => {
+                geometry::letterbox(size, self.mapping(dest_size).dst)
+            }
+            (None, None) => self.mapping(dest_size).dst,
+        }
+    }
+
+    /// The displayed region of the texture stretched over a target of `dest_size`
+    fn mapping(&self, dest_size: Size<i32, Physical>) -> geometry::Mapping {
+        geometry::destination(self.texture.size(), self.texture_src, dest_size)
+    }
+
+    /// Size of the displayed region once `transform` is undone
+    pub fn upright_size(&self) -> Size<i32, Buffer> {
+        geometry::transformed_size(self.texture_src.size, self.transform)
+    }
+
+    /// Transform to draw `texture` with, undoing `transform` and the order of its rows
+    pub fn texture_transform(&self) -> Transform {
+        if self.texture_flipped {
+            geometry::compose(self.transform, Transform::Flipped180)
+        } else {
+            self.transform
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(make: &str, model: &str) -> OutputEntry {
+        OutputEntry {
+            make: make.to_string(),
+            model: model.to_string(),
+            global_id: 42,
+            mode: Some((1920, 1080, 60000)),
+        }
+    }
+
+    #[test]
+    fn internal_panels() {
+        // wlroots names outputs of unknown make after their connector
+        assert!(output("Unknown", "eDP-1").is_internal());
+        assert!(output("LVDS-1", "").is_internal());
+        assert!(output("Generic", "DSI-1 panel").is_internal());
+        assert!(!output("Dell Inc.", "DELL U2720Q").is_internal());
+        assert!(!output("Unknown", "DP-2").is_internal());
+        // only whole words count
+        assert!(!output("Redpoint", "HDMI-A-1").is_internal());
+    }
+
+    #[test]
+    fn fallback_to_the_only_external_output() {
+        let outputs = [output("Unknown", "eDP-1"), output("Dell Inc.", "DELL U2720Q")];
+        assert_eq!(fallback_source(&outputs), Some(1));
+        assert_eq!(fallback_source(&outputs[1..]), Some(0));
+    }
+
+    #[test]
+    fn no_fallback_if_ambiguous() {
+        let outputs = [
+            output("Unknown", "eDP-1"),
+            output("Dell Inc.", "DELL U2720Q"),
+            output("Unknown", "HDMI-A-1"),
+        ];
+        assert_eq!(fallback_source(&outputs), None);
+    }
+
+    #[test]
+    fn no_fallback_to_internal_panels() {
+        assert_eq!(fallback_source(&[output("Unknown", "eDP-1")]), None);
+        assert_eq!(fallback_source(&[]), None);
+    }
+
+    #[test]
+    fn output_listing() {
+        assert_eq!(
+            output("Dell Inc.", "DELL U2720Q").to_string(),
+            "Dell Inc. DELL U2720Q (1920x1080@60.00Hz)"
+        );
+        let unknown_mode = OutputEntry {
+            mode: None,
+            ..output("Unknown", "HDMI-A-1")
+        };
+        assert_eq!(unknown_mode.to_string(), "Unknown HDMI-A-1");
+    }
+
+    fn spec(input: &str, index: Option<usize>) -> SourceSpec {
+        SourceSpec {
+            index,
+            ..input.parse().unwrap()
+        }
+    }
+
+    /// Two DisplayPort monitors next to the headless output of sway
+    fn outputs() -> Vec<OutputEntry> {
+        vec![
+            output("DP-1", "DELL U2720Q"),
+            output("HEADLESS-1", ""),
+            output("DP-2", "LG 27UL850"),
+        ]
+    }
+
+    #[test]
+    fn select_the_only_match() {
+        assert_eq!(select_output(&outputs(), &spec("HEADLESS", None)).unwrap(), Some(1));
+        assert_eq!(select_output(&outputs(), &spec("model:LG", None)).unwrap(), Some(2));
+        assert_eq!(select_output(&outputs(), &spec("HDMI", None)).unwrap(), None);
+        assert_eq!(select_output(&[], &spec("HEADLESS", None)).unwrap(), None);
+    }
+
+    #[test]
+    fn refuse_ambiguous_sources() {
+        let err = select_output(&outputs(), &spec("DP", None)).unwrap_err().to_string();
+        assert!(err.starts_with("2 outputs match source DP"), "{}", err);
+        // the matches are listed with the index picking them
+        assert!(err.contains("\n  0: DP-1 DELL U2720Q") && err.contains("\n  1: DP-2 LG 27UL850"), "{}", err);
+        assert!(!err.contains("HEADLESS"), "{}", err);
+    }
+
+    #[test]
+    fn index_picks_among_matches() {
+        assert_eq!(select_output(&outputs(), &spec("DP", Some(0))).unwrap(), Some(0));
+        assert_eq!(select_output(&outputs(), &spec("DP", Some(1))).unwrap(), Some(2));
+        let err = select_output(&outputs(), &spec("DP", Some(2))).unwrap_err().to_string();
+        assert!(err.starts_with("Source index 2 is out of range, 2 outputs match DP"), "{}", err);
+        // an index for an unambiguous source needs to be in range as well
+        assert!(select_output(&outputs(), &spec("HEADLESS", Some(1))).is_err());
+    }
+
+    #[test]
+    fn exact_sources() {
+        let exact = SourceSpec {
+            selector: spec("DP-1", None).selector.exact(),
+            ..spec("DP-1", None)
+        };
+        assert_eq!(select_output(&outputs(), &exact).unwrap(), Some(0));
+        let prefix = SourceSpec {
+            selector: spec("DP", None).selector.exact(),
+            ..spec("DP", None)
+        };
+        assert_eq!(select_output(&outputs(), &prefix).unwrap(), None);
+    }
+
+    #[test]
+    fn default_source() {
+        assert!(DEFAULT_SOURCE.parse::<SourceSpec>().unwrap().is_default());
+        assert!(!"HDMI-A-1".parse::<SourceSpec>().unwrap().is_default());
+    }
+}
@@ -0,0 +1,198 @@
+use regex::Regex;
+
+use std::{fmt, str::FromStr};
+
+/// What the compositor tells about an output, which sources are selected by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputProps<'a> {
+    pub make: &'a str,
+    pub model: &'a str,
+    /// Name of the wl_output global in the registry
+    pub global_id: u32,
+}
+
+/// Selects outputs, given as "NAME", "exact:NAME", "regex:PATTERN" or "model:NAME".
+///
+/// wl_output does not tell serial numbers, so outputs can't be selected by them.
+/// Scripts knowing the registry name of an output select it by that instead, through `--source-global-id`.
+#[derive(Debug, Clone)]
+pub enum SourceSelector {
+    /// Part of the make
+    Substring(String),
+    /// The whole make
+    Exact(String),
+    /// Matched against the make, anchors are up to the pattern
+    Regex(Regex),
+    /// Part of the model, e.g. "DELL U2720Q"
+    Model(String),
+    /// The output with this registry name, which changes if it goes away and comes back
+    GlobalId(u32),
+}
+
+impl SourceSelector {
+    pub fn matches(&self, output: OutputProps<'_>) -> bool {
+        match self {
+            SourceSelector::Substring(name) => output.make.contains(name.as_str()),
+            SourceSelector::Exact(name) => output.make == name.as_str(),
+            SourceSelector::Regex(regex) => regex.is_match(output.make),
+            SourceSelector::Model(model) => output.model.contains(model.as_str()),
+            SourceSelector::GlobalId(id) => output.global_id == *id,
+        }
+    }
+
+    /// The name or pattern, without the kind of the selector
+    pub fn name(&self) -> String {
+        match self {
+            SourceSelector::Substring(name) | SourceSelector::Exact(name) | SourceSelector::Model(name) => name.clone(),
+            SourceSelector::Regex(regex) => regex.as_str().to_string(),
+            SourceSelector::GlobalId(id) => format!("global {}", id),
+        }
+    }
+
+    /// Matches the whole make instead of part of it, as `--source-exact` asks for
+    pub fn exact(self) -> SourceSelector {
+        match self {
+            SourceSelector::Substring(name) => SourceSelector::Exact(name),
+            selector => selector,
+        }
+    }
+}
+
+impl PartialEq for SourceSelector {
+    fn eq(&self, other: &SourceSelector) -> bool {
+        match (self, other) {
+            (SourceSelector::Substring(a), SourceSelector::Substring(b)) => a == b,
+            (SourceSelector::Exact(a), SourceSelector::Exact(b)) => a == b,
+            (SourceSelector::Regex(a), SourceSelector::Regex(b)) => a.as_str() == b.as_str(),
+            (SourceSelector::Model(a), SourceSelector::Model(b)) => a == b,
+            (SourceSelector::GlobalId(a), SourceSelector::GlobalId(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for SourceSelector {}
+
+impl FromStr for SourceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<SourceSelector> {
+        let selector = match input.split_once(':') {
+            Some(("exact", name)) => SourceSelector::Exact(name.to_string()),
+            Some(("regex", pattern)) => SourceSelector::Regex(
+                Regex::new(pattern).map_err(|err| anyhow::anyhow!("Invalid source regex {:?}: {}", pattern, err))?,
+            ),
+            Some(("model", model)) => SourceSelector::Model(model.to_string()),
+            // makes may contain colons themselves
+            _ => SourceSelector::Substring(input.to_string()),
+        };
+        match &selector {
+            SourceSelector::Substring(name) | SourceSelector::Exact(name) | SourceSelector::Model(name)
+                if name.is_empty() =>
+            {
+                anyhow::bail!("Source name is empty")
+            }
+            SourceSelector::Regex(regex) if regex.as_str().is_empty() => anyhow::bail!("Source regex is empty"),
+            _ => Ok(selector),
+        }
+    }
+}
+
+impl fmt::Display for SourceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceSelector::Substring(name) => write!(f, "{}", name),
+            SourceSelector::Exact(name) => write!(f, "exact:{}", name),
+            SourceSelector::Regex(regex) => write!(f, "regex:{}", regex.as_str()),
+            SourceSelector::Model(model) => write!(f, "model:{}", model),
+            SourceSelector::GlobalId(id) => write!(f, "global id {}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output<'a>(make: &'a str, model: &'a str) -> OutputProps<'a> {
+        OutputProps {
+            make,
+            model,
+            global_id: 7,
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            "headless".parse::<SourceSelector>().unwrap(),
+            SourceSelector::Substring(String::from("headless"))
+        );
+        assert_eq!(
+            "exact:HEADLESS-1".parse::<SourceSelector>().unwrap(),
+            SourceSelector::Exact(String::from("HEADLESS-1"))
+        );
+        assert_eq!(
+            "model:DELL U2720Q".parse::<SourceSelector>().unwrap(),
+            SourceSelector::Model(String::from("DELL U2720Q"))
+        );
+        assert_eq!(
+            "regex:^HEADLESS-[12]$".parse::<SourceSelector>().unwrap().name(),
+            "^HEADLESS-[12]$"
+        );
+        // unknown kinds are part of the make
+        assert_eq!(
+            "Unknown:Make".parse::<SourceSelector>().unwrap(),
+            SourceSelector::Substring(String::from("Unknown:Make"))
+        );
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        for input in ["", "exact:", "model:", "regex:"] {
+            assert!(input.parse::<SourceSelector>().is_err(), "{:?}", input);
+        }
+        let err = "regex:HEADLESS-[".parse::<SourceSelector>().unwrap_err().to_string();
+        assert!(err.starts_with("Invalid source regex \"HEADLESS-[\""), "{}", err);
+    }
+
+    #[test]
+    fn matching() {
+        let headless = output("HEADLESS-1", "headless");
+        let dell = output("Dell Inc.", "DELL U2720Q");
+        let matches = |input: &str| {
+            let selector = input.parse::<SourceSelector>().unwrap();
+            (selector.matches(headless), selector.matches(dell))
+        };
+        assert_eq!(matches("HEADLESS"), (true, false));
+        assert_eq!(matches("Dell"), (false, true));
+        // matching is case sensitive
+        assert_eq!(matches("dell"), (false, false));
+        assert_eq!(matches("exact:HEADLESS"), (false, false));
+        assert_eq!(matches("exact:HEADLESS-1"), (true, false));
+        assert_eq!(matches("regex:^HEADLESS-[12]$"), (true, false));
+        assert_eq!(matches("regex:^HEADLESS-[23]$"), (false, false));
+        assert_eq!(matches("regex:(?i)dell"), (false, true));
+        assert_eq!(matches("model:U2720Q"), (false, true));
+        assert_eq!(matches("model:HEADLESS-1"), (false, false));
+        assert!(SourceSelector::GlobalId(7).matches(headless));
+        assert!(!SourceSelector::GlobalId(8).matches(headless));
+    }
+
+    #[test]
+    fn exact_only_changes_substrings() {
+        let exact = |input: &str| input.parse::<SourceSelector>().unwrap().exact();
+        assert_eq!(exact("Dell"), SourceSelector::Exact(String::from("Dell")));
+        assert_eq!(exact("model:U2720Q"), SourceSelector::Model(String::from("U2720Q")));
+        assert_eq!(exact("regex:^D").to_string(), "regex:^D");
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for input in ["HEADLESS", "exact:HEADLESS-1", "regex:^HEADLESS-[12]$", "model:DELL U2720Q"] {
+            let selector = input.parse::<SourceSelector>().unwrap();
+            assert_eq!(selector.to_string(), input);
+            assert_eq!(selector.to_string().parse::<SourceSelector>().unwrap(), selector);
+        }
+    }
+}
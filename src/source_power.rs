@@ -0,0 +1,83 @@
+use smithay_client_toolkit::reexports::{
+    client::{protocol::wl_output::WlOutput, Attached, Main},
+    protocols::wlr::unstable::output_power_management::v1::client::{
+        zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+        zwlr_output_power_v1::{Event, Mode, ZwlrOutputPowerV1},
+    },
+};
+
+use std::time::{Duration, Instant};
+
+/// Powers the source outputs off while nobody looks at the mirror, for `--manage-source-power`.
+///
+/// Outputs are turned off once the mirror was idle for `grace` and turned on again before capturing resumes,
+/// as capturing an output that is off only fails.
+pub struct SourcePower {
+    manager: Attached<ZwlrOutputPowerManagerV1>,
+    grace: Duration,
+    /// Since when the mirror shows nothing, `None` while mirroring
+    idle_since: Option<Instant>,
+    /// Controls of the outputs we turned off
+    off: Vec<Main<ZwlrOutputPowerV1>>,
+    log: slog::Logger,
+}
+
+impl SourcePower {
+    pub fn new(manager: Attached<ZwlrOutputPowerManagerV1>, grace: Duration, log: slog::Logger) -> SourcePower {
+        SourcePower {
+            manager,
+            grace,
+            idle_since: None,
+            off: Vec::new(),
+            log,
+        }
+    }
+
+    /// Follows the mirror becoming idle or busy, turning `outputs` off once it was idle for the grace period.
+    ///
+    /// Becoming busy leaves the outputs off, `power_on` turns them on right before capturing.
+    pub fn update(&mut self, idle: bool, outputs: &[WlOutput]) {
+        if !idle {
+            self.idle_since = None;
+            return;
+        }
+        let since = *self.idle_since.get_or_insert_with(Instant::now);
+        if !self.off.is_empty() || since.elapsed() < self.grace || outputs.is_empty() {
+            return;
+        }
+        slog::info!(self.log, "Nothing shows the mirror for {:?}, powering the source outputs off", self.grace);
+        for output in outputs {
+            let power = self.manager.get_output_power(output);
+            let log = self.log.clone();
+            power.quick_assign(move |power, event, _| match event {
+                Event::Mode { mode } => slog::debug!(log, "Source output power mode: {:?}", mode),
+                Event::Failed => {
+                    slog::warn!(log, "The compositor can't power the source output off");
+                    power.destroy();
+                }
+                _ => {}
+            });
+            power.set_mode(Mode::Off);
+            self.off.push(power);
+        }
+    }
+
+    /// Turns the outputs we turned off on again, before capturing them
+    pub fn power_on(&mut self) {
+        if self.off.is_empty() {
+            return;
+        }
+        slog::info!(self.log, "Powering the source outputs on");
+        for power in self.off.drain(..) {
+            power.set_mode(Mode::On);
+            power.destroy();
+        }
+    }
+
+    /// When the grace period ends, `None` if the outputs are off already or the mirror is busy
+    pub fn deadline(&self) -> Option<Instant> {
+        self.idle_since
+            .filter(|_| self.off.is_empty())
+            .map(|since| since + self.grace)
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use smithay::{
+    backend::renderer::gles2::{Gles2Renderer, Gles2Texture},
+    utils::{Buffer, Rectangle, Size},
+};
+
+use crate::{
+    gpu::{ColorDepth, GlCapabilities},
+    render,
+};
+
+use std::path::Path;
+
+/// Image of `--splash`, shown instead of the background while there is nothing to mirror
+pub struct Splash {
+    /// 8 bit RGBA
+    pixels: Vec<u8>,
+    size: Size<i32, Buffer>,
+    /// `None` until uploaded to the current context of the target
+    texture: Option<Gles2Texture>,
+}
+
+impl Splash {
+    /// Decodes the PNG at `path`, any color type and bit depth is converted to 8 bit RGBA
+    pub fn load(path: &Path) -> Result<Splash> {
+        let image = image::io::Reader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .with_context(|| format!("Failed to open splash {}", path.display()))?
+            .decode()
+            .with_context(|| format!("Failed to decode splash {}", path.display()))?
+            .into_rgba8();
+        Ok(Splash {
+            size: Size::from((image.width() as i32, image.height() as i32)),
+            pixels: image.into_raw(),
+            texture: None,
+        })
+    }
+
+    /// Uploads the image to a new context of the target, the texture of the previous one is gone with it
+    pub fn upload(&mut self, renderer: &mut Gles2Renderer, caps: GlCapabilities) -> Result<()> {
+        let texture = render::create_texture(renderer, caps, self.size.w, self.size.h, ColorDepth::Eight)?;
+        render::update_bitmap(
+            renderer,
+            caps,
+            &texture,
+            &self.pixels,
+            self.size.w * 4,
+            &[Rectangle::from_loc_and_size((0, 0), self.size)],
+            ColorDepth::Eight,
+        )?;
+        self.texture = Some(texture);
+        Ok(())
+    }
+
+    pub fn texture(&self) -> Option<(&Gles2Texture, Size<i32, Buffer>)> {
+        self.texture.as_ref().map(|texture| (texture, self.size))
+    }
+}
@@ -0,0 +1,709 @@
+use anyhow::{Context, Result};
+use calloop::{generic::Generic, Interest, PostAction};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        socket::{
+            recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag,
+            SockType,
+        },
+        uio::IoVec,
+    },
+    unistd::{close, setpgid, Pid},
+};
+use smithay::{
+    backend::{
+        allocator::{
+            dmabuf::{Dmabuf, DmabufFlags},
+            Fourcc, Modifier,
+        },
+        renderer::{gles2::Gles2Texture, Frame, ImportDma, Renderer, Transform},
+    },
+    utils::{Buffer, Rectangle, Size},
+};
+
+use crate::{
+    convert,
+    gpu::ColorDepth,
+    raw::Sink,
+    render, stats,
+    test_pattern::Standalone,
+    Options, WaylandState,
+};
+
+use std::{
+    cell::{Cell, RefCell},
+    convert::{TryFrom, TryInto},
+    ffi::{CString, OsString},
+    fs::File,
+    io::Write,
+    os::unix::{
+        fs::FileExt,
+        io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process::{Child, Command},
+    rc::Rc,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Starts every header, followed by `VERSION`
+const MAGIC: [u8; 4] = *b"NVSF";
+const VERSION: u16 = 1;
+/// Bytes of an encoded `FrameHeader`
+pub const HEADER_LEN: usize = 40;
+const KIND_SHM: u16 = 1;
+const KIND_DMABUF: u16 = 2;
+
+/// Frames waiting to be sent, further ones are dropped instead of stalling the capture
+const QUEUE_LENGTH: usize = 2;
+/// Display processes running shorter than this count as crashed right away
+const STABLE_RUNTIME: Duration = Duration::from_secs(10);
+/// Crashes in a row after which the display process is not restarted anymore
+const MAX_RESTARTS: u32 = 5;
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How the pixels of a frame travel along its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    /// The fd is a memfd holding `len` bytes of pixels
+    Shm { len: u64 },
+    /// The fd is a single plane dmabuf, to be imported on the display gpu
+    Dmabuf { offset: u32, modifier: u64 },
+}
+
+/// Message sent for every frame of `--split-display`, the fd of the payload is attached as SCM_RIGHTS.
+///
+/// Encoded little-endian as magic, version, kind, width, height, stride, format and the fields of the payload,
+/// padded to `HEADER_LEN` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    /// Drm fourcc of the pixels
+    pub format: u32,
+    pub payload: Payload,
+}
+
+impl FrameHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        let (kind, wide, offset) = match self.payload {
+            Payload::Shm { len } => (KIND_SHM, len, 0),
+            Payload::Dmabuf { offset, modifier } => (KIND_DMABUF, modifier, offset),
+        };
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&kind.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.width.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.height.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.stride.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.format.to_le_bytes());
+        bytes[24..32].copy_from_slice(&wide.to_le_bytes());
+        bytes[32..36].copy_from_slice(&offset.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a header, failing on anything `encode` would not have produced or that does not describe a frame
+    pub fn decode(bytes: &[u8]) -> Result<FrameHeader> {
+        if bytes.len() != HEADER_LEN {
+            anyhow::bail!("Frame header has {} bytes instead of {}", bytes.len(), HEADER_LEN);
+        }
+        if bytes[0..4] != MAGIC {
+            anyhow::bail!("Frame header does not start with {:?}", MAGIC);
+        }
+        let u16_at = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        if u16_at(4) != VERSION {
+            anyhow::bail!("Frame header has version {}, expected {}", u16_at(4), VERSION);
+        }
+        let payload = match u16_at(6) {
+            KIND_SHM => Payload::Shm { len: u64_at(24) },
+            KIND_DMABUF => Payload::Dmabuf {
+                offset: u32_at(32),
+                modifier: u64_at(24),
+            },
+            kind => anyhow::bail!("Unknown payload kind {}", kind),
+        };
+        if bytes[36..40] != [0; 4] {
+            anyhow::bail!("Reserved bytes of the frame header are set");
+        }
+        let header = FrameHeader {
+            width: u32_at(8),
+            height: u32_at(12),
+            stride: u32_at(16),
+            format: u32_at(20),
+            payload,
+        };
+        if header.width == 0 || header.height == 0 {
+            anyhow::bail!("Frame of {}x{} is empty", header.width, header.height);
+        }
+        if (header.stride as u64) < header.width as u64 * 4 {
+            anyhow::bail!("Stride {} is too small for frames {} wide", header.stride, header.width);
+        }
+        if let Payload::Shm { len } = header.payload {
+            if len < header.stride as u64 * header.height as u64 {
+                anyhow::bail!(
+                    "{} bytes are too few for {} rows of {} bytes",
+                    len,
+                    header.height,
+                    header.stride
+                );
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// Sends `header` and `fd` as a single message.
+///
+/// Rust programs ignore SIGPIPE, so a display process that went away fails this with EPIPE.
+pub fn send_frame(socket: RawFd, header: &FrameHeader, fd: RawFd) -> nix::Result<()> {
+    let bytes = header.encode();
+    let fds = [fd];
+    sendmsg(
+        socket,
+        &[IoVec::from_slice(&bytes)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Outcome of `recv_frame`
+pub enum Received {
+    Frame(FrameHeader, File),
+    /// Nothing queued on the non-blocking socket
+    Empty,
+    /// The other process went away
+    Closed,
+}
+
+/// Receives the next message without blocking
+pub fn recv_frame(socket: RawFd) -> Result<Received> {
+    let mut bytes = [0u8; HEADER_LEN];
+    let mut cmsgs = nix::cmsg_space!([RawFd; 1]);
+    let msg = match recvmsg(
+        socket,
+        &[IoVec::from_mut_slice(&mut bytes)],
+        Some(&mut cmsgs),
+        MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_CMSG_CLOEXEC,
+    ) {
+        Ok(msg) => msg,
+        Err(nix::Error::Sys(Errno::EAGAIN)) => return Ok(Received::Empty),
+        Err(err) => return Err(err).context("Failed to receive frame"),
+    };
+    // take ownership of every fd first, so none leaks on errors
+    let mut files = msg
+        .cmsgs()
+        .filter_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmRights(fds) => Some(fds),
+            _ => None,
+        })
+        .flatten()
+        .map(|fd| unsafe { File::from_raw_fd(fd) })
+        .collect::<Vec<_>>();
+    if msg.bytes == 0 {
+        return Ok(Received::Closed);
+    }
+    if msg.flags.intersects(MsgFlags::MSG_TRUNC | MsgFlags::MSG_CTRUNC) {
+        anyhow::bail!("Frame message was truncated");
+    }
+    let header = FrameHeader::decode(&bytes[..msg.bytes])?;
+    if files.len() != 1 {
+        anyhow::bail!("Frame came with {} fds instead of one", files.len());
+    }
+    Ok(Received::Frame(header, files.pop().unwrap()))
+}
+
+/// How `--split-display` starts the display process, the fd of its end of the socket is appended to `args`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitCommand {
+    pub program: PathBuf,
+    pub args: Vec<OsString>,
+}
+
+/// A running display process along our end of its socket
+struct DisplayProcess {
+    child: Child,
+    socket: File,
+    started: Instant,
+}
+
+impl DisplayProcess {
+    fn spawn(command: &SplitCommand, log: &slog::Logger) -> Result<DisplayProcess> {
+        // packets keep the headers apart, the fds travel with them
+        let (ours, theirs) = socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)
+            .context("Failed to create socket for the display process")?;
+        let socket = unsafe { File::from_raw_fd(ours) };
+        let spawned = unsafe {
+            Command::new(&command.program)
+                .args(&command.args)
+                .arg(theirs.to_string())
+                .pre_exec(move || {
+                    fcntl(theirs, FcntlArg::F_SETFD(FdFlag::empty())).map_err(std::io::Error::from)?;
+                    // ctrl-c goes to us only, the display process exits once the socket closes
+                    setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(std::io::Error::from)?;
+                    Ok(())
+                })
+                .spawn()
+        };
+        let _ = close(theirs);
+        let child = spawned.with_context(|| format!("Failed to start display process {}", command.program.display()))?;
+        slog::info!(log, "Started display process {}", child.id());
+        Ok(DisplayProcess {
+            child,
+            socket,
+            started: Instant::now(),
+        })
+    }
+
+    /// Closes the socket, which makes the display process exit, and waits for it
+    fn stop(self, log: &slog::Logger) {
+        let DisplayProcess { mut child, socket, .. } = self;
+        drop(socket);
+        match child.wait() {
+            Ok(status) => slog::info!(log, "Display process {}", status),
+            Err(err) => slog::warn!(log, "Failed to wait for display process: {}", err),
+        }
+    }
+}
+
+/// Puts `pixels` into a memfd, which the display process reads them from
+fn shm_file(pixels: &[u8]) -> Result<File> {
+    let name = CString::new("nvscreencopy-frame").unwrap();
+    let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC).context("Failed to create shm file")?;
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(pixels).context("Failed to write shm file")?;
+    Ok(file)
+}
+
+/// One more nvidia gpu driven by a display process of its own, given as "N" or "N:CONNECTOR" to `--extra-target`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetSpec {
+    pub device_index: usize,
+    pub connector: Option<String>,
+}
+
+impl FromStr for TargetSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<TargetSpec> {
+        let (index, connector) = match input.split_once(':') {
+            Some((index, connector)) if connector.is_empty() => anyhow::bail!("Connector of {:?} is empty", index),
+            Some((index, connector)) => (index, Some(connector.to_string())),
+            None => (input, None),
+        };
+        Ok(TargetSpec {
+            device_index: usize::from_str_radix(index, 10)
+                .map_err(|err| anyhow::anyhow!("Failed to parse device index {:?}: {}", index, err))?,
+            connector,
+        })
+    }
+}
+
+/// A display process of one target, with its crashes in a row
+struct Display {
+    /// 0 for the target of `--device-index`, the ones of `--extra-target` follow
+    target: usize,
+    command: SplitCommand,
+    process: Option<DisplayProcess>,
+    crashes: u32,
+    /// Frames are not sent to it before this, after it crashed
+    restart_at: Option<Instant>,
+}
+
+impl Display {
+    /// Sends a frame, restarting the process once it is due. Fails once it keeps crashing.
+    fn send(&mut self, header: &FrameHeader, file: &File, log: &slog::Logger) -> Result<()> {
+        if self.process.is_none() {
+            if self.restart_at.map(|at| at > Instant::now()).unwrap_or(false) {
+                return Ok(());
+            }
+            self.process = Some(DisplayProcess::spawn(&self.command, log)?);
+            self.restart_at = None;
+        }
+        let process = self.process.as_ref().unwrap();
+        if let Err(err) = send_frame(process.socket.as_raw_fd(), header, file.as_raw_fd()) {
+            slog::debug!(log, "Failed to send frame: {}", err);
+            let DisplayProcess { mut child, started, .. } = self.process.take().unwrap();
+            let status = child.wait().context("Failed to wait for display process")?;
+            self.crashes = if started.elapsed() < STABLE_RUNTIME {
+                self.crashes + 1
+            } else {
+                1
+            };
+            if self.crashes > MAX_RESTARTS {
+                anyhow::bail!("Display process keeps exiting, last {}", status);
+            }
+            slog::warn!(log, "Display process {}, restarting it", status);
+            // the other targets keep getting frames meanwhile
+            self.restart_at = Some(Instant::now() + RESTART_DELAY);
+        }
+        Ok(())
+    }
+}
+
+/// Sends the frames of the capture process to the display processes, one per target, restarting them whenever they
+/// exit.
+///
+/// Sending happens on a thread of its own, so a busy display process does not stall capturing.
+/// A target whose display process keeps crashing is given up, without affecting the others.
+pub struct FrameSender {
+    frames: Option<SyncSender<(u32, u32, Vec<u8>)>>,
+    /// Buffers of sent frames, to be reused
+    spare: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<Result<()>>>,
+    size: (u32, u32),
+}
+
+impl FrameSender {
+    pub fn new(commands: Vec<SplitCommand>, log: slog::Logger) -> Result<FrameSender> {
+        // started right away, so they set up the targets while we connect to the compositor
+        let mut displays = commands
+            .into_iter()
+            .enumerate()
+            .map(|(target, command)| {
+                Ok(Display {
+                    target,
+                    process: Some(DisplayProcess::spawn(&command, &log)?),
+                    command,
+                    crashes: 0,
+                    restart_at: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (frames, queue) = mpsc::sync_channel::<(u32, u32, Vec<u8>)>(QUEUE_LENGTH);
+        let (recycle, spare) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("split-sender"))
+            .spawn(move || {
+                for (width, height, pixels) in queue {
+                    let header = FrameHeader {
+                        width,
+                        height,
+                        stride: width * 4,
+                        format: Fourcc::Argb8888 as u32,
+                        payload: Payload::Shm {
+                            len: pixels.len() as u64,
+                        },
+                    };
+                    // read back once, every display process maps the same memfd
+                    let file = shm_file(&pixels)?;
+                    let mut i = 0;
+                    while i < displays.len() {
+                        match displays[i].send(&header, &file, &log) {
+                            Ok(()) => i += 1,
+                            Err(err) if displays.len() == 1 => return Err(err),
+                            Err(err) => {
+                                slog::error!(log, "Giving up on target {}: {:?}", displays[i].target, err);
+                                displays.remove(i);
+                            }
+                        }
+                    }
+                    // the event loop is gone, if nobody takes it back
+                    let _ = recycle.send(pixels);
+                }
+                for display in displays {
+                    if let Some(process) = display.process {
+                        process.stop(&log);
+                    }
+                }
+                Ok(())
+            })
+            .context("Failed to spawn split sender thread")?;
+        Ok(FrameSender {
+            frames: Some(frames),
+            spare,
+            thread: Some(thread),
+            size: (0, 0),
+        })
+    }
+}
+
+impl Sink for FrameSender {
+    fn start(&mut self, width: i32, height: i32, _fps: f64) -> Result<()> {
+        self.size = (width as u32, height as u32);
+        Ok(())
+    }
+
+    /// Reuses the buffers already sent
+    fn buffer(&mut self) -> Vec<u8> {
+        self.spare.try_recv().unwrap_or_default()
+    }
+
+    fn push(&mut self, frame: Vec<u8>) -> Result<bool> {
+        let (width, height) = self.size;
+        match self.frames.as_ref().map(|frames| frames.try_send((width, height, frame))) {
+            Some(Ok(())) => Ok(true),
+            Some(Err(TrySendError::Full(_))) => Ok(false),
+            _ => Err(self.finish().err().unwrap_or_else(|| anyhow::anyhow!("Split sender stopped"))),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.frames = None;
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("Split sender thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Texture of the last frame of the capture process, reused while frames keep their size
+struct Upload {
+    texture: Gles2Texture,
+    size: Size<i32, Buffer>,
+    depth: ColorDepth,
+}
+
+/// Uploads or imports the frame of `header` and shows it scaled to the whole target
+fn draw(state: &mut WaylandState, upload: &mut Option<Upload>, header: FrameHeader, file: File) -> Result<()> {
+    let size = Size::<i32, Buffer>::from((header.width as i32, header.height as i32));
+    let format =
+        Fourcc::try_from(header.format).map_err(|_| anyhow::anyhow!("Unknown frame format 0x{:x}", header.format))?;
+    let target = state.target.as_mut().expect("Drawing without a target");
+    let imported;
+    let texture = match header.payload {
+        Payload::Shm { len } => {
+            let layout = render::memory_layout(format)
+                .with_context(|| format!("Unsupported format for shm frames: {:?}", format))?;
+            let mut pixels = vec![0u8; len as usize];
+            file.read_exact_at(&mut pixels, 0).context("Failed to read shm frame")?;
+            convert::swizzle(&mut pixels, layout, layout.depth);
+            if upload
+                .as_ref()
+                .map(|upload| upload.size != size || upload.depth != layout.depth)
+                .unwrap_or(true)
+            {
+                *upload = Some(Upload {
+                    texture: render::create_texture(&mut target.renderer, target.gl, size.w, size.h, layout.depth)?,
+                    size,
+                    depth: layout.depth,
+                });
+            }
+            let texture = &upload.as_ref().unwrap().texture;
+            render::update_bitmap(
+                &mut target.renderer,
+                target.gl,
+                texture,
+                &pixels,
+                header.stride as i32,
+                &[Rectangle::from_loc_and_size((0, 0), size)],
+                layout.depth,
+            )?;
+            texture
+        }
+        Payload::Dmabuf { offset, modifier } => {
+            let mut builder = Dmabuf::builder(size, format, DmabufFlags::empty());
+            builder.add_plane(file.into_raw_fd(), 0, offset, header.stride, Modifier::from(modifier));
+            let dmabuf = builder.build().context("Failed to build dmabuf")?;
+            imported = target.renderer.import_dmabuf(&dmabuf)?;
+            &imported
+        }
+    };
+
+    let dest_size = state.dest_size;
+    target.bind().context("Failed to bind target")?;
+    let (surface_size, transform) = (target.surface_size(), target.transform);
+    target.renderer.render(surface_size, transform, |_, frame| {
+        frame.clear([0.0, 0.0, 0.0, 1.0])?;
+        frame.render_texture_from_to(
+            texture,
+            Rectangle::from_loc_and_size((0, 0), size),
+            Rectangle::from_loc_and_size((0.0, 0.0), (dest_size.w as f64, dest_size.h as f64)),
+            Transform::Normal,
+            1.0,
+        )
+    })??;
+    render::swap_frame(state, stats::monotonic_now());
+    Ok(())
+}
+
+/// Shows the frames arriving on `socket` on the target of `options`, until the capture process goes away.
+///
+/// This is the display process of `--split-display`, it never loads the EGL implementation of the render gpu.
+pub fn display(options: &Options, socket: RawFd, log: slog::Logger) -> Result<()> {
+    let socket = unsafe { File::from_raw_fd(socket) };
+    let mut standalone = Standalone::new(options, false, log.clone())?;
+    let dest_size = standalone.state.dest_size;
+    slog::info!(log, "Showing frames of the capture process in {}x{}", dest_size.w, dest_size.h);
+
+    let latest: Rc<RefCell<Option<(FrameHeader, File)>>> = Rc::new(RefCell::new(None));
+    let closed = Rc::new(Cell::new(false));
+    let (received, receiver_closed) = (latest.clone(), closed.clone());
+    let fd = socket.as_raw_fd();
+    standalone
+        .event_loop
+        .handle()
+        .insert_source(
+            Generic::from_fd(socket.as_raw_fd(), Interest::READ, calloop::Mode::Level),
+            move |_, _, state: &mut WaylandState| {
+                loop {
+                    match recv_frame(fd) {
+                        Ok(Received::Frame(header, file)) => {
+                            state.stats.frame_captured();
+                            // only the newest frame is shown
+                            if received.borrow_mut().replace((header, file)).is_some() {
+                                state.stats.frame_dropped();
+                            }
+                        }
+                        Ok(Received::Empty) => return Ok(PostAction::Continue),
+                        Ok(Received::Closed) => {
+                            receiver_closed.set(true);
+                            return Ok(PostAction::Remove);
+                        }
+                        Err(err) => {
+                            state.fatal = Some(err);
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+            },
+        )
+        .map_err(|err| err.error)
+        .context("Failed to add split socket to event loop")?;
+
+    let mut upload = None;
+    while !standalone.stopped.get() && !closed.get() {
+        if standalone.dispatch()? {
+            upload = None;
+        }
+        if standalone.state.swap_pending {
+            continue;
+        }
+        let frame = latest.borrow_mut().take();
+        if let Some((header, file)) = frame {
+            if let Err(err) = draw(&mut standalone.state, &mut upload, header, file) {
+                render::render_failed(&mut standalone.state, err);
+            }
+        }
+    }
+    if closed.get() {
+        slog::info!(log, "Capture process went away, exiting");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shm_header() -> FrameHeader {
+        FrameHeader {
+            width: 3,
+            height: 2,
+            stride: 16,
+            format: Fourcc::Xrgb8888 as u32,
+            payload: Payload::Shm { len: 32 },
+        }
+    }
+
+    fn dmabuf_header() -> FrameHeader {
+        FrameHeader {
+            width: 1920,
+            height: 1080,
+            stride: 7680,
+            format: Fourcc::Argb8888 as u32,
+            payload: Payload::Dmabuf {
+                offset: 4096,
+                modifier: 0x0100_0000_0000_0002,
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for header in [shm_header(), dmabuf_header()] {
+            assert_eq!(FrameHeader::decode(&header.encode()).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let bytes = shm_header().encode();
+        assert_eq!(&bytes[0..4], b"NVSF");
+        assert_eq!(bytes[4..8], [1, 0, 1, 0]);
+        assert_eq!(bytes[8..24], [3, 0, 0, 0, 2, 0, 0, 0, 16, 0, 0, 0, b'X', b'R', b'2', b'4']);
+        assert_eq!(bytes[24..32], 32u64.to_le_bytes());
+        assert_eq!(bytes[32..], [0; 8]);
+
+        let bytes = dmabuf_header().encode();
+        assert_eq!(bytes[6..8], [2, 0]);
+        assert_eq!(bytes[24..32], 0x0100_0000_0000_0002u64.to_le_bytes());
+        assert_eq!(bytes[32..36], 4096u32.to_le_bytes());
+    }
+
+    fn rejected(bytes: &[u8], message: &str) {
+        let err = FrameHeader::decode(bytes).unwrap_err().to_string();
+        assert!(err.contains(message), "{:?} lacks {:?}", err, message);
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let bytes = shm_header().encode();
+        rejected(&bytes[..HEADER_LEN - 1], "39 bytes");
+        let mut changed = bytes;
+        changed[0] = b'X';
+        rejected(&changed, "does not start with");
+        let mut changed = bytes;
+        changed[4] = 2;
+        rejected(&changed, "version 2");
+        let mut changed = bytes;
+        changed[6] = 3;
+        rejected(&changed, "kind 3");
+        let mut changed = bytes;
+        changed[39] = 1;
+        rejected(&changed, "Reserved");
+    }
+
+    #[test]
+    fn headers_not_describing_frames() {
+        let empty = FrameHeader { width: 0, ..shm_header() };
+        rejected(&empty.encode(), "is empty");
+        let narrow = FrameHeader { stride: 11, ..shm_header() };
+        rejected(&narrow.encode(), "Stride 11");
+        let short = FrameHeader {
+            payload: Payload::Shm { len: 31 },
+            ..shm_header()
+        };
+        rejected(&short.encode(), "31 bytes");
+    }
+
+    #[test]
+    fn frames_over_a_socket() {
+        let (sender, receiver) = socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)
+            .unwrap();
+        let (sender, receiver) = unsafe { (File::from_raw_fd(sender), File::from_raw_fd(receiver)) };
+        assert!(matches!(recv_frame(receiver.as_raw_fd()).unwrap(), Received::Empty));
+
+        let pixels = (0..32).collect::<Vec<u8>>();
+        let shm = shm_file(&pixels).unwrap();
+        send_frame(sender.as_raw_fd(), &shm_header(), shm.as_raw_fd()).unwrap();
+        drop(shm);
+        match recv_frame(receiver.as_raw_fd()).unwrap() {
+            Received::Frame(header, file) => {
+                assert_eq!(header, shm_header());
+                let mut received = vec![0u8; pixels.len()];
+                file.read_exact_at(&mut received, 0).unwrap();
+                assert_eq!(received, pixels);
+            }
+            _ => panic!("No frame received"),
+        }
+        assert!(matches!(recv_frame(receiver.as_raw_fd()).unwrap(), Received::Empty));
+
+        drop(sender);
+        assert!(matches!(recv_frame(receiver.as_raw_fd()).unwrap(), Received::Closed));
+    }
+}
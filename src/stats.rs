@@ -0,0 +1,298 @@
+use nix::time::{clock_gettime, ClockId};
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Number of latency samples considered for the reported values
+const SAMPLES: usize = 256;
+/// Frames swapped but not yet displayed, more indicates we missed vblanks
+const MAX_IN_FLIGHT: usize = 8;
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval frame rates are averaged over
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+/// At most one warning about orphan events is logged within this time
+const ORPHAN_WARN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Current time of the monotonic clock, which is what compositors use for frame timestamps.
+pub fn monotonic_now() -> Duration {
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("Monotonic clock unavailable");
+    Duration::new(now.tv_sec() as u64, now.tv_nsec() as u32)
+}
+
+/// Converts the split timestamp of the capture protocols
+pub fn protocol_timestamp(tv_sec_hi: u32, tv_sec_lo: u32, tv_nsec: u32) -> Duration {
+    Duration::new(((tv_sec_hi as u64) << 32) | tv_sec_lo as u64, tv_nsec)
+}
+
+/// Counts events to determine their rate per second
+struct Rate {
+    count: u32,
+    since: Instant,
+    value: f64,
+}
+
+impl Rate {
+    fn new() -> Rate {
+        Rate {
+            count: 0,
+            since: Instant::now(),
+            value: 0.0,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.count += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            self.value = self.count as f64 / elapsed.as_secs_f64();
+            self.count = 0;
+            self.since = Instant::now();
+        }
+    }
+}
+
+/// Runtime statistics of the mirroring pipeline
+pub struct Stats {
+    in_flight: VecDeque<Duration>,
+    /// Frames handed to the display since the start
+    swapped: u64,
+    latencies: VecDeque<Duration>,
+    last_report: Instant,
+    captured: Rate,
+    /// Frames with new content, which is less than `captured` if captures outpace the source
+    source: Rate,
+    /// When the newest frame arrived
+    last_captured: Option<Instant>,
+    displayed: Rate,
+    last_error: Option<String>,
+    /// `last_error` was not handed out through `take_error` yet
+    error_pending: bool,
+    /// Frames dropped since the last report, because the output could not keep up
+    dropped: u64,
+    dropped_total: u64,
+    /// Wayland events nothing handled since the start, e.g. of interfaces newer than we know
+    orphan_events: u64,
+    last_orphan_warning: Option<Instant>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            in_flight: VecDeque::with_capacity(MAX_IN_FLIGHT),
+            swapped: 0,
+            latencies: VecDeque::with_capacity(SAMPLES),
+            last_report: Instant::now(),
+            captured: Rate::new(),
+            source: Rate::new(),
+            last_captured: None,
+            displayed: Rate::new(),
+            last_error: None,
+            error_pending: false,
+            dropped: 0,
+            dropped_total: 0,
+            orphan_events: 0,
+            last_orphan_warning: None,
+        }
+    }
+
+    /// A frame arrived from the compositor
+    pub fn frame_captured(&mut self) {
+        self.captured.tick();
+        self.last_captured = Some(Instant::now());
+    }
+
+    /// A captured frame holds new content, as far as the capture backend can tell
+    pub fn source_frame(&mut self) {
+        self.source.tick();
+    }
+
+    /// Frames per second the source renders
+    pub fn source_fps(&self) -> f64 {
+        self.source.value
+    }
+
+    pub fn last_captured(&self) -> Option<Instant> {
+        self.last_captured
+    }
+
+    /// A frame was dropped, because the output could not keep up
+    pub fn frame_dropped(&mut self) {
+        self.dropped += 1;
+        self.dropped_total += 1;
+    }
+
+    /// Frames dropped since the start
+    pub fn frames_dropped(&self) -> u64 {
+        self.dropped_total
+    }
+
+    /// Counts the event `name` of `interface`@`id`, which nothing handles.
+    ///
+    /// Warns about it unless another one was warned about lately, it is only logged for debugging then.
+    pub fn orphan_event(&mut self, interface: &str, id: u32, name: &str, log: &slog::Logger) {
+        self.orphan_events += 1;
+        let warn = self
+            .last_orphan_warning
+            .map(|last| last.elapsed() >= ORPHAN_WARN_INTERVAL)
+            .unwrap_or(true);
+        if warn {
+            self.last_orphan_warning = Some(Instant::now());
+            slog::warn!(
+                log,
+                "Ignoring orphan event: {}@{} : {} ({} so far)",
+                interface,
+                id,
+                name,
+                self.orphan_events
+            );
+        } else {
+            slog::debug!(log, "Ignoring orphan event: {}@{} : {}", interface, id, name);
+        }
+    }
+
+    /// Orphan events since the start
+    pub fn orphan_events(&self) -> u64 {
+        self.orphan_events
+    }
+
+    /// Something went wrong, that we recovered from
+    pub fn error(&mut self, error: impl Into<String>) {
+        self.last_error = Some(error.into());
+        self.error_pending = true;
+    }
+
+    /// The last error, if it happened since the previous call
+    pub fn take_error(&mut self) -> Option<String> {
+        if !self.error_pending {
+            return None;
+        }
+        self.error_pending = false;
+        self.last_error.clone()
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Captured and displayed frames per second
+    pub fn fps(&self) -> (f64, f64) {
+        (self.captured.value, self.displayed.value)
+    }
+
+    /// A frame captured at `captured` was handed to the display
+    pub fn frame_swapped(&mut self, captured: Duration) {
+        self.swapped += 1;
+        if self.in_flight.len() == MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back(captured);
+    }
+
+    pub fn frames_swapped(&self) -> u64 {
+        self.swapped
+    }
+
+    /// The oldest swapped frame reached the screen at `now`
+    pub fn frame_displayed(&mut self, now: Duration) {
+        self.displayed.tick();
+        if let Some(captured) = self.in_flight.pop_front() {
+            if self.latencies.len() == SAMPLES {
+                self.latencies.pop_front();
+            }
+            self.latencies.push_back(now.checked_sub(captured).unwrap_or_default());
+        }
+    }
+
+    /// Average and 95th percentile of the capture to scanout latency
+    pub fn latency(&self) -> Option<(Duration, Duration)> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let mut sorted = self.latencies.iter().copied().collect::<Vec<_>>();
+        sorted.sort();
+        let avg = sorted.iter().sum::<Duration>() / sorted.len() as u32;
+        let p95 = sorted[(sorted.len() * 95 / 100).min(sorted.len() - 1)];
+        Some((avg, p95))
+    }
+
+    /// Logs the current values, if the last report is long enough ago. Returns whether it did.
+    pub fn report(&mut self, log: &slog::Logger) -> bool {
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return false;
+        }
+        self.last_report = Instant::now();
+        if let Some((avg, p95)) = self.latency() {
+            slog::info!(
+                log,
+                "Capture to scanout latency: avg {:.1}ms, p95 {:.1}ms",
+                avg.as_secs_f64() * 1000.0,
+                p95.as_secs_f64() * 1000.0
+            );
+        }
+        if self.dropped > 0 {
+            slog::info!(log, "Dropped {} frames, the output could not keep up", self.dropped);
+            self.dropped = 0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the messages logged along their level
+    struct Collect(Arc<Mutex<Vec<(slog::Level, String)>>>);
+
+    impl slog::Drain for Collect {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record<'_>, _values: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push((record.level(), record.msg().to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn orphan_events_are_counted_and_logged() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = slog::Logger::root(Collect(lines.clone()), slog::o!());
+        let mut stats = Stats::new();
+        // an event of a newer version of the interface than we know
+        stats.orphan_event("zwlr_export_dmabuf_frame_v1", 42, "damage", &log);
+        stats.orphan_event("wl_output", 7, "name", &log);
+        assert_eq!(stats.orphan_events(), 2);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                (
+                    slog::Level::Warning,
+                    String::from("Ignoring orphan event: zwlr_export_dmabuf_frame_v1@42 : damage (1 so far)")
+                ),
+                (slog::Level::Debug, String::from("Ignoring orphan event: wl_output@7 : name")),
+            ]
+        );
+
+        // processing goes on
+        stats.frame_captured();
+        stats.frame_dropped();
+        assert_eq!(stats.frames_dropped(), 1);
+
+        // warned about again once the last warning is long enough ago
+        stats.last_orphan_warning = Instant::now().checked_sub(ORPHAN_WARN_INTERVAL);
+        stats.orphan_event("wl_output", 7, "name", &log);
+        assert_eq!(stats.orphan_events(), 3);
+        assert_eq!(
+            lines.lock().unwrap().last().unwrap(),
+            &(
+                slog::Level::Warning,
+                String::from("Ignoring orphan event: wl_output@7 : name (3 so far)")
+            )
+        );
+    }
+}
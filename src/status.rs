@@ -0,0 +1,292 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use std::{
+    io::Write,
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Mutex,
+    },
+};
+
+/// What a running `ScreenCopy` is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MirrorState {
+    Mirroring,
+    /// The session or another process took the output away
+    Paused,
+    /// A source output is missing
+    WaitingForSource,
+    /// No monitor is plugged into the target connector
+    WaitingForMonitor,
+    /// The compositor went away
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StatusMode {
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Why frames are not imported directly, as the last failed import reported it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusImportFailure {
+    pub fourcc: String,
+    pub modifier: String,
+    pub planes: usize,
+    pub width: i32,
+    pub height: i32,
+    pub reason: String,
+    /// The import succeeded, but `--verify-import` found it does not show the frame
+    pub garbage: bool,
+}
+
+/// Snapshot written by `--stats-file` and answered on `--stats-socket`, one JSON object per snapshot
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Status {
+    pub state: MirrorState,
+    /// Makes of the captured outputs, in the order of the sources
+    pub sources: Vec<String>,
+    /// Name of the target connector, `None` without a monitor
+    pub connector: Option<String>,
+    /// Mode of the target connector, `None` without a monitor
+    pub mode: Option<StatusMode>,
+    pub captured_fps: f64,
+    /// Frames per second with new content, below `captured_fps` if captures outpace the source
+    pub source_fps: f64,
+    pub displayed_fps: f64,
+    /// Frames dropped since the start, because a newer one arrived before the output took them
+    pub dropped_frames: u64,
+    /// Wayland events nothing handled since the start, which hints at a protocol version mismatch
+    pub orphan_events: u64,
+    /// Path the last frame took, `None` before the first one
+    pub copy_path: Option<String>,
+    /// `None` while DirectImport works or was not tried
+    pub import_failure: Option<StatusImportFailure>,
+    pub last_error: Option<String>,
+    pub uptime_secs: f64,
+}
+
+/// Publishes status snapshots off the event loop.
+///
+/// The file is written by a thread of its own, snapshots arriving while it is still busy are skipped.
+/// The socket is served by another thread, which answers every connection with the newest snapshot.
+pub struct StatusExporter {
+    file: Option<SyncSender<String>>,
+    latest: Arc<Mutex<String>>,
+    /// Removed again on drop
+    socket_path: Option<PathBuf>,
+}
+
+impl StatusExporter {
+    pub fn new(file: Option<PathBuf>, socket: Option<PathBuf>, log: slog::Logger) -> Result<StatusExporter> {
+        let latest = Arc::new(Mutex::new(String::from("{}")));
+        let file = file.map(|path| {
+            // holds one snapshot, so at most one waits while the previous one is written
+            let (sender, receiver) = mpsc::sync_channel::<String>(1);
+            let log = log.clone();
+            std::thread::spawn(move || {
+                for snapshot in receiver {
+                    if let Err(err) = write_atomically(&path, &snapshot) {
+                        slog::warn!(log, "Failed to write stats file: {:#}", err);
+                    }
+                }
+            });
+            sender
+        });
+        if let Some(path) = socket.as_ref() {
+            // a socket left behind by a previous run is in the way
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("Failed to listen on stats socket {}", path.display()))?;
+            let latest = latest.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            slog::debug!(log, "Failed to accept stats client: {}", err);
+                            continue;
+                        }
+                    };
+                    let snapshot = latest.lock().unwrap().clone();
+                    let _ = writeln!(stream, "{}", snapshot);
+                }
+            });
+        }
+        Ok(StatusExporter {
+            file,
+            latest,
+            socket_path: socket,
+        })
+    }
+
+    pub fn publish(&self, status: &Status) {
+        let snapshot = match serde_json::to_string(status) {
+            Ok(snapshot) => snapshot,
+            Err(_) => return,
+        };
+        if let Some(file) = self.file.as_ref() {
+            // skipped while the previous one is still being written
+            let _ = file.try_send(snapshot.clone());
+        }
+        *self.latest.lock().unwrap() = snapshot;
+    }
+}
+
+impl Drop for StatusExporter {
+    fn drop(&mut self) {
+        if let Some(path) = self.socket_path.as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Replaces `path` by a file holding `contents`, readers never see it half written
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let mut name = path.file_name().context("Stats file has no name")?.to_owned();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    std::fs::write(&temp, format!("{}\n", contents))
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix::net::UnixStream,
+        time::{Duration, Instant},
+    };
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn status() -> Status {
+        Status {
+            state: MirrorState::WaitingForMonitor,
+            sources: vec![String::from("HEADLESS-1")],
+            connector: Some(String::from("HDMI-A-1")),
+            mode: Some(StatusMode {
+                width: 1920,
+                height: 1080,
+            }),
+            captured_fps: 60.0,
+            source_fps: 30.0,
+            displayed_fps: 59.5,
+            dropped_frames: 3,
+            orphan_events: 0,
+            copy_path: Some(String::from("CPUCopy")),
+            import_failure: Some(StatusImportFailure {
+                fourcc: String::from("XR24"),
+                modifier: String::from("0x0100000000000001"),
+                planes: 1,
+                width: 1920,
+                height: 1080,
+                reason: String::from("EGL_BAD_MATCH"),
+                garbage: false,
+            }),
+            last_error: None,
+            uptime_secs: 12.5,
+        }
+    }
+
+    /// Status bars parse these names, renaming any of them breaks their configs
+    #[test]
+    fn schema() {
+        assert_eq!(
+            serde_json::to_value(status()).unwrap(),
+            json!({
+                "state": "waiting-for-monitor",
+                "sources": ["HEADLESS-1"],
+                "connector": "HDMI-A-1",
+                "mode": { "width": 1920, "height": 1080 },
+                "captured_fps": 60.0,
+                "source_fps": 30.0,
+                "displayed_fps": 59.5,
+                "dropped_frames": 3,
+                "orphan_events": 0,
+                "copy_path": "CPUCopy",
+                "import_failure": {
+                    "fourcc": "XR24",
+                    "modifier": "0x0100000000000001",
+                    "planes": 1,
+                    "width": 1920,
+                    "height": 1080,
+                    "reason": "EGL_BAD_MATCH",
+                    "garbage": false,
+                },
+                "last_error": null,
+                "uptime_secs": 12.5,
+            })
+        );
+    }
+
+    #[test]
+    fn states() {
+        let names = [
+            (MirrorState::Mirroring, "mirroring"),
+            (MirrorState::Paused, "paused"),
+            (MirrorState::WaitingForSource, "waiting-for-source"),
+            (MirrorState::WaitingForMonitor, "waiting-for-monitor"),
+            (MirrorState::Disconnected, "disconnected"),
+        ];
+        for (state, name) in names {
+            assert_eq!(serde_json::to_value(state).unwrap(), json!(name));
+        }
+    }
+
+    #[test]
+    fn file_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.json");
+        write_atomically(&path, "{\"old\":true}").unwrap();
+        write_atomically(&path, "{}").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}\n");
+        // the temporary file is gone
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn exporter_writes_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.json");
+        let exporter = StatusExporter::new(Some(path.clone()), None, log()).unwrap();
+        exporter.publish(&status());
+        // written by a thread of the exporter
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !path.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, serde_json::to_value(status()).unwrap());
+    }
+
+    #[test]
+    fn exporter_answers_on_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status.sock");
+        let exporter = StatusExporter::new(None, Some(path.clone()), log()).unwrap();
+        let read = || {
+            let mut line = String::new();
+            BufReader::new(UnixStream::connect(&path).unwrap()).read_line(&mut line).unwrap();
+            line
+        };
+        assert_eq!(read(), "{}\n");
+        exporter.publish(&status());
+        let answer: serde_json::Value = serde_json::from_str(&read()).unwrap();
+        assert_eq!(answer, serde_json::to_value(status()).unwrap());
+        drop(exporter);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,116 @@
+/// What to do about a failure, decided by `Streak::failed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Keep quiet, the streak was reported recently
+    Quiet,
+    /// Worth a warning, the streak doubled since the last one
+    Warn,
+    /// Too many in a row to still be considered temporary
+    Escalate,
+}
+
+/// Consecutive failures of one kind.
+///
+/// Warnings are limited to streaks of a power of two starting at `warn_from`,
+/// so a failure on every frame does not flood the log.
+pub struct Streak {
+    count: u32,
+    warn_from: u32,
+    /// Failures in a row that escalate, `None` never does
+    limit: Option<u32>,
+}
+
+impl Streak {
+    pub fn new(warn_from: u32, limit: Option<u32>) -> Streak {
+        Streak {
+            count: 0,
+            warn_from,
+            limit,
+        }
+    }
+
+    pub fn failed(&mut self) -> Verdict {
+        self.count = self.count.saturating_add(1);
+        if self.limit.map(|limit| self.count >= limit).unwrap_or(false) {
+            Verdict::Escalate
+        } else if self.count >= self.warn_from && self.count.is_power_of_two() {
+            Verdict::Warn
+        } else {
+            Verdict::Quiet
+        }
+    }
+
+    pub fn succeeded(&mut self) {
+        self.count = 0;
+    }
+
+    /// Failures in a row so far
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdicts(streak: &mut Streak, failures: usize) -> Vec<Verdict> {
+        (0..failures).map(|_| streak.failed()).collect()
+    }
+
+    #[test]
+    fn warns_at_powers_of_two() {
+        let mut streak = Streak::new(1, None);
+        let warned = verdicts(&mut streak, 16)
+            .iter()
+            .enumerate()
+            .filter(|(_, verdict)| **verdict == Verdict::Warn)
+            .map(|(index, _)| index + 1)
+            .collect::<Vec<_>>();
+        assert_eq!(warned, vec![1, 2, 4, 8, 16]);
+        assert_eq!(streak.count(), 16);
+    }
+
+    #[test]
+    fn quiet_below_warn_from() {
+        let mut streak = Streak::new(4, None);
+        assert_eq!(
+            verdicts(&mut streak, 5),
+            vec![Verdict::Quiet, Verdict::Quiet, Verdict::Quiet, Verdict::Warn, Verdict::Quiet]
+        );
+    }
+
+    #[test]
+    fn escalates_at_the_limit() {
+        let mut streak = Streak::new(1, Some(3));
+        assert_eq!(
+            verdicts(&mut streak, 4),
+            vec![Verdict::Warn, Verdict::Warn, Verdict::Escalate, Verdict::Escalate]
+        );
+    }
+
+    #[test]
+    fn escalating_wins_over_warning() {
+        let mut streak = Streak::new(1, Some(4));
+        assert_eq!(verdicts(&mut streak, 4)[3], Verdict::Escalate);
+    }
+
+    #[test]
+    fn success_resets() {
+        let mut streak = Streak::new(1, Some(3));
+        verdicts(&mut streak, 2);
+        streak.succeeded();
+        assert_eq!(streak.count(), 0);
+        // starts over, warning again and escalating only after the full limit
+        assert_eq!(
+            verdicts(&mut streak, 3),
+            vec![Verdict::Warn, Verdict::Warn, Verdict::Escalate]
+        );
+    }
+
+    #[test]
+    fn never_escalates_without_limit() {
+        let mut streak = Streak::new(1, None);
+        assert!(verdicts(&mut streak, 1000).iter().all(|verdict| *verdict != Verdict::Escalate));
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    fmt,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    str::FromStr,
+};
+
+const MAGIC: &[u8; 6] = b"i3-ipc";
+const RUN_COMMAND: u32 = 0;
+const GET_OUTPUTS: u32 = 3;
+
+/// Minimal client for the sway ipc protocol
+pub struct SwayIpc {
+    stream: UnixStream,
+}
+
+impl SwayIpc {
+    pub fn connect() -> Result<SwayIpc> {
+        let path = std::env::var_os("SWAYSOCK")
+            .context("SWAYSOCK is not set, creating headless outputs is only supported on sway")?;
+        let stream = UnixStream::connect(&path).with_context(|| {
+            format!("Failed to connect to sway ipc at {}", Path::new(&path).display())
+        })?;
+        Ok(SwayIpc { stream })
+    }
+
+    fn request(&mut self, kind: u32, payload: &str) -> Result<serde_json::Value> {
+        let mut message = Vec::with_capacity(14 + payload.len());
+        message.extend_from_slice(MAGIC);
+        message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(payload.as_bytes());
+        self.stream
+            .write_all(&message)
+            .context("Failed to send sway ipc message")?;
+
+        let mut header = [0u8; 14];
+        self.stream
+            .read_exact(&mut header)
+            .context("Failed to read sway ipc reply")?;
+        if &header[..6] != MAGIC {
+            anyhow::bail!("Invalid sway ipc reply");
+        }
+        let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+        let mut reply = vec![0u8; len as usize];
+        self.stream
+            .read_exact(&mut reply)
+            .context("Failed to read sway ipc reply")?;
+        serde_json::from_slice(&reply).context("Failed to parse sway ipc reply")
+    }
+
+    /// Runs the given sway command, failing if sway reports an error
+    pub fn run_command(&mut self, command: &str) -> Result<()> {
+        let reply = self.request(RUN_COMMAND, command)?;
+        // sway replies with one result per command
+        for result in reply.as_array().into_iter().flatten() {
+            if result["success"] != true {
+                anyhow::bail!(
+                    "sway failed to run \"{}\": {}",
+                    command,
+                    result["error"].as_str().unwrap_or("Unknown error")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn output_names(&mut self) -> Result<HashSet<String>> {
+        let reply = self.request(GET_OUTPUTS, "")?;
+        Ok(reply
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|output| output["name"].as_str())
+            .map(String::from)
+            .collect())
+    }
+}
+
+/// Mode of a headless output in the format "WIDTHxHEIGHT[@HZ]"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadlessMode {
+    pub width: i32,
+    pub height: i32,
+    pub refresh: Option<f64>,
+}
+
+impl FromStr for HeadlessMode {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<HeadlessMode> {
+        let (size, refresh) = match input.split_once('@') {
+            Some((size, refresh)) => (size, Some(refresh.trim_end_matches("Hz"))),
+            None => (input, None),
+        };
+        let (width, height) = size
+            .split_once('x')
+            .context("Mode needs to have the format \"WIDTHxHEIGHT[@HZ]\"")?;
+        let mode = HeadlessMode {
+            width: u16::from_str(width).context("Failed to parse width of mode")? as i32,
+            height: u16::from_str(height).context("Failed to parse height of mode")? as i32,
+            refresh: refresh
+                .map(f64::from_str)
+                .transpose()
+                .context("Failed to parse refresh rate of mode")?,
+        };
+        if mode.width == 0 || mode.height == 0 || mode.refresh.map(|x| x <= 0.0).unwrap_or(false) {
+            anyhow::bail!("Mode with an empty size or refresh rate");
+        }
+        Ok(mode)
+    }
+}
+
+impl fmt::Display for HeadlessMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)?;
+        if let Some(refresh) = self.refresh {
+            write!(f, "@{}Hz", refresh)?;
+        }
+        Ok(())
+    }
+}
+
+/// A headless output created through sway, that is unplugged again when dropped
+pub struct HeadlessOutput {
+    ipc: SwayIpc,
+    name: String,
+    log: slog::Logger,
+}
+
+impl HeadlessOutput {
+    pub fn create(mode: HeadlessMode, log: slog::Logger) -> Result<HeadlessOutput> {
+        let mut ipc = SwayIpc::connect()?;
+        let existing = ipc.output_names()?;
+        ipc.run_command("create_output")?;
+        let name = ipc
+            .output_names()?
+            .into_iter()
+            .find(|name| !existing.contains(name) && name.starts_with("HEADLESS-"))
+            .context("sway did not create a headless output")?;
+        // unplugs the output again, if configuring it fails
+        let mut output = HeadlessOutput { ipc, name, log };
+        let command = format!("output {} mode {}", output.name, mode);
+        output.ipc.run_command(&command)?;
+        slog::info!(output.log, "Created headless output {} ({})", output.name, mode);
+        Ok(output)
+    }
+}
+
+impl Drop for HeadlessOutput {
+    fn drop(&mut self) {
+        let command = format!("output {} unplug", self.name);
+        match self.ipc.run_command(&command) {
+            Ok(()) => slog::info!(self.log, "Removed headless output {}", self.name),
+            Err(err) => slog::warn!(self.log, "Failed to remove headless output {}: {}", self.name, err),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use anyhow::{Context, Result};
+use calloop::{
+    signals::{Signal, Signals},
+    EventLoop,
+};
+use smithay::{
+    backend::{
+        drm::DrmEvent,
+        renderer::{gles2::Gles2Texture, Frame, Renderer, Transform},
+    },
+    utils::{Buffer, Physical, Rectangle, Size},
+};
+
+use crate::{
+    adjust::Adjustments,
+    capture::{self, CaptureRate, QueuePolicy},
+    constraints::CaptureConstraints,
+    copy_path::{CopyPath, CopyPathKind},
+    damage::IdleDetect,
+    events::{self, Event, FrameStats},
+    gamma_lut::GammaLut,
+    geometry::FilterKind,
+    gpu::{self, ColorDepth},
+    overlay::Overlay,
+    render, session, stats, Options, WaylandState,
+};
+
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Top two thirds: 75% white, yellow, cyan, green, magenta, red and blue
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+/// The narrow row below, the complement of the bars above
+const CASTELLATIONS: [[u8; 3]; 7] = [
+    [0, 0, 191],
+    [0, 0, 0],
+    [191, 0, 191],
+    [0, 0, 0],
+    [0, 191, 191],
+    [0, 0, 0],
+    [191, 191, 191],
+];
+/// Bottom row: -I, 100% white and +Q, each 5/4 of a bar wide
+const BOTTOM: [[u8; 3]; 3] = [[0, 33, 76], [255, 255, 255], [50, 0, 106]];
+/// Below the red bar: black, 4% and 8% grey to adjust the black level against
+const PLUGE: [[u8; 3]; 3] = [[0, 0, 0], [10, 10, 10], [20, 20, 20]];
+/// Flips the moving box takes to cross the output
+const BOX_CROSSING: u64 = 120;
+/// How long to wait for a vblank before drawing again, which retries failed swaps
+const FRAME_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Color of the SMPTE color bars at `x`,`y` of a `width`x`height` image
+fn bar_color(x: i32, y: i32, width: i32, height: i32) -> [u8; 3] {
+    let bar = (x * 7 / width) as usize;
+    if y < height * 2 / 3 {
+        return BARS[bar];
+    }
+    if y < height * 3 / 4 {
+        return CASTELLATIONS[bar];
+    }
+    // in eighths of a bar, to place the 5/4 wide and the pluge bars
+    let eighth = x * 56 / width;
+    match eighth {
+        0..=29 => BOTTOM[(eighth / 10) as usize],
+        40..=47 => PLUGE[((eighth - 40) * 3 / 8) as usize],
+        _ => [0, 0, 0],
+    }
+}
+
+/// RGBA pixels of the SMPTE color bars in the given size
+pub fn bars(width: i32, height: i32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b] = bar_color(x, y, width, height);
+            pixels.extend_from_slice(&[r, g, b, 0xff]);
+        }
+    }
+    pixels
+}
+
+/// Left edge of the moving box in flip `frame`, bouncing between the sides of an output `width` wide
+fn box_position(frame: u64, width: i32, size: i32) -> i32 {
+    let travel = (width - size).max(0) as u64;
+    let phase = frame % (2 * BOX_CROSSING);
+    let progress = if phase < BOX_CROSSING {
+        phase
+    } else {
+        2 * BOX_CROSSING - phase
+    };
+    (progress * travel / BOX_CROSSING) as i32
+}
+
+/// Everything drawn on the target besides the frame counter, which is left to the overlay
+struct Pattern {
+    /// `None` after the context of the target was rebuilt
+    bars: Option<(Gles2Texture, Size<i32, Physical>)>,
+    /// Flips drawn so far, the box moves by one step each
+    frame: u64,
+}
+
+/// Uploads the color bars for the current size of the target
+fn create_bars(state: &mut WaylandState) -> Result<(Gles2Texture, Size<i32, Physical>)> {
+    let size = state.dest_size;
+    let target = state.target.as_mut().expect("Drawing without a target");
+    let texture = render::create_texture(&mut target.renderer, target.gl, size.w, size.h, ColorDepth::Eight)?;
+    render::update_bitmap(
+        &mut target.renderer,
+        target.gl,
+        &texture,
+        &bars(size.w, size.h),
+        size.w * 4,
+        &[Rectangle::from_loc_and_size((0, 0), (size.w, size.h))],
+        ColorDepth::Eight,
+    )?;
+    Ok((texture, size))
+}
+
+/// Draws the next frame of the pattern and swaps it through the same path mirrored frames take
+fn draw(state: &mut WaylandState, pattern: &mut Pattern) -> Result<()> {
+    if pattern.bars.as_ref().map(|(_, size)| *size != state.dest_size).unwrap_or(true) {
+        pattern.bars = Some(create_bars(state)?);
+    }
+    let (bars, _) = pattern.bars.as_ref().unwrap();
+    let dest_size = state.dest_size;
+    let lines = vec![
+        format!("FRAME {}", pattern.frame),
+        format!("MODE {}X{}", dest_size.w, dest_size.h),
+        format!("ERROR {}", state.stats.last_error().unwrap_or("-")),
+    ];
+    let overlay = state.overlay.as_ref();
+    let target = state.target.as_mut().expect("Drawing without a target");
+    target.bind().context("Failed to bind target")?;
+    let (surface_size, transform) = (target.surface_size(), target.transform);
+    let box_size = (dest_size.h / 6).max(1);
+    let box_dst = Rectangle::from_loc_and_size(
+        (
+            box_position(pattern.frame, dest_size.w, box_size) as f64,
+            ((dest_size.h * 2 / 3 - box_size) / 2) as f64,
+        ),
+        (box_size as f64, box_size as f64),
+    );
+    // a pixel of the 100% white bar, stretched over the box
+    let white: Rectangle<i32, Buffer> =
+        Rectangle::from_loc_and_size((dest_size.w * 15 / 56, dest_size.h - 1), (1, 1));
+    target.renderer.render(surface_size, transform, |_, frame| {
+        frame.clear([0.0, 0.0, 0.0, 1.0])?;
+        frame.render_texture_from_to(
+            bars,
+            Rectangle::from_loc_and_size((0, 0), (dest_size.w, dest_size.h)),
+            Rectangle::from_loc_and_size((0.0, 0.0), (dest_size.w as f64, dest_size.h as f64)),
+            Transform::Normal,
+            1.0,
+        )?;
+        frame.render_texture_from_to(bars, white, box_dst, Transform::Normal, 1.0)?;
+        match overlay {
+            Some(overlay) => overlay.draw(frame, &lines),
+            None => Ok(()),
+        }
+    })??;
+    pattern.frame += 1;
+    render::swap_frame(state, stats::monotonic_now());
+    Ok(())
+}
+
+/// A target driven without a compositor, by the test pattern and the display process of `--split-display`
+pub struct Standalone {
+    pub state: WaylandState,
+    pub event_loop: EventLoop<'static, WaylandState>,
+    /// Set once SIGINT or SIGTERM arrived
+    pub stopped: Rc<Cell<bool>>,
+    _session: session::Session,
+}
+
+impl Standalone {
+    /// Opens the target of `options` in the mode it asks for, or the preferred one of the monitor
+    pub fn new(options: &Options, show_overlay: bool, log: slog::Logger) -> Result<Standalone> {
+        let connector = options.connector.as_deref();
+        let any_driver = options.target_backend == gpu::TargetBackendKind::Gbm;
+        let seat = gpu::resolve_seat(options.seat.as_deref(), std::env::var("XDG_SEAT").ok());
+        let path = crate::find_target_gpu(
+            &seat,
+            connector,
+            options.device_index,
+            any_driver,
+            options.wait_for_connector,
+            options.wait_for_gpu,
+            &log,
+        )?;
+        let driver = gpu::gpu_driver(&path)?;
+        let backend = options.target_backend.resolve(&driver);
+        slog::info!(log, "Found gpu {} ({}), target backend: {:?}", path.display(), driver, backend);
+        // there is no source to fit, so the monitor picks
+        let mode = match options.mode.or_else(|| {
+            options
+                .modeline
+                .map(|modeline| (modeline.size().0 as i32, modeline.size().1 as i32))
+        }) {
+            Some(mode) => mode,
+            None => {
+                let (width, height, _) = gpu::preferred_mode(&path, connector, log.clone())?;
+                (width, height)
+            }
+        };
+        let (mut session, session_notifier) = session::Session::new(options.session, &log)?;
+        slog::info!(log, "Session backend: {}", session.name());
+        let target_fd = session.open_target(&path)?;
+        let target_options = gpu::TargetOptions {
+            backend,
+            connector: connector.map(String::from),
+            mode,
+            modeline: options.modeline,
+            refresh: None,
+            strict_mode: options.strict_mode,
+            allow_crtc_steal: options.allow_crtc_steal,
+            depth: options.color_depth.unwrap_or(
+                if options.colorspace.is_some() || options.hdr_metadata.is_some() {
+                    ColorDepth::Ten
+                } else {
+                    ColorDepth::Eight
+                },
+            ),
+            stream: options.stream.clone(),
+            legacy_modesetting: options.legacy_modesetting,
+            vrr: options.vrr,
+            connector_props: options.connector_props.clone(),
+            colorspace: options.colorspace.clone(),
+            hdr_metadata: options.hdr_metadata.clone(),
+            gamma_lut: options.gamma_lut.as_deref().map(GammaLut::load).transpose()?,
+            transform: options.transform,
+            plane_scaling: None,
+            plane: options.plane,
+            gl_debug: options.gl_debug,
+        };
+        let (mut target, device) = gpu::TargetGPU::initialize_with_recovery(target_fd, &target_options, log.clone())?;
+        let dest_size = target.size();
+        let overlay = if show_overlay {
+            Some(Overlay::new(&mut target.renderer, target.gl, dest_size).with_context(|| "Failed to create overlay")?)
+        } else {
+            None
+        };
+
+        let state = WaylandState {
+            render: None,
+            color_depth: target.upload_depth(),
+            target: Some(target),
+            sources: Vec::new(),
+            releasing: VecDeque::new(),
+            pipeline_depth: 1,
+            log: log.clone(),
+            damage_tracking: false,
+            idle_detect: IdleDetect::Off,
+            copy: None,
+            copy_path: CopyPath::new(CopyPathKind::Auto),
+            verify_import: false,
+            scanout: None,
+            readback_route: None,
+            import_formats: HashSet::new(),
+            constraints: CaptureConstraints::new(None),
+            async_readback: false,
+            downscale: render::Downscale::Off,
+            converter: None,
+            reject_yuv: false,
+            stats: stats::Stats::new(),
+            tracer: None,
+            background: [0.0, 0.0, 0.0, 1.0],
+            splash: None,
+            adjustments: Adjustments::NEUTRAL,
+            adjust_shader: None,
+            overlay,
+            overlay_cursor: false,
+            cursor: None,
+            pacing: None,
+            #[cfg(feature = "nvenc")]
+            recorder: None,
+            events: events::Events::default(),
+            dest_size,
+            crop: None,
+            placement: None,
+            orientation_follow_source: false,
+            layout: None,
+            labels: None,
+            filter: FilterKind::Auto,
+            linear_blending: false,
+            retry: capture::Retry::new(FRAME_TIMEOUT),
+            robustness: options.robustness,
+            render_failures: 0,
+            capture_rate: CaptureRate::VBlank,
+            queue_policy: QueuePolicy::Latest,
+            swap_pending: false,
+            latest_frame: None,
+            swap_failures: HashMap::new(),
+            swap_failure_limit: options.swap_failure_limit,
+            swap_failure_policy: options.swap_failure_policy,
+            frame_error: None,
+            fatal: None,
+            target_lost: false,
+            target_paused: false,
+        };
+
+        let event_loop: EventLoop<'static, WaylandState> =
+            EventLoop::try_new().with_context(|| "Failed to create event loop")?;
+        if let Some(notifier) = session_notifier {
+            event_loop
+                .handle()
+                .insert_source(notifier, |_, _, _: &mut WaylandState| {})
+                .map_err(|err| err.error)
+                .context("Failed to add session to event loop")?;
+        }
+        let vblank_log = log.clone();
+        event_loop
+            .handle()
+            .insert_source(device, move |event, _, state: &mut WaylandState| match event {
+                DrmEvent::VBlank(_crtc) => {
+                    if let Some(target) = state.target.as_mut() {
+                        target.frame_submitted();
+                    }
+                    state.swap_pending = false;
+                    state.stats.frame_displayed(stats::monotonic_now());
+                    if state.stats.report(&vblank_log) {
+                        let snapshot = FrameStats::of(&state.stats);
+                        state.events.emit(Event::Stats(snapshot));
+                    }
+                }
+                DrmEvent::Error(error) => slog::error!(vblank_log, "{:?}", error),
+            })
+            .map_err(|err| err.error)
+            .context("Failed to add drm device to event loop")?;
+        let stopped = Rc::new(Cell::new(false));
+        let stop = stopped.clone();
+        event_loop
+            .handle()
+            .insert_source(
+                Signals::new(&[Signal::SIGINT, Signal::SIGTERM]).with_context(|| "Failed to block signals")?,
+                move |event, _, state: &mut WaylandState| {
+                    slog::info!(state.log, "Received {:?}, exiting", event.signal());
+                    stop.set(true);
+                },
+            )
+            .map_err(|err| err.error)
+            .context("Failed to add signals to event loop")?;
+
+        Ok(Standalone {
+            state,
+            event_loop,
+            stopped,
+            _session: session,
+        })
+    }
+
+    /// Handles events for up to one frame, returns `true` if the target was rebuilt and lost its textures
+    pub fn dispatch(&mut self) -> Result<bool> {
+        // the vblank waited for only follows the acquire of the swapped frame
+        render::acquire_frame(&mut self.state);
+        self.event_loop
+            .dispatch(Some(FRAME_TIMEOUT), &mut self.state)
+            .with_context(|| "Failed to dispatch event loop")?;
+        if let Some(err) = self.state.fatal.take() {
+            return Err(err);
+        }
+        if self.state.target_paused {
+            anyhow::bail!("Lost the output to another process");
+        }
+        if self.state.target_lost {
+            crate::rebuild_target(&mut self.state).context("Failed to recover from gpu reset")?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// Shows an animated test pattern on the target of `options` for `duration`, without connecting to a compositor.
+///
+/// Swap errors are handled just like while mirroring, so problems of the driver show up the same way.
+pub fn run(options: &Options, duration: Duration, log: slog::Logger) -> Result<()> {
+    let mut standalone = Standalone::new(options, true, log.clone())?;
+    let dest_size = standalone.state.dest_size;
+    slog::info!(log, "Showing test pattern in {}x{} for {:?}", dest_size.w, dest_size.h, duration);
+    let mut pattern = Pattern { bars: None, frame: 0 };
+
+    let deadline = Instant::now() + duration;
+    while !standalone.stopped.get() && Instant::now() < deadline {
+        if standalone.dispatch()? {
+            pattern.bars = None;
+        }
+        // a failed swap gets no vblank, so it is retried once the dispatch timed out
+        if !standalone.state.swap_pending {
+            if let Err(err) = draw(&mut standalone.state, &mut pattern) {
+                render::render_failed(&mut standalone.state, err);
+            }
+        }
+    }
+    slog::info!(log, "Showed {} frames", pattern.frame);
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use crate::stats;
+
+use std::collections::VecDeque;
+
+/// Swapped frames remembered until their vblank, older ones never reached the screen
+const MAX_SWAPPED: usize = 8;
+
+/// Step of a frame on its way from the compositor to the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// `capture_output` was sent
+    Requested,
+    /// The compositor described the buffer
+    Metadata,
+    /// The buffer holds the frame
+    Ready,
+    CopyStart,
+    CopyEnd,
+    /// Drawn onto the target
+    RenderEnd,
+    /// Handed to the display, by a swap or a direct scanout flip
+    SwapEnd,
+    /// The vblank showing it arrived
+    Displayed,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Requested => "requested",
+            Stage::Metadata => "metadata",
+            Stage::Ready => "ready",
+            Stage::CopyStart => "copy-start",
+            Stage::CopyEnd => "copy-end",
+            Stage::RenderEnd => "render-end",
+            Stage::SwapEnd => "swap-end",
+            Stage::Displayed => "displayed",
+        }
+    }
+}
+
+/// Numbers the captured frames and logs every stage of them for `--trace-frames`.
+///
+/// Each stage is one line `frame=<id> stage=<name> t=<µs>`, `t` being the monotonic clock in microseconds.
+pub struct FrameTracer {
+    next_id: u64,
+    /// Frame being copied and rendered
+    current: Option<u64>,
+    /// Swapped frames waiting for their vblank, oldest first
+    swapped: VecDeque<u64>,
+    log: slog::Logger,
+}
+
+impl FrameTracer {
+    pub fn new(log: &slog::Logger) -> FrameTracer {
+        FrameTracer {
+            next_id: 0,
+            current: None,
+            swapped: VecDeque::new(),
+            log: log.new(slog::o!("trace" => "frames")),
+        }
+    }
+
+    /// Numbers a frame about to be requested
+    pub fn requested(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.record(id, Stage::Requested);
+        id
+    }
+
+    pub fn record(&self, id: u64, stage: Stage) {
+        slog::info!(
+            self.log,
+            "frame={} stage={} t={}",
+            id,
+            stage.name(),
+            stats::monotonic_now().as_micros()
+        );
+    }
+
+    /// `id` is copied and rendered next, the following stages refer to it
+    pub fn begin(&mut self, id: u64) {
+        self.current = Some(id);
+    }
+
+    /// A stage of the frame last passed to `begin`
+    pub fn current(&self, stage: Stage) {
+        if let Some(id) = self.current {
+            self.record(id, stage);
+        }
+    }
+
+    /// The current frame was handed to the display, later stages belong to the next one
+    pub fn swapped(&mut self) {
+        if let Some(id) = self.current.take() {
+            self.record(id, Stage::SwapEnd);
+            if self.swapped.len() == MAX_SWAPPED {
+                self.swapped.pop_front();
+            }
+            self.swapped.push_back(id);
+        }
+    }
+
+    /// The oldest swapped frame reached the screen
+    pub fn displayed(&mut self) {
+        if let Some(id) = self.swapped.pop_front() {
+            self.record(id, Stage::Displayed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    const STAGES: [Stage; 8] = [
+        Stage::Requested,
+        Stage::Metadata,
+        Stage::Ready,
+        Stage::CopyStart,
+        Stage::CopyEnd,
+        Stage::RenderEnd,
+        Stage::SwapEnd,
+        Stage::Displayed,
+    ];
+
+    /// Keeps the messages logged
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl slog::Drain for Collect {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record<'_>, _values: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    fn parse(line: &str) -> Option<(u64, Stage, u128)> {
+        let mut fields = line.split(' ').map(|field| field.split_once('='));
+        match (fields.next()?, fields.next()?, fields.next()?, fields.next()) {
+            (Some(("frame", id)), Some(("stage", name)), Some(("t", t)), None) => Some((
+                id.parse().ok()?,
+                STAGES.iter().copied().find(|stage| stage.name() == name)?,
+                t.parse().ok()?,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Stages every frame passed, in the order they were logged, with their timestamps
+    fn analyze(lines: &[String]) -> BTreeMap<u64, Vec<(Stage, u128)>> {
+        let mut frames = BTreeMap::<u64, Vec<(Stage, u128)>>::new();
+        for line in lines {
+            let (id, stage, t) = parse(line).unwrap_or_else(|| panic!("Malformed trace line {:?}", line));
+            frames.entry(id).or_default().push((stage, t));
+        }
+        frames
+    }
+
+    /// Microseconds spent from each stage of `stages` to the next
+    fn durations(stages: &[(Stage, u128)]) -> Vec<(Stage, u128)> {
+        stages
+            .windows(2)
+            .map(|pair| (pair[1].0, pair[1].1.checked_sub(pair[0].1).expect("time went backwards")))
+            .collect()
+    }
+
+    #[test]
+    fn trace_of_two_frames() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = slog::Logger::root(Collect(lines.clone()), slog::o!());
+        let mut tracer = FrameTracer::new(&log);
+
+        // nothing is traced before a frame was begun or swapped
+        tracer.current(Stage::CopyStart);
+        tracer.displayed();
+
+        let first = tracer.requested();
+        tracer.record(first, Stage::Metadata);
+        tracer.record(first, Stage::Ready);
+        tracer.begin(first);
+        tracer.current(Stage::CopyStart);
+        tracer.current(Stage::CopyEnd);
+        tracer.current(Stage::RenderEnd);
+        tracer.swapped();
+        let second = tracer.requested();
+        tracer.record(second, Stage::Metadata);
+        tracer.displayed();
+        tracer.record(second, Stage::Ready);
+        tracer.begin(second);
+        tracer.current(Stage::CopyStart);
+        tracer.current(Stage::CopyEnd);
+        tracer.current(Stage::RenderEnd);
+        tracer.swapped();
+        tracer.displayed();
+
+        let frames = analyze(&lines.lock().unwrap());
+        assert_eq!(frames.keys().copied().collect::<Vec<_>>(), vec![first, second]);
+        assert_eq!(second, first + 1);
+        for stages in frames.values() {
+            assert_eq!(stages.iter().map(|(stage, _)| *stage).collect::<Vec<_>>(), STAGES);
+            let durations = durations(stages);
+            assert_eq!(durations.len(), STAGES.len() - 1);
+            let total = durations.iter().map(|(_, duration)| duration).sum::<u128>();
+            assert_eq!(total, stages[STAGES.len() - 1].1 - stages[0].1);
+        }
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert_eq!(parse("frame=3 stage=ready t=17"), Some((3, Stage::Ready, 17)));
+        assert_eq!(parse("frame=3 stage=ready"), None);
+        assert_eq!(parse("frame=3 stage=ready t=17 extra=1"), None);
+        assert_eq!(parse("frame=3 stage=unknown t=17"), None);
+        assert_eq!(parse("Frame ready"), None);
+    }
+}
@@ -0,0 +1,544 @@
+use anyhow::{Context, Result};
+use ash::{extensions::khr::ExternalMemoryFd, vk, Device, Entry, Instance};
+use nix::{
+    sys::stat::{fstat, major, minor},
+    unistd::{close, dup},
+};
+use smithay::{
+    backend::allocator::{dmabuf::Dmabuf, Buffer},
+    utils::{Buffer as BufferCoords, Rectangle},
+};
+
+use crate::{
+    gpu::ColorDepth,
+    render::{self, ChannelOrder, Layout},
+};
+
+use std::{ffi::CStr, os::unix::io::RawFd};
+
+/// `DRM_FORMAT_MOD_INVALID`, vulkan can only import buffers with an explicit modifier
+const MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+/// Upper bound for waiting on a copy, in nanoseconds
+const COPY_TIMEOUT: u64 = 1_000_000_000;
+
+fn device_extensions() -> [&'static CStr; 4] {
+    [
+        ExternalMemoryFd::name(),
+        vk::ExtExternalMemoryDmaBufFn::name(),
+        vk::ExtImageDrmFormatModifierFn::name(),
+        vk::ExtQueueFamilyForeignFn::name(),
+    ]
+}
+
+/// Host visible buffer the frames are copied into
+struct Staging {
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    size: u64,
+    ptr: *const u8,
+}
+
+/// Copies dmabufs of the render gpu into cpu memory through vulkan.
+///
+/// Unlike reading back through GL this handles tiled buffers without rendering them first,
+/// the copy engine of the render gpu does the detiling.
+/// Only single plane 8 bit RGB formats are supported so far.
+pub struct VulkanCopy {
+    _entry: Entry,
+    instance: Instance,
+    device: Device,
+    queue: vk::Queue,
+    queue_family: u32,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    external_memory: ExternalMemoryFd,
+    staging: Option<Staging>,
+    log: slog::Logger,
+}
+
+/// Finds the vulkan device driving the drm node `dev`
+unsafe fn find_device(instance: &Instance, dev: (u64, u64)) -> Result<Option<vk::PhysicalDevice>> {
+    for physical in instance.enumerate_physical_devices()? {
+        let extensions = instance.enumerate_device_extension_properties(physical)?;
+        let supported = |name: &CStr| {
+            extensions
+                .iter()
+                .any(|ext| CStr::from_ptr(ext.extension_name.as_ptr()) == name)
+        };
+        if !supported(vk::ExtPhysicalDeviceDrmFn::name())
+            || !device_extensions().iter().all(|name| supported(name))
+        {
+            continue;
+        }
+        let mut drm = vk::PhysicalDeviceDrmPropertiesEXT::default();
+        let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut drm);
+        instance.get_physical_device_properties2(physical, &mut properties);
+        let render = drm.has_render == vk::TRUE
+            && (drm.render_major as u64, drm.render_minor as u64) == dev;
+        let primary = drm.has_primary == vk::TRUE
+            && (drm.primary_major as u64, drm.primary_minor as u64) == dev;
+        if render || primary {
+            return Ok(Some(physical));
+        }
+    }
+    Ok(None)
+}
+
+fn find_memory_type(
+    properties: &vk::PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+    flags: vk::MemoryPropertyFlags,
+) -> Option<u32> {
+    (0..properties.memory_type_count).find(|&i| {
+        type_bits & (1 << i) != 0
+            && properties.memory_types[i as usize].property_flags.contains(flags)
+    })
+}
+
+impl VulkanCopy {
+    /// Sets up copies on the gpu behind the drm node `fd`
+    pub fn new(fd: RawFd, log: &slog::Logger) -> Result<VulkanCopy> {
+        let stat = fstat(fd).context("Failed to stat render gpu")?;
+        let dev = (major(stat.st_rdev), minor(stat.st_rdev));
+
+        unsafe {
+            let entry = Entry::new().context("Failed to load vulkan")?;
+            let app_info = vk::ApplicationInfo::builder()
+                .application_name(CStr::from_bytes_with_nul(b"nvscreencopy\0").unwrap())
+                .api_version(vk::API_VERSION_1_2);
+            let instance = entry
+                .create_instance(&vk::InstanceCreateInfo::builder().application_info(&app_info), None)
+                .context("Failed to create vulkan instance")?;
+
+            let physical = match find_device(&instance, dev) {
+                Ok(Some(physical)) => physical,
+                Ok(None) => {
+                    instance.destroy_instance(None);
+                    anyhow::bail!("No vulkan device with dmabuf import found for the render gpu");
+                }
+                Err(err) => {
+                    instance.destroy_instance(None);
+                    return Err(err);
+                }
+            };
+            let properties = instance.get_physical_device_properties(physical);
+            slog::info!(
+                log,
+                "Vulkan device: {}",
+                CStr::from_ptr(properties.device_name.as_ptr()).to_string_lossy()
+            );
+
+            // every graphics or compute queue can also do transfers
+            let queue_family = match instance
+                .get_physical_device_queue_family_properties(physical)
+                .iter()
+                .position(|family| {
+                    family.queue_flags.intersects(
+                        vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER,
+                    )
+                }) {
+                Some(family) => family as u32,
+                None => {
+                    instance.destroy_instance(None);
+                    anyhow::bail!("Vulkan device has no queue for transfers");
+                }
+            };
+            let priorities = [1.0];
+            let queue_info = [vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(queue_family)
+                .queue_priorities(&priorities)
+                .build()];
+            let extensions = device_extensions()
+                .iter()
+                .map(|name| name.as_ptr())
+                .collect::<Vec<_>>();
+            let device = match instance.create_device(
+                physical,
+                &vk::DeviceCreateInfo::builder()
+                    .queue_create_infos(&queue_info)
+                    .enabled_extension_names(&extensions),
+                None,
+            ) {
+                Ok(device) => device,
+                Err(err) => {
+                    instance.destroy_instance(None);
+                    return Err(err).context("Failed to create vulkan device");
+                }
+            };
+            let queue = device.get_device_queue(queue_family, 0);
+            let external_memory = ExternalMemoryFd::new(&instance, &device);
+            let memory_properties = instance.get_physical_device_memory_properties(physical);
+
+            let mut copy = VulkanCopy {
+                _entry: entry,
+                instance,
+                device,
+                queue,
+                queue_family,
+                command_pool: vk::CommandPool::null(),
+                command_buffer: vk::CommandBuffer::null(),
+                fence: vk::Fence::null(),
+                memory_properties,
+                external_memory,
+                staging: None,
+                log: log.clone(),
+            };
+            // from here on `Drop` cleans up
+            copy.command_pool = copy.device.create_command_pool(
+                &vk::CommandPoolCreateInfo::builder()
+                    .queue_family_index(queue_family)
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
+                None,
+            )?;
+            copy.command_buffer = copy.device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(copy.command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY)
+                    .command_buffer_count(1),
+            )?[0];
+            copy.fence = copy
+                .device
+                .create_fence(&vk::FenceCreateInfo::default(), None)?;
+            Ok(copy)
+        }
+    }
+
+    /// Imports the dmabuf as image, without copying it
+    unsafe fn import(&self, buf: &Dmabuf, format: vk::Format) -> Result<(vk::Image, vk::DeviceMemory)> {
+        let modifier: u64 = buf.format().modifier.into();
+        if modifier == MOD_INVALID {
+            anyhow::bail!("Vulkan needs an explicit modifier to import dmabufs");
+        }
+        let (fd, offset, stride) = match (buf.handles().next(), buf.offsets().next(), buf.strides().next()) {
+            (Some(fd), Some(offset), Some(stride)) => (fd, offset, stride),
+            _ => anyhow::bail!("Dmabuf without planes"),
+        };
+        let plane_layouts = [vk::SubresourceLayout {
+            offset: offset as u64,
+            size: 0,
+            row_pitch: stride as u64,
+            array_pitch: 0,
+            depth_pitch: 0,
+        }];
+        let mut modifier_info = vk::ImageDrmFormatModifierExplicitCreateInfoEXT::builder()
+            .drm_format_modifier(modifier)
+            .plane_layouts(&plane_layouts);
+        let mut external_info = vk::ExternalMemoryImageCreateInfo::builder()
+            .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let (width, height) = (buf.width(), buf.height());
+        let image = self.device.create_image(
+            &vk::ImageCreateInfo::builder()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+                .usage(vk::ImageUsageFlags::TRANSFER_SRC)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .push_next(&mut external_info)
+                .push_next(&mut modifier_info),
+            None,
+        )?;
+
+        let memory = self.import_memory(image, fd);
+        match memory {
+            Ok(memory) => Ok((image, memory)),
+            Err(err) => {
+                self.device.destroy_image(image, None);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn import_memory(&self, image: vk::Image, fd: RawFd) -> Result<vk::DeviceMemory> {
+        // vulkan takes ownership of the fd, if the import succeeds
+        let fd = dup(fd)?;
+        let fd_properties = match self
+            .external_memory
+            .get_memory_fd_properties(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT, fd)
+        {
+            Ok(properties) => properties,
+            Err(err) => {
+                let _ = close(fd);
+                return Err(err.into());
+            }
+        };
+        let requirements = self.device.get_image_memory_requirements(image);
+        let type_bits = requirements.memory_type_bits & fd_properties.memory_type_bits;
+        let memory_type = match find_memory_type(&self.memory_properties, type_bits, vk::MemoryPropertyFlags::empty()) {
+            Some(memory_type) => memory_type,
+            None => {
+                let _ = close(fd);
+                anyhow::bail!("No memory type to import the dmabuf into");
+            }
+        };
+        let mut import_info = vk::ImportMemoryFdInfoKHR::builder()
+            .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
+            .fd(fd);
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+        let memory = match self.device.allocate_memory(
+            &vk::MemoryAllocateInfo::builder()
+                .allocation_size(requirements.size)
+                .memory_type_index(memory_type)
+                .push_next(&mut import_info)
+                .push_next(&mut dedicated_info),
+            None,
+        ) {
+            Ok(memory) => memory,
+            Err(err) => {
+                let _ = close(fd);
+                return Err(err.into());
+            }
+        };
+        if let Err(err) = self.device.bind_image_memory(image, memory, 0) {
+            self.device.free_memory(memory, None);
+            return Err(err.into());
+        }
+        Ok(memory)
+    }
+
+    /// A staging buffer of at least `size` bytes
+    unsafe fn staging(&mut self, size: u64) -> Result<&Staging> {
+        if self.staging.as_ref().map(|staging| staging.size < size).unwrap_or(true) {
+            if let Some(staging) = self.staging.take() {
+                self.device.destroy_buffer(staging.buffer, None);
+                self.device.free_memory(staging.memory, None);
+            }
+            let buffer = self.device.create_buffer(
+                &vk::BufferCreateInfo::builder()
+                    .size(size)
+                    .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                None,
+            )?;
+            let requirements = self.device.get_buffer_memory_requirements(buffer);
+            let visible = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+            // cached memory is a lot faster to read from the cpu
+            let memory_type = find_memory_type(
+                &self.memory_properties,
+                requirements.memory_type_bits,
+                visible | vk::MemoryPropertyFlags::HOST_CACHED,
+            )
+            .or_else(|| find_memory_type(&self.memory_properties, requirements.memory_type_bits, visible));
+            let memory = match memory_type {
+                Some(memory_type) => self.device.allocate_memory(
+                    &vk::MemoryAllocateInfo::builder()
+                        .allocation_size(requirements.size)
+                        .memory_type_index(memory_type),
+                    None,
+                ),
+                None => Err(vk::Result::ERROR_OUT_OF_HOST_MEMORY),
+            };
+            let memory = match memory {
+                Ok(memory) => memory,
+                Err(err) => {
+                    self.device.destroy_buffer(buffer, None);
+                    return Err(err).context("Failed to allocate vulkan staging buffer");
+                }
+            };
+            let mapped = self
+                .device
+                .bind_buffer_memory(buffer, memory, 0)
+                .and_then(|_| self.device.map_memory(memory, 0, size, vk::MemoryMapFlags::empty()));
+            let ptr = match mapped {
+                Ok(ptr) => ptr as *const u8,
+                Err(err) => {
+                    self.device.destroy_buffer(buffer, None);
+                    self.device.free_memory(memory, None);
+                    return Err(err).context("Failed to map vulkan staging buffer");
+                }
+            };
+            self.staging = Some(Staging {
+                buffer,
+                memory,
+                size,
+                ptr,
+            });
+        }
+        Ok(self.staging.as_ref().unwrap())
+    }
+
+    /// Copies `region` of `buf` tightly packed into `pixels` and returns their layout
+    pub fn copy(
+        &mut self,
+        buf: &Dmabuf,
+        region: Rectangle<i32, BufferCoords>,
+        pixels: &mut Vec<u8>,
+    ) -> Result<Layout> {
+        let layout = render::memory_layout(buf.format().code)
+            .filter(|layout| layout.depth == ColorDepth::Eight)
+            .with_context(|| format!("Vulkan copies of {:?} are not supported", buf.format().code))?;
+        if buf.num_planes() != 1 {
+            anyhow::bail!("Vulkan copies of multi-planar buffers are not supported");
+        }
+        // the alpha channel is carried along, just like the other channels
+        let format = match layout.order {
+            ChannelOrder::Bgra => vk::Format::B8G8R8A8_UNORM,
+            ChannelOrder::Rgba => vk::Format::R8G8B8A8_UNORM,
+        };
+        let size = (region.size.w * region.size.h * 4) as u64;
+
+        unsafe {
+            let (image, memory) = self.import(buf, format)?;
+            let result = self.record_and_submit(image, region, size);
+            // a copy that timed out may still be reading the image
+            if let Err(err) = result {
+                if let Err(idle) = self.device.device_wait_idle() {
+                    slog::warn!(
+                        self.log,
+                        "Vulkan device did not finish the failed copy ({}), leaking its image",
+                        idle
+                    );
+                    return Err(err);
+                }
+                self.device.destroy_image(image, None);
+                self.device.free_memory(memory, None);
+                return Err(err);
+            }
+            self.device.destroy_image(image, None);
+            self.device.free_memory(memory, None);
+
+            let staging = self.staging.as_ref().unwrap();
+            pixels.resize(size as usize, 0);
+            std::ptr::copy_nonoverlapping(staging.ptr, pixels.as_mut_ptr(), size as usize);
+        }
+        Ok(layout)
+    }
+
+    unsafe fn record_and_submit(
+        &mut self,
+        image: vk::Image,
+        region: Rectangle<i32, BufferCoords>,
+        size: u64,
+    ) -> Result<()> {
+        let staging = self.staging(size)?.buffer;
+        let device = &self.device;
+        let command_buffer = self.command_buffer;
+        device.begin_command_buffer(
+            command_buffer,
+            &vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        // the compositor owns the image, so it needs to be acquired from outside of vulkan and released again
+        let acquire = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_FOREIGN_EXT)
+            .dst_queue_family_index(self.queue_family)
+            .image(image)
+            .subresource_range(range)
+            .build();
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[acquire],
+        );
+        let copy = vk::BufferImageCopy {
+            buffer_offset: 0,
+            // tightly packed
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D {
+                x: region.loc.x,
+                y: region.loc.y,
+                z: 0,
+            },
+            image_extent: vk::Extent3D {
+                width: region.size.w as u32,
+                height: region.size.h as u32,
+                depth: 1,
+            },
+        };
+        device.cmd_copy_image_to_buffer(
+            command_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            staging,
+            &[copy],
+        );
+        let release = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(self.queue_family)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_FOREIGN_EXT)
+            .image(image)
+            .subresource_range(range)
+            .build();
+        let host_read = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(staging)
+            .offset(0)
+            .size(size)
+            .build();
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[host_read],
+            &[release],
+        );
+        device.end_command_buffer(command_buffer)?;
+
+        let command_buffers = [command_buffer];
+        device.reset_fences(&[self.fence])?;
+        device.queue_submit(
+            self.queue,
+            &[vk::SubmitInfo::builder().command_buffers(&command_buffers).build()],
+            self.fence,
+        )?;
+        device
+            .wait_for_fences(&[self.fence], true, COPY_TIMEOUT)
+            .context("Vulkan copy did not finish")?;
+        Ok(())
+    }
+}
+
+impl Drop for VulkanCopy {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.device_wait_idle();
+            if let Some(staging) = self.staging.take() {
+                self.device.destroy_buffer(staging.buffer, None);
+                self.device.free_memory(staging.memory, None);
+            }
+            self.device.destroy_fence(self.fence, None);
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_device(None);
+            self.instance.destroy_instance(None);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::streak::{Streak, Verdict};
+
+use std::time::{Duration, Instant};
+
+/// What to do about stalled captures, decided by `Watchdog::check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Drop the frames requested so far and request new ones
+    Restart,
+    /// Set up the capture backend again and look up the sources anew
+    Reinitialize,
+}
+
+/// Notices captures that stopped delivering frames, without the compositor cancelling them.
+///
+/// Fires whenever no frame arrived for `timeout` while capturing, every `escalate_after`th
+/// firing in a row asks for more than restarting the captures.
+pub struct Watchdog {
+    timeout: Duration,
+    /// Since when capturing goes on without a frame, or since the last firing
+    since: Instant,
+    firings: Streak,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, escalate_after: u32, now: Instant) -> Watchdog {
+        Watchdog {
+            timeout,
+            since: now,
+            firings: Streak::new(1, Some(escalate_after)),
+        }
+    }
+
+    /// Capturing is not expected at `now`, e.g. because the source or the target is gone
+    pub fn idle(&mut self, now: Instant) {
+        self.since = now;
+    }
+
+    /// Checks on capturing at `now`, `last_frame` is when the newest frame arrived
+    pub fn check(&mut self, now: Instant, last_frame: Option<Instant>) -> Option<Action> {
+        if let Some(frame) = last_frame.filter(|frame| *frame > self.since) {
+            self.since = frame;
+            self.firings.succeeded();
+        }
+        if now.saturating_duration_since(self.since) < self.timeout {
+            return None;
+        }
+        self.since = now;
+        match self.firings.failed() {
+            Verdict::Escalate => {
+                self.firings.succeeded();
+                Some(Action::Reinitialize)
+            }
+            _ => Some(Action::Restart),
+        }
+    }
+
+    /// When `check` would fire next, unless a frame arrives meanwhile
+    pub fn deadline(&self) -> Instant {
+        self.since + self.timeout
+    }
+
+    /// Firings in a row since the last frame
+    pub fn firings(&self) -> u32 {
+        self.firings.count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn quiet_while_frames_arrive() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT, 3, start);
+        for second in 1..10 {
+            assert_eq!(watchdog.check(secs(start, second), Some(secs(start, second))), None);
+        }
+        assert_eq!(watchdog.deadline(), secs(start, 9) + TIMEOUT);
+    }
+
+    #[test]
+    fn restarts_stalled_captures() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT, 3, start);
+        assert_eq!(watchdog.check(secs(start, 2), None), None);
+        assert_eq!(watchdog.check(secs(start, 3), None), Some(Action::Restart));
+        assert_eq!(watchdog.firings(), 1);
+        // the timeout starts over after firing
+        assert_eq!(watchdog.check(secs(start, 5), None), None);
+        assert_eq!(watchdog.deadline(), secs(start, 6));
+    }
+
+    #[test]
+    fn escalates_after_firings_in_a_row() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT, 3, start);
+        let last_frame = Some(start);
+        assert_eq!(watchdog.check(secs(start, 3), last_frame), Some(Action::Restart));
+        assert_eq!(watchdog.check(secs(start, 6), last_frame), Some(Action::Restart));
+        assert_eq!(watchdog.check(secs(start, 9), last_frame), Some(Action::Reinitialize));
+        // reinitializing starts a new streak
+        assert_eq!(watchdog.firings(), 0);
+        assert_eq!(watchdog.check(secs(start, 12), last_frame), Some(Action::Restart));
+    }
+
+    #[test]
+    fn frames_reset_the_streak() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT, 3, start);
+        assert_eq!(watchdog.check(secs(start, 3), None), Some(Action::Restart));
+        assert_eq!(watchdog.check(secs(start, 6), None), Some(Action::Restart));
+        // a frame after the restart
+        assert_eq!(watchdog.check(secs(start, 7), Some(secs(start, 7))), None);
+        assert_eq!(watchdog.firings(), 0);
+        assert_eq!(watchdog.check(secs(start, 10), Some(secs(start, 7))), Some(Action::Restart));
+        assert_eq!(watchdog.check(secs(start, 13), Some(secs(start, 7))), Some(Action::Restart));
+        assert_eq!(watchdog.check(secs(start, 16), Some(secs(start, 7))), Some(Action::Reinitialize));
+    }
+
+    #[test]
+    fn idle_time_does_not_count() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT, 3, start);
+        // the source was gone for a while
+        watchdog.idle(secs(start, 10));
+        assert_eq!(watchdog.check(secs(start, 12), Some(start)), None);
+        assert_eq!(watchdog.check(secs(start, 13), Some(start)), Some(Action::Restart));
+    }
+}
@@ -0,0 +1,252 @@
+//! Renders synthetic frames offscreen and compares them against the references in `tests/golden`.
+//!
+//! Skipped on machines without a render node. `NVSCREENCOPY_GOLDEN_UPDATE=1` writes the references anew.
+
+use nvscreencopy::golden::{self, FilterKind, Fourcc, Frame, Scene, Transform};
+
+use std::path::{Path, PathBuf};
+
+/// Colors of the 4x2 frame, top row first
+const PATTERN: [[u8; 3]; 8] = [
+    [0xff, 0x00, 0x00],
+    [0x00, 0xff, 0x00],
+    [0x00, 0x00, 0xff],
+    [0xff, 0xff, 0xff],
+    [0x00, 0x00, 0x00],
+    [0xff, 0xff, 0x00],
+    [0x00, 0xff, 0xff],
+    [0xff, 0x00, 0xff],
+];
+const WIDTH: i32 = 4;
+const HEIGHT: i32 = 2;
+/// Every pixel of the frame covers 4x4 pixels of the target
+const SCALED: (i32, i32) = (16, 8);
+
+/// The pattern stored in `format`, with `padding` bytes after every row
+fn frame(format: Fourcc, padding: i32, y_invert: bool) -> Frame {
+    let stride = WIDTH * 4 + padding;
+    let mut pixels = vec![0u8; (stride * HEIGHT) as usize];
+    for (index, [r, g, b]) in PATTERN.iter().copied().enumerate() {
+        let (x, y) = (index as i32 % WIDTH, index as i32 / WIDTH);
+        let row = if y_invert { HEIGHT - 1 - y } else { y };
+        let ten = |value: u8| u32::from(value) << 2 | u32::from(value) >> 6;
+        let bytes = match format {
+            Fourcc::Argb8888 | Fourcc::Xrgb8888 => [b, g, r, 0xff],
+            Fourcc::Abgr8888 | Fourcc::Xbgr8888 => [r, g, b, 0xff],
+            Fourcc::Xrgb2101010 => (0x3 << 30 | ten(r) << 20 | ten(g) << 10 | ten(b)).to_le_bytes(),
+            Fourcc::Abgr2101010 => (0x3 << 30 | ten(b) << 20 | ten(g) << 10 | ten(r)).to_le_bytes(),
+            format => panic!("No pattern for {:?}", format),
+        };
+        let at = (row * stride + x * 4) as usize;
+        pixels[at..at + 4].copy_from_slice(&bytes);
+    }
+    Frame {
+        format,
+        size: (WIDTH, HEIGHT),
+        stride,
+        pixels,
+        y_invert,
+    }
+}
+
+fn scene(size: (i32, i32), transform: Transform) -> Scene {
+    Scene {
+        size,
+        transform,
+        filter: FilterKind::Nearest,
+        linear_blending: false,
+    }
+}
+
+/// Renders `frame`, `None` if the machine has no gpu to render on
+fn render(frame: &Frame, scene: &Scene) -> Option<Vec<u8>> {
+    let node = match golden::render_node() {
+        Some(node) => node,
+        None => {
+            eprintln!("No render node, skipping, {} selects one", golden::NODE_VAR);
+            return None;
+        }
+    };
+    Some(golden::render(&node, frame, scene).unwrap())
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name))
+}
+
+fn load_png(path: &Path) -> ((i32, i32), Vec<u8>) {
+    let image = image::open(path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+        .into_rgba8();
+    ((image.width() as i32, image.height() as i32), image.into_raw())
+}
+
+/// Compares `pixels` of `size` with the reference `name`, keeping them next to the test binaries if they differ
+fn assert_golden(name: &str, size: (i32, i32), pixels: &[u8]) {
+    let path = reference_path(name);
+    if std::env::var_os("NVSCREENCOPY_GOLDEN_UPDATE").is_some() {
+        golden::save_png(pixels, size, &path).unwrap();
+        return;
+    }
+    if load_png(&path) != (size, pixels.to_vec()) {
+        let actual = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.png", name));
+        golden::save_png(pixels, size, &actual).unwrap();
+        panic!("{} differs from {}", actual.display(), path.display());
+    }
+}
+
+/// The colors of the pattern as 2x2 blocks, each with the color on its diagonal and black next to it
+fn checkered() -> Frame {
+    let (width, height) = (WIDTH * 2, HEIGHT * 2);
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+    for (index, [r, g, b]) in PATTERN.iter().copied().enumerate() {
+        let (x, y) = (index as i32 % WIDTH * 2, index as i32 / WIDTH * 2);
+        for (x, y) in [(x, y), (x + 1, y + 1)] {
+            let at = ((y * width + x) * 4) as usize;
+            pixels[at..at + 4].copy_from_slice(&[b, g, r, 0xff]);
+        }
+    }
+    Frame {
+        format: Fourcc::Argb8888,
+        size: (width, height),
+        stride: width * 4,
+        pixels,
+        y_invert: false,
+    }
+}
+
+fn to_linear(value: u8) -> f64 {
+    let value = f64::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_srgb(value: f64) -> u8 {
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round() as u8
+}
+
+/// RGBA `frame` of 32 bit ARGB scaled down to half its size, averaging every 2x2 block in linear light
+fn downscaled_in_linear_light(frame: &Frame) -> Vec<u8> {
+    let (width, height) = (frame.size.0 / 2, frame.size.1 / 2);
+    let mut pixels = Vec::new();
+    for (x, y) in (0..height).flat_map(|y| (0..width).map(move |x| (x, y))) {
+        // bgra in memory
+        for channel in [2, 1, 0] {
+            let sum: f64 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                .iter()
+                .map(|(dx, dy)| {
+                    let at = (y * 2 + dy) * frame.stride + (x * 2 + dx) * 4 + channel;
+                    to_linear(frame.pixels[at as usize])
+                })
+                .sum();
+            pixels.push(to_srgb(sum / 4.0));
+        }
+        pixels.push(0xff);
+    }
+    pixels
+}
+
+/// `pixels` turned by 180 degrees
+fn turned(pixels: &[u8]) -> Vec<u8> {
+    pixels.chunks_exact(4).rev().flatten().copied().collect()
+}
+
+#[test]
+fn scaling() {
+    if let Some(pixels) = render(&frame(Fourcc::Argb8888, 0, false), &scene(SCALED, Transform::Normal)) {
+        assert_golden("scaled", SCALED, &pixels);
+    }
+}
+
+#[test]
+fn auto_filter_keeps_integer_scaling_sharp() {
+    let scene = Scene {
+        filter: FilterKind::Auto,
+        ..scene(SCALED, Transform::Normal)
+    };
+    if let Some(pixels) = render(&frame(Fourcc::Argb8888, 0, false), &scene) {
+        assert_golden("scaled", SCALED, &pixels);
+    }
+}
+
+#[test]
+fn transforms() {
+    for (transform, name) in [(Transform::_180, "turned_180"), (Transform::Flipped, "flipped")] {
+        if let Some(pixels) = render(&frame(Fourcc::Argb8888, 0, false), &scene(SCALED, transform)) {
+            assert_golden(name, SCALED, &pixels);
+        }
+    }
+}
+
+#[test]
+fn quarter_turns() {
+    // the target is portrait, a quarter turn one way is a quarter turn the other way turned by 180 degrees
+    let size = (SCALED.1, SCALED.0);
+    for (transform, opposite) in [(Transform::_90, Transform::_270), (Transform::Flipped90, Transform::Flipped270)] {
+        let frame = frame(Fourcc::Argb8888, 0, false);
+        let pixels = render(&frame, &scene(size, transform));
+        if let (Some(pixels), Some(other)) = (pixels, render(&frame, &scene(size, opposite))) {
+            assert_ne!(pixels, other, "{:?}", transform);
+            assert_eq!(pixels, turned(&other), "{:?}", transform);
+        }
+    }
+}
+
+#[test]
+fn y_invert() {
+    if let Some(pixels) = render(&frame(Fourcc::Argb8888, 0, true), &scene(SCALED, Transform::Normal)) {
+        assert_golden("scaled", SCALED, &pixels);
+    }
+}
+
+#[test]
+fn format_conversions() {
+    for format in [
+        Fourcc::Argb8888,
+        Fourcc::Xrgb8888,
+        Fourcc::Abgr8888,
+        Fourcc::Xbgr8888,
+        Fourcc::Xrgb2101010,
+        Fourcc::Abgr2101010,
+    ] {
+        if let Some(pixels) = render(&frame(format, 0, false), &scene(SCALED, Transform::Normal)) {
+            assert_golden("scaled", SCALED, &pixels);
+        }
+    }
+}
+
+#[test]
+fn padded_rows() {
+    if let Some(pixels) = render(&frame(Fourcc::Xrgb8888, 12, false), &scene(SCALED, Transform::Normal)) {
+        assert_golden("scaled", SCALED, &pixels);
+    }
+}
+
+#[test]
+fn linear_blending() {
+    let frame = checkered();
+    let scene = Scene {
+        filter: FilterKind::Linear,
+        linear_blending: true,
+        ..scene((WIDTH, HEIGHT), Transform::Normal)
+    };
+    if let Some(pixels) = render(&frame, &scene) {
+        let expected = downscaled_in_linear_light(&frame);
+        // full channels blend to 188 in linear light, but only to 128 on the sRGB values
+        assert!(expected.contains(&188));
+        // the shader may round a little differently
+        let close = pixels.len() == expected.len()
+            && pixels.iter().zip(&expected).all(|(a, b)| (i32::from(*a) - i32::from(*b)).abs() <= 2);
+        assert!(close, "{:?} instead of {:?}", pixels, expected);
+    }
+}